@@ -39,6 +39,17 @@ fn main() {
     for flag in common_flags {
         cc_sqlite.flag(flag);
     }
+    // Optional SQLite modules, one Cargo feature each (`db info` lists
+    // what a build has)
+    if !feature("SQLITE_JSON") {
+        cc_sqlite.define("SQLITE_OMIT_JSON", "1");
+    }
+    if feature("SQLITE_FTS5") {
+        cc_sqlite.define("SQLITE_ENABLE_FTS5", "1");
+    }
+    if feature("SQLITE_RTREE") {
+        cc_sqlite.define("SQLITE_ENABLE_RTREE", "1");
+    }
     cc_sqlite.warnings(false).flag("-w").compile("sqlite3");
 
     // ---- setjmp/longjmp assembly ----
@@ -112,3 +123,8 @@ fn main() {
     println!("cargo:rerun-if-changed=vendor/sqlite/heaven_setjmp.S");
     println!("cargo:rerun-if-changed=vendor/lua");
 }
+
+/// Is Cargo feature `name` (upper case, `-` as `_`) enabled?
+fn feature(name: &str) -> bool {
+    std::env::var_os(format!("CARGO_FEATURE_{}", name)).is_some()
+}
@@ -0,0 +1,174 @@
+/// Message Batches API: requests answered within 24 hours at half the
+/// price, for work where latency doesn't matter.
+///
+/// `submit` sends a set of requests, each tagged with a `custom_id`;
+/// `status` polls the batch until its `processing_status` is "ended", and
+/// `results` then fetches every request's outcome. The outbox drives it
+/// (see `sqlite::outbox`): requests queued with `ask -b` or
+/// `ask_queued{..., batch=true}` are submitted together by the maintenance
+/// `batches` task, which later writes each answer to its mailbox and logs
+/// the usage with the batch discount.
+///
+/// Results are read from `/v1/messages/batches/<id>/results` on the
+/// configured host, not from the `results_url` the API returns, so the key
+/// is never sent anywhere else.
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use super::{api_request, check_config, check_status, http, json, message_params, parse_message};
+use super::{escape_json, ApiError, ClaudeConfig, ClaudeResponse, Message};
+use crate::net::NetStack;
+
+/// Share of the usual price a batch saves.
+pub const DISCOUNT_PERCENT: u8 = 50;
+
+/// Most requests the outbox puts in one batch.
+pub const MAX_REQUESTS: usize = 32;
+
+/// Largest response accepted: the results of a full batch.
+const MAX_RESPONSE: usize = 4 * 1024 * 1024;
+
+/// One request of a batch.
+pub struct Request {
+    /// Matches the result to the request: 1 to 64 of `[A-Za-z0-9_-]`.
+    pub custom_id: String,
+    pub model: String,
+    pub system: Option<String>,
+    pub messages: Vec<Message>,
+}
+
+/// A batch as the API reports it.
+#[derive(Debug, Clone)]
+pub struct Batch {
+    pub id: String,
+    /// "in_progress", "canceling" or "ended".
+    pub processing_status: String,
+}
+
+impl Batch {
+    /// Are all its results available?
+    pub fn ended(&self) -> bool {
+        self.processing_status == "ended"
+    }
+}
+
+/// What became of one request.
+pub enum Outcome {
+    Succeeded(ClaudeResponse),
+    Errored(String),
+    Canceled,
+    /// Not processed within 24 hours.
+    Expired,
+}
+
+/// Submit `requests` as one batch.
+pub fn submit(net: &mut NetStack, config: &ClaudeConfig, requests: &[Request]) -> Result<Batch, ApiError> {
+    let mut body = String::from(r#"{"requests":["#);
+    for (i, request) in requests.iter().enumerate() {
+        if !valid_custom_id(&request.custom_id) {
+            return Err(ApiError::ApiError(format!("bad custom_id {:?}", request.custom_id)));
+        }
+        if i > 0 {
+            body.push(',');
+        }
+        let params =
+            message_params(&request.model, None, request.system.as_deref(), &request.messages, false, &[]);
+        body.push_str(&format!(r#"{{"custom_id":"{}","params":{}}}"#, escape_json(&request.custom_id), params));
+    }
+    body.push_str("]}");
+    let response = exchange(net, config, "POST /v1/messages/batches", &body)?;
+    parse_batch(&response)
+}
+
+/// The current state of batch `id`.
+pub fn status(net: &mut NetStack, config: &ClaudeConfig, id: &str) -> Result<Batch, ApiError> {
+    check_id(id)?;
+    let response = exchange(net, config, &format!("GET /v1/messages/batches/{}", id), "")?;
+    parse_batch(&response)
+}
+
+/// The outcome of every request in ended batch `id`, by custom_id.
+pub fn results(net: &mut NetStack, config: &ClaudeConfig, id: &str) -> Result<Vec<(String, Outcome)>, ApiError> {
+    check_id(id)?;
+    let response = exchange(net, config, &format!("GET /v1/messages/batches/{}/results", id), "")?;
+    Ok(parse_results(&response))
+}
+
+/// Read a batch object.
+pub fn parse_batch(body: &str) -> Result<Batch, ApiError> {
+    let parsed = json::parse(body.trim()).map_err(|e| ApiError::ApiError(format!("batch: {}", e)))?;
+    let field = |name| parsed.get(name).and_then(|v| v.as_str()).map(String::from);
+    match (field("id"), field("processing_status")) {
+        (Some(id), Some(processing_status)) => Ok(Batch { id, processing_status }),
+        _ => Err(ApiError::ApiError(String::from("batch: no id or processing_status"))),
+    }
+}
+
+/// Read a results file: one JSON object per line. Lines that don't parse
+/// or carry no custom_id are skipped.
+pub fn parse_results(body: &str) -> Vec<(String, Outcome)> {
+    let mut out = Vec::new();
+    for line in body.lines().filter(|l| !l.trim().is_empty()) {
+        let Ok(parsed) = json::parse(line.trim()) else { continue };
+        let Some(custom_id) = parsed.get("custom_id").and_then(|v| v.as_str()) else { continue };
+        let result = parsed.get("result");
+        let outcome = match result.and_then(|r| r.get("type")).and_then(|v| v.as_str()) {
+            Some("succeeded") => match result.and_then(|r| r.get("message")).map(parse_message) {
+                Some(Ok(message)) => Outcome::Succeeded(message),
+                Some(Err(e)) => Outcome::Errored(format!("{}", e)),
+                None => Outcome::Errored(String::from("no message in result")),
+            },
+            Some("canceled") => Outcome::Canceled,
+            Some("expired") => Outcome::Expired,
+            _ => {
+                // {"type":"errored","error":{"type":"error","error":{"type":...,"message":...}}}
+                let error = result.and_then(|r| r.get("error"));
+                let inner = error.and_then(|e| e.get("error")).or(error);
+                let message = inner.and_then(|e| e.get("message")).and_then(|m| m.as_str());
+                Outcome::Errored(String::from(message.unwrap_or("request errored")))
+            }
+        };
+        out.push((String::from(custom_id), outcome));
+    }
+    out
+}
+
+fn valid_custom_id(id: &str) -> bool {
+    (1..=64).contains(&id.len()) && id.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-')
+}
+
+/// Batch ids go into the request line.
+fn check_id(id: &str) -> Result<(), ApiError> {
+    if valid_custom_id(id) {
+        Ok(())
+    } else {
+        Err(ApiError::ApiError(format!("bad batch id {:?}", id)))
+    }
+}
+
+/// Send one request and return the body of a successful response.
+fn exchange(net: &mut NetStack, config: &ClaudeConfig, method_path: &str, body: &str) -> Result<String, ApiError> {
+    check_config(config)?;
+    let request = api_request(config, method_path, "application/json", body);
+    let deadline = config.attempt_deadline();
+    let raw = if config.use_tls {
+        http::exchange_tls(net, config.target_ip, config.target_port, &config.host, request.as_bytes(), MAX_RESPONSE, deadline)?
+    } else {
+        http::exchange_plain(net, config.target_ip, config.target_port, request.as_bytes(), MAX_RESPONSE, deadline)?
+    };
+    let resp = http::HttpResponse::parse(&raw).map_err(|_| ApiError::EmptyResponse)?;
+    let body = resp.body(&raw)?;
+    let text = String::from_utf8_lossy(&body).into_owned();
+    if let Some(ApiError::RateLimited(limits)) = check_status(&resp) {
+        return Err(ApiError::RateLimited(limits));
+    }
+    if let Some(msg) = resp.error_message() {
+        // Prefer the API's own message
+        let detail = json::parse(text.trim()).ok().and_then(|v| {
+            v.get("error").and_then(|e| e.get("message")).and_then(|m| m.as_str()).map(String::from)
+        });
+        return Err(ApiError::HttpStatus(resp.status, detail.unwrap_or_else(|| String::from(msg)), resp.retry_after_secs()));
+    }
+    Ok(text)
+}
@@ -0,0 +1,159 @@
+/// Text embeddings from a configurable provider.
+///
+/// Anthropic has no embeddings endpoint, so the provider is configured
+/// separately with config keys:
+///
+///   embed.url     endpoint, e.g. https://api.voyageai.com/v1/embeddings
+///                 or http://10.0.2.2:11434/api/embed (required)
+///   embed.model   model name sent in the request (required)
+///   embed.key     bearer token, if the provider wants one
+///
+/// The request is the common `{"model": ..., "input": ...}` shape; the
+/// vector is read from `data[0].embedding` (OpenAI, Voyage),
+/// `embeddings[0]` (Ollama /api/embed) or `embedding`.
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use smoltcp::wire::IpAddress;
+
+use super::{escape_json, http, json, ApiError};
+use crate::crypto::zeroize::Zeroizing;
+use crate::net::NetStack;
+
+/// Largest response accepted (a 3072-dim vector as JSON is ~70 KiB).
+const MAX_RESPONSE: usize = 512 * 1024;
+
+/// Where embeddings come from, read from config.
+pub struct Provider {
+    pub endpoint: Endpoint,
+    pub model: String,
+    /// Wiped when the provider drops.
+    pub key: Option<Zeroizing<String>>,
+}
+
+impl Provider {
+    /// Read `embed.url`, `embed.model` and `embed.key`. Takes the DB lock.
+    pub fn from_config() -> Result<Self, String> {
+        let url = crate::sqlite::config_get("embed.url")
+            .ok_or_else(|| String::from("embed.url not set (config set embed.url https://...)"))?;
+        let model = crate::sqlite::config_get("embed.model")
+            .ok_or_else(|| String::from("embed.model not set"))?;
+        let key = crate::sqlite::config_get("embed.key").filter(|k| !k.is_empty()).map(Zeroizing::new);
+        if [&url, &model].into_iter().chain(key.as_deref()).any(|v| v.contains(['\r', '\n'])) {
+            return Err(String::from("embed.*: line breaks not allowed"));
+        }
+        Ok(Provider { endpoint: Endpoint::parse(url.trim())?, model: String::from(model.trim()), key })
+    }
+}
+
+/// A parsed `http://` or `https://` URL.
+pub struct Endpoint {
+    pub tls: bool,
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+
+impl Endpoint {
+    pub fn parse(url: &str) -> Result<Self, String> {
+        let (tls, rest) = if let Some(rest) = url.strip_prefix("https://") {
+            (true, rest)
+        } else if let Some(rest) = url.strip_prefix("http://") {
+            (false, rest)
+        } else {
+            return Err(format!("{}: expected http:// or https://", url));
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            // "[2001:db8::1]" has colons but no port
+            Some((host, port)) if !authority.ends_with(']') => {
+                (host, port.parse().map_err(|_| format!("{}: bad port", url))?)
+            }
+            _ => (authority, if tls { 443 } else { 80 }),
+        };
+        if host.is_empty() {
+            return Err(format!("{}: no host", url));
+        }
+        Ok(Endpoint { tls, host: String::from(host), port, path: String::from(path) })
+    }
+
+    /// The host as an address literal (IPv6 in brackets), if it is one.
+    fn literal_ip(&self) -> Option<IpAddress> {
+        self.host.trim_start_matches('[').trim_end_matches(']').parse().ok()
+    }
+}
+
+/// Embed `text` with the configured provider.
+pub fn embed(net: &mut NetStack, provider: &Provider, text: &str) -> Result<Vec<f32>, ApiError> {
+    let ep = &provider.endpoint;
+    let ip = match ep.literal_ip() {
+        Some(ip) => ip,
+        None => crate::net::dns::resolve(net, &ep.host).map_err(|e| ApiError::DnsError(format!("{}", e)))?,
+    };
+
+    let body = format!(r#"{{"model":"{}","input":"{}"}}"#, escape_json(&provider.model), escape_json(text));
+    // Built at its final size so the bearer token leaves no stray copies
+    let head = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nAccept-Encoding: gzip\r\n",
+        ep.path, ep.host
+    );
+    let tail = format!("Content-Length: {}\r\nConnection: close\r\n\r\n", body.len());
+    let key = provider.key.as_deref().map_or("", String::as_str);
+    let mut request = Zeroizing::new(String::with_capacity(head.len() + key.len() + 32 + tail.len() + body.len()));
+    request.push_str(&head);
+    if provider.key.is_some() {
+        request.push_str("Authorization: Bearer ");
+        request.push_str(key);
+        request.push_str("\r\n");
+    }
+    request.push_str(&tail);
+    request.push_str(&body);
+
+    let deadline = crate::time::monotonic_ms() + super::REQUEST_TIMEOUT_SECS * 1000;
+    let raw = if ep.tls {
+        http::exchange_tls(net, ip, ep.port, &ep.host, request.as_bytes(), MAX_RESPONSE, deadline)?
+    } else {
+        http::exchange_plain(net, ip, ep.port, request.as_bytes(), MAX_RESPONSE, deadline)?
+    };
+    parse_response(&raw)
+}
+
+/// Extract the vector from a complete HTTP response.
+fn parse_response(raw: &[u8]) -> Result<Vec<f32>, ApiError> {
+    let resp = http::HttpResponse::parse(raw).map_err(|_| ApiError::EmptyResponse)?;
+    let body = resp.body(raw)?;
+    let text = String::from_utf8_lossy(&body);
+    let parsed = json::parse(&text);
+
+    if let Some(msg) = resp.error_message() {
+        // Prefer the provider's own message
+        let detail = parsed.as_ref().ok().and_then(|v| {
+            let err = v.get("error")?;
+            err.get("message").and_then(|m| m.as_str()).or_else(|| err.as_str()).map(String::from)
+        });
+        return Err(ApiError::HttpStatus(resp.status, detail.unwrap_or_else(|| String::from(msg)), resp.retry_after_secs()));
+    }
+
+    let parsed = parsed.map_err(|e| ApiError::ApiError(format!("embedding response: {}", e)))?;
+    let first = |v: Option<&json::JsonValue>| v.and_then(|a| a.as_array()).and_then(|a| a.first()).cloned();
+    let vector = first(parsed.get("data"))
+        .and_then(|d| d.get("embedding").cloned())
+        .or_else(|| first(parsed.get("embeddings")))
+        .or_else(|| parsed.get("embedding").cloned())
+        .ok_or_else(|| ApiError::ApiError(String::from("no embedding in response")))?;
+    let numbers = vector
+        .as_array()
+        .ok_or_else(|| ApiError::ApiError(String::from("embedding is not an array")))?;
+    let mut out = Vec::with_capacity(numbers.len());
+    for n in numbers {
+        out.push(n.as_number().ok_or_else(|| ApiError::ApiError(String::from("non-numeric embedding")))? as f32);
+    }
+    if out.is_empty() {
+        return Err(ApiError::ApiError(String::from("empty embedding")));
+    }
+    Ok(out)
+}
@@ -1,11 +1,20 @@
 /// HTTP/1.1 response parser.
 ///
 /// Parses the status line and headers from raw HTTP response data.
-/// Handles chunked detection, content-type checking, and error classification.
+/// Handles chunked detection and decoding, gzip/deflate content encoding
+/// (`inflate`), and error classification. `exchange_plain` and
+/// `exchange_tls` send a request and read the whole response, for callers
+/// that don't stream.
 
 use alloc::string::String;
 use alloc::vec::Vec;
 
+use smoltcp::wire::IpAddress;
+
+use super::ApiError;
+use crate::net::stats::{set_phase, Phase};
+use crate::net::NetStack;
+
 /// Parsed HTTP response headers.
 pub struct HttpResponse {
     /// HTTP status code (200, 401, 429, 500, etc.).
@@ -16,6 +25,21 @@ pub struct HttpResponse {
     pub body_start: usize,
 }
 
+/// A `Content-Encoding` the body can be decoded from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Identity,
+    Gzip,
+    Deflate,
+}
+
+/// Most a compressed body may inflate to.
+pub const MAX_DECODED: usize = 8 * 1024 * 1024;
+
+/// Silence from the server after which `exchange_plain` gives up, however
+/// far off the deadline is (the TLS stream's idle limit).
+const READ_IDLE_MS: u64 = 30_000;
+
 /// HTTP response parsing error.
 #[derive(Debug)]
 pub enum HttpParseError {
@@ -95,6 +119,55 @@ impl HttpResponse {
         self.header("retry-after")
             .and_then(|v| v.parse::<u64>().ok())
     }
+
+    /// Is the body sent with `Transfer-Encoding: chunked`?
+    pub fn is_chunked(&self) -> bool {
+        self.header("transfer-encoding").is_some_and(|v| v.eq_ignore_ascii_case("chunked"))
+    }
+
+    /// The body's `Content-Encoding`. Requests only ever offer gzip, so
+    /// anything else (br, zstd, ...) is an error rather than garbage.
+    pub fn encoding(&self) -> Result<Encoding, ApiError> {
+        match self.header("content-encoding").map(str::trim) {
+            None | Some("") => Ok(Encoding::Identity),
+            Some(v) if v.eq_ignore_ascii_case("identity") => Ok(Encoding::Identity),
+            Some(v) if v.eq_ignore_ascii_case("gzip") || v.eq_ignore_ascii_case("x-gzip") => Ok(Encoding::Gzip),
+            Some(v) if v.eq_ignore_ascii_case("deflate") => Ok(Encoding::Deflate),
+            Some(v) => Err(ApiError::ApiError(alloc::format!("unsupported content encoding {:?}", v))),
+        }
+    }
+
+    /// The decoded body of complete response `raw`.
+    pub fn body(&self, raw: &[u8]) -> Result<Vec<u8>, ApiError> {
+        decode_body(raw.get(self.body_start..).unwrap_or(&[]), self.is_chunked(), self.encoding()?)
+    }
+}
+
+/// Undo the transfer and then the content encoding of a complete body.
+pub fn decode_body(body: &[u8], chunked: bool, encoding: Encoding) -> Result<Vec<u8>, ApiError> {
+    let body = if chunked { dechunk(body) } else { body.to_vec() };
+    let decoded = match encoding {
+        Encoding::Identity => return Ok(body),
+        Encoding::Gzip => super::inflate::gunzip(&body, MAX_DECODED),
+        Encoding::Deflate => super::inflate::zlib_or_raw(&body, MAX_DECODED),
+    };
+    decoded.map_err(|e| ApiError::ApiError(alloc::format!("response body: {}", e)))
+}
+
+/// Decode a `Transfer-Encoding: chunked` body (trailers ignored).
+pub fn dechunk(body: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut rest = body;
+    while let Some(eol) = rest.windows(2).position(|w| w == b"\r\n") {
+        let size_field = core::str::from_utf8(&rest[..eol]).unwrap_or("");
+        let size = usize::from_str_radix(size_field.split(';').next().unwrap_or("").trim(), 16).unwrap_or(0);
+        if size == 0 || rest.len() < eol + 2 + size {
+            break;
+        }
+        out.extend_from_slice(&rest[eol + 2..eol + 2 + size]);
+        rest = rest.get(eol + 2 + size + 2..).unwrap_or(&[]);
+    }
+    out
 }
 
 /// Find the position of "\r\n\r\n" which separates headers from body.
@@ -136,6 +209,120 @@ impl ToAsciiLowercase for str {
     }
 }
 
+/// Send `request` over plain TCP and read until the server closes, failing
+/// past `max` bytes, or with `RequestTimeout` once `monotonic_ms` reaches
+/// `deadline_ms` before the response is complete or the server has sent
+/// nothing for `READ_IDLE_MS`.
+pub fn exchange_plain(
+    net: &mut NetStack,
+    ip: IpAddress,
+    port: u16,
+    request: &[u8],
+    max: usize,
+    deadline_ms: u64,
+) -> Result<Vec<u8>, ApiError> {
+    let remaining = || deadline_ms.saturating_sub(crate::time::monotonic_ms());
+    let handle = net.tcp_connect(ip, port).ok_or(ApiError::ConnectionFailed)?;
+    if !net.poll_until(|n| n.tcp_can_send(handle), remaining().min(10_000)) {
+        net.tcp_close(handle);
+        return Err(if remaining() == 0 { ApiError::RequestTimeout } else { ApiError::ConnectionTimeout });
+    }
+
+    set_phase(handle, Phase::Request);
+    let mut sent = 0;
+    while sent < request.len() {
+        if remaining() == 0 {
+            net.tcp_close(handle);
+            return Err(ApiError::RequestTimeout);
+        }
+        net.poll();
+        if net.tcp_can_send(handle) {
+            sent += net.tcp_send(handle, &request[sent..]);
+        } else {
+            crate::arch::x86_64::idle::wait();
+        }
+    }
+    set_phase(handle, Phase::Response);
+
+    let mut raw = Vec::new();
+    let mut buf = [0u8; 4096];
+    let mut heard_ms = crate::time::monotonic_ms();
+    loop {
+        net.poll();
+        if net.tcp_can_recv(handle) {
+            let n = net.tcp_recv(handle, &mut buf);
+            raw.extend_from_slice(&buf[..n]);
+            heard_ms = crate::time::monotonic_ms();
+            if raw.len() > max {
+                net.tcp_close(handle);
+                return Err(ApiError::ApiError(alloc::format!("response over {} bytes", max)));
+            }
+        }
+        if !net.tcp_is_active(handle) && !net.tcp_can_recv(handle) {
+            break;
+        }
+        if remaining() == 0 || crate::time::monotonic_ms() - heard_ms >= READ_IDLE_MS {
+            net.tcp_close(handle);
+            return Err(ApiError::RequestTimeout);
+        }
+        if !net.tcp_can_recv(handle) {
+            crate::arch::x86_64::idle::wait();
+        }
+    }
+    net.tcp_close(handle);
+    if raw.is_empty() {
+        return Err(ApiError::EmptyResponse);
+    }
+    Ok(raw)
+}
+
+/// Send `request` over TLS to `server_name` and read until close_notify
+/// or EOF, failing past `max` bytes, or with `RequestTimeout` once
+/// `monotonic_ms` reaches `deadline_ms` first.
+pub fn exchange_tls(
+    net: &mut NetStack,
+    ip: IpAddress,
+    port: u16,
+    server_name: &str,
+    request: &[u8],
+    max: usize,
+    deadline_ms: u64,
+) -> Result<Vec<u8>, ApiError> {
+    use crate::net::tls::{TlsBuffers, TlsClient};
+
+    let mut bufs = TlsBuffers::take();
+    let mut tls = TlsClient::new(server_name).with_deadline(deadline_ms).connect(net, ip, port, &mut bufs)?;
+
+    set_phase(tls.handle(), Phase::Request);
+    tls.send(request).map_err(|_| ApiError::SendFailed)?;
+    set_phase(tls.handle(), Phase::Response);
+
+    let mut raw = Vec::new();
+    let mut buf = [0u8; 4096];
+    loop {
+        match tls.read(&mut buf) {
+            Ok(0) => break,
+            Err(_) if crate::time::monotonic_ms() >= deadline_ms => {
+                tls.close();
+                return Err(ApiError::RequestTimeout);
+            }
+            Err(_) => break,
+            Ok(n) => {
+                raw.extend_from_slice(&buf[..n]);
+                if raw.len() > max {
+                    tls.close();
+                    return Err(ApiError::ApiError(alloc::format!("response over {} bytes", max)));
+                }
+            }
+        }
+    }
+    tls.close();
+    if raw.is_empty() {
+        return Err(ApiError::EmptyResponse);
+    }
+    Ok(raw)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
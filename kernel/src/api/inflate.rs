@@ -0,0 +1,341 @@
+/// DEFLATE decompression (RFC 1951) and its gzip (RFC 1952) and zlib
+/// (RFC 1950) wrappers, for HTTP bodies sent with `Content-Encoding: gzip`
+/// or `deflate`. Written for this one use, after zlib's `puff.c`: one bit
+/// at a time and canonical Huffman tables, so it is small rather than fast
+/// — bodies here are API responses, not archives.
+///
+/// Checksums are verified (CRC-32 for gzip, Adler-32 for zlib) and the
+/// output is capped at `max` bytes, so a small body can't expand without
+/// bound.
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+const MAX_BITS: usize = 15;
+
+/// Length codes 257..285: base length and extra bits.
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+
+/// Distance codes 0..29: base distance and extra bits.
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097,
+    6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+
+/// Order of the code length code lengths in a dynamic block header.
+const CLEN_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+/// Decode a gzip body: one or more members, each checked against its
+/// CRC-32 and length.
+pub fn gunzip(data: &[u8], max: usize) -> Result<Vec<u8>, String> {
+    let mut out = Vec::new();
+    let mut rest = data;
+    while !rest.is_empty() {
+        let start = gzip_header(rest)?;
+        let (member, used) = inflate_at(&rest[start..], max.saturating_sub(out.len()))?;
+        let trailer = rest.get(start + used..start + used + 8).ok_or("gzip: truncated trailer")?;
+        let crc = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+        let size = u32::from_le_bytes([trailer[4], trailer[5], trailer[6], trailer[7]]);
+        if crc != crc32(&member) || size != member.len() as u32 {
+            return Err(String::from("gzip: checksum mismatch"));
+        }
+        out.extend_from_slice(&member);
+        rest = &rest[start + used + 8..];
+    }
+    Ok(out)
+}
+
+/// Decode a `Content-Encoding: deflate` body: zlib-wrapped as the spec
+/// says, or raw DEFLATE as some servers send it.
+pub fn zlib_or_raw(data: &[u8], max: usize) -> Result<Vec<u8>, String> {
+    let zlib = data.len() >= 2 && data[0] & 0x0f == 8 && (u16::from(data[0]) << 8 | u16::from(data[1])) % 31 == 0;
+    if !zlib {
+        return inflate_at(data, max).map(|(out, _)| out);
+    }
+    if data[1] & 0x20 != 0 {
+        return Err(String::from("zlib: preset dictionary not supported"));
+    }
+    let (out, used) = inflate_at(&data[2..], max)?;
+    let trailer = data.get(2 + used..2 + used + 4).ok_or("zlib: truncated trailer")?;
+    if u32::from_be_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]) != adler32(&out) {
+        return Err(String::from("zlib: checksum mismatch"));
+    }
+    Ok(out)
+}
+
+/// Decode raw DEFLATE data.
+pub fn inflate(data: &[u8], max: usize) -> Result<Vec<u8>, String> {
+    inflate_at(data, max).map(|(out, _)| out)
+}
+
+/// Length of the gzip member header at the start of `data`.
+fn gzip_header(data: &[u8]) -> Result<usize, String> {
+    const FHCRC: u8 = 0x02;
+    const FEXTRA: u8 = 0x04;
+    const FNAME: u8 = 0x08;
+    const FCOMMENT: u8 = 0x10;
+
+    if data.len() < 10 || data[0] != 0x1f || data[1] != 0x8b {
+        return Err(String::from("gzip: bad magic"));
+    }
+    if data[2] != 8 {
+        return Err(format!("gzip: compression method {}", data[2]));
+    }
+    let flags = data[3];
+    let mut at = 10;
+    if flags & FEXTRA != 0 {
+        let len = data.get(at..at + 2).ok_or("gzip: truncated header")?;
+        at += 2 + usize::from(u16::from_le_bytes([len[0], len[1]]));
+    }
+    for flag in [FNAME, FCOMMENT] {
+        if flags & flag != 0 {
+            let end = data.get(at..).and_then(|d| d.iter().position(|&b| b == 0)).ok_or("gzip: truncated header")?;
+            at += end + 1;
+        }
+    }
+    if flags & FHCRC != 0 {
+        at += 2;
+    }
+    if at > data.len() {
+        return Err(String::from("gzip: truncated header"));
+    }
+    Ok(at)
+}
+
+/// Inflate the DEFLATE stream at the start of `data`. Returns the output
+/// and the bytes of `data` it took (whole bytes: the last is padded).
+fn inflate_at(data: &[u8], max: usize) -> Result<(Vec<u8>, usize), String> {
+    let mut bits = Bits { data, at: 0 };
+    let mut out = Vec::new();
+    loop {
+        let last = bits.take(1)? == 1;
+        match bits.take(2)? {
+            0 => stored(&mut bits, &mut out, max)?,
+            1 => {
+                let (lit, dist) = fixed_tables()?;
+                codes(&mut bits, &mut out, &lit, &dist, max)?;
+            }
+            2 => {
+                let (lit, dist) = dynamic_tables(&mut bits)?;
+                codes(&mut bits, &mut out, &lit, &dist, max)?;
+            }
+            _ => return Err(String::from("deflate: invalid block type")),
+        }
+        if last {
+            return Ok((out, bits.at.div_ceil(8)));
+        }
+    }
+}
+
+/// Reads bits least significant first, as DEFLATE packs them.
+struct Bits<'a> {
+    data: &'a [u8],
+    /// Position in bits.
+    at: usize,
+}
+
+impl Bits<'_> {
+    fn take(&mut self, n: u32) -> Result<u32, String> {
+        let mut value = 0;
+        for i in 0..n {
+            let byte = *self.data.get(self.at / 8).ok_or("deflate: unexpected end of data")?;
+            value |= u32::from((byte >> (self.at % 8)) & 1) << i;
+            self.at += 1;
+        }
+        Ok(value)
+    }
+
+    /// Skip to the next byte boundary.
+    fn align(&mut self) {
+        self.at = self.at.div_ceil(8) * 8;
+    }
+}
+
+/// A canonical Huffman code: how many codes of each length, and the
+/// symbols in code order.
+struct Huffman {
+    counts: [u16; MAX_BITS + 1],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    /// Build from each symbol's code length (0 = unused). An incomplete
+    /// code is accepted (it only fails on the missing codes); an
+    /// over-subscribed one is not.
+    fn new(lengths: &[u8]) -> Result<Self, String> {
+        let mut counts = [0u16; MAX_BITS + 1];
+        for &len in lengths {
+            counts[usize::from(len)] += 1;
+        }
+        let mut left: i32 = 1;
+        for &count in &counts[1..] {
+            left = (left << 1) - i32::from(count);
+            if left < 0 {
+                return Err(String::from("deflate: over-subscribed code"));
+            }
+        }
+        let mut offsets = [0u16; MAX_BITS + 2];
+        for len in 1..=MAX_BITS {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+        let mut symbols = vec![0u16; usize::from(offsets[MAX_BITS + 1])];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbols[usize::from(offsets[usize::from(len)])] = symbol as u16;
+                offsets[usize::from(len)] += 1;
+            }
+        }
+        counts[0] = 0;
+        Ok(Huffman { counts, symbols })
+    }
+
+    fn decode(&self, bits: &mut Bits) -> Result<u16, String> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for &count in &self.counts[1..] {
+            code |= bits.take(1)? as i32;
+            let count = i32::from(count);
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(String::from("deflate: invalid code"))
+    }
+}
+
+fn stored(bits: &mut Bits, out: &mut Vec<u8>, max: usize) -> Result<(), String> {
+    bits.align();
+    let at = bits.at / 8;
+    let header = bits.data.get(at..at + 4).ok_or("deflate: truncated stored block")?;
+    let len = u16::from_le_bytes([header[0], header[1]]);
+    if len != !u16::from_le_bytes([header[2], header[3]]) {
+        return Err(String::from("deflate: stored block length mismatch"));
+    }
+    let len = usize::from(len);
+    let block = bits.data.get(at + 4..at + 4 + len).ok_or("deflate: truncated stored block")?;
+    if out.len() + len > max {
+        return Err(format!("deflate: output over {} bytes", max));
+    }
+    out.extend_from_slice(block);
+    bits.at = (at + 4 + len) * 8;
+    Ok(())
+}
+
+fn fixed_tables() -> Result<(Huffman, Huffman), String> {
+    let mut lengths = [0u8; 288];
+    lengths[..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..].fill(8);
+    Ok((Huffman::new(&lengths)?, Huffman::new(&[5u8; 30])?))
+}
+
+fn dynamic_tables(bits: &mut Bits) -> Result<(Huffman, Huffman), String> {
+    let nlen = bits.take(5)? as usize + 257;
+    let ndist = bits.take(5)? as usize + 1;
+    let ncode = bits.take(4)? as usize + 4;
+    if nlen > 286 || ndist > 30 {
+        return Err(String::from("deflate: bad code counts"));
+    }
+
+    let mut clen = [0u8; 19];
+    for &i in &CLEN_ORDER[..ncode] {
+        clen[i] = bits.take(3)? as u8;
+    }
+    let clen = Huffman::new(&clen)?;
+
+    let mut lengths = vec![0u8; nlen + ndist];
+    let mut i = 0;
+    while i < lengths.len() {
+        let symbol = clen.decode(bits)?;
+        let (value, repeat) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 => {
+                let previous = *lengths[..i].last().ok_or("deflate: repeat with no previous length")?;
+                (previous, 3 + bits.take(2)? as usize)
+            }
+            17 => (0, 3 + bits.take(3)? as usize),
+            _ => (0, 11 + bits.take(7)? as usize),
+        };
+        if i + repeat > lengths.len() {
+            return Err(String::from("deflate: too many lengths"));
+        }
+        lengths[i..i + repeat].fill(value);
+        i += repeat;
+    }
+    if lengths[256] == 0 {
+        return Err(String::from("deflate: no end-of-block code"));
+    }
+    Ok((Huffman::new(&lengths[..nlen])?, Huffman::new(&lengths[nlen..])?))
+}
+
+/// Decode literals and length/distance pairs up to the end-of-block code.
+fn codes(bits: &mut Bits, out: &mut Vec<u8>, lit: &Huffman, dist: &Huffman, max: usize) -> Result<(), String> {
+    loop {
+        let symbol = usize::from(lit.decode(bits)?);
+        if symbol < 256 {
+            if out.len() >= max {
+                return Err(format!("deflate: output over {} bytes", max));
+            }
+            out.push(symbol as u8);
+            continue;
+        }
+        if symbol == 256 {
+            return Ok(());
+        }
+        let code = symbol - 257;
+        if code >= LENGTH_BASE.len() {
+            return Err(String::from("deflate: invalid length code"));
+        }
+        let len = usize::from(LENGTH_BASE[code]) + bits.take(u32::from(LENGTH_EXTRA[code]))? as usize;
+        let code = usize::from(dist.decode(bits)?);
+        if code >= DIST_BASE.len() {
+            return Err(String::from("deflate: invalid distance code"));
+        }
+        let distance = usize::from(DIST_BASE[code]) + bits.take(u32::from(DIST_EXTRA[code]))? as usize;
+        if distance > out.len() {
+            return Err(String::from("deflate: distance too far back"));
+        }
+        if out.len() + len > max {
+            return Err(format!("deflate: output over {} bytes", max));
+        }
+        // Byte by byte: the copy may overlap what it produces
+        let from = out.len() - distance;
+        for k in 0..len {
+            out.push(out[from + k]);
+        }
+    }
+}
+
+/// CRC-32 as gzip uses it (reflected 0xEDB88320).
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    const MOD: u32 = 65521;
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += u32::from(byte);
+            b += a;
+        }
+        a %= MOD;
+        b %= MOD;
+    }
+    (b << 16) | a
+}
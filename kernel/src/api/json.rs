@@ -68,6 +68,49 @@ impl JsonValue {
             _ => None,
         }
     }
+
+    /// Serialize back to compact JSON. Object keys keep their order.
+    pub fn to_json(&self) -> String {
+        let mut out = String::new();
+        self.write_json(&mut out);
+        out
+    }
+
+    fn write_json(&self, out: &mut String) {
+        match self {
+            JsonValue::Null => out.push_str("null"),
+            JsonValue::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+            JsonValue::Number(n) => out.push_str(&alloc::format!("{}", n)),
+            JsonValue::Str(s) => {
+                out.push('"');
+                out.push_str(&super::escape_json(s));
+                out.push('"');
+            }
+            JsonValue::Array(items) => {
+                out.push('[');
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    item.write_json(out);
+                }
+                out.push(']');
+            }
+            JsonValue::Object(fields) => {
+                out.push('{');
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    out.push('"');
+                    out.push_str(&super::escape_json(key));
+                    out.push_str("\":");
+                    value.write_json(out);
+                }
+                out.push('}');
+            }
+        }
+    }
 }
 
 /// Parse a JSON string into a `JsonValue`.
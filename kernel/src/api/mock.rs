@@ -0,0 +1,152 @@
+/// Canned-response provider for offline development (`mockapi` feature).
+///
+/// A kernel built with `--features mockapi` never contacts the API: `ask`,
+/// Lua `ask()`, the outbox and the agent loop are answered from rules
+/// stored as namespace files under `/mock/api/`, so the agent loop and its
+/// tools can be exercised in QEMU with no network. No API key is needed,
+/// nor a network device: `ask`, Lua `ask()`, the agent loop and the outbox
+/// call `respond` before they would take `NET_STACK`, and the API host
+/// "resolves" without a DNS query.
+///
+/// Each rule is a JSON file, tried in path order:
+///
+///   {"match": "weather",
+///    "steps": [
+///      {"text": "Let me look.",
+///       "tool_use": [{"name": "read_file", "input": {"path": "/etc/motd"}}]},
+///      {"text": "It is sunny."}]}
+///
+/// `match` is a case-insensitive substring of the conversation's first
+/// user message ("" or absent matches anything). The step used is the
+/// number of assistant turns already in the conversation, so an agent run
+/// walks through `steps` one turn at a time; a step with `tool_use` stops
+/// with "tool_use", any other with "end_turn". Past the last step, and
+/// when no rule matches, the reply is a short text saying so.
+///
+/// Stop sequences are honoured as the API does: the text ends before the
+/// first one found, with no tool calls, and stops with "stop_sequence".
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use super::json::{self, JsonValue};
+use super::{ApiError, ClaudeResponse, Message, ToolCall, Usage};
+use crate::sqlite::{SqlValue, DB};
+
+/// Namespace directory holding the rules.
+pub const RULES_PREFIX: &str = "/mock/api/";
+
+/// Is this build answering from canned responses?
+pub const fn enabled() -> bool {
+    cfg!(feature = "mockapi")
+}
+
+/// Answer a conversation, calling `on_token` with the reply text.
+pub fn respond<F: Fn(&str)>(
+    messages: &[Message],
+    stop_sequences: &[String],
+    on_token: F,
+) -> Result<ClaudeResponse, ApiError> {
+    let prompt = messages.iter().find(|m| m.role == "user").map_or("", |m| m.content.as_str());
+    let turn = messages.iter().filter(|m| m.role == "assistant").count();
+    let rules = load_rules()?;
+    let step = rules
+        .iter()
+        .find(|(_, rule)| matches(rule, prompt))
+        .map(|(path, rule)| (path, rule.get("steps").and_then(|s| s.as_array()).and_then(|s| s.get(turn))));
+
+    let (mut text, mut tool_calls) = match step {
+        Some((_, Some(step))) => (
+            String::from(step.get("text").and_then(|t| t.as_str()).unwrap_or("")),
+            tool_calls(step, turn)?,
+        ),
+        Some((path, None)) => (format!("[mock] {} has no step {}", path, turn + 1), Vec::new()),
+        None => (format!("[mock] no rule in {} matches this prompt", RULES_PREFIX), Vec::new()),
+    };
+
+    let stop_sequence = stop_sequences
+        .iter()
+        .filter_map(|s| text.find(s.as_str()).map(|at| (at, s)))
+        .min_by_key(|(at, _)| *at)
+        .map(|(at, s)| {
+            text.truncate(at);
+            tool_calls.clear();
+            s.clone()
+        });
+    let stop_reason = match (&stop_sequence, tool_calls.is_empty()) {
+        (Some(_), _) => "stop_sequence",
+        (None, true) => "end_turn",
+        (None, false) => "tool_use",
+    };
+
+    if !text.is_empty() {
+        on_token(&text);
+    }
+    let input_chars: usize = messages.iter().map(|m| m.content.len()).sum();
+    Ok(ClaudeResponse {
+        stop_reason: String::from(stop_reason),
+        stop_sequence,
+        usage: Usage {
+            // About four characters a token, so budgets have something to count
+            input_tokens: input_chars.div_ceil(4) as u64,
+            output_tokens: text.len().div_ceil(4) as u64,
+            ..Usage::default()
+        },
+        text,
+        tool_calls,
+    })
+}
+
+fn matches(rule: &JsonValue, prompt: &str) -> bool {
+    let pattern = rule.get("match").and_then(|m| m.as_str()).unwrap_or("");
+    pattern.is_empty() || prompt.to_lowercase().contains(&pattern.to_lowercase())
+}
+
+/// The step's `tool_use` entries, with ids unique to the turn.
+fn tool_calls(step: &JsonValue, turn: usize) -> Result<Vec<ToolCall>, ApiError> {
+    let Some(calls) = step.get("tool_use") else { return Ok(Vec::new()) };
+    let calls = calls
+        .as_array()
+        .ok_or_else(|| ApiError::ApiError(String::from("mock: tool_use must be an array")))?;
+    calls
+        .iter()
+        .enumerate()
+        .map(|(i, call)| {
+            let name = call
+                .get("name")
+                .and_then(|n| n.as_str())
+                .ok_or_else(|| ApiError::ApiError(String::from("mock: tool_use entry without a name")))?;
+            Ok(ToolCall {
+                id: format!("toolu_mock_{}_{}", turn + 1, i + 1),
+                name: String::from(name),
+                input_json: call.get("input").map_or_else(|| String::from("{}"), |v| v.to_json()),
+            })
+        })
+        .collect()
+}
+
+/// Every rule file as (path, parsed JSON), in path order.
+fn load_rules() -> Result<Vec<(String, JsonValue)>, ApiError> {
+    let rows = {
+        let guard = DB.lock();
+        let db = guard
+            .as_ref()
+            .ok_or_else(|| ApiError::ApiError(String::from("mock: database not open")))?;
+        db.query_params(
+            "SELECT path, unpack(content, compressed) FROM namespace \
+             WHERE substr(path, 1, length(?1)) = ?1 COLLATE NOCASE AND type = 'data' ORDER BY path",
+            &[SqlValue::Text(String::from(RULES_PREFIX))],
+        )
+        .map_err(|e| ApiError::ApiError(format!("mock: {}", e)))?
+        .rows
+    };
+    rows.iter()
+        .filter(|r| r.len() == 2)
+        .map(|r| {
+            let path = String::from(r[0].as_str().unwrap_or(""));
+            let rule = json::parse(r[1].as_str().unwrap_or(""))
+                .map_err(|e| ApiError::ApiError(format!("mock: {}: {}", path, e)))?;
+            Ok((path, rule))
+        })
+        .collect()
+}
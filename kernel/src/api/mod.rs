@@ -8,17 +8,48 @@
 ///
 /// - **Proxy mode** (`use_tls: false`): Plain HTTP to a local socat/nginx proxy
 ///   on the QEMU host that terminates TLS. Fallback for debugging.
+///
+/// Responses stream as SSE by default. With `stream: false` (config
+/// `api.stream off`, or `stream=false` in a Lua `ask()` table) the API
+/// sends the whole message as one JSON body instead.
+///
+/// Requests that don't stream offer `Accept-Encoding: gzip`; compressed
+/// bodies are inflated (`inflate`) before they are parsed.
+///
+/// `api trace on` keeps redacted transcripts of each exchange (`trace`).
+///
+/// Embeddings come from a separately configured provider (`embed`).
+///
+/// Rate limits reported by the API are kept, shown by `api status`, and
+/// slow requests down before they run out (`ratelimit`).
+///
+/// Queued requests that can wait a day go through the Message Batches API
+/// at half price (`batch`).
+///
+/// Kernels built with the `mockapi` feature answer every request from
+/// canned responses in the namespace instead (`mock`).
+pub mod batch;
+pub mod embed;
 pub mod http;
+pub mod inflate;
 pub mod json;
+pub mod mock;
+pub mod ratelimit;
 pub mod tools;
+pub mod trace;
 
+use alloc::boxed::Box;
 use alloc::format;
 use alloc::string::{String, ToString};
 use alloc::vec;
 use alloc::vec::Vec;
 
+use crate::crypto::zeroize::Zeroizing;
+use crate::error::{KernelError, Layer};
+use crate::net::stats::{set_phase, Phase};
+use crate::net::tls::{ConnectError, TlsBuffers, TlsClient};
 use crate::net::NetStack;
-use smoltcp::wire::Ipv4Address;
+use smoltcp::wire::{IpAddress, Ipv4Address};
 
 /// Whether to enforce SPKI pinning. Currently disabled because embedded-tls 0.18
 /// marks CertificateRef.entries as pub(crate), preventing external certificate
@@ -26,11 +57,26 @@ use smoltcp::wire::Ipv4Address;
 /// infrastructure that's ready for when this limitation is resolved.
 pub const ENFORCE_PINNING: bool = false;
 
+/// The Anthropic API server.
+pub const API_HOST: &str = "api.anthropic.com";
+
 // ---- Retry configuration ----
 
 const MAX_RETRIES: u32 = 3;
 const BASE_DELAY_MS: u64 = 1000;
 
+/// Retries one command (one `span`) may make across all its requests, so
+/// an agent run against a failing API gives up rather than retrying
+/// every turn.
+pub const RETRY_BUDGET: u32 = 6;
+
+/// Longest one attempt may take, connect to last byte, when `api.timeout`
+/// is unset.
+pub const REQUEST_TIMEOUT_SECS: u64 = 300;
+
+/// Smallest `api.timeout` accepted.
+pub const MIN_REQUEST_TIMEOUT_SECS: u64 = 10;
+
 // ---- Types ----
 
 /// A single message in a conversation.
@@ -104,8 +150,58 @@ pub struct ToolCall {
 pub struct ClaudeResponse {
     pub text: String,
     pub tool_calls: Vec<ToolCall>,
-    /// "end_turn" or "tool_use" — indicates why the model stopped.
+    /// Why the model stopped: "end_turn", "tool_use", or early —
+    /// "stop_sequence" or "max_tokens", with `text` cut short; or
+    /// "interrupted" when Ctrl-C ended the response.
     pub stop_reason: String,
+    /// The stop sequence that ended the text, when `stop_reason` is
+    /// "stop_sequence". The sequence itself is not in `text`.
+    pub stop_sequence: Option<String>,
+    pub usage: Usage,
+}
+
+/// Tokens billed for a request, as the API reports them. With prompt
+/// caching, `input_tokens` counts only the prompt after the last cache
+/// breakpoint; the cached prefix is in the `cache_*` counts.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Usage {
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    /// Prompt tokens written to the cache (`cache_creation_input_tokens`).
+    pub cache_write_tokens: u64,
+    /// Prompt tokens read from the cache (`cache_read_input_tokens`).
+    pub cache_read_tokens: u64,
+}
+
+impl Usage {
+    pub fn total(&self) -> u64 {
+        self.prompt_tokens() + self.output_tokens
+    }
+
+    /// The whole prompt, cached or not.
+    pub fn prompt_tokens(&self) -> u64 {
+        self.input_tokens + self.cache_write_tokens + self.cache_read_tokens
+    }
+
+    /// Take the counts present in a `usage` object. Streams report input
+    /// tokens in message_start and the running output count in
+    /// message_delta, so fields missing here keep their value.
+    fn update(&mut self, usage: Option<&json::JsonValue>) {
+        let Some(usage) = usage else { return };
+        let count = |key| usage.get(key).and_then(|v| v.as_i64()).map(|n| n.max(0) as u64);
+        if let Some(n) = count("input_tokens") {
+            self.input_tokens = n;
+        }
+        if let Some(n) = count("output_tokens") {
+            self.output_tokens = n;
+        }
+        if let Some(n) = count("cache_creation_input_tokens") {
+            self.cache_write_tokens = n;
+        }
+        if let Some(n) = count("cache_read_input_tokens") {
+            self.cache_read_tokens = n;
+        }
+    }
 }
 
 /// Full request parameters for the Claude API.
@@ -115,43 +211,84 @@ pub struct ClaudeRequest {
     pub messages: Vec<Message>,
     /// Whether to include tool definitions in the request.
     pub use_tools: bool,
+    /// Strings that end the response where the model generates them
+    /// (`stop_sequences`); empty sends none.
+    pub stop_sequences: Vec<String>,
 }
 
 /// Claude API configuration.
 pub struct ClaudeConfig {
-    /// API key (sk-ant-...).
-    pub api_key: String,
-    /// Target IP address.
+    /// API key (sk-ant-...). Wiped when the config drops.
+    pub api_key: Zeroizing<String>,
+    /// Server name: the Host header, and SNI and certificate name in TLS mode.
+    pub host: String,
+    /// Target IP address, either family.
     /// TLS mode: IP of api.anthropic.com (resolved via DNS or manually).
     /// Proxy mode: QEMU host (10.0.2.2).
-    pub target_ip: Ipv4Address,
+    pub target_ip: IpAddress,
     pub target_port: u16,
     /// Model to use.
     pub model: String,
     /// Whether to use TLS (direct HTTPS) or plain HTTP (proxy mode).
     pub use_tls: bool,
+    /// Ask for an SSE stream, or for the whole message as one JSON body
+    /// (for proxies that buffer or mangle SSE). See `stream_default`.
+    pub stream: bool,
+    /// Seconds an attempt may run in all before it fails with
+    /// `RequestTimeout` and is retried. See `timeout_default`.
+    pub timeout_secs: u64,
+    /// Polled while waiting on the network or to retry; true ends the
+    /// request with what has arrived, or `Interrupted`. The shell passes
+    /// its Ctrl-C check.
+    pub abort: Option<fn() -> bool>,
+    /// Run with the stack while waiting on the network or to retry, before
+    /// `abort` is polled. The agent loop serves its ctl file from it.
+    pub on_wait: Option<fn(&mut NetStack)>,
+    /// `monotonic_ms` past which no retry starts and a running attempt
+    /// fails with `RequestTimeout`, for callers with a time budget across
+    /// all attempts. None: only `timeout_secs` bounds each one.
+    pub deadline_ms: Option<u64>,
 }
 
 impl ClaudeConfig {
+    /// When an attempt starting now must be done: `timeout_secs` from
+    /// now, or `deadline_ms` if that comes first.
+    pub fn attempt_deadline(&self) -> u64 {
+        let own = crate::time::monotonic_ms() + self.timeout_secs * 1000;
+        self.deadline_ms.map_or(own, |d| d.min(own))
+    }
+
     /// Default config for QEMU with a local TLS-terminating proxy on port 8080.
     pub fn default_proxy() -> Self {
         Self {
-            api_key: String::from(""),
-            target_ip: Ipv4Address::new(10, 0, 2, 2),
+            api_key: Zeroizing::default(),
+            host: String::from(API_HOST),
+            target_ip: IpAddress::Ipv4(Ipv4Address::new(10, 0, 2, 2)),
             target_port: 8080,
             model: String::from("claude-sonnet-4-6-20250514"),
             use_tls: false,
+            stream: true,
+            timeout_secs: REQUEST_TIMEOUT_SECS,
+            abort: None,
+            on_wait: None,
+            deadline_ms: None,
         }
     }
 
     /// Config for direct HTTPS to api.anthropic.com via QEMU NAT.
-    pub fn direct_tls(target_ip: Ipv4Address) -> Self {
+    pub fn direct_tls(target_ip: IpAddress) -> Self {
         Self {
-            api_key: String::from(""),
+            api_key: Zeroizing::default(),
+            host: String::from(API_HOST),
             target_ip,
             target_port: 443,
             model: String::from("claude-sonnet-4-6-20250514"),
             use_tls: true,
+            stream: true,
+            timeout_secs: REQUEST_TIMEOUT_SECS,
+            abort: None,
+            on_wait: None,
+            deadline_ms: None,
         }
     }
 }
@@ -159,9 +296,9 @@ impl ClaudeConfig {
 // ---- Request building ----
 
 /// Build the HTTP request for a single-turn prompt (backward compat).
-fn build_http_request(config: &ClaudeConfig, prompt: &str) -> Result<String, ApiError> {
+fn build_http_request(config: &ClaudeConfig, prompt: &str) -> Result<Zeroizing<String>, ApiError> {
     let messages = vec![Message::text("user", String::from(prompt))];
-    build_http_request_multi(config, None, &messages, false)
+    build_http_request_multi(config, None, &messages, false, &[])
 }
 
 /// Build the HTTP request for a multi-turn conversation with optional system prompt.
@@ -170,44 +307,94 @@ fn build_http_request_multi(
     system: Option<&str>,
     messages: &[Message],
     use_tools: bool,
-) -> Result<String, ApiError> {
-    // Validate inputs — reject CRLF to prevent header injection
+    stop_sequences: &[String],
+) -> Result<Zeroizing<String>, ApiError> {
+    check_config(config)?;
+    let body = message_params(&config.model, Some(config.stream), system, messages, use_tools, stop_sequences);
+    let accept = if config.stream { "text/event-stream" } else { "application/json" };
+    Ok(api_request(config, "POST /v1/messages", accept, &body))
+}
+
+/// Validate inputs — reject CRLF to prevent header injection.
+fn check_config(config: &ClaudeConfig) -> Result<(), ApiError> {
     if config.model.contains('\r') || config.model.contains('\n') {
         return Err(ApiError::SendFailed);
     }
     if config.api_key.contains('\r') || config.api_key.contains('\n') {
         return Err(ApiError::SendFailed);
     }
+    if config.host.contains('\r') || config.host.contains('\n') {
+        return Err(ApiError::SendFailed);
+    }
+    Ok(())
+}
+
+/// An HTTP request to the API: `method_path` ("POST /v1/messages"), the
+/// key and version headers, then `body`. The caller has run
+/// `check_config`.
+fn api_request(config: &ClaudeConfig, method_path: &str, accept: &str, body: &str) -> Zeroizing<String> {
+    // Compression would hold back a stream's events until a block fills
+    let encoding = if accept == "text/event-stream" { "" } else { "\r\nAccept-Encoding: gzip" };
+    // Tie the API's logs to ours (`crate::span`)
+    let request_id = crate::span::current().map_or_else(String::new, |id| format!("\r\nX-Request-Id: {}", id));
+
+    // Sized up front: the request carries the key, so it must not leave
+    // copies behind in blocks outgrown while it is built
+    let length = format!("{}", body.len());
+    let mut request = Zeroizing::new(String::with_capacity(
+        256 + method_path.len() + config.host.len() + config.api_key.len() + request_id.len() + body.len(),
+    ));
+    for part in [
+        method_path,
+        " HTTP/1.1\r\nHost: ",
+        config.host.as_str(),
+        "\r\nContent-Type: application/json\r\nX-API-Key: ",
+        config.api_key.as_str(),
+        "\r\nAnthropic-Version: 2023-06-01\r\nAccept: ",
+        accept,
+        encoding,
+        &request_id,
+        "\r\nContent-Length: ",
+        &length,
+        "\r\nConnection: close\r\n\r\n",
+        body,
+    ] {
+        request.push_str(part);
+    }
+    request
+}
 
-    // Build messages JSON array
-    let mut msgs_json = String::from("[");
+/// `messages` as the Messages API takes them: a JSON array, content
+/// blocks and all. `parse_messages` reads it back.
+pub fn messages_json(messages: &[Message]) -> String {
+    let mut out = String::from("[");
     for (i, msg) in messages.iter().enumerate() {
         if i > 0 {
-            msgs_json.push(',');
+            out.push(',');
         }
         if msg.content_blocks.is_empty() {
             // Simple text message
-            msgs_json.push_str(&format!(
+            out.push_str(&format!(
                 r#"{{"role":"{}","content":"{}"}}"#,
                 escape_json(msg.role),
                 escape_json(&msg.content),
             ));
         } else {
             // Structured content blocks
-            msgs_json.push_str(&format!(r#"{{"role":"{}","content":["#, escape_json(msg.role)));
+            out.push_str(&format!(r#"{{"role":"{}","content":["#, escape_json(msg.role)));
             for (j, block) in msg.content_blocks.iter().enumerate() {
                 if j > 0 {
-                    msgs_json.push(',');
+                    out.push(',');
                 }
                 match block {
                     ContentBlock::Text(text) => {
-                        msgs_json.push_str(&format!(
+                        out.push_str(&format!(
                             r#"{{"type":"text","text":"{}"}}"#,
                             escape_json(text),
                         ));
                     }
                     ContentBlock::ToolUse { id, name, input_json } => {
-                        msgs_json.push_str(&format!(
+                        out.push_str(&format!(
                             r#"{{"type":"tool_use","id":"{}","name":"{}","input":{}}}"#,
                             escape_json(id),
                             escape_json(name),
@@ -216,13 +403,13 @@ fn build_http_request_multi(
                     }
                     ContentBlock::ToolResult { tool_use_id, content, is_error } => {
                         if *is_error {
-                            msgs_json.push_str(&format!(
+                            out.push_str(&format!(
                                 r#"{{"type":"tool_result","tool_use_id":"{}","is_error":true,"content":"{}"}}"#,
                                 escape_json(tool_use_id),
                                 escape_json(content),
                             ));
                         } else {
-                            msgs_json.push_str(&format!(
+                            out.push_str(&format!(
                                 r#"{{"type":"tool_result","tool_use_id":"{}","content":"{}"}}"#,
                                 escape_json(tool_use_id),
                                 escape_json(content),
@@ -231,50 +418,95 @@ fn build_http_request_multi(
                     }
                 }
             }
-            msgs_json.push_str("]}");
+            out.push_str("]}");
         }
     }
-    msgs_json.push(']');
+    out.push(']');
+    out
+}
 
-    // Build body
-    let tools_part = if use_tools {
-        format!(r#","tools":{}"#, tools::tools_json())
+/// The messages of a `messages_json` array. Content is a string or a list
+/// of text, tool_use and tool_result blocks; anything else is skipped.
+pub fn parse_messages(value: &json::JsonValue) -> Vec<Message> {
+    let text = |v: &json::JsonValue, name| v.get(name).and_then(|v| v.as_str()).map(String::from).unwrap_or_default();
+    let mut messages = Vec::new();
+    for m in value.as_array().unwrap_or(&[]) {
+        let role = if m.get("role").and_then(|v| v.as_str()) == Some("assistant") { "assistant" } else { "user" };
+        let Some(blocks) = m.get("content").and_then(|c| c.as_array()) else {
+            messages.push(Message::text(role, text(m, "content")));
+            continue;
+        };
+        let content_blocks = blocks
+            .iter()
+            .filter_map(|b| match b.get("type").and_then(|v| v.as_str())? {
+                "text" => Some(ContentBlock::Text(text(b, "text"))),
+                "tool_use" => Some(ContentBlock::ToolUse {
+                    id: text(b, "id"),
+                    name: text(b, "name"),
+                    input_json: b.get("input").map_or_else(|| String::from("{}"), |v| v.to_json()),
+                }),
+                "tool_result" => Some(ContentBlock::ToolResult {
+                    tool_use_id: text(b, "tool_use_id"),
+                    content: text(b, "content"),
+                    is_error: b.get("is_error").and_then(|v| v.as_bool()).unwrap_or(false),
+                }),
+                _ => None,
+            })
+            .collect();
+        messages.push(Message { role, content: String::new(), content_blocks });
+    }
+    messages
+}
+
+/// The Messages API parameters as a JSON object: everything but the
+/// transport. `stream` None leaves the field out, as a batch request must.
+fn message_params(
+    model: &str,
+    stream: Option<bool>,
+    system: Option<&str>,
+    messages: &[Message],
+    use_tools: bool,
+    stop_sequences: &[String],
+) -> String {
+    let msgs_json = messages_json(messages);
+
+    // Build body. A tool-using request is one turn of an agent loop, which
+    // resends the same tools and system prompt every turn: mark both as
+    // cache breakpoints so later turns read that prefix from the server's
+    // prompt cache. The tools come first in the prefix and get their own
+    // breakpoint, so they stay cached when the system prompt changes.
+    // One-shot requests aren't marked, as a cache write costs extra.
+    let mut tools_part = if use_tools {
+        format!(r#","tools":{}"#, tools::tools_json(true))
     } else {
         String::new()
     };
+    if !stop_sequences.is_empty() {
+        let quoted: Vec<String> = stop_sequences.iter().map(|s| format!(r#""{}""#, escape_json(s))).collect();
+        tools_part.push_str(&format!(r#","stop_sequences":[{}]"#, quoted.join(",")));
+    }
 
-    let body = if let Some(sys) = system {
+    let stream_part = stream.map_or_else(String::new, |stream| format!(r#","stream":{}"#, stream));
+    if let Some(sys) = system {
+        let cache = if use_tools { r#","cache_control":{"type":"ephemeral"}"# } else { "" };
         format!(
-            r#"{{"model":"{}","max_tokens":4096,"stream":true,"system":"{}","messages":{}{}}}"#,
-            escape_json(&config.model),
+            r#"{{"model":"{}","max_tokens":4096{},"system":[{{"type":"text","text":"{}"{}}}],"messages":{}{}}}"#,
+            escape_json(model),
+            stream_part,
             escape_json(sys),
+            cache,
             msgs_json,
             tools_part,
         )
     } else {
         format!(
-            r#"{{"model":"{}","max_tokens":4096,"stream":true,"messages":{}{}}}"#,
-            escape_json(&config.model),
+            r#"{{"model":"{}","max_tokens":4096{},"messages":{}{}}}"#,
+            escape_json(model),
+            stream_part,
             msgs_json,
             tools_part,
         )
-    };
-
-    Ok(format!(
-        "POST /v1/messages HTTP/1.1\r\n\
-         Host: api.anthropic.com\r\n\
-         Content-Type: application/json\r\n\
-         X-API-Key: {}\r\n\
-         Anthropic-Version: 2023-06-01\r\n\
-         Accept: text/event-stream\r\n\
-         Content-Length: {}\r\n\
-         Connection: close\r\n\
-         \r\n\
-         {}",
-        config.api_key,
-        body.len(),
-        body,
-    ))
+    }
 }
 
 // ---- Public API ----
@@ -292,6 +524,10 @@ pub fn claude_request<F>(
 where
     F: Fn(&str),
 {
+    if mock::enabled() {
+        let messages = [Message::text("user", String::from(prompt))];
+        return mock::respond(&messages, &[], on_token).map(|r| r.text);
+    }
     let request = build_http_request(config, prompt)?;
     claude_send_with_retry(net, config, &request, on_token)
 }
@@ -305,16 +541,21 @@ pub fn claude_request_multi<F>(
 where
     F: Fn(&str),
 {
+    if mock::enabled() {
+        return mock::respond(&request.messages, &request.stop_sequences, on_token).map(|r| r.text);
+    }
     let http_req = build_http_request_multi(
         &request.config,
         request.system.as_deref(),
         &request.messages,
         request.use_tools,
+        &request.stop_sequences,
     )?;
     claude_send_with_retry(net, &request.config, &http_req, on_token)
 }
 
-/// Send an agentic request to Claude — returns full response with tool calls.
+/// Send an agentic request to Claude — returns the full response: text,
+/// tool calls, and why the model stopped.
 pub fn claude_request_agentic<F>(
     net: &mut NetStack,
     request: &ClaudeRequest,
@@ -323,11 +564,15 @@ pub fn claude_request_agentic<F>(
 where
     F: Fn(&str),
 {
+    if mock::enabled() {
+        return mock::respond(&request.messages, &request.stop_sequences, on_token);
+    }
     let http_req = build_http_request_multi(
         &request.config,
         request.system.as_deref(),
         &request.messages,
         request.use_tools,
+        &request.stop_sequences,
     )?;
     claude_send_agentic(net, &request.config, &http_req, on_token)
 }
@@ -342,46 +587,13 @@ fn claude_send_with_retry<F>(
 where
     F: Fn(&str),
 {
-    let mut last_err = ApiError::EmptyResponse;
-
-    for attempt in 0..=MAX_RETRIES {
-        if attempt > 0 {
-            let delay_ms = BASE_DELAY_MS * (1u64 << (attempt - 1).min(4));
-            crate::serial_println!("[API] Retry {}/{} after {}ms...", attempt, MAX_RETRIES, delay_ms);
-            crate::arch::x86_64::timer::delay_us(delay_ms * 1000);
-        }
-
-        let result = if config.use_tls {
+    with_retry(net, config, |net| {
+        if config.use_tls {
             claude_request_tls(net, config, request, &on_token)
         } else {
             claude_request_plain(net, config, request, &on_token)
-        };
-
-        match result {
-            Ok(response) => return Ok(response),
-            Err(ApiError::HttpStatus(status, ref msg, retry_after)) => {
-                // Retry on server errors, not client errors
-                if status == 429 || status == 500 || status == 529 {
-                    // Honor Retry-After header if present (e.g. 429)
-                    if let Some(secs) = retry_after {
-                        let wait_ms = (secs * 1000).min(60_000);
-                        crate::serial_println!("[API] Retry-After: {}s", secs);
-                        crate::arch::x86_64::timer::delay_us(wait_ms * 1000);
-                    }
-                    last_err = ApiError::HttpStatus(status, msg.clone(), retry_after);
-                    continue;
-                }
-                return Err(ApiError::HttpStatus(status, msg.clone(), retry_after));
-            }
-            Err(ApiError::ConnectionTimeout) | Err(ApiError::ConnectionFailed) => {
-                last_err = ApiError::ConnectionTimeout;
-                continue;
-            }
-            Err(e) => return Err(e),
         }
-    }
-
-    Err(last_err)
+    })
 }
 
 /// Agentic send — parses both text and tool_use blocks from SSE stream.
@@ -395,34 +607,62 @@ fn claude_send_agentic<F>(
 where
     F: Fn(&str),
 {
+    with_retry(net, config, |net| claude_request_tls_agentic(net, config, request, &on_token))
+}
+
+/// Run `attempt` until it succeeds or fails for good. Timeouts, failed
+/// connections and 429/500/529 are retried up to `MAX_RETRIES` times,
+/// within the command's `RETRY_BUDGET`, after the server's Retry-After or
+/// a jittered backoff. Each attempt first waits out `ratelimit::pace`.
+/// `config.abort` cuts any wait short.
+fn with_retry<T>(
+    net: &mut NetStack,
+    config: &ClaudeConfig,
+    mut attempt: impl FnMut(&mut NetStack) -> Result<T, ApiError>,
+) -> Result<T, ApiError> {
     let mut last_err = ApiError::EmptyResponse;
+    let mut retry_after = None;
 
-    for attempt in 0..=MAX_RETRIES {
-        if attempt > 0 {
-            let delay_ms = BASE_DELAY_MS * (1u64 << (attempt - 1).min(4));
-            crate::serial_println!("[API] Retry {}/{} after {}ms...", attempt, MAX_RETRIES, delay_ms);
-            crate::arch::x86_64::timer::delay_us(delay_ms * 1000);
+    for n in 0..=MAX_RETRIES {
+        if n > 0 {
+            if !spend_retry() {
+                crate::serial_println!("[API] Retry budget spent ({} per command){}", RETRY_BUDGET, crate::span::tag());
+                break;
+            }
+            // Honor Retry-After if the server sent one (e.g. 429)
+            let delay_ms = retry_after.take().map_or_else(|| backoff_ms(n), |secs: u64| (secs * 1000).min(60_000));
+            if config.deadline_ms.is_some_and(|d| crate::time::monotonic_ms() + delay_ms >= d) {
+                break;
+            }
+            crate::serial_println!("[API] Retry {}/{} after {}ms...{}", n, MAX_RETRIES, delay_ms, crate::span::tag());
+            pause(net, config, delay_ms)?;
+        }
+        if let Some((delay_ms, why)) = ratelimit::pace() {
+            crate::serial_println!("[API] Near the rate limit ({}): waiting {}ms{}", why, delay_ms, crate::span::tag());
+            pause(net, config, delay_ms)?;
         }
 
-        let result = claude_request_tls_agentic(net, config, request, &on_token);
-
-        match result {
+        match attempt(net) {
             Ok(response) => return Ok(response),
-            Err(ApiError::HttpStatus(status, ref msg, retry_after)) => {
-                if status == 429 || status == 500 || status == 529 {
-                    if let Some(secs) = retry_after {
-                        let wait_ms = (secs * 1000).min(60_000);
-                        crate::serial_println!("[API] Retry-After: {}s", secs);
-                        crate::arch::x86_64::timer::delay_us(wait_ms * 1000);
-                    }
-                    last_err = ApiError::HttpStatus(status, msg.clone(), retry_after);
-                    continue;
-                }
-                return Err(ApiError::HttpStatus(status, msg.clone(), retry_after));
+            Err(_) if aborted(config) => return Err(ApiError::Interrupted),
+            // Whatever broke, no retry helps until the link is back
+            Err(_) if !net.link_up() => return Err(ApiError::LinkDown),
+            // Retry on server errors, not client errors
+            Err(ApiError::HttpStatus(status, msg, after)) if matches!(status, 500 | 529) => {
+                retry_after = after;
+                last_err = ApiError::HttpStatus(status, msg, after);
+            }
+            // With nothing left, pacing waits for the reset
+            Err(ApiError::RateLimited(limits)) => {
+                retry_after = limits.retry_after;
+                last_err = ApiError::RateLimited(limits);
             }
             Err(ApiError::ConnectionTimeout) | Err(ApiError::ConnectionFailed) => {
                 last_err = ApiError::ConnectionTimeout;
-                continue;
+            }
+            Err(ApiError::RequestTimeout) => {
+                crate::serial_println!("[API] No complete response in {}s{}", config.timeout_secs, crate::span::tag());
+                last_err = ApiError::RequestTimeout;
             }
             Err(e) => return Err(e),
         }
@@ -431,6 +671,57 @@ where
     Err(last_err)
 }
 
+/// Delay before retry `n` (from 1): half of `BASE_DELAY_MS` doubled per
+/// retry, plus a random part of the other half, so clients that failed
+/// together don't all come back together.
+pub fn backoff_ms(n: u32) -> u64 {
+    let full = BASE_DELAY_MS << n.saturating_sub(1).min(4);
+    let mut random = [0u8; 8];
+    crate::crypto::entropy::fill(&mut random);
+    full / 2 + u64::from_le_bytes(random) % (full / 2 + 1)
+}
+
+/// Take a retry from the current command's budget; false once it is
+/// spent. Requests outside any span only have their own `MAX_RETRIES`.
+pub fn spend_retry() -> bool {
+    let Some(id) = crate::span::current() else { return true };
+    let mut spent = RETRIES.lock();
+    let count = match *spent {
+        Some((span, count)) if span == id => count,
+        _ => 0,
+    };
+    if count >= RETRY_BUDGET {
+        return false;
+    }
+    *spent = Some((id, count + 1));
+    true
+}
+
+/// Has `config.abort` said to stop? Ctrl-C stays pending once typed,
+/// so every check after the first agrees.
+fn aborted(config: &ClaudeConfig) -> bool {
+    config.abort.is_some_and(|abort| abort())
+}
+
+/// `aborted`, after running `config.on_wait`: for loops waiting on `net`.
+fn waiting(net: &mut NetStack, config: &ClaudeConfig) -> bool {
+    if let Some(on_wait) = config.on_wait {
+        on_wait(net);
+    }
+    aborted(config)
+}
+
+/// Wait `ms` before a retry, failing with `Interrupted` as soon as
+/// `config.abort` says so.
+fn pause(net: &mut NetStack, config: &ClaudeConfig, ms: u64) -> Result<(), ApiError> {
+    let deadline = crate::time::Deadline::after_ms(ms);
+    if !deadline.wait_unless(|| waiting(net, config)) {
+        crate::serial_println!("[API] Retry interrupted{}", crate::span::tag());
+        return Err(ApiError::Interrupted);
+    }
+    Ok(())
+}
+
 /// TLS agentic request — returns ClaudeResponse with text + tool calls.
 fn claude_request_tls_agentic<F>(
     net: &mut NetStack,
@@ -441,192 +732,117 @@ fn claude_request_tls_agentic<F>(
 where
     F: Fn(&str),
 {
-    use crate::crypto::RdRandRng;
-    use crate::net::tls::TcpStream;
-    use embedded_tls::blocking::TlsConnection;
-    use embedded_tls::{TlsConfig, TlsContext};
-
-    let handle = net.tcp_connect(config.target_ip, config.target_port)
-        .ok_or(ApiError::ConnectionFailed)?;
-
-    let connected = net.poll_until(|n| n.tcp_can_send(handle), 10_000);
-    if !connected {
-        net.tcp_close(handle);
-        return Err(ApiError::ConnectionTimeout);
-    }
-
-    let tcp = TcpStream::new(net, handle);
-
-    let mut read_buf = vec![0u8; 16640];
-    let mut write_buf = vec![0u8; 16640];
-
-    let tls_config = TlsConfig::new()
-        .with_server_name("api.anthropic.com")
-        .enable_rsa_signatures();
-
-    let mut tls = TlsConnection::new(tcp, &mut read_buf, &mut write_buf);
-    let rng = RdRandRng::new();
-
-    {
-        use embedded_tls::{Aes128GcmSha256, UnsecureProvider};
-        tls.open(TlsContext::new(
-            &tls_config,
-            UnsecureProvider::new::<Aes128GcmSha256>(rng),
-        )).map_err(|e| {
-            crate::serial_println!("[TLS] Handshake failed: {:?}", e);
-            ApiError::TlsHandshakeFailed
-        })?;
+    let deadline = config.attempt_deadline();
+    let mut trace = trace::Recorder::start(request);
+    let mut bufs = TlsBuffers::take();
+    let mut client = TlsClient::new(&config.host)
+        .with_deadline(deadline)
+        .with_abort(config.abort)
+        .with_on_wait(config.on_wait);
+    let connected = client.connect(net, config.target_ip, config.target_port, &mut bufs);
+    if let Some(handshake) = client.handshake() {
+        trace.handshake(handshake);
     }
+    let mut tls = connected?;
 
     // Send request
-    let request_bytes = request.as_bytes();
-    let mut sent = 0;
-    while sent < request_bytes.len() {
-        let n = tls.write(&request_bytes[sent..]).map_err(|_| ApiError::SendFailed)?;
-        sent += n;
-    }
-    tls.flush().map_err(|_| ApiError::SendFailed)?;
+    set_phase(tls.handle(), Phase::Request);
+    tls.send(request.as_bytes()).map_err(|_| ApiError::SendFailed)?;
+    set_phase(tls.handle(), Phase::Response);
 
     // Parse SSE stream with tool_use support
-    let mut text_response = String::new();
-    let mut tool_calls: Vec<ToolCall> = Vec::new();
-    let mut stop_reason = String::from("end_turn");
-
-    // State for accumulating tool_use blocks
-    let mut current_tool_id = String::new();
-    let mut current_tool_name = String::new();
-    let mut current_tool_input = String::new();
-
+    let mut message = StreamedMessage::new();
     let mut raw_buf = Vec::new();
     let mut recv_buf = [0u8; 4096];
     let mut headers_parsed = false;
+    let mut chunked = false;
+    let mut encoding = http::Encoding::Identity;
+    // Did a read fail, rather than the server end the response?
+    let mut cut = false;
 
     loop {
         match tls.read(&mut recv_buf) {
             Ok(0) => break,
             Ok(n) => {
                 raw_buf.extend_from_slice(&recv_buf[..n]);
+                trace.record(&recv_buf[..n]);
 
                 if !headers_parsed {
                     if let Ok(resp) = http::HttpResponse::parse(&raw_buf) {
                         headers_parsed = true;
-                        if let Some(err_msg) = resp.error_message() {
-                            let retry = resp.retry_after_secs();
-                            let _ = tls.close();
-                            return Err(ApiError::HttpStatus(resp.status, String::from(err_msg), retry));
+                        if let Some(err) = check_status(&resp) {
+                            tls.close();
+                            return Err(err);
                         }
+                        chunked = resp.is_chunked();
+                        encoding = match resp.encoding() {
+                            Ok(encoding) => encoding,
+                            Err(e) => {
+                                tls.close();
+                                return Err(e);
+                            }
+                        };
                         raw_buf = raw_buf[resp.body_start..].to_vec();
                     }
                 }
 
-                if headers_parsed {
+                // A compressed stream can only be read once it is complete
+                if headers_parsed && config.stream && encoding == http::Encoding::Identity {
                     while let Some(event_end) = find_sse_event_end(&raw_buf) {
                         let event_bytes = raw_buf[..event_end].to_vec();
                         raw_buf = raw_buf[event_end..].to_vec();
 
-                        let event_str = match core::str::from_utf8(&event_bytes) {
-                            Ok(s) => s,
-                            Err(_) => continue,
-                        };
-
-                        let data = match extract_sse_data(event_str) {
-                            Some(d) => d,
-                            None => continue,
+                        let Some(data) = core::str::from_utf8(&event_bytes).ok().and_then(extract_sse_data) else {
+                            continue;
                         };
-
-                        // Parse the SSE data JSON
-                        if let Ok(parsed) = json::parse(data) {
-                            let event_type = parsed.get("type")
-                                .and_then(|v| v.as_str())
-                                .unwrap_or("");
-
-                            match event_type {
-                                "content_block_start" => {
-                                    // Check if this is a tool_use block
-                                    if let Some(cb) = parsed.get("content_block") {
-                                        let cb_type = cb.get("type")
-                                            .and_then(|v| v.as_str())
-                                            .unwrap_or("");
-                                        if cb_type == "tool_use" {
-                                            current_tool_id = cb.get("id")
-                                                .and_then(|v| v.as_str())
-                                                .map(String::from)
-                                                .unwrap_or_default();
-                                            current_tool_name = cb.get("name")
-                                                .and_then(|v| v.as_str())
-                                                .map(String::from)
-                                                .unwrap_or_default();
-                                            current_tool_input.clear();
-                                        }
-                                    }
-                                }
-                                "content_block_delta" => {
-                                    if let Some(delta) = parsed.get("delta") {
-                                        let delta_type = delta.get("type")
-                                            .and_then(|v| v.as_str())
-                                            .unwrap_or("");
-                                        match delta_type {
-                                            "text_delta" => {
-                                                if let Some(text) = delta.get("text").and_then(|v| v.as_str()) {
-                                                    on_token(text);
-                                                    text_response.push_str(text);
-                                                }
-                                            }
-                                            "input_json_delta" => {
-                                                if let Some(pj) = delta.get("partial_json").and_then(|v| v.as_str()) {
-                                                    current_tool_input.push_str(pj);
-                                                }
-                                            }
-                                            _ => {}
-                                        }
-                                    }
-                                }
-                                "content_block_stop" => {
-                                    // If we were accumulating a tool_use, finalize it
-                                    if !current_tool_id.is_empty() {
-                                        tool_calls.push(ToolCall {
-                                            id: core::mem::take(&mut current_tool_id),
-                                            name: core::mem::take(&mut current_tool_name),
-                                            input_json: core::mem::take(&mut current_tool_input),
-                                        });
-                                    }
-                                }
-                                "message_delta" => {
-                                    // Extract stop_reason
-                                    if let Some(delta) = parsed.get("delta") {
-                                        if let Some(sr) = delta.get("stop_reason").and_then(|v| v.as_str()) {
-                                            stop_reason = String::from(sr);
-                                        }
-                                    }
-                                }
-                                "message_stop" => {
-                                    let _ = tls.close();
-                                    return Ok(ClaudeResponse {
-                                        text: text_response,
-                                        tool_calls,
-                                        stop_reason,
-                                    });
-                                }
-                                _ => {}
-                            }
+                        if message.event(data, on_token) {
+                            tls.close();
+                            return Ok(message.finish());
                         }
                     }
                 }
             }
-            Err(_) => break,
+            Err(_) => {
+                cut = true;
+                break;
+            }
         }
     }
 
-    let _ = tls.close();
+    tls.close();
+    // Reads fail on Ctrl-C: keep what streamed in so far
+    if cut && aborted(config) {
+        return if message.is_empty() { Err(ApiError::Interrupted) } else { Ok(message.interrupted()) };
+    }
+    // Reads fail at the deadline: what arrived is cut short
+    if cut && crate::time::monotonic_ms() >= deadline {
+        return Err(ApiError::RequestTimeout);
+    }
+
+    if !config.stream {
+        let body = http::decode_body(&raw_buf, chunked, encoding)?;
+        let raw = String::from_utf8_lossy(&body);
+        let parsed = json::parse(raw.trim()).map_err(|e| ApiError::ApiError(format!("response: {}", e)))?;
+        let message = parse_message(&parsed)?;
+        on_token(&message.text);
+        return Ok(message);
+    }
+
+    if encoding != http::Encoding::Identity {
+        let body = http::decode_body(&raw_buf, chunked, encoding)?;
+        let mut rest = &body[..];
+        while let Some(event_end) = find_sse_event_end(rest) {
+            if let Some(data) = core::str::from_utf8(&rest[..event_end]).ok().and_then(extract_sse_data) {
+                message.event(data, on_token);
+            }
+            rest = &rest[event_end..];
+        }
+    }
 
-    if text_response.is_empty() && tool_calls.is_empty() {
+    if message.is_empty() {
         Err(ApiError::EmptyResponse)
     } else {
-        Ok(ClaudeResponse {
-            text: text_response,
-            tool_calls,
-            stop_reason,
-        })
+        Ok(message.finish())
     }
 }
 
@@ -640,95 +856,78 @@ fn claude_request_tls<F>(
 where
     F: Fn(&str),
 {
-    use crate::crypto::RdRandRng;
-    use crate::net::tls::TcpStream;
-    use embedded_tls::blocking::TlsConnection;
-    use embedded_tls::{TlsConfig, TlsContext};
-
-    // 1. TCP connect + wait for established
-    let handle = net.tcp_connect(config.target_ip, config.target_port)
-        .ok_or(ApiError::ConnectionFailed)?;
-
-    let connected = net.poll_until(|n| n.tcp_can_send(handle), 10_000);
-    if !connected {
-        net.tcp_close(handle);
-        return Err(ApiError::ConnectionTimeout);
-    }
-
-    // 2. Wrap in embedded-io adapter
-    let tcp = TcpStream::new(net, handle);
-
-    // 3. TLS handshake — with SPKI pin verification if enabled
-    let mut read_buf = vec![0u8; 16640];
-    let mut write_buf = vec![0u8; 16640];
-
-    let tls_config = TlsConfig::new()
-        .with_server_name("api.anthropic.com")
-        .enable_rsa_signatures();
-
-    let mut tls = TlsConnection::new(tcp, &mut read_buf, &mut write_buf);
-
-    let rng = RdRandRng::new();
-
     // NOTE: SPKI pin verification is not yet possible because embedded-tls 0.18
     // marks CertificateRef.entries as pub(crate), preventing external code from
     // inspecting the server certificate. See crypto/pin_verifier.rs for details.
-    // When this limitation is resolved, ENFORCE_PINNING will enable the pin check.
-    {
-        use embedded_tls::{Aes128GcmSha256, UnsecureProvider};
-        if !ENFORCE_PINNING {
-            crate::serial_println!(
-                "[SECURITY WARNING] TLS without certificate pinning — \
-                 API key may be exposed to MITM attacks"
-            );
-        }
-        tls.open(TlsContext::new(
-            &tls_config,
-            UnsecureProvider::new::<Aes128GcmSha256>(rng),
-        )).map_err(|e| {
-            crate::serial_println!("[TLS] Handshake failed: {:?}", e);
-            ApiError::TlsHandshakeFailed
-        })?;
+    // When this limitation is resolved, ENFORCE_PINNING will enable the pin check
+    // for `tls.pin_enforce on` (see `pin tofu`), and pin_verifier::inspect will
+    // warn on the leaf's expiry and names here.
+    if !crate::crypto::pin_verifier::enforcing() {
+        crate::serial_println!(
+            "[SECURITY WARNING] TLS without certificate pinning — \
+             API key may be exposed to MITM attacks"
+        );
     }
 
-    // 4. Send HTTP request over TLS
-    let request_bytes = request.as_bytes();
-    let mut sent = 0;
-    while sent < request_bytes.len() {
-        let chunk = &request_bytes[sent..];
-        let n = tls.write(chunk).map_err(|_| ApiError::SendFailed)?;
-        sent += n;
+    // 1. TCP connect + TLS handshake
+    let deadline = config.attempt_deadline();
+    let mut trace = trace::Recorder::start(request);
+    let mut bufs = TlsBuffers::take();
+    let mut client = TlsClient::new(&config.host)
+        .with_deadline(deadline)
+        .with_abort(config.abort)
+        .with_on_wait(config.on_wait);
+    let connected = client.connect(net, config.target_ip, config.target_port, &mut bufs);
+    if let Some(handshake) = client.handshake() {
+        trace.handshake(handshake);
     }
-    tls.flush().map_err(|_| ApiError::SendFailed)?;
+    let mut tls = connected?;
+
+    // 2. Send HTTP request over TLS
+    set_phase(tls.handle(), Phase::Request);
+    tls.send(request.as_bytes()).map_err(|_| ApiError::SendFailed)?;
+    set_phase(tls.handle(), Phase::Response);
 
-    // 5. Receive + parse response over TLS
+    // 3. Receive + parse response over TLS
     let mut response = String::new();
     let mut raw_buf = Vec::new();
     let mut recv_buf = [0u8; 4096];
     let mut headers_parsed = false;
+    let mut chunked = false;
+    let mut encoding = http::Encoding::Identity;
+    // Did a read fail, rather than the server end the response?
+    let mut cut = false;
 
     loop {
         match tls.read(&mut recv_buf) {
             Ok(0) => break, // EOF
             Ok(n) => {
                 raw_buf.extend_from_slice(&recv_buf[..n]);
+                trace.record(&recv_buf[..n]);
 
                 // Parse HTTP headers once we have them
                 if !headers_parsed {
                     if let Ok(resp) = http::HttpResponse::parse(&raw_buf) {
                         headers_parsed = true;
-                        if let Some(err_msg) = resp.error_message() {
-                            let retry = resp.retry_after_secs();
-                            let _ = tls.close();
-                            return Err(ApiError::HttpStatus(resp.status, String::from(err_msg), retry));
+                        if let Some(err) = check_status(&resp) {
+                            tls.close();
+                            return Err(err);
                         }
                         // Strip headers from buffer, keep body
+                        chunked = resp.is_chunked();
+                        encoding = match resp.encoding() {
+                            Ok(encoding) => encoding,
+                            Err(e) => {
+                                tls.close();
+                                return Err(e);
+                            }
+                        };
                         raw_buf = raw_buf[resp.body_start..].to_vec();
                     }
                 }
 
-                // Parse SSE events from body
-                if headers_parsed {
+                // Parse SSE events from body (once complete, if compressed)
+                if headers_parsed && config.stream && encoding == http::Encoding::Identity {
                     while let Some(event_end) = find_sse_event_end(&raw_buf) {
                         let event_bytes = raw_buf[..event_end].to_vec();
                         raw_buf = raw_buf[event_end..].to_vec();
@@ -739,18 +938,29 @@ where
                         }
 
                         if is_message_stop(&event_bytes) {
-                            let _ = tls.close();
+                            tls.close();
                             return Ok(response);
                         }
                     }
                 }
             }
-            Err(_) => break,
+            Err(_) => {
+                cut = true;
+                break;
+            }
         }
     }
 
-    let _ = tls.close();
-    finish_response(response, raw_buf, on_token)
+    tls.close();
+    // Reads fail on Ctrl-C: keep what streamed in so far
+    if cut && aborted(config) {
+        return if response.is_empty() { Err(ApiError::Interrupted) } else { Ok(response) };
+    }
+    // Reads fail at the deadline: what arrived is cut short
+    if cut && crate::time::monotonic_ms() >= deadline {
+        return Err(ApiError::RequestTimeout);
+    }
+    finish_response(response, raw_buf, chunked, encoding, on_token)
 }
 
 /// Plain HTTP path — for proxy mode.
@@ -763,15 +973,20 @@ fn claude_request_plain<F>(
 where
     F: Fn(&str),
 {
+    let deadline = config.attempt_deadline();
     let handle = net.tcp_connect(config.target_ip, config.target_port)
         .ok_or(ApiError::ConnectionFailed)?;
 
-    let connected = net.poll_until(|n| n.tcp_can_send(handle), 10_000);
+    let remaining = || deadline.saturating_sub(crate::time::monotonic_ms());
+    let connected = net.poll_until(|n| n.tcp_can_send(handle), remaining().min(10_000));
     if !connected {
-        return Err(ApiError::ConnectionTimeout);
+        net.tcp_close(handle);
+        return Err(if remaining() == 0 { ApiError::RequestTimeout } else { ApiError::ConnectionTimeout });
     }
 
     // Send request
+    set_phase(handle, Phase::Request);
+    let mut trace = trace::Recorder::start(request);
     let request_bytes = request.as_bytes();
     let mut sent = 0;
     while sent < request_bytes.len() {
@@ -780,14 +995,23 @@ where
             let n = net.tcp_send(handle, &request_bytes[sent..]);
             sent += n;
         }
-        core::hint::spin_loop();
+        if sent < request_bytes.len() && remaining() == 0 {
+            net.tcp_close(handle);
+            return Err(ApiError::RequestTimeout);
+        }
+        if !net.tcp_can_send(handle) {
+            crate::arch::x86_64::idle::wait();
+        }
     }
+    set_phase(handle, Phase::Response);
 
     // Receive response — parse SSE stream
     let mut response = String::new();
     let mut raw_buf = Vec::new();
     let mut recv_buf = [0u8; 4096];
     let mut headers_parsed = false;
+    let mut chunked = false;
+    let mut encoding = http::Encoding::Identity;
 
     loop {
         net.poll();
@@ -796,21 +1020,29 @@ where
             let n = net.tcp_recv(handle, &mut recv_buf);
             if n > 0 {
                 raw_buf.extend_from_slice(&recv_buf[..n]);
+                trace.record(&recv_buf[..n]);
 
                 // Parse HTTP headers
                 if !headers_parsed {
                     if let Ok(resp) = http::HttpResponse::parse(&raw_buf) {
                         headers_parsed = true;
-                        if let Some(err_msg) = resp.error_message() {
-                            let retry = resp.retry_after_secs();
+                        if let Some(err) = check_status(&resp) {
                             net.tcp_close(handle);
-                            return Err(ApiError::HttpStatus(resp.status, String::from(err_msg), retry));
+                            return Err(err);
                         }
+                        chunked = resp.is_chunked();
+                        encoding = match resp.encoding() {
+                            Ok(encoding) => encoding,
+                            Err(e) => {
+                                net.tcp_close(handle);
+                                return Err(e);
+                            }
+                        };
                         raw_buf = raw_buf[resp.body_start..].to_vec();
                     }
                 }
 
-                if headers_parsed {
+                if headers_parsed && config.stream && encoding == http::Encoding::Identity {
                     while let Some(event_end) = find_sse_event_end(&raw_buf) {
                         let event_bytes = raw_buf[..event_end].to_vec();
                         raw_buf = raw_buf[event_end..].to_vec();
@@ -829,56 +1061,214 @@ where
             }
         }
 
+        // The server closed: the response is whole, however late
         if !net.tcp_is_active(handle) && !net.tcp_can_recv(handle) {
             break;
         }
+        if remaining() == 0 {
+            net.tcp_close(handle);
+            return Err(ApiError::RequestTimeout);
+        }
+        if waiting(net, config) {
+            net.tcp_close(handle);
+            return if response.is_empty() { Err(ApiError::Interrupted) } else { Ok(response) };
+        }
 
-        core::hint::spin_loop();
+        if !net.tcp_can_recv(handle) {
+            crate::arch::x86_64::idle::wait();
+        }
     }
 
     net.tcp_close(handle);
-    finish_response(response, raw_buf, on_token)
+    finish_response(response, raw_buf, chunked, encoding, on_token)
 }
 
-/// Handle response completion — extract content from non-streaming or error responses.
+/// Record the rate limits a response reports; the error for its status,
+/// if it is one.
+fn check_status(resp: &http::HttpResponse) -> Option<ApiError> {
+    let limits = ratelimit::Limits::from_response(resp, crate::time::monotonic_ms());
+    ratelimit::record(&limits);
+    let msg = resp.error_message()?;
+    if resp.status == 429 {
+        return Some(ApiError::RateLimited(Box::new(limits)));
+    }
+    Some(ApiError::HttpStatus(resp.status, String::from(msg), resp.retry_after_secs()))
+}
+
+/// Handle response completion — extract content from non-streaming,
+/// compressed or error responses.
 fn finish_response<F: Fn(&str)>(
-    response: String,
+    mut response: String,
     raw_buf: Vec<u8>,
+    chunked: bool,
+    encoding: http::Encoding,
     on_token: &F,
 ) -> Result<String, ApiError> {
-    if response.is_empty() {
-        let raw = String::from_utf8_lossy(&raw_buf).into_owned();
-        if raw.is_empty() {
-            return Err(ApiError::EmptyResponse);
+    if !response.is_empty() {
+        return Ok(response);
+    }
+    let body = http::decode_body(&raw_buf, chunked, encoding)?;
+    // A compressed event stream, read only now that it is complete
+    if encoding != http::Encoding::Identity {
+        let mut rest = &body[..];
+        while let Some(event_end) = find_sse_event_end(rest) {
+            if let Some(text) = extract_content_delta_json(&rest[..event_end]) {
+                on_token(&text);
+                response.push_str(&text);
+            }
+            rest = &rest[event_end..];
+        }
+        if !response.is_empty() {
+            return Ok(response);
         }
-        // Try to parse as JSON error response
-        if let Ok(parsed) = json::parse(&raw) {
-            if let Some(err_obj) = parsed.get("error") {
-                let msg = err_obj.get("message")
-                    .and_then(|v| v.as_str())
-                    .unwrap_or("unknown error");
-                return Err(ApiError::ApiError(String::from(msg)));
+    }
+    let raw = String::from_utf8_lossy(&body).into_owned();
+    if raw.is_empty() {
+        return Err(ApiError::EmptyResponse);
+    }
+    // A JSON error or a complete (non-streaming) message
+    if let Ok(parsed) = json::parse(raw.trim()) {
+        let message = parse_message(&parsed)?;
+        on_token(&message.text);
+        return Ok(message.text);
+    }
+    // Fallback: return raw
+    Ok(raw)
+}
+
+/// Read a complete Messages API response: the text of every text block,
+/// concatenated, and every tool_use block in order.
+pub fn parse_message(parsed: &json::JsonValue) -> Result<ClaudeResponse, ApiError> {
+    if let Some(err_obj) = parsed.get("error") {
+        let msg = err_obj.get("message")
+            .and_then(|v| v.as_str())
+            .unwrap_or("unknown error");
+        return Err(ApiError::ApiError(String::from(msg)));
+    }
+    let blocks = parsed.get("content")
+        .and_then(|c| c.as_array())
+        .ok_or_else(|| ApiError::ApiError(String::from("no content in response")))?;
+
+    let mut text = String::new();
+    let mut tool_calls = Vec::new();
+    for block in blocks {
+        let field = |name| block.get(name).and_then(|v| v.as_str()).map(String::from).unwrap_or_default();
+        match block.get("type").and_then(|v| v.as_str()).unwrap_or("") {
+            "text" => text.push_str(&field("text")),
+            "tool_use" => tool_calls.push(ToolCall {
+                id: field("id"),
+                name: field("name"),
+                input_json: block.get("input").map_or_else(|| String::from("{}"), |v| v.to_json()),
+            }),
+            _ => {}
+        }
+    }
+    let stop_reason = parsed.get("stop_reason")
+        .and_then(|v| v.as_str())
+        .unwrap_or("end_turn");
+    let stop_sequence = parsed.get("stop_sequence").and_then(|v| v.as_str()).map(String::from);
+    let mut usage = Usage::default();
+    usage.update(parsed.get("usage"));
+    Ok(ClaudeResponse { text, tool_calls, stop_reason: String::from(stop_reason), stop_sequence, usage })
+}
+
+// ---- SSE parsing helpers ----
+
+/// A message read from its stream of SSE events: text, tool_use blocks,
+/// stop reason and usage.
+pub(crate) struct StreamedMessage {
+    response: ClaudeResponse,
+    /// The tool_use block being streamed, if any.
+    tool: Option<ToolCall>,
+}
+
+impl StreamedMessage {
+    pub(crate) fn new() -> Self {
+        StreamedMessage {
+            response: ClaudeResponse {
+                text: String::new(),
+                tool_calls: Vec::new(),
+                stop_reason: String::from("end_turn"),
+                stop_sequence: None,
+                usage: Usage::default(),
+            },
+            tool: None,
+        }
+    }
+
+    /// Take in the `data:` of one event, passing text to `on_token`.
+    /// True at message_stop.
+    pub(crate) fn event<F: Fn(&str)>(&mut self, data: &str, on_token: &F) -> bool {
+        let Ok(parsed) = json::parse(data) else { return false };
+        let response = &mut self.response;
+        match parsed.get("type").and_then(|v| v.as_str()).unwrap_or("") {
+            "message_start" => response.usage.update(parsed.get("message").and_then(|m| m.get("usage"))),
+            "content_block_start" => {
+                let block = parsed.get("content_block");
+                if block.and_then(|b| b.get("type")).and_then(|v| v.as_str()) == Some("tool_use") {
+                    let field = |name| {
+                        block.and_then(|b| b.get(name)).and_then(|v| v.as_str()).map(String::from).unwrap_or_default()
+                    };
+                    self.tool = Some(ToolCall { id: field("id"), name: field("name"), input_json: String::new() });
+                }
             }
-            // Try to extract content from non-streaming response
-            if let Some(content) = parsed.get("content") {
-                if let Some(arr) = content.as_array() {
-                    for block in arr {
-                        if let Some(text) = block.get("text").and_then(|v| v.as_str()) {
+            "content_block_delta" => {
+                let Some(delta) = parsed.get("delta") else { return false };
+                match delta.get("type").and_then(|v| v.as_str()).unwrap_or("") {
+                    "text_delta" => {
+                        if let Some(text) = delta.get("text").and_then(|v| v.as_str()) {
                             on_token(text);
-                            return Ok(String::from(text));
+                            response.text.push_str(text);
+                        }
+                    }
+                    "input_json_delta" => {
+                        if let (Some(tool), Some(pj)) =
+                            (self.tool.as_mut(), delta.get("partial_json").and_then(|v| v.as_str()))
+                        {
+                            tool.input_json.push_str(pj);
                         }
                     }
+                    _ => {}
+                }
+            }
+            "content_block_stop" => {
+                if let Some(tool) = self.tool.take() {
+                    response.tool_calls.push(tool);
+                }
+            }
+            "message_delta" => {
+                response.usage.update(parsed.get("usage"));
+                if let Some(delta) = parsed.get("delta") {
+                    if let Some(sr) = delta.get("stop_reason").and_then(|v| v.as_str()) {
+                        response.stop_reason = String::from(sr);
+                    }
+                    if let Some(seq) = delta.get("stop_sequence").and_then(|v| v.as_str()) {
+                        response.stop_sequence = Some(String::from(seq));
+                    }
                 }
             }
+            "message_stop" => return true,
+            _ => {}
         }
-        // Fallback: return raw
-        Ok(raw)
-    } else {
-        Ok(response)
+        false
     }
-}
 
-// ---- SSE parsing helpers ----
+    fn is_empty(&self) -> bool {
+        self.response.text.is_empty() && self.response.tool_calls.is_empty()
+    }
+
+    fn finish(self) -> ClaudeResponse {
+        self.response
+    }
+
+    /// What arrived before a Ctrl-C: the text so far, stop_reason
+    /// "interrupted", and no tool calls — nothing half-asked gets run.
+    pub(crate) fn interrupted(mut self) -> ClaudeResponse {
+        self.response.tool_calls.clear();
+        self.response.stop_reason = String::from("interrupted");
+        self.response
+    }
+}
 
 /// Find the end of an SSE event (delimited by double newline).
 fn find_sse_event_end(buf: &[u8]) -> Option<usize> {
@@ -1042,42 +1432,137 @@ fn unescape_json(s: &str) -> String {
 pub enum ApiError {
     ConnectionFailed,
     ConnectionTimeout,
+    /// The network link went down (before or during the request).
+    LinkDown,
     TlsHandshakeFailed,
     SendFailed,
     EmptyResponse,
     DnsError(String),
+    /// The connection worked but the response didn't finish within
+    /// `ClaudeConfig::timeout_secs`.
+    RequestTimeout,
+    /// Given up between retries at the user's request (`ClaudeConfig::abort`).
+    Interrupted,
     /// HTTP error with status code, human-readable message, and optional retry-after (secs).
     HttpStatus(u16, String, Option<u64>),
+    /// HTTP 429, with the limits the response reported.
+    RateLimited(Box<ratelimit::Limits>),
     ApiError(String),
 }
 
+impl ApiError {
+    /// Did the request fail for lack of connectivity (worth retrying once
+    /// the network is back) rather than because of the API's answer?
+    pub fn is_network(&self) -> bool {
+        matches!(
+            self,
+            ApiError::ConnectionFailed
+                | ApiError::ConnectionTimeout
+                | ApiError::RequestTimeout
+                | ApiError::LinkDown
+                | ApiError::TlsHandshakeFailed
+                | ApiError::SendFailed
+                | ApiError::DnsError(_)
+        )
+    }
+}
+
 impl core::fmt::Display for ApiError {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         match self {
             ApiError::ConnectionFailed => write!(f, "TCP connection failed"),
             ApiError::ConnectionTimeout => write!(f, "connection timeout"),
+            ApiError::RequestTimeout => write!(f, "request timed out"),
+            ApiError::Interrupted => write!(f, "interrupted"),
+            ApiError::LinkDown => write!(f, "network link down"),
             ApiError::TlsHandshakeFailed => write!(f, "TLS handshake failed"),
             ApiError::SendFailed => write!(f, "failed to send request"),
             ApiError::EmptyResponse => write!(f, "empty response from API"),
             ApiError::DnsError(msg) => write!(f, "DNS error: {}", msg),
             ApiError::HttpStatus(code, msg, _) => write!(f, "HTTP {}: {}", code, msg),
+            ApiError::RateLimited(limits) => {
+                write!(f, "HTTP 429: rate limited")?;
+                let exhausted = limits.exhausted();
+                if !exhausted.is_empty() {
+                    write!(f, " ({})", exhausted)?;
+                }
+                if let Some(secs) = limits.retry_after {
+                    write!(f, ", retry after {}s", secs)?;
+                }
+                Ok(())
+            }
             ApiError::ApiError(msg) => write!(f, "API error: {}", msg),
         }
     }
 }
 
+impl From<ConnectError> for ApiError {
+    fn from(e: ConnectError) -> Self {
+        match e {
+            ConnectError::NoSocket | ConnectError::Refused => ApiError::ConnectionFailed,
+            ConnectError::Timeout => ApiError::ConnectionTimeout,
+            ConnectError::Handshake(_) => ApiError::TlsHandshakeFailed,
+        }
+    }
+}
+
+/// Connection failures start below the API, in the layer that failed;
+/// only an error answer from the API itself originates here.
+impl From<ApiError> for KernelError {
+    fn from(e: ApiError) -> Self {
+        let (layer, code) = match &e {
+            ApiError::ConnectionFailed => (Layer::Net, String::from("ConnectionFailed")),
+            ApiError::ConnectionTimeout => (Layer::Net, String::from("ConnectionTimeout")),
+            ApiError::RequestTimeout => (Layer::Net, String::from("RequestTimeout")),
+            ApiError::Interrupted => (Layer::Api, String::from("Interrupted")),
+            ApiError::LinkDown => (Layer::Net, String::from("LinkDown")),
+            ApiError::SendFailed => (Layer::Net, String::from("SendFailed")),
+            ApiError::TlsHandshakeFailed => (Layer::Tls, String::from("HandshakeFailed")),
+            ApiError::DnsError(msg) => {
+                return KernelError::new(Layer::Dns, "ResolveFailed", msg).context(Layer::Api, "request");
+            }
+            ApiError::EmptyResponse => (Layer::Http, String::from("EmptyResponse")),
+            ApiError::HttpStatus(status, _, _) => (Layer::Http, format!("HttpStatus({})", status)),
+            ApiError::RateLimited(_) => (Layer::Http, String::from("RateLimited")),
+            ApiError::ApiError(_) => return KernelError::new(Layer::Api, "ApiError", e),
+        };
+        KernelError::new(layer, code, e).context(Layer::Api, "request")
+    }
+}
+
 // ---- Static API key storage ----
 
 use spin::Mutex;
-static API_KEY: Mutex<Option<String>> = Mutex::new(None);
+static API_KEY: Mutex<Option<Zeroizing<String>>> = Mutex::new(None);
 static MODEL: Mutex<Option<String>> = Mutex::new(None);
 
+/// Retries spent by the latest span that retried (`spend_retry`).
+static RETRIES: Mutex<Option<(crate::span::RequestId, u32)>> = Mutex::new(None);
+
+/// Store the API key. The previous key, if any, is wiped.
 pub fn set_api_key(key: &str) {
-    *API_KEY.lock() = Some(String::from(key));
+    *API_KEY.lock() = Some(Zeroizing::new(String::from(key)));
+}
+
+/// Forget (and wipe) the API key.
+pub fn clear_api_key() {
+    *API_KEY.lock() = None;
 }
 
-pub fn get_api_key() -> Option<String> {
-    API_KEY.lock().clone()
+/// A copy of the API key, wiped when the caller drops it. `mockapi` builds
+/// make one up when none is set.
+pub fn get_api_key() -> Option<Zeroizing<String>> {
+    let key = API_KEY.lock().clone();
+    key.or_else(|| mock::enabled().then(|| Zeroizing::new(String::from("sk-ant-mock"))))
+}
+
+/// Resolve `API_HOST`. `mockapi` builds never connect, so they skip the
+/// lookup and get the loopback address.
+pub fn resolve_api_host(net: &mut NetStack) -> Result<IpAddress, crate::net::dns::DnsError> {
+    if mock::enabled() {
+        return Ok(IpAddress::Ipv4(Ipv4Address::new(127, 0, 0, 1)));
+    }
+    crate::net::dns::resolve(net, API_HOST)
 }
 
 pub fn set_model(model: &str) {
@@ -1087,3 +1572,18 @@ pub fn set_model(model: &str) {
 pub fn get_model() -> String {
     MODEL.lock().clone().unwrap_or_else(|| String::from("claude-sonnet-4-6-20250514"))
 }
+
+/// Whether requests stream by default: true unless config `api.stream`
+/// is "off". Takes the DB lock.
+pub fn stream_default() -> bool {
+    crate::sqlite::config_get("api.stream").is_none_or(|v| v.trim() != "off")
+}
+
+/// The per-attempt deadline in seconds: config `api.timeout`, or
+/// `REQUEST_TIMEOUT_SECS`. Takes the DB lock.
+pub fn timeout_default() -> u64 {
+    crate::sqlite::config_get("api.timeout")
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .filter(|&secs| secs >= MIN_REQUEST_TIMEOUT_SECS)
+        .unwrap_or(REQUEST_TIMEOUT_SECS)
+}
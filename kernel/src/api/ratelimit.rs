@@ -0,0 +1,193 @@
+/// The API's rate limits, as its `anthropic-ratelimit-*` headers report
+/// them.
+///
+/// Each response says, for requests, input tokens, output tokens and all
+/// tokens, how many the organization may use per minute, how many remain
+/// and when the bucket is full again. `record` keeps the latest set for
+/// `api status` and `/sys/ratelimit`, and a 429 carries its set in
+/// `ApiError::RateLimited`.
+///
+/// Reset times are RFC 3339; they are turned into uptime against the
+/// response's own `Date` header, so the local clock needn't be right.
+///
+/// Before each request `pace` says how long to hold off: until the reset
+/// if a quota is empty, and while one is under `LOW_PERCENT`, long enough
+/// to spread what is left until the reset — so a busy agent slows down
+/// rather than running into 429s.
+use alloc::format;
+use alloc::string::String;
+use core::fmt::Write;
+use spin::Mutex;
+
+use super::http::HttpResponse;
+use crate::time::epoch_secs;
+
+/// Share of a quota left under which requests are spaced out.
+pub const LOW_PERCENT: u64 = 10;
+
+/// Longest `pace` holds a request back.
+pub const MAX_PACE_MS: u64 = 60_000;
+
+/// One limit: its size, what is left and when it refills.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quota {
+    pub limit: u64,
+    pub remaining: u64,
+    /// `monotonic_ms` at which it is full again, if the response said.
+    pub reset_ms: Option<u64>,
+}
+
+impl Quota {
+    fn low(&self) -> bool {
+        self.remaining * 100 < self.limit * LOW_PERCENT
+    }
+}
+
+/// The limits reported by one response.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Limits {
+    pub requests: Option<Quota>,
+    pub tokens: Option<Quota>,
+    pub input_tokens: Option<Quota>,
+    pub output_tokens: Option<Quota>,
+    /// Seconds to wait from a `Retry-After` header.
+    pub retry_after: Option<u64>,
+    /// `monotonic_ms` when the response arrived.
+    pub at_ms: u64,
+}
+
+impl Limits {
+    /// Read the headers of a response that arrived at `now_ms`.
+    pub fn from_response(resp: &HttpResponse, now_ms: u64) -> Self {
+        let date = resp.header("date").and_then(http_date);
+        let quota = |name: &str| {
+            let number = |field: &str| {
+                resp.header(&format!("anthropic-ratelimit-{}-{}", name, field)).and_then(|v| v.trim().parse().ok())
+            };
+            let reset = resp.header(&format!("anthropic-ratelimit-{}-reset", name)).and_then(rfc3339);
+            let reset_ms = match (reset, date) {
+                (Some(reset), Some(date)) => Some(now_ms + (reset - date).max(0) as u64 * 1000),
+                _ => None,
+            };
+            Some(Quota { limit: number("limit")?, remaining: number("remaining")?, reset_ms })
+        };
+        Limits {
+            requests: quota("requests"),
+            tokens: quota("tokens"),
+            input_tokens: quota("input-tokens"),
+            output_tokens: quota("output-tokens"),
+            retry_after: resp.retry_after_secs(),
+            at_ms: now_ms,
+        }
+    }
+
+    /// Did the response carry any limits?
+    pub fn is_empty(&self) -> bool {
+        self.quotas().all(|(_, q)| q.is_none())
+    }
+
+    fn quotas(&self) -> impl Iterator<Item = (&'static str, Option<&Quota>)> {
+        [
+            ("requests", self.requests.as_ref()),
+            ("tokens", self.tokens.as_ref()),
+            ("input_tokens", self.input_tokens.as_ref()),
+            ("output_tokens", self.output_tokens.as_ref()),
+        ]
+        .into_iter()
+    }
+
+    /// The quotas that have run out, e.g. "input_tokens 0/40000".
+    pub fn exhausted(&self) -> String {
+        let mut out = String::new();
+        for (name, quota) in self.quotas() {
+            if let Some(q) = quota.filter(|q| q.remaining == 0) {
+                let sep = if out.is_empty() { "" } else { ", " };
+                let _ = write!(out, "{}{} 0/{}", sep, name, q.limit);
+            }
+        }
+        out
+    }
+
+    /// How long to wait at `now_ms` before the next request, and why;
+    /// None while every quota is comfortably above `LOW_PERCENT`.
+    pub fn pace(&self, now_ms: u64) -> Option<(u64, String)> {
+        let mut wait: Option<(u64, String)> = None;
+        for (name, quota) in self.quotas() {
+            let Some(q) = quota.filter(|q| q.low()) else { continue };
+            let Some(reset) = q.reset_ms.filter(|&r| r > now_ms) else { continue };
+            let ms = ((reset - now_ms) / (q.remaining + 1)).min(MAX_PACE_MS);
+            if wait.as_ref().is_none_or(|(longest, _)| ms > *longest) {
+                wait = Some((ms, format!("{} {}/{} left", name, q.remaining, q.limit)));
+            }
+        }
+        wait.filter(|(ms, _)| *ms > 0)
+    }
+}
+
+/// The latest limits any response reported.
+static LATEST: Mutex<Option<Limits>> = Mutex::new(None);
+
+/// Keep `limits` if the response had any.
+pub fn record(limits: &Limits) {
+    if !limits.is_empty() {
+        *LATEST.lock() = Some(limits.clone());
+    }
+}
+
+/// The latest limits reported, if any response has had them.
+pub fn latest() -> Option<Limits> {
+    LATEST.lock().clone()
+}
+
+/// How long to hold the next request back (see `Limits::pace`).
+pub fn pace() -> Option<(u64, String)> {
+    let now = crate::time::monotonic_ms();
+    LATEST.lock().as_ref().and_then(|l| l.pace(now))
+}
+
+/// `api status` and `/sys/ratelimit`: one line per quota.
+pub fn report() -> alloc::vec::Vec<u8> {
+    let Some(limits) = latest() else {
+        return b"no rate limits reported yet\n".to_vec();
+    };
+    let now = crate::time::monotonic_ms();
+    let mut out = String::new();
+    let _ = writeln!(out, "{:<14} {:>10} {:>10} {:>9}", "quota", "remaining", "limit", "reset_s");
+    for (name, quota) in limits.quotas() {
+        if let Some(q) = quota {
+            let reset = q.reset_ms.map_or_else(|| String::from("-"), |r| format!("{}", r.saturating_sub(now).div_ceil(1000)));
+            let _ = writeln!(out, "{:<14} {:>10} {:>10} {:>9}", name, q.remaining, q.limit, reset);
+        }
+    }
+    let _ = writeln!(out, "reported {}s ago", now.saturating_sub(limits.at_ms) / 1000);
+    if let Some((ms, why)) = limits.pace(now) {
+        let _ = writeln!(out, "pacing: next request waits {}ms ({})", ms, why);
+    }
+    out.into_bytes()
+}
+
+/// Seconds since 1970 of an RFC 3339 UTC time, "2026-10-15T12:00:30Z"
+/// (fractions ignored; "+00:00" accepted for "Z").
+pub fn rfc3339(s: &str) -> Option<i64> {
+    let s = s.trim();
+    let (date, time) = s.split_once(['T', 't', ' '])?;
+    let mut ymd = date.splitn(3, '-').map(|p| p.parse::<i64>().ok());
+    let (year, month, day) = (ymd.next()??, ymd.next()??, ymd.next()??);
+    let time = time.strip_suffix(['Z', 'z']).or_else(|| time.strip_suffix("+00:00"))?;
+    let time = time.split('.').next()?;
+    let mut hms = time.splitn(3, ':').map(|p| p.parse::<i64>().ok());
+    let (hour, minute, second) = (hms.next()??, hms.next()??, hms.next()??);
+    epoch_secs(year, month, day, hour, minute, second)
+}
+
+/// Seconds since 1970 of an HTTP date, "Thu, 15 Oct 2026 12:00:00 GMT".
+pub fn http_date(s: &str) -> Option<i64> {
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let mut words = s.split_whitespace().skip(1);
+    let day = words.next()?.parse().ok()?;
+    let month = words.next().and_then(|m| MONTHS.iter().position(|&name| name == m))? as i64 + 1;
+    let year = words.next()?.parse().ok()?;
+    let mut hms = words.next()?.splitn(3, ':').map(|p| p.parse::<i64>().ok());
+    let (hour, minute, second) = (hms.next()??, hms.next()??, hms.next()??);
+    epoch_secs(year, month, day, hour, minute, second)
+}
@@ -18,17 +18,22 @@ pub struct ToolDef {
 pub const TOOLS: &[ToolDef] = &[
     ToolDef {
         name: "read_file",
-        description: "Read a file from the OSqlite namespace. Returns the file content as a string, or an error if not found.",
+        description: "Read a file from the OSqlite namespace. Returns the file content as a string, or an error if not found. A file over the tool result limit comes back as its start and end around a marker; read_range reads the rest.",
         input_schema: r#"{"type":"object","properties":{"path":{"type":"string","description":"Namespace path to read (e.g. /agents/indexer)"}},"required":["path"]}"#,
     },
+    ToolDef {
+        name: "read_range",
+        description: "Read part of a file: length bytes from a byte offset (0 = start). The result starts with a line giving the range returned and the file's total size. Use it to page through files too large for read_file.",
+        input_schema: r#"{"type":"object","properties":{"path":{"type":"string","description":"Namespace path to read"},"offset":{"type":"integer","description":"First byte to read (default 0)"},"length":{"type":"integer","description":"Bytes to read (default and maximum: the tool result limit)"}},"required":["path"]}"#,
+    },
     ToolDef {
         name: "write_file",
-        description: "Write content to a file in the OSqlite namespace. Creates or overwrites the file.",
+        description: "Write content to a file in the OSqlite namespace. Creates or overwrites the file. Paths under /tmp/ are kept in RAM and lost at reboot: use them for scratch files.",
         input_schema: r#"{"type":"object","properties":{"path":{"type":"string","description":"Namespace path to write"},"content":{"type":"string","description":"File content to write"}},"required":["path","content"]}"#,
     },
     ToolDef {
         name: "sql_query",
-        description: "Execute a read-only SQL query on the OSqlite system database. Only SELECT, EXPLAIN, and PRAGMA are allowed.",
+        description: "Execute a read-only SQL query on the OSqlite system database. Only SELECT, EXPLAIN, and PRAGMA are allowed. File content (namespace.content, embeddings.chunk) can't be selected: use read_file.",
         input_schema: r#"{"type":"object","properties":{"query":{"type":"string","description":"SQL query to execute"}},"required":["query"]}"#,
     },
     ToolDef {
@@ -43,8 +48,10 @@ pub const TOOLS: &[ToolDef] = &[
     },
 ];
 
-/// Serialize the tools array as JSON for the API request body.
-pub fn tools_json() -> String {
+/// Serialize the tools array as JSON for the API request body. With
+/// `cache`, the last tool carries a prompt cache breakpoint, covering
+/// the whole array.
+pub fn tools_json(cache: bool) -> String {
     use super::escape_json;
 
     let mut out = String::from("[");
@@ -52,11 +59,14 @@ pub fn tools_json() -> String {
         if i > 0 {
             out.push(',');
         }
+        let cache_control =
+            if cache && i + 1 == TOOLS.len() { r#","cache_control":{"type":"ephemeral"}"# } else { "" };
         out.push_str(&format!(
-            r#"{{"name":"{}","description":"{}","input_schema":{}}}"#,
+            r#"{{"name":"{}","description":"{}","input_schema":{}{}}}"#,
             escape_json(tool.name),
             escape_json(tool.description),
             tool.input_schema,
+            cache_control,
         ));
     }
     out.push(']');
@@ -69,11 +79,13 @@ mod tests {
 
     #[test]
     fn test_tools_json_valid() {
-        let json = tools_json();
+        let json = tools_json(true);
         // Should parse as valid JSON array
         let parsed = super::super::json::parse(&json).unwrap();
         let arr = parsed.as_array().unwrap();
-        assert_eq!(arr.len(), 5);
+        assert_eq!(arr.len(), 6);
+        assert!(arr[5].get("cache_control").is_some());
+        assert!(arr[0].get("cache_control").is_none());
 
         // Check first tool
         assert_eq!(arr[0].get("name").unwrap().as_str(), Some("read_file"));
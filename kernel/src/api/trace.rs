@@ -0,0 +1,204 @@
+/// Request/response transcripts for debugging the Messages API client.
+///
+/// Off by default. `api trace on` (config `api.trace`) makes every Claude
+/// request leave a namespace file
+///
+///   /sys/api/trace/<n>    the request id (`crate::span`), the TLS
+///                         handshake's parameters (direct mode), the
+///                         HTTP request with credentials redacted,
+///                         then the raw response bytes as received —
+///                         headers, SSE events, chunk framing and all
+///
+/// so a stream the parser chokes on can be copied out and replayed
+/// offline, and a failed handshake leaves a transcript saying how far it
+/// got. Requests are capped at `MAX_REQUEST` bytes and responses at
+/// `MAX_RESPONSE`; only the newest `KEEP` transcripts are kept. They are
+/// written mode 0600, so agents and Styx clients can't read them.
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::sqlite::acl::{self, Principal};
+use crate::sqlite::{SqlValue, DB};
+
+/// Namespace directory holding the transcripts.
+pub const TRACE_DIR: &str = "/sys/api/trace/";
+
+const TRACE_KEY: &str = "api.trace";
+const MAX_REQUEST: usize = 16 * 1024;
+const MAX_RESPONSE: usize = 64 * 1024;
+const KEEP: i64 = 32;
+
+/// Transcripts hold whole prompts and tool results: shell only.
+const TRACE_MODE: i64 = 0o600;
+
+/// Is tracing on? Takes the DB lock.
+pub fn enabled() -> bool {
+    crate::sqlite::config_get(TRACE_KEY).is_some_and(|v| v.trim() == "on")
+}
+
+/// Turn tracing on or off.
+pub fn set_enabled(on: bool) -> Result<(), String> {
+    crate::sqlite::config_set(TRACE_KEY, if on { "on" } else { "off" })
+}
+
+/// Collects one exchange and saves it when dropped, so every exit path of
+/// a request — success, HTTP error, broken stream — leaves its transcript.
+/// Does nothing while tracing is off.
+pub struct Recorder {
+    request: Option<String>,
+    /// The request this exchange was made for.
+    request_id: Option<crate::span::RequestId>,
+    /// `net::tls::Handshake` summary, in TLS mode.
+    handshake: Option<String>,
+    response: Vec<u8>,
+    /// Response bytes past the cap, counted but not kept.
+    dropped: usize,
+}
+
+impl Recorder {
+    /// Start a transcript of `request` (the full HTTP request text).
+    pub fn start(request: &str) -> Self {
+        let request = enabled().then(|| redact(request));
+        Recorder {
+            request,
+            request_id: crate::span::current(),
+            handshake: None,
+            response: Vec::new(),
+            dropped: 0,
+        }
+    }
+
+    /// Note how the TLS handshake went.
+    pub fn handshake(&mut self, handshake: &crate::net::tls::Handshake) {
+        if self.request.is_some() {
+            self.handshake = Some(alloc::format!("{}", handshake));
+        }
+    }
+
+    /// Append received bytes.
+    pub fn record(&mut self, bytes: &[u8]) {
+        if self.request.is_none() {
+            return;
+        }
+        let room = MAX_RESPONSE.saturating_sub(self.response.len());
+        let kept = bytes.len().min(room);
+        self.response.extend_from_slice(&bytes[..kept]);
+        self.dropped += bytes.len() - kept;
+    }
+
+    fn save(&self, request: &str) -> Result<String, String> {
+        let mut text = String::new();
+        if let Some(id) = self.request_id {
+            text.push_str(&alloc::format!("--- request id {}\n", id));
+        }
+        if let Some(handshake) = &self.handshake {
+            text.push_str("--- tls\n");
+            text.push_str(handshake);
+            text.push('\n');
+        }
+        text.push_str("--- request\n");
+        text.push_str(request);
+        text.push_str(&alloc::format!("\n--- response ({} bytes", self.response.len() + self.dropped));
+        if self.dropped > 0 {
+            text.push_str(&alloc::format!(", last {} not kept", self.dropped));
+        }
+        text.push_str(")\n");
+        text.push_str(&String::from_utf8_lossy(&self.response));
+
+        let guard = DB.lock();
+        let db = guard.as_ref().ok_or_else(|| String::from("database not open"))?;
+        let next: i64 = db
+            .query_value(&alloc::format!(
+                "SELECT coalesce(max(CAST(substr(path, {}) AS INTEGER)), 0) + 1 FROM namespace \
+                 WHERE path LIKE '{}%'",
+                TRACE_DIR.len() + 1,
+                TRACE_DIR
+            ))?
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(1);
+        let path = alloc::format!("{}{}", TRACE_DIR, next);
+        acl::write_file(db, &Principal::Shell, &path, "log", &text)?;
+        acl::chmod(db, &path, TRACE_MODE)?;
+        db.query_params(
+            &alloc::format!(
+                "DELETE FROM namespace WHERE path LIKE '{}%' AND CAST(substr(path, {}) AS INTEGER) <= ?",
+                TRACE_DIR,
+                TRACE_DIR.len() + 1
+            ),
+            &[SqlValue::Integer(next - KEEP)],
+        )?;
+        Ok(path)
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        if let Some(request) = self.request.take() {
+            if let Err(e) = self.save(&request) {
+                crate::serial_println!("[API] trace: {}", e);
+            }
+        }
+    }
+}
+
+/// Saved transcripts as (path, bytes), oldest first.
+pub fn list() -> Result<Vec<(String, i64)>, String> {
+    let guard = DB.lock();
+    let db = guard.as_ref().ok_or_else(|| String::from("database not open"))?;
+    let result = db.query(&alloc::format!(
+        "SELECT path, unpacked_length(content, compressed) FROM namespace WHERE path LIKE '{}%' \
+         ORDER BY CAST(substr(path, {}) AS INTEGER)",
+        TRACE_DIR,
+        TRACE_DIR.len() + 1
+    ))?;
+    Ok(result
+        .rows
+        .iter()
+        .map(|row| {
+            let path = String::from(row.first().and_then(SqlValue::as_str).unwrap_or(""));
+            (path, row.get(1).and_then(SqlValue::as_integer).unwrap_or(0))
+        })
+        .collect())
+}
+
+/// Delete every transcript. Returns how many went.
+pub fn clear() -> Result<u64, String> {
+    let guard = DB.lock();
+    let db = guard.as_ref().ok_or_else(|| String::from("database not open"))?;
+    db.exec(&alloc::format!("DELETE FROM namespace WHERE path LIKE '{}%'", TRACE_DIR))?;
+    Ok(db.query_value("SELECT changes()")?.and_then(|v| v.parse().ok()).unwrap_or(0))
+}
+
+/// `request` with credential header values replaced, cut to `MAX_REQUEST`.
+pub fn redact(request: &str) -> String {
+    let mut out = String::with_capacity(request.len().min(MAX_REQUEST));
+    let (head, body) = match request.find("\r\n\r\n") {
+        Some(i) => (&request[..i], &request[i..]),
+        None => (request, ""),
+    };
+    for (i, line) in head.split("\r\n").enumerate() {
+        if i > 0 {
+            out.push_str("\r\n");
+        }
+        match line.split_once(':') {
+            Some((name, _))
+                if ["x-api-key", "authorization"].iter().any(|h| name.trim().eq_ignore_ascii_case(h)) =>
+            {
+                out.push_str(name);
+                out.push_str(": [redacted]");
+            }
+            _ => out.push_str(line),
+        }
+    }
+    out.push_str(body);
+    if out.len() > MAX_REQUEST {
+        let mut end = MAX_REQUEST;
+        while !out.is_char_boundary(end) {
+            end -= 1;
+        }
+        let cut = out.len() - end;
+        out.truncate(end);
+        out.push_str(&alloc::format!("\n[{} more bytes not kept]", cut));
+    }
+    out
+}
@@ -0,0 +1,199 @@
+/// Minimal ACPI support — just enough to power off (S5).
+///
+/// Walks RSDP → RSDT/XSDT → FADT for the PM1 control ports, then scans the
+/// DSDT bytes for the `\_S5_` package to get SLP_TYPa/b. There is no AML
+/// interpreter; this is the same shortcut most hobby kernels take, and it
+/// works on QEMU (i440fx and q35), Bochs and most real firmware.
+///
+/// If ACPI is unavailable or the write has no effect, `poweroff` falls back
+/// to the well-known emulator shutdown ports and returns to the caller.
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::{inw, outb, outl, outw};
+use crate::mem::{self, PhysAddr};
+
+/// Physical address of the RSDP (0 = unknown). Set once at boot.
+static RSDP_PHYS: AtomicU64 = AtomicU64::new(0);
+
+/// SLP_EN bit in PM1x_CNT.
+const SLP_EN: u16 = 1 << 13;
+/// SCI_EN bit in PM1x_CNT: set when the firmware has handed over to ACPI.
+const SCI_EN: u16 = 1 << 0;
+
+/// Record the RSDP address from the bootloader. Accepts either a physical
+/// address or an HHDM virtual one (older Limine base revisions).
+pub fn set_rsdp(addr: u64) {
+    let hhdm = mem::hhdm_offset();
+    let phys = if addr >= hhdm { addr - hhdm } else { addr };
+    RSDP_PHYS.store(phys, Ordering::Release);
+}
+
+/// Power-off parameters extracted from the FADT and DSDT.
+#[derive(Debug, Clone, Copy)]
+pub struct S5Info {
+    pub pm1a_cnt: u16,
+    pub pm1b_cnt: u16,
+    pub slp_typ_a: u16,
+    pub slp_typ_b: u16,
+    smi_cmd: u32,
+    acpi_enable: u8,
+}
+
+/// Borrow `len` bytes of physical memory through the HHDM.
+fn phys_bytes(phys: u64, len: usize) -> &'static [u8] {
+    unsafe { core::slice::from_raw_parts(PhysAddr::new(phys).as_ptr::<u8>(), len) }
+}
+
+fn read_u32(b: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes(b[off..off + 4].try_into().unwrap())
+}
+
+fn read_u64(b: &[u8], off: usize) -> u64 {
+    u64::from_le_bytes(b[off..off + 8].try_into().unwrap())
+}
+
+/// Map a whole system description table, verifying its checksum.
+fn sdt(phys: u64) -> Option<&'static [u8]> {
+    if phys == 0 {
+        return None;
+    }
+    let len = read_u32(phys_bytes(phys, 36), 4) as usize;
+    if !(36..=16 * 1024 * 1024).contains(&len) {
+        return None;
+    }
+    let table = phys_bytes(phys, len);
+    let sum = table.iter().fold(0u8, |a, &b| a.wrapping_add(b));
+    if sum == 0 { Some(table) } else { None }
+}
+
+/// Find a table by signature via the XSDT (ACPI 2+) or RSDT.
+fn find_table(signature: &[u8; 4]) -> Option<&'static [u8]> {
+    let rsdp_phys = RSDP_PHYS.load(Ordering::Acquire);
+    if rsdp_phys == 0 {
+        return None;
+    }
+    let rsdp = phys_bytes(rsdp_phys, 36);
+    if &rsdp[0..8] != b"RSD PTR " {
+        return None;
+    }
+
+    let revision = rsdp[15];
+    let (root, entry_size) = if revision >= 2 && read_u64(rsdp, 24) != 0 {
+        (sdt(read_u64(rsdp, 24))?, 8)
+    } else {
+        (sdt(read_u32(rsdp, 16) as u64)?, 4)
+    };
+
+    root[36..].chunks_exact(entry_size).find_map(|entry| {
+        let phys = if entry_size == 8 { read_u64(entry, 0) } else { read_u32(entry, 0) as u64 };
+        sdt(phys).filter(|t| &t[0..4] == signature)
+    })
+}
+
+/// Decode one integer element of an AML package (ZeroOp, OneOp, BytePrefix…).
+fn aml_small_int(b: &[u8], i: &mut usize) -> Option<u16> {
+    let op = *b.get(*i)?;
+    *i += 1;
+    match op {
+        0x00 => Some(0),
+        0x01 => Some(1),
+        0x0A => {
+            let v = *b.get(*i)?;
+            *i += 1;
+            Some(v as u16)
+        }
+        0x0B => {
+            let v = u16::from_le_bytes([*b.get(*i)?, *b.get(*i + 1)?]);
+            *i += 2;
+            Some(v)
+        }
+        _ => None,
+    }
+}
+
+/// Scan DSDT AML for `Name(_S5_, Package() { SLP_TYPa, SLP_TYPb, ... })`.
+fn find_s5(dsdt: &[u8]) -> Option<(u16, u16)> {
+    let body = &dsdt[36..];
+    let pos = body.windows(4).position(|w| w == b"_S5_")?;
+
+    // Must be preceded by NameOp (optionally with a root prefix '\')
+    let name_op = pos >= 1 && body[pos - 1] == 0x08
+        || pos >= 2 && body[pos - 2] == 0x08 && body[pos - 1] == b'\\';
+    if !name_op || body.get(pos + 4) != Some(&0x12) {
+        return None; // Not a Name(…, Package) — e.g. a method reference
+    }
+
+    // PkgLength: top two bits of the lead byte = number of extra bytes
+    let mut i = pos + 5;
+    let lead = *body.get(i)?;
+    i += 1 + (lead >> 6) as usize;
+    i += 1; // NumElements
+
+    let a = aml_small_int(body, &mut i)?;
+    let b = aml_small_int(body, &mut i)?;
+    Some((a, b))
+}
+
+/// Gather everything needed to enter S5. None if ACPI is absent or odd.
+pub fn s5_info() -> Option<S5Info> {
+    let fadt = find_table(b"FACP")?;
+    if fadt.len() < 72 {
+        return None;
+    }
+
+    let dsdt_phys = if fadt.len() >= 148 && read_u64(fadt, 140) != 0 {
+        read_u64(fadt, 140)
+    } else {
+        read_u32(fadt, 40) as u64
+    };
+    let (slp_typ_a, slp_typ_b) = find_s5(sdt(dsdt_phys)?)?;
+
+    Some(S5Info {
+        pm1a_cnt: read_u32(fadt, 64) as u16,
+        pm1b_cnt: read_u32(fadt, 68) as u16,
+        slp_typ_a,
+        slp_typ_b,
+        smi_cmd: read_u32(fadt, 48),
+        acpi_enable: fadt[52],
+    })
+}
+
+/// Put the firmware into ACPI mode if it isn't already.
+fn enable_acpi(info: &S5Info) {
+    if inw(info.pm1a_cnt) & SCI_EN != 0 || info.smi_cmd == 0 || info.acpi_enable == 0 {
+        return;
+    }
+    outb(info.smi_cmd as u16, info.acpi_enable);
+    for _ in 0..300 {
+        if inw(info.pm1a_cnt) & SCI_EN != 0 {
+            return;
+        }
+        crate::time::delay(crate::time::Duration::from_millis(1));
+    }
+}
+
+/// Turn the machine off. Returns only if every method failed.
+///
+/// Callers must have flushed storage first — this cuts power immediately.
+pub fn poweroff() {
+    if let Some(info) = s5_info().filter(|i| i.pm1a_cnt != 0) {
+        enable_acpi(&info);
+        let a = (inw(info.pm1a_cnt) & !(0x7 << 10)) | (info.slp_typ_a << 10) | SLP_EN;
+        outw(info.pm1a_cnt, a);
+        if info.pm1b_cnt != 0 {
+            let b = (inw(info.pm1b_cnt) & !(0x7 << 10)) | (info.slp_typ_b << 10) | SLP_EN;
+            outw(info.pm1b_cnt, b);
+        }
+        crate::time::delay(crate::time::Duration::from_millis(100));
+    }
+
+    // Emulator fallbacks: QEMU (PIIX4/ICH9 PM base 0x600), Bochs and old
+    // QEMU, VirtualBox.
+    outw(0x604, 0x2000);
+    outw(0xB004, 0x2000);
+    outw(0x4004, 0x3400);
+    crate::time::delay(crate::time::Duration::from_millis(100));
+
+    // Last resort under QEMU with -device isa-debug-exit: exit status 33.
+    outl(0xf4, 0x10);
+}
@@ -25,6 +25,12 @@ pub fn has_rdrand() -> bool {
     ecx & (1 << 30) != 0
 }
 
+/// Check if AES-NI is supported (CPUID.01H:ECX.AES[bit 25]).
+pub fn has_aesni() -> bool {
+    let (_, _, ecx, _) = cpuid(1);
+    ecx & (1 << 25) != 0
+}
+
 /// Check if CLFLUSHOPT is supported (CPUID.07H.0:EBX.CLFLUSHOPT[bit 23]).
 pub fn has_clflushopt() -> bool {
     let (_, ebx, _, _) = cpuid_count(7, 0);
@@ -37,6 +43,42 @@ pub fn has_invariant_tsc() -> bool {
     edx & (1 << 8) != 0
 }
 
+/// Check for the local APIC's TSC-deadline timer mode (CPUID.01H:ECX[bit 24]).
+pub fn has_tsc_deadline() -> bool {
+    let (_, _, ecx, _) = cpuid(1);
+    ecx & (1 << 24) != 0
+}
+
+/// Check for x2APIC, the MSR interface to the local APIC (CPUID.01H:ECX[bit 21]).
+pub fn has_x2apic() -> bool {
+    let (_, _, ecx, _) = cpuid(1);
+    ecx & (1 << 21) != 0
+}
+
+/// Read a model-specific register.
+///
+/// # Safety
+/// `msr` must exist on this CPU, or RDMSR raises #GP.
+pub unsafe fn rdmsr(msr: u32) -> u64 {
+    let (lo, hi): (u32, u32);
+    core::arch::asm!("rdmsr", in("ecx") msr, out("eax") lo, out("edx") hi, options(nostack, preserves_flags));
+    ((hi as u64) << 32) | (lo as u64)
+}
+
+/// Write a model-specific register.
+///
+/// # Safety
+/// `msr` must exist on this CPU and accept `val`, or WRMSR raises #GP.
+pub unsafe fn wrmsr(msr: u32, val: u64) {
+    core::arch::asm!(
+        "wrmsr",
+        in("ecx") msr,
+        in("eax") val as u32,
+        in("edx") (val >> 32) as u32,
+        options(nostack, preserves_flags),
+    );
+}
+
 /// Read the Time Stamp Counter.
 #[inline(always)]
 pub fn rdtsc() -> u64 {
@@ -0,0 +1,192 @@
+/// FPU/SSE state policy.
+///
+/// The kernel is built with SSE2 (see the target spec), and SQLite, Lua
+/// and the JSON parser do f64 arithmetic in ordinary kernel code, so the
+/// x87/SSE unit is on for the whole kernel rather than saved lazily:
+///
+/// - `init` sets the control bits (CR0.MP/NE, CR4.OSFXSR/OSXMMEXCPT,
+///   EM and TS clear) and a known MXCSR, instead of trusting whatever the
+///   bootloader left behind.
+/// - There is one kernel thread, so the FPU registers belong to it. Once
+///   threads exist, each one carries an `FpuState` that the switch code
+///   saves and restores eagerly (`fxsave64`/`fxrstor64`).
+/// - Interrupt handlers must not touch the FPU, since they would clobber
+///   the interrupted code's registers. Handlers mark themselves with
+///   `InterruptContext::enter()`; with the `fpu-check` feature that also
+///   sets CR0.TS, so any x87/SSE instruction in the handler raises #NM and
+///   is reported (then allowed, to keep a debug build running).
+use core::sync::atomic::{AtomicU32, Ordering};
+
+const CR0_MP: u64 = 1 << 1;
+const CR0_EM: u64 = 1 << 2;
+const CR0_TS: u64 = 1 << 3;
+const CR0_NE: u64 = 1 << 5;
+const CR4_OSFXSR: u64 = 1 << 9;
+const CR4_OSXMMEXCPT: u64 = 1 << 10;
+
+/// MXCSR at reset: every exception masked, round to nearest.
+const MXCSR_DEFAULT: u32 = 0x1F80;
+
+/// Nesting depth of interrupt handlers currently running.
+static INTERRUPT_DEPTH: AtomicU32 = AtomicU32::new(0);
+
+/// #NM faults taken for FPU use inside a handler (`fpu-check` only).
+static MISUSE_COUNT: AtomicU32 = AtomicU32::new(0);
+
+fn read_cr0() -> u64 {
+    let v: u64;
+    unsafe { core::arch::asm!("mov {}, cr0", out(reg) v, options(nostack, nomem, preserves_flags)) };
+    v
+}
+
+unsafe fn write_cr0(v: u64) {
+    unsafe { core::arch::asm!("mov cr0, {}", in(reg) v, options(nostack, preserves_flags)) };
+}
+
+fn read_cr4() -> u64 {
+    let v: u64;
+    unsafe { core::arch::asm!("mov {}, cr4", out(reg) v, options(nostack, nomem, preserves_flags)) };
+    v
+}
+
+unsafe fn write_cr4(v: u64) {
+    unsafe { core::arch::asm!("mov cr4, {}", in(reg) v, options(nostack, preserves_flags)) };
+}
+
+/// Read MXCSR.
+pub fn mxcsr() -> u32 {
+    let mut v: u32 = 0;
+    unsafe { core::arch::asm!("stmxcsr [{}]", in(reg) &mut v, options(nostack, preserves_flags)) };
+    v
+}
+
+/// Write MXCSR.
+///
+/// # Safety
+/// Unmasking exceptions makes later SSE arithmetic fault (#XM).
+pub unsafe fn set_mxcsr(v: u32) {
+    unsafe { core::arch::asm!("ldmxcsr [{}]", in(reg) &v, options(nostack, preserves_flags)) };
+}
+
+/// Enable the FPU and SSE with a clean state. Returns an error naming a
+/// missing feature; the kernel can't run correctly without them.
+///
+/// # Safety
+/// Call once during boot, before SQLite or Lua run.
+pub unsafe fn init() -> Result<(), &'static str> {
+    let (_, _, _, edx) = super::cpu::cpuid(1);
+    if edx & (1 << 24) == 0 {
+        return Err("FXSAVE/FXRSTOR");
+    }
+    if edx & (1 << 26) == 0 {
+        return Err("SSE2");
+    }
+    unsafe {
+        write_cr0((read_cr0() & !(CR0_EM | CR0_TS)) | CR0_MP | CR0_NE);
+        write_cr4(read_cr4() | CR4_OSFXSR | CR4_OSXMMEXCPT);
+        core::arch::asm!("fninit", options(nostack, nomem));
+        set_mxcsr(MXCSR_DEFAULT);
+    }
+    Ok(())
+}
+
+/// Saved x87/SSE registers of one thread (the FXSAVE area).
+#[repr(C, align(16))]
+pub struct FpuState([u8; 512]);
+
+impl FpuState {
+    /// The state `init` leaves: a fresh thread starts from this.
+    pub fn new() -> Self {
+        let mut s = FpuState([0; 512]);
+        // FCW = 0x037F (all x87 exceptions masked), MXCSR at offset 24
+        s.0[0..2].copy_from_slice(&0x037Fu16.to_le_bytes());
+        s.0[24..28].copy_from_slice(&MXCSR_DEFAULT.to_le_bytes());
+        s
+    }
+
+    /// Save the current registers into this area.
+    pub fn save(&mut self) {
+        unsafe { core::arch::asm!("fxsave64 [{}]", in(reg) self.0.as_mut_ptr(), options(nostack, preserves_flags)) };
+    }
+
+    /// Load the registers from this area.
+    pub fn restore(&self) {
+        unsafe { core::arch::asm!("fxrstor64 [{}]", in(reg) self.0.as_ptr(), options(nostack, preserves_flags)) };
+    }
+
+    /// MXCSR as saved.
+    pub fn saved_mxcsr(&self) -> u32 {
+        u32::from_le_bytes([self.0[24], self.0[25], self.0[26], self.0[27]])
+    }
+}
+
+impl Default for FpuState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Marks an interrupt handler's extent; see the module docs.
+pub struct InterruptContext {
+    /// CR0.TS before entry, restored on exit.
+    was_trapping: bool,
+}
+
+impl InterruptContext {
+    pub fn enter() -> Self {
+        INTERRUPT_DEPTH.fetch_add(1, Ordering::Relaxed);
+        let was_trapping = read_cr0() & CR0_TS != 0;
+        if cfg!(feature = "fpu-check") && !was_trapping {
+            unsafe { write_cr0(read_cr0() | CR0_TS) };
+        }
+        InterruptContext { was_trapping }
+    }
+}
+
+impl Drop for InterruptContext {
+    fn drop(&mut self) {
+        if cfg!(feature = "fpu-check") && !self.was_trapping {
+            unsafe { core::arch::asm!("clts", options(nostack, nomem)) };
+        }
+        INTERRUPT_DEPTH.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Is an interrupt handler running?
+pub fn in_interrupt() -> bool {
+    INTERRUPT_DEPTH.load(Ordering::Relaxed) > 0
+}
+
+/// #NM handler hook. True if the fault was a `fpu-check` trap on FPU use
+/// inside a handler — reported, TS cleared, and the instruction retried.
+/// False means a genuine #NM.
+pub fn handle_nm(rip: u64) -> bool {
+    if !cfg!(feature = "fpu-check") || !in_interrupt() {
+        return false;
+    }
+    unsafe { core::arch::asm!("clts", options(nostack, nomem)) };
+    MISUSE_COUNT.fetch_add(1, Ordering::Relaxed);
+    crate::serial_println!("[fpu] FPU/SSE used in interrupt context at {:#x}", rip);
+    true
+}
+
+/// How many times `fpu-check` caught FPU use in a handler.
+pub fn misuse_count() -> u32 {
+    MISUSE_COUNT.load(Ordering::Relaxed)
+}
+
+/// One-line summary of the control state, for the boot log.
+pub fn describe() -> alloc::string::String {
+    let (cr0, cr4) = (read_cr0(), read_cr4());
+    alloc::format!(
+        "MP={} EM={} TS={} NE={} OSFXSR={} OSXMMEXCPT={} MXCSR={:#06x}{}",
+        (cr0 & CR0_MP != 0) as u8,
+        (cr0 & CR0_EM != 0) as u8,
+        (cr0 & CR0_TS != 0) as u8,
+        (cr0 & CR0_NE != 0) as u8,
+        (cr4 & CR4_OSFXSR != 0) as u8,
+        (cr4 & CR4_OSXMMEXCPT != 0) as u8,
+        mxcsr(),
+        if cfg!(feature = "fpu-check") { " (fpu-check)" } else { "" }
+    )
+}
@@ -0,0 +1,161 @@
+/// Idle — wait for the next timer tick instead of spinning.
+///
+/// The kernel polls its devices, and a poll loop that spins keeps a host
+/// core at 100% under QEMU. `wait` stops the CPU until the next tick of
+/// PIT channel 0, programmed at `TICK_HZ`, so a loop that calls it in
+/// place of `spin_loop` costs next to nothing while nothing happens and
+/// notices what does within a tick.
+///
+/// Interrupts stay disabled (IF=0) everywhere else: IRQ0 is unmasked at
+/// the PIC but only let in by `wait` itself — `sti; hlt; cli`, or, where
+/// CPUID offers MONITOR/MWAIT with interrupts as break events even when
+/// masked, `mwait` followed by a one-instruction `sti` window. The tick
+/// is taken by the PIC stub (EOI only); the TSC stays the clock.
+///
+/// Devices without an interrupt of their own (NVMe, virtio-net) are still
+/// polled: the NVMe completion loops spin, since a command completes in
+/// microseconds and a tick would cost a millisecond each. Network waits,
+/// the idle prompt and `time` delays idle.
+///
+/// Time spent in `wait` is counted for `/sys/cpu`.
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+
+use super::cpu::{cpuid, rdtsc};
+use super::outb;
+
+/// Timer ticks per second: how late a waiting loop may notice an event.
+pub const TICK_HZ: u64 = 1000;
+
+const PIT_CH0_DATA: u16 = 0x40;
+const PIT_CMD: u16 = 0x43;
+const PIT_FREQ: u64 = 1_193_182;
+
+/// How `wait` stops the CPU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Not set up yet (early boot): `wait` spins once.
+    Spin = 0,
+    Hlt = 1,
+    Mwait = 2,
+}
+
+impl Mode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Mode::Spin => "spin",
+            Mode::Hlt => "hlt",
+            Mode::Mwait => "mwait",
+        }
+    }
+}
+
+static MODE: AtomicU8 = AtomicU8::new(Mode::Spin as u8);
+
+/// TSC ticks spent in `wait`.
+static IDLE_TSC: AtomicU64 = AtomicU64::new(0);
+
+/// Monitored by MWAIT; nothing writes it, the tick is the wake-up.
+#[repr(align(64))]
+struct MonitorLine([u8; 64]);
+static MONITOR_LINE: MonitorLine = MonitorLine([0; 64]);
+
+/// Has `init` run?
+static READY: AtomicBool = AtomicBool::new(false);
+
+/// Start the tick and pick hlt or MWAIT. Call once, after the IDT is
+/// loaded and the PIC remapped.
+pub fn init() -> Mode {
+    let divisor = (PIT_FREQ / TICK_HZ) as u16;
+    outb(PIT_CMD, 0x34); // channel 0, lobyte/hibyte, mode 2 (rate generator)
+    outb(PIT_CH0_DATA, divisor as u8);
+    outb(PIT_CH0_DATA, (divisor >> 8) as u8);
+    super::pic::unmask(0);
+
+    let mode = if mwait_wakes_masked() { Mode::Mwait } else { Mode::Hlt };
+    MODE.store(mode as u8, Ordering::Relaxed);
+    READY.store(true, Ordering::Release);
+    mode
+}
+
+/// MONITOR/MWAIT present (CPUID.1:ECX[3]), with the extensions leaf
+/// saying an interrupt ends MWAIT even with IF=0 (CPUID.5:ECX[0,1]).
+fn mwait_wakes_masked() -> bool {
+    let (max_leaf, _, _, _) = cpuid(0);
+    if max_leaf < 5 || cpuid(1).2 & (1 << 3) == 0 {
+        return false;
+    }
+    cpuid(5).2 & 0b11 == 0b11
+}
+
+/// How `wait` stops the CPU.
+pub fn mode() -> Mode {
+    match MODE.load(Ordering::Relaxed) {
+        1 => Mode::Hlt,
+        2 => Mode::Mwait,
+        _ => Mode::Spin,
+    }
+}
+
+/// Stop the CPU until the next tick (at most 1/`TICK_HZ` s). Before
+/// `init`, a single `spin_loop`.
+pub fn wait() {
+    if !READY.load(Ordering::Acquire) {
+        core::hint::spin_loop();
+        return;
+    }
+    let start = rdtsc();
+    match mode() {
+        Mode::Mwait => unsafe {
+            core::arch::asm!(
+                "monitor",
+                in("rax") MONITOR_LINE.0.as_ptr(),
+                in("ecx") 0u32,
+                in("edx") 0u32,
+                options(nostack, readonly),
+            );
+            // C1, and wake on interrupts though IF=0 (ECX bit 0)
+            core::arch::asm!("mwait", in("eax") 0u32, in("ecx") 1u32, options(nostack, nomem));
+            // Take the pending tick: the instruction after STI runs first
+            core::arch::asm!("sti", "nop", "cli", options(nostack));
+        },
+        // STI holds interrupts off until after HLT, so a tick can't slip in between
+        Mode::Hlt | Mode::Spin => unsafe { core::arch::asm!("sti", "hlt", "cli", options(nostack)) },
+    }
+    IDLE_TSC.fetch_add(rdtsc() - start, Ordering::Relaxed);
+}
+
+/// Like `wait`, but also woken by the local APIC once the TSC reaches
+/// `tsc`, where it has a deadline timer — so the wait can end between
+/// ticks.
+pub fn wait_until(tsc: u64) {
+    let armed = super::lapic::arm(tsc);
+    wait();
+    if armed {
+        super::lapic::disarm();
+    }
+}
+
+/// Milliseconds spent in `wait` since boot.
+pub fn idle_ms() -> u64 {
+    crate::time::duration(IDLE_TSC.load(Ordering::Relaxed)).as_millis() as u64
+}
+
+/// Share of the time since boot spent in `wait`, in percent.
+pub fn idle_percent() -> u64 {
+    let up = crate::time::monotonic_ms();
+    (idle_ms() * 100).checked_div(up).unwrap_or(0).min(100)
+}
+
+/// `/sys/cpu`: the idle mode and how much time went to it.
+pub fn report() -> alloc::vec::Vec<u8> {
+    alloc::format!(
+        "idle_mode: {}\ntick_hz: {}\ndeadline_timer: {}\nuptime_ms: {}\nidle_ms: {}\nidle_percent: {}\n",
+        mode().as_str(),
+        TICK_HZ,
+        if super::lapic::deadline_timer() { "tsc" } else { "none" },
+        crate::time::monotonic_ms(),
+        idle_ms(),
+        idle_percent()
+    )
+    .into_bytes()
+}
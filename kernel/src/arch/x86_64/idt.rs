@@ -12,6 +12,9 @@
 /// - #DF (8)  Double fault (uses IST1 for safe stack)
 /// - #GP (13) General protection fault
 /// - #PF (14) Page fault (detects guard page = stack overflow)
+///
+/// and the interrupts that wake an idle wait: the PIC IRQs (32-47) and
+/// the local APIC's deadline timer (48) and spurious vector (255).
 use super::gdt;
 use core::sync::atomic::Ordering;
 
@@ -144,6 +147,12 @@ pub unsafe fn init() {
             idt.entries[i] = IdtEntry::interrupt_gate(isr_irq_stub as *const () as u64);
         }
 
+        // Local APIC (see lapic.rs)
+        idt.entries[super::lapic::TIMER_VECTOR as usize] =
+            IdtEntry::interrupt_gate(isr_deadline as *const () as u64);
+        idt.entries[super::lapic::SPURIOUS_VECTOR as usize] =
+            IdtEntry::interrupt_gate(isr_spurious as *const () as u64);
+
         idt
     });
 
@@ -178,6 +187,7 @@ extern "x86-interrupt" fn isr_nmi(frame: InterruptFrame) {
 }
 
 extern "x86-interrupt" fn isr_bp(frame: InterruptFrame) {
+    let _ctx = super::fpu::InterruptContext::enter();
     // Breakpoint — don't halt, just log
     crate::serial_println!("[int] Breakpoint at {:#x}", frame.rip);
 }
@@ -195,6 +205,10 @@ extern "x86-interrupt" fn isr_ud(frame: InterruptFrame) {
 }
 
 extern "x86-interrupt" fn isr_nm(frame: InterruptFrame) {
+    // An `fpu-check` trap on FPU use inside a handler, not a real fault
+    if super::fpu::handle_nm(frame.rip) {
+        return;
+    }
     exception_handler("Device not available (#NM)", &frame, None);
 }
 
@@ -255,10 +269,20 @@ extern "x86-interrupt" fn isr_pf(frame: InterruptFrame, error_code: u64) {
 }
 
 extern "x86-interrupt" fn isr_irq_stub(_frame: InterruptFrame) {
+    let _ctx = super::fpu::InterruptContext::enter();
     // Send EOI to PIC (both master and slave for safety)
     super::pic::send_eoi_both();
 }
 
+/// TSC deadline reached: the wait it ended carries on by itself.
+extern "x86-interrupt" fn isr_deadline(_frame: InterruptFrame) {
+    let _ctx = super::fpu::InterruptContext::enter();
+    super::lapic::eoi();
+}
+
+/// APIC spurious interrupt: nothing to acknowledge.
+extern "x86-interrupt" fn isr_spurious(_frame: InterruptFrame) {}
+
 /// Common exception reporting.
 fn exception_handler(name: &str, frame: &InterruptFrame, error_code: Option<u64>) {
     crate::serial_println!("!!! CPU EXCEPTION: {} !!!", name);
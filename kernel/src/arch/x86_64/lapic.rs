@@ -0,0 +1,98 @@
+/// Local APIC — the TSC-deadline one-shot timer, through x2APIC.
+///
+/// In TSC-deadline mode the local APIC raises its timer interrupt once
+/// the TSC reaches the value written to IA32_TSC_DEADLINE; writing 0
+/// disarms it. `idle::wait_until` arms it so a wait ends on its deadline
+/// rather than on the next PIT tick.
+///
+/// Only the x2APIC (MSR) interface is used, so no MMIO page has to be
+/// mapped. Without both x2APIC and TSC-deadline in CPUID, `init` leaves
+/// the APIC alone and waits fall back to the tick.
+///
+/// The PIC still delivers the tick through LINT0 (virtual wire). If the
+/// firmware left the APIC software-disabled, its LVTs are masked, so
+/// enabling it also sets LINT0 to ExtINT and LINT1 to NMI, as the
+/// firmware would have.
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use super::cpu::{has_tsc_deadline, has_x2apic, rdmsr, wrmsr};
+
+/// Vector of the deadline interrupt (just above the PIC's 32-47).
+pub const TIMER_VECTOR: u8 = 48;
+
+/// Vector the APIC uses for spurious interrupts; needs no EOI.
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+const IA32_APIC_BASE: u32 = 0x1B;
+const IA32_TSC_DEADLINE: u32 = 0x6E0;
+const X2APIC_EOI: u32 = 0x80B;
+const X2APIC_SVR: u32 = 0x80F;
+const X2APIC_LVT_TIMER: u32 = 0x832;
+const X2APIC_LVT_LINT0: u32 = 0x835;
+const X2APIC_LVT_LINT1: u32 = 0x836;
+
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_X2APIC: u64 = 1 << 10;
+const SVR_ENABLE: u64 = 1 << 8;
+const LVT_TSC_DEADLINE: u64 = 0b10 << 17;
+const LVT_EXTINT: u64 = 0b111 << 8;
+const LVT_NMI: u64 = 0b100 << 8;
+
+/// Is the deadline timer set up?
+static READY: AtomicBool = AtomicBool::new(false);
+
+/// Switch the local APIC to x2APIC mode and its timer to TSC-deadline.
+/// Returns false, touching nothing, if the CPU lacks either. Call once,
+/// after the IDT is loaded.
+pub fn init() -> bool {
+    if !has_x2apic() || !has_tsc_deadline() {
+        return false;
+    }
+    unsafe {
+        // Disabled → x2APIC must go through xAPIC (a direct switch is #GP)
+        let base = rdmsr(IA32_APIC_BASE);
+        if base & APIC_BASE_ENABLE == 0 {
+            wrmsr(IA32_APIC_BASE, base | APIC_BASE_ENABLE);
+        }
+        wrmsr(IA32_APIC_BASE, base | APIC_BASE_ENABLE | APIC_BASE_X2APIC);
+
+        let svr = rdmsr(X2APIC_SVR);
+        if svr & SVR_ENABLE == 0 {
+            wrmsr(X2APIC_LVT_LINT0, LVT_EXTINT);
+            wrmsr(X2APIC_LVT_LINT1, LVT_NMI);
+        }
+        wrmsr(X2APIC_SVR, SVR_ENABLE | SPURIOUS_VECTOR as u64);
+
+        wrmsr(IA32_TSC_DEADLINE, 0);
+        wrmsr(X2APIC_LVT_TIMER, LVT_TSC_DEADLINE | TIMER_VECTOR as u64);
+    }
+    READY.store(true, Ordering::Release);
+    true
+}
+
+/// Is the deadline timer available?
+pub fn deadline_timer() -> bool {
+    READY.load(Ordering::Acquire)
+}
+
+/// Raise the timer interrupt once the TSC reaches `tsc` (at once if it
+/// already has). Replaces any earlier deadline. False without the timer.
+pub fn arm(tsc: u64) -> bool {
+    if !deadline_timer() {
+        return false;
+    }
+    unsafe { wrmsr(IA32_TSC_DEADLINE, tsc.max(1)) };
+    true
+}
+
+/// Cancel the deadline, if one is pending.
+pub fn disarm() {
+    if deadline_timer() {
+        unsafe { wrmsr(IA32_TSC_DEADLINE, 0) };
+    }
+}
+
+/// Signal end of interrupt to the local APIC.
+pub fn eoi() {
+    unsafe { wrmsr(X2APIC_EOI, 0) };
+}
@@ -4,11 +4,19 @@
 /// - Port I/O (in/out instructions)
 /// - Serial console (COM1) for debug output
 /// - CPU feature detection
+/// - FPU/SSE state policy
 /// - Interrupt descriptor table (IDT) skeleton
+/// - ACPI power-off
+/// - Idle waits (hlt/MWAIT on a timer tick)
+/// - Local APIC TSC-deadline timer (x2APIC)
+pub mod acpi;
 pub mod serial;
 pub mod cpu;
+pub mod fpu;
 pub mod gdt;
+pub mod idle;
 pub mod idt;
+pub mod lapic;
 pub mod pic;
 pub mod timer;
 
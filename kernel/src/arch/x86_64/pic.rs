@@ -1,8 +1,9 @@
 /// 8259 PIC (Programmable Interrupt Controller) — remap and mask.
 ///
 /// The legacy PIC maps IRQ 0-7 to interrupts 8-15, which collides with
-/// CPU exceptions. We remap IRQs to 32-47, then mask all of them since
-/// we don't use hardware IRQs yet (NVMe uses polling, not MSI-X).
+/// CPU exceptions. We remap IRQs to 32-47, then mask all of them: devices
+/// are polled (NVMe uses polling, not MSI-X). Only the idle tick (IRQ0,
+/// see idle.rs) is unmasked later.
 
 const PIC1_CMD: u16 = 0x20;
 const PIC1_DATA: u16 = 0x21;
@@ -57,6 +58,12 @@ pub unsafe fn init() {
     let _ = (mask1, mask2); // original masks preserved if needed later
 }
 
+/// Let IRQ `irq` (0-7, master PIC) through.
+pub fn unmask(irq: u8) {
+    use super::{outb, inb};
+    outb(PIC1_DATA, inb(PIC1_DATA) & !(1 << irq));
+}
+
 /// Send End-of-Interrupt to both PICs.
 pub fn send_eoi_both() {
     super::outb(PIC2_CMD, EOI);
@@ -2,20 +2,170 @@
 ///
 /// Output: debug logging via serial_println!
 /// Input: interactive shell via read_byte / try_read_byte
+///
+/// Output goes out a FIFO-load at a time. Between loads the port honours
+/// the configured flow control: XON/XOFF from the host (the control bytes
+/// are swallowed, anything else read meanwhile is kept for the next
+/// read), or the CTS line. A host that stops us for longer than
+/// `STALL_MS` is ignored rather than allowed to hang the kernel.
+///
+/// A Ctrl-C read from the UART, whoever reads it, raises the interrupt
+/// flag: long-running work polls `interrupted` and stops, and the flag
+/// stays up until `clear_interrupt` — the shell's, once it waits for the
+/// next key — so every layer on the way out sees it. The byte itself is
+/// dropped once `interrupted` has seen it.
 use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
 use spin::Mutex;
 
+use crate::time;
+
 const COM1: u16 = 0x3F8;
 
+/// Standard PC UART input clock (1.8432 MHz); its fastest rate is 115200.
+pub const DEFAULT_CLOCK: u32 = 1_843_200;
+
+/// Fastest rate `set_baud` accepts (needs a 24 MHz UART clock).
+pub const MAX_BAUD: u32 = 1_500_000;
+
+/// 16550 transmit FIFO depth: bytes written per "transmit empty".
+const TX_FIFO: usize = 16;
+
+/// Longest a flow-control stop is honoured before output resumes anyway.
+const STALL_MS: u64 = 2_000;
+
+/// Input read while waiting to transmit, kept for read_byte.
+const PENDING_LEN: usize = 64;
+
+const XON: u8 = 0x11;
+const XOFF: u8 = 0x13;
+const CTRL_C: u8 = 0x03;
+
 pub static SERIAL: Mutex<Serial> = Mutex::new(Serial::new(COM1));
 
+/// Raised when Ctrl-C arrives; see the module doc.
+static INTERRUPT: AtomicBool = AtomicBool::new(false);
+
+/// Has Ctrl-C been typed since the last `clear_interrupt`? Input waiting
+/// in the UART is looked at and kept for the next read, all but the
+/// Ctrl-C itself: the flag carries it, and the next prompt mustn't take
+/// it for a fresh one.
+pub fn interrupted() -> bool {
+    if let Some(mut serial) = SERIAL.try_lock() {
+        serial.poll_input();
+        serial.pending.remove(CTRL_C);
+    }
+    INTERRUPT.load(Ordering::Relaxed)
+}
+
+/// Raise the interrupt flag as a typed Ctrl-C would.
+pub fn raise_interrupt() {
+    INTERRUPT.store(true, Ordering::Relaxed);
+}
+
+/// Lower the interrupt flag: the Ctrl-C has been dealt with.
+pub fn clear_interrupt() {
+    INTERRUPT.store(false, Ordering::Relaxed);
+}
+
+/// Output flow control (`config set serial.flow`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlowControl {
+    None,
+    /// Software: the host sends XOFF (Ctrl-S) to pause, XON (Ctrl-Q) to resume.
+    XonXoff,
+    /// Hardware: transmit only while CTS is asserted.
+    RtsCts,
+}
+
+impl FlowControl {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "none" | "off" => Some(FlowControl::None),
+            "xon" | "xonxoff" => Some(FlowControl::XonXoff),
+            "rts" | "rtscts" => Some(FlowControl::RtsCts),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FlowControl::None => "none",
+            FlowControl::XonXoff => "xon",
+            FlowControl::RtsCts => "rts",
+        }
+    }
+}
+
 pub struct Serial {
     port: u16,
+    clock: u32,
+    baud: u32,
+    flow: FlowControl,
+    /// XOFF received and no XON since.
+    stopped: bool,
+    /// Input bytes drained while transmitting.
+    pending: Pending,
+}
+
+/// Ring of input read ahead of `read_byte`, oldest first. When full the
+/// newest bytes are dropped.
+pub(crate) struct Pending {
+    bytes: [u8; PENDING_LEN],
+    head: usize,
+    len: usize,
+}
+
+impl Pending {
+    pub(crate) const fn new() -> Self {
+        Self { bytes: [0u8; PENDING_LEN], head: 0, len: 0 }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub(crate) fn push(&mut self, b: u8) {
+        if self.len < PENDING_LEN {
+            self.bytes[(self.head + self.len) % PENDING_LEN] = b;
+            self.len += 1;
+        }
+    }
+
+    pub(crate) fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        let b = self.bytes[self.head];
+        self.head = (self.head + 1) % PENDING_LEN;
+        self.len -= 1;
+        Some(b)
+    }
+
+    /// Take every `byte` out, keeping the rest in order.
+    pub(crate) fn remove(&mut self, byte: u8) {
+        let mut kept = 0;
+        for i in 0..self.len {
+            let b = self.bytes[(self.head + i) % PENDING_LEN];
+            if b != byte {
+                self.bytes[(self.head + kept) % PENDING_LEN] = b;
+                kept += 1;
+            }
+        }
+        self.len = kept;
+    }
 }
 
 impl Serial {
     pub const fn new(port: u16) -> Self {
-        Self { port }
+        Self {
+            port,
+            clock: DEFAULT_CLOCK,
+            baud: 115_200,
+            flow: FlowControl::None,
+            stopped: false,
+            pending: Pending::new(),
+        }
     }
 
     /// Initialize the serial port (8N1, 115200 baud).
@@ -29,6 +179,48 @@ impl Serial {
         super::outb(self.port + 4, 0x0B); // IRQs enabled, RTS/DSR set
     }
 
+    // ---- Configuration ----
+
+    /// Reprogram the baud rate. `clock` is the UART input clock in Hz —
+    /// `DEFAULT_CLOCK` on a PC, 24 MHz on boards that reach 1.5 Mbps.
+    /// Rates the divisor cannot hit within 3% are refused.
+    pub fn set_baud(&mut self, baud: u32, clock: u32) -> Result<(), &'static str> {
+        if baud == 0 || baud > MAX_BAUD {
+            return Err("baud must be between 1 and 1500000");
+        }
+        let divisor = divisor_for(baud, clock).ok_or("baud not reachable from this UART clock")?;
+
+        // Let the FIFO drain at the old rate first
+        while !self.is_transmit_empty() {
+            core::hint::spin_loop();
+        }
+        let lcr = super::inb(self.port + 3);
+        super::outb(self.port + 3, lcr | 0x80);
+        super::outb(self.port, divisor as u8);
+        super::outb(self.port + 1, (divisor >> 8) as u8);
+        super::outb(self.port + 3, lcr & !0x80);
+        self.clock = clock;
+        self.baud = baud;
+        Ok(())
+    }
+
+    pub fn set_flow(&mut self, flow: FlowControl) {
+        self.flow = flow;
+        self.stopped = false;
+    }
+
+    pub fn baud(&self) -> u32 {
+        self.baud
+    }
+
+    pub fn clock(&self) -> u32 {
+        self.clock
+    }
+
+    pub fn flow(&self) -> FlowControl {
+        self.flow
+    }
+
     // ---- Output ----
 
     /// Check if the transmit buffer is empty.
@@ -36,51 +228,156 @@ impl Serial {
         super::inb(self.port + 5) & 0x20 != 0
     }
 
+    /// Clear To Send (MSR bit 4).
+    fn cts(&self) -> bool {
+        super::inb(self.port + 6) & 0x10 != 0
+    }
+
     /// Write a single byte, waiting for the transmit buffer.
-    pub fn write_byte(&self, byte: u8) {
+    pub fn write_byte(&mut self, byte: u8) {
+        self.wait_clear_to_send();
         while !self.is_transmit_empty() {
             core::hint::spin_loop();
         }
         super::outb(self.port, byte);
     }
 
-    /// Write a string.
-    pub fn write_str_raw(&self, s: &str) {
+    /// Write bytes a FIFO-load at a time, checking flow control between
+    /// loads. No other conversion is done.
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        for chunk in bytes.chunks(TX_FIFO) {
+            self.wait_clear_to_send();
+            while !self.is_transmit_empty() {
+                core::hint::spin_loop();
+            }
+            for &b in chunk {
+                super::outb(self.port, b);
+            }
+            // A chunk boundary is where a scheduler would let others run
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Write a string, expanding `\n` to `\r\n`.
+    pub fn write_str_raw(&mut self, s: &str) {
+        let mut buf = [0u8; TX_FIFO];
+        let mut n = 0;
         for byte in s.bytes() {
             if byte == b'\n' {
-                self.write_byte(b'\r');
+                if n == TX_FIFO {
+                    self.write_bytes(&buf);
+                    n = 0;
+                }
+                buf[n] = b'\r';
+                n += 1;
+            }
+            if n == TX_FIFO {
+                self.write_bytes(&buf);
+                n = 0;
             }
-            self.write_byte(byte);
+            buf[n] = byte;
+            n += 1;
+        }
+        self.write_bytes(&buf[..n]);
+    }
+
+    /// Wait until the host lets us transmit, or until `STALL_MS` passes.
+    fn wait_clear_to_send(&mut self) {
+        match self.flow {
+            FlowControl::None => {}
+            FlowControl::XonXoff => {
+                self.poll_input();
+                if !self.stopped {
+                    return;
+                }
+                let start = time::monotonic_ms();
+                while self.stopped && time::monotonic_ms() - start < STALL_MS {
+                    core::hint::spin_loop();
+                    self.poll_input();
+                }
+                self.stopped = false;
+            }
+            FlowControl::RtsCts => {
+                if self.cts() {
+                    return;
+                }
+                let start = time::monotonic_ms();
+                while !self.cts() && time::monotonic_ms() - start < STALL_MS {
+                    core::hint::spin_loop();
+                }
+            }
+        }
+    }
+
+    /// Move waiting input into the pending ring, acting on XON/XOFF.
+    fn poll_input(&mut self) {
+        while let Some(b) = self.rx_byte() {
+            self.pending.push(b);
         }
     }
 
     // ---- Input ----
 
     /// Check if a byte is available to read (LSR bit 0 = Data Ready).
-    pub fn has_data(&self) -> bool {
+    fn uart_has_data(&self) -> bool {
         super::inb(self.port + 5) & 0x01 != 0
     }
 
+    /// Next byte from the UART, consuming XON/XOFF when that mode is on.
+    fn rx_byte(&mut self) -> Option<u8> {
+        while self.uart_has_data() {
+            let b = super::inb(self.port);
+            if self.flow == FlowControl::XonXoff && (b == XON || b == XOFF) {
+                self.stopped = b == XOFF;
+                continue;
+            }
+            if b == CTRL_C {
+                raise_interrupt();
+            }
+            return Some(b);
+        }
+        None
+    }
+
+    /// Check if a byte is available to read.
+    pub fn has_data(&self) -> bool {
+        !self.pending.is_empty() || self.uart_has_data()
+    }
+
     /// Read a byte, blocking until one is available.
-    pub fn read_byte(&self) -> u8 {
-        while !self.has_data() {
+    pub fn read_byte(&mut self) -> u8 {
+        loop {
+            if let Some(b) = self.try_read_byte() {
+                return b;
+            }
             core::hint::spin_loop();
         }
-        super::inb(self.port)
     }
 
     /// Try to read a byte without blocking. Returns None if no data available.
-    pub fn try_read_byte(&self) -> Option<u8> {
-        if self.has_data() {
-            Some(super::inb(self.port))
-        } else {
-            None
-        }
+    pub fn try_read_byte(&mut self) -> Option<u8> {
+        self.pending.pop().or_else(|| self.rx_byte())
+    }
+}
+
+/// Divisor latch value for `baud` from `clock`, if within 3% of the rate.
+pub(crate) fn divisor_for(baud: u32, clock: u32) -> Option<u16> {
+    let base = clock as u64 / 16;
+    let baud = baud as u64;
+    let divisor = ((base + baud / 2) / baud).max(1);
+    if divisor > u16::MAX as u64 {
+        return None;
+    }
+    let actual = base / divisor;
+    if actual.abs_diff(baud) * 100 > baud * 3 {
+        return None;
     }
+    Some(divisor as u16)
 }
 
 impl fmt::Write for Serial {
     fn write_str(&mut self, s: &str) -> fmt::Result {
+        crate::klog::record(s);
         self.write_str_raw(s);
         Ok(())
     }
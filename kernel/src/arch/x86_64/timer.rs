@@ -6,7 +6,7 @@
 ///   2. Read TSC before and after the PIT counts down
 ///   3. Compute TSC frequency = delta_tsc / known_delay
 ///
-/// After calibration, `monotonic_ms()` converts TSC ticks to milliseconds.
+/// After calibration, `crate::time` turns TSC ticks into time.
 use core::sync::atomic::{AtomicU64, Ordering};
 use super::{outb, inb};
 use super::cpu::rdtsc;
@@ -37,6 +37,22 @@ const PIT_FREQ: u64 = 1_193_182;
 /// # Safety
 /// Must be called during boot, with interrupts disabled.
 pub fn calibrate_tsc() {
+    let (freq_hz, tsc_end) = measure_tsc();
+    let per_ms = freq_hz / 1000;
+
+    TSC_FREQ_HZ.store(freq_hz, Ordering::Release);
+    TSC_PER_MS.store(per_ms, Ordering::Release);
+    BOOT_TSC.store(tsc_end, Ordering::Release);
+}
+
+/// Time the TSC against the PIT again without changing the calibration,
+/// to see whether it still holds (`selftest`).
+pub fn measure_tsc_hz() -> u64 {
+    measure_tsc().0
+}
+
+/// TSC frequency over one ~10 ms PIT countdown, and the TSC at its end.
+fn measure_tsc() -> (u64, u64) {
     // Target: ~10ms calibration window.
     // PIT counter value for 10ms: 1_193_182 * 0.010 = 11_932
     let pit_count: u16 = 11_932;  // ~10.0006 ms
@@ -74,12 +90,7 @@ pub fn calibrate_tsc() {
 
     // 8. Compute TSC frequency
     let delta = tsc_end - tsc_start;
-    let freq_hz = (delta * 1_000_000) / expected_us;
-    let per_ms = freq_hz / 1000;
-
-    TSC_FREQ_HZ.store(freq_hz, Ordering::Release);
-    TSC_PER_MS.store(per_ms, Ordering::Release);
-    BOOT_TSC.store(tsc_end, Ordering::Release);
+    ((delta * 1_000_000) / expected_us, tsc_end)
 }
 
 /// Get the calibrated TSC frequency in Hz.
@@ -92,29 +103,7 @@ pub fn tsc_per_ms() -> u64 {
     TSC_PER_MS.load(Ordering::Acquire)
 }
 
-/// Milliseconds since boot. Uses calibrated TSC.
-pub fn monotonic_ms() -> u64 {
-    let boot = BOOT_TSC.load(Ordering::Acquire);
-    let now = rdtsc();
-    let per_ms = TSC_PER_MS.load(Ordering::Acquire);
-    if per_ms == 0 {
-        return 0;
-    }
-    (now - boot) / per_ms
-}
-
-/// Seconds since boot.
-pub fn uptime_secs() -> u64 {
-    monotonic_ms() / 1000
-}
-
-/// Busy-wait for the specified number of microseconds using calibrated TSC.
-pub fn delay_us(us: u64) {
-    let per_ms = TSC_PER_MS.load(Ordering::Acquire);
-    // per_ms = ticks/ms, so ticks/us = per_ms/1000
-    let target_ticks = us * per_ms / 1000;
-    let start = rdtsc();
-    while rdtsc() - start < target_ticks {
-        core::hint::spin_loop();
-    }
+/// TSC value at the end of calibration: time zero for `time`.
+pub fn boot_tsc() -> u64 {
+    BOOT_TSC.load(Ordering::Acquire)
 }
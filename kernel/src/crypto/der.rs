@@ -1,5 +1,6 @@
 /// Minimal DER/X.509 parser — extracts the SPKI (Subject Public Key Info)
-/// field from an X.509 certificate encoded in DER.
+/// field, the validity period and the subjectAltName DNS names from an
+/// X.509 certificate encoded in DER.
 ///
 /// X.509 structure (simplified):
 /// ```text
@@ -12,7 +13,7 @@
 ///         validity        SEQUENCE { notBefore, notAfter },
 ///         subject         Name (SEQUENCE),
 ///         subjectPublicKeyInfo  SEQUENCE { ... }  ← target
-///         ...
+///         extensions      [3] EXPLICIT SEQUENCE { ... subjectAltName ... }
 ///     },
 ///     signatureAlgorithm  AlgorithmIdentifier,
 ///     signatureValue      BIT STRING,
@@ -20,12 +21,20 @@
 /// ```
 ///
 /// We navigate the DER TLV (Tag-Length-Value) structure deterministically
-/// to reach the 7th field of tbsCertificate. No heap allocation.
+/// to reach the fields of tbsCertificate. No heap allocation.
 
 /// DER tag constants.
 const TAG_SEQUENCE: u8 = 0x30;
 const TAG_INTEGER: u8 = 0x02;
 const TAG_CONTEXT_0: u8 = 0xA0; // [0] EXPLICIT for version
+const TAG_CONTEXT_1_PRIM: u8 = 0x81; // [1] issuerUniqueID
+const TAG_CONTEXT_2_PRIM: u8 = 0x82; // [2] subjectUniqueID, dNSName in a SAN
+const TAG_CONTEXT_3: u8 = 0xA3; // [3] EXPLICIT for extensions
+const TAG_BOOLEAN: u8 = 0x01;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_OID: u8 = 0x06;
+const TAG_UTC_TIME: u8 = 0x17;
+const TAG_GENERALIZED_TIME: u8 = 0x18;
 
 /// Errors from DER parsing.
 #[derive(Debug, Clone, Copy)]
@@ -36,6 +45,8 @@ pub enum DerError {
     UnexpectedTag,
     /// Length encoding is malformed.
     BadLength,
+    /// A UTCTime or GeneralizedTime that isn't "...Z" digits.
+    BadTime,
 }
 
 /// A parsed TLV (Tag-Length-Value) element.
@@ -107,10 +118,9 @@ fn skip_tlv(data: &[u8]) -> Result<&[u8], DerError> {
     Ok(&data[tlv.total_len..])
 }
 
-/// Extract the raw SPKI (SubjectPublicKeyInfo) bytes from an X.509 DER
-/// certificate. Returns the complete SEQUENCE TLV (tag + length + value),
-/// which is what gets hashed for SPKI pinning (RFC 7469).
-pub fn extract_spki(cert_der: &[u8]) -> Result<&[u8], DerError> {
+/// The fields of tbsCertificate from `validity` on: version,
+/// serialNumber, signature and issuer skipped.
+fn tbs_from_validity(cert_der: &[u8]) -> Result<&[u8], DerError> {
     // 1. Parse outer Certificate SEQUENCE
     let outer = parse_tlv(cert_der)?;
     if outer.tag != TAG_SEQUENCE {
@@ -130,9 +140,11 @@ pub fn extract_spki(cert_der: &[u8]) -> Result<&[u8], DerError> {
     //    field 1: serialNumber  INTEGER
     //    field 2: signature     SEQUENCE (AlgorithmIdentifier)
     //    field 3: issuer        SEQUENCE (Name)
-    //    field 4: validity      SEQUENCE
+    //    field 4: validity      SEQUENCE  ← returned from here
     //    field 5: subject       SEQUENCE (Name)
-    //    field 6: subjectPublicKeyInfo  SEQUENCE  ← we want this
+    //    field 6: subjectPublicKeyInfo  SEQUENCE
+    //    [1], [2]: issuer/subject unique IDs (optional, rare)
+    //    [3] EXPLICIT extensions (v3)
 
     // Field 0: version — optional, tagged [0]
     if pos.first() == Some(&TAG_CONTEXT_0) {
@@ -161,7 +173,13 @@ pub fn extract_spki(cert_der: &[u8]) -> Result<&[u8], DerError> {
     if pos.first() != Some(&TAG_SEQUENCE) {
         return Err(DerError::UnexpectedTag);
     }
-    pos = skip_tlv(pos)?;
+    Ok(pos)
+}
+
+/// The fields of tbsCertificate from `subjectPublicKeyInfo` on.
+fn tbs_from_spki(cert_der: &[u8]) -> Result<&[u8], DerError> {
+    // Field 4: validity
+    let mut pos = skip_tlv(tbs_from_validity(cert_der)?)?;
 
     // Field 5: subject (Name = SEQUENCE)
     if pos.first() != Some(&TAG_SEQUENCE) {
@@ -173,6 +191,14 @@ pub fn extract_spki(cert_der: &[u8]) -> Result<&[u8], DerError> {
     if pos.first() != Some(&TAG_SEQUENCE) {
         return Err(DerError::UnexpectedTag);
     }
+    Ok(pos)
+}
+
+/// Extract the raw SPKI (SubjectPublicKeyInfo) bytes from an X.509 DER
+/// certificate. Returns the complete SEQUENCE TLV (tag + length + value),
+/// which is what gets hashed for SPKI pinning (RFC 7469).
+pub fn extract_spki(cert_der: &[u8]) -> Result<&[u8], DerError> {
+    let pos = tbs_from_spki(cert_der)?;
 
     // Return the complete TLV (not just the value) — RFC 7469 hashes
     // the full DER encoding of SubjectPublicKeyInfo.
@@ -180,6 +206,152 @@ pub fn extract_spki(cert_der: &[u8]) -> Result<&[u8], DerError> {
     Ok(&pos[..spki.total_len])
 }
 
+/// A certificate time, UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Time {
+    pub year: i64,
+    pub month: i64,
+    pub day: i64,
+    pub hour: i64,
+    pub minute: i64,
+    pub second: i64,
+}
+
+impl core::fmt::Display for Time {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// Parse a UTCTime ("YYMMDDHHMMSSZ", years 1950–2049) or a
+/// GeneralizedTime ("YYYYMMDDHHMMSSZ") TLV value.
+fn parse_time(tag: u8, value: &[u8]) -> Result<Time, DerError> {
+    let digits = match (tag, value.split_last()) {
+        (TAG_UTC_TIME | TAG_GENERALIZED_TIME, Some((b'Z', digits))) => digits,
+        (TAG_UTC_TIME | TAG_GENERALIZED_TIME, _) => return Err(DerError::BadTime),
+        _ => return Err(DerError::UnexpectedTag),
+    };
+    let year_len = if tag == TAG_UTC_TIME { 2 } else { 4 };
+    if digits.len() != year_len + 10 || !digits.iter().all(u8::is_ascii_digit) {
+        return Err(DerError::BadTime);
+    }
+    let number = |at: usize, len: usize| {
+        digits[at..at + len].iter().fold(0i64, |n, d| n * 10 + (d - b'0') as i64)
+    };
+    let mut year = number(0, year_len);
+    if tag == TAG_UTC_TIME {
+        // RFC 5280 §4.1.2.5.1: YY >= 50 is 19YY, otherwise 20YY.
+        year += if year >= 50 { 1900 } else { 2000 };
+    }
+    let at = year_len;
+    Ok(Time {
+        year,
+        month: number(at, 2),
+        day: number(at + 2, 2),
+        hour: number(at + 4, 2),
+        minute: number(at + 6, 2),
+        second: number(at + 8, 2),
+    })
+}
+
+/// Extract `validity` as (notBefore, notAfter).
+pub fn extract_validity(cert_der: &[u8]) -> Result<(Time, Time), DerError> {
+    let validity = parse_tlv(tbs_from_validity(cert_der)?)?;
+    let not_before = parse_tlv(validity.value)?;
+    let not_after = parse_tlv(&validity.value[not_before.total_len..])?;
+    Ok((
+        parse_time(not_before.tag, not_before.value)?,
+        parse_time(not_after.tag, not_after.value)?,
+    ))
+}
+
+/// The DER of OID 2.5.29.17, subjectAltName.
+const OID_SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1D, 0x11];
+
+/// Extract the value of the subjectAltName extension — the GeneralNames
+/// SEQUENCE contents, to walk with `DnsNames`. Empty if the certificate
+/// has no extensions or no SAN.
+pub fn extract_san(cert_der: &[u8]) -> Result<&[u8], DerError> {
+    // Skip subjectPublicKeyInfo and the optional unique IDs.
+    let mut pos = skip_tlv(tbs_from_spki(cert_der)?)?;
+    while matches!(pos.first(), Some(&TAG_CONTEXT_1_PRIM) | Some(&TAG_CONTEXT_2_PRIM)) {
+        pos = skip_tlv(pos)?;
+    }
+    if pos.first() != Some(&TAG_CONTEXT_3) {
+        return Ok(&[]);
+    }
+
+    // [3] EXPLICIT SEQUENCE OF Extension {
+    //     extnID OID, critical BOOLEAN DEFAULT FALSE, extnValue OCTET STRING }
+    let explicit = parse_tlv(pos)?;
+    let extensions = parse_tlv(explicit.value)?;
+    if extensions.tag != TAG_SEQUENCE {
+        return Err(DerError::UnexpectedTag);
+    }
+    let mut rest = extensions.value;
+    while !rest.is_empty() {
+        let extension = parse_tlv(rest)?;
+        rest = &rest[extension.total_len..];
+
+        let oid = parse_tlv(extension.value)?;
+        if oid.tag != TAG_OID || oid.value != OID_SUBJECT_ALT_NAME {
+            continue;
+        }
+        let mut field = &extension.value[oid.total_len..];
+        if field.first() == Some(&TAG_BOOLEAN) {
+            field = skip_tlv(field)?;
+        }
+        let value = parse_tlv(field)?;
+        if value.tag != TAG_OCTET_STRING {
+            return Err(DerError::UnexpectedTag);
+        }
+        let names = parse_tlv(value.value)?;
+        if names.tag != TAG_SEQUENCE {
+            return Err(DerError::UnexpectedTag);
+        }
+        return Ok(names.value);
+    }
+    Ok(&[])
+}
+
+/// The dNSName entries of a GeneralNames value (see `extract_san`);
+/// other kinds of name (IP addresses, URIs, ...) are skipped.
+pub struct DnsNames<'a> {
+    rest: &'a [u8],
+}
+
+impl<'a> DnsNames<'a> {
+    pub fn new(general_names: &'a [u8]) -> Self {
+        Self { rest: general_names }
+    }
+}
+
+impl<'a> Iterator for DnsNames<'a> {
+    type Item = Result<&'a str, DerError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.rest.is_empty() {
+            let name = match parse_tlv(self.rest) {
+                Ok(tlv) => tlv,
+                Err(e) => {
+                    self.rest = &[];
+                    return Some(Err(e));
+                }
+            };
+            self.rest = &self.rest[name.total_len..];
+            // dNSName [2] IMPLICIT IA5String
+            if name.tag == TAG_CONTEXT_2_PRIM {
+                return Some(core::str::from_utf8(name.value).map_err(|_| DerError::UnexpectedTag));
+            }
+        }
+        None
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
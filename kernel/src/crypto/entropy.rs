@@ -0,0 +1,238 @@
+/// Entropy sources and the kernel RNG.
+///
+/// `init` probes, in order of preference:
+///
+///   rdrand       the CPU's DRNG, if CPUID reports it and its output isn't stuck
+///   virtio-rng   the host's RNG, through QEMU `-device virtio-rng-pci`
+///   jitter       CPU timing jitter: TSC deltas across a cache-bound loop,
+///                if they pass a health check on this machine
+///
+/// Everything random — TLS key exchange, SQLite's xRandomness — comes from
+/// `fill`. With RDRAND it reads the DRNG directly. Without it, output comes
+/// from a SHA-256 counter-mode generator seeded from virtio-rng and/or
+/// jitter, rekeyed after every call and reseeded every `RESEED_BYTES`.
+///
+/// With no source at all `init` fails and boot stops: a kernel that would
+/// hand out predictable TLS keys and database salts is worse than one that
+/// doesn't start. `cpu` and /sys/entropy report what was found.
+use alloc::string::String;
+use alloc::vec::Vec;
+use sha2::{Digest, Sha256};
+use spin::Mutex;
+
+use crate::drivers::virtio::rng::VIRTIO_RNG;
+
+/// Output between reseeds of the fallback generator.
+const RESEED_BYTES: u64 = 1024 * 1024;
+
+/// TSC deltas hashed into one jitter seed (each carries well under a bit
+/// of entropy in the worst case; 2048 leaves a wide margin for 256 bits).
+const JITTER_SAMPLES: usize = 2048;
+
+/// Deltas in the jitter health check, and how many distinct values they
+/// must show. A VM with a coarse or trapped TSC gives a handful.
+const HEALTH_SAMPLES: usize = 256;
+const HEALTH_MIN_DISTINCT: usize = 32;
+
+/// An entropy source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Rdrand,
+    VirtioRng,
+    Jitter,
+}
+
+impl Source {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Source::Rdrand => "rdrand",
+            Source::VirtioRng => "virtio-rng",
+            Source::Jitter => "jitter",
+        }
+    }
+}
+
+struct State {
+    /// Usable sources, best first; `sources[0]` is the one `fill` uses.
+    sources: Vec<Source>,
+    /// Why each unusable source was skipped.
+    skipped: Vec<(Source, &'static str)>,
+    /// Fallback generator (unused while RDRAND is primary).
+    key: [u8; 32],
+    counter: u64,
+    since_reseed: u64,
+    reseeds: u64,
+    bytes: u64,
+}
+
+static STATE: Mutex<Option<State>> = Mutex::new(None);
+
+/// Probe the sources. Err (naming what was tried) if none is usable.
+/// Call after the TSC is calibrated and virtio-rng has been probed.
+pub fn init() -> Result<(), String> {
+    let mut sources = Vec::new();
+    let mut skipped = Vec::new();
+
+    if !crate::arch::x86_64::cpu::has_rdrand() {
+        skipped.push((Source::Rdrand, "not in CPUID"));
+    } else if !rdrand_healthy() {
+        skipped.push((Source::Rdrand, "output stuck or failing"));
+    } else {
+        sources.push(Source::Rdrand);
+    }
+    match VIRTIO_RNG.lock().as_mut() {
+        None => skipped.push((Source::VirtioRng, "no device")),
+        Some(dev) => match dev.fill(&mut [0u8; 32]) {
+            Ok(()) => sources.push(Source::VirtioRng),
+            Err(e) => skipped.push((Source::VirtioRng, e)),
+        },
+    }
+    if jitter_healthy() {
+        sources.push(Source::Jitter);
+    } else {
+        skipped.push((Source::Jitter, "too few distinct TSC deltas"));
+    }
+
+    if sources.is_empty() {
+        let tried: Vec<String> =
+            skipped.iter().map(|(s, why)| alloc::format!("{}: {}", s.as_str(), why)).collect();
+        return Err(alloc::format!("no entropy source ({})", tried.join("; ")));
+    }
+
+    let mut state = State { sources, skipped, key: [0; 32], counter: 0, since_reseed: 0, reseeds: 0, bytes: 0 };
+    if state.sources[0] != Source::Rdrand {
+        state.reseed();
+    }
+    *STATE.lock() = Some(state);
+    Ok(())
+}
+
+/// Fill `dest` with random bytes from the primary source.
+///
+/// Panics before `init` has succeeded: nothing may draw randomness the
+/// kernel can't vouch for.
+pub fn fill(dest: &mut [u8]) {
+    let mut guard = STATE.lock();
+    let state = guard.as_mut().expect("entropy used before crypto::entropy::init");
+    state.bytes += dest.len() as u64;
+    if state.sources[0] == Source::Rdrand {
+        for chunk in dest.chunks_mut(8) {
+            let value = super::RdRandRng::rdrand64().expect("RDRAND failed after 32 retries — hardware RNG fault");
+            chunk.copy_from_slice(&value.to_le_bytes()[..chunk.len()]);
+        }
+        return;
+    }
+    state.generate(dest);
+}
+
+/// The usable sources, best first (empty before `init`).
+pub fn sources() -> Vec<Source> {
+    STATE.lock().as_ref().map(|s| s.sources.clone()).unwrap_or_default()
+}
+
+/// /sys/entropy: the primary source, every source's status, and counters.
+pub fn report() -> Vec<u8> {
+    let guard = STATE.lock();
+    let Some(state) = guard.as_ref() else {
+        return b"entropy: not initialized\n".to_vec();
+    };
+    let mut out = alloc::format!("primary: {}\n", state.sources[0].as_str());
+    for source in &state.sources {
+        out.push_str(&alloc::format!("{}: ok\n", source.as_str()));
+    }
+    for (source, why) in &state.skipped {
+        out.push_str(&alloc::format!("{}: unavailable ({})\n", source.as_str(), why));
+    }
+    out.push_str(&alloc::format!("bytes: {}\nreseeds: {}\n", state.bytes, state.reseeds));
+    out.into_bytes()
+}
+
+impl State {
+    /// Mix fresh seed material from every non-RDRAND source into the key.
+    fn reseed(&mut self) {
+        let mut hasher = Sha256::new();
+        hasher.update(self.key);
+        for source in &self.sources {
+            match source {
+                Source::Rdrand => {}
+                Source::VirtioRng => {
+                    let mut seed = [0u8; 32];
+                    if let Some(dev) = VIRTIO_RNG.lock().as_mut() {
+                        if dev.fill(&mut seed).is_ok() {
+                            hasher.update(seed);
+                        }
+                    }
+                    super::zeroize::zeroize(&mut seed);
+                }
+                Source::Jitter => hasher.update(jitter_seed()),
+            }
+        }
+        self.key = hasher.finalize().into();
+        self.since_reseed = 0;
+        self.reseeds += 1;
+    }
+
+    /// SHA-256(key || counter) blocks, then a fresh key so earlier output
+    /// can't be recomputed from a later state.
+    fn generate(&mut self, dest: &mut [u8]) {
+        if self.since_reseed >= RESEED_BYTES {
+            self.reseed();
+        }
+        for chunk in dest.chunks_mut(32) {
+            let block: [u8; 32] = Sha256::new().chain_update(self.key).chain_update(self.counter.to_le_bytes()).finalize().into();
+            self.counter += 1;
+            chunk.copy_from_slice(&block[..chunk.len()]);
+        }
+        self.key = Sha256::new().chain_update(self.key).chain_update(b"rekey").chain_update(self.counter.to_le_bytes()).finalize().into();
+        self.counter += 1;
+        self.since_reseed += dest.len() as u64;
+    }
+}
+
+/// RDRAND answers and isn't repeating itself (some buggy parts return
+/// all-ones or a constant after resume).
+pub(crate) fn rdrand_healthy() -> bool {
+    let mut draws = [0u64; 4];
+    for draw in draws.iter_mut() {
+        match super::RdRandRng::rdrand64() {
+            Some(v) => *draw = v,
+            None => return false,
+        }
+    }
+    draws.iter().all(|&v| v != 0 && v != u64::MAX) && draws.windows(2).any(|w| w[0] != w[1])
+}
+
+/// One timing sample: TSC cycles across a data-dependent walk of a small
+/// buffer, which varies with cache, TLB and pipeline state.
+fn jitter_delta(scratch: &mut [u8; 256], salt: u64) -> u64 {
+    let start = crate::arch::x86_64::cpu::rdtsc();
+    let mut index = salt as usize;
+    for _ in 0..16 {
+        index = (index.wrapping_mul(31) + scratch[index % 256] as usize + 1) % 256;
+        scratch[index] = scratch[index].wrapping_add(index as u8);
+    }
+    core::hint::black_box(&scratch);
+    crate::arch::x86_64::cpu::rdtsc().wrapping_sub(start)
+}
+
+/// Does this machine's TSC show enough jitter to be worth hashing?
+fn jitter_healthy() -> bool {
+    let mut scratch = [0u8; 256];
+    let mut deltas: Vec<u64> = (0..HEALTH_SAMPLES as u64).map(|i| jitter_delta(&mut scratch, i)).collect();
+    deltas.sort_unstable();
+    deltas.dedup();
+    deltas.len() >= HEALTH_MIN_DISTINCT
+}
+
+/// 32 bytes hashed from `JITTER_SAMPLES` timing deltas.
+fn jitter_seed() -> [u8; 32] {
+    let mut scratch = [0u8; 256];
+    let mut hasher = Sha256::new();
+    let mut previous = 0u64;
+    for _ in 0..JITTER_SAMPLES {
+        let delta = jitter_delta(&mut scratch, previous);
+        hasher.update(delta.to_le_bytes());
+        previous = delta;
+    }
+    hasher.finalize().into()
+}
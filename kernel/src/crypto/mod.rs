@@ -1,10 +1,15 @@
 /// Cryptographic primitives for bare-metal TLS.
 ///
-/// Provides an RDRAND-based RNG that implements `rand_core::CryptoRng`.
-/// RDRAND is a hardware random number generator available on Intel Ivy Bridge+
-/// and AMD Zen+. We verified its presence via CPUID during boot.
+/// `KernelRng` implements `rand_core::CryptoRng` over `entropy::fill`,
+/// which uses RDRAND (Intel Ivy Bridge+, AMD Zen+) when the CPU has it and
+/// falls back to virtio-rng or timing jitter when it doesn't.
+///
+/// `zeroize` wipes secrets (the API key, requests carrying it, TLS
+/// buffers) before their memory goes back to the heap.
 pub mod der;
+pub mod entropy;
 pub mod pin_verifier;
+pub mod zeroize;
 
 /// RDRAND-based cryptographically secure RNG.
 pub struct RdRandRng;
@@ -16,7 +21,7 @@ impl RdRandRng {
 
     /// Read a 64-bit random value via RDRAND.
     /// Retries up to 32 times (Intel recommends 10).
-    fn rdrand64() -> Option<u64> {
+    pub(crate) fn rdrand64() -> Option<u64> {
         for _ in 0..32 {
             let val: u64;
             let ok: u8;
@@ -77,3 +82,43 @@ impl rand_core::RngCore for RdRandRng {
 }
 
 impl rand_core::CryptoRng for RdRandRng {}
+
+/// The kernel RNG for TLS: whatever source `entropy::init` chose.
+pub struct KernelRng;
+
+impl KernelRng {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl Default for KernelRng {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl rand_core::RngCore for KernelRng {
+    fn next_u32(&mut self) -> u32 {
+        let mut bytes = [0u8; 4];
+        entropy::fill(&mut bytes);
+        u32::from_le_bytes(bytes)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let mut bytes = [0u8; 8];
+        entropy::fill(&mut bytes);
+        u64::from_le_bytes(bytes)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        entropy::fill(dest);
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        entropy::fill(dest);
+        Ok(())
+    }
+}
+
+impl rand_core::CryptoRng for KernelRng {}
@@ -12,9 +12,28 @@
 /// shell commands) and the SHA-256 helper, ready for when cert access is
 /// available. The `ENFORCE_PINNING` flag in `api/mod.rs` is set to `false`
 /// until the upstream limitation is resolved.
+///
+/// `inspect` reads what a certificate says about itself — notAfter, the
+/// subjectAltName DNS names, the SPKI hash — and warns when it is expired,
+/// expires within `EXPIRY_WARN_DAYS`, or doesn't name the host. Until the
+/// handshake can hand us the server's leaf, `pin check` runs it on a
+/// certificate saved to a file; the SPKI hash it sees is kept as the
+/// observed one, which `pin show` offers as the pin to set.
+///
+/// `pin tofu <cert>` shows the SPKI hash of a certificate saved from the
+/// API host and, once confirmed, persists it as `tls.pin` with
+/// `tls.pin_enforce on`. True trust on first use would take the key from
+/// the handshake itself, which the same limitation rules out. `pin()`
+/// prefers a runtime override to that; `enforcing()` also needs
+/// `ENFORCE_PINNING`, i.e. the handshake able to show us the leaf.
 
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
 use spin::Mutex;
 
+use super::der::{self, DerError};
+
 // ============================================================
 // Runtime pin storage (for `pin set` shell command)
 // ============================================================
@@ -38,6 +57,44 @@ pub fn get_pin_override() -> Option<[u8; 32]> {
     *PIN_OVERRIDE.lock()
 }
 
+/// The pin in force: the runtime override, else `tls.pin` from config.
+pub fn pin() -> Option<[u8; 32]> {
+    get_pin_override().or_else(|| crate::sqlite::config_get("tls.pin").and_then(|hex| parse_hex(&hex)))
+}
+
+/// Has `tls.pin_enforce on` asked for the pin to be enforced?
+pub fn enforce_requested() -> bool {
+    crate::sqlite::config_get("tls.pin_enforce").as_deref() == Some("on")
+}
+
+/// Is the pin actually checked on connect? Needs a pin, the request, and
+/// a TLS stack that lets us see the certificate (`ENFORCE_PINNING`).
+pub fn enforcing() -> bool {
+    crate::api::ENFORCE_PINNING && enforce_requested() && pin().is_some()
+}
+
+/// Persist `hash` as the API host's pin and ask for it to be enforced.
+pub fn trust(hash: [u8; 32]) -> Result<(), String> {
+    crate::sqlite::config_set("tls.pin", &hex(&hash))?;
+    crate::sqlite::config_set("tls.pin_enforce", "on")?;
+    set_pin_override(hash);
+    Ok(())
+}
+
+/// Parse a 64-character hex string into a 32-byte array.
+pub fn parse_hex(hex: &str) -> Option<[u8; 32]> {
+    let hex = hex.trim();
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+    let mut result = [0u8; 32];
+    for i in 0..32 {
+        let byte_str = &hex[i * 2..i * 2 + 2];
+        result[i] = u8::from_str_radix(byte_str, 16).ok()?;
+    }
+    Some(result)
+}
+
 /// Compute the SHA-256 hash of raw bytes.
 ///
 /// Used by the `pin` shell command to compute SPKI hashes, and will be
@@ -49,3 +106,92 @@ pub fn sha256_hash(data: &[u8]) -> [u8; 32] {
     result.copy_from_slice(hash.as_slice());
     result
 }
+
+// ============================================================
+// Certificate inspection
+// ============================================================
+
+/// How close to notAfter a certificate draws a warning.
+pub const EXPIRY_WARN_DAYS: i64 = 14;
+
+/// What `inspect` found in a certificate.
+#[derive(Debug)]
+pub struct CertReport<'a> {
+    /// notAfter, as the certificate gives it.
+    pub not_after: der::Time,
+    /// notAfter, seconds since 1970.
+    pub expires: i64,
+    /// The subjectAltName DNS names.
+    pub dns_names: Vec<&'a str>,
+    /// SHA-256 of the DER SubjectPublicKeyInfo — the pin.
+    pub spki_hash: [u8; 32],
+    /// Expiry and hostname problems, one line each.
+    pub warnings: Vec<String>,
+}
+
+/// Inspect a DER certificate presented for `host` at `now` (seconds since
+/// 1970).
+pub fn inspect<'a>(cert_der: &'a [u8], host: &str, now: i64) -> Result<CertReport<'a>, DerError> {
+    let (_, not_after) = der::extract_validity(cert_der)?;
+    let expires = crate::time::epoch_secs(
+        not_after.year, not_after.month, not_after.day,
+        not_after.hour, not_after.minute, not_after.second,
+    ).ok_or(DerError::BadTime)?;
+    let dns_names = der::DnsNames::new(der::extract_san(cert_der)?).collect::<Result<Vec<_>, _>>()?;
+    let spki_hash = sha256_hash(der::extract_spki(cert_der)?);
+
+    let mut warnings = Vec::new();
+    let left = expires - now;
+    if left < 0 {
+        warnings.push(format!("certificate expired {} day(s) ago", (-left as u64).div_ceil(86_400)));
+    } else if left < EXPIRY_WARN_DAYS * 86_400 {
+        warnings.push(format!("certificate expires in {} day(s)", left / 86_400));
+    }
+    if !dns_names.iter().any(|name| host_matches(name, host)) {
+        warnings.push(format!("certificate does not name {}", host));
+    }
+    Ok(CertReport { not_after, expires, dns_names, spki_hash, warnings })
+}
+
+/// Does SAN entry `pattern` cover `host`? A leading "*." stands for exactly
+/// one label (RFC 6125 §6.4.3), so "*.example.com" matches
+/// "api.example.com" but neither "example.com" nor "a.b.example.com".
+pub fn host_matches(pattern: &str, host: &str) -> bool {
+    let host = host.trim_end_matches('.');
+    let pattern = pattern.trim_end_matches('.');
+    match pattern.strip_prefix("*.") {
+        Some(parent) => host
+            .split_once('.')
+            .is_some_and(|(label, rest)| !label.is_empty() && rest.eq_ignore_ascii_case(parent)),
+        None => pattern.eq_ignore_ascii_case(host),
+    }
+}
+
+/// An SPKI hash seen for a host, a candidate pin.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Observed {
+    pub host: String,
+    pub spki_hash: [u8; 32],
+}
+
+/// The last SPKI hash inspected.
+static OBSERVED: Mutex<Option<Observed>> = Mutex::new(None);
+
+/// Remember the SPKI hash seen for `host`.
+pub fn record_observed(host: &str, spki_hash: [u8; 32]) {
+    *OBSERVED.lock() = Some(Observed { host: String::from(host), spki_hash });
+}
+
+/// The last SPKI hash seen, if any.
+pub fn observed() -> Option<Observed> {
+    OBSERVED.lock().clone()
+}
+
+/// Lowercase hex of a hash, as `pin set` takes it.
+pub fn hex(hash: &[u8; 32]) -> String {
+    let mut out = String::with_capacity(64);
+    for b in hash {
+        out.push_str(&format!("{:02x}", b));
+    }
+    out
+}
@@ -0,0 +1,96 @@
+/// Wiping secrets from memory once they are no longer needed.
+///
+/// The heap hands freed blocks out again without clearing them, so an API
+/// key or a TLS buffer left in a dropped String is readable by whatever
+/// gets the block next, and by anyone dumping memory through the debugger.
+/// `zeroize` overwrites a buffer with volatile writes the optimizer can't
+/// remove as dead stores; `Zeroizing<T>` does it when T drops.
+///
+/// Only the live allocation is wiped: a String that grew has already left
+/// copies in the blocks it was reallocated out of. Size buffers that hold
+/// secrets up front where it matters.
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{compiler_fence, Ordering};
+
+/// Overwrite `buf` with zeros.
+pub fn zeroize(buf: &mut [u8]) {
+    for byte in buf.iter_mut() {
+        unsafe { core::ptr::write_volatile(byte, 0) };
+    }
+    compiler_fence(Ordering::SeqCst);
+}
+
+/// Something that can wipe its own contents.
+pub trait Zeroize {
+    fn zeroize(&mut self);
+}
+
+impl Zeroize for Vec<u8> {
+    /// Zero the whole allocation, spare capacity included, then empty it.
+    fn zeroize(&mut self) {
+        let capacity = self.capacity();
+        self.resize(capacity, 0);
+        zeroize(self);
+        self.clear();
+    }
+}
+
+impl Zeroize for String {
+    fn zeroize(&mut self) {
+        // All-zero bytes are valid UTF-8, and the String is emptied anyway
+        unsafe { self.as_mut_vec().zeroize() };
+    }
+}
+
+impl<const N: usize> Zeroize for [u8; N] {
+    fn zeroize(&mut self) {
+        zeroize(self);
+    }
+}
+
+impl<T: Zeroize> Zeroize for Option<T> {
+    fn zeroize(&mut self) {
+        if let Some(inner) = self {
+            inner.zeroize();
+        }
+    }
+}
+
+/// A value that is wiped when dropped. Derefs to the value; deliberately
+/// not Debug or Display, so a secret isn't printed by accident.
+#[derive(Clone, Default)]
+pub struct Zeroizing<T: Zeroize>(T);
+
+impl<T: Zeroize> Zeroizing<T> {
+    pub fn new(value: T) -> Self {
+        Zeroizing(value)
+    }
+}
+
+impl<T: Zeroize> From<T> for Zeroizing<T> {
+    fn from(value: T) -> Self {
+        Zeroizing(value)
+    }
+}
+
+impl<T: Zeroize> Deref for Zeroizing<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: Zeroize> DerefMut for Zeroizing<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+impl<T: Zeroize> Drop for Zeroizing<T> {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
@@ -0,0 +1,224 @@
+/// Line diffs in unified format (`diff -u`).
+///
+/// The edit script comes from Myers' O((N+M)·D) algorithm after trimming
+/// the common head and tail, so small edits to large files stay cheap.
+/// Past `MAX_TRACE` remembered cells (very different inputs) the middle is
+/// reported as one replacement instead — still a correct diff, just not
+/// the shortest.
+///
+/// Text is split on `\n`; a missing final newline is not marked.
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// Context lines around each change, as `diff -u`.
+pub const DEFAULT_CONTEXT: usize = 3;
+
+/// Cap on Myers trace cells (8 bytes each) before giving up on minimality.
+const MAX_TRACE: usize = 1 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Edit {
+    /// Same line: index in a, index in b.
+    Keep(usize, usize),
+    /// Line a[i] removed.
+    Delete(usize),
+    /// Line b[j] added.
+    Insert(usize),
+}
+
+/// Lines added and removed between `a` and `b`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DiffStat {
+    pub added: usize,
+    pub removed: usize,
+}
+
+/// Unified diff of `a` → `b` with `---`/`+++` headers naming them.
+/// Empty when the texts have the same lines.
+pub fn unified(a: &str, b: &str, a_name: &str, b_name: &str, context: usize) -> String {
+    let a_lines = split_lines(a);
+    let b_lines = split_lines(b);
+    let script = edit_script(&a_lines, &b_lines);
+    if script.iter().all(|e| matches!(e, Edit::Keep(..))) {
+        return String::new();
+    }
+
+    let mut out = alloc::format!("--- {}\n+++ {}\n", a_name, b_name);
+    for (start, end) in hunks(&script, context) {
+        let hunk = &script[start..end];
+        let (a_start, b_start) = position(&script[..start]);
+        let a_len = hunk.iter().filter(|e| !matches!(e, Edit::Insert(_))).count();
+        let b_len = hunk.iter().filter(|e| !matches!(e, Edit::Delete(_))).count();
+        out.push_str(&alloc::format!(
+            "@@ -{} +{} @@\n",
+            range(a_start, a_len),
+            range(b_start, b_len)
+        ));
+        for edit in hunk {
+            match *edit {
+                Edit::Keep(i, _) => push_line(&mut out, ' ', a_lines[i]),
+                Edit::Delete(i) => push_line(&mut out, '-', a_lines[i]),
+                Edit::Insert(j) => push_line(&mut out, '+', b_lines[j]),
+            }
+        }
+    }
+    out
+}
+
+/// Count added and removed lines without formatting a diff.
+pub fn stat(a: &str, b: &str) -> DiffStat {
+    let script = edit_script(&split_lines(a), &split_lines(b));
+    let mut stat = DiffStat::default();
+    for edit in script {
+        match edit {
+            Edit::Delete(_) => stat.removed += 1,
+            Edit::Insert(_) => stat.added += 1,
+            Edit::Keep(..) => {}
+        }
+    }
+    stat
+}
+
+fn split_lines(text: &str) -> Vec<&str> {
+    let mut lines: Vec<&str> = text.split('\n').collect();
+    if lines.last() == Some(&"") {
+        lines.pop();
+    }
+    lines
+}
+
+fn push_line(out: &mut String, sign: char, line: &str) {
+    out.push(sign);
+    out.push_str(line);
+    out.push('\n');
+}
+
+/// "start,len" as `diff -u` writes it: an empty range names the line
+/// before it, and a length of 1 is left out.
+fn range(start: usize, len: usize) -> String {
+    match len {
+        0 => alloc::format!("{},0", start),
+        1 => alloc::format!("{}", start + 1),
+        _ => alloc::format!("{},{}", start + 1, len),
+    }
+}
+
+/// Lines of a and b consumed by `prefix` (0-based start of what follows).
+fn position(prefix: &[Edit]) -> (usize, usize) {
+    prefix.iter().fold((0, 0), |(a, b), e| match e {
+        Edit::Keep(..) => (a + 1, b + 1),
+        Edit::Delete(_) => (a + 1, b),
+        Edit::Insert(_) => (a, b + 1),
+    })
+}
+
+/// Script ranges of each hunk: changes plus `context` lines either side,
+/// merging changes separated by at most 2·context unchanged lines.
+fn hunks(script: &[Edit], context: usize) -> Vec<(usize, usize)> {
+    let mut hunks: Vec<(usize, usize)> = Vec::new();
+    for (i, edit) in script.iter().enumerate() {
+        if matches!(edit, Edit::Keep(..)) {
+            continue;
+        }
+        let start = i.saturating_sub(context);
+        let end = (i + 1 + context).min(script.len());
+        match hunks.last_mut() {
+            Some(last) if start <= last.1 => last.1 = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+    hunks
+}
+
+/// Shortest edit script from `a` to `b`.
+fn edit_script(a: &[&str], b: &[&str]) -> Vec<Edit> {
+    let head = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+    let tail = a[head..]
+        .iter()
+        .rev()
+        .zip(b[head..].iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+
+    let mut script: Vec<Edit> = (0..head).map(|i| Edit::Keep(i, i)).collect();
+    let (a_mid, b_mid) = (&a[head..a.len() - tail], &b[head..b.len() - tail]);
+    match myers(a_mid, b_mid) {
+        Some(middle) => script.extend(middle.into_iter().map(|e| match e {
+            Edit::Keep(i, j) => Edit::Keep(i + head, j + head),
+            Edit::Delete(i) => Edit::Delete(i + head),
+            Edit::Insert(j) => Edit::Insert(j + head),
+        })),
+        None => {
+            script.extend((0..a_mid.len()).map(|i| Edit::Delete(i + head)));
+            script.extend((0..b_mid.len()).map(|j| Edit::Insert(j + head)));
+        }
+    }
+    let (a_tail, b_tail) = (a.len() - tail, b.len() - tail);
+    script.extend((0..tail).map(|i| Edit::Keep(a_tail + i, b_tail + i)));
+    script
+}
+
+/// Myers' greedy algorithm with backtracking. None if the trace would
+/// outgrow `MAX_TRACE`.
+fn myers(a: &[&str], b: &[&str]) -> Option<Vec<Edit>> {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let max = n + m;
+    let width = 2 * max as usize + 3;
+    let offset = max + 1;
+    let idx = |k: isize| (k + offset) as usize;
+
+    let mut v = vec![0isize; width];
+    let mut trace: Vec<Vec<isize>> = Vec::new();
+    'search: for d in 0..=max {
+        if (trace.len() + 1) * width > MAX_TRACE {
+            return None;
+        }
+        trace.push(v.clone());
+        let mut k = -d;
+        while k <= d {
+            let mut x = if k == -d || (k != d && v[idx(k - 1)] < v[idx(k + 1)]) {
+                v[idx(k + 1)]
+            } else {
+                v[idx(k - 1)] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[idx(k)] = x;
+            if x >= n && y >= m {
+                break 'search;
+            }
+            k += 2;
+        }
+    }
+
+    // Walk back from (n, m) through the saved frontiers
+    let mut edits = Vec::new();
+    let (mut x, mut y) = (n, m);
+    for (d, v) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        let k = x - y;
+        let prev_k = if k == -d || (k != d && v[idx(k - 1)] < v[idx(k + 1)]) { k + 1 } else { k - 1 };
+        let prev_x = v[idx(prev_k)];
+        let prev_y = prev_x - prev_k;
+        while x > prev_x && y > prev_y {
+            edits.push(Edit::Keep(x as usize - 1, y as usize - 1));
+            x -= 1;
+            y -= 1;
+        }
+        if d > 0 {
+            if x == prev_x {
+                edits.push(Edit::Insert(y as usize - 1));
+            } else {
+                edits.push(Edit::Delete(x as usize - 1));
+            }
+            x = prev_x;
+            y = prev_y;
+        }
+    }
+    edits.reverse();
+    Some(edits)
+}
@@ -1,5 +1,8 @@
 /// NVMe command construction and PRP list building.
+use alloc::format;
 use core::fmt;
+use core::sync::atomic::{AtomicU32, Ordering};
+use crate::error::{KernelError, Layer};
 use crate::mem::{PhysAddr, DmaBuf};
 
 /// NVMe admin command opcodes.
@@ -23,13 +26,14 @@ pub enum NvmOpcode {
 }
 
 /// NVMe error types.
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum NvmeError {
     /// Controller reported fatal status (CSTS.CFS).
     ControllerFatal,
     /// Command timed out waiting for completion.
     Timeout,
-    /// NVMe command completed with non-zero status.
+    /// NVMe command completed with non-zero status: the completion's
+    /// Status Field (see `status`).
     CommandFailed(u16),
     /// No NVMe device found during PCI enumeration.
     DeviceNotFound,
@@ -46,7 +50,16 @@ impl fmt::Display for NvmeError {
         match self {
             NvmeError::ControllerFatal => write!(f, "NVMe controller fatal error"),
             NvmeError::Timeout => write!(f, "NVMe command timeout"),
-            NvmeError::CommandFailed(s) => write!(f, "NVMe command failed: status {:#x}", s),
+            NvmeError::CommandFailed(s) => {
+                write!(f, "NVMe command failed: {} (status {:#x}", status::describe(*s), s)?;
+                if status::dnr(*s) {
+                    write!(f, ", do not retry")?;
+                }
+                if status::more(*s) {
+                    write!(f, ", more in error log")?;
+                }
+                write!(f, ")")
+            }
             NvmeError::DeviceNotFound => write!(f, "NVMe device not found"),
             NvmeError::NotInitialized => write!(f, "NVMe driver not initialized"),
             NvmeError::OutOfMemory => write!(f, "NVMe DMA allocation failed"),
@@ -55,14 +68,195 @@ impl fmt::Display for NvmeError {
     }
 }
 
+impl From<NvmeError> for KernelError {
+    fn from(e: NvmeError) -> Self {
+        let code = match e {
+            NvmeError::CommandFailed(s) => format!("CommandFailed({:#x})", s),
+            other => format!("{:?}", other),
+        };
+        KernelError::new(Layer::Nvme, code, e)
+    }
+}
+
+/// Decoding of the completion Status Field (CQE DW3 bits 31:17, phase
+/// tag excluded): SC in bits 7:0, SCT in 10:8, CRD in 12:11, More in 13,
+/// DNR in 14. Messages follow the NVMe 1.4 base spec tables.
+pub mod status {
+    /// Status Code Type.
+    pub fn sct(status: u16) -> u8 {
+        ((status >> 8) & 0x7) as u8
+    }
+
+    /// Status Code, meaning depends on `sct`.
+    pub fn sc(status: u16) -> u8 {
+        status as u8
+    }
+
+    /// More: the controller has further detail in the Error Information log.
+    pub fn more(status: u16) -> bool {
+        status & (1 << 13) != 0
+    }
+
+    /// Do Not Retry: the same command would fail again.
+    pub fn dnr(status: u16) -> bool {
+        status & (1 << 14) != 0
+    }
+
+    /// Human-readable meaning of a status.
+    pub fn describe(status: u16) -> &'static str {
+        match (sct(status), sc(status)) {
+            // Generic Command Status
+            (0, 0x00) => "success",
+            (0, 0x01) => "invalid command opcode",
+            (0, 0x02) => "invalid field in command",
+            (0, 0x03) => "command ID conflict",
+            (0, 0x04) => "data transfer error",
+            (0, 0x05) => "aborted: power loss notification",
+            (0, 0x06) => "internal error",
+            (0, 0x07) => "aborted by request",
+            (0, 0x08) => "aborted: submission queue deleted",
+            (0, 0x09) => "aborted: failed fused command",
+            (0, 0x0A) => "aborted: missing fused command",
+            (0, 0x0B) => "invalid namespace or format",
+            (0, 0x0C) => "command sequence error",
+            (0, 0x0D..=0x11) | (0, 0x16) | (0, 0x1E) => "invalid SGL",
+            (0, 0x12) => "invalid use of controller memory buffer",
+            (0, 0x13) => "PRP offset invalid",
+            (0, 0x14) => "atomic write unit exceeded",
+            (0, 0x15) => "operation denied",
+            (0, 0x1C) => "sanitize failed",
+            (0, 0x1D) => "sanitize in progress",
+            (0, 0x20) => "namespace is write protected",
+            (0, 0x21) => "command interrupted",
+            (0, 0x22) => "transient transport error",
+            (0, 0x80) => "LBA out of range",
+            (0, 0x81) => "capacity exceeded",
+            (0, 0x82) => "namespace not ready",
+            (0, 0x83) => "reservation conflict",
+            (0, 0x84) => "format in progress",
+            (0, _) => "generic error",
+            // Command Specific Status
+            (1, 0x00) => "invalid completion queue",
+            (1, 0x01) => "invalid queue identifier",
+            (1, 0x02) => "invalid queue size",
+            (1, 0x03) => "abort command limit exceeded",
+            (1, 0x05) => "async event request limit exceeded",
+            (1, 0x06) => "invalid firmware slot",
+            (1, 0x07) => "invalid firmware image",
+            (1, 0x08) => "invalid interrupt vector",
+            (1, 0x09) => "invalid log page",
+            (1, 0x0A) => "invalid format",
+            (1, 0x0C) => "invalid queue deletion",
+            (1, 0x80) => "conflicting attributes",
+            (1, 0x81) => "invalid protection information",
+            (1, 0x82) => "write to read-only range",
+            (1, _) => "command specific error",
+            // Media and Data Integrity Errors
+            (2, 0x80) => "write fault",
+            (2, 0x81) => "unrecovered read error",
+            (2, 0x82) => "end-to-end guard check error",
+            (2, 0x83) => "end-to-end application tag check error",
+            (2, 0x84) => "end-to-end reference tag check error",
+            (2, 0x85) => "compare failure",
+            (2, 0x86) => "access denied",
+            (2, 0x87) => "deallocated or unwritten block",
+            (2, _) => "media error",
+            // Path Related Status
+            (3, 0x00) => "internal path error",
+            (3, 0x01..=0x03) => "asymmetric namespace access error",
+            (3, 0x60) => "controller pathing error",
+            (3, 0x70) => "host pathing error",
+            (3, 0x71) => "aborted by host",
+            (3, _) => "path error",
+            (7, _) => "vendor specific error",
+            _ => "reserved status",
+        }
+    }
+}
+
+/// Error classes counted by `record_error`, for the `nvme` command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// SCT 0: generic command status (bad field, LBA out of range, ...).
+    Generic,
+    /// SCT 1: specific to the command (mostly admin).
+    CommandSpecific,
+    /// SCT 2: media and data integrity (write fault, unrecovered read).
+    Media,
+    /// SCT 3: path related.
+    Path,
+    /// SCT 7 or a reserved type.
+    Other,
+    /// No completion within the timeout.
+    Timeout,
+    /// Controller fatal status.
+    Fatal,
+}
+
+impl ErrorClass {
+    pub const ALL: [ErrorClass; 7] = [
+        ErrorClass::Generic,
+        ErrorClass::CommandSpecific,
+        ErrorClass::Media,
+        ErrorClass::Path,
+        ErrorClass::Other,
+        ErrorClass::Timeout,
+        ErrorClass::Fatal,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ErrorClass::Generic => "generic",
+            ErrorClass::CommandSpecific => "command",
+            ErrorClass::Media => "media",
+            ErrorClass::Path => "path",
+            ErrorClass::Other => "other",
+            ErrorClass::Timeout => "timeout",
+            ErrorClass::Fatal => "fatal",
+        }
+    }
+
+    /// The class of a device error; None for driver-side errors.
+    pub fn of(e: &NvmeError) -> Option<ErrorClass> {
+        match e {
+            NvmeError::CommandFailed(s) => Some(match status::sct(*s) {
+                0 => ErrorClass::Generic,
+                1 => ErrorClass::CommandSpecific,
+                2 => ErrorClass::Media,
+                3 => ErrorClass::Path,
+                _ => ErrorClass::Other,
+            }),
+            NvmeError::MediaError => Some(ErrorClass::Media),
+            NvmeError::Timeout => Some(ErrorClass::Timeout),
+            NvmeError::ControllerFatal => Some(ErrorClass::Fatal),
+            _ => None,
+        }
+    }
+}
+
+static ERROR_COUNTS: [AtomicU32; ErrorClass::ALL.len()] = [const { AtomicU32::new(0) }; ErrorClass::ALL.len()];
+
+/// Count a device error against its class. Returns it, for `map_err`.
+pub fn record_error(e: NvmeError) -> NvmeError {
+    if let Some(class) = ErrorClass::of(&e) {
+        ERROR_COUNTS[class as usize].fetch_add(1, Ordering::Relaxed);
+    }
+    e
+}
+
+/// Device errors of `class` since boot.
+pub fn error_count(class: ErrorClass) -> u32 {
+    ERROR_COUNTS[class as usize].load(Ordering::Relaxed)
+}
+
 /// Classify an NVMe completion status into an error category.
 pub fn classify_status(status: u16) -> Result<(), NvmeError> {
     if status == 0 {
         return Ok(());
     }
 
-    let sct = (status >> 9) & 0x7; // Status Code Type
-    let sc = (status >> 1) & 0xFF;  // Status Code
+    let sct = status::sct(status); // Status Code Type
+    let sc = status::sc(status);   // Status Code
 
     match sct {
         0 => {
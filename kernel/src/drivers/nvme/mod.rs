@@ -4,9 +4,10 @@ mod queue;
 mod command;
 pub mod pci;
 
-pub use command::{NvmeCommand, AdminOpcode, NvmOpcode, NvmeError};
+pub use command::{NvmeCommand, AdminOpcode, NvmOpcode, NvmeError, ErrorClass, error_count, status};
 pub use queue::{SubmissionEntry, CompletionEntry};
 
+use alloc::string::String;
 use core::sync::atomic::{compiler_fence, Ordering};
 use spin::Mutex;
 use crate::mem::DmaBuf;
@@ -15,18 +16,19 @@ use queue::{QueuePair, AdminQueue};
 /// Default I/O command timeout in milliseconds (30 seconds).
 const IO_TIMEOUT_MS: u64 = 30_000;
 
-/// Spin-wait for a completion with a TSC-based timeout.
+/// Admin command timeout in milliseconds.
+const ADMIN_TIMEOUT_MS: u64 = 10_000;
+
+/// Spin-wait for a completion with a TSC-based timeout (see
+/// `time::FALLBACK_TSC_PER_MS` for before calibration).
 /// Returns `Some(status)` if the completion arrived, `None` on timeout.
 fn poll_with_timeout<F: FnMut() -> Option<u16>>(mut poll_fn: F, timeout_ms: u64) -> Option<u16> {
-    let per_ms = crate::arch::x86_64::timer::tsc_per_ms();
-    let start = crate::arch::x86_64::cpu::rdtsc();
-    let deadline = if per_ms > 0 { timeout_ms.saturating_mul(per_ms) } else { u64::MAX };
+    let deadline = crate::time::Deadline::after_ms(timeout_ms);
     loop {
         if let Some(status) = poll_fn() {
             return Some(status);
         }
-        let elapsed = crate::arch::x86_64::cpu::rdtsc().wrapping_sub(start);
-        if elapsed >= deadline {
+        if deadline.expired() {
             return None;
         }
         core::hint::spin_loop();
@@ -54,13 +56,30 @@ pub struct NamespaceInfo {
     pub metadata_size: u32,
 }
 
+/// Identify Controller fields worth showing.
+#[derive(Debug, Clone)]
+pub struct ControllerInfo {
+    pub serial: String,
+    pub model: String,
+    pub firmware: String,
+}
+
 /// Main NVMe driver state.
+///
+/// A command that doesn't complete within its timeout fails with
+/// `NvmeError::Timeout` and the controller is reset: disabled, given an
+/// emptied admin queue and fresh I/O queues, re-enabled. The command is not retried — the
+/// caller (the VFS, then SQLite) sees an I/O error — but later commands
+/// run on a working controller.
 pub struct NvmeDriver {
     bar0: *mut u8,                 // MMIO base address
     doorbell_stride: usize,        // From CAP.DSTRD
+    max_queue_entries: u16,        // From CAP.MQES
+    ready_timeout_ms: u64,         // From CAP.TO
     admin_queue: AdminQueue,
     io_queue: Option<QueuePair>,
     ns_info: Option<NamespaceInfo>,
+    resets: u32,
 }
 
 unsafe impl Send for NvmeDriver {}
@@ -75,9 +94,12 @@ impl NvmeDriver {
         let mut driver = Self {
             bar0,
             doorbell_stride: 4, // default, updated from CAP
+            max_queue_entries: 2,
+            ready_timeout_ms: 500,
             admin_queue: AdminQueue::uninit(),
             io_queue: None,
             ns_info: None,
+            resets: 0,
         };
 
         driver.init_controller()?;
@@ -89,16 +111,39 @@ impl NvmeDriver {
         // 1. Read capabilities
         let cap = self.read_reg64(regs::CAP);
         self.doorbell_stride = 4 << ((cap >> 32) & 0xF) as usize; // CAP.DSTRD
-        let max_queue_entries = ((cap & 0xFFFF) + 1) as u16;
-        let timeout_500ms = ((cap >> 24) & 0xFF) as u32; // in 500ms units
+        self.max_queue_entries = ((cap & 0xFFFF) + 1) as u16;
+        self.ready_timeout_ms = ((cap >> 24) & 0xFF).max(1) * 500; // CAP.TO, 500ms units
+
+        // 2-4. Disable, set up the admin queue, enable
+        self.enable_with_fresh_queues()?;
+
+        // 5. Identify Controller (admin command)
+        self.identify_controller()?;
+
+        // 6. Create I/O Completion Queue
+        // 7. Create I/O Submission Queue
+        self.create_io_queues(64.min(self.max_queue_entries))?;
+
+        // 8. Identify Namespace 1
+        self.identify_namespace(1)?;
+
+        Ok(())
+    }
 
+    /// Disable the controller, set up an empty admin queue and re-enable.
+    /// Any I/O queue is gone afterwards and must be created again.
+    unsafe fn enable_with_fresh_queues(&mut self) -> Result<(), NvmeError> {
         // 2. Disable controller (CC.EN = 0)
         self.write_reg32(regs::CC, 0);
-        self.wait_for_ready(false, timeout_500ms)?;
+        self.wait_for_ready(false)?;
+        self.io_queue = None;
 
-        // 3. Configure Admin Queue
+        // 3. Configure Admin Queue: allocated on the first enable, then
+        // emptied and reused by each reset
         let aq_size: u16 = 32; // entries
-        self.admin_queue = AdminQueue::new(aq_size).map_err(|_| NvmeError::OutOfMemory)?;
+        if !self.admin_queue.reset() {
+            self.admin_queue = AdminQueue::new(aq_size).map_err(|_| NvmeError::OutOfMemory)?;
+        }
 
         // Set Admin Queue Attributes
         let aqa = ((aq_size as u32 - 1) << 16) | (aq_size as u32 - 1);
@@ -112,50 +157,58 @@ impl NvmeDriver {
         // CC: IOCQES=4 (16B), IOSQES=6 (64B), MPS=0 (4K pages), CSS=0 (NVM), EN=1
         let cc = (4 << 20) | (6 << 16) | (0 << 7) | (0 << 4) | 1;
         self.write_reg32(regs::CC, cc);
-        self.wait_for_ready(true, timeout_500ms)?;
-
-        // 5. Identify Controller (admin command)
-        self.identify_controller()?;
-
-        // 6. Create I/O Completion Queue
-        // 7. Create I/O Submission Queue
-        self.create_io_queues(64.min(max_queue_entries))?;
-
-        // 8. Identify Namespace 1
-        self.identify_namespace(1)?;
+        self.wait_for_ready(true)
+    }
 
+    /// Recover from a command timeout: reset the controller and re-create
+    /// the I/O queues. Commands in flight are lost.
+    unsafe fn reset(&mut self) -> Result<(), NvmeError> {
+        self.resets = self.resets.saturating_add(1);
+        crate::serial_println!("[nvme] command timed out, resetting controller (reset #{})", self.resets);
+        self.enable_with_fresh_queues()?;
+        self.create_io_queues(64.min(self.max_queue_entries))?;
+        crate::serial_println!("[nvme] controller reset complete");
         Ok(())
     }
 
-    /// Wait for CSTS.RDY to reach the desired state.
-    unsafe fn wait_for_ready(&self, ready: bool, timeout_500ms: u32) -> Result<(), NvmeError> {
+    /// Wait up to CAP.TO for CSTS.RDY to reach the desired state.
+    unsafe fn wait_for_ready(&self, ready: bool) -> Result<(), NvmeError> {
         let target = if ready { 1 } else { 0 };
-        // Simple spin wait. A real implementation would use a timer.
-        let max_spins = (timeout_500ms as u64) * 500_000; // rough approximation
-        for _ in 0..max_spins {
-            let csts = self.read_reg32(regs::CSTS);
-            if (csts & 1) == target {
-                return Ok(());
-            }
-            if csts & 0x2 != 0 {
-                // CFS (Controller Fatal Status)
-                return Err(NvmeError::ControllerFatal);
+        let bar0 = self.bar0;
+        let mut fatal = false;
+        let done = poll_with_timeout(|| {
+            let csts = core::ptr::read_volatile(bar0.add(regs::CSTS) as *const u32);
+            // CFS (Controller Fatal Status) only matters once enabled:
+            // disabling is how a fatal controller is recovered.
+            if ready && csts & 0x2 != 0 {
+                fatal = true;
+                return Some(0);
             }
-            core::hint::spin_loop();
+            ((csts & 1) == target).then_some(0)
+        }, self.ready_timeout_ms);
+        match done {
+            Some(_) if fatal => Err(command::record_error(NvmeError::ControllerFatal)),
+            Some(_) => Ok(()),
+            None => Err(NvmeError::Timeout),
         }
-        Err(NvmeError::Timeout)
     }
 
     /// Send Identify Controller command via admin queue.
-    unsafe fn identify_controller(&mut self) -> Result<(), NvmeError> {
+    unsafe fn identify_controller(&mut self) -> Result<ControllerInfo, NvmeError> {
         let mut buf = DmaBuf::alloc(4096).map_err(|_| NvmeError::OutOfMemory)?;
         let cmd = SubmissionEntry::identify(0, 1, buf.phys_addr()); // CNS=1: identify controller
         let status = self.admin_submit_wait(cmd, &mut buf)?;
         if status != 0 {
             return Err(NvmeError::CommandFailed(status));
         }
-        // Parse identify data if needed (model name, firmware, etc.)
-        Ok(())
+
+        buf.invalidate_cache();
+        let data = buf.as_slice();
+        // SN (bytes 4-23), MN (24-63), FR (64-71): ASCII, space-padded
+        let text = |range: core::ops::Range<usize>| {
+            String::from(String::from_utf8_lossy(&data[range]).trim_end_matches([' ', '\0']))
+        };
+        Ok(ControllerInfo { serial: text(4..24), model: text(24..64), firmware: text(64..72) })
     }
 
     /// Create I/O queue pair (CQ first, then SQ per spec).
@@ -222,20 +275,12 @@ impl NvmeDriver {
         cmd: SubmissionEntry,
         _buf: &mut DmaBuf,
     ) -> Result<u16, NvmeError> {
-        self.admin_queue.submit(cmd);
-        compiler_fence(Ordering::SeqCst);
-        self.ring_admin_sq_doorbell();
-
-        let aq = &mut self.admin_queue;
-        match poll_with_timeout(|| aq.poll_completion(), IO_TIMEOUT_MS) {
-            Some(status) => {
-                self.ring_admin_cq_doorbell();
-                Ok(status)
-            }
-            None => Err(NvmeError::Timeout),
-        }
+        self.admin_submit_wait_no_buf(cmd)
     }
 
+    /// Submit an admin command and wait up to `ADMIN_TIMEOUT_MS`. Admin
+    /// commands only run during (re)initialization, which fails as a
+    /// whole on a timeout, so there is no reset here.
     unsafe fn admin_submit_wait_no_buf(
         &mut self,
         cmd: SubmissionEntry,
@@ -245,7 +290,7 @@ impl NvmeDriver {
         self.ring_admin_sq_doorbell();
 
         let aq = &mut self.admin_queue;
-        match poll_with_timeout(|| aq.poll_completion(), IO_TIMEOUT_MS) {
+        match poll_with_timeout(|| aq.poll_completion(), ADMIN_TIMEOUT_MS) {
             Some(status) => {
                 self.ring_admin_cq_doorbell();
                 Ok(status)
@@ -273,31 +318,13 @@ impl NvmeDriver {
         let ns = self.ns_info.as_ref().ok_or(NvmeError::NotInitialized)?;
         let nsid = ns.nsid;
         let bs = ns.block_size;
-        let bar0 = self.bar0;
-        let stride = self.doorbell_stride;
-        let qp = self.io_queue.as_mut().ok_or(NvmeError::NotInitialized)?;
-        let qid = qp.id() as usize;
 
         let (prp1, prp2, _prp_list) = command::build_prp(buf, block_count as usize * bs as usize);
 
         let cmd = SubmissionEntry::read(nsid, lba, block_count - 1, prp1, prp2);
-        qp.submit(cmd);
-        compiler_fence(Ordering::SeqCst);
-        let sq_tail = qp.sq_tail();
-        unsafe { Self::write_doorbell(bar0, regs::SQ0TDBL + (2 * qid) * stride, sq_tail as u32) };
-
-        match poll_with_timeout(|| qp.poll_completion(), IO_TIMEOUT_MS) {
-            Some(status) => {
-                let cq_head = qp.cq_head();
-                unsafe { Self::write_doorbell(bar0, regs::SQ0TDBL + (2 * qid + 1) * stride, cq_head as u32) };
-                if status != 0 {
-                    return Err(NvmeError::CommandFailed(status));
-                }
-                buf.invalidate_cache();
-                Ok(())
-            }
-            None => Err(NvmeError::Timeout),
-        }
+        self.io_submit_wait(cmd)?;
+        buf.invalidate_cache();
+        Ok(())
     }
 
     /// Write `block_count` blocks starting at `lba` from `buf`.
@@ -310,44 +337,38 @@ impl NvmeDriver {
         let ns = self.ns_info.as_ref().ok_or(NvmeError::NotInitialized)?;
         let nsid = ns.nsid;
         let bs = ns.block_size;
-        let bar0 = self.bar0;
-        let stride = self.doorbell_stride;
-        let qp = self.io_queue.as_mut().ok_or(NvmeError::NotInitialized)?;
-        let qid = qp.id() as usize;
 
         buf.flush_cache();
         let (prp1, prp2, _prp_list) = command::build_prp(buf, block_count as usize * bs as usize);
 
         let cmd = SubmissionEntry::write(nsid, lba, block_count - 1, prp1, prp2);
-        qp.submit(cmd);
-        compiler_fence(Ordering::SeqCst);
-        let sq_tail = qp.sq_tail();
-        unsafe { Self::write_doorbell(bar0, regs::SQ0TDBL + (2 * qid) * stride, sq_tail as u32) };
+        self.io_submit_wait(cmd)
+    }
 
-        match poll_with_timeout(|| qp.poll_completion(), IO_TIMEOUT_MS) {
-            Some(status) => {
-                let cq_head = qp.cq_head();
-                unsafe { Self::write_doorbell(bar0, regs::SQ0TDBL + (2 * qid + 1) * stride, cq_head as u32) };
-                if status != 0 {
-                    return Err(NvmeError::CommandFailed(status));
-                }
-                Ok(())
-            }
-            None => Err(NvmeError::Timeout),
-        }
+    /// Issue Identify Controller again, e.g. to check the admin queue.
+    pub fn identify(&mut self) -> Result<ControllerInfo, NvmeError> {
+        // The controller was initialized when the driver was created
+        unsafe { self.identify_controller() }
     }
 
     /// Flush — force all written data to non-volatile storage.
     /// This is the ACID guarantee for SQLite.
     pub fn flush(&mut self) -> Result<(), NvmeError> {
         let ns = self.ns_info.as_ref().ok_or(NvmeError::NotInitialized)?;
-        let nsid = ns.nsid;
+        let cmd = SubmissionEntry::flush(ns.nsid);
+        self.io_submit_wait(cmd)
+    }
+
+    /// Submit an I/O command and wait up to `IO_TIMEOUT_MS` for it.
+    /// On a timeout the controller is reset (see `NvmeDriver`) and the
+    /// command fails with `Timeout`; if the reset fails too, so does
+    /// every later command, with `NotInitialized`.
+    fn io_submit_wait(&mut self, cmd: SubmissionEntry) -> Result<(), NvmeError> {
         let bar0 = self.bar0;
         let stride = self.doorbell_stride;
         let qp = self.io_queue.as_mut().ok_or(NvmeError::NotInitialized)?;
         let qid = qp.id() as usize;
 
-        let cmd = SubmissionEntry::flush(nsid);
         qp.submit(cmd);
         compiler_fence(Ordering::SeqCst);
         let sq_tail = qp.sq_tail();
@@ -358,11 +379,17 @@ impl NvmeDriver {
                 let cq_head = qp.cq_head();
                 unsafe { Self::write_doorbell(bar0, regs::SQ0TDBL + (2 * qid + 1) * stride, cq_head as u32) };
                 if status != 0 {
-                    return Err(NvmeError::CommandFailed(status));
+                    return Err(command::record_error(NvmeError::CommandFailed(status)));
                 }
                 Ok(())
             }
-            None => Err(NvmeError::Timeout),
+            None => {
+                command::record_error(NvmeError::Timeout);
+                if let Err(e) = unsafe { self.reset() } {
+                    crate::serial_println!("[nvme] controller reset failed: {}", e);
+                }
+                Err(NvmeError::Timeout)
+            }
         }
     }
 
@@ -371,6 +398,11 @@ impl NvmeDriver {
         self.ns_info.as_ref()
     }
 
+    /// Controller resets after command timeouts since boot.
+    pub fn reset_count(&self) -> u32 {
+        self.resets
+    }
+
     // ---- MMIO helpers ----
 
     unsafe fn read_reg32(&self, offset: usize) -> u32 {
@@ -128,7 +128,7 @@ pub struct CompletionEntry {
     pub dw1: u32,
     /// SQ Head Pointer[15:0] | SQ Identifier[31:16]
     pub sq_head_sqid: u32,
-    /// Command Identifier[15:0] | Phase Tag[0] | Status[15:1]
+    /// Command Identifier[15:0] | Phase Tag[16] | Status Field[31:17]
     pub cid_status: u32,
 }
 
@@ -141,17 +141,18 @@ impl CompletionEntry {
 
     /// Extract the phase bit from this completion entry.
     pub fn phase(&self) -> bool {
-        self.cid_status & 1 != 0
+        self.cid_status & (1 << 16) != 0
     }
 
-    /// Extract the status code (14 bits).
+    /// Extract the Status Field (15 bits, phase excluded); see
+    /// `command::status` for its layout.
     pub fn status(&self) -> u16 {
-        ((self.cid_status >> 1) & 0x7FFF) as u16
+        (self.cid_status >> 17) as u16
     }
 
-    /// Extract the command identifier.
+    /// Extract the command identifier (bits 15:0).
     pub fn command_id(&self) -> u16 {
-        (self.cid_status >> 16) as u16
+        self.cid_status as u16
     }
 }
 
@@ -188,6 +189,17 @@ impl QueuePair {
         })
     }
 
+    /// Empty both queues and start over at slot 0, as a newly allocated
+    /// pair. Only while the controller is disabled.
+    pub fn reset(&mut self) {
+        self.sq_buf.as_mut_slice().fill(0);
+        self.cq_buf.as_mut_slice().fill(0);
+        self.sq_tail = 0;
+        self.cq_head = 0;
+        self.cq_phase = true;
+        self.next_cid = 0;
+    }
+
     pub fn id(&self) -> u16 {
         self.id
     }
@@ -262,6 +274,11 @@ impl AdminQueue {
         })
     }
 
+    /// Empty the queue for reuse. False if it was never allocated.
+    pub fn reset(&mut self) -> bool {
+        self.inner.as_mut().map(QueuePair::reset).is_some()
+    }
+
     pub fn sq_phys(&self) -> PhysAddr {
         self.inner.as_ref().unwrap().sq_phys()
     }
@@ -4,6 +4,67 @@
 /// virtio-net (network) and optionally virtio-blk (block device) via PCI.
 ///
 /// We implement virtio-net here as the path to network connectivity,
-/// which is required to reach the Claude API.
+/// which is required to reach the Claude API, and virtio-rng
+/// (`-device virtio-rng-pci`) as an entropy source for CPUs without RDRAND.
 pub mod net;
+pub mod rng;
 pub mod virtqueue;
+
+use crate::drivers::pci::{pci_read32, pci_write32};
+
+/// Check if a PCI device is multi-function (Header Type bit 7).
+fn is_multi_function(bus: u8, device: u8) -> bool {
+    let header_type = pci_read32(bus, device, 0, 0x0C);
+    ((header_type >> 16) & 0x80) != 0
+}
+
+/// Scan PCI for a legacy virtio device (vendor 0x1AF4, `device_id`) whose
+/// subsystem ID names the device type. Checks all functions (0..7) on
+/// multi-function devices. Enables bus mastering and I/O space on the
+/// match and returns (bus, device, I/O port base from BAR0).
+fn find_legacy(device_id: u16, subsystem_id: u16) -> Option<(u8, u8, u16)> {
+    for bus in 0..=255u16 {
+        for device in 0..32u8 {
+            let vendor_device = pci_read32(bus as u8, device, 0, 0x00);
+            let vendor_id = (vendor_device & 0xFFFF) as u16;
+
+            if vendor_id == 0xFFFF {
+                continue;
+            }
+
+            let max_func = if is_multi_function(bus as u8, device) { 8 } else { 1 };
+
+            for func in 0..max_func {
+                let vd = if func == 0 { vendor_device } else {
+                    let vd = pci_read32(bus as u8, device, func, 0x00);
+                    if (vd & 0xFFFF) as u16 == 0xFFFF { continue; }
+                    vd
+                };
+                let vid = (vd & 0xFFFF) as u16;
+                let did = ((vd >> 16) & 0xFFFF) as u16;
+
+                if vid != 0x1AF4 || did != device_id {
+                    continue;
+                }
+
+                // Check subsystem ID to confirm the device type
+                let subsys = pci_read32(bus as u8, device, func, 0x2C);
+                let subsys_id = ((subsys >> 16) & 0xFFFF) as u16;
+                if subsys_id != subsystem_id {
+                    continue;
+                }
+
+                // Enable bus mastering + I/O space access
+                let cmd = pci_read32(bus as u8, device, func, 0x04);
+                pci_write32(bus as u8, device, func, 0x04, cmd | 0x05);
+
+                // Read BAR0 — for legacy virtio this is an I/O port BAR
+                let bar0_raw = pci_read32(bus as u8, device, func, 0x10);
+                let iobase = (bar0_raw & !0x3) as u16;
+
+                return Some((bus as u8, device, iobase));
+            }
+        }
+    }
+    None
+}
@@ -13,12 +13,36 @@
 ///   0x10  Queue Notify         (WO, 16-bit)
 ///   0x12  Device Status        (RW, 8-bit)
 ///   0x13  ISR Status           (RO, 8-bit)
-///   0x14+ Device-specific config (MAC at 0x14..0x19)
+///   0x14+ Device-specific config (MAC at 0x14..0x19, status at 0x1A)
+///
+/// Notification is the legacy Queue Notify register, written only when
+/// the device hasn't set VIRTQ_USED_F_NO_NOTIFY. Each transmitted frame
+/// owns a DMA buffer until the device returns its descriptor on the used
+/// ring; when every tx descriptor is in flight `tx_ready` says so and
+/// smoltcp holds its packets instead of them being dropped.
+///
+/// Receive: with VIRTIO_NET_F_MRG_RXBUF the device may spread one frame
+/// over several `MRG_BUF_SIZE` buffers (the header's `num_buffers`), and
+/// `receive` joins them; without it every rx buffer is sized for a whole
+/// frame at the configured MTU (`net.mtu`, `DEFAULT_MTU`..`MAX_MTU`).
+///
+/// Checksum offload (`net.offload`, on by default): with VIRTIO_NET_F_CSUM
+/// the device fills in TCP/UDP checksums of frames we send, and with
+/// VIRTIO_NET_F_GUEST_CSUM it tells us which received frames it already
+/// checked. smoltcp is told to skip whichever side is covered.
+///
+/// Host TSO (VIRTIO_NET_F_HOST_TSO4/6) is deliberately not negotiated.
+/// smoltcp never builds a segment larger than the MTU, so the device would
+/// have nothing to split and `gso_type` stays 0 (none). Offering TSO
+/// needs a TCP stack that hands over super-segments, which smoltcp is not.
+///
+/// Link state: with VIRTIO_NET_F_STATUS the device reports carrier in its
+/// config space and `link_up` reads it; `NetStack` polls it. Devices
+/// without the feature are always up.
 use alloc::vec::Vec;
 use spin::Mutex;
 
 use crate::arch::x86_64::{inb, inl, inw, outb, outl, outw};
-use crate::drivers::pci::{pci_read32, pci_write32};
 use crate::mem::DmaBuf;
 use super::virtqueue::Virtqueue;
 
@@ -33,6 +57,7 @@ mod regs {
     pub const DEVICE_STATUS: u16   = 0x12;  // 8-bit RW
     pub const ISR_STATUS: u16      = 0x13;  // 8-bit RO
     pub const MAC_BASE: u16        = 0x14;  // device-specific: 6 bytes
+    pub const NET_STATUS: u16      = 0x1A;  // device-specific: 16-bit, with F_STATUS
 }
 
 /// Virtio device status bits.
@@ -42,6 +67,17 @@ const STATUS_DRIVER_OK: u8 = 4;
 
 /// Virtio-net feature bits.
 const VIRTIO_NET_F_MAC: u32 = 1 << 5;
+const VIRTIO_NET_F_CSUM: u32 = 1 << 0;
+const VIRTIO_NET_F_GUEST_CSUM: u32 = 1 << 1;
+const VIRTIO_NET_F_MRG_RXBUF: u32 = 1 << 15;
+const VIRTIO_NET_F_STATUS: u32 = 1 << 16;
+
+/// Config-space status bit: carrier present.
+const VIRTIO_NET_S_LINK_UP: u16 = 1;
+
+/// `VirtioNetHeader::flags` bits.
+const HDR_F_NEEDS_CSUM: u8 = 1;
+const HDR_F_DATA_VALID: u8 = 2;
 
 /// Virtio-net header prepended to every packet (legacy, 10 bytes).
 #[repr(C)]
@@ -57,8 +93,24 @@ pub struct VirtioNetHeader {
 
 const NET_HDR_SIZE: usize = core::mem::size_of::<VirtioNetHeader>(); // 10 bytes
 
-/// Maximum Ethernet frame + virtio header.
-const RX_BUF_SIZE: usize = 1514 + NET_HDR_SIZE;
+/// Header with MRG_RXBUF negotiated: `VirtioNetHeader` + num_buffers (u16),
+/// on both rx and tx.
+const MRG_HDR_SIZE: usize = NET_HDR_SIZE + 2;
+
+/// Rx buffer size with MRG_RXBUF: a standard frame fits in one.
+const MRG_BUF_SIZE: usize = 2048;
+
+/// Ethernet header (no FCS; the device strips it).
+pub const ETH_HDR_SIZE: usize = 14;
+
+/// IP MTU used unless `net.mtu` says otherwise.
+pub const DEFAULT_MTU: usize = 1500;
+
+/// Smallest MTU accepted (the IPv4 minimum datagram every host takes).
+pub const MIN_MTU: usize = 576;
+
+/// Largest MTU accepted (jumbo frames).
+pub const MAX_MTU: usize = 9000;
 
 /// Number of pre-allocated receive buffers.
 const RX_POOL_SIZE: usize = 64;
@@ -75,8 +127,37 @@ pub struct VirtioNet {
     mac: [u8; 6],
     /// Pre-allocated receive buffers, indexed by descriptor index.
     rx_buffers: Vec<DmaBuf>,
-    /// In-flight TX buffers awaiting device completion.
+    /// Size of each receive buffer.
+    rx_buf_size: usize,
+    /// Virtio-net header length: `MRG_HDR_SIZE` or `NET_HDR_SIZE`.
+    hdr_len: usize,
+    /// IP MTU; frames are at most `mtu + ETH_HDR_SIZE` bytes.
+    mtu: usize,
+    /// Received frames dropped (oversized, incomplete or bad checksum).
+    rx_dropped: u64,
+    /// The device computes tx checksums (VIRTIO_NET_F_CSUM).
+    tx_csum: bool,
+    /// The device flags rx checksum state (VIRTIO_NET_F_GUEST_CSUM).
+    rx_csum: bool,
+    /// The device reports link state (VIRTIO_NET_F_STATUS).
+    link_status: bool,
+    /// In-flight TX buffers awaiting device completion, by descriptor index.
     tx_inflight: Vec<Option<DmaBuf>>,
+    /// Times the tx ring filled up: counted once per stall, not per retry.
+    tx_ring_full: u64,
+    /// The last `tx_ready` found no free descriptor.
+    tx_stalled: bool,
+}
+
+/// TX ring occupancy, for `netstat`-style reporting.
+#[derive(Debug, Clone, Copy)]
+pub struct TxStats {
+    /// Frames handed to the device and not yet returned.
+    pub in_flight: u16,
+    /// Descriptors in the tx ring.
+    pub capacity: u16,
+    /// Times the ring filled up; retries while it stays full count once.
+    pub ring_full: u64,
 }
 
 unsafe impl Send for VirtioNet {}
@@ -87,7 +168,11 @@ impl VirtioNet {
     /// # Safety
     /// `iobase` must be the I/O port address from BAR0 of a legacy
     /// virtio-net PCI device (vendor 0x1AF4, device 0x1000, subsys 1).
-    pub unsafe fn new(iobase: u16) -> Result<Self, VirtioNetError> {
+    /// `mtu` is clamped to `MIN_MTU..=MAX_MTU`; `offload` allows checksum
+    /// offload when the device offers it.
+    pub unsafe fn new(iobase: u16, mtu: usize, offload: bool) -> Result<Self, VirtioNetError> {
+        let mtu = mtu.clamp(MIN_MTU, MAX_MTU);
+
         // 1. Reset device (write 0 to status)
         outb(iobase + regs::DEVICE_STATUS, 0);
 
@@ -97,11 +182,22 @@ impl VirtioNet {
         // 3. Driver: we know how to drive it
         outb(iobase + regs::DEVICE_STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER);
 
-        // 4. Negotiate features (we want MAC address)
+        // 4. Negotiate features (MAC address, mergeable rx buffers, link
+        //    status, checksums)
         let device_features = inl(iobase + regs::DEVICE_FEATURES);
-        let our_features = device_features & VIRTIO_NET_F_MAC;
+        let mut wanted = VIRTIO_NET_F_MAC | VIRTIO_NET_F_MRG_RXBUF | VIRTIO_NET_F_STATUS;
+        if offload {
+            // Checksums only; HOST_TSO4/6 stay off (see the module doc)
+            wanted |= VIRTIO_NET_F_CSUM | VIRTIO_NET_F_GUEST_CSUM;
+        }
+        let our_features = device_features & wanted;
         outl(iobase + regs::DRIVER_FEATURES, our_features);
         // Legacy virtio has no FEATURES_OK step — features are just set.
+        let (hdr_len, rx_buf_size) = if our_features & VIRTIO_NET_F_MRG_RXBUF != 0 {
+            (MRG_HDR_SIZE, MRG_BUF_SIZE)
+        } else {
+            (NET_HDR_SIZE, NET_HDR_SIZE + ETH_HDR_SIZE + mtu)
+        };
 
         // 5. Read MAC address from device-specific config
         let mut mac = [0u8; 6];
@@ -113,13 +209,24 @@ impl VirtioNet {
         let rx_queue = Self::setup_queue(iobase, 0)?;
         let tx_queue = Self::setup_queue(iobase, 1)?;
 
+        let mut tx_inflight = Vec::new();
+        tx_inflight.resize_with(tx_queue.size() as usize, || None);
         let mut driver = Self {
             iobase,
             rx_queue,
             tx_queue,
             mac,
             rx_buffers: Vec::new(),
-            tx_inflight: Vec::new(),
+            rx_buf_size,
+            hdr_len,
+            mtu,
+            rx_dropped: 0,
+            tx_csum: our_features & VIRTIO_NET_F_CSUM != 0,
+            rx_csum: our_features & VIRTIO_NET_F_GUEST_CSUM != 0,
+            link_status: our_features & VIRTIO_NET_F_STATUS != 0,
+            tx_inflight,
+            tx_ring_full: 0,
+            tx_stalled: false,
         };
 
         // 7. Pre-allocate and post receive buffers
@@ -154,12 +261,16 @@ impl VirtioNet {
         Ok(vq)
     }
 
-    /// Pre-allocate receive buffers and post them to the rx queue.
+    /// Pre-allocate receive buffers and post them to the rx queue. The
+    /// free list hands out descriptors 0, 1, 2, ... here, so buffer `i`
+    /// is descriptor `i` — and stays so, since `rx_take` re-posts each
+    /// buffer right after its descriptor is freed.
     fn fill_rx_pool(&mut self) -> Result<(), VirtioNetError> {
-        for _ in 0..RX_POOL_SIZE {
-            let buf = DmaBuf::alloc(RX_BUF_SIZE).map_err(|_| VirtioNetError::OutOfMemory)?;
+        let count = RX_POOL_SIZE.min(self.rx_queue.size() as usize);
+        for _ in 0..count {
+            let buf = DmaBuf::alloc(self.rx_buf_size).map_err(|_| VirtioNetError::OutOfMemory)?;
             let phys = buf.phys_addr();
-            self.rx_queue.add_buf(phys, RX_BUF_SIZE as u32, true);
+            self.rx_queue.add_buf(phys, self.rx_buf_size as u32, true);
             self.rx_buffers.push(buf);
         }
         // Notify device that rx queue has buffers
@@ -167,49 +278,72 @@ impl VirtioNet {
         Ok(())
     }
 
-    /// Reclaim completed TX buffers from the device.
+    /// Free the DMA buffers of frames the device has finished sending.
     fn reclaim_tx_buffers(&mut self) {
         while let Some((desc_idx, _len)) = self.tx_queue.poll_used() {
-            let idx = desc_idx as usize;
-            if idx < self.tx_inflight.len() {
-                self.tx_inflight[idx] = None;
+            if let Some(slot) = self.tx_inflight.get_mut(desc_idx as usize) {
+                *slot = None;
             }
         }
     }
 
+    /// Is there a free tx descriptor? Reclaims completed frames first.
+    /// False means the device is behind: the caller should hold the frame
+    /// and try again after the next poll.
+    pub fn tx_ready(&mut self) -> bool {
+        self.reclaim_tx_buffers();
+        if self.tx_queue.num_free() > 0 {
+            self.tx_stalled = false;
+            return true;
+        }
+        if !self.tx_stalled {
+            self.tx_stalled = true;
+            self.tx_ring_full += 1;
+        }
+        false
+    }
+
+    pub fn tx_stats(&self) -> TxStats {
+        TxStats {
+            in_flight: self.tx_queue.size() - self.tx_queue.num_free(),
+            capacity: self.tx_queue.size(),
+            ring_full: self.tx_ring_full,
+        }
+    }
+
     /// Transmit an Ethernet frame. Prepends the virtio-net header.
+    /// Fails with `QueueFull` when every tx descriptor is in flight.
     pub fn transmit(&mut self, frame: &[u8]) -> Result<(), VirtioNetError> {
-        self.reclaim_tx_buffers();
+        if !self.tx_ready() {
+            return Err(VirtioNetError::QueueFull);
+        }
 
-        let total_len = NET_HDR_SIZE + frame.len();
+        let total_len = self.hdr_len + frame.len();
         let mut buf = DmaBuf::alloc(total_len).map_err(|_| VirtioNetError::OutOfMemory)?;
 
-        // Write virtio-net header (all zeros = no offload)
-        let hdr = VirtioNetHeader::default();
-        let hdr_bytes = unsafe {
-            core::slice::from_raw_parts(
-                &hdr as *const VirtioNetHeader as *const u8,
-                NET_HDR_SIZE,
-            )
-        };
-
+        // Virtio-net header: all zeros = no offload (num_buffers, when
+        // present, is unused on tx)
         let data = buf.as_mut_slice();
-        data[..NET_HDR_SIZE].copy_from_slice(hdr_bytes);
-        data[NET_HDR_SIZE..total_len].copy_from_slice(frame);
+        data[..self.hdr_len].fill(0);
+        data[self.hdr_len..total_len].copy_from_slice(frame);
+        if self.tx_csum {
+            let (hdr, frame) = data[..total_len].split_at_mut(self.hdr_len);
+            if let Some((start, offset)) = csum::prepare_tx(frame) {
+                hdr[0] = HDR_F_NEEDS_CSUM;
+                hdr[6..8].copy_from_slice(&start.to_le_bytes());
+                hdr[8..10].copy_from_slice(&offset.to_le_bytes());
+            }
+        }
 
         buf.flush_cache();
         let phys = buf.phys_addr();
 
         match self.tx_queue.add_buf(phys, total_len as u32, false) {
             Some(desc_idx) => {
+                // Track buffer until device returns it
+                self.tx_inflight[desc_idx as usize] = Some(buf);
                 // Notify device: tx is queue 1
                 self.notify_queue(1);
-                // Track buffer until device returns it
-                let idx = desc_idx as usize;
-                if idx >= self.tx_inflight.len() {
-                    self.tx_inflight.resize_with(idx + 1, || None);
-                }
-                self.tx_inflight[idx] = Some(buf);
                 Ok(())
             }
             None => Err(VirtioNetError::QueueFull),
@@ -217,100 +351,256 @@ impl VirtioNet {
     }
 
     /// Poll for received Ethernet frames. Returns the frame data (without
-    /// the virtio-net header).
+    /// the virtio-net header), joined from all its buffers.
     pub fn receive(&mut self) -> Option<Vec<u8>> {
         let (desc_idx, len) = self.rx_queue.poll_used()?;
 
-        if (desc_idx as usize) < self.rx_buffers.len() && len as usize > NET_HDR_SIZE {
-            let buf = &self.rx_buffers[desc_idx as usize];
-            buf.invalidate_cache();
-
-            let frame = buf.as_slice()[NET_HDR_SIZE..len as usize].to_vec();
+        let mut packet = Vec::new();
+        let mut complete = self.rx_take(desc_idx, len, &mut packet);
+        let num_buffers = if complete && self.hdr_len == MRG_HDR_SIZE && packet.len() >= MRG_HDR_SIZE {
+            u16::from_le_bytes([packet[NET_HDR_SIZE], packet[NET_HDR_SIZE + 1]]).max(1)
+        } else {
+            1
+        };
+        // The device publishes all buffers of a frame at once
+        for _ in 1..num_buffers {
+            match self.rx_queue.poll_used() {
+                Some((desc_idx, len)) => complete &= self.rx_take(desc_idx, len, &mut packet),
+                None => {
+                    complete = false;
+                    break;
+                }
+            }
+        }
+        // Notify device that rx queue has new buffers
+        self.notify_queue(0);
 
-            // Re-post the buffer for future receives
-            let phys = buf.phys_addr();
-            self.rx_queue.add_buf(phys, RX_BUF_SIZE as u32, true);
-            // Notify device that rx queue has a new buffer
-            self.notify_queue(0);
+        let max_frame = self.mtu + ETH_HDR_SIZE;
+        if !complete || packet.len() <= self.hdr_len || packet.len() - self.hdr_len > max_frame {
+            self.rx_dropped += 1;
+            return None;
+        }
+        if self.rx_csum && !self.rx_checksum(&mut packet) {
+            self.rx_dropped += 1;
+            return None;
+        }
+        packet.drain(..self.hdr_len);
+        Some(packet)
+    }
 
-            Some(frame)
+    /// With GUEST_CSUM smoltcp doesn't verify TCP/UDP checksums, so every
+    /// frame it gets must be right: finish the ones the device left
+    /// partial, trust the ones it checked, check the rest here.
+    fn rx_checksum(&self, packet: &mut [u8]) -> bool {
+        let (hdr, frame) = packet.split_at_mut(self.hdr_len);
+        if hdr[0] & HDR_F_NEEDS_CSUM != 0 {
+            let start = u16::from_le_bytes([hdr[6], hdr[7]]) as usize;
+            let offset = u16::from_le_bytes([hdr[8], hdr[9]]) as usize;
+            csum::complete(frame, start, offset)
+        } else if hdr[0] & HDR_F_DATA_VALID != 0 {
+            true
         } else {
-            None
+            csum::verify(frame)
         }
     }
 
+    /// Append the `len` bytes the device wrote into buffer `desc_idx` to
+    /// `packet`, then hand the buffer back to the device. False if the
+    /// descriptor is not one of ours.
+    fn rx_take(&mut self, desc_idx: u16, len: u32, packet: &mut Vec<u8>) -> bool {
+        let Some(buf) = self.rx_buffers.get(desc_idx as usize) else {
+            return false;
+        };
+        buf.invalidate_cache();
+        let len = (len as usize).min(self.rx_buf_size);
+        packet.extend_from_slice(&buf.as_slice()[..len]);
+
+        // Re-post the buffer for future receives
+        let phys = buf.phys_addr();
+        self.rx_queue.add_buf(phys, self.rx_buf_size as u32, true);
+        true
+    }
+
     /// Get the MAC address.
     pub fn mac(&self) -> [u8; 6] {
         self.mac
     }
 
-    /// Notify the device that a queue has new buffers.
-    /// For legacy virtio: write the queue index to the Queue Notify register.
+    /// Is there carrier? Always true when the device doesn't report it.
+    pub fn link_up(&self) -> bool {
+        !self.link_status || inw(self.iobase + regs::NET_STATUS) & VIRTIO_NET_S_LINK_UP != 0
+    }
+
+    /// Does the device report link state at all?
+    pub fn reports_link(&self) -> bool {
+        self.link_status
+    }
+
+    /// IP MTU in use.
+    pub fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    /// Checksum offload in effect: (device fills tx checksums, device
+    /// reports rx checksum state).
+    pub fn checksum_offload(&self) -> (bool, bool) {
+        (self.tx_csum, self.rx_csum)
+    }
+
+    /// Was VIRTIO_NET_F_MRG_RXBUF negotiated?
+    pub fn mergeable_rx(&self) -> bool {
+        self.hdr_len == MRG_HDR_SIZE
+    }
+
+    /// Received frames dropped since boot.
+    pub fn rx_dropped(&self) -> u64 {
+        self.rx_dropped
+    }
+
+    /// Notify the device that a queue has new buffers, unless it asked
+    /// not to be. For legacy virtio: write the queue index to the Queue
+    /// Notify register.
     #[inline]
     fn notify_queue(&self, queue_idx: u16) {
-        outw(self.iobase + regs::QUEUE_NOTIFY, queue_idx);
+        let queue = if queue_idx == 0 { &self.rx_queue } else { &self.tx_queue };
+        if queue.needs_notify() {
+            outw(self.iobase + regs::QUEUE_NOTIFY, queue_idx);
+        }
     }
 }
 
-/// Check if a PCI device is multi-function (Header Type bit 7).
-fn is_multi_function(bus: u8, device: u8) -> bool {
-    let header_type = pci_read32(bus, device, 0, 0x0C);
-    ((header_type >> 16) & 0x80) != 0
-}
-
-/// Scan PCI for a legacy virtio-net device.
-/// Legacy virtio: vendor 0x1AF4, device 0x1000, subsystem ID 1 (network).
-/// Checks all functions (0..7) on multi-function devices.
-pub fn find_virtio_net() -> Option<VirtioNetPciInfo> {
-    for bus in 0..=255u16 {
-        for device in 0..32u8 {
-            let vendor_device = pci_read32(bus as u8, device, 0, 0x00);
-            let vendor_id = (vendor_device & 0xFFFF) as u16;
+/// TCP/UDP checksums (RFC 1071) over IPv4 and IPv6 for the offload paths.
+mod csum {
+    use super::ETH_HDR_SIZE;
+
+    const IPV6_HDR_SIZE: usize = 40;
+
+    /// Where a frame's TCP or UDP header is.
+    struct L4 {
+        /// Offset of the header in the frame.
+        start: usize,
+        /// Header plus payload.
+        len: usize,
+        /// Checksum field offset in the header.
+        field: usize,
+        /// IP protocol number (6 = TCP, 17 = UDP).
+        proto: u8,
+    }
 
-            if vendor_id == 0xFFFF {
-                continue;
+    /// Locate the TCP or UDP header of an unfragmented IPv4 frame, or of
+    /// an IPv6 frame without extension headers.
+    fn l4(frame: &[u8]) -> Option<L4> {
+        if frame.len() < ETH_HDR_SIZE + 20 {
+            return None;
+        }
+        let ip = &frame[ETH_HDR_SIZE..];
+        let (hdr, total, proto) = match frame[12..14] {
+            [0x08, 0x00] => {
+                let ihl = (ip[0] & 0x0F) as usize * 4;
+                let total = u16::from_be_bytes([ip[2], ip[3]]) as usize;
+                let fragment = u16::from_be_bytes([ip[6], ip[7]]) & 0x3FFF;
+                if ip[0] >> 4 != 4 || ihl < 20 || total < ihl || fragment != 0 {
+                    return None;
+                }
+                (ihl, total, ip[9])
             }
+            [0x86, 0xDD] => {
+                if ip.len() < IPV6_HDR_SIZE || ip[0] >> 4 != 6 {
+                    return None;
+                }
+                let payload = u16::from_be_bytes([ip[4], ip[5]]) as usize;
+                (IPV6_HDR_SIZE, IPV6_HDR_SIZE + payload, ip[6])
+            }
+            _ => return None,
+        };
+        if total > ip.len() {
+            return None;
+        }
+        let field = match proto {
+            6 => 16,
+            17 => 6,
+            _ => return None,
+        };
+        if total - hdr < field + 2 {
+            return None;
+        }
+        Some(L4 { start: ETH_HDR_SIZE + hdr, len: total - hdr, field, proto })
+    }
 
-            let max_func = if is_multi_function(bus as u8, device) { 8 } else { 1 };
-
-            for func in 0..max_func {
-                let vd = if func == 0 { vendor_device } else {
-                    let vd = pci_read32(bus as u8, device, func, 0x00);
-                    if (vd & 0xFFFF) as u16 == 0xFFFF { continue; }
-                    vd
-                };
-                let vid = (vd & 0xFFFF) as u16;
-                let did = ((vd >> 16) & 0xFFFF) as u16;
+    /// One's-complement sum of big-endian 16-bit words, unfolded.
+    fn sum(data: &[u8], mut acc: u64) -> u64 {
+        let (words, rest) = data.as_chunks::<2>();
+        for w in words {
+            acc += u16::from_be_bytes(*w) as u64;
+        }
+        if let [last] = rest {
+            acc += (*last as u64) << 8;
+        }
+        acc
+    }
 
-                if vid != 0x1AF4 || did != 0x1000 {
-                    continue;
-                }
+    fn fold(mut acc: u64) -> u16 {
+        while acc >> 16 != 0 {
+            acc = (acc & 0xFFFF) + (acc >> 16);
+        }
+        acc as u16
+    }
 
-                // Check subsystem ID to confirm it's a network device (subsys 1)
-                let subsys = pci_read32(bus as u8, device, func, 0x2C);
-                let subsys_id = ((subsys >> 16) & 0xFFFF) as u16;
-                if subsys_id != 1 {
-                    continue;
-                }
+    /// Pseudo-header: source, destination, protocol, L4 length.
+    fn pseudo(frame: &[u8], l4: &L4) -> u64 {
+        let ip = &frame[ETH_HDR_SIZE..];
+        let addrs = if frame[12..14] == [0x86, 0xDD] { &ip[8..40] } else { &ip[12..20] };
+        sum(addrs, l4.proto as u64 + l4.len as u64)
+    }
 
-                // Enable bus mastering + I/O space access
-                let cmd = pci_read32(bus as u8, device, func, 0x04);
-                pci_write32(bus as u8, device, func, 0x04, cmd | 0x05);
+    /// Set a frame up for the device to checksum: the field holds the
+    /// pseudo-header sum, and the device adds everything from
+    /// `csum_start`. Returns (csum_start, csum_offset), or None for
+    /// frames that carry no TCP/UDP checksum.
+    pub fn prepare_tx(frame: &mut [u8]) -> Option<(u16, u16)> {
+        let l4 = l4(frame)?;
+        let partial = fold(pseudo(frame, &l4));
+        let at = l4.start + l4.field;
+        frame[at..at + 2].copy_from_slice(&partial.to_be_bytes());
+        Some((l4.start as u16, l4.field as u16))
+    }
 
-                // Read BAR0 — for legacy virtio this is an I/O port BAR
-                let bar0_raw = pci_read32(bus as u8, device, func, 0x10);
-                let iobase = (bar0_raw & !0x3) as u16;
+    /// Finish a partial checksum the device left (the field already holds
+    /// the pseudo-header sum). False if the offsets don't fit the frame.
+    pub fn complete(frame: &mut [u8], start: usize, offset: usize) -> bool {
+        let end = l4(frame).map_or(frame.len(), |l4| l4.start + l4.len);
+        if start + offset + 2 > end {
+            return false;
+        }
+        let mut c = !fold(sum(&frame[start..end], 0));
+        if c == 0 {
+            c = 0xFFFF;
+        }
+        frame[start + offset..start + offset + 2].copy_from_slice(&c.to_be_bytes());
+        true
+    }
 
-                return Some(VirtioNetPciInfo {
-                    bus: bus as u8,
-                    device,
-                    device_id: did,
-                    iobase,
-                });
-            }
+    /// Does a received frame's TCP/UDP checksum hold? Frames without one
+    /// (ARP, ICMP, UDP over IPv4 sent with checksum 0) pass.
+    pub fn verify(frame: &[u8]) -> bool {
+        let Some(l4) = l4(frame) else {
+            return true;
+        };
+        let at = l4.start + l4.field;
+        // A zero UDP checksum means "none" over IPv4 only (RFC 8200 §8.1)
+        let is_v4_udp = l4.proto == 17 && frame[12..14] == [0x08, 0x00];
+        if is_v4_udp && frame[at] == 0 && frame[at + 1] == 0 {
+            return true;
         }
+        fold(sum(&frame[l4.start..l4.start + l4.len], pseudo(frame, &l4))) == 0xFFFF
     }
-    None
+}
+
+/// Scan PCI for a legacy virtio-net device.
+/// Legacy virtio: vendor 0x1AF4, device 0x1000, subsystem ID 1 (network).
+pub fn find_virtio_net() -> Option<VirtioNetPciInfo> {
+    let (bus, device, iobase) = super::find_legacy(0x1000, 1)?;
+    Some(VirtioNetPciInfo { bus, device, device_id: 0x1000, iobase })
 }
 
 #[derive(Debug)]
@@ -342,5 +632,11 @@ impl core::fmt::Display for VirtioNetError {
     }
 }
 
+impl From<VirtioNetError> for crate::error::KernelError {
+    fn from(e: VirtioNetError) -> Self {
+        crate::error::KernelError::new(crate::error::Layer::Net, alloc::format!("{:?}", e), e)
+    }
+}
+
 /// Global virtio-net driver instance.
 pub static VIRTIO_NET: Mutex<Option<VirtioNet>> = Mutex::new(None);
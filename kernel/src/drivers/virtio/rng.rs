@@ -0,0 +1,107 @@
+/// Virtio-rng driver — the host's entropy via QEMU `-device virtio-rng-pci`
+/// (legacy mode, port I/O, same register layout as virtio-net).
+///
+/// The device has one queue: the driver posts a device-writable buffer
+/// and the device fills some or all of it with random bytes. We post one
+/// buffer at a time and poll for it, which is all a seed source needs.
+use spin::Mutex;
+
+use crate::arch::x86_64::{inw, outb, outl, outw};
+use crate::mem::DmaBuf;
+use super::virtqueue::Virtqueue;
+
+mod regs {
+    pub const DRIVER_FEATURES: u16 = 0x04;
+    pub const QUEUE_ADDRESS: u16   = 0x08;
+    pub const QUEUE_SIZE: u16      = 0x0C;
+    pub const QUEUE_SELECT: u16    = 0x0E;
+    pub const QUEUE_NOTIFY: u16    = 0x10;
+    pub const DEVICE_STATUS: u16   = 0x12;
+}
+
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+
+/// Bytes requested per round trip.
+const CHUNK: usize = 64;
+
+/// How long to wait for the device to fill a buffer. QEMU rate-limits
+/// virtio-rng when told to, so this is generous.
+const TIMEOUT_MS: u64 = 1000;
+
+pub struct VirtioRng {
+    iobase: u16,
+    queue: Virtqueue,
+    buf: DmaBuf,
+}
+
+unsafe impl Send for VirtioRng {}
+
+impl VirtioRng {
+    /// Initialize the virtio-rng device at the given I/O port base.
+    ///
+    /// # Safety
+    /// `iobase` must be the I/O port address from BAR0 of a legacy
+    /// virtio-rng PCI device (vendor 0x1AF4, device 0x1005, subsys 4).
+    pub unsafe fn new(iobase: u16) -> Result<Self, &'static str> {
+        outb(iobase + regs::DEVICE_STATUS, 0);
+        outb(iobase + regs::DEVICE_STATUS, STATUS_ACKNOWLEDGE);
+        outb(iobase + regs::DEVICE_STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+        // virtio-rng has no feature bits we use
+        outl(iobase + regs::DRIVER_FEATURES, 0);
+
+        outw(iobase + regs::QUEUE_SELECT, 0);
+        let size = inw(iobase + regs::QUEUE_SIZE);
+        if size == 0 {
+            return Err("virtio-rng queue not available");
+        }
+        let queue = Virtqueue::new(size).map_err(|_| "out of memory")?;
+        outl(iobase + regs::QUEUE_ADDRESS, queue.pfn());
+        let buf = DmaBuf::alloc(CHUNK).map_err(|_| "out of memory")?;
+
+        outb(iobase + regs::DEVICE_STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_DRIVER_OK);
+        Ok(VirtioRng { iobase, queue, buf })
+    }
+
+    /// Fill `dest` with bytes from the device. Fails if the device stops
+    /// answering.
+    pub fn fill(&mut self, dest: &mut [u8]) -> Result<(), &'static str> {
+        let mut filled = 0;
+        while filled < dest.len() {
+            self.queue
+                .add_buf(self.buf.phys_addr(), CHUNK as u32, true)
+                .ok_or("virtio-rng queue full")?;
+            outw(self.iobase + regs::QUEUE_NOTIFY, 0);
+
+            let deadline = crate::time::monotonic_ms() + TIMEOUT_MS;
+            let len = loop {
+                if let Some((_, len)) = self.queue.poll_used() {
+                    break len as usize;
+                }
+                if crate::time::monotonic_ms() > deadline {
+                    return Err("virtio-rng timed out");
+                }
+                core::hint::spin_loop();
+            };
+
+            self.buf.invalidate_cache();
+            let n = len.min(CHUNK).min(dest.len() - filled);
+            if n == 0 {
+                return Err("virtio-rng returned no bytes");
+            }
+            dest[filled..filled + n].copy_from_slice(&self.buf.as_slice()[..n]);
+            filled += n;
+        }
+        crate::crypto::zeroize::zeroize(self.buf.as_mut_slice());
+        Ok(())
+    }
+}
+
+/// Scan PCI for a legacy virtio-rng device: (bus, device, I/O port base).
+pub fn find_virtio_rng() -> Option<(u8, u8, u16)> {
+    super::find_legacy(0x1005, 4)
+}
+
+/// Global virtio-rng driver instance, if the VM has one.
+pub static VIRTIO_RNG: Mutex<Option<VirtioRng>> = Mutex::new(None);
@@ -17,6 +17,9 @@ use crate::mem::{PhysAddr, DmaBuf, AllocError};
 /// Virtqueue descriptor flags.
 const VIRTQ_DESC_F_WRITE: u16 = 2; // Buffer is device-writable (for rx)
 
+/// Used ring flag: the device polls the available ring, don't notify it.
+const VIRTQ_USED_F_NO_NOTIFY: u16 = 1;
+
 /// A single descriptor in the descriptor table (16 bytes).
 #[repr(C)]
 #[derive(Clone, Copy, Default)]
@@ -140,6 +143,20 @@ impl Virtqueue {
         self.size
     }
 
+    /// Descriptors not currently owned by the device.
+    pub fn num_free(&self) -> u16 {
+        self.num_free
+    }
+
+    /// Does the device want a notification for new available buffers?
+    /// It clears this while it is already processing the ring.
+    pub fn needs_notify(&self) -> bool {
+        // Order our avail.idx write before reading the device's flag
+        fence(Ordering::SeqCst);
+        let flags = unsafe { core::ptr::read_volatile(&(*self.used_hdr_ptr()).flags) };
+        flags & VIRTQ_USED_F_NO_NOTIFY == 0
+    }
+
     // ---- Internal pointer helpers ----
 
     fn avail_hdr_ptr(&self) -> *mut VirtqAvailHdr {
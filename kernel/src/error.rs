@@ -0,0 +1,120 @@
+/// Kernel-wide error report with a trail through the layers.
+///
+/// Subsystems keep their own error enums — callers still decide what to
+/// do by matching `FileError::Full` or `ApiError::HttpStatus`. What they
+/// print is a `KernelError`: the layer the failure started in, a stable
+/// code, and each layer it passed through on the way up, outermost first:
+///
+///   vfs: xWrite → storage: write → nvme: CommandFailed(0x2): NVMe command failed: status 0x2
+///
+/// Every subsystem error converts with `From`; a layer adds itself with
+/// `.context(Layer::Storage, "write")`, on a `KernelError` or (through
+/// the `Context` trait) on any `Result` whose error converts.
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+/// Where in the kernel an error was raised or passed through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layer {
+    Nvme,
+    Storage,
+    Vfs,
+    Net,
+    Dns,
+    Tls,
+    Http,
+    Api,
+}
+
+impl Layer {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Layer::Nvme => "nvme",
+            Layer::Storage => "storage",
+            Layer::Vfs => "vfs",
+            Layer::Net => "net",
+            Layer::Dns => "dns",
+            Layer::Tls => "tls",
+            Layer::Http => "http",
+            Layer::Api => "api",
+        }
+    }
+}
+
+impl fmt::Display for Layer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KernelError {
+    /// Layer the failure started in.
+    layer: Layer,
+    /// Variant name, e.g. `CommandFailed(0x2)` — stable enough to grep for.
+    code: String,
+    /// Human-readable description from the originating error.
+    message: String,
+    /// Layers the error passed through, innermost first.
+    trail: Vec<(Layer, String)>,
+}
+
+impl KernelError {
+    pub fn new(layer: Layer, code: impl Into<String>, message: impl fmt::Display) -> Self {
+        Self {
+            layer,
+            code: code.into(),
+            message: alloc::format!("{}", message),
+            trail: Vec::new(),
+        }
+    }
+
+    /// Record that the error passed through `layer` while doing `what`.
+    pub fn context(mut self, layer: Layer, what: impl Into<String>) -> Self {
+        self.trail.push((layer, what.into()));
+        self
+    }
+
+    /// The layer the failure started in.
+    pub fn layer(&self) -> Layer {
+        self.layer
+    }
+
+    pub fn code(&self) -> &str {
+        &self.code
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// Every layer involved, outermost first, ending with the origin.
+    pub fn layers(&self) -> impl Iterator<Item = Layer> + '_ {
+        self.trail.iter().rev().map(|(layer, _)| *layer).chain(core::iter::once(self.layer))
+    }
+}
+
+impl fmt::Display for KernelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (layer, what) in self.trail.iter().rev() {
+            write!(f, "{}: {} → ", layer, what)?;
+        }
+        write!(f, "{}: {}", self.layer, self.code)?;
+        if !self.message.is_empty() && self.message != self.code {
+            write!(f, ": {}", self.message)?;
+        }
+        Ok(())
+    }
+}
+
+/// `.context()` on a `Result`, converting its error to a `KernelError`.
+pub trait Context<T> {
+    fn context(self, layer: Layer, what: impl Into<String>) -> Result<T, KernelError>;
+}
+
+impl<T, E: Into<KernelError>> Context<T> for Result<T, E> {
+    fn context(self, layer: Layer, what: impl Into<String>) -> Result<T, KernelError> {
+        self.map_err(|e| e.into().context(layer, what))
+    }
+}
@@ -1 +1,2 @@
 pub mod styx;
+pub mod tmpfs;
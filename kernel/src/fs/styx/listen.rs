@@ -0,0 +1,208 @@
+/// 9P over TCP — serves the namespace to host tools.
+///
+/// With `config set styx.port 564` (off by default) a listening socket
+/// takes one client at a time; each connection gets a fresh
+/// `StyxServer`, so fids never outlive it. The port is advertised over
+/// mDNS as `_9p._tcp`.
+///
+/// `pump` runs from the console's idle loop like the other network
+/// pumps. The stack is released before a message is handled: files such
+/// as `/lua/eval` may need the network themselves. The agent loop also
+/// serves clients while its request holds the stack (`pump_held`), so
+/// an agent's ctl file answers mid-run.
+///
+/// Any client that can reach the port can attach under any uname, so
+/// the namespace ACL is the only guard — keep the port behind QEMU's
+/// user-mode NAT or a firewall.
+use alloc::vec::Vec;
+use smoltcp::iface::SocketHandle;
+use spin::Mutex;
+
+use super::namespace;
+use super::StyxServer;
+use crate::net::stack::NetStack;
+
+/// The mDNS service type for 9P.
+const SERVICE: &str = "_9p._tcp";
+
+/// Minimum time between two `pump` runs.
+const POLL_MS: u64 = 10;
+
+/// Unanswered bytes a client may queue before it is dropped (two
+/// messages of the largest msize).
+const MAX_INBOX: usize = 2 * 65536;
+
+struct Listener {
+    port: u16,
+    /// Listening or connected socket; None until the stack is up.
+    socket: Option<SocketHandle>,
+    /// Session of the connected client.
+    server: Option<StyxServer>,
+    /// Bytes received but not yet a whole message.
+    inbox: Vec<u8>,
+    /// Replies not yet taken by the socket.
+    outbox: Vec<u8>,
+    last_poll_ms: u64,
+}
+
+static LISTENER: Mutex<Option<Listener>> = Mutex::new(None);
+
+/// The port from `styx.port`, if serving is on.
+pub fn port() -> Option<u16> {
+    crate::sqlite::config_get("styx.port").and_then(|v| parse_port(&v))
+}
+
+/// `styx.port`: a port number, or "off"/0 for none.
+pub fn parse_port(value: &str) -> Option<u16> {
+    value.trim().parse().ok().filter(|&p| p != 0)
+}
+
+/// Start or stop serving per `styx.port`. Safe to call again after the
+/// setting changes.
+pub fn configure() {
+    let old = LISTENER.lock().take();
+    if let Some(handle) = old.and_then(|l| l.socket) {
+        if let Some(net) = crate::net::NET_STACK.lock().as_mut() {
+            net.tcp_close(handle);
+        }
+    }
+    let Some(port) = port() else {
+        crate::net::mdns::withdraw(SERVICE);
+        return;
+    };
+    *LISTENER.lock() = Some(Listener {
+        port,
+        socket: None,
+        server: None,
+        inbox: Vec::new(),
+        outbox: Vec::new(),
+        last_poll_ms: 0,
+    });
+    crate::net::mdns::advertise(SERVICE, port, Vec::new());
+}
+
+/// The port being served on, if any.
+pub fn serving() -> Option<u16> {
+    LISTENER.lock().as_ref().map(|l| l.port)
+}
+
+/// Accept a client, answer its whole messages and send the replies.
+/// Cheap when serving is off or the last run was under `POLL_MS` ago;
+/// skipped while the network stack is in use.
+pub fn pump() {
+    let now = crate::time::monotonic_ms();
+    let Some(mut guard) = LISTENER.try_lock() else { return };
+    let Some(l) = guard.as_mut() else { return };
+    if now.saturating_sub(l.last_poll_ms) < POLL_MS {
+        return;
+    }
+    l.last_poll_ms = now;
+
+    let requests = {
+        let Some(mut net_guard) = crate::net::NET_STACK.try_lock() else { return };
+        let Some(net) = net_guard.as_mut() else { return };
+        receive(l, net)
+    };
+    answer(l, &requests);
+    if l.outbox.is_empty() {
+        return;
+    }
+    let Some(mut net_guard) = crate::net::NET_STACK.try_lock() else { return };
+    let Some(net) = net_guard.as_mut() else { return };
+    send(l, net);
+}
+
+/// `pump` for a caller that holds the network stack and is waiting on
+/// it, such as the agent loop during a request, so a `stop` written to
+/// an agent's ctl file gets through. Messages are answered with the stack
+/// still held: a file that needs the network itself finds it busy.
+pub fn pump_held(net: &mut NetStack) {
+    let now = crate::time::monotonic_ms();
+    let Some(mut guard) = LISTENER.try_lock() else { return };
+    let Some(l) = guard.as_mut() else { return };
+    if now.saturating_sub(l.last_poll_ms) < POLL_MS {
+        return;
+    }
+    l.last_poll_ms = now;
+
+    let requests = receive(l, net);
+    answer(l, &requests);
+    if !l.outbox.is_empty() {
+        send(l, net);
+    }
+}
+
+/// Accept a client and take its whole messages off the socket. Drops
+/// a client that is gone or floods, to listen again on the next run.
+fn receive(l: &mut Listener, net: &mut NetStack) -> Vec<Vec<u8>> {
+    let mut requests = Vec::new();
+    net.poll();
+    let handle = match l.socket {
+        Some(handle) => handle,
+        None => match net.tcp_listen(l.port) {
+            Some(handle) => *l.socket.insert(handle),
+            None => return requests,
+        },
+    };
+    if net.tcp_is_listening(handle) {
+        return requests;
+    }
+    let mut buf = [0u8; 4096];
+    while net.tcp_can_recv(handle) {
+        let n = net.tcp_recv(handle, &mut buf);
+        if n == 0 {
+            break;
+        }
+        l.inbox.extend_from_slice(&buf[..n]);
+    }
+    if l.inbox.len() > MAX_INBOX || (!net.tcp_is_active(handle) && !net.tcp_can_recv(handle)) {
+        // Client gone or flooding: close, and listen again on the next run
+        net.tcp_close(handle);
+        l.socket = None;
+        l.server = None;
+        l.inbox.clear();
+        l.outbox.clear();
+        return requests;
+    }
+    while let Some(msg) = next_message(&mut l.inbox) {
+        requests.push(msg);
+    }
+    requests
+}
+
+/// Answer `requests` in the client's session, queueing the replies.
+fn answer(l: &mut Listener, requests: &[Vec<u8>]) {
+    if requests.is_empty() {
+        return;
+    }
+    let server = l.server.get_or_insert_with(|| StyxServer::new(namespace::build_root()));
+    for msg in requests {
+        let reply = server.handle_message(msg);
+        l.outbox.extend_from_slice(&reply);
+    }
+}
+
+/// Hand queued replies to the socket.
+fn send(l: &mut Listener, net: &mut NetStack) {
+    let Some(handle) = l.socket else { return };
+    if net.tcp_can_send(handle) {
+        let n = net.tcp_send(handle, &l.outbox);
+        l.outbox.drain(..n);
+        net.poll();
+    }
+}
+
+/// Take one whole 9P message (size[4] counts itself) off the front of
+/// `inbox`. A size under the 7-byte header is passed on alone for the
+/// server to reject.
+pub(crate) fn next_message(inbox: &mut Vec<u8>) -> Option<Vec<u8>> {
+    if inbox.len() < 4 {
+        return None;
+    }
+    let size = u32::from_le_bytes([inbox[0], inbox[1], inbox[2], inbox[3]]) as usize;
+    let take = size.max(4);
+    if inbox.len() < take {
+        return None;
+    }
+    Some(inbox.drain(..take).collect())
+}
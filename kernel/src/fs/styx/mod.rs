@@ -8,6 +8,9 @@
 /// - 9P2000 message parsing and serialization
 /// - A synthetic file tree (no on-disk files — all generated on read)
 /// - The /db/ctl SQL interface (Styx → SQLite)
+/// - /lua/eval: one-shot sandboxed Lua evaluation for host tooling
+/// - A TCP transport, off unless `styx.port` is set (see `listen`)
+pub mod listen;
 mod message;
 mod server;
 pub mod namespace;
@@ -21,6 +21,15 @@ pub struct Node {
     pub kind: NodeKind,
 }
 
+/// Stream read callback: (cursor, max bytes) → (data, next cursor).
+pub type StreamReader = fn(u64, usize) -> Result<(Vec<u8>, u64), String>;
+
+/// Write callback for one instance: (key, data).
+pub type KeyedWriter = fn(u64, &[u8]) -> Result<(), String>;
+
+/// Stream read callback for one instance: (key, cursor, max bytes).
+pub type KeyedStreamReader = fn(u64, u64, usize) -> Result<(Vec<u8>, u64), String>;
+
 /// What kind of node this is.
 pub enum NodeKind {
     /// Directory containing child nodes.
@@ -34,6 +43,38 @@ pub enum NodeKind {
         on_write: Option<fn(&[u8]) -> Result<(), String>>,
     },
 
+    /// Stream file — records produced incrementally, never materialized.
+    /// Each read continues from a per-fid cursor; a read at offset 0
+    /// starts over. Stat reports length 0.
+    StreamFile {
+        /// Given the cursor and a byte budget, return the next bytes and
+        /// the new cursor. Empty data means end of stream for now.
+        read_from: StreamReader,
+    },
+
+    /// Directory whose entries come and go with kernel state (e.g. one
+    /// per agent session). `list` names the current entries; `make` builds
+    /// the node for a name not seen before. `refresh` syncs `children`,
+    /// keeping existing nodes so their qids stay stable.
+    DynamicDir {
+        list: fn() -> Vec<String>,
+        make: fn(&str) -> Option<Node>,
+        children: BTreeMap<String, Node>,
+    },
+
+    /// Synthetic file belonging to one instance; callbacks get its key.
+    KeyedFile {
+        key: u64,
+        on_read: fn(u64) -> Vec<u8>,
+        on_write: Option<KeyedWriter>,
+    },
+
+    /// Stream file belonging to one instance (see StreamFile).
+    KeyedStream {
+        key: u64,
+        read_from: KeyedStreamReader,
+    },
+
     /// Control file — write commands, read responses.
     /// Used for /db/ctl and /hw/gpu/compute/ctl.
     CtlFile {
@@ -84,6 +125,81 @@ impl Node {
         }
     }
 
+    /// Create a read-only stream file.
+    pub fn stream(name: &str, read_from: StreamReader) -> Self {
+        Self {
+            name: String::from(name),
+            path_id: alloc_path(),
+            kind: NodeKind::StreamFile { read_from },
+        }
+    }
+
+    /// Create a directory of dynamic entries (see NodeKind::DynamicDir).
+    pub fn dynamic_dir(name: &str, list: fn() -> Vec<String>, make: fn(&str) -> Option<Node>) -> Self {
+        Self {
+            name: String::from(name),
+            path_id: alloc_path(),
+            kind: NodeKind::DynamicDir {
+                list,
+                make,
+                children: BTreeMap::new(),
+            },
+        }
+    }
+
+    /// Create a synthetic file for instance `key`; writable if `on_write`.
+    pub fn keyed_file(
+        name: &str,
+        key: u64,
+        on_read: fn(u64) -> Vec<u8>,
+        on_write: Option<KeyedWriter>,
+    ) -> Self {
+        Self {
+            name: String::from(name),
+            path_id: alloc_path(),
+            kind: NodeKind::KeyedFile { key, on_read, on_write },
+        }
+    }
+
+    /// Create a read-only stream file for instance `key`.
+    pub fn keyed_stream(name: &str, key: u64, read_from: KeyedStreamReader) -> Self {
+        Self {
+            name: String::from(name),
+            path_id: alloc_path(),
+            kind: NodeKind::KeyedStream { key, read_from },
+        }
+    }
+
+    /// Is this a stream file (read through a per-fid cursor)?
+    pub fn is_stream(&self) -> bool {
+        matches!(self.kind, NodeKind::StreamFile { .. } | NodeKind::KeyedStream { .. })
+    }
+
+    /// Read a stream file from `cursor`; None if this isn't one.
+    pub fn read_stream(&self, cursor: u64, count: usize) -> Option<Result<(Vec<u8>, u64), String>> {
+        match &self.kind {
+            NodeKind::StreamFile { read_from } => Some(read_from(cursor, count)),
+            NodeKind::KeyedStream { key, read_from } => Some(read_from(*key, cursor, count)),
+            _ => None,
+        }
+    }
+
+    /// Bring a dynamic directory's entries up to date; no-op otherwise.
+    pub fn refresh(&mut self) {
+        let NodeKind::DynamicDir { list, make, children } = &mut self.kind else {
+            return;
+        };
+        let names = list();
+        children.retain(|name, _| names.contains(name));
+        for name in names {
+            if let alloc::collections::btree_map::Entry::Vacant(entry) = children.entry(name) {
+                if let Some(node) = make(entry.key()) {
+                    entry.insert(node);
+                }
+            }
+        }
+    }
+
     /// Create a control file (write command, read response).
     pub fn ctl(name: &str, on_command: fn(&[u8]) -> Vec<u8>) -> Self {
         Self {
@@ -98,14 +214,14 @@ impl Node {
 
     /// Add a child to a directory node.
     pub fn add_child(&mut self, child: Node) {
-        if let NodeKind::Dir { children } = &mut self.kind {
+        if let NodeKind::Dir { children } | NodeKind::DynamicDir { children, .. } = &mut self.kind {
             children.insert(child.name.clone(), child);
         }
     }
 
     /// Look up a child by name.
     pub fn child(&self, name: &str) -> Option<&Node> {
-        if let NodeKind::Dir { children } = &self.kind {
+        if let NodeKind::Dir { children } | NodeKind::DynamicDir { children, .. } = &self.kind {
             children.get(name)
         } else {
             None
@@ -114,7 +230,7 @@ impl Node {
 
     /// Look up a child by name (mutable).
     pub fn child_mut(&mut self, name: &str) -> Option<&mut Node> {
-        if let NodeKind::Dir { children } = &mut self.kind {
+        if let NodeKind::Dir { children } | NodeKind::DynamicDir { children, .. } = &mut self.kind {
             children.get_mut(name)
         } else {
             None
@@ -123,13 +239,13 @@ impl Node {
 
     /// Is this a directory?
     pub fn is_dir(&self) -> bool {
-        matches!(self.kind, NodeKind::Dir { .. })
+        matches!(self.kind, NodeKind::Dir { .. } | NodeKind::DynamicDir { .. })
     }
 
     /// Read this node's content.
     pub fn read(&self) -> Vec<u8> {
         match &self.kind {
-            NodeKind::Dir { children } => {
+            NodeKind::Dir { children } | NodeKind::DynamicDir { children, .. } => {
                 // Directory listing: one name per line
                 let mut out = Vec::new();
                 for name in children.keys() {
@@ -139,6 +255,8 @@ impl Node {
                 out
             }
             NodeKind::SyntheticFile { on_read, .. } => on_read(),
+            NodeKind::StreamFile { .. } | NodeKind::KeyedStream { .. } => Vec::new(), // read through the fid cursor
+            NodeKind::KeyedFile { key, on_read, .. } => on_read(*key),
             NodeKind::CtlFile { response, .. } => response.clone(),
         }
     }
@@ -146,7 +264,12 @@ impl Node {
     /// Write data to this node.
     pub fn write(&mut self, data: &[u8]) -> Result<(), String> {
         match &mut self.kind {
-            NodeKind::Dir { .. } => Err(String::from("cannot write to directory")),
+            NodeKind::Dir { .. } | NodeKind::DynamicDir { .. } => Err(String::from("cannot write to directory")),
+            NodeKind::StreamFile { .. } | NodeKind::KeyedStream { .. } => Err(String::from("read-only file")),
+            NodeKind::KeyedFile { key, on_write, .. } => match on_write {
+                Some(handler) => handler(*key, data),
+                None => Err(String::from("read-only file")),
+            },
             NodeKind::SyntheticFile { on_write, .. } => {
                 if let Some(handler) = on_write {
                     handler(data)
@@ -194,6 +317,15 @@ pub fn build_root() -> Node {
         );
         msg.into_bytes()
     }));
+    sys.add_child(Node::stream("audit", |cursor, count| {
+        crate::sqlite::audit::ndjson_after(cursor, count)
+    }));
+    sys.add_child(Node::file("quota", crate::sqlite::quota::report));
+    sys.add_child(Node::file("entropy", crate::crypto::entropy::report));
+    sys.add_child(Node::file("sockets", crate::net::stats::report));
+    sys.add_child(Node::file("spans", crate::span::report));
+    sys.add_child(Node::file("ratelimit", crate::api::ratelimit::report));
+    sys.add_child(Node::file("cpu", crate::arch::x86_64::idle::report));
     root.add_child(sys);
 
     // /hw/
@@ -204,9 +336,30 @@ pub fn build_root() -> Node {
     hw.add_child(gpu);
     root.add_child(hw);
 
-    // /agents/
-    let agents = Node::dir("agents");
-    root.add_child(agents);
+    // /lua/eval — write Lua, read back its result
+    let mut lua = Node::dir("lua");
+    lua.add_child(Node::ctl("eval", crate::lua::eval::ctl));
+    root.add_child(lua);
+
+    // /agents/<id>/{ctl,log,status} — agent loop sessions
+    root.add_child(Node::dynamic_dir("agents", agent_session_ids, agent_session_dir));
 
     root
 }
+
+/// Names under /agents/: one per live or recent agent session.
+fn agent_session_ids() -> Vec<String> {
+    crate::shell::sessions::ids().iter().map(|id| alloc::format!("{}", id)).collect()
+}
+
+/// /agents/<id>/ for a session.
+fn agent_session_dir(name: &str) -> Option<Node> {
+    use crate::shell::sessions;
+
+    let id: u64 = name.parse().ok()?;
+    let mut dir = Node::dir(name);
+    dir.add_child(Node::keyed_file("ctl", id, sessions::ctl_read, Some(sessions::ctl_write)));
+    dir.add_child(Node::keyed_stream("log", id, sessions::log_after));
+    dir.add_child(Node::keyed_file("status", id, sessions::status, None));
+    Some(dir)
+}
@@ -1,10 +1,18 @@
 /// Styx server — handles 9P2000 requests against the synthetic namespace.
+///
+/// Each attach acts as the principal `styx:<uname>` (`styx:none` for an
+/// empty uname). Topen checks the node's path against the namespace ACL
+/// (see `sqlite::acl`), so a namespace row for e.g. `/db/ctl` with mode
+/// 0o600 keeps other clients out of it. Twalk resolves `.` and `..` and
+/// refuses names with a `/` (see `sqlite::namespace::canonicalize`), so a
+/// fid's path is the one the ACL is checked against.
 use alloc::collections::BTreeMap;
 use alloc::string::String;
 use alloc::vec::Vec;
 
 use super::message::{self, StyxMsg, Qid, Stat};
 use super::namespace::Node;
+use crate::sqlite::acl::{self, Access, Principal};
 
 /// Maximum message size negotiated in Tversion.
 const MAX_MSIZE: u32 = 65536;
@@ -18,6 +26,10 @@ struct Fid {
     path: Vec<String>,
     /// Is this fid open for I/O?
     open: bool,
+    /// Position in a stream file (opaque to the server; see StreamFile).
+    cursor: u64,
+    /// Who attached (inherited by walks from this fid).
+    principal: Principal,
 }
 
 /// The Styx server: processes 9P2000 messages against a namespace.
@@ -37,13 +49,19 @@ impl StyxServer {
     }
 
     /// Process a raw 9P2000 message buffer and return the response bytes.
+    /// Each message is a request of its own (`crate::span`).
     pub fn handle_message(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut span = crate::span::begin("styx");
         match message::parse(data) {
             Ok(msg) => {
                 let response = self.dispatch(msg);
+                if matches!(response, StyxMsg::Rerror { .. }) {
+                    span.fail();
+                }
                 message::encode(&response)
             }
             Err(_) => {
+                span.fail();
                 let err = StyxMsg::Rerror {
                     tag: 0,
                     ename: String::from("parse error"),
@@ -70,13 +88,16 @@ impl StyxServer {
                 }
             }
 
-            StyxMsg::Tattach { tag, fid, .. } => {
+            StyxMsg::Tattach { tag, fid, uname, .. } => {
                 if self.fids.len() >= MAX_FIDS {
                     return self.error(tag, "too many fids");
                 }
+                let uname = if uname.is_empty() { String::from("none") } else { uname };
                 self.fids.insert(fid, Fid {
                     path: Vec::new(), // root
                     open: false,
+                    cursor: 0,
+                    principal: Principal::Styx(uname),
                 });
                 StyxMsg::Rattach {
                     tag,
@@ -85,8 +106,8 @@ impl StyxServer {
             }
 
             StyxMsg::Twalk { tag, fid, newfid, wnames } => {
-                let base_path = match self.fids.get(&fid) {
-                    Some(f) => f.path.clone(),
+                let (base_path, principal) = match self.fids.get(&fid) {
+                    Some(f) => (f.path.clone(), f.principal.clone()),
                     None => return self.error(tag, "unknown fid"),
                 };
 
@@ -94,7 +115,20 @@ impl StyxServer {
                 let mut qids = Vec::new();
 
                 for name in &wnames {
-                    current_path.push(name.clone());
+                    self.refresh_path(&current_path);
+                    if name.contains('/') {
+                        return self.error(tag, "invalid name");
+                    }
+                    // `..` at the root stays there, as in 9P
+                    if name != ".." || !current_path.is_empty() {
+                        let joined = alloc::format!("/{}/{}", current_path.join("/"), name);
+                        match crate::sqlite::namespace::canonicalize(&joined) {
+                            Ok(path) => {
+                                current_path = path.split('/').filter(|p| !p.is_empty()).map(String::from).collect();
+                            }
+                            Err(e) => return self.error(tag, &e),
+                        }
+                    }
                     match self.resolve_path(&current_path) {
                         Some(node) => {
                             let qid = if node.is_dir() {
@@ -116,16 +150,23 @@ impl StyxServer {
                 self.fids.insert(newfid, Fid {
                     path: current_path,
                     open: false,
+                    cursor: 0,
+                    principal,
                 });
 
                 StyxMsg::Rwalk { tag, qids }
             }
 
-            StyxMsg::Topen { tag, fid, .. } => {
+            StyxMsg::Topen { tag, fid, mode } => {
                 let node = match self.fid_to_node(&fid) {
                     Some(n) => n,
                     None => return self.error(tag, "unknown fid"),
                 };
+                if let Some(f) = self.fids.get(&fid) {
+                    if let Err(e) = check_open(f, mode) {
+                        return self.error(tag, &e);
+                    }
+                }
 
                 let qid = if node.is_dir() {
                     Qid::dir(node.path_id)
@@ -135,6 +176,7 @@ impl StyxServer {
 
                 if let Some(f) = self.fids.get_mut(&fid) {
                     f.open = true;
+                    f.cursor = 0;
                 }
 
                 StyxMsg::Ropen {
@@ -145,16 +187,37 @@ impl StyxServer {
             }
 
             StyxMsg::Tread { tag, fid, offset, count } => {
-                match self.fids.get(&fid) {
+                let path = match self.fids.get(&fid) {
                     Some(f) if !f.open => return self.error(tag, "fid not open"),
                     None => return self.error(tag, "unknown fid"),
-                    _ => {}
-                }
+                    Some(f) => f.path.clone(),
+                };
+                self.refresh_path(&path);
                 let node = match self.fid_to_node(&fid) {
                     Some(n) => n,
                     None => return self.error(tag, "unknown fid"),
                 };
 
+                // Streams ignore the byte offset (except 0 = rewind) and
+                // continue from the fid's cursor.
+                if node.is_stream() {
+                    let cursor = match self.fids.get(&fid) {
+                        Some(f) if offset != 0 => f.cursor,
+                        _ => 0,
+                    };
+                    let result = node.read_stream(cursor, count as usize).unwrap_or_else(|| Ok((Vec::new(), cursor)));
+                    let Some(f) = self.fids.get_mut(&fid) else {
+                        return self.error(tag, "unknown fid");
+                    };
+                    return match result {
+                        Ok((data, next)) => {
+                            f.cursor = next;
+                            StyxMsg::Rread { tag, data }
+                        }
+                        Err(e) => self.error(tag, &e),
+                    };
+                }
+
                 let content = node.read();
                 let offset = offset as usize;
                 let count = count as usize;
@@ -192,6 +255,9 @@ impl StyxServer {
             }
 
             StyxMsg::Tstat { tag, fid } => {
+                if let Some(path) = self.fids.get(&fid).map(|f| f.path.clone()) {
+                    self.refresh_path(&path);
+                }
                 let node = match self.fid_to_node(&fid) {
                     Some(n) => n,
                     None => return self.error(tag, "unknown fid"),
@@ -229,6 +295,22 @@ impl StyxServer {
         Some(current)
     }
 
+    /// Refresh every dynamic directory from the root down to `path`, so
+    /// entries that appeared or went away since the last request show.
+    fn refresh_path(&mut self, path: &[String]) {
+        let mut current = &mut self.root;
+        current.refresh();
+        for component in path {
+            match current.child_mut(component) {
+                Some(next) => {
+                    current = next;
+                    current.refresh();
+                }
+                None => return,
+            }
+        }
+    }
+
     fn resolve_path_mut(&mut self, path: &[String]) -> Option<&mut Node> {
         let mut current = &mut self.root;
         for component in path {
@@ -254,3 +336,28 @@ impl StyxServer {
         }
     }
 }
+
+/// 9P open modes (low two bits of Topen's mode).
+const OWRITE: u8 = 1;
+const ORDWR: u8 = 2;
+const OTRUNC: u8 = 0x10;
+
+/// Check an open against the namespace ACL. Without a database only the
+/// synthetic tree is reachable, so nothing is denied.
+fn check_open(fid: &Fid, mode: u8) -> Result<(), String> {
+    let path = alloc::format!("/{}", fid.path.join("/"));
+    let guard = crate::sqlite::DB.lock();
+    let Some(db) = guard.as_ref() else {
+        return Ok(());
+    };
+    let wants: &[Access] = match mode & 3 {
+        OWRITE => &[Access::Write],
+        ORDWR => &[Access::Read, Access::Write],
+        _ if mode & OTRUNC != 0 => &[Access::Read, Access::Write],
+        _ => &[Access::Read],
+    };
+    for &access in wants {
+        acl::check(db, &fid.principal, &path, access)?;
+    }
+    Ok(())
+}
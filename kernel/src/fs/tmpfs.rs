@@ -0,0 +1,172 @@
+/// `/tmp`: namespace files kept in RAM instead of SQLite.
+///
+/// Scratch output — an agent's intermediate results, a response about to
+/// be parsed — doesn't need to outlive the boot, and writing it through
+/// SQLite costs NVMe write cycles, journal traffic and database pages.
+/// Paths under `/tmp/` live in a heap map instead. Every namespace write
+/// made through `acl::write_file` (Lua `write()`, the agent tools, `rx`,
+/// `edit`) lands here for such a path, and Lua `read`/`read_range`/
+/// `lines`/`ls`, the agent tools and the shell's `cat`, `ls` and `rm` read
+/// from here. Nothing is persisted: `/tmp` is empty after every boot.
+///
+/// The total size is capped by config `tmp.size` (bytes, or K/M/G;
+/// `DEFAULT_LIMIT` when unset); a write that would pass it fails. As in a
+/// sticky Unix `/tmp`, anyone may read a file or create one, but only its
+/// creator (and the shell) may overwrite or remove it.
+///
+/// Paths compare as in the namespace — ASCII case folded, spelling kept:
+/// files are keyed by the folded path and remember the one last written.
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+use crate::sqlite::acl::{self, Access, Principal};
+use crate::sqlite::namespace;
+
+/// Paths under this prefix are in RAM.
+pub const PREFIX: &str = "/tmp/";
+
+/// Size limit when config `tmp.size` is unset.
+pub const DEFAULT_LIMIT: u64 = 4 * 1024 * 1024;
+
+const SIZE_KEY: &str = "tmp.size";
+
+struct File {
+    /// The path as last written.
+    path: String,
+    data: Vec<u8>,
+    owner: Principal,
+}
+
+/// A file, for listings.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub path: String,
+    pub len: u64,
+    pub owner: Principal,
+}
+
+static FILES: Mutex<BTreeMap<String, File>> = Mutex::new(BTreeMap::new());
+static LIMIT: AtomicU64 = AtomicU64::new(DEFAULT_LIMIT);
+
+/// Is `path` in `/tmp` (the directory itself included)?
+pub fn is_tmp(path: &str) -> bool {
+    namespace::has_prefix(path, PREFIX) || path.eq_ignore_ascii_case("/tmp")
+}
+
+/// The key of `path` in `FILES`.
+fn key(path: &str) -> String {
+    path.to_ascii_lowercase()
+}
+
+/// (Re)read `tmp.size`. Files already stored are kept even if they no
+/// longer fit; only new writes are refused.
+pub fn configure() {
+    let limit = crate::sqlite::config_get(SIZE_KEY)
+        .and_then(|v| crate::sqlite::quota::parse_size(&v))
+        .unwrap_or(DEFAULT_LIMIT);
+    LIMIT.store(limit, Ordering::Relaxed);
+}
+
+/// The size limit, in bytes.
+pub fn limit() -> u64 {
+    LIMIT.load(Ordering::Relaxed)
+}
+
+/// Files and bytes stored.
+pub fn usage() -> (usize, u64) {
+    let files = FILES.lock();
+    (files.len(), files.values().map(|f| f.data.len() as u64).sum())
+}
+
+/// The whole content of a file.
+pub fn read(path: &str) -> Option<Vec<u8>> {
+    FILES.lock().get(&key(path)).map(|f| f.data.clone())
+}
+
+/// Copy up to `buf.len()` bytes from `offset` (0 at or past the end).
+pub fn read_at(path: &str, offset: usize, buf: &mut [u8]) -> Option<usize> {
+    let files = FILES.lock();
+    let data = &files.get(&key(path))?.data;
+    let start = offset.min(data.len());
+    let n = buf.len().min(data.len() - start);
+    buf[..n].copy_from_slice(&data[start..start + n]);
+    Some(n)
+}
+
+/// A file's length.
+pub fn len(path: &str) -> Option<usize> {
+    FILES.lock().get(&key(path)).map(|f| f.data.len())
+}
+
+/// Create or overwrite a file on behalf of `who`.
+pub fn write(who: &Principal, path: &str, data: &[u8]) -> Result<(), String> {
+    if path.len() <= PREFIX.len() || !namespace::has_prefix(path, PREFIX) || path.ends_with('/') {
+        return Err(alloc::format!("{}: not a file name under {}", path, PREFIX));
+    }
+    let key = key(path);
+    let mut files = FILES.lock();
+    let old = match files.get(&key) {
+        Some(f) if !may_modify(who, &f.owner) => return Err(acl::denied(who, path, Access::Write)),
+        Some(f) => f.data.len() as u64,
+        None => 0,
+    };
+    let used: u64 = files.values().map(|f| f.data.len() as u64).sum();
+    let after = used - old + data.len() as u64;
+    if after > limit() {
+        return Err(alloc::format!(
+            "{}: /tmp full ({} of {} bytes used, {} more needed)",
+            path,
+            used,
+            limit(),
+            after - limit()
+        ));
+    }
+    let owner = files.get(&key).map_or_else(|| who.clone(), |f| f.owner.clone());
+    files.insert(key, File { path: String::from(path), data: Vec::from(data), owner });
+    Ok(())
+}
+
+/// Remove a file, or with `tree` everything under `path/`. Returns how
+/// many files went; fails without removing anything if one of them isn't
+/// `who`'s.
+pub fn remove(who: &Principal, path: &str, tree: bool) -> Result<u64, String> {
+    let mut files = FILES.lock();
+    let doomed: Vec<String> = if tree {
+        let prefix = key(&alloc::format!("{}/", path.trim_end_matches('/')));
+        files.keys().filter(|p| p.starts_with(prefix.as_str())).cloned().collect()
+    } else {
+        files.keys().filter(|p| **p == key(path)).cloned().collect()
+    };
+    if doomed.is_empty() {
+        return Err(alloc::format!("{}: no such file", path));
+    }
+    if let Some(f) = doomed.iter().map(|p| &files[p.as_str()]).find(|f| !may_modify(who, &f.owner)) {
+        return Err(acl::denied(who, &f.path, Access::Write));
+    }
+    for p in &doomed {
+        files.remove(p.as_str());
+    }
+    Ok(doomed.len() as u64)
+}
+
+/// Paths starting with `prefix` (case folded), sorted.
+pub fn list(prefix: &str) -> Vec<String> {
+    let prefix = key(prefix);
+    FILES.lock().iter().filter(|(k, _)| k.starts_with(prefix.as_str())).map(|(_, f)| f.path.clone()).collect()
+}
+
+/// Every file, sorted by path.
+pub fn entries() -> Vec<Entry> {
+    FILES
+        .lock()
+        .values()
+        .map(|f| Entry { path: f.path.clone(), len: f.data.len() as u64, owner: f.owner.clone() })
+        .collect()
+}
+
+fn may_modify(who: &Principal, owner: &Principal) -> bool {
+    *who == Principal::Shell || who == owner
+}
@@ -0,0 +1,114 @@
+/// Kernel message buffer, shown by `dmesg`.
+///
+/// Everything written to the serial console from power-on until the
+/// shell starts — the boot log — is kept in a `CAPACITY`-byte ring, so it
+/// can be read back after it has scrolled away. Once the shell takes the
+/// console, capture stops: command output is not kernel messages.
+/// When the ring is full the oldest text is dropped.
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+
+/// Bytes of log text kept.
+const CAPACITY: usize = 32 * 1024;
+
+/// Fixed ring, so logging works before the heap exists.
+struct Ring {
+    buf: [u8; CAPACITY],
+    /// Next write position.
+    head: usize,
+    /// Total bytes ever written (saturating); >= CAPACITY once wrapped.
+    written: usize,
+}
+
+static LOG: Mutex<Ring> = Mutex::new(Ring { buf: [0; CAPACITY], head: 0, written: 0 });
+
+static CAPTURING: AtomicBool = AtomicBool::new(true);
+
+/// Append console output to the log while capture is on. Never blocks:
+/// text printed while the log is locked (a panic mid-append) is skipped.
+pub fn record(s: &str) {
+    if !CAPTURING.load(Ordering::Relaxed) {
+        return;
+    }
+    let Some(mut log) = LOG.try_lock() else {
+        return;
+    };
+    for &b in s.as_bytes() {
+        let head = log.head;
+        log.buf[head] = b;
+        log.head = (head + 1) % CAPACITY;
+    }
+    log.written = log.written.saturating_add(s.len());
+}
+
+/// Stop capturing (the shell owns the console from here on).
+pub fn stop_capture() {
+    CAPTURING.store(false, Ordering::Relaxed);
+}
+
+/// The captured text, oldest first. A line cut by the ring wrapping is
+/// dropped.
+pub fn contents() -> String {
+    let log = LOG.lock();
+    let mut bytes = Vec::with_capacity(log.written.min(CAPACITY));
+    let wrapped = log.written >= CAPACITY;
+    if wrapped {
+        bytes.extend_from_slice(&log.buf[log.head..]);
+    }
+    bytes.extend_from_slice(&log.buf[..log.head]);
+    drop(log);
+    let mut text = String::from_utf8_lossy(&bytes).into_owned();
+    if wrapped {
+        if let Some(nl) = text.find('\n') {
+            text.drain(..=nl);
+        }
+    }
+    text
+}
+
+/// Total bytes captured so far — a cursor for `lines_since`.
+pub fn written() -> usize {
+    LOG.lock().written
+}
+
+/// Complete lines captured after `cursor` (an earlier `written()`), and
+/// the cursor to pass next time. A line still being written stays for
+/// the next call; text that has already been overwritten is skipped.
+pub fn lines_since(cursor: usize) -> (Vec<String>, usize) {
+    let log = LOG.lock();
+    let behind = log.written.saturating_sub(cursor);
+    let lost = behind > CAPACITY;
+    let n = behind.min(CAPACITY);
+    let start = (log.head + CAPACITY - n) % CAPACITY;
+    let mut bytes = Vec::with_capacity(n);
+    if start + n > CAPACITY {
+        bytes.extend_from_slice(&log.buf[start..]);
+        bytes.extend_from_slice(&log.buf[..log.head]);
+    } else {
+        bytes.extend_from_slice(&log.buf[start..start + n]);
+    }
+    let written = log.written;
+    drop(log);
+
+    // The partial line after the last newline is picked up next time
+    let Some(end) = bytes.iter().rposition(|&b| b == b'\n') else {
+        return (Vec::new(), if lost { written - n } else { cursor });
+    };
+    let next = written - (bytes.len() - end - 1);
+    let mut text = &bytes[..end];
+    if lost {
+        // The first line lost its beginning to the wrap
+        match text.iter().position(|&b| b == b'\n') {
+            Some(nl) => text = &text[nl + 1..],
+            None => return (Vec::new(), next),
+        }
+    }
+    let lines = text
+        .split(|&b| b == b'\n')
+        .map(|l| String::from_utf8_lossy(l).trim_end_matches('\r').into())
+        .filter(|l: &String| !l.is_empty())
+        .collect();
+    (lines, next)
+}
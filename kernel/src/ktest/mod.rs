@@ -0,0 +1,2453 @@
+/// In-kernel integration tests — built with `--features ktest`.
+///
+/// Host tests (`make test`) cover what can run against stubs. This suite
+/// covers the rest: real DMA buffers, the NVMe driver, SQLite on the VFS,
+/// and the Styx server, all on the booted kernel. `kmain` boots as usual,
+/// then calls `run` instead of dropping into the shell.
+///
+/// The result is reported through QEMU's isa-debug-exit device
+/// (`-device isa-debug-exit,iobase=0xf4,iosize=0x04`): QEMU exits with
+/// status `(code << 1) | 1`, i.e. 33 for pass and 35 for fail. A kernel
+/// panic also exits with the failure code, so a hung or crashed suite never
+/// looks like a pass.
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::arch::x86_64::{hlt, outl};
+use crate::serial_println;
+
+/// I/O port of QEMU's isa-debug-exit device.
+pub const DEBUG_EXIT_PORT: u16 = 0xf4;
+
+/// Code written to `DEBUG_EXIT_PORT`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum QemuExit {
+    /// QEMU exit status 33.
+    Success = 0x10,
+    /// QEMU exit status 35.
+    Failed = 0x11,
+}
+
+/// Exit QEMU with the given code. Halts forever if the device is absent
+/// (e.g. on real hardware).
+pub fn exit_qemu(code: QemuExit) -> ! {
+    outl(DEBUG_EXIT_PORT, code as u32);
+    loop {
+        hlt();
+    }
+}
+
+type TestFn = fn() -> Result<(), String>;
+
+/// The suite, in run order. SQLite tests assume `sqlite::init` succeeded.
+const SUITE: &[(&str, TestFn)] = &[
+    ("arch::fpu_state", fpu_state),
+    ("arch::serial_line_settings", serial_line_settings),
+    ("arch::serial_pending", serial_pending),
+    ("arch::idle_wait", idle_wait),
+    ("time::conversions", time_conversions),
+    ("time::calibration_drift", calibration_drift),
+    ("time::deadline_timer", deadline_timer),
+    ("mem::heap_arenas", heap_arenas),
+    ("storage::ramdisk_format_load_relocate", storage_ramdisk),
+    ("storage::nvme_superblock", storage_nvme_superblock),
+    ("storage::nvme_status_decoding", nvme_status_decoding),
+    ("sqlite::open_insert_select", sqlite_insert_select),
+    ("vfs::relocation_under_sqlite", vfs_relocation),
+    ("vfs::temp_files_in_ram", vfs_temp_files),
+    ("vfs::mmap_reads", vfs_mmap_reads),
+    ("sqlite::busy_handler", sqlite_busy_handler),
+    ("sqlite::db_stats", sqlite_db_stats),
+    ("sqlite::rtree_bbox", sqlite_rtree_bbox),
+    ("fs::tmpfs_scratch", tmpfs_scratch),
+    ("sqlite::namespace_acl", namespace_acl),
+    ("sqlite::agent_sql_content", agent_sql_content),
+    ("sqlite::nocase_paths", nocase_paths),
+    ("sqlite::archive_subtree", archive_subtree),
+    ("sqlite::canonical_paths", canonical_paths),
+    ("sqlite::subtree_quota", subtree_quota),
+    ("sqlite::compressed_files", compressed_files),
+    ("sqlite::change_journal", change_journal),
+    ("sqlite::response_cache", response_cache),
+    ("sqlite::vector_search", vector_search),
+    ("sqlite::outbox_queue", outbox_queue),
+    ("sqlite::outbox_batches", outbox_batches),
+    ("sqlite::system_prompts", system_prompts),
+    ("sqlite::tool_policy", tool_policy),
+    ("sqlite::build_info", build_info),
+    ("sqlite::json_views", json_views),
+    ("sqlite::json_output_error", json_output_error),
+    ("sqlite::lifecycle_events", lifecycle_events),
+    ("sqlite::audit_request_ids", audit_request_ids),
+    ("diff::unified", diff_unified),
+    ("lua::agent_timers", agent_timers),
+    ("lua::agent_on_change", agent_on_change),
+    ("lua::autorun", lua_autorun),
+    ("lua::interrupt_foreground", lua_interrupt_foreground),
+    ("shell::command_registry", command_registry),
+    ("shell::mv_tree", mv_tree),
+    ("shell::key_bindings", key_bindings),
+    ("shell::alias", shell_alias),
+    ("shell::tool_result_cap", tool_result_cap),
+    ("shell::context_window", context_window),
+    ("shell::model_routing", model_routing),
+    ("crypto::zeroize", zeroize_buffers),
+    ("crypto::entropy", entropy_sources),
+    ("crypto::cert_inspect", cert_inspect),
+    ("crypto::pin_trust", pin_trust),
+    ("api::json_parse", json_parse),
+    ("api::non_streaming_message", non_streaming_message),
+    ("api::dechunk_body", dechunk_body),
+    ("api::batch_results", batch_results),
+    ("api::inflate_bodies", inflate_bodies),
+    ("api::request_timeout", request_timeout),
+    ("api::interrupted_stream", interrupted_stream),
+    ("api::retry_policy", retry_policy),
+    ("api::rate_limits", rate_limits),
+    ("api::trace_redaction", trace_redaction),
+    ("api::trace_private", trace_private),
+    ("api::mock_responses", mock_responses),
+    ("net::tcp_retransmit_rtt", tcp_retransmit_rtt),
+    ("net::slaac_router_advert", slaac_router_advert),
+    ("net::syslog_target", syslog_target),
+    ("net::dns_answers", dns_answers),
+    ("net::mdns_answer", mdns_answer),
+    ("net::tls_buffer_pool", tls_buffer_pool),
+    ("net::tls_cipher_suites", tls_cipher_suites),
+    ("styx::encode_decode", styx_roundtrip),
+    ("styx::agent_sessions", styx_agent_sessions),
+    ("styx::agent_ctl_stop", styx_agent_ctl_stop),
+    ("styx::lua_eval", styx_lua_eval),
+    ("styx::framing", styx_framing),
+];
+
+/// Run every test, print a summary, and exit QEMU.
+pub fn run() -> ! {
+    serial_println!("[ktest] running {} tests", SUITE.len());
+
+    let mut failed = 0usize;
+    for (name, test) in SUITE {
+        match test() {
+            Ok(()) => serial_println!("[ktest] {} ... ok", name),
+            Err(e) => {
+                failed += 1;
+                serial_println!("[ktest] {} ... FAILED: {}", name, e);
+            }
+        }
+    }
+
+    let passed = SUITE.len() - failed;
+    if failed == 0 {
+        serial_println!("[ktest] result: ok. {} passed; 0 failed", passed);
+        exit_qemu(QemuExit::Success)
+    } else {
+        serial_println!("[ktest] result: FAILED. {} passed; {} failed", passed, failed);
+        exit_qemu(QemuExit::Failed)
+    }
+}
+
+/// Return `Err(format!(...))` unless `cond` holds.
+macro_rules! ensure {
+    ($cond:expr, $($arg:tt)*) => {
+        if !$cond {
+            return Err(format!($($arg)*));
+        }
+    };
+}
+
+// ---- FPU ----
+
+/// SSE is on with the default MXCSR, and FXSAVE/FXRSTOR round-trip it.
+fn fpu_state() -> Result<(), String> {
+    use crate::arch::x86_64::fpu::{self, FpuState};
+
+    ensure!(fpu::mxcsr() == 0x1F80, "MXCSR {:#x}", fpu::mxcsr());
+    ensure!(!fpu::in_interrupt(), "interrupt depth leaked");
+    let mut saved = FpuState::new();
+    saved.save();
+    ensure!(saved.saved_mxcsr() == 0x1F80, "saved MXCSR {:#x}", saved.saved_mxcsr());
+    unsafe { fpu::set_mxcsr(0x7F80) }; // round toward zero
+    ensure!(fpu::mxcsr() == 0x7F80, "MXCSR not written");
+    saved.restore();
+    ensure!(fpu::mxcsr() == 0x1F80, "MXCSR not restored: {:#x}", fpu::mxcsr());
+
+    let x = core::hint::black_box(2.0f64);
+    ensure!(x * 1.5 == 3.0, "f64 arithmetic");
+    ensure!(fpu::misuse_count() == 0, "{} FPU uses in handlers", fpu::misuse_count());
+    Ok(())
+}
+
+/// Divisors for standard and fast rates, and what the UART cannot do.
+fn serial_line_settings() -> Result<(), String> {
+    use crate::arch::x86_64::serial::{divisor_for, FlowControl, DEFAULT_CLOCK};
+
+    ensure!(divisor_for(115_200, DEFAULT_CLOCK) == Some(1), "115200");
+    ensure!(divisor_for(9_600, DEFAULT_CLOCK) == Some(12), "9600");
+    ensure!(divisor_for(921_600, DEFAULT_CLOCK).is_none(), "921600 from 1.8432 MHz");
+    ensure!(divisor_for(1_500_000, 24_000_000) == Some(1), "1.5M from 24 MHz");
+    ensure!(divisor_for(100_000, DEFAULT_CLOCK).is_none(), "100000 is off by more than 3%");
+    ensure!(FlowControl::parse("xon") == Some(FlowControl::XonXoff), "xon");
+    ensure!(FlowControl::parse("rtscts") == Some(FlowControl::RtsCts), "rtscts");
+    ensure!(FlowControl::parse("maybe").is_none(), "maybe");
+    Ok(())
+}
+
+/// Dropping the Ctrl-Cs read ahead keeps the other bytes, in order, even
+/// across the ring's wrap.
+fn serial_pending() -> Result<(), String> {
+    use crate::arch::x86_64::serial::Pending;
+
+    let mut pending = Pending::new();
+    // Move the head near the end, so what follows wraps
+    for _ in 0..60 {
+        pending.push(b'.');
+        pending.pop();
+    }
+    for &b in b"a\x03bc\x03\x03d\x03" {
+        pending.push(b);
+    }
+    pending.remove(0x03);
+    let mut kept = Vec::new();
+    while let Some(b) = pending.pop() {
+        kept.push(b);
+    }
+    ensure!(kept == b"abcd", "kept {:?}", kept);
+    ensure!(pending.is_empty(), "ring not empty");
+    Ok(())
+}
+
+/// The tick wakes `wait`, a delay idles through it yet ends on time,
+/// and the idle time is counted.
+fn idle_wait() -> Result<(), String> {
+    use crate::arch::x86_64::idle;
+    use crate::time;
+
+    ensure!(idle::mode() != idle::Mode::Spin, "idle not initialized");
+    let start = time::monotonic_us();
+    for _ in 0..5 {
+        idle::wait();
+    }
+    let waited = time::monotonic_us() - start;
+    ensure!(waited < 50_000, "5 waits took {}us: no tick?", waited);
+
+    let (idle_before, start) = (idle::idle_ms(), time::monotonic_us());
+    time::delay(time::Duration::from_millis(20));
+    let took = time::monotonic_us() - start;
+    ensure!((20_000..25_000).contains(&took), "20ms delay took {}us", took);
+    let idled = idle::idle_ms() - idle_before;
+    ensure!(idled >= 10, "only {}ms of a 20ms delay idle", idled);
+    ensure!(idle::idle_percent() <= 100, "idle over 100%");
+    Ok(())
+}
+
+// ---- Time ----
+
+/// TSC ticks and durations convert both ways at any rate, saturating
+/// rather than wrapping; instants and deadlines order as they should.
+fn time_conversions() -> Result<(), String> {
+    use crate::time::{duration_at, ticks_at, Deadline, Duration, Instant};
+
+    ensure!(ticks_at(Duration::from_millis(1), 2_000_000) == 2_000_000, "1ms at 2 GHz");
+    ensure!(ticks_at(Duration::from_nanos(1_500), 2_000_000) == 3_000, "1.5us at 2 GHz");
+    ensure!(duration_at(3_000_000_000, 3_000_000) == Duration::from_secs(1), "3e9 ticks at 3 GHz");
+    ensure!(duration_at(1, 1_000_000) == Duration::from_nanos(1), "1 tick at 1 GHz");
+    ensure!(ticks_at(Duration::MAX, 5_000_000) == u64::MAX, "saturates");
+    ensure!(duration_at(u64::MAX, 1) > Duration::from_secs(1 << 40), "no overflow at 1 kHz");
+    let d = Duration::from_nanos(123_456_789);
+    let back = duration_at(ticks_at(d, 2_500_000), 2_500_000);
+    // Both directions truncate: under a tick, then under a nanosecond
+    ensure!(back <= d && d - back <= Duration::from_nanos(1), "round trip {:?} -> {:?}", d, back);
+
+    let now = Instant::now();
+    let later = now + Duration::from_millis(5);
+    ensure!(later > now && later - now <= Duration::from_millis(5), "instant + duration");
+    ensure!(now - later == Duration::ZERO, "backwards is zero");
+    ensure!(!Deadline::never().expired(), "never expired");
+    let soon = Deadline::after(Duration::ZERO);
+    ensure!(soon.expired() && soon.remaining() == Duration::ZERO, "zero deadline");
+    Ok(())
+}
+
+/// The TSC still runs at the rate calibrated at boot, and the clocks
+/// built on it agree.
+fn calibration_drift() -> Result<(), String> {
+    use crate::arch::x86_64::timer;
+    use crate::time::{self, Duration, Instant};
+
+    let calibrated = timer::tsc_freq_hz();
+    ensure!(calibrated != 0, "not calibrated");
+    // An interrupt during a measurement can only make it read high
+    let measured = (0..3).map(|_| timer::measure_tsc_hz()).min().unwrap_or(0);
+    let off = calibrated.abs_diff(measured) * 1000 / calibrated;
+    ensure!(
+        off <= time::DRIFT_TOLERANCE_PERMILLE,
+        "{} Hz at boot, {} Hz now ({} permille)", calibrated, measured, off
+    );
+
+    let (ms, us) = (time::monotonic_ms(), time::monotonic_us());
+    ensure!(us / 1000 >= ms && us / 1000 - ms <= 1, "ms {} vs us {}", ms, us);
+    ensure!(time::uptime_secs() <= ms / 1000 + 1, "uptime_secs ahead");
+    let start = Instant::now();
+    time::delay(Duration::from_millis(10));
+    let took = start.elapsed();
+    ensure!(took >= Duration::from_millis(10), "10ms delay took {:?}", took);
+    Ok(())
+}
+
+/// With a TSC-deadline timer, a wait ends on its deadline rather than a
+/// tick later. Without one there is nothing to check.
+fn deadline_timer() -> Result<(), String> {
+    use crate::arch::x86_64::lapic;
+    use crate::time::{Deadline, Duration, Instant};
+
+    if !lapic::deadline_timer() {
+        return Ok(());
+    }
+    for _ in 0..3 {
+        let start = Instant::now();
+        Deadline::after(Duration::from_micros(2_300)).wait();
+        let took = start.elapsed();
+        ensure!(took >= Duration::from_micros(2_300), "woke early: {:?}", took);
+        ensure!(took < Duration::from_micros(2_800), "woke late: {:?}", took);
+    }
+    Ok(())
+}
+
+// ---- Heap ----
+
+/// Blocks stay in their arena, a wrong-arena free goes home, and an
+/// overrun is caught by the tail canary and the block kept out of reuse.
+fn heap_arenas() -> Result<(), String> {
+    use crate::mem::heap::{LUA_HEAP, SQLITE_HEAP};
+
+    let before = (SQLITE_HEAP.stats(), LUA_HEAP.stats());
+    let p = SQLITE_HEAP.malloc(100);
+    ensure!(!p.is_null(), "sqlite malloc failed");
+    ensure!(SQLITE_HEAP.stats().live == before.0.live + 1, "not counted in sqlite");
+    ensure!(LUA_HEAP.stats().live == before.1.live, "counted in lua");
+    unsafe { LUA_HEAP.free(p) };
+    ensure!(SQLITE_HEAP.stats().live == before.0.live, "wrong-arena free not sent home");
+
+    let q = LUA_HEAP.malloc(32);
+    ensure!(!q.is_null(), "lua malloc failed");
+    unsafe {
+        ensure!(LUA_HEAP.usable_size(q) == 32, "usable size {}", LUA_HEAP.usable_size(q));
+        q.add(32).write(0); // one byte past the end
+        LUA_HEAP.free(q);
+    }
+    let after = LUA_HEAP.stats();
+    ensure!(after.corrupt == before.1.corrupt + 1, "overrun not detected");
+    ensure!(after.last_corrupt == q as u64, "wrong block blamed");
+    ensure!(SQLITE_HEAP.stats().corrupt == before.0.corrupt, "blamed on sqlite");
+    let r = LUA_HEAP.malloc(32);
+    ensure!(r != q, "corrupt block handed out again");
+    unsafe { LUA_HEAP.free(r) };
+    Ok(())
+}
+
+// ---- Storage ----
+
+/// Format, write past the initial allocation (forcing relocation), sync,
+/// reload and read back — on a RAM disk, but through real DMA buffers.
+fn storage_ramdisk() -> Result<(), String> {
+    use crate::storage::{self, file_ops, mock_device::RamDisk, BlockAllocator, FileTable};
+
+    const BS: u32 = 4096;
+    let mut dev = RamDisk::new(512, BS);
+    let mut alloc = BlockAllocator::format(&mut dev, 512, BS).map_err(|e| format!("format: {}", e))?;
+    let ft_lba = alloc.data_start_lba() - 1;
+    let mut ft = FileTable::new(ft_lba, BS);
+
+    let data: Vec<u8> = (0..(file_ops::INITIAL_ALLOC_BLOCKS as usize + 4) * BS as usize)
+        .map(|i| (i * 7) as u8)
+        .collect();
+    let mut file = file_ops::open(&mut alloc, &mut ft, b"ktest.bin", true)
+        .map_err(|e| format!("open: {}", e))?;
+    let first_lba = file.start_lba;
+    file_ops::write(&mut dev, &mut alloc, &mut ft, &mut file, &data, 0)
+        .map_err(|e| format!("write: {}", e))?;
+    ensure!(file.start_lba != first_lba, "file was not relocated");
+    file_ops::sync(&mut dev, &mut alloc, &mut ft, &file).map_err(|e| format!("sync: {}", e))?;
+
+    let mut alloc = BlockAllocator::load(&mut dev).map_err(|e| format!("load: {}", e))?;
+    let mut ft = FileTable::load(&mut dev, ft_lba, BS).map_err(|e| format!("ft load: {}", e))?;
+    let damage = storage::check(&alloc, &ft);
+    ensure!(damage.is_empty(), "damage after reload: {:?}", damage);
+
+    let file = file_ops::open(&mut alloc, &mut ft, b"ktest.bin", false)
+        .map_err(|e| format!("reopen: {}", e))?;
+    let mut back = vec![0u8; data.len()];
+    file_ops::read(&mut dev, &file, &mut back, 0).map_err(|e| format!("read: {}", e))?;
+    ensure!(back == data, "data mismatch after reload");
+    Ok(())
+}
+
+/// The superblock on the real NVMe namespace is valid and matches it.
+fn storage_nvme_superblock() -> Result<(), String> {
+    use crate::drivers::nvme::NVME;
+    use crate::storage::BlockAllocator;
+
+    let mut guard = NVME.lock();
+    let nvme = guard.as_mut().ok_or_else(|| String::from("no NVMe controller"))?;
+    let ns = nvme.namespace_info().ok_or_else(|| String::from("no namespace"))?.clone();
+    let alloc = BlockAllocator::load(nvme).map_err(|e| format!("load: {}", e))?;
+
+    ensure!(alloc.block_size() == ns.block_size,
+        "block size {} != namespace {}", alloc.block_size(), ns.block_size);
+    ensure!(alloc.data_start_lba() + alloc.data_block_count() < ns.block_count,
+        "data region exceeds namespace");
+    ensure!(alloc.free_count() <= alloc.data_block_count(), "free count out of range");
+    Ok(())
+}
+
+/// Completion status fields decode to the spec's messages and classes.
+fn nvme_status_decoding() -> Result<(), String> {
+    use crate::drivers::nvme::{status, CompletionEntry, ErrorClass, NvmeError};
+
+    // SCT 0 / SC 0x80, DNR set
+    let lba = (1 << 14) | 0x80;
+    ensure!(status::describe(lba) == "LBA out of range", "0x80: {}", status::describe(lba));
+    ensure!(status::dnr(lba) && !status::more(lba), "DNR/More bits");
+    // SCT 2 / SC 0x80
+    let fault = (2 << 8) | 0x80;
+    ensure!(status::describe(fault) == "write fault", "media 0x80: {}", status::describe(fault));
+    ensure!(ErrorClass::of(&NvmeError::CommandFailed(fault)) == Some(ErrorClass::Media), "media class");
+    ensure!(ErrorClass::of(&NvmeError::CommandFailed(0x02)) == Some(ErrorClass::Generic), "generic class");
+    ensure!(ErrorClass::of(&NvmeError::NotInitialized).is_none(), "driver errors are not counted");
+
+    let shown = format!("{}", NvmeError::CommandFailed(lba));
+    ensure!(shown == "NVMe command failed: LBA out of range (status 0x4080, do not retry)", "display: {}", shown);
+
+    // DW3: CID in 15:0, phase in 16, status in 31:17
+    let entry = CompletionEntry { cid_status: (0x4080 << 17) | (1 << 16) | 0xBEEF, ..CompletionEntry::zeroed() };
+    ensure!(entry.command_id() == 0xBEEF, "command id {:#x}", entry.command_id());
+    ensure!(entry.phase(), "phase bit");
+    ensure!(entry.status() == 0x4080, "status {:#x}", entry.status());
+    Ok(())
+}
+
+// ---- SQLite / VFS ----
+
+/// Run `f` against the open system database, refusing read-only mounts.
+fn with_writable_db(f: impl FnOnce(&crate::sqlite::SqliteDb) -> Result<(), String>) -> Result<(), String> {
+    match crate::sqlite::mount_mode() {
+        Some(mode) if mode.is_writable() => {}
+        Some(mode) => return Err(format!("storage mounted {}", mode)),
+        None => return Err(String::from("database not open")),
+    }
+    let guard = crate::sqlite::DB.lock();
+    let db = guard.as_ref().ok_or_else(|| String::from("database not open"))?;
+    f(db)
+}
+
+fn sqlite_insert_select() -> Result<(), String> {
+    with_writable_db(|db| {
+        db.exec("DROP TABLE IF EXISTS ktest_kv")?;
+        db.exec("CREATE TABLE ktest_kv (k TEXT PRIMARY KEY, v INTEGER)")?;
+        db.exec("INSERT INTO ktest_kv VALUES ('a', 1), ('b', 2), ('c', 39)")?;
+
+        let sum = db.query_value("SELECT sum(v) FROM ktest_kv")?;
+        ensure!(sum.as_deref() == Some("42"), "sum = {:?}", sum);
+        let keys = db.query_column("SELECT k FROM ktest_kv ORDER BY k")?;
+        ensure!(keys == ["a", "b", "c"], "keys = {:?}", keys);
+
+        db.exec("DROP TABLE ktest_kv")
+    })
+}
+
+/// Temp storage is RAM by default; with temp_store=FILE the temp database
+/// goes to a VFS RAM file, freed when the temp database closes.
+fn vfs_temp_files() -> Result<(), String> {
+    ensure!(crate::sqlite::info()?.temp_store == "memory", "temp_store not memory by default");
+    let mut sorted = None;
+    with_writable_db(|db| {
+        db.exec("PRAGMA temp_store=FILE")?;
+        db.exec("CREATE TEMP TABLE ktest_tmp (v TEXT)")?;
+        db.exec("WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 500) \
+                 INSERT INTO ktest_tmp SELECT hex(randomblob(64)) FROM n")?;
+        sorted = db.query_value("SELECT count(*) FROM (SELECT v FROM ktest_tmp ORDER BY v)")?;
+        Ok(())
+    })?;
+    // The temp database stays open on the writer until temp_store changes
+    let during = crate::sqlite::info()?;
+    with_writable_db(|db| db.exec("PRAGMA temp_store=MEMORY"))?;
+    let after = crate::sqlite::info()?;
+
+    ensure!(sorted.as_deref() == Some("500"), "sorted {:?} rows", sorted);
+    ensure!(during.temp_files >= 1 && during.temp_bytes >= 500 * 128, "{} temp file(s), {} bytes", during.temp_files, during.temp_bytes);
+    ensure!(after.temp_files == 0 && after.temp_bytes == 0, "{} temp file(s) left", after.temp_files);
+    Ok(())
+}
+
+/// A reader blocked by the writer's EXCLUSIVE lock retries through the
+/// busy handler, then gives up with SQLITE_BUSY; the writer queue counts
+/// every lock taken.
+fn sqlite_busy_handler() -> Result<(), String> {
+    let before = (crate::sqlite::writer::busy_stats(), crate::sqlite::DB.stats());
+    // A connection of its own: with_reader could fall back to the writer
+    let reader = crate::sqlite::SqliteDb::open_readonly("heaven.db")?;
+    let mut blocked = Ok(None);
+    with_writable_db(|db| {
+        db.exec("BEGIN EXCLUSIVE")?;
+        blocked = reader.query_value("SELECT count(*) FROM namespace");
+        db.exec("COMMIT")
+    })?;
+    let after = (crate::sqlite::writer::busy_stats(), crate::sqlite::DB.stats());
+
+    ensure!(blocked.as_ref().is_err_and(|e| e.contains("locked")), "reader got {:?}", blocked);
+    ensure!(after.0.episodes > before.0.episodes && after.0.timeouts > before.0.timeouts, "busy {} -> {}", before.0, after.0);
+    ensure!(after.0.retries >= before.0.retries + 10, "{} retries", after.0.retries - before.0.retries);
+    ensure!(after.1.acquired > before.1.acquired, "writer {} -> {}", before.1, after.1);
+    Ok(())
+}
+
+/// `db stats` counts the namespace table and finds its primary key index.
+fn sqlite_db_stats() -> Result<(), String> {
+    let stats = crate::sqlite::stats()?;
+    let ns = stats.objects.iter().find(|o| o.name == "namespace").ok_or("no namespace table")?;
+    ensure!(ns.kind == "table" && ns.rows.is_some_and(|n| n > 0), "namespace: {} with {:?} rows", ns.kind, ns.rows);
+    ensure!(stats.sized, "dbstat not compiled in");
+    ensure!(ns.bytes.is_some_and(|b| b > 0), "namespace not sized");
+    ensure!(stats.objects.iter().any(|o| o.kind == "index" && o.table == "namespace"), "no index on namespace");
+    ensure!(!stats.largest.is_empty(), "no namespace files listed");
+    Ok(())
+}
+
+/// An R-Tree finds the boxes around a point, and the geo helpers agree.
+fn sqlite_rtree_bbox() -> Result<(), String> {
+    ensure!(crate::sqlite::info()?.has("ENABLE_RTREE"), "built without sqlite-rtree");
+    let mut hits = None;
+    let mut helpers = None;
+    with_writable_db(|db| {
+        db.exec("CREATE VIRTUAL TABLE temp.ktest_grid USING rtree(id, minX, maxX, minY, maxY)")?;
+        db.exec("INSERT INTO temp.ktest_grid VALUES (1, 0, 10, 0, 10), (2, 5, 15, 5, 15), (3, 20, 30, 20, 30)")?;
+        hits = db.query_value(
+            "SELECT group_concat(id) FROM (SELECT id FROM temp.ktest_grid \
+             WHERE minX <= 7 AND maxX >= 7 AND minY <= 7 AND maxY >= 7 ORDER BY id)",
+        )?;
+        helpers = db.query_value(
+            "SELECT bbox_contains(0, 10, 0, 10, 10, 3) || bbox_contains(0, 10, 0, 10, 11, 3) \
+             || bbox_overlaps(0, 10, 0, 10, 5, 15, 5, 15) || bbox_overlaps(0, 10, 0, 10, 20, 30, 20, 30) \
+             || coalesce(bbox_contains(0, 10, 0, 10, NULL, 3), 'n')",
+        )?;
+        db.exec("DROP TABLE temp.ktest_grid")
+    })?;
+    ensure!(hits.as_deref() == Some("1,2"), "boxes around (7, 7): {:?}", hits);
+    ensure!(helpers.as_deref() == Some("1010n"), "helpers gave {:?}", helpers);
+    Ok(())
+}
+
+/// With mmap_size set, reads go through xFetch's RAM image of heaven.db,
+/// which a write on the same connection keeps current; mmap_size=0 frees it.
+fn vfs_mmap_reads() -> Result<(), String> {
+    let mut first = None;
+    with_writable_db(|db| {
+        db.exec("PRAGMA mmap_size=67108864")?;
+        db.exec("CREATE TABLE ktest_mmap (k INTEGER PRIMARY KEY, v TEXT)")?;
+        db.exec("WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 200) \
+                 INSERT INTO ktest_mmap SELECT i, hex(randomblob(100)) FROM n")?;
+        // Empty the page cache so the next reads are fetched
+        db.exec("PRAGMA shrink_memory")?;
+        first = db.query_value("SELECT count(*) FROM ktest_mmap WHERE length(v) = 200")?;
+        Ok(())
+    })?;
+    let during = crate::sqlite::info()?;
+    let mut changed = None;
+    with_writable_db(|db| {
+        db.exec("UPDATE ktest_mmap SET v = 'changed' WHERE k = 100")?;
+        db.exec("PRAGMA shrink_memory")?;
+        changed = db.query_value("SELECT v FROM ktest_mmap WHERE k = 100")?;
+        db.exec("DROP TABLE ktest_mmap")?;
+        db.exec("PRAGMA mmap_size=0")
+    })?;
+    let after = crate::sqlite::info()?;
+
+    ensure!(first.as_deref() == Some("200"), "{:?} rows read back", first);
+    ensure!(changed.as_deref() == Some("changed"), "mapped read after write = {:?}", changed);
+    ensure!(during.mapped_files >= 1 && during.mapped_bytes >= 200 * 200, "{} mapped file(s), {} bytes", during.mapped_files, during.mapped_bytes);
+    ensure!(after.mapped_bytes < during.mapped_bytes, "{} bytes still mapped", after.mapped_bytes);
+    Ok(())
+}
+
+/// Grow heaven.db well past its initial allocation inside one transaction,
+/// so the VFS relocates it mid-transaction, then verify the database.
+fn vfs_relocation() -> Result<(), String> {
+    with_writable_db(|db| {
+        db.exec("DROP TABLE IF EXISTS ktest_blob")?;
+        db.exec("CREATE TABLE ktest_blob (b BLOB)")?;
+        db.exec(
+            "WITH RECURSIVE n(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM n WHERE x < 64) \
+             INSERT INTO ktest_blob SELECT randomblob(4000) FROM n",
+        )?;
+
+        let bytes = db.query_value("SELECT sum(length(b)) FROM ktest_blob")?;
+        ensure!(bytes.as_deref() == Some("256000"), "stored bytes = {:?}", bytes);
+        let check = db.query_value("PRAGMA integrity_check")?;
+        ensure!(check.as_deref() == Some("ok"), "integrity_check: {:?}", check);
+
+        db.exec("DROP TABLE ktest_blob")
+    })
+}
+
+/// /tmp writes stay out of SQLite, keep their creator, and respect the
+/// size limit.
+fn tmpfs_scratch() -> Result<(), String> {
+    use crate::fs::tmpfs;
+    use crate::sqlite::acl::{self, Principal};
+
+    const PATH: &str = "/tmp/ktest/scratch.txt";
+    let owner = Principal::Agent(String::from("/ktest/a.lua"));
+    let peer = Principal::Agent(String::from("/ktest/b.lua"));
+    with_writable_db(|db| {
+        acl::write_file(db, &owner, PATH, "data", "one")?;
+        let rows = db.query_value("SELECT count(*) FROM namespace WHERE path = '/tmp/ktest/scratch.txt'")?;
+        ensure!(rows.as_deref() == Some("0"), "{:?} namespace rows for a /tmp file", rows);
+        ensure!(acl::write_file(db, &peer, PATH, "data", "two").is_err(), "peer overwrote a /tmp file");
+        acl::write_file(db, &Principal::Shell, PATH, "data", "three")?;
+        Ok(())
+    })?;
+    ensure!(tmpfs::read(PATH).as_deref() == Some(&b"three"[..]), "read back {:?}", tmpfs::read(PATH));
+    let mut buf = [0u8; 8];
+    ensure!(tmpfs::read_at(PATH, 2, &mut buf) == Some(3), "read_at past the middle");
+    ensure!(tmpfs::list("/tmp/ktest/") == [String::from(PATH)], "list: {:?}", tmpfs::list("/tmp/ktest/"));
+    ensure!(tmpfs::is_tmp("/TMP/ktest"), "/TMP not in /tmp");
+    ensure!(tmpfs::len("/Tmp/KTEST/Scratch.txt") == Some(5), "lookup under another case");
+    ensure!(tmpfs::write(&owner, "/tmp/", b"x").is_err(), "wrote the directory itself");
+
+    crate::sqlite::config_set("tmp.size", "1K")?;
+    tmpfs::configure();
+    let too_big = tmpfs::write(&owner, "/tmp/ktest/big", &[0u8; 2048]);
+    crate::sqlite::config_delete("tmp.size")?;
+    tmpfs::configure();
+    ensure!(too_big.is_err(), "2K write under a 1K limit succeeded");
+    ensure!(tmpfs::limit() == tmpfs::DEFAULT_LIMIT, "limit {} after unset", tmpfs::limit());
+
+    ensure!(tmpfs::remove(&peer, "/tmp/ktest", true).is_err(), "peer removed another's file");
+    let removed = tmpfs::remove(&owner, "/tmp/ktest", true)?;
+    ensure!(removed == 1 && tmpfs::read(PATH).is_none(), "removed {}, file still there", removed);
+    Ok(())
+}
+
+/// Paths differing only in ASCII case name one row, which keeps the
+/// spelling of its last write.
+fn nocase_paths() -> Result<(), String> {
+    let mut found = None;
+    let mut rows = None;
+    with_writable_db(|db| {
+        db.exec("DELETE FROM namespace WHERE path = '/ktest/Case.txt'")?;
+        db.exec("INSERT INTO namespace (path, type, content) VALUES ('/ktest/Case.txt', 'data', 'one')")?;
+        found = db.query_value("SELECT content FROM namespace WHERE path = '/KTEST/case.TXT'")?;
+        ensure!(
+            db.exec("INSERT INTO namespace (path, type, content) VALUES ('/ktest/case.txt', 'data', 'x')").is_err(),
+            "duplicate under another case accepted"
+        );
+        db.exec("INSERT OR REPLACE INTO namespace (path, type, content) VALUES ('/ktest/CASE.txt', 'data', 'two')")?;
+        rows = db.query_value(
+            "SELECT group_concat(path || '=' || content) FROM namespace \
+             WHERE substr(path, 1, 8) = '/KTEST/c' COLLATE NOCASE",
+        )?;
+        db.exec("DELETE FROM namespace WHERE path = '/ktest/case.txt'")
+    })?;
+    ensure!(found.as_deref() == Some("one"), "lookup under another case: {:?}", found);
+    ensure!(rows.as_deref() == Some("/ktest/CASE.txt=two"), "after replace: {:?}", rows);
+    Ok(())
+}
+
+/// An archive takes the subtree under any spelling of its prefix, and
+/// not its siblings that merely start the same.
+fn archive_subtree() -> Result<(), String> {
+    const CLEAN: &str = "DELETE FROM namespace WHERE path LIKE '/ktest/arc%'";
+    with_writable_db(|db| {
+        db.exec(CLEAN)?;
+        db.exec(
+            "INSERT INTO namespace (path, type, content) VALUES \
+             ('/ktest/arc/A.txt', 'data', 'a'), ('/ktest/arc/sub/b', 'data', 'b'), ('/ktest/arcx/c', 'data', 'c')",
+        )
+    })?;
+    let archived = crate::sqlite::archive::create("/ktest/arc.har", "/KTEST/Arc/");
+    with_writable_db(|db| db.exec(CLEAN))?;
+    let (count, _) = archived?;
+    ensure!(count == 2, "archived {} entries, want 2", count);
+    Ok(())
+}
+
+/// `..`, `.` and doubled slashes resolve; NULs, escapes above the root
+/// and over-long or over-deep paths are refused.
+fn canonical_paths() -> Result<(), String> {
+    use crate::sqlite::namespace::{canonicalize, MAX_DEPTH, MAX_PATH_LEN};
+
+    for (path, want) in [
+        ("/", "/"),
+        ("", "/"),
+        ("/agents/x.lua", "/agents/x.lua"),
+        ("agents//x.lua/", "/agents/x.lua"),
+        ("/tmp/../agents/./x.lua", "/agents/x.lua"),
+        ("/a/b/../../c", "/c"),
+    ] {
+        let got = canonicalize(path)?;
+        ensure!(got == want, "{:?} -> {:?}, expected {:?}", path, got, want);
+    }
+    let deep = "/d".repeat(MAX_DEPTH + 1);
+    let long = format!("/{}", "x".repeat(MAX_PATH_LEN));
+    for bad in ["/a\0b", "/..", "/a/../../etc", deep.as_str(), long.as_str()] {
+        ensure!(canonicalize(bad).is_err(), "{:?} accepted", bad);
+    }
+    ensure!(canonicalize(&"/d".repeat(MAX_DEPTH)).is_ok(), "{} levels refused", MAX_DEPTH);
+    Ok(())
+}
+
+/// Owner/kind/other bits, ownership of new files, and chmod/chown.
+fn namespace_acl() -> Result<(), String> {
+    use crate::sqlite::acl::{self, Access, Principal};
+
+    const PATH: &str = "/ktest/acl.txt";
+    with_writable_db(|db| {
+        let owner = Principal::Agent(String::from("/ktest/a.lua"));
+        let peer = Principal::Agent(String::from("/ktest/b.lua"));
+        let client = Principal::Styx(String::from("ktest"));
+
+        db.exec("DELETE FROM namespace WHERE path = '/ktest/acl.txt'")?;
+        acl::write_file(db, &owner, PATH, "data", "one")?;
+        let (mode, recorded) = acl::lookup(db, PATH)?.ok_or_else(|| String::from("row missing"))?;
+        ensure!(mode == acl::DEFAULT_MODE && recorded == owner, "new file: {:o} {}", mode, recorded);
+
+        ensure!(acl::permits(db, &peer, PATH, Access::Read)?, "peer agent can't read 644");
+        ensure!(!acl::permits(db, &peer, PATH, Access::Write)?, "peer agent can write 644");
+        ensure!(acl::write_file(db, &peer, PATH, "data", "two").is_err(), "peer overwrote 644");
+
+        acl::chmod(db, PATH, 0o600)?;
+        acl::write_file(db, &owner, PATH, "data", "three")?;
+        let (mode, _) = acl::lookup(db, PATH)?.ok_or_else(|| String::from("row missing"))?;
+        ensure!(mode == 0o600, "overwrite reset mode to {:o}", mode);
+        ensure!(!acl::permits(db, &peer, PATH, Access::Read)?, "peer agent can read 600");
+        ensure!(!acl::permits(db, &client, PATH, Access::Read)?, "styx client can read 600");
+        ensure!(acl::permits(db, &Principal::Shell, PATH, Access::Write)?, "shell denied");
+
+        acl::chown(db, PATH, &client)?;
+        ensure!(acl::permits(db, &client, PATH, Access::Write)?, "new owner can't write");
+        ensure!(!acl::permits(db, &owner, PATH, Access::Read)?, "old owner can still read");
+
+        db.exec("DELETE FROM namespace WHERE path = '/ktest/acl.txt'")
+    })
+}
+
+/// SQL run for an agent sees paths but no file content, however it asks.
+fn agent_sql_content() -> Result<(), String> {
+    with_writable_db(|db| {
+        db.without_content(|db| {
+            ensure!(db.query("SELECT path, mode FROM namespace LIMIT 1").is_ok(), "paths refused");
+            for sql in [
+                "SELECT content FROM namespace LIMIT 1",
+                "SELECT * FROM namespace LIMIT 1",
+                "SELECT path FROM namespace WHERE content LIKE 'x%'",
+                "SELECT chunk FROM embeddings LIMIT 1",
+            ] {
+                let result = db.query(sql);
+                ensure!(result.as_ref().is_err_and(|e| e.contains("prohibited")), "{}: {:?}", sql, result.is_ok());
+            }
+            Ok(())
+        })?;
+        ensure!(db.query("SELECT content FROM namespace LIMIT 1").is_ok(), "authorizer left installed");
+        Ok(())
+    })
+}
+
+/// Agent writes under a quota'd prefix stop at the limit; the shell doesn't.
+fn subtree_quota() -> Result<(), String> {
+    use crate::sqlite::acl::{self, Principal};
+    use crate::sqlite::quota;
+
+    with_writable_db(|db| {
+        let agent = Principal::Agent(String::from("/ktest/q.lua"));
+        db.exec("DELETE FROM namespace WHERE path LIKE '/ktest/q/%'")?;
+        quota::set(db, "/ktest/q", Some(100))?;
+
+        acl::write_file(db, &agent, "/ktest/q/a", "data", &"x".repeat(60))?;
+        acl::write_file(db, &agent, "/ktest/q/a", "data", &"x".repeat(90))?; // replaces, still fits
+        let over = acl::write_file(db, &agent, "/ktest/q/b", "data", &"x".repeat(20));
+        ensure!(over.as_ref().is_err_and(|e| e.starts_with("quota exceeded")), "over quota: {:?}", over);
+        let recased = acl::write_file(db, &agent, "/KTEST/Q/b", "data", &"x".repeat(20));
+        ensure!(recased.as_ref().is_err_and(|e| e.starts_with("quota exceeded")), "other case: {:?}", recased);
+        acl::write_file(db, &Principal::Shell, "/ktest/q/b", "data", &"x".repeat(20))?;
+
+        ensure!(quota::used(db, "/ktest/q/")? == 110, "used = {}", quota::used(db, "/ktest/q/")?);
+        let du = quota::du(db, "/ktest")?;
+        ensure!(du.iter().any(|(child, bytes)| child == "/ktest/q/" && *bytes == 110), "du = {:?}", du);
+
+        quota::set(db, "/ktest/q", None)?;
+        db.exec("DELETE FROM namespace WHERE path LIKE '/ktest/q/%'")
+    })
+}
+
+/// LZ4 round trips, and large files stored compressed but read back whole.
+fn compressed_files() -> Result<(), String> {
+    use crate::sqlite::acl::{self, Principal};
+    use crate::sqlite::{compress, SqlValue};
+
+    let text: String = (0..800).map(|i| format!("line {} of the ktest file\n", i % 37)).collect();
+    let mut noise = vec![0u8; 5000];
+    crate::crypto::entropy::fill(&mut noise);
+    for data in [&b""[..], b"abc", b"abcabcabcabcabcabcabcabc", text.as_bytes(), &noise] {
+        let stored = compress::encode(data);
+        ensure!(compress::decode(&stored)? == data, "{}-byte round trip differs", data.len());
+    }
+    ensure!(compress::decode(&[9, 0, 0, 0, 0x10]).is_err(), "truncated block decoded");
+
+    const PATH: &str = "/ktest/compressed.txt";
+    with_writable_db(|db| {
+        db.exec("DELETE FROM namespace WHERE path = '/config/storage.compress'")?;
+        acl::write_file(db, &Principal::Shell, PATH, "data", &text)?;
+        let row = db.query_params(
+            "SELECT compressed, octet_length(content), unpack(content, compressed), \
+                    unpacked_length(content, compressed) FROM namespace WHERE path = ?",
+            &[SqlValue::Text(String::from(PATH))],
+        )?;
+        let row = row.rows.first().ok_or_else(|| String::from("row missing"))?;
+        let stored = row[1].as_integer().unwrap_or(0) as usize;
+        ensure!(row[0].as_integer() == Some(1), "{}-byte file not compressed", text.len());
+        ensure!(stored < text.len() / 2, "stored {} of {} bytes", stored, text.len());
+        ensure!(row[2].as_str() == Some(text.as_str()), "unpack() differs from what was written");
+        ensure!(row[3].as_integer() == Some(text.len() as i64), "unpacked_length {:?}", row[3]);
+
+        acl::write_file(db, &Principal::Shell, PATH, "data", "short")?;
+        let flag = db.query_value("SELECT compressed FROM namespace WHERE path = '/ktest/compressed.txt'")?;
+        ensure!(flag.as_deref() == Some("0"), "short file compressed ({:?})", flag);
+
+        db.exec(
+            "INSERT OR REPLACE INTO namespace (path, type, content) \
+             VALUES ('/config/storage.compress', 'config', 'off')",
+        )?;
+        let written = acl::write_file(db, &Principal::Shell, PATH, "data", &text);
+        db.exec("DELETE FROM namespace WHERE path = '/config/storage.compress'")?;
+        written?;
+        let flag = db.query_value("SELECT compressed FROM namespace WHERE path = '/ktest/compressed.txt'")?;
+        ensure!(flag.as_deref() == Some("0"), "compressed with storage.compress off ({:?})", flag);
+        db.exec("DELETE FROM namespace WHERE path = '/ktest/compressed.txt'")
+    })
+}
+
+/// Journaled tool writes come back filtered by path and conversation.
+fn change_journal() -> Result<(), String> {
+    use crate::sqlite::changes::{self, Change, Filter};
+
+    with_writable_db(|db| {
+        let conv = changes::new_conversation_id(db)?;
+        ensure!(conv.len() == 12, "conversation id = {:?}", conv);
+        let change = |tool_use_id, path, before, after| Change {
+            conversation: &conv,
+            tool_use_id,
+            tool: "write_file",
+            path,
+            before,
+            after,
+            reason: "ktest",
+            input: "{}",
+        };
+        changes::record(db, &change("toolu_1", "/ktest/j/a", None, "one\n"))?;
+        changes::record(db, &change("toolu_2", "/ktest/j/a", Some("one\n"), "one\ntwo\n"))?;
+        changes::record(db, &change("toolu_3", "/ktest/other", None, "x\n"))?;
+
+        let filter = Filter { prefix: Some(String::from("/ktest/j/")), conversation: Some(conv.clone()), limit: 10 };
+        let rows = changes::query(db, &filter)?;
+        ensure!(rows.len() == 2, "rows = {}", rows.len());
+        ensure!(rows[0].tool_use_id == "toolu_2" && rows[0].added == 1 && rows[0].removed == 0, "newest = {:?}", rows[0]);
+        ensure!(rows[1].before_hash.is_none(), "new file has a before hash");
+        ensure!(rows[0].before_hash.as_deref() == Some(rows[1].after_hash.as_str()), "hashes don't chain");
+
+        db.query_params(
+            "DELETE FROM changes WHERE conversation = ?",
+            &[crate::sqlite::SqlValue::Text(conv.clone())],
+        )?;
+        Ok(())
+    })
+}
+
+/// Keys separate requests that differ anywhere; stored answers come back
+/// until they expire.
+fn response_cache() -> Result<(), String> {
+    use crate::api::Message;
+    use crate::sqlite::cache;
+
+    with_writable_db(|_| Ok(()))?;
+    let ask = |text: &str| alloc::vec![Message::text("user", String::from(text))];
+    let key = cache::key("m", None, &ask("hi"), &[]);
+    ensure!(key.len() == 64, "key = {:?}", key);
+    ensure!(key == cache::key("m", None, &ask("hi"), &[]), "key not stable");
+    ensure!(key != cache::key("m", Some(""), &ask("hi"), &[]), "system prompt not in key");
+    ensure!(key != cache::key("m2", None, &ask("hi"), &[]), "model not in key");
+    let stop = [String::from("END")];
+    ensure!(key != cache::key("m", None, &ask("hi"), &stop), "stop sequences not in key");
+
+    // Stored under "ktest:" keys so the live cache is left alone
+    let key = alloc::format!("ktest:{}", key);
+    let answer = |text: &str, stop: Option<&str>| cache::Answer {
+        text: String::from(text),
+        stop_reason: String::from(if stop.is_some() { "stop_sequence" } else { "end_turn" }),
+        stop_sequence: stop.map(String::from),
+    };
+    cache::store(&key, "m", &answer("hello", Some("END")), 60)?;
+    let hit = cache::lookup(&key);
+    ensure!(hit == Some(answer("hello", Some("END"))), "lookup = {:?}", hit);
+    let expired = alloc::format!("ktest:{}", cache::key("m", None, &ask("old"), &[]));
+    cache::store(&expired, "m", &answer("stale", None), 0)?;
+    ensure!(cache::lookup(&expired).is_none(), "expired entry returned");
+
+    let entries = cache::list()?;
+    ensure!(entries.iter().any(|e| e.key == key && e.hits == 1), "hits not counted");
+    let pruned = cache::clear("ktest:", true)?;
+    ensure!(pruned == 1, "expired clear removed {}", pruned);
+    ensure!(cache::lookup(&key).is_some(), "expired clear took a live entry");
+    let cleared = cache::clear("ktest:", false)?;
+    ensure!(cleared == 1, "clear removed {}", cleared);
+    ensure!(cache::lookup(&key).is_none(), "clear left entries");
+    Ok(())
+}
+
+/// cosine_similarity() in SQL, and stored chunks ranked by it.
+fn vector_search() -> Result<(), String> {
+    use crate::sqlite::vector;
+
+    with_writable_db(|db| {
+        // [1, 0] against [2, 0] and [0, 1]
+        let parallel = db.query_value("SELECT cosine_similarity(x'0000803F00000000', x'0000004000000000') > 0.9999")?;
+        ensure!(parallel.as_deref() == Some("1"), "parallel = {:?}", parallel);
+        let orthogonal = db.query_value("SELECT cosine_similarity(x'0000803F00000000', x'000000000000803F')")?;
+        ensure!(orthogonal.as_deref() == Some("0"), "orthogonal = {:?}", orthogonal);
+        let mismatch = db.query_value("SELECT cosine_similarity(x'0000803F', x'0000803F00000000')")?;
+        ensure!(mismatch.is_none(), "length mismatch = {:?}", mismatch);
+
+        db.exec("DELETE FROM embeddings WHERE model LIKE 'ktest%'")?;
+        vector::store(db, Some("/ktest/x"), "east", "ktest", &[1.0, 0.0])?;
+        vector::store(db, None, "north", "ktest", &[0.0, 1.0])?;
+        vector::store(db, None, "north-east", "ktest", &[0.7, 0.7])?;
+        vector::store(db, None, "other model", "ktest-other", &[1.0, 0.1])?;
+        let hits = vector::search(db, &[1.0, 0.1], "ktest", 2, |_| true)?;
+        let texts: Vec<&str> = hits.iter().map(|h| h.text.as_str()).collect();
+        ensure!(texts == ["east", "north-east"], "ranking = {:?}", texts);
+        ensure!(hits[0].path.as_deref() == Some("/ktest/x"), "path = {:?}", hits[0].path);
+        ensure!(hits[0].score > 0.99 && hits[0].score <= 1.0, "score = {}", hits[0].score);
+
+        // A chunk from a path the caller can't read is skipped, not counted
+        let hits = vector::search(db, &[1.0, 0.1], "ktest", 2, |path| path != "/ktest/x")?;
+        let texts: Vec<&str> = hits.iter().map(|h| h.text.as_str()).collect();
+        ensure!(texts == ["north-east", "north"], "filtered ranking = {:?}", texts);
+
+        db.exec("DELETE FROM embeddings WHERE model LIKE 'ktest%'")
+    })
+}
+
+fn outbox_queue() -> Result<(), String> {
+    use crate::api::Message;
+    use crate::sqlite::outbox;
+
+    ensure!(outbox::mailbox("/agents/ktest.lua") == "/mail/agents/ktest.lua/", "script mailbox");
+    ensure!(outbox::mailbox("<repl>") == "/mail/repl/", "repl mailbox");
+
+    with_writable_db(|db| {
+        db.exec("DELETE FROM outbox WHERE agent = 'ktest'")?;
+        let (id, reply_to) = outbox::enqueue(db, "ktest", "m", Some("be brief"), &[Message::text("user", String::from("hi \"there\""))], false)?;
+        ensure!(reply_to == format!("/mail/ktest/{}", id), "reply_to = {}", reply_to);
+
+        let item = outbox::next_pending(db)?.ok_or_else(|| String::from("nothing pending"))?;
+        ensure!(item.id == id, "oldest pending = #{}, want #{}", item.id, id);
+        ensure!(item.system.as_deref() == Some("be brief"), "system = {:?}", item.system);
+        ensure!(item.messages == [(String::from("user"), String::from("hi \"there\""))], "messages = {:?}", item.messages);
+
+        outbox::defer(db, id, "no route")?;
+        let item = outbox::next_pending(db)?.ok_or_else(|| String::from("deferred request gone"))?;
+        ensure!(item.attempts == 1 && item.last_error.as_deref() == Some("no route"), "after defer: {:?}", item);
+
+        outbox::complete(db, &item, "hello")?;
+        let mail = db.query_value(&format!("SELECT content FROM namespace WHERE path = '{}'", reply_to))?;
+        ensure!(mail.as_deref() == Some("hello"), "mail = {:?}", mail);
+        let status = db.query_value(&format!("SELECT status || attempts FROM outbox WHERE id = {}", id))?;
+        ensure!(status.as_deref() == Some("done2"), "status = {:?}", status);
+        ensure!(!outbox::cancel(db, -1)?, "cancelled a missing request");
+
+        // Tool calls and their results survive the queue
+        let call = crate::api::ToolCall { id: String::from("t1"), name: String::from("ls"), input_json: String::from(r#"{"path":"/"}"#) };
+        let turn = [
+            Message::text("user", String::from("list /")),
+            Message::assistant_tool_use(String::from("looking"), vec![call]),
+            Message::tool_result(String::from("t1"), String::from("/etc"), false),
+        ];
+        outbox::enqueue(db, "ktest", "m", None, &turn, false)?;
+        let item = outbox::next_pending(db)?.ok_or_else(|| String::from("tool turn not queued"))?;
+        let back = item.api_messages();
+        ensure!(crate::api::messages_json(&back) == crate::api::messages_json(&turn), "blocks lost: {}", item.encoded);
+        ensure!(item.messages[1].1 == "looking[ls]", "display text = {:?}", item.messages);
+
+        db.exec("DELETE FROM outbox WHERE agent = 'ktest'")?;
+        db.exec("DELETE FROM namespace WHERE path LIKE '/mail/ktest/%'")
+    })
+}
+
+/// Batch requests wait for the batches task, not the one-at-a-time sender,
+/// and go back in line when their batch expires.
+fn outbox_batches() -> Result<(), String> {
+    use crate::api::Message;
+    use crate::sqlite::outbox;
+
+    with_writable_db(|db| {
+        db.exec("DELETE FROM outbox WHERE agent = 'ktest'")?;
+        let ask = |text: &str| [Message::text("user", String::from(text))];
+        let (id, _) = outbox::enqueue(db, "ktest", "m", None, &ask("tonight"), true)?;
+        let mine = |items: Vec<outbox::Item>| items.into_iter().filter(|i| i.agent == "ktest").collect::<Vec<_>>();
+
+        let single = outbox::next_pending(db)?;
+        ensure!(single.is_none_or(|i| i.id != id), "batch request sent on its own");
+        let waiting = mine(outbox::unbatched(db, 100)?);
+        ensure!(waiting.len() == 1 && waiting[0].id == id && waiting[0].batch, "unbatched = {:?}", waiting);
+
+        outbox::set_batch(db, &[id], Some(outbox::SUBMITTING))?;
+        ensure!(mine(outbox::unbatched(db, 100)?).is_empty(), "request being submitted still unbatched");
+        let oldest = outbox::oldest_batch(db)?;
+        ensure!(oldest.as_deref() != Some(outbox::SUBMITTING), "polled a batch being submitted");
+        outbox::set_batch(db, &[id], Some("msgbatch_ktest"))?;
+        ensure!(mine(outbox::unbatched(db, 100)?).is_empty(), "submitted request still unbatched");
+        let in_batch = mine(outbox::in_batch(db, "msgbatch_ktest")?);
+        ensure!(in_batch.len() == 1 && in_batch[0].batch_id.as_deref() == Some("msgbatch_ktest"), "in_batch = {:?}", in_batch);
+
+        outbox::unbatch(db, id, "batch expired")?;
+        let again = mine(outbox::unbatched(db, 100)?);
+        ensure!(again.len() == 1 && again[0].attempts == 1, "after expiry: {:?}", again);
+
+        db.exec("DELETE FROM outbox WHERE agent = 'ktest'")
+    })
+}
+
+fn system_prompts() -> Result<(), String> {
+    use crate::sqlite::prompts;
+
+    ensure!(prompts::path_of("reviewer")? == "/prompts/system/reviewer", "name → path");
+    ensure!(prompts::path_of("/ktest/p")? == "/ktest/p", "full path kept");
+    ensure!(prompts::path_of("../config/x").is_err(), "name with a slash accepted");
+
+    with_writable_db(|db| {
+        let seeded = prompts::read(db, "/prompts/system/agent")?;
+        ensure!(seeded.is_some_and(|p| !p.is_empty()), "default agent prompt not seeded");
+        ensure!(prompts::read(db, "/prompts/system/ktest-missing")?.is_none(), "missing prompt found");
+        Ok(())
+    })
+}
+
+fn tool_policy() -> Result<(), String> {
+    use crate::sqlite::policy::{self, Action};
+
+    with_writable_db(|db| {
+        let saved = policy::list(db)?;
+        db.exec("DELETE FROM tool_policy")?;
+
+        ensure!(policy::lookup(db, "write_file")? == Action::Allow, "no rows should allow");
+        policy::set(db, "*", Some(Action::Confirm))?;
+        policy::set(db, "read_file", Some(Action::Allow))?;
+        policy::set(db, "sql_query", Some(Action::Deny))?;
+        ensure!(policy::lookup(db, "write_file")? == Action::Confirm, "* row not applied");
+        ensure!(policy::lookup(db, "read_file")? == Action::Allow, "own row must beat *");
+        ensure!(policy::lookup(db, "sql_query")? == Action::Deny, "deny row");
+        policy::set(db, "*", None)?;
+        ensure!(policy::lookup(db, "write_file")? == Action::Allow, "* row not removed");
+
+        db.exec("DELETE FROM tool_policy")?;
+        for (tool, action) in saved {
+            policy::set(db, &tool, Some(action))?;
+        }
+        Ok(())
+    })
+}
+
+/// `db info` reflects the build: our OMITs are listed, JSON is always in.
+fn build_info() -> Result<(), String> {
+    let info = crate::sqlite::info()?;
+    ensure!(info.version.starts_with("3."), "version {:?}", info.version);
+    ensure!(info.has("OMIT_WAL") && info.has("THREADSAFE"), "options {:?}", info.compile_options);
+    ensure!(info.modules().contains(&"json"), "modules {:?}", info.modules());
+    ensure!(info.page_size > 0 && info.page_count > 0, "size {} x {}", info.page_count, info.page_size);
+    Ok(())
+}
+
+/// Config changes reach the events table with secrets withheld, and an
+/// event recorded while the writer is busy is queued, then written.
+fn lifecycle_events() -> Result<(), String> {
+    use crate::sqlite::lifecycle::{self, Kind};
+
+    with_writable_db(|_| Ok(()))?;
+    let newest = |kind| -> Result<String, String> {
+        let events = lifecycle::recent(1, Some(kind))?;
+        Ok(events.last().map(|e| e.detail.clone()).unwrap_or_default())
+    };
+
+    crate::sqlite::config_set("ktest.key", "hunter2")?;
+    let detail = newest("config")?;
+    ensure!(detail == "ktest.key = <redacted>", "set: {:?}", detail);
+    crate::sqlite::config_delete("ktest.key")?;
+    let detail = newest("config")?;
+    ensure!(detail == "ktest.key unset", "unset: {:?}", detail);
+
+    {
+        let _busy = crate::sqlite::DB.lock();
+        lifecycle::record(Kind::Repair, "ktest queued");
+        ensure!(
+            lifecycle::pending().iter().any(|p| p.detail == "ktest queued"),
+            "not queued while DB held"
+        );
+    }
+    ensure!(lifecycle::flush() >= 1, "flush wrote nothing");
+    ensure!(lifecycle::pending().is_empty(), "{} still pending", lifecycle::pending().len());
+    let detail = newest("repair")?;
+    ensure!(detail == "ktest queued", "queued: {:?}", detail);
+    Ok(())
+}
+
+/// Nested spans share a request id, and audit rows carry it.
+fn audit_request_ids() -> Result<(), String> {
+    use crate::span;
+
+    ensure!(span::current().is_none(), "request {:?} already open", span::current());
+    let outer = span::begin("ktest");
+    let id = format!("{}", outer.id());
+    {
+        let inner = span::begin("ktest");
+        ensure!(inner.id() == outer.id(), "nested span started request {}", inner.id());
+    }
+    ensure!(span::current() == Some(outer.id()), "inner span did not restore the outer request");
+
+    with_writable_db(|db| {
+        let usage = crate::api::Usage { input_tokens: 1, output_tokens: 1, ..Default::default() };
+        crate::sqlite::audit::record_usage(db, "ktest", "ktest-request-id", "ktest-model", 1, &usage, 0)?;
+        let got = db.query_value(
+            "SELECT request_id FROM audit WHERE target = 'ktest-request-id' ORDER BY id DESC LIMIT 1",
+        )?;
+        db.exec("DELETE FROM audit WHERE target = 'ktest-request-id'")?;
+        ensure!(got.as_deref() == Some(id.as_str()), "audit row has {:?}, expected {}", got, id);
+        Ok(())
+    })?;
+    drop(outer);
+    ensure!(span::current().is_none(), "request still open after its span ended");
+
+    let next = span::begin("ktest");
+    ensure!(format!("{}", next.id()) != id, "request id {} reused", id);
+    drop(next);
+    let count = span::stats().iter().find(|(k, _)| *k == "ktest").map_or(0, |(_, s)| s.count);
+    ensure!(count >= 3, "{} ktest spans counted", count);
+    Ok(())
+}
+
+/// A statement failing after some rows still closes its JSON array.
+fn json_output_error() -> Result<(), String> {
+    let mut lines: Vec<String> = Vec::new();
+    let result = crate::sqlite::exec_streamed(
+        "WITH RECURSIVE n(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM n WHERE x < 3) \
+         SELECT CASE WHEN x < 3 THEN x ELSE abs(-9223372036854775808) END AS v FROM n",
+        crate::sqlite::OutputMode::Json,
+        None,
+        None,
+        |line, _| {
+            lines.push(String::from(line));
+            true
+        },
+    );
+    ensure!(result.is_err(), "overflow not reported: {:?}", result);
+    ensure!(lines == [r#"[{"v":1}"#, r#",{"v":2}"#, "]"], "lines = {:?}", lines);
+    Ok(())
+}
+
+/// Tool inputs and usage rows come back apart through the JSON views.
+fn json_views() -> Result<(), String> {
+    use crate::sqlite::changes::{self, Change};
+    use crate::sqlite::{audit, SqlValue};
+
+    ensure!(!crate::sqlite::info()?.has("OMIT_JSON"), "built without sqlite-json");
+    with_writable_db(|db| {
+        let conv = changes::new_conversation_id(db)?;
+        let big = alloc::format!("{{\"path\":\"/ktest/v/b\",\"content\":\"{}\"}}", "x".repeat(5000));
+        let change = |tool_use_id, path, input| Change {
+            conversation: &conv,
+            tool_use_id,
+            tool: "write_file",
+            path,
+            before: None,
+            after: "x\n",
+            reason: "ktest",
+            input,
+        };
+        changes::record(db, &change("toolu_1", "/ktest/v/a", "{\"path\":\"/ktest/v/a\",\"content\":\"x\\n\"}"))?;
+        changes::record(db, &change("toolu_2", "/ktest/v/b", &big))?;
+        changes::record(db, &change("toolu_3", "/ktest/v/c", "not json"))?;
+
+        let conv_param = || [SqlValue::Text(conv.clone())];
+        let keys = db.query_params(
+            "SELECT tool_use_id || ':' || key FROM tool_inputs WHERE conversation = ? ORDER BY change_id, key",
+            &conv_param(),
+        )?;
+        let keys: Vec<&str> = keys.rows.iter().filter_map(|r| r.first().and_then(SqlValue::as_str)).collect();
+        ensure!(keys == ["toolu_1:content", "toolu_1:path", "toolu_2:path"], "tool_inputs = {:?}", keys);
+
+        let agent = "agent:ktest";
+        let usage = |i, o, w, r| crate::api::Usage {
+            input_tokens: i,
+            output_tokens: o,
+            cache_write_tokens: w,
+            cache_read_tokens: r,
+        };
+        audit::record_usage(db, agent, &conv, "m", 1, &usage(100, 20, 900, 0), 0)?;
+        audit::record_usage(db, agent, &conv, "m", 2, &usage(150, 5, 0, 900), 50)?;
+        let result = db.query_params(
+            "SELECT turns, input_tokens, output_tokens, cache_write_tokens, cache_read_tokens, \
+                    saved_input_tokens, saved_output_tokens, json_array_length(paths) \
+             FROM conversation_usage WHERE conversation = ?",
+            &conv_param(),
+        )?;
+        let row: Vec<i64> = result.rows.first().map(|r| r.iter().filter_map(SqlValue::as_integer).collect()).unwrap_or_default();
+        ensure!(row == [2, 250, 25, 900, 900, 75, 2, 3], "conversation_usage = {:?}", row);
+        ensure!(usage(150, 5, 0, 900).total() == 1055, "cached tokens left out of the total");
+
+        db.query_params("DELETE FROM changes WHERE conversation = ?", &conv_param())?;
+        db.query_params("DELETE FROM audit WHERE action = 'API_USAGE' AND target = ?", &conv_param())?;
+        Ok(())
+    })
+}
+
+// ---- Diff ----
+
+fn diff_unified() -> Result<(), String> {
+    use crate::diff;
+
+    ensure!(diff::unified("a\nb\n", "a\nb\n", "x", "y", 3).is_empty(), "identical texts differ");
+
+    let old = "1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n";
+    let new = "1\n2\nthree\n4\n5\n6\n7\n8\n9\n10\n11\n";
+    let out = diff::unified(old, new, "a/f", "b/f", 1);
+    let want = "--- a/f\n+++ b/f\n@@ -2,3 +2,3 @@\n 2\n-3\n+three\n 4\n@@ -10 +10,2 @@\n 10\n+11\n";
+    ensure!(out == want, "hunks:\n{}", out);
+
+    let out = diff::unified("", "x\n", "a", "b", 3);
+    ensure!(out == "--- a\n+++ b\n@@ -0,0 +1 @@\n+x\n", "from empty:\n{}", out);
+
+    let stat = diff::stat(old, new);
+    ensure!(stat.added == 2 && stat.removed == 1, "stat = {:?}", stat);
+    Ok(())
+}
+
+// ---- Lua ----
+
+/// defer/every keep an agent resident, `pump` fires what is due, cancel
+/// stops a timer, and an agent left with no timers is closed.
+fn agent_timers() -> Result<(), String> {
+    use crate::lua::{self, agents};
+
+    const NAME: &str = "ktest-timers";
+    let timers = || agents::list().into_iter().find(|a| a.name == NAME).map(|a| a.timers);
+
+    lua::run_string("local id, err = every(50, function() end)\nassert(id == nil and err)", NAME)?;
+    ensure!(timers().is_none(), "agent resident after a refused every()");
+
+    let script = "local tick = every(100, function() end)\n\
+                  defer(0, function() assert(cancel(tick)) end)";
+    lua::run_string(script, NAME)?;
+    ensure!(timers() == Some(2), "timers after run: {:?}", timers());
+
+    // Timers fire only from pump: the defer is due and cancels the every
+    agents::pump();
+    let left = timers();
+    agents::kill(NAME);
+    ensure!(left.is_none(), "agent still resident with {:?} timer(s)", left);
+    Ok(())
+}
+
+/// A write under a resident agent's on_change prefix, in any case, runs
+/// its callback once, from `pump`; a write beside the prefix doesn't.
+fn agent_on_change() -> Result<(), String> {
+    use crate::lua::{self, agents};
+
+    const NAME: &str = "ktest-on-change";
+    const CLEAN: &str = "DELETE FROM namespace WHERE path LIKE '/ktest/oc%'; \
+                         DELETE FROM audit WHERE action = 'KTEST_ON_CHANGE'";
+    const FIRED: &str = "SELECT count(*) || ':' || coalesce(group_concat(detail), '') FROM audit WHERE action = 'KTEST_ON_CHANGE'";
+    with_writable_db(|db| db.exec(CLEAN))?;
+    lua::run_string(
+        "on_change('/ktest/oc/', function(path, op) audit('INFO', 'KTEST_ON_CHANGE', op .. ' ' .. path) end)",
+        NAME,
+    )?;
+    with_writable_db(|db| {
+        db.exec("INSERT INTO namespace (path, type, content) VALUES ('/KTEST/OC/a', 'data', 'a'), ('/ktest/ocx', 'data', 'x')")
+    })?;
+    let (mut before, mut once, mut again) = (None, None, None);
+    with_writable_db(|db| {
+        before = db.query_value(FIRED)?;
+        Ok(())
+    })?;
+    agents::pump();
+    with_writable_db(|db| {
+        once = db.query_value(FIRED)?;
+        Ok(())
+    })?;
+    agents::pump();
+    agents::kill(NAME);
+    with_writable_db(|db| {
+        again = db.query_value(FIRED)?;
+        db.exec(CLEAN)
+    })?;
+    ensure!(before.as_deref() == Some("0:"), "callback ran before pump: {:?}", before);
+    ensure!(once.as_deref() == Some("1:insert /KTEST/OC/a"), "after pump: {:?}", once);
+    ensure!(again == once, "second pump: {:?}", again);
+    Ok(())
+}
+
+/// `boot.autorun` runs the agent it names and records the outcome; a
+/// failing agent is recorded too, and autorun returns so boot goes on.
+fn lua_autorun() -> Result<(), String> {
+    use crate::sqlite::lifecycle;
+
+    const OK: &str = "/ktest/autorun/ok.lua";
+    const BAD: &str = "/ktest/autorun/bad.lua";
+    const CLEAN: &str = "DELETE FROM namespace WHERE path LIKE '/ktest/autorun/%'; \
+                         DELETE FROM audit WHERE action = 'KTEST_AUTORUN'";
+    with_writable_db(|db| {
+        db.exec(CLEAN)?;
+        db.exec(
+            "INSERT INTO namespace (path, type, content) VALUES \
+             ('/ktest/autorun/ok.lua', 'lua', 'audit(''INFO'', ''KTEST_AUTORUN'', ''ran'')'), \
+             ('/ktest/autorun/bad.lua', 'lua', 'error(''ktest boom'')')",
+        )
+    })?;
+    let newest = || -> Result<String, String> {
+        let events = lifecycle::recent(1, Some("autorun"))?;
+        Ok(events.last().map(|e| e.detail.clone()).unwrap_or_default())
+    };
+
+    let saved = crate::sqlite::config_get("boot.autorun");
+    crate::sqlite::config_set("boot.autorun", OK)?;
+    crate::lua::autorun();
+    let ok = newest()?;
+    crate::sqlite::config_set("boot.autorun", BAD)?;
+    crate::lua::autorun();
+    let bad = newest()?;
+    match saved {
+        Some(value) => crate::sqlite::config_set("boot.autorun", &value)?,
+        None => crate::sqlite::config_delete("boot.autorun")?,
+    }
+    let mut ran = None;
+    with_writable_db(|db| {
+        ran = db.query_value("SELECT count(*) FROM audit WHERE action = 'KTEST_AUTORUN'")?;
+        db.exec(CLEAN)
+    })?;
+
+    ensure!(ran.as_deref() == Some("1"), "script ran {:?} time(s)", ran);
+    ensure!(ok == alloc::format!("{}: ok", OK), "ok: {:?}", ok);
+    ensure!(bad.starts_with(BAD) && bad.contains("ktest boom"), "bad: {:?}", bad);
+    Ok(())
+}
+
+/// A raised interrupt stops the script the console waits for, but not
+/// code run away from it, such as `/lua/eval`.
+fn lua_interrupt_foreground() -> Result<(), String> {
+    use crate::arch::x86_64::serial::{clear_interrupt, raise_interrupt};
+
+    raise_interrupt();
+    let foreground = crate::lua::run_string("local n = 0\nfor i = 1, 10000000 do n = n + i end", "ktest-interrupt");
+    let eval = crate::lua::eval::eval("local n = 0 for i = 1, 100000 do n = n + i end return n");
+    clear_interrupt();
+    ensure!(
+        foreground.as_ref().is_err_and(|e| e.contains("interrupted")),
+        "foreground script: {:?}", foreground
+    );
+    ensure!(eval.as_deref() == Ok("5000050000"), "eval: {:?}", eval);
+    Ok(())
+}
+
+// ---- Shell ----
+
+/// Names and aliases are unique, usage lines start with the name, and
+/// lookup, completion and registration agree.
+fn command_registry() -> Result<(), String> {
+    use crate::shell::registry::{self, Command, Section};
+
+    let all = registry::all();
+    let mut words: Vec<&str> = Vec::new();
+    for cmd in &all {
+        ensure!(cmd.usage.starts_with(cmd.name), "{}: usage {:?}", cmd.name, cmd.usage);
+        ensure!(!cmd.summary.is_empty(), "{}: no summary", cmd.name);
+        for word in core::iter::once(&cmd.name).chain(cmd.aliases) {
+            ensure!(!words.contains(word), "{} defined twice", word);
+            words.push(word);
+        }
+    }
+    ensure!(registry::find("poweroff").is_some_and(|c| c.name == "shutdown"), "alias lookup");
+    ensure!(registry::complete("sq") == ["sql", "sqlsh"], "complete sq: {:?}", registry::complete("sq"));
+
+    registry::register(Command {
+        name: "ktest-cmd", aliases: &[], usage: "ktest-cmd",
+        summary: "registered at run time", details: &[],
+        section: Section::System, run: |_| {},
+    });
+    ensure!(registry::find("ktest-cmd").is_some(), "registered command not found");
+    ensure!(registry::all().len() == all.len() + 1, "registered command not listed");
+    ensure!(registry::unregister("ktest-cmd"), "unregister found nothing");
+    ensure!(registry::find("ktest-cmd").is_none(), "unregistered command still found");
+    ensure!(registry::all().len() == all.len(), "unregistered command still listed");
+    Ok(())
+}
+
+/// `mv -r` refuses a destination holding the source, which would rename
+/// rows onto the source's own, and moves the whole tree elsewhere.
+fn mv_tree() -> Result<(), String> {
+    const CLEAN: &str = "DELETE FROM namespace WHERE path LIKE '/ktest/mv/%'";
+    const LIST: &str =
+        "SELECT group_concat(path, ',') FROM (SELECT path FROM namespace WHERE path LIKE '/ktest/mv/%' ORDER BY path)";
+    with_writable_db(|db| {
+        db.exec(CLEAN)?;
+        db.exec(
+            "INSERT INTO namespace (path, type, content) VALUES \
+             ('/ktest/mv/a/b/x', 'data', 'x'), ('/ktest/mv/a/b/b/y', 'data', 'y')",
+        )
+    })?;
+    crate::shell::files::mv(["-r", "/ktest/mv/a/b", "/ktest/mv/a"].into_iter());
+    let mut onto_parent = None;
+    with_writable_db(|db| {
+        onto_parent = db.query_value(LIST)?;
+        Ok(())
+    })?;
+    crate::shell::files::mv(["-r", "/ktest/mv/a/b", "/ktest/mv/c"].into_iter());
+    let mut moved = None;
+    with_writable_db(|db| {
+        moved = db.query_value(LIST)?;
+        db.exec(CLEAN)
+    })?;
+    ensure!(
+        onto_parent.as_deref() == Some("/ktest/mv/a/b/b/y,/ktest/mv/a/b/x"),
+        "moved onto its parent: {:?}", onto_parent
+    );
+    ensure!(moved.as_deref() == Some("/ktest/mv/c/b/y,/ktest/mv/c/x"), "moved: {:?}", moved);
+    Ok(())
+}
+
+/// A line starting with an alias runs its expansion, with the words typed
+/// after the alias appended.
+fn shell_alias() -> Result<(), String> {
+    const KEY: &str = "alias.ktest-mv";
+    const CLEAN: &str = "DELETE FROM namespace WHERE path LIKE '/ktest/alias/%'";
+    with_writable_db(|db| {
+        db.exec(CLEAN)?;
+        db.exec("INSERT INTO namespace (path, type, content) VALUES ('/ktest/alias/a', 'data', 'a')")
+    })?;
+    crate::sqlite::config_set(KEY, "mv /ktest/alias/a")?;
+    crate::shell::commands::dispatch("ktest-mv /ktest/alias/b");
+    crate::sqlite::config_delete(KEY)?;
+    let mut moved = None;
+    with_writable_db(|db| {
+        moved = db.query_value("SELECT group_concat(path) FROM namespace WHERE path LIKE '/ktest/alias/%'")?;
+        db.exec(CLEAN)
+    })?;
+    ensure!(moved.as_deref() == Some("/ktest/alias/b"), "after alias: {:?}", moved);
+    Ok(())
+}
+
+/// An oversized tool result keeps its head and tail, cut on char
+/// boundaries, and says how much went missing.
+fn tool_result_cap() -> Result<(), String> {
+    use crate::shell::agent::cap_result;
+
+    let short = String::from("small result");
+    ensure!(cap_result("sql_query", short.clone(), 4096) == short, "short result changed");
+
+    let big: String = (0..20_000).map(|i| if i % 3 == 0 { 'é' } else { 'x' }).collect();
+    let capped = cap_result("read_file", big.clone(), 4096);
+    ensure!(capped.len() <= 4096, "capped to {} bytes", capped.len());
+    ensure!(capped.contains("bytes left out") && capped.contains("read_range"), "no marker: {}", &capped[..200]);
+    let head = capped.split('\n').next().unwrap_or("");
+    let tail = capped.rsplit('\n').next().unwrap_or("");
+    ensure!(head.len() > 2000 && big.starts_with(head), "head of {} bytes", head.len());
+    ensure!(tail.len() > 500 && big.ends_with(tail), "tail of {} bytes", tail.len());
+    Ok(())
+}
+
+/// Near the window, the oldest exchanges give way to a note in the system
+/// prompt; the prompt and the last exchange stay, roles still alternating.
+fn context_window() -> Result<(), String> {
+    use crate::api::{Message, ToolCall};
+    use crate::shell::context::Context;
+
+    let mut messages = vec![Message::text("user", String::from("index /docs"))];
+    for i in 0..12 {
+        let call = ToolCall {
+            id: format!("t{}", i),
+            name: String::from("read_file"),
+            input_json: format!(r#"{{"path":"/docs/{}.md"}}"#, i),
+        };
+        messages.push(Message::assistant_tool_use(format!("reading part {}", i), vec![call]));
+        messages.push(Message::tool_result(format!("t{}", i), "x".repeat(4000), i == 3));
+    }
+    let mut context = Context::with_window(10_000);
+    let (before, after, dropped) = context.fit("base", &mut messages, 0).ok_or("nothing dropped")?;
+    ensure!(before > 7_500 && after <= 5_000 + 200, "estimate {} -> {}", before, after);
+    ensure!(dropped % 2 == 0 && messages.len() == 25 - dropped, "{} dropped, {} left", dropped, messages.len());
+    ensure!(messages[0].content == "index /docs", "prompt dropped");
+    let alternating = messages.iter().enumerate().all(|(i, m)| m.role == if i % 2 == 0 { "user" } else { "assistant" });
+    ensure!(alternating, "roles out of order");
+    let system = context.system("base");
+    ensure!(system.starts_with("base") && system.contains("read of /docs/0.md"), "note: {}", system);
+    ensure!(system.contains("read of /docs/3.md (failed)"), "failed call not marked: {}", system);
+    ensure!(context.fit("base", &mut messages, 0).is_none(), "compacted twice");
+    Ok(())
+}
+
+/// The first turn and turns after a failure stay on the big model; the
+/// route decides the rest.
+fn model_routing() -> Result<(), String> {
+    use crate::shell::agent::{LastCalls, Route, Routing};
+
+    let reads = Some(LastCalls { reads_only: true, failed: false });
+    let writes = Some(LastCalls { reads_only: false, failed: false });
+    let failed = Some(LastCalls { reads_only: true, failed: true });
+    for (route, first, after_reads, after_writes, after_failure) in [
+        (Route::Off, "big", "big", "big", "big"),
+        (Route::Reads, "big", "small", "big", "big"),
+        (Route::Followups, "big", "small", "small", "big"),
+    ] {
+        let routing = Routing { route, small_model: String::from("small") };
+        let got = [None, reads, writes, failed].map(|last| routing.model_for("big", last));
+        ensure!(
+            got == [first, after_reads, after_writes, after_failure],
+            "{:?}: {:?}", route, got
+        );
+    }
+    ensure!(Route::parse("bogus").is_none() && Route::parse(" reads ") == Some(Route::Reads), "parse");
+    Ok(())
+}
+
+/// Key names parse to control bytes, fixed keys are refused, and an
+/// unknown action is a command line.
+fn key_bindings() -> Result<(), String> {
+    use crate::shell::line::{self, Binding};
+
+    ensure!(line::parse_key("ctrl-w") == Some(0x17), "ctrl-w");
+    ensure!(line::parse_key("^T") == Some(0x14), "^T");
+    ensure!(line::parse_key("ctrl-c").is_none() && line::parse_key("ctrl-m").is_none(), "fixed keys bindable");
+    ensure!(line::parse_key("ctrl-ab").is_none(), "two letters");
+    ensure!(line::key_name(0x17) == "ctrl-w", "key_name {}", line::key_name(0x17));
+    ensure!(Binding::parse("delete-word") == Binding::DeleteWord, "action");
+    ensure!(Binding::parse("sql select 1") == Binding::Run(String::from("sql select 1")), "command");
+
+    line::set_binding(0x14, Some(Binding::Redraw));
+    let bound = line::bindings();
+    line::set_binding(0x14, None);
+    ensure!(bound.contains(&(0x14, Binding::Redraw)), "bindings {:?}", bound);
+    ensure!(!line::bindings().iter().any(|(k, _)| *k == 0x14), "binding not cleared");
+    Ok(())
+}
+
+// ---- Crypto ----
+
+/// Wiping clears spare capacity too, and the API key store hands out
+/// wiping copies.
+fn zeroize_buffers() -> Result<(), String> {
+    use crate::crypto::zeroize::{Zeroize, Zeroizing};
+
+    let mut v = Vec::with_capacity(64);
+    v.extend_from_slice(b"secret bytes past the end");
+    v.truncate(6);
+    let ptr = v.as_ptr();
+    v.zeroize();
+    ensure!(v.is_empty() && v.capacity() >= 64, "len {} cap {}", v.len(), v.capacity());
+    let spare = unsafe { core::slice::from_raw_parts(ptr, 25) };
+    ensure!(spare.iter().all(|&b| b == 0), "left {:?}", spare);
+
+    let mut key = String::from("sk-ant-ktest");
+    key.zeroize();
+    ensure!(key.is_empty(), "string not emptied");
+
+    let saved = crate::api::get_api_key();
+    crate::api::set_api_key("sk-ant-ktest-0123456789");
+    let copy = crate::api::get_api_key().ok_or_else(|| String::from("key not stored"))?;
+    ensure!(copy.as_str() == "sk-ant-ktest-0123456789", "key = {:?}", copy.as_str());
+    let wiped: Zeroizing<String> = copy.clone();
+    drop(wiped);
+    ensure!(copy.len() == 23, "clone shares the buffer");
+    match saved {
+        Some(key) => crate::api::set_api_key(&key),
+        None => crate::api::clear_api_key(),
+    }
+    Ok(())
+}
+
+/// Self-signed P-256, notAfter 2026-11-01 00:00:00 UTC, SAN
+/// DNS:*.example.com, DNS:example.com, IP:10.0.0.1.
+const TEST_CERT_B64: &str = "\
+MIIBmzCCAUGgAwIBAgIUcJYROTpwJ20zOYwNr534KCVTlnYwCgYIKoZIzj0EAwIwDDEKMAgGA1UE\
+AwwBdDAeFw0yNjAxMDEwMDAwMDBaFw0yNjExMDEwMDAwMDBaMAwxCjAIBgNVBAMMAXQwWTATBgcq\
+hkjOPQIBBggqhkjOPQMBBwNCAARJen9eIwenhocggbTzYXrCYjqihzP2rXls8eseBw1VTsvhmiYT\
++MVRU+mTJW2m7jizGpgYVY1ub1th9Ay6IXD2o4GAMH4wHQYDVR0OBBYEFMnSmA4E5tuPgMd9Tkl2\
+ncUmUPExMB8GA1UdIwQYMBaAFMnSmA4E5tuPgMd9Tkl2ncUmUPExMA8GA1UdEwEB/wQFMAMBAf8w\
+KwYDVR0RBCQwIoINKi5leGFtcGxlLmNvbYILZXhhbXBsZS5jb22HBAoAAAEwCgYIKoZIzj0EAwID\
+SAAwRQIhAK7KtsnorQfuEw3O2peBHrZNUpBGnT8W4CcLIqaBblL1AiA4K7pJzPzB+2LUuWeVb4jx\
+aVv/EgHprjqH7iKMUkxliw==";
+
+/// The parser behind `pin check <file>`, which works offline only:
+/// embedded-tls never shows us the server's certificate.
+fn cert_inspect() -> Result<(), String> {
+    use crate::crypto::pin_verifier::{self, host_matches};
+
+    let cert = crate::lua::util::base64_decode(TEST_CERT_B64.as_bytes())?;
+    let expires = 1_793_491_200;
+    let report = pin_verifier::inspect(&cert, "api.example.com", expires - 30 * 86_400)
+        .map_err(|e| format!("{:?}", e))?;
+    ensure!(report.expires == expires, "notAfter = {} ({})", report.not_after, report.expires);
+    ensure!(format!("{}", report.not_after) == "2026-11-01 00:00:00 UTC", "notAfter = {}", report.not_after);
+    ensure!(report.dns_names == ["*.example.com", "example.com"], "SAN = {:?}", report.dns_names);
+    ensure!(report.warnings.is_empty(), "warnings = {:?}", report.warnings);
+    ensure!(
+        pin_verifier::hex(&report.spki_hash) == "ffb519ccde9912d526827ac52d5ad43913ac140a8e8d35bb7fcf485022c219f4",
+        "SPKI hash = {}", pin_verifier::hex(&report.spki_hash)
+    );
+
+    let soon = pin_verifier::inspect(&cert, "example.com", expires - 3 * 86_400).map_err(|e| format!("{:?}", e))?;
+    ensure!(soon.warnings == ["certificate expires in 3 day(s)"], "warnings = {:?}", soon.warnings);
+    let late = pin_verifier::inspect(&cert, "api.anthropic.com", expires + 86_400).map_err(|e| format!("{:?}", e))?;
+    ensure!(
+        late.warnings == ["certificate expired 1 day(s) ago", "certificate does not name api.anthropic.com"],
+        "warnings = {:?}", late.warnings
+    );
+    ensure!(pin_verifier::inspect(&cert[..200], "example.com", 0).is_err(), "truncated cert accepted");
+
+    ensure!(host_matches("*.example.com", "API.example.com."), "wildcard");
+    ensure!(!host_matches("*.example.com", "example.com"), "wildcard matched the parent");
+    ensure!(!host_matches("*.example.com", "a.b.example.com"), "wildcard matched two labels");
+    ensure!(!host_matches("example.com", "badexample.com"), "suffix matched");
+    Ok(())
+}
+
+fn pin_trust() -> Result<(), String> {
+    use crate::crypto::pin_verifier;
+
+    let hex = "ffb519ccde9912d526827ac52d5ad43913ac140a8e8d35bb7fcf485022c219f4";
+    let hash = pin_verifier::parse_hex(hex).ok_or("hex not parsed")?;
+    ensure!(pin_verifier::hex(&hash) == hex, "round trip = {}", pin_verifier::hex(&hash));
+    ensure!(pin_verifier::parse_hex(&hex[1..]).is_none(), "63 digits accepted");
+    ensure!(pin_verifier::parse_hex(&hex.replace('f', "g")).is_none(), "non-hex accepted");
+
+    let before = pin_verifier::get_pin_override();
+    let saved = ["tls.pin", "tls.pin_enforce"].map(|key| (key, crate::sqlite::config_get(key)));
+    pin_verifier::trust(hash)?;
+    pin_verifier::clear_pin_override();
+    let pinned = pin_verifier::pin();
+    let requested = pin_verifier::enforce_requested();
+    let enforcing = pin_verifier::enforcing();
+    for (key, value) in saved {
+        match value {
+            Some(value) => crate::sqlite::config_set(key, &value)?,
+            None => crate::sqlite::config_delete(key)?,
+        }
+    }
+    if let Some(pin) = before {
+        pin_verifier::set_pin_override(pin);
+    }
+    ensure!(pinned == Some(hash), "tls.pin not read back: {:?}", pinned);
+    ensure!(requested, "tls.pin_enforce not on");
+    ensure!(enforcing == crate::api::ENFORCE_PINNING, "enforcing = {}", enforcing);
+    Ok(())
+}
+
+/// Boot found a source, and the RNG doesn't repeat itself.
+fn entropy_sources() -> Result<(), String> {
+    use crate::crypto::entropy;
+
+    let sources = entropy::sources();
+    ensure!(!sources.is_empty(), "no entropy source after boot");
+    ensure!(sources.contains(&entropy::Source::Rdrand) == crate::arch::x86_64::cpu::has_rdrand(), "sources {:?}", sources);
+    let (mut a, mut b) = ([0u8; 48], [0u8; 48]);
+    entropy::fill(&mut a);
+    entropy::fill(&mut b);
+    ensure!(a != b && a.iter().any(|&x| x != 0), "fill repeated or returned zeros");
+    let report = String::from_utf8(entropy::report()).map_err(|_| String::from("report not UTF-8"))?;
+    ensure!(report.starts_with(&format!("primary: {}\n", sources[0].as_str())), "report:\n{}", report);
+    Ok(())
+}
+
+// ---- JSON ----
+
+fn json_parse() -> Result<(), String> {
+    use crate::api::json::{self, JsonValue};
+
+    let input = r#"{"id":"msg_01","content":[{"type":"text","text":"café \"ok\"\n"}],
+        "usage":{"input_tokens":12,"output_tokens":-3.5e1},"stop":null,"ok":true}"#;
+    let v = json::parse(input).map_err(|e| format!("parse: {}", e))?;
+
+    ensure!(v.get("id").and_then(|x| x.as_str()) == Some("msg_01"), "id");
+    let text = v.get("content")
+        .and_then(|c| c.as_array())
+        .and_then(|a| a.first())
+        .and_then(|b| b.get("text"))
+        .and_then(|t| t.as_str());
+    ensure!(text == Some("café \"ok\"\n"), "text = {:?}", text);
+    let usage = v.get("usage").ok_or_else(|| String::from("usage"))?;
+    ensure!(usage.get("input_tokens").and_then(|n| n.as_i64()) == Some(12), "input_tokens");
+    ensure!(usage.get("output_tokens").and_then(|n| n.as_number()) == Some(-35.0), "output_tokens");
+    ensure!(matches!(v.get("stop"), Some(JsonValue::Null)), "stop");
+    ensure!(v.get("ok").and_then(|b| b.as_bool()) == Some(true), "ok");
+
+    ensure!(json::parse(r#"{"a":"#).is_err(), "truncated input accepted");
+    ensure!(json::parse("[1,]").is_err(), "trailing comma accepted");
+    Ok(())
+}
+
+fn non_streaming_message() -> Result<(), String> {
+    use crate::api::{json, parse_message};
+
+    let body = r#"{"id":"msg_02","type":"message","role":"assistant","content":[
+        {"type":"text","text":"Let me look. "},
+        {"type":"tool_use","id":"toolu_1","name":"ls","input":{"path":"/ktest","deep":false,"n":[1,2.5]}},
+        {"type":"text","text":"Done."}],"stop_reason":"tool_use","stop_sequence":null,
+        "usage":{"input_tokens":120,"output_tokens":34}}"#;
+    let parsed = json::parse(body).map_err(|e| format!("parse: {}", e))?;
+    let message = parse_message(&parsed).map_err(|e| format!("{}", e))?;
+    ensure!(message.text == "Let me look. Done.", "text = {:?}", message.text);
+    ensure!(message.stop_reason == "tool_use", "stop_reason = {}", message.stop_reason);
+    ensure!(message.stop_sequence.is_none(), "stop_sequence = {:?}", message.stop_sequence);
+    ensure!(message.usage.total() == 154, "usage = {:?}", message.usage);
+    ensure!(message.tool_calls.len() == 1, "{} tool calls", message.tool_calls.len());
+    let call = &message.tool_calls[0];
+    ensure!(call.id == "toolu_1" && call.name == "ls", "call = {} {}", call.id, call.name);
+    ensure!(call.input_json == r#"{"path":"/ktest","deep":false,"n":[1,2.5]}"#, "input = {}", call.input_json);
+
+    let error = json::parse(r#"{"type":"error","error":{"type":"overloaded_error","message":"Overloaded"}}"#)
+        .map_err(|e| format!("parse: {}", e))?;
+    ensure!(parse_message(&error).is_err(), "error body accepted as a message");
+    Ok(())
+}
+
+/// A chunked body comes back whole, chunk extensions skipped.
+fn dechunk_body() -> Result<(), String> {
+    use crate::api::http::{dechunk, HttpResponse};
+
+    let raw = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n7;x=y\r\n, world\r\n0\r\n\r\n";
+    let resp = HttpResponse::parse(raw).map_err(|e| format!("parse: {:?}", e))?;
+    ensure!(resp.is_chunked(), "chunked encoding not seen");
+    let body = dechunk(&raw[resp.body_start..]);
+    ensure!(body == b"hello, world", "body = {:?}", String::from_utf8_lossy(&body));
+    Ok(())
+}
+
+/// A mock rule walks an agent run through its steps, one turn each.
+fn mock_responses() -> Result<(), String> {
+    use crate::api::{mock, Message};
+    use crate::sqlite::SqlValue;
+
+    let path = format!("{}ktest.json", mock::RULES_PREFIX);
+    let rule = r#"{"match":"KTEST-MOCK","steps":[
+        {"text":"Looking.","tool_use":[{"name":"ls","input":{"path":"/ktest"}}]},
+        {"text":"All done."}]}"#;
+    with_writable_db(|db| {
+        db.query_params(
+            "INSERT OR REPLACE INTO namespace (path, type, content) VALUES (?1, 'data', ?2)",
+            &[SqlValue::Text(path.clone()), SqlValue::Text(String::from(rule))],
+        )?;
+        Ok(())
+    })?;
+
+    let mut messages = vec![Message::text("user", String::from("please ktest-mock this"))];
+    let first = mock::respond(&messages, &[], |_| {}).map_err(|e| format!("{}", e));
+    let stopped = mock::respond(&messages, &[String::from("ing"), String::from("ok")], |_| {})
+        .map_err(|e| format!("{}", e));
+    let second = first.as_ref().ok().map(|r| {
+        messages.push(Message::assistant_tool_use(r.text.clone(), r.tool_calls.clone()));
+        messages.push(Message::tool_result(String::from("toolu_mock_1_1"), String::from("a\nb"), false));
+        mock::respond(&messages, &[], |_| {}).map_err(|e| format!("{}", e))
+    });
+    with_writable_db(|db| {
+        db.query_params("DELETE FROM namespace WHERE path = ?1", &[SqlValue::Text(path.clone())])?;
+        Ok(())
+    })?;
+
+    let first = first?;
+    ensure!(first.stop_reason == "tool_use", "first stop_reason = {}", first.stop_reason);
+    ensure!(first.tool_calls.len() == 1, "{} tool calls", first.tool_calls.len());
+    let call = &first.tool_calls[0];
+    ensure!(call.name == "ls" && call.input_json == r#"{"path":"/ktest"}"#, "call = {} {}", call.name, call.input_json);
+    let second = second.ok_or("no second turn")??;
+    ensure!(second.text == "All done." && second.stop_reason == "end_turn", "second = {:?} {}", second.text, second.stop_reason);
+    let stopped = stopped?;
+    ensure!(stopped.text == "Lo" && stopped.tool_calls.is_empty(), "stopped = {:?}", stopped.text);
+    ensure!(
+        stopped.stop_reason == "stop_sequence" && stopped.stop_sequence.as_deref() == Some("ok"),
+        "stopped by {} {:?}", stopped.stop_reason, stopped.stop_sequence
+    );
+    Ok(())
+}
+
+/// Batch objects and result lines, each outcome kind.
+fn batch_results() -> Result<(), String> {
+    use crate::api::batch::{self, Outcome};
+
+    let batch = batch::parse_batch(r#"{"id":"msgbatch_01","type":"message_batch","processing_status":"ended"}"#)
+        .map_err(|e| format!("{}", e))?;
+    ensure!(batch.id == "msgbatch_01" && batch.ended(), "batch = {:?}", batch);
+    ensure!(batch::parse_batch(r#"{"type":"message_batch"}"#).is_err(), "batch without an id accepted");
+
+    let lines = r#"{"custom_id":"outbox-1","result":{"type":"succeeded","message":{"id":"msg_1","type":"message","role":"assistant","content":[{"type":"text","text":"Night."}],"stop_reason":"end_turn","usage":{"input_tokens":10,"output_tokens":2}}}}
+{"custom_id":"outbox-2","result":{"type":"errored","error":{"type":"error","error":{"type":"invalid_request_error","message":"bad model"}}}}
+not json
+{"custom_id":"outbox-3","result":{"type":"expired"}}
+{"custom_id":"outbox-4","result":{"type":"canceled"}}
+"#;
+    let results = batch::parse_results(lines);
+    ensure!(results.len() == 4, "{} results", results.len());
+    let ids: Vec<&str> = results.iter().map(|(id, _)| id.as_str()).collect();
+    ensure!(ids == ["outbox-1", "outbox-2", "outbox-3", "outbox-4"], "ids = {:?}", ids);
+    match &results[0].1 {
+        Outcome::Succeeded(m) => ensure!(m.text == "Night." && m.usage.total() == 12, "succeeded = {:?}", m.text),
+        _ => return Err(String::from("outbox-1 not succeeded")),
+    }
+    match &results[1].1 {
+        Outcome::Errored(e) => ensure!(e == "bad model", "error = {:?}", e),
+        _ => return Err(String::from("outbox-2 not errored")),
+    }
+    ensure!(matches!(results[2].1, Outcome::Expired), "outbox-3 not expired");
+    ensure!(matches!(results[3].1, Outcome::Canceled), "outbox-4 not canceled");
+    Ok(())
+}
+
+fn inflate_bodies() -> Result<(), String> {
+    use crate::api::{http, inflate};
+
+    // gzip -n of "Hello, Hello, Hello!": one fixed-Huffman block with a match
+    let gz: &[u8] = b"\x1f\x8b\x08\x00\x00\x00\x00\x00\x02\x03\xf3\x48\xcd\xc9\xc9\xd7\x51\xf0\x40\xa2\x14\x01\x6f\xa5\xfc\x50\x14\x00\x00\x00";
+    let out = inflate::gunzip(gz, 1024)?;
+    ensure!(out == b"Hello, Hello, Hello!", "gunzip = {:?}", String::from_utf8_lossy(&out));
+    ensure!(inflate::gunzip(gz, 10).is_err(), "cap of 10 bytes not enforced");
+    let mut bad = gz.to_vec();
+    bad[22] ^= 1;
+    ensure!(inflate::gunzip(&bad, 1024).is_err(), "bad CRC accepted");
+    ensure!(inflate::gunzip(&gz[..20], 1024).is_err(), "truncated member accepted");
+
+    // Raw DEFLATE, one stored block
+    let stored = inflate::zlib_or_raw(b"\x01\x06\x00\xf9\xff\x73\x74\x6f\x72\x65\x64", 1024)?;
+    ensure!(stored == b"stored", "stored = {:?}", String::from_utf8_lossy(&stored));
+
+    // zlib level 9 of "{i} squared is {i*i}\n" for 0..10: a dynamic block
+    let zlib: &[u8] = b"\x78\xda\x55\xcc\xb1\x0d\xc0\x20\x0c\x44\xd1\xfe\xa6\xf0\x08\x18\x8c\xc1\xe3\x20\x41\x41\x99\xa0\xec\x9f\xf6\x28\x9f\xf4\xf5\x93\x9c\xe7\x1b\xef\x9a\xb2\x8f\x24\x28\x53\x91\x99\x86\xc2\x0c\xd8\x15\x3b\x2a\x3b\x57\x38\xbb\x38\xda\x75\x0b\x74\xb6\x1b\x82\xdd\x15\x3f\xca\xc6\x2f\xa1";
+    let expected: String = (0..10).map(|i| format!("{} squared is {}\n", i, i * i)).collect();
+    let out = inflate::zlib_or_raw(zlib, 1024)?;
+    ensure!(out == expected.as_bytes(), "zlib = {:?}", String::from_utf8_lossy(&out));
+    let mut bad = zlib.to_vec();
+    bad[zlib.len() - 1] ^= 1;
+    ensure!(inflate::zlib_or_raw(&bad, 1024).is_err(), "bad Adler-32 accepted");
+
+    // Through the HTTP layer: chunked, then gzip
+    let mut raw = b"HTTP/1.1 200 OK\r\nContent-Encoding: gzip\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+    raw.extend_from_slice(b"10\r\n");
+    raw.extend_from_slice(&gz[..16]);
+    raw.extend_from_slice(b"\r\ne\r\n");
+    raw.extend_from_slice(&gz[16..]);
+    raw.extend_from_slice(b"\r\n0\r\n\r\n");
+    let resp = http::HttpResponse::parse(&raw).map_err(|e| format!("{:?}", e))?;
+    ensure!(resp.encoding().ok() == Some(http::Encoding::Gzip), "encoding not gzip");
+    let body = resp.body(&raw).map_err(|e| format!("{}", e))?;
+    ensure!(body == b"Hello, Hello, Hello!", "body = {:?}", String::from_utf8_lossy(&body));
+
+    let br = http::HttpResponse::parse(b"HTTP/1.1 200 OK\r\nContent-Encoding: br\r\n\r\n")
+        .map_err(|e| format!("{:?}", e))?;
+    ensure!(br.encoding().is_err(), "br accepted");
+    Ok(())
+}
+
+fn request_timeout() -> Result<(), String> {
+    use crate::api::{self, ApiError};
+
+    with_writable_db(|_| Ok(()))?;
+    ensure!(api::timeout_default() == api::REQUEST_TIMEOUT_SECS, "default {}", api::timeout_default());
+    crate::sqlite::config_set("api.timeout", "45")?;
+    let set = api::timeout_default();
+    crate::sqlite::config_set("api.timeout", "1")?;
+    let too_short = api::timeout_default();
+    crate::sqlite::config_delete("api.timeout")?;
+    ensure!(set == 45, "api.timeout 45 read as {}", set);
+    ensure!(too_short == api::REQUEST_TIMEOUT_SECS, "api.timeout 1 read as {}", too_short);
+
+    // A stalled stream is retried, and queued asks wait for the network
+    ensure!(ApiError::RequestTimeout.is_network(), "request timeout not a network failure");
+    let err = crate::error::KernelError::from(ApiError::RequestTimeout);
+    ensure!(format!("{}", err).contains("RequestTimeout"), "kernel error {}", err);
+    Ok(())
+}
+
+/// A stream cut short keeps its text but drops its tool calls, so
+/// nothing half-asked gets run.
+fn interrupted_stream() -> Result<(), String> {
+    use crate::api::StreamedMessage;
+
+    let mut message = StreamedMessage::new();
+    for data in [
+        r#"{"type":"content_block_start","index":0,"content_block":{"type":"text","text":""}}"#,
+        r#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"Listing"}}"#,
+        r#"{"type":"content_block_stop","index":0}"#,
+        r#"{"type":"content_block_start","index":1,"content_block":{"type":"tool_use","id":"t1","name":"list_dir"}}"#,
+        r#"{"type":"content_block_delta","index":1,"delta":{"type":"input_json_delta","partial_json":"{\"path\":\"/\"}"}}"#,
+        r#"{"type":"content_block_stop","index":1}"#,
+    ] {
+        ensure!(!message.event(data, &|_| {}), "message_stop in {}", data);
+    }
+    let response = message.interrupted();
+    ensure!(response.text == "Listing", "text {:?}", response.text);
+    ensure!(response.tool_calls.is_empty(), "{} tool call(s) kept", response.tool_calls.len());
+    ensure!(response.stop_reason == "interrupted", "stop_reason {}", response.stop_reason);
+    Ok(())
+}
+
+fn retry_policy() -> Result<(), String> {
+    use crate::api;
+
+    for n in 1..=6u32 {
+        let full = 1000u64 << (n - 1).min(4);
+        let delays: Vec<u64> = (0..8).map(|_| api::backoff_ms(n)).collect();
+        ensure!(delays.iter().all(|&d| d >= full / 2 && d <= full), "retry {}: {:?} outside {}..={}", n, delays, full / 2, full);
+        ensure!(delays.iter().any(|&d| d != delays[0]), "retry {}: no jitter in {:?}", n, delays);
+    }
+
+    // The budget is per span, and a new span starts afresh
+    let first = {
+        let _span = crate::span::begin("ktest");
+        (0..api::RETRY_BUDGET + 2).filter(|_| api::spend_retry()).count()
+    };
+    ensure!(first == api::RETRY_BUDGET as usize, "{} retries granted in one span", first);
+    let _span = crate::span::begin("ktest");
+    ensure!(api::spend_retry(), "fresh span started with its budget spent");
+    Ok(())
+}
+
+fn rate_limits() -> Result<(), String> {
+    use crate::api::ratelimit::{self, Limits};
+    use crate::api::{http, ApiError};
+
+    ensure!(ratelimit::rfc3339("1970-01-01T00:00:00Z") == Some(0), "epoch");
+    ensure!(ratelimit::rfc3339("2026-10-15T12:00:30.250Z") == Some(1_792_065_630), "rfc3339");
+    ensure!(ratelimit::http_date("Thu, 15 Oct 2026 12:00:00 GMT") == Some(1_792_065_600), "http date");
+    ensure!(ratelimit::rfc3339("2026-13-01T00:00:00Z").is_none(), "month 13 accepted");
+
+    let raw = b"HTTP/1.1 429 Too Many Requests\r\nDate: Thu, 15 Oct 2026 12:00:00 GMT\r\nRetry-After: 20\r\n\
+anthropic-ratelimit-requests-limit: 50\r\nanthropic-ratelimit-requests-remaining: 2\r\n\
+anthropic-ratelimit-requests-reset: 2026-10-15T12:00:30Z\r\n\
+anthropic-ratelimit-input-tokens-limit: 40000\r\nanthropic-ratelimit-input-tokens-remaining: 0\r\n\
+anthropic-ratelimit-input-tokens-reset: 2026-10-15T12:00:12Z\r\n\r\n";
+    let resp = http::HttpResponse::parse(raw).map_err(|e| format!("{:?}", e))?;
+    let limits = Limits::from_response(&resp, 5_000);
+    let requests = limits.requests.ok_or("no requests quota")?;
+    ensure!(requests.limit == 50 && requests.remaining == 2, "requests = {:?}", requests);
+    ensure!(requests.reset_ms == Some(35_000), "requests reset at {:?}", requests.reset_ms);
+    ensure!(limits.tokens.is_none() && limits.retry_after == Some(20), "limits = {:?}", limits);
+    ensure!(limits.exhausted() == "input_tokens 0/40000", "exhausted = {:?}", limits.exhausted());
+
+    // Input tokens are gone until 17_000; requests (2 left) spread 30 s over 3
+    let (wait, why) = limits.pace(5_000).ok_or("no pacing with a quota spent")?;
+    ensure!(wait == 12_000 && why.starts_with("input_tokens"), "pace = {} ({})", wait, why);
+    let (wait, why) = limits.pace(20_000).ok_or("no pacing with 2 requests left")?;
+    ensure!(wait == 5_000 && why.starts_with("requests"), "pace = {} ({})", wait, why);
+    ensure!(limits.pace(40_000).is_none(), "pacing after every reset");
+
+    let err = format!("{}", ApiError::RateLimited(Box::new(limits)));
+    ensure!(err == "HTTP 429: rate limited (input_tokens 0/40000), retry after 20s", "error = {:?}", err);
+    Ok(())
+}
+
+fn trace_redaction() -> Result<(), String> {
+    use crate::api::trace;
+
+    let request = "POST /v1/messages HTTP/1.1\r\nX-API-Key: sk-ant-secret\r\nauthorization: Bearer t0k\r\n\
+                   Content-Length: 13\r\n\r\n{\"x-api-key\":1}";
+    let out = trace::redact(request);
+    ensure!(!out.contains("sk-ant-secret") && !out.contains("t0k"), "credentials kept:\n{}", out);
+    ensure!(out.contains("X-API-Key: [redacted]\r\n"), "header dropped:\n{}", out);
+    ensure!(out.ends_with("\r\n\r\n{\"x-api-key\":1}"), "body changed:\n{}", out);
+
+    let long = trace::redact(&"x".repeat(20_000));
+    ensure!(long.len() < 17_000 && long.ends_with("more bytes not kept]"), "cap: {} bytes", long.len());
+    Ok(())
+}
+
+/// A saved transcript is readable by the shell and by no agent.
+fn trace_private() -> Result<(), String> {
+    use crate::api::trace;
+    use crate::sqlite::acl::{self, Access, Principal};
+
+    with_writable_db(|_| Ok(()))?;
+    let was_on = trace::enabled();
+    trace::set_enabled(true)?;
+    let mut recorder = trace::Recorder::start("POST /v1/messages HTTP/1.1\r\n\r\n{\"ktest\":1}");
+    recorder.record(b"HTTP/1.1 200 OK\r\n\r\n");
+    drop(recorder);
+    trace::set_enabled(was_on)?;
+
+    let (path, _) = trace::list()?.pop().ok_or("no transcript saved")?;
+    with_writable_db(|db| {
+        let agent = Principal::Agent(String::from("/agents/ktest.lua"));
+        let agent_reads = acl::permits(db, &agent, &path, Access::Read)?;
+        let shell_reads = acl::permits(db, &Principal::Shell, &path, Access::Read)?;
+        db.query_params("DELETE FROM namespace WHERE path = ?", &[crate::sqlite::SqlValue::Text(path.clone())])?;
+        ensure!(!agent_reads, "agent may read {}", path);
+        ensure!(shell_reads, "shell may not read {}", path);
+        Ok(())
+    })
+}
+
+// ---- Network ----
+
+/// An Ethernet/IPv4/TCP frame between 10.0.2.15:`local` and 1.2.3.4:443.
+fn tcp_frame(outgoing: bool, local: u16, seq: u32, ack: u32, flags: u8, payload: usize) -> Vec<u8> {
+    let mut f = vec![0u8; 14 + 20 + 20 + payload];
+    f[12..14].copy_from_slice(&[0x08, 0x00]);
+    let ip = &mut f[14..];
+    ip[0] = 0x45;
+    ip[2..4].copy_from_slice(&((40 + payload) as u16).to_be_bytes());
+    ip[9] = 6;
+    let (src, dst) = if outgoing { (local, 443) } else { (443, local) };
+    let tcp = &mut ip[20..];
+    tcp[0..2].copy_from_slice(&src.to_be_bytes());
+    tcp[2..4].copy_from_slice(&dst.to_be_bytes());
+    tcp[4..8].copy_from_slice(&seq.to_be_bytes());
+    tcp[8..12].copy_from_slice(&ack.to_be_bytes());
+    tcp[12] = 5 << 4;
+    tcp[13] = flags;
+    f
+}
+
+fn tcp_retransmit_rtt() -> Result<(), String> {
+    use crate::net::stats;
+    const SYN: u8 = 0x02;
+    const ACK: u8 = 0x10;
+    const PORT: u16 = 65001;
+
+    let handle = smoltcp::iface::SocketHandle::default();
+    stats::open(handle, PORT);
+    stats::observe_tx(&tcp_frame(true, PORT, 1000, 0, SYN, 0));
+    stats::observe_rx(&tcp_frame(false, PORT, 5000, 1001, SYN | ACK, 0));
+    let c = stats::get(handle).ok_or("not tracked")?;
+    ensure!(c.srtt_ms.is_some() && c.retransmits == 0, "handshake: {:?}", c);
+
+    stats::observe_tx(&tcp_frame(true, PORT, 1001, 5001, ACK, 100));
+    stats::observe_tx(&tcp_frame(true, PORT, 1101, 5001, ACK, 0));
+    stats::observe_tx(&tcp_frame(true, PORT, 1001, 5001, ACK, 100));
+    let c = stats::get(handle).ok_or("not tracked")?;
+    ensure!(c.retransmits == 1, "retransmits: {}", c.retransmits);
+
+    stats::forget(handle);
+    ensure!(stats::get(handle).is_none(), "still tracked after forget");
+    Ok(())
+}
+
+fn slaac_router_advert() -> Result<(), String> {
+    use crate::net::slaac;
+    use smoltcp::wire::Ipv6Address;
+
+    // fe80::1 → ff02::1: RA with prefix 2001:db8::/64 and RDNSS 2001:db8::53
+    let mut icmp = vec![134, 0, 0, 0, 64, 0, 0x07, 0x08, 0, 0, 0, 0, 0, 0, 0, 0];
+    let prefix = Ipv6Address::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0);
+    icmp.extend_from_slice(&[3, 4, 64, 0xC0, 0, 0, 0x0E, 0x10, 0, 0, 0x07, 0x08, 0, 0, 0, 0]);
+    icmp.extend_from_slice(prefix.as_bytes());
+    let dns = Ipv6Address::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0x53);
+    icmp.extend_from_slice(&[25, 3, 0, 0, 0, 0, 0x0E, 0x10]);
+    icmp.extend_from_slice(dns.as_bytes());
+
+    let router = Ipv6Address::new(0xfe80, 0, 0, 0, 0, 0, 0, 1);
+    let mut f = vec![0u8; 14 + 40];
+    f[12..14].copy_from_slice(&[0x86, 0xDD]);
+    f[14] = 0x60;
+    f[18..20].copy_from_slice(&(icmp.len() as u16).to_be_bytes());
+    f[20] = 58;
+    f[21] = 255;
+    f[22..38].copy_from_slice(router.as_bytes());
+    f[38..54].copy_from_slice(Ipv6Address::LINK_LOCAL_ALL_NODES.as_bytes());
+    f.extend_from_slice(&icmp);
+
+    let ra = slaac::parse_router_advert(&f).ok_or("advert not parsed")?;
+    ensure!(ra.router == router && ra.router_lifetime_s == 1800, "router: {:?}", ra);
+    ensure!(ra.prefix == Some((prefix, 3600)), "prefix: {:?}", ra.prefix);
+    ensure!(ra.dns == Some(dns), "dns: {:?}", ra.dns);
+
+    let mac = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+    let addr = slaac::address(prefix, mac);
+    let want = Ipv6Address::new(0x2001, 0xdb8, 0, 0, 0x5054, 0x00ff, 0xfe12, 0x3456);
+    ensure!(addr == want, "EUI-64 address: {}", addr);
+
+    // Forwarded by a router on the way (hop limit < 255): ignored
+    f[21] = 254;
+    ensure!(slaac::parse_router_advert(&f).is_none(), "off-link advert accepted");
+
+    // Hop limit 255 but a global source address: ignored
+    f[21] = 255;
+    f[22..38].copy_from_slice(Ipv6Address::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1).as_bytes());
+    ensure!(slaac::parse_router_advert(&f).is_none(), "advert from a global address accepted");
+    Ok(())
+}
+
+fn mdns_answer() -> Result<(), String> {
+    use crate::net::mdns::{answer, Service, Zone};
+    use smoltcp::wire::{IpAddress, IpEndpoint, Ipv4Address};
+
+    /// A one-question query, QU bit off.
+    fn query(id: u16, name: &str, qtype: u16) -> Vec<u8> {
+        let mut q = id.to_be_bytes().to_vec();
+        q.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+        for label in name.split('.') {
+            q.push(label.len() as u8);
+            q.extend_from_slice(label.as_bytes());
+        }
+        q.extend_from_slice(&[0, 0, qtype as u8, 0, 1]);
+        q
+    }
+    let count = |msg: &[u8], at: usize| u16::from_be_bytes([msg[at], msg[at + 1]]);
+
+    let addr = Ipv4Address::new(10, 0, 2, 15);
+    let services = [Service { service: "_heavenos._tcp", port: 0, txt: Vec::new() }];
+    let zone = Zone { hostname: "box", addrs: &[IpAddress::Ipv4(addr)], services: &services };
+    let peer = IpEndpoint::new(IpAddress::Ipv4(Ipv4Address::new(10, 0, 2, 2)), 5353);
+
+    // Case-insensitive A query: multicast reply, one answer holding the address
+    let (reply, to) = answer(&zone, &query(0, "BOX.local", 1), peer).ok_or("A query not answered")?;
+    ensure!(to.port == 5353 && to.addr != peer.addr, "A reply sent to {}", to);
+    ensure!(count(&reply, 6) == 1 && count(&reply, 4) == 0, "A reply counts: {:?}", &reply[..12]);
+    ensure!(reply.ends_with(addr.as_bytes()), "A reply lacks the address");
+
+    // Browsing: PTR answer, instance SRV/TXT and the address as additional
+    let (reply, _) = answer(&zone, &query(0, "_heavenos._tcp.local", 12), peer).ok_or("PTR not answered")?;
+    ensure!(count(&reply, 6) == 1 && count(&reply, 10) == 3, "PTR reply counts: {:?}", &reply[..12]);
+
+    // Legacy unicast: straight back, with the ID and the question
+    let legacy = IpEndpoint::new(peer.addr, 40000);
+    let (reply, to) = answer(&zone, &query(0x1234, "box.local", 1), legacy).ok_or("legacy query not answered")?;
+    ensure!(to == legacy && count(&reply, 0) == 0x1234 && count(&reply, 4) == 1, "legacy reply: {:?}", &reply[..12]);
+
+    ensure!(answer(&zone, &query(0, "other.local", 1), peer).is_none(), "answered for another name");
+    Ok(())
+}
+
+fn tls_buffer_pool() -> Result<(), String> {
+    use crate::net::tls::{TlsBuffers, RECORD_BUF_SIZE};
+
+    let mut bufs = TlsBuffers::take();
+    let (read, write) = bufs.slices();
+    ensure!(read.len() == RECORD_BUF_SIZE && write.len() == RECORD_BUF_SIZE, "buffer sizes");
+    write[..12].copy_from_slice(b"x-api-key: s");
+    let ptr = write.as_ptr();
+    drop(bufs);
+    let pooled = TlsBuffers::pooled();
+    ensure!(pooled >= 2, "{} buffers pooled after drop", pooled);
+
+    // The next session gets the same memory back, wiped
+    let mut again = TlsBuffers::take();
+    let (read, write) = again.slices();
+    let reused = read.as_ptr() == ptr || write.as_ptr() == ptr;
+    ensure!(reused, "buffer not reused");
+    ensure!(read.iter().chain(write.iter()).all(|&b| b == 0), "reused buffer not wiped");
+    ensure!(TlsBuffers::pooled() == pooled - 2, "pool did not lend");
+    Ok(())
+}
+
+fn tls_cipher_suites() -> Result<(), String> {
+    use crate::net::tls::{Chacha20Poly1305Sha256, Suite};
+    use chacha20poly1305::aead::{AeadInPlace, KeyInit};
+    use embedded_tls::TlsCipherSuite;
+
+    for suite in [Suite::Aes128Gcm, Suite::Aes256Gcm, Suite::Chacha20Poly1305] {
+        ensure!(Suite::parse(suite.as_str()) == Some(suite), "{} does not round-trip", suite.as_str());
+    }
+    let expected = if crate::arch::x86_64::cpu::has_aesni() { Suite::Aes128Gcm } else { Suite::Chacha20Poly1305 };
+    ensure!(Suite::for_cpu() == expected, "default suite ignores AES-NI");
+    ensure!(Suite::from_setting(Some("auto")) == expected, "auto is not the CPU's suite");
+    ensure!(Suite::from_setting(None) == expected, "unset is not the CPU's suite");
+    ensure!(Suite::from_setting(Some("aes256-gcm")) == Suite::Aes256Gcm, "aes256-gcm not honoured");
+
+    // RFC 8439 §2.8.2 AEAD test vector, through the suite's cipher type
+    let key: Vec<u8> = (0x80..0xA0).collect();
+    let nonce = [0x07, 0, 0, 0, 0x40, 0x41, 0x42, 0x43, 0x44, 0x45, 0x46, 0x47];
+    let aad = [0x50, 0x51, 0x52, 0x53, 0xC0, 0xC1, 0xC2, 0xC3, 0xC4, 0xC5, 0xC6, 0xC7];
+    let mut text = b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip \
+for the future, sunscreen would be it."
+        .to_vec();
+    let cipher = <Chacha20Poly1305Sha256 as TlsCipherSuite>::Cipher::new_from_slice(&key).map_err(|_| "key length")?;
+    let tag = cipher
+        .encrypt_in_place_detached(&nonce.into(), &aad, &mut text)
+        .map_err(|_| String::from("encrypt failed"))?;
+    ensure!(text[..4] == [0xD3, 0x1A, 0x8D, 0x34], "ciphertext {:02x?}", &text[..4]);
+    let want = [
+        0x1A, 0xE1, 0x0B, 0x59, 0x4F, 0x09, 0xE2, 0x6A, 0x7E, 0x90, 0x2E, 0xCB, 0xD0, 0x60, 0x06, 0x91,
+    ];
+    ensure!(tag[..] == want, "tag {:02x?}", &tag[..]);
+    Ok(())
+}
+
+fn dns_answers() -> Result<(), String> {
+    use crate::net::dns::{interleave, parse_response, TYPE_A};
+    use smoltcp::wire::{IpAddress, Ipv4Address, Ipv6Address};
+
+    // Response to "api.example" A: a CNAME, then two A records
+    let mut msg = vec![0x12, 0x34, 0x81, 0x80, 0, 1, 0, 3, 0, 0, 0, 0];
+    msg.extend_from_slice(b"\x03api\x07example\x00\x00\x01\x00\x01");
+    msg.extend_from_slice(&[0xC0, 12, 0, 5, 0, 1, 0, 0, 0, 60, 0, 2, 0xC0, 16]);
+    for (last, ttl) in [(1, 300u32), (2, 30)] {
+        msg.extend_from_slice(&[0xC0, 12, 0, 1, 0, 1]);
+        msg.extend_from_slice(&ttl.to_be_bytes());
+        msg.extend_from_slice(&[0, 4, 192, 0, 2, last]);
+    }
+    let (ips, ttl) = parse_response(&msg, TYPE_A).map_err(|e| alloc::format!("{}", e))?;
+    let a1 = IpAddress::Ipv4(Ipv4Address::new(192, 0, 2, 1));
+    let a2 = IpAddress::Ipv4(Ipv4Address::new(192, 0, 2, 2));
+    ensure!(ips == vec![a1, a2], "addresses {:?}", ips);
+    ensure!(ttl == 30, "ttl {} is not the lowest", ttl);
+
+    // One address per family in turn
+    let b1 = IpAddress::Ipv6(Ipv6Address::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1));
+    let order = interleave(vec![vec![a1, a2], vec![b1]]);
+    ensure!(order == vec![a1, b1, a2], "order {:?}", order);
+    Ok(())
+}
+
+fn syslog_target() -> Result<(), String> {
+    use crate::net::syslog::{parse_target, DEFAULT_PORT};
+    use smoltcp::wire::{IpAddress, IpEndpoint};
+
+    let v4: IpAddress = "10.0.2.2".parse().map_err(|_| "parse")?;
+    let v6: IpAddress = "2001:db8::1".parse().map_err(|_| "parse")?;
+    for (s, want) in [
+        ("10.0.2.2", Some(IpEndpoint::new(v4, DEFAULT_PORT))),
+        ("10.0.2.2:5140", Some(IpEndpoint::new(v4, 5140))),
+        ("2001:db8::1", Some(IpEndpoint::new(v6, DEFAULT_PORT))),
+        ("[2001:db8::1]", Some(IpEndpoint::new(v6, DEFAULT_PORT))),
+        ("[2001:db8::1]:5140", Some(IpEndpoint::new(v6, 5140))),
+        ("10.0.2.2:0", None),
+        ("0.0.0.0", None),
+        ("loghost", None),
+    ] {
+        ensure!(parse_target(s) == want, "{}: {:?}", s, parse_target(s));
+    }
+    Ok(())
+}
+
+// ---- Styx ----
+
+/// Builds a T-message: size[4] type[1] tag[2] body.
+struct TMsg(Vec<u8>);
+
+impl TMsg {
+    fn new(msg_type: u8, tag: u16) -> Self {
+        let mut buf = vec![0u8; 4];
+        buf.push(msg_type);
+        buf.extend_from_slice(&tag.to_le_bytes());
+        TMsg(buf)
+    }
+
+    fn u8(mut self, v: u8) -> Self { self.0.push(v); self }
+    fn u16(mut self, v: u16) -> Self { self.0.extend_from_slice(&v.to_le_bytes()); self }
+    fn u32(mut self, v: u32) -> Self { self.0.extend_from_slice(&v.to_le_bytes()); self }
+    fn u64(mut self, v: u64) -> Self { self.0.extend_from_slice(&v.to_le_bytes()); self }
+
+    fn str(self, s: &str) -> Self {
+        let mut m = self.u16(s.len() as u16);
+        m.0.extend_from_slice(s.as_bytes());
+        m
+    }
+
+    /// count[4] data, as in Twrite.
+    fn str_u32(self, s: &str) -> Self {
+        let mut m = self.u32(s.len() as u32);
+        m.0.extend_from_slice(s.as_bytes());
+        m
+    }
+
+    fn build(mut self) -> Vec<u8> {
+        let size = self.0.len() as u32;
+        self.0[0..4].copy_from_slice(&size.to_le_bytes());
+        self.0
+    }
+}
+
+/// Check an R-message header and return it for further inspection.
+fn expect_reply(reply: &[u8], msg_type: u8, tag: u16) -> Result<&[u8], String> {
+    ensure!(reply.len() >= 7, "short reply ({} bytes)", reply.len());
+    let size = u32::from_le_bytes(reply[0..4].try_into().unwrap()) as usize;
+    ensure!(size == reply.len(), "size field {} != {}", size, reply.len());
+    ensure!(reply[4] == msg_type, "reply type {} != {}", reply[4], msg_type);
+    ensure!(u16::from_le_bytes([reply[5], reply[6]]) == tag, "tag mismatch");
+    Ok(reply)
+}
+
+/// version → attach → walk → open → read → clunk against the real namespace.
+fn styx_roundtrip() -> Result<(), String> {
+    use crate::fs::styx::{namespace, StyxMsgType as T, StyxServer, NOFID, NOTAG};
+
+    let mut server = StyxServer::new(namespace::build_root());
+
+    let r = server.handle_message(&TMsg::new(T::Tversion as u8, NOTAG).u32(8192).str("9P2000").build());
+    let r = expect_reply(&r, T::Rversion as u8, NOTAG)?;
+    ensure!(&r[13..] == b"9P2000", "version {:?}", &r[13..]);
+
+    let r = server.handle_message(&TMsg::new(T::Tattach as u8, 1)
+        .u32(0).u32(NOFID).str("ktest").str("").build());
+    expect_reply(&r, T::Rattach as u8, 1)?;
+
+    let r = server.handle_message(&TMsg::new(T::Twalk as u8, 2)
+        .u32(0).u32(1).u16(2).str("sys").str("uptime").build());
+    let r = expect_reply(&r, T::Rwalk as u8, 2)?;
+    ensure!(u16::from_le_bytes([r[7], r[8]]) == 2, "walked {} of 2 names", u16::from_le_bytes([r[7], r[8]]));
+
+    let r = server.handle_message(&TMsg::new(T::Topen as u8, 3).u32(1).u8(0).build());
+    expect_reply(&r, T::Ropen as u8, 3)?;
+
+    let r = server.handle_message(&TMsg::new(T::Tread as u8, 4).u32(1).u64(0).u32(256).build());
+    let r = expect_reply(&r, T::Rread as u8, 4)?;
+    let count = u32::from_le_bytes(r[7..11].try_into().unwrap()) as usize;
+    ensure!(count > 0 && r.len() == 11 + count, "read {} bytes", count);
+
+    let r = server.handle_message(&TMsg::new(T::Tclunk as u8, 5).u32(1).build());
+    expect_reply(&r, T::Rclunk as u8, 5)?;
+
+    // Walking a missing name must fail cleanly, not panic.
+    let r = server.handle_message(&TMsg::new(T::Twalk as u8, 6)
+        .u32(0).u32(2).u16(1).str("no-such-file").build());
+    expect_reply(&r, T::Rerror as u8, 6)?;
+    Ok(())
+}
+
+/// A session appears under /agents/, its log streams, and ctl writes reach
+/// the loop.
+fn styx_agent_sessions() -> Result<(), String> {
+    use crate::fs::styx::{namespace, StyxMsgType as T, StyxServer, NOFID};
+    use crate::shell::sessions::{self, Control, State};
+
+    let mut server = StyxServer::new(namespace::build_root());
+    let r = server.handle_message(&TMsg::new(T::Tattach as u8, 1)
+        .u32(0).u32(NOFID).str("ktest").str("").build());
+    expect_reply(&r, T::Rattach as u8, 1)?;
+
+    let id = sessions::start("ktest prompt");
+    let name = format!("{}", id);
+    sessions::log_line(id, "first line");
+    let read = |server: &mut StyxServer, fid: u32, offset: u64, tag: u16| -> Result<Vec<u8>, String> {
+        let r = server.handle_message(&TMsg::new(T::Tread as u8, tag).u32(fid).u64(offset).u32(4096).build());
+        let r = expect_reply(&r, T::Rread as u8, tag)?;
+        Ok(r[11..].to_vec())
+    };
+
+    // status
+    let r = server.handle_message(&TMsg::new(T::Twalk as u8, 2)
+        .u32(0).u32(1).u16(3).str("agents").str(&name).str("status").build());
+    expect_reply(&r, T::Rwalk as u8, 2)?;
+    let r = server.handle_message(&TMsg::new(T::Topen as u8, 3).u32(1).u8(0).build());
+    expect_reply(&r, T::Ropen as u8, 3)?;
+    let status = read(&mut server, 1, 0, 4)?;
+    ensure!(status.starts_with(b"state: running\nprompt: ktest prompt\n"), "status {:?}", String::from_utf8_lossy(&status));
+
+    // log: a second read continues where the first stopped
+    let r = server.handle_message(&TMsg::new(T::Twalk as u8, 5)
+        .u32(0).u32(2).u16(3).str("agents").str(&name).str("log").build());
+    expect_reply(&r, T::Rwalk as u8, 5)?;
+    let r = server.handle_message(&TMsg::new(T::Topen as u8, 6).u32(2).u8(0).build());
+    expect_reply(&r, T::Ropen as u8, 6)?;
+    ensure!(read(&mut server, 2, 0, 7)? == b"first line\n", "first log read");
+    sessions::log_text(id, "more");
+    ensure!(read(&mut server, 2, 11, 8)? == b"more", "log did not continue");
+
+    // ctl
+    let r = server.handle_message(&TMsg::new(T::Twalk as u8, 9)
+        .u32(0).u32(3).u16(3).str("agents").str(&name).str("ctl").build());
+    expect_reply(&r, T::Rwalk as u8, 9)?;
+    let r = server.handle_message(&TMsg::new(T::Topen as u8, 10).u32(3).u8(2).build());
+    expect_reply(&r, T::Ropen as u8, 10)?;
+    let r = server.handle_message(&TMsg::new(T::Twrite as u8, 11).u32(3).u64(0).str_u32("pause\n").build());
+    expect_reply(&r, T::Rwrite as u8, 11)?;
+    ensure!(sessions::take_control(id) == Some(Control::Pause), "pause not delivered");
+    let r = server.handle_message(&TMsg::new(T::Twrite as u8, 12).u32(3).u64(0).str_u32("explode").build());
+    expect_reply(&r, T::Rerror as u8, 12)?;
+
+    sessions::finish(id, State::Done, None);
+    ensure!(read(&mut server, 3, 0, 13)? == b"done\n", "ctl state after finish");
+    ensure!(sessions::control(id, Control::Stop).is_err(), "stop accepted by a finished session");
+    Ok(())
+}
+
+/// A stop written to ctl cuts the request in flight short (the loop's
+/// abort check sees it) and ends the run with the ctl's reason.
+fn styx_agent_ctl_stop() -> Result<(), String> {
+    use crate::fs::styx::{namespace, StyxMsgType as T, StyxServer, NOFID};
+    use crate::shell::agent::{stop_requested, Run};
+    use crate::shell::sessions::{self, State};
+
+    let mut server = StyxServer::new(namespace::build_root());
+    let r = server.handle_message(&TMsg::new(T::Tattach as u8, 1)
+        .u32(0).u32(NOFID).str("ktest").str("").build());
+    expect_reply(&r, T::Rattach as u8, 1)?;
+
+    let id = sessions::start("ktest stop");
+    let run = Run::new(id);
+    ensure!(!stop_requested(), "stop requested before any ctl write");
+    ensure!(run.check_control().is_none(), "running session told to stop");
+
+    let r = server.handle_message(&TMsg::new(T::Twalk as u8, 2)
+        .u32(0).u32(1).u16(3).str("agents").str(&format!("{}", id)).str("ctl").build());
+    expect_reply(&r, T::Rwalk as u8, 2)?;
+    let r = server.handle_message(&TMsg::new(T::Topen as u8, 3).u32(1).u8(1).build());
+    expect_reply(&r, T::Ropen as u8, 3)?;
+    let r = server.handle_message(&TMsg::new(T::Twrite as u8, 4).u32(1).u64(0).str_u32("stop\n").build());
+    expect_reply(&r, T::Rwrite as u8, 4)?;
+
+    let aborted = stop_requested();
+    let reason = run.check_control();
+    let after = stop_requested();
+    sessions::finish(id, State::Stopped, reason.as_deref());
+    ensure!(aborted, "ctl stop did not abort the request");
+    ensure!(reason.as_deref() == Some("stop requested"), "stop reason {:?}", reason);
+    ensure!(!after, "stop still pending once acted on");
+    Ok(())
+}
+
+/// A chunk written to /lua/eval is run, and reading the file returns its
+/// results; a failing chunk reads back as `error: ...`.
+fn styx_lua_eval() -> Result<(), String> {
+    use crate::fs::styx::{namespace, StyxMsgType as T, StyxServer, NOFID};
+
+    let mut server = StyxServer::new(namespace::build_root());
+    let r = server.handle_message(&TMsg::new(T::Tattach as u8, 1)
+        .u32(0).u32(NOFID).str("ktest").str("").build());
+    expect_reply(&r, T::Rattach as u8, 1)?;
+    let r = server.handle_message(&TMsg::new(T::Twalk as u8, 2)
+        .u32(0).u32(1).u16(2).str("lua").str("eval").build());
+    expect_reply(&r, T::Rwalk as u8, 2)?;
+    let r = server.handle_message(&TMsg::new(T::Topen as u8, 3).u32(1).u8(2).build());
+    expect_reply(&r, T::Ropen as u8, 3)?;
+
+    let mut eval = |code: &str, tag: u16| -> Result<String, String> {
+        let r = server.handle_message(&TMsg::new(T::Twrite as u8, tag).u32(1).u64(0).str_u32(code).build());
+        expect_reply(&r, T::Rwrite as u8, tag)?;
+        let r = server.handle_message(&TMsg::new(T::Tread as u8, tag + 1).u32(1).u64(0).u32(4096).build());
+        let r = expect_reply(&r, T::Rread as u8, tag + 1)?;
+        Ok(String::from_utf8_lossy(&r[11..]).into_owned())
+    };
+
+    // Not an expression: run as a chunk
+    let out = eval("local t = {}\nfor i = 1, 3 do t[i] = i * i end\nreturn table.concat(t, ','), 'ok'", 4)?;
+    ensure!(out == "1,4,9\tok\n", "chunk result {:?}", out);
+
+    let out = eval("error('boom', 0)", 6)?;
+    ensure!(out.starts_with("error: ") && out.contains("boom"), "error result {:?}", out);
+    Ok(())
+}
+
+/// The TCP transport splits a byte stream into whole 9P messages, and
+/// keeps a partial one for the next read.
+fn styx_framing() -> Result<(), String> {
+    use crate::fs::styx::listen::next_message;
+    use crate::fs::styx::{StyxMsgType as T, NOTAG};
+
+    let msg = TMsg::new(T::Tversion as u8, NOTAG).u32(8192).str("9P2000").build();
+    let mut inbox = msg.clone();
+    inbox.extend_from_slice(&msg[..5]);
+    ensure!(next_message(&mut inbox) == Some(msg.clone()), "first message");
+    ensure!(next_message(&mut inbox).is_none(), "partial taken");
+    inbox.extend_from_slice(&msg[5..]);
+    ensure!(next_message(&mut inbox) == Some(msg), "second message");
+    ensure!(inbox.is_empty(), "{} bytes left", inbox.len());
+    Ok(())
+}
@@ -12,20 +12,36 @@ pub mod arch;
 #[cfg(not(test))]
 pub mod crypto;
 #[cfg(not(test))]
+pub mod diff;
+#[cfg(not(test))]
 pub mod drivers;
 #[cfg(not(test))]
 pub mod fs;
 #[cfg(not(test))]
+pub mod klog;
+#[cfg(not(test))]
+pub mod maintenance;
+#[cfg(not(test))]
 pub mod mem;
 #[cfg(not(test))]
 pub mod net;
 #[cfg(not(test))]
+pub mod selftest;
+#[cfg(not(test))]
 pub mod shell;
 #[cfg(not(test))]
+pub mod span;
+#[cfg(not(test))]
 pub mod sqlite;
 #[cfg(not(test))]
 pub mod lua;
 #[cfg(not(test))]
+pub mod term;
+#[cfg(not(test))]
+pub mod time;
+#[cfg(all(not(test), feature = "ktest"))]
+pub mod ktest;
+#[cfg(not(test))]
 pub mod vfs;
 
 // --- Test stubs for types referenced by the storage module ---
@@ -37,7 +53,7 @@ pub mod vfs;
 pub mod drivers {
     pub mod nvme {
         /// Stub NvmeError for host-target tests.
-        #[derive(Debug)]
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
         pub enum NvmeError {
             OutOfMemory,
             MediaError,
@@ -51,6 +67,12 @@ pub mod drivers {
                 }
             }
         }
+
+        impl From<NvmeError> for crate::error::KernelError {
+            fn from(e: NvmeError) -> Self {
+                crate::error::KernelError::new(crate::error::Layer::Nvme, alloc::format!("{:?}", e), e)
+            }
+        }
     }
 }
 
@@ -95,7 +117,13 @@ pub mod mem {
         pub fn copy_from_slice(&mut self, src: &[u8]) {
             self.data[..src.len()].copy_from_slice(src);
         }
+
+        pub fn copy_to_slice(&self, dest: &mut [u8], offset: usize, len: usize) {
+            dest[..len].copy_from_slice(&self.data[offset..offset + len]);
+        }
     }
 }
 
+pub mod error;
+pub mod secrets;
 pub mod storage;
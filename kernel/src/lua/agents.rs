@@ -0,0 +1,390 @@
+//! Resident Lua agents: states kept alive to receive namespace events.
+//!
+//! An agent whose script registers `on_change` subscriptions or
+//! `defer`/`every` timers is not closed when the script body returns. Its
+//! state moves here, and committed namespace changes (from
+//! `sqlite::events`) that match one of its prefixes — case-insensitively,
+//! as the namespace compares paths — are queued in its mailbox. `pump()` delivers them by calling the subscribed functions, a
+//! bounded number per pass, so a chatty writer can't starve the shell,
+//! and calls the timer functions that have come due. Nothing runs between
+//! events and timers — no polling loop. An agent left with neither is
+//! closed.
+//!
+//! Nothing runs on its own: there is no scheduler, and `pump()` is called
+//! only where the console is idle — the shell waiting for input
+//! (`shell::line::wait_byte`) and a paused agent loop. A timer never fires
+//! early but can fire late: whatever comes due while a command runs waits
+//! for the prompt to come back, and a periodic timer then fires once, not
+//! once per period missed.
+
+#![allow(non_snake_case)] // `L` for the Lua state, as in the C API
+
+use ::alloc::boxed::Box;
+use ::alloc::collections::VecDeque;
+use ::alloc::string::String;
+use ::alloc::vec::Vec;
+use core::ffi::c_char;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+use super::alloc::LuaAllocState;
+use super::ffi::*;
+use crate::serial_println;
+use crate::sqlite::events::{self, Change};
+use crate::sqlite::namespace::has_prefix;
+
+/// Time limit for a single on_change or timer callback.
+const CALLBACK_TIMEOUT_MS: u64 = 5_000;
+
+/// Undelivered changes kept per agent; older ones are dropped beyond this.
+const MAILBOX_CAPACITY: usize = 256;
+
+/// Callbacks run per `pump()` call, across all agents.
+const DELIVERIES_PER_PUMP: usize = 32;
+
+/// A Lua state that outlived its script body.
+struct ResidentAgent {
+    name: String,
+    L: *mut LuaState,
+    /// Allocator bookkeeping referenced by the state's userdata pointer.
+    _alloc: Box<LuaAllocState>,
+    /// Cached copy of the subscription prefixes, for cheap matching.
+    prefixes: Vec<String>,
+    mailbox: VecDeque<Change>,
+    dropped: u64,
+    /// Cached timer count and earliest due time (ms since boot).
+    timers: usize,
+    next_due: Option<u64>,
+}
+
+// The state is only ever touched by whoever holds it out of AGENTS.
+unsafe impl Send for ResidentAgent {}
+
+impl Drop for ResidentAgent {
+    fn drop(&mut self) {
+        unsafe { lua_close(self.L) };
+    }
+}
+
+static AGENTS: Mutex<Vec<ResidentAgent>> = Mutex::new(Vec::new());
+
+/// Earliest timer due across all agents; u64::MAX when there is none.
+static NEXT_DUE: AtomicU64 = AtomicU64::new(u64::MAX);
+
+/// Summary of a resident agent, for `ls /agents`.
+pub struct AgentInfo {
+    pub name: String,
+    pub subscriptions: usize,
+    pub timers: usize,
+    pub queued: usize,
+    pub dropped: u64,
+}
+
+/// Keep `L` alive if its script registered subscriptions or timers.
+///
+/// Returns `Err(L)` (giving the state back to the caller to close) when
+/// there is nothing to wait for. A resident agent with the same name is
+/// replaced. Returns the number of subscriptions plus timers.
+pub(super) unsafe fn adopt(
+    name: &str,
+    L: *mut LuaState,
+    alloc: Box<LuaAllocState>,
+) -> Result<usize, (*mut LuaState, Box<LuaAllocState>)> {
+    let prefixes = unsafe { subscription_prefixes(L) };
+    let (timers, next_due) = unsafe { self::timers(L) };
+    if prefixes.is_empty() && timers == 0 {
+        return Err((L, alloc));
+    }
+    let count = prefixes.len() + timers;
+
+    let agent = ResidentAgent {
+        name: String::from(name),
+        L,
+        _alloc: alloc,
+        prefixes,
+        mailbox: VecDeque::new(),
+        dropped: 0,
+        timers,
+        next_due,
+    };
+
+    let mut agents = AGENTS.lock();
+    agents.retain(|a| a.name != name);
+    agents.push(agent);
+    events::set_listening(true);
+    update_next_due(&agents);
+    Ok(count)
+}
+
+/// Stop and close a resident agent. Returns false if none had that name.
+pub fn kill(name: &str) -> bool {
+    let mut agents = AGENTS.lock();
+    let before = agents.len();
+    agents.retain(|a| a.name != name);
+    if agents.is_empty() {
+        events::set_listening(false);
+    }
+    update_next_due(&agents);
+    before != agents.len()
+}
+
+/// List resident agents.
+pub fn list() -> Vec<AgentInfo> {
+    AGENTS
+        .lock()
+        .iter()
+        .map(|a| AgentInfo {
+            name: a.name.clone(),
+            subscriptions: a.prefixes.len(),
+            timers: a.timers,
+            queued: a.mailbox.len(),
+            dropped: a.dropped,
+        })
+        .collect()
+}
+
+/// Route new changes into mailboxes, run pending callbacks and fire the
+/// timers that are due.
+///
+/// Cheap when nothing is pending. Must not be called with the DB lock held.
+pub fn pump() {
+    let now = crate::time::monotonic_ms();
+    let timers_due = now >= NEXT_DUE.load(Ordering::Relaxed);
+    if !events::pending() && !timers_due {
+        return;
+    }
+
+    // Take the agents out so callbacks (which may write, producing more
+    // events) never run under the AGENTS lock.
+    let mut agents = core::mem::take(&mut *AGENTS.lock());
+
+    for change in events::drain() {
+        for agent in agents.iter_mut() {
+            if agent.prefixes.iter().any(|p| has_prefix(&change.path, p)) {
+                if agent.mailbox.len() >= MAILBOX_CAPACITY {
+                    agent.mailbox.pop_front();
+                    agent.dropped += 1;
+                }
+                agent.mailbox.push_back(change.clone());
+            }
+        }
+    }
+
+    // Round-robin one message per agent so no mailbox monopolises the pass
+    let mut budget = DELIVERIES_PER_PUMP;
+    while budget > 0 {
+        let mut delivered = false;
+        for agent in agents.iter_mut() {
+            if budget == 0 {
+                break;
+            }
+            if let Some(change) = agent.mailbox.pop_front() {
+                unsafe { deliver(agent, &change) };
+                budget -= 1;
+                delivered = true;
+            }
+        }
+        if !delivered {
+            break;
+        }
+    }
+
+    if timers_due {
+        for agent in agents.iter_mut() {
+            if agent.next_due.is_some_and(|due| due <= now) {
+                unsafe { fire_timers(agent, now) };
+            }
+        }
+    }
+
+    // An agent with nothing left to wait for is done
+    agents.retain(|a| {
+        let done = a.prefixes.is_empty() && a.timers == 0 && a.mailbox.is_empty();
+        if done {
+            serial_println!("[lua] {} finished", a.name);
+        }
+        !done
+    });
+
+    if agents.iter().any(|a| !a.mailbox.is_empty()) {
+        events::rearm();
+    }
+
+    // Anything adopted while we held the list out goes after the old ones
+    let mut guard = AGENTS.lock();
+    agents.append(&mut guard);
+    *guard = agents;
+    if guard.is_empty() {
+        events::set_listening(false);
+    }
+    update_next_due(&guard);
+}
+
+fn update_next_due(agents: &[ResidentAgent]) {
+    let next = agents.iter().filter_map(|a| a.next_due).min().unwrap_or(u64::MAX);
+    NEXT_DUE.store(next, Ordering::Relaxed);
+}
+
+/// Call every timer of `agent` that is due at `now`, earliest first.
+unsafe fn fire_timers(agent: &mut ResidentAgent, now: u64) {
+    let L = agent.L;
+    // Collect first: callbacks may add or cancel timers
+    let mut due = Vec::new();
+    unsafe {
+        if lua_getfield(L, LUA_REGISTRYINDEX, c"_TIMERS".as_ptr()) == LUA_TTABLE {
+            lua_pushnil(L);
+            while lua_next(L, -2) != 0 {
+                let id = lua_tointegerx(L, -2, core::ptr::null_mut());
+                lua_getfield(L, -1, c"due".as_ptr());
+                let at = lua_tointegerx(L, -1, core::ptr::null_mut());
+                lua_pop(L, 2); // due, entry
+                if at <= now as i64 {
+                    due.push((at, id));
+                }
+            }
+        }
+        lua_pop(L, 1); // _TIMERS
+    }
+    due.sort_unstable();
+    for (_, id) in due {
+        unsafe { fire(agent, id, now) };
+    }
+    unsafe { refresh(agent) };
+}
+
+/// Run timer `id` of `agent`: a periodic one is rescheduled first, a
+/// one-shot removed, so the callback may cancel or re-add itself.
+unsafe fn fire(agent: &ResidentAgent, id: i64, now: u64) {
+    let L = agent.L;
+    unsafe {
+        if lua_getfield(L, LUA_REGISTRYINDEX, c"_TIMERS".as_ptr()) != LUA_TTABLE {
+            lua_pop(L, 1);
+            return;
+        }
+        if lua_rawgeti(L, -1, id) != LUA_TTABLE {
+            lua_pop(L, 2); // cancelled by an earlier callback
+            return;
+        }
+        lua_getfield(L, -1, c"period".as_ptr());
+        let period = lua_tointegerx(L, -1, core::ptr::null_mut());
+        lua_pop(L, 1);
+        if period > 0 {
+            lua_pushinteger(L, now as i64 + period);
+            lua_setfield(L, -2, c"due".as_ptr());
+        } else {
+            lua_pushnil(L);
+            lua_rawseti(L, -3, id);
+        }
+
+        lua_getfield(L, -1, c"fn".as_ptr());
+        super::set_deadline(L, CALLBACK_TIMEOUT_MS);
+        let mut span = crate::span::begin("lua");
+        if lua_pcall(L, 0, 0, 0) != LUA_OK {
+            span.fail();
+            let err = match lua_to_str(L, -1) {
+                Some(b) => String::from_utf8_lossy(b).into_owned(),
+                None => String::from("unknown Lua error"),
+            };
+            lua_pop(L, 1);
+            serial_println!("[lua] {}: timer {} failed: {}{}", agent.name, id, err, crate::span::tag());
+        }
+        drop(span);
+        lua_pop(L, 2); // entry, _TIMERS
+    }
+}
+
+/// Re-read what a callback may have changed: subscriptions and timers.
+unsafe fn refresh(agent: &mut ResidentAgent) {
+    agent.prefixes = unsafe { subscription_prefixes(agent.L) };
+    (agent.timers, agent.next_due) = unsafe { timers(agent.L) };
+}
+
+/// Number of timers in the registry's _TIMERS table, and the earliest
+/// due time among them.
+pub(super) unsafe fn timers(L: *mut LuaState) -> (usize, Option<u64>) {
+    let mut count = 0;
+    let mut next: Option<u64> = None;
+    unsafe {
+        if lua_getfield(L, LUA_REGISTRYINDEX, c"_TIMERS".as_ptr()) == LUA_TTABLE {
+            lua_pushnil(L);
+            while lua_next(L, -2) != 0 {
+                lua_getfield(L, -1, c"due".as_ptr());
+                let at = lua_tointegerx(L, -1, core::ptr::null_mut()).max(0) as u64;
+                lua_pop(L, 2); // due, entry
+                count += 1;
+                next = Some(next.map_or(at, |n| n.min(at)));
+            }
+        }
+        lua_pop(L, 1);
+    }
+    (count, next)
+}
+
+/// Call every subscription of `agent` that matches `change`.
+unsafe fn deliver(agent: &mut ResidentAgent, change: &Change) {
+    let L = agent.L;
+    unsafe {
+        if lua_getfield(L, LUA_REGISTRYINDEX, c"_SUBSCRIPTIONS".as_ptr()) != LUA_TTABLE {
+            lua_pop(L, 1);
+            return;
+        }
+        let n = lua_rawlen(L, -1) as i64;
+        for i in 1..=n {
+            lua_rawgeti(L, -1, i);
+            lua_getfield(L, -1, c"prefix".as_ptr());
+            let matches = lua_to_str(L, -1)
+                .and_then(|p| core::str::from_utf8(p).ok())
+                .is_some_and(|p| has_prefix(&change.path, p));
+            lua_pop(L, 1);
+
+            if matches {
+                lua_getfield(L, -1, c"fn".as_ptr());
+                lua_pushlstring(L, change.path.as_ptr() as *const c_char, change.path.len());
+                let op = ::alloc::format!("{}", change.op);
+                lua_pushlstring(L, op.as_ptr() as *const c_char, op.len());
+                super::set_deadline(L, CALLBACK_TIMEOUT_MS);
+                let mut span = crate::span::begin("lua");
+                if lua_pcall(L, 2, 0, 0) != LUA_OK {
+                    span.fail();
+                    let err = match lua_to_str(L, -1) {
+                        Some(b) => String::from_utf8_lossy(b).into_owned(),
+                        None => String::from("unknown Lua error"),
+                    };
+                    lua_pop(L, 1);
+                    serial_println!(
+                        "[lua] {}: on_change({}) failed: {}{}",
+                        agent.name,
+                        change.path,
+                        err,
+                        crate::span::tag()
+                    );
+                }
+            }
+            lua_pop(L, 1); // subscription entry
+        }
+        lua_pop(L, 1); // _SUBSCRIPTIONS
+    }
+
+    // A callback may have added subscriptions or timers of its own
+    unsafe { refresh(agent) };
+}
+
+/// Read the prefixes out of the registry's _SUBSCRIPTIONS table.
+unsafe fn subscription_prefixes(L: *mut LuaState) -> Vec<String> {
+    let mut prefixes = Vec::new();
+    unsafe {
+        if lua_getfield(L, LUA_REGISTRYINDEX, c"_SUBSCRIPTIONS".as_ptr()) == LUA_TTABLE {
+            let n = lua_rawlen(L, -1) as i64;
+            for i in 1..=n {
+                lua_rawgeti(L, -1, i);
+                lua_getfield(L, -1, c"prefix".as_ptr());
+                if let Some(p) = lua_to_str(L, -1) {
+                    prefixes.push(String::from_utf8_lossy(p).into_owned());
+                }
+                lua_pop(L, 2);
+            }
+        }
+        lua_pop(L, 1);
+    }
+    prefixes
+}
@@ -1,4 +1,5 @@
-//! Lua allocator bridge — delegates to the kernel slab allocator.
+//! Lua allocator bridge — delegates to the kernel slab allocator's Lua
+//! arena (`mem::heap::LUA_HEAP`), kept apart from SQLite's and the kernel's.
 //!
 //! Lua calls `l_alloc(ud, ptr, osize, nsize)` for all memory operations.
 //! This function is passed to `lua_newstate()`.
@@ -37,11 +38,7 @@ pub unsafe extern "C" fn heaven_lua_alloc(
     osize: usize,
     nsize: usize,
 ) -> *mut c_void {
-    extern "C" {
-        fn heavenos_malloc(size: usize) -> *mut u8;
-        fn heavenos_free(ptr: *mut u8);
-        fn heavenos_realloc(ptr: *mut u8, new_size: usize) -> *mut u8;
-    }
+    use crate::mem::heap::LUA_HEAP;
 
     let state = &mut *(ud as *mut LuaAllocState);
 
@@ -49,7 +46,7 @@ pub unsafe extern "C" fn heaven_lua_alloc(
         // Free
         if !ptr.is_null() {
             state.used = state.used.saturating_sub(osize);
-            heavenos_free(ptr as *mut u8);
+            LUA_HEAP.free(ptr as *mut u8);
         }
         core::ptr::null_mut()
     } else if ptr.is_null() {
@@ -57,7 +54,7 @@ pub unsafe extern "C" fn heaven_lua_alloc(
         if state.used + nsize > state.limit {
             return core::ptr::null_mut(); // OOM — Lua will raise memory error
         }
-        let p = heavenos_malloc(nsize);
+        let p = LUA_HEAP.malloc(nsize);
         if !p.is_null() {
             state.used += nsize;
         }
@@ -70,7 +67,7 @@ pub unsafe extern "C" fn heaven_lua_alloc(
                 return core::ptr::null_mut(); // OOM
             }
         }
-        let p = heavenos_realloc(ptr as *mut u8, nsize);
+        let p = LUA_HEAP.realloc(ptr as *mut u8, nsize);
         if !p.is_null() {
             // Update accounting: remove old size, add new size
             state.used = state.used.saturating_sub(osize) + nsize;
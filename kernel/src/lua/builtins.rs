@@ -1,53 +1,105 @@
 //! OSqlite builtin functions exposed to Lua scripts.
 //!
-//! sql(query, ...)    — execute SQL, return table of results
+//! sql(query, ...)    — execute SQL, return table of results; extra args
+//!                      are bound to `?` placeholders by Lua type
 //! read(path)         — read from namespace → string or nil
-//! write(path, data)  — write to namespace → boolean
+//! read_range(path, offset, len) — read part of a namespace file → string
+//! lines(path)        — iterator over the lines of a namespace file
+//! write(path, data)  — write to namespace → boolean (keeps mode and owner)
 //! ls(path)           — list namespace entries → table of strings
 //! log(msg)           — write to serial console
 //! sleep(ms)          — busy-wait using TSC
 //! now()              — monotonic timestamp in ms
 //! audit(level, action, detail) — write to audit table
-//! ask(prompt) or ask(table)   — call Claude API → string
+//! ask(prompt) or ask(table)   — call Claude API → text, stop reason, stop
+//!                               sequence (cached when `cache.ttl` is set —
+//!                               see `sqlite::cache` — a cached answer
+//!                               returns the same three; queued on network
+//!                               failure when `ask.queue` is on — see
+//!                               `sqlite::outbox`)
+//!                               table form: {system=... | prompt=<name>,
+//!                               messages={...}, stream=bool, stop=...},
+//!                               prompt naming a file in /prompts/system/
+//!                               (see `sqlite::prompts`), stream=false
+//!                               asking for one JSON response instead of
+//!                               SSE, stop a string or list of strings that
+//!                               end the answer (not included in it)
+//! ask_queued(prompt | table)   — queue for the outbox → id, mailbox path;
+//!                               batch=true in the table waits for the next
+//!                               Message Batches API batch (half price,
+//!                               answered within 24 hours — `api::batch`)
+//! on_change(prefix, fn)        — call fn(path, op) after writes under prefix
+//! defer(ms, fn)                — call fn() once, ms from now → timer id
+//! every(ms, fn)                — call fn() every ms → timer id
+//! cancel(id)                   — stop a defer/every timer → boolean
+//! diff(a, b [, context])       — unified diff of two strings → string ("" if equal)
+//! embed(text [, path])         — embed text and store it → embeddings row id
+//! semantic_search(query [, k]) — k most similar stored chunks → table of
+//!                                {id, path, text, score} (see `sqlite::vector`)
+//!
+//! Failures return nil (false for write) plus an error table — see
+//! `error.rs` for the codes. Also installs the `util` table (see `util.rs`).
+//!
+//! read, read_range, lines and write honour the path's mode for the
+//! calling agent (the REPL acts as the shell) — see `sqlite::acl`.
+//! Paths are canonicalized first (`sqlite::namespace::canonicalize`):
+//! `//`, `.` and `..` are resolved, and NULs or over-long paths rejected.
+//! Paths under /tmp/ are kept in RAM, not SQLite — see `fs::tmpfs`.
+//! Large files may be stored compressed; the file builtins unpack them,
+//! and sql() reads them as `unpack(content, compressed)` — see
+//! `sqlite::compress`.
+
+#![allow(non_snake_case)] // `L` for the Lua state, as in the C API
 
 use alloc::vec;
 use alloc::vec::Vec;
 use core::ffi::{c_char, c_int};
+use super::error::{fail, fail_with, push_error_value, ErrorCode, LuaError};
 use super::ffi::*;
+use crate::fs::tmpfs;
+use crate::sqlite::acl::{self, Access, Principal};
 use crate::sqlite::SqlValue;
 
 /// Register all OSqlite builtins in a Lua state.
 pub unsafe fn register_builtins(L: *mut LuaState) {
-    lua_register(L, b"sql\0".as_ptr() as _, lua_sql);
-    lua_register(L, b"read\0".as_ptr() as _, lua_read);
-    lua_register(L, b"write\0".as_ptr() as _, lua_write);
-    lua_register(L, b"ls\0".as_ptr() as _, lua_ls);
-    lua_register(L, b"log\0".as_ptr() as _, lua_log);
-    lua_register(L, b"sleep\0".as_ptr() as _, lua_sleep);
-    lua_register(L, b"now\0".as_ptr() as _, lua_now);
-    lua_register(L, b"audit\0".as_ptr() as _, lua_audit);
-    lua_register(L, b"ask\0".as_ptr() as _, lua_ask);
+    super::error::register_errors(L);
+    lua_register(L, c"sql".as_ptr(), lua_sql);
+    lua_register(L, c"read".as_ptr(), lua_read);
+    lua_register(L, c"read_range".as_ptr(), lua_read_range);
+    lua_register(L, c"lines".as_ptr(), lua_lines);
+    lua_register(L, c"write".as_ptr(), lua_write);
+    lua_register(L, c"ls".as_ptr(), lua_ls);
+    lua_register(L, c"log".as_ptr(), lua_log);
+    lua_register(L, c"sleep".as_ptr(), lua_sleep);
+    lua_register(L, c"now".as_ptr(), lua_now);
+    lua_register(L, c"audit".as_ptr(), lua_audit);
+    lua_register(L, c"ask".as_ptr(), lua_ask);
+    lua_register(L, c"ask_queued".as_ptr(), lua_ask_queued);
+    lua_register(L, c"on_change".as_ptr(), lua_on_change);
+    lua_register(L, c"defer".as_ptr(), lua_defer);
+    lua_register(L, c"every".as_ptr(), lua_every);
+    lua_register(L, c"cancel".as_ptr(), lua_cancel);
+    lua_register(L, c"diff".as_ptr(), lua_diff);
+    lua_register(L, c"embed".as_ptr(), lua_embed);
+    lua_register(L, c"semantic_search".as_ptr(), lua_semantic_search);
+    super::util::register_util(L);
 }
 
 // ============================================================
 // sql(query, ...) → table of result rows
+//
+// Extra arguments bind to `?` placeholders in order: integers, floats,
+// strings and nil map to the matching SQLite types, booleans to 1/0.
+//   sql("SELECT * FROM namespace WHERE path = ?", p)
 // ============================================================
 
 unsafe extern "C" fn lua_sql(L: *mut LuaState) -> c_int {
     let query = match lua_to_str(L, 1) {
         Some(b) => match core::str::from_utf8(b) {
             Ok(s) => s,
-            Err(_) => {
-                lua_pushnil(L);
-                lua_pushstring(L, b"invalid UTF-8 in query\0".as_ptr() as _);
-                return 2;
-            }
+            Err(_) => return fail(L, ErrorCode::InvalidArgument, "invalid UTF-8 in query"),
         },
-        None => {
-            lua_pushnil(L);
-            lua_pushstring(L, b"sql() requires a string argument\0".as_ptr() as _);
-            return 2;
-        }
+        None => return fail(L, ErrorCode::InvalidArgument, "sql() requires a string argument"),
     };
 
     // Block dangerous SQL from agents (not REPL).
@@ -59,28 +111,30 @@ unsafe extern "C" fn lua_sql(L: *mut LuaState) -> c_int {
             || starts_with_ignore_case(trimmed, b"EXPLAIN")
             || starts_with_ignore_case(trimmed, b"PRAGMA");
         if !allowed {
-            lua_pushnil(L);
-            lua_pushstring(L, b"sql() is read-only for agents\0".as_ptr() as _);
-            return 2;
+            return fail(L, ErrorCode::ReadOnly, "sql() is read-only for agents");
         }
     }
 
-    // Use the SQLite database — structured query API
-    let guard = crate::sqlite::DB.lock();
-    let db = match guard.as_ref() {
-        Some(db) => db,
-        None => {
-            lua_pushnil(L);
-            lua_pushstring(L, b"database not open\0".as_ptr() as _);
-            return 2;
-        }
+    let params = match collect_bind_params(L, 2) {
+        Ok(p) => p,
+        Err(e) => return fail(L, ErrorCode::InvalidArgument, &e),
+    };
+
+    // Structured query API; SELECTs run on a read-only connection. Agents
+    // read file content through read(), which checks the ACL
+    let outcome = crate::sqlite::with_connection_for(query, restricted, |_, mut stmt| {
+        stmt.bind_all(&params)?;
+        stmt.collect()
+    });
+    let outcome = match outcome {
+        Ok(outcome) => outcome,
+        Err(e) => return fail(L, ErrorCode::Unavailable, &e),
     };
 
-    match db.query(query) {
+    match outcome {
         Ok(result) => {
             if result.columns.is_empty() {
                 // DDL/DML — return true
-                drop(guard);
                 audit_log(L, "SQL_EXEC", query);
                 lua_pushboolean(L, 1);
                 return 1;
@@ -110,17 +164,34 @@ unsafe extern "C" fn lua_sql(L: *mut LuaState) -> c_int {
                 lua_rawseti(L, -2, (row_idx + 1) as i64);
             }
 
-            drop(guard);
             audit_log(L, "SQL_EXEC", query);
             1 // return the result table
         }
-        Err(e) => {
-            drop(guard);
-            lua_pushnil(L);
-            push_rust_string(L, &e);
-            2
-        }
+        Err(e) => fail_with(L, &LuaError::from_sql(&e)),
+    }
+}
+
+/// Convert Lua arguments `first..=top` into SQL bind values.
+unsafe fn collect_bind_params(L: *mut LuaState, first: c_int) -> Result<Vec<SqlValue>, alloc::string::String> {
+    let top = lua_gettop(L);
+    let mut params = Vec::with_capacity((top - first + 1).max(0) as usize);
+    for idx in first..=top {
+        let value = match lua_type(L, idx) {
+            LUA_TNIL => SqlValue::Null,
+            LUA_TBOOLEAN => SqlValue::Integer((lua_toboolean(L, idx) != 0) as i64),
+            LUA_TNUMBER if lua_isinteger(L, idx) != 0 => {
+                SqlValue::Integer(lua_tointegerx(L, idx, core::ptr::null_mut()))
+            }
+            LUA_TNUMBER => SqlValue::Real(lua_tonumberx(L, idx, core::ptr::null_mut())),
+            LUA_TSTRING => match lua_to_str(L, idx).map(core::str::from_utf8) {
+                Some(Ok(s)) => SqlValue::Text(alloc::string::String::from(s)),
+                _ => return Err(alloc::format!("sql(): argument #{} is not valid UTF-8", idx)),
+            },
+            _ => return Err(alloc::format!("sql(): argument #{} cannot be bound", idx)),
+        };
+        params.push(value);
     }
+    Ok(params)
 }
 
 /// Push a SqlValue onto the Lua stack with correct typing.
@@ -143,27 +214,50 @@ unsafe fn push_rust_string(L: *mut LuaState, s: &str) {
     lua_pushstring(L, buf.as_ptr() as *const c_char);
 }
 
+/// Argument `idx` as a canonical namespace path (see
+/// `sqlite::namespace::canonicalize`); `usage` if it is missing.
+unsafe fn path_arg(L: *mut LuaState, idx: c_int, usage: &str) -> Result<alloc::string::String, LuaError> {
+    let bytes = lua_to_str(L, idx).ok_or_else(|| LuaError::new(ErrorCode::InvalidArgument, usage))?;
+    let path = core::str::from_utf8(bytes)
+        .map_err(|_| LuaError::new(ErrorCode::InvalidArgument, "invalid UTF-8 in path"))?;
+    crate::sqlite::namespace::canonicalize(path).map_err(|e| LuaError::new(ErrorCode::InvalidArgument, &e))
+}
+
 // ============================================================
-// read(path) → string or nil
+// read(path) → string, or nil + error
 // ============================================================
 
 unsafe extern "C" fn lua_read(L: *mut LuaState) -> c_int {
-    let path = match lua_to_str(L, 1) {
-        Some(b) => match core::str::from_utf8(b) {
-            Ok(s) => s,
-            Err(_) => { lua_pushnil(L); return 1; }
-        },
-        None => { lua_pushnil(L); return 1; }
+    let path = match path_arg(L, 1, "read() requires a path") {
+        Ok(p) => p,
+        Err(e) => return fail_with(L, &e),
     };
+    let path = path.as_str();
+
+    if tmpfs::is_tmp(path) {
+        return match tmpfs::read(path) {
+            Some(content) => {
+                lua_pushlstring(L, content.as_ptr() as *const c_char, content.len());
+                audit_log(L, "FILE_READ", path);
+                1
+            }
+            None => fail(L, ErrorCode::NotFound, &alloc::format!("no such path: {}", path)),
+        };
+    }
 
     let guard = crate::sqlite::DB.lock();
     let db = match guard.as_ref() {
         Some(db) => db,
-        None => { lua_pushnil(L); return 1; }
+        None => return fail(L, ErrorCode::Unavailable, "database not open"),
     };
 
+    if let Err(e) = check_access(L, db, path, Access::Read) {
+        drop(guard);
+        return fail_with(L, &e);
+    }
+
     let query = alloc::format!(
-        "SELECT content FROM namespace WHERE path='{}'",
+        "SELECT unpack(content, compressed) FROM namespace WHERE path='{}'",
         path.replace('\'', "''")
     );
 
@@ -174,53 +268,298 @@ unsafe extern "C" fn lua_read(L: *mut LuaState) -> c_int {
             audit_log(L, "FILE_READ", path);
             1
         }
-        _ => {
-            lua_pushnil(L);
+        Ok(None) => {
+            drop(guard);
+            fail(L, ErrorCode::NotFound, &alloc::format!("no such path: {}", path))
+        }
+        Err(e) => {
+            drop(guard);
+            fail_with(L, &LuaError::from_sql(&e))
+        }
+    }
+}
+
+// ============================================================
+// read_range(path, offset, len) → string, or nil + error
+//
+// Reads through SQLite's incremental blob I/O, so only the requested
+// bytes reach the Lua heap — except for a compressed file, which is
+// unpacked whole on every call. offset is 0-based; a range past the end
+// is clamped (empty string at EOF). len is capped at READ_RANGE_MAX.
+// ============================================================
+
+/// Largest slice read_range() hands back in one call.
+const READ_RANGE_MAX: usize = 256 * 1024;
+
+/// Chunk size lines() reads while looking for the next newline.
+const LINES_CHUNK: usize = 1024;
+
+/// Longer lines are returned in pieces of this size.
+const LINE_MAX: usize = 64 * 1024;
+
+unsafe extern "C" fn lua_read_range(L: *mut LuaState) -> c_int {
+    let path = match path_arg(L, 1, "read_range() requires (path, offset, len)") {
+        Ok(p) => p,
+        Err(e) => return fail_with(L, &e),
+    };
+    let path = path.as_str();
+    let mut isnum: c_int = 0;
+    let offset = lua_tointegerx(L, 2, &mut isnum);
+    let have_offset = isnum != 0;
+    let len = lua_tointegerx(L, 3, &mut isnum);
+    if !have_offset || isnum == 0 || offset < 0 || len < 0 {
+        return fail(L, ErrorCode::InvalidArgument, "read_range(): offset and len must be non-negative integers");
+    }
+
+    let mut buf = vec![0u8; (len as usize).min(READ_RANGE_MAX)];
+    let result = if tmpfs::is_tmp(path) {
+        Ok(tmpfs::read_at(path, offset as usize, &mut buf))
+    } else {
+        let guard = crate::sqlite::DB.lock();
+        match guard.as_ref() {
+            Some(db) => match check_access(L, db, path, Access::Read).and_then(|()| {
+                open_namespace_content(db, path).map_err(|e| LuaError::from_sql(&e))
+            }) {
+                Ok(Some(Content::Blob(blob))) => {
+                    blob.read_at(offset as usize, &mut buf).map(Some).map_err(|e| LuaError::from_sql(&e))
+                }
+                Ok(Some(Content::Unpacked(data))) => Ok(Some(copy_at(&data, offset as usize, &mut buf))),
+                Ok(None) => Ok(None),
+                Err(e) => Err(e),
+            },
+            None => Err(LuaError::new(ErrorCode::Unavailable, "database not open")),
+        }
+    };
+
+    match result {
+        Ok(Some(n)) => {
+            audit_log(L, "FILE_READ", path);
+            lua_pushlstring(L, buf.as_ptr() as *const c_char, n);
             1
         }
+        Ok(None) => fail(L, ErrorCode::NotFound, &alloc::format!("no such path: {}", path)),
+        Err(e) => fail_with(L, &e),
     }
 }
 
 // ============================================================
-// write(path, data) → boolean
+// lines(path) → iterator
+//
+//   for line in lines("/notes/big.txt") do ... end
+//
+// Each step reads forward from the saved offset until the next newline,
+// so memory use is bounded by the longest line, not the file (a
+// compressed file is unpacked once, when the loop starts). Line
+// terminators ("\n" or "\r\n") are stripped. A missing file yields nothing.
+// ============================================================
+
+unsafe extern "C" fn lua_lines(L: *mut LuaState) -> c_int {
+    let path = match path_arg(L, 1, "lines() requires a path") {
+        Ok(p) => p,
+        Err(e) => return fail_with(L, &e),
+    };
+    let guard = crate::sqlite::DB.lock();
+    let allowed = match guard.as_ref() {
+        _ if tmpfs::is_tmp(&path) => Ok(None),
+        Some(db) => check_access(L, db, &path, Access::Read).map(|()| match open_namespace_content(db, &path) {
+            Ok(Some(Content::Unpacked(data))) => Some(data),
+            _ => None,
+        }),
+        None => Err(LuaError::new(ErrorCode::Unavailable, "database not open")),
+    };
+    drop(guard);
+    let unpacked = match allowed {
+        Ok(unpacked) => unpacked,
+        Err(e) => return fail_with(L, &e),
+    };
+    audit_log(L, "FILE_READ", &path);
+
+    // Upvalues: 1 = path, 2 = next byte offset, 3 = unpacked content of a
+    // compressed file (nil otherwise)
+    lua_pushlstring(L, path.as_ptr() as *const c_char, path.len());
+    lua_pushinteger(L, 0);
+    match unpacked {
+        Some(data) => {
+            lua_pushlstring(L, data.as_ptr() as *const c_char, data.len());
+        }
+        None => lua_pushnil(L),
+    }
+    lua_pushcclosure(L, lines_next, 3);
+    1
+}
+
+unsafe extern "C" fn lines_next(L: *mut LuaState) -> c_int {
+    let path = match lua_to_str(L, lua_upvalueindex(1)).map(core::str::from_utf8) {
+        Some(Ok(s)) => alloc::string::String::from(s),
+        _ => return 0,
+    };
+    let offset = lua_tointegerx(L, lua_upvalueindex(2), core::ptr::null_mut());
+    if offset < 0 {
+        return 0; // exhausted
+    }
+
+    let start = offset as usize;
+    let scanned = if let Some(data) = lua_to_str(L, lua_upvalueindex(3)) {
+        scan_line(start, data.len(), |pos, buf| Some(copy_at(data, pos, buf)))
+    } else if tmpfs::is_tmp(&path) {
+        match tmpfs::len(&path) {
+            Some(len) => scan_line(start, len, |pos, buf| tmpfs::read_at(&path, pos, buf)),
+            None => return 0,
+        }
+    } else {
+        let guard = crate::sqlite::DB.lock();
+        let db = match guard.as_ref() {
+            Some(db) => db,
+            None => return 0,
+        };
+        let blob = match open_namespace_content(db, &path) {
+            Ok(Some(Content::Blob(blob))) => blob,
+            _ => return 0,
+        };
+        scan_line(start, blob.len(), |pos, buf| blob.read_at(pos, buf).ok())
+    };
+    let Some((mut line, next, at_end)) = scanned else { return 0 };
+
+    // Past the last newline with nothing left: done
+    let next = match next {
+        Some(n) => n as i64,
+        None if line.is_empty() && at_end => {
+            lua_pushinteger(L, -1);
+            lua_copy(L, -1, lua_upvalueindex(2));
+            lua_pop(L, 1);
+            return 0;
+        }
+        None if at_end => -1,
+        None => (start + line.len()) as i64, // overlong line: continue mid-line
+    };
+    lua_pushinteger(L, next);
+    lua_copy(L, -1, lua_upvalueindex(2));
+    lua_pop(L, 1);
+
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    lua_pushlstring(L, line.as_ptr() as *const c_char, line.len());
+    1
+}
+
+/// Read forward from `start` through `read_at` to the next newline, at
+/// most `LINE_MAX` bytes. Returns the line without its newline, the offset
+/// past the newline if one was found, and whether the read reached the
+/// end of the `len`-byte file; None on a read error.
+fn scan_line(
+    start: usize,
+    len: usize,
+    mut read_at: impl FnMut(usize, &mut [u8]) -> Option<usize>,
+) -> Option<(Vec<u8>, Option<usize>, bool)> {
+    let mut line = Vec::new();
+    let mut chunk = [0u8; LINES_CHUNK];
+    let mut pos = start;
+    let mut next = None;
+    while line.len() < LINE_MAX {
+        let want = LINES_CHUNK.min(LINE_MAX - line.len());
+        let n = read_at(pos, &mut chunk[..want])?;
+        if n == 0 {
+            break;
+        }
+        if let Some(nl) = chunk[..n].iter().position(|&b| b == b'\n') {
+            line.extend_from_slice(&chunk[..nl]);
+            next = Some(pos + nl + 1);
+            break;
+        }
+        line.extend_from_slice(&chunk[..n]);
+        pos += n;
+    }
+    Some((line, next, pos >= len))
+}
+
+/// A namespace file opened for reading.
+enum Content<'a> {
+    /// Stored as is: read in place.
+    Blob(crate::sqlite::Blob<'a>),
+    /// Stored compressed: the whole content, unpacked.
+    Unpacked(Vec<u8>),
+}
+
+/// Open the `content` of a namespace row for incremental reads.
+/// Ok(None) if the path doesn't exist or has no content.
+fn open_namespace_content<'a>(
+    db: &'a crate::sqlite::SqliteDb,
+    path: &str,
+) -> Result<Option<Content<'a>>, alloc::string::String> {
+    let params = [SqlValue::Text(alloc::string::String::from(path))];
+    let result = db.query_params(
+        "SELECT rowid, compressed FROM namespace WHERE path = ? AND content IS NOT NULL",
+        &params,
+    )?;
+    let Some(row) = result.rows.first() else { return Ok(None) };
+    let Some(rowid) = row.first().and_then(SqlValue::as_integer) else { return Ok(None) };
+    if row.get(1).and_then(SqlValue::as_integer).unwrap_or(0) == 0 {
+        return db.open_blob("namespace", "content", rowid).map(|b| Some(Content::Blob(b)));
+    }
+    let data = db.query_params("SELECT unpack(content, compressed) FROM namespace WHERE path = ?", &params)?;
+    match data.rows.into_iter().next().and_then(|r| r.into_iter().next()) {
+        Some(SqlValue::Text(text)) => Ok(Some(Content::Unpacked(text.into_bytes()))),
+        _ => Ok(None),
+    }
+}
+
+/// Copy up to `buf.len()` bytes of `data` from `offset` (0 at or past
+/// the end).
+fn copy_at(data: &[u8], offset: usize, buf: &mut [u8]) -> usize {
+    let start = offset.min(data.len());
+    let n = buf.len().min(data.len() - start);
+    buf[..n].copy_from_slice(&data[start..start + n]);
+    n
+}
+
+// ============================================================
+// write(path, data) → true, or false + error
 // ============================================================
 
 unsafe extern "C" fn lua_write(L: *mut LuaState) -> c_int {
-    let path = match lua_to_str(L, 1) {
-        Some(b) => match core::str::from_utf8(b) {
-            Ok(s) => alloc::string::String::from(s),
-            Err(_) => { lua_pushboolean(L, 0); return 1; }
-        },
-        None => { lua_pushboolean(L, 0); return 1; }
+    let path = match path_arg(L, 1, "write() requires (path, data)") {
+        Ok(p) => p,
+        Err(e) => return write_failed(L, e.code, &e.message),
     };
 
     let data = match lua_to_str(L, 2) {
         Some(b) => match core::str::from_utf8(b) {
             Ok(s) => alloc::string::String::from(s),
-            Err(_) => { lua_pushboolean(L, 0); return 1; }
+            Err(_) => return write_failed(L, ErrorCode::InvalidArgument, "invalid UTF-8 in data"),
         },
-        None => { lua_pushboolean(L, 0); return 1; }
+        None => return write_failed(L, ErrorCode::InvalidArgument, "write() requires (path, data)"),
     };
 
     let guard = crate::sqlite::DB.lock();
     let db = match guard.as_ref() {
         Some(db) => db,
-        None => { lua_pushboolean(L, 0); return 1; }
+        None => return write_failed(L, ErrorCode::Unavailable, "database not open"),
     };
 
-    // mtime = strftime('%s','now') via SQL expression
-    let query = alloc::format!(
-        "INSERT OR REPLACE INTO namespace (path, type, content, mtime) \
-         VALUES ('{}', 'data', '{}', strftime('%s','now'))",
-        path.replace('\'', "''"),
-        data.replace('\'', "''")
-    );
-
-    let ok = db.exec(&query).is_ok();
+    // Keeps the mode and owner of an existing file; a new one is ours
+    let who = principal(L);
+    let result = acl::write_file(db, &who, &path, "data", &data);
     drop(guard);
     audit_log(L, "FILE_WRITE", &path);
-    lua_pushboolean(L, ok as c_int);
-    1
+    match result {
+        Ok(()) => {
+            lua_pushboolean(L, 1);
+            1
+        }
+        Err(e) => {
+            lua_pushboolean(L, 0);
+            push_error_value(L, &access_error(&e));
+            2
+        }
+    }
+}
+
+/// write() reports failure as `false, err` (it has always returned a boolean).
+unsafe fn write_failed(L: *mut LuaState, code: ErrorCode, message: &str) -> c_int {
+    lua_pushboolean(L, 0);
+    push_error_value(L, &LuaError::new(code, message));
+    2
 }
 
 // ============================================================
@@ -228,13 +567,15 @@ unsafe extern "C" fn lua_write(L: *mut LuaState) -> c_int {
 // ============================================================
 
 unsafe extern "C" fn lua_ls(L: *mut LuaState) -> c_int {
-    let path = match lua_to_str(L, 1) {
-        Some(b) => match core::str::from_utf8(b) {
-            Ok(s) => s,
-            Err(_) => "/",
-        },
-        None => "/",
+    let path = if lua_to_str(L, 1).is_none() {
+        alloc::string::String::from("/")
+    } else {
+        match path_arg(L, 1, "ls() requires a path") {
+            Ok(p) => p,
+            Err(e) => return fail_with(L, &e),
+        }
     };
+    let path = path.as_str();
 
     let guard = crate::sqlite::DB.lock();
     let db = match guard.as_ref() {
@@ -254,13 +595,15 @@ unsafe extern "C" fn lua_ls(L: *mut LuaState) -> c_int {
     };
 
     let query = alloc::format!(
-        "SELECT path FROM namespace WHERE substr(path, 1, {}) = '{}' ORDER BY path",
+        "SELECT path FROM namespace WHERE substr(path, 1, {}) = '{}' COLLATE NOCASE ORDER BY path",
         prefix.len(),
         prefix.replace('\'', "''")
     );
 
     match db.query_column(&query) {
-        Ok(paths) => {
+        Ok(mut paths) => {
+            paths.extend(tmpfs::list(&prefix));
+            paths.sort();
             lua_createtable(L, paths.len() as c_int, 0);
             for (i, p) in paths.iter().enumerate() {
                 lua_pushlstring(L, p.as_ptr() as *const c_char, p.len());
@@ -320,7 +663,7 @@ fn type_name(t: c_int) -> &'static str {
 }
 
 // ============================================================
-// sleep(ms) — busy-wait using TSC
+// sleep(ms) — idle until the TSC reaches the deadline
 // ============================================================
 
 const MAX_SLEEP_MS: i64 = 60_000; // 60 seconds max
@@ -329,7 +672,13 @@ unsafe extern "C" fn lua_sleep(L: *mut LuaState) -> c_int {
     let ms = lua_tointegerx(L, 1, core::ptr::null_mut());
     if ms > 0 {
         let clamped = if ms > MAX_SLEEP_MS { MAX_SLEEP_MS } else { ms };
-        crate::arch::x86_64::timer::delay_us(clamped as u64 * 1000);
+        // Ctrl-C ends a foreground nap; the timeout hook then stops the script
+        let nap = crate::time::Duration::from_millis(clamped as u64);
+        if super::foreground(L) {
+            crate::time::sleep(nap, crate::arch::x86_64::serial::interrupted);
+        } else {
+            crate::time::delay(nap);
+        }
     }
     0
 }
@@ -339,7 +688,7 @@ unsafe extern "C" fn lua_sleep(L: *mut LuaState) -> c_int {
 // ============================================================
 
 unsafe extern "C" fn lua_now(L: *mut LuaState) -> c_int {
-    let ms = crate::arch::x86_64::timer::monotonic_ms();
+    let ms = crate::time::monotonic_ms();
     lua_pushinteger(L, ms as i64);
     1
 }
@@ -367,21 +716,23 @@ unsafe extern "C" fn lua_audit(L: *mut LuaState) -> c_int {
 
     let guard = crate::sqlite::DB.lock();
     if let Some(db) = guard.as_ref() {
-        let query = alloc::format!(
-            "INSERT INTO audit (level, agent, action, detail) VALUES ('{}', '{}', '{}', '{}')",
-            level.replace('\'', "''"),
-            agent.replace('\'', "''"),
-            action.replace('\'', "''"),
-            detail.replace('\'', "''"),
+        let _ = db.query_params(
+            "INSERT INTO audit (level, agent, action, detail, request_id) VALUES (?, ?, ?, ?, ?)",
+            &[
+                SqlValue::Text(alloc::string::String::from(level)),
+                SqlValue::Text(agent),
+                SqlValue::Text(alloc::string::String::from(action)),
+                SqlValue::Text(alloc::string::String::from(detail)),
+                crate::sqlite::audit::request_id(),
+            ],
         );
-        let _ = db.exec(&query);
     }
 
     0
 }
 
 // ============================================================
-// ask(prompt) or ask({system=..., messages={...}}) → string
+// ask(prompt) or ask({system=..., messages={...}}) → text, stop_reason, stop_sequence
 // ============================================================
 
 /// Rate limit: minimum interval between ask() calls (ms).
@@ -389,17 +740,14 @@ const ASK_MIN_INTERVAL_MS: u64 = 10_000;
 static LAST_ASK_MS: spin::Mutex<u64> = spin::Mutex::new(0);
 
 unsafe extern "C" fn lua_ask(L: *mut LuaState) -> c_int {
-    use alloc::string::String;
-    use alloc::vec::Vec;
-
     // Rate limiting
-    let now_ms = crate::arch::x86_64::timer::monotonic_ms();
+    let now_ms = crate::time::monotonic_ms();
     {
         let mut last = LAST_ASK_MS.lock();
         if now_ms - *last < ASK_MIN_INTERVAL_MS {
-            lua_pushnil(L);
-            push_rust_string(L, "ask() rate limited (10s between calls)");
-            return 2;
+            let mut err = LuaError::new(ErrorCode::RateLimited, "ask() rate limited (10s between calls)");
+            err.retry_after = Some((ASK_MIN_INTERVAL_MS - (now_ms - *last)).div_ceil(1000));
+            return fail_with(L, &err);
         }
         *last = now_ms;
     }
@@ -407,41 +755,153 @@ unsafe extern "C" fn lua_ask(L: *mut LuaState) -> c_int {
     // Check API key
     let api_key = match crate::api::get_api_key() {
         Some(k) => k,
-        None => {
-            lua_pushnil(L);
-            push_rust_string(L, "API key not set");
-            return 2;
-        }
+        None => return fail(L, ErrorCode::Auth, "API key not set"),
+    };
+
+    let AskArgs { system, messages, stream, stop_sequences, batch } = match ask_args(L, "ask()") {
+        Ok(args) => args,
+        Err(e) => return fail_with(L, &e),
+    };
+    if batch {
+        return fail(L, ErrorCode::InvalidArgument, "ask() does not take 'batch'; use ask_queued()");
+    }
+
+    // Answer repeated requests from the response cache
+    let model = crate::api::get_model();
+    let cache_ttl = crate::sqlite::cache::ttl();
+    let cache_key = cache_ttl.map(|_| crate::sqlite::cache::key(&model, system.as_deref(), &messages, &stop_sequences));
+    if let Some(hit) = cache_key.as_deref().and_then(crate::sqlite::cache::lookup) {
+        audit_log(L, "API_CACHE_HIT", "ask()");
+        return push_answer(L, &hit.text, &hit.stop_reason, hit.stop_sequence.as_deref());
+    }
+
+    // Build request; the API host is resolved once the stack is taken
+    let mut request = crate::api::ClaudeRequest {
+        config: crate::api::ClaudeConfig {
+            api_key,
+            model: model.clone(),
+            stream: stream.unwrap_or_else(crate::api::stream_default),
+            timeout_secs: crate::api::timeout_default(),
+            abort: super::foreground(L).then_some(crate::arch::x86_64::serial::interrupted as fn() -> bool),
+            ..crate::api::ClaudeConfig::direct_tls(smoltcp::wire::IpAddress::Ipv4(smoltcp::wire::Ipv4Address::UNSPECIFIED))
+        },
+        system,
+        messages,
+        use_tools: false,
+        stop_sequences,
+    };
+
+    // Send request (no streaming to console for Lua — collect full
+    // response). The agentic call, without tools, is the one that reports
+    // why the model stopped. `mockapi` builds answer without a network stack.
+    let result = if crate::api::mock::enabled() {
+        crate::api::mock::respond(&request.messages, &request.stop_sequences, |_| {})
+    } else {
+        // A Styx client served mid-request (`listen::pump_held`) finds
+        // the stack taken: fail rather than wait on it forever
+        let Some(mut net_guard) = crate::net::NET_STACK.try_lock() else {
+            return fail(L, ErrorCode::Busy, "network stack in use");
+        };
+        let net = match net_guard.as_mut() {
+            Some(n) => n,
+            None => return fail(L, ErrorCode::Unavailable, "network stack not initialized"),
+        };
+        request.config.target_ip = match crate::api::resolve_api_host(net) {
+            Ok(ip) => ip,
+            Err(e) => {
+                drop(net_guard);
+                let msg = alloc::format!("DNS resolution failed: {}", e);
+                let error = LuaError::new(ErrorCode::Network, &msg);
+                if !request.stop_sequences.is_empty() {
+                    return fail_with(L, &error);
+                }
+                return queue_or_fail(L, &model, request.system.as_deref(), &request.messages, error);
+            }
+        };
+        crate::api::claude_request_agentic(net, &request, |_| {})
     };
 
-    // Parse arguments: either a string or a table
+    match result {
+        Ok(response) => {
+            audit_log(L, "API_CALL", "ask()");
+            let complete = response.stop_reason != "interrupted";
+            if let (Some(key), Some(ttl), true) = (cache_key.as_deref(), cache_ttl, complete) {
+                let answer = crate::sqlite::cache::Answer {
+                    text: response.text.clone(),
+                    stop_reason: response.stop_reason.clone(),
+                    stop_sequence: response.stop_sequence.clone(),
+                };
+                if let Err(e) = crate::sqlite::cache::store(key, &model, &answer, ttl) {
+                    crate::serial_println!("[lua] response cache: {}", e);
+                }
+            }
+            push_answer(L, &response.text, &response.stop_reason, response.stop_sequence.as_deref())
+        }
+        // A queued request would be answered without its stop sequences
+        Err(e) if e.is_network() && request.stop_sequences.is_empty() => {
+            queue_or_fail(L, &model, request.system.as_deref(), &request.messages, LuaError::from_api(&e))
+        }
+        Err(e) => fail_with(L, &LuaError::from_api(&e)),
+    }
+}
+
+/// Push ask()'s three results: text, stop reason, stop sequence or nil.
+unsafe fn push_answer(L: *mut LuaState, text: &str, stop_reason: &str, stop_sequence: Option<&str>) -> c_int {
+    lua_pushlstring(L, text.as_ptr() as *const c_char, text.len());
+    lua_pushlstring(L, stop_reason.as_ptr() as *const c_char, stop_reason.len());
+    match stop_sequence {
+        Some(seq) => {
+            lua_pushlstring(L, seq.as_ptr() as *const c_char, seq.len());
+        }
+        None => lua_pushnil(L),
+    }
+    3
+}
+
+/// A request as ask()/ask_queued() received it.
+struct AskArgs {
+    system: Option<alloc::string::String>,
+    messages: Vec<crate::api::Message>,
+    /// `stream=` from the table form; None follows `api.stream`.
+    stream: Option<bool>,
+    /// `stop=` from the table form.
+    stop_sequences: Vec<alloc::string::String>,
+    /// `batch=` from the table form.
+    batch: bool,
+}
+
+/// The request in argument 1 of ask()/ask_queued(): a prompt string, or a
+/// table `{system=..., messages={...}, stream=..., stop=..., batch=...}` where
+/// `prompt="<name>"` may stand in for `system` (see `sqlite::prompts`).
+unsafe fn ask_args(L: *mut LuaState, func: &str) -> Result<AskArgs, LuaError> {
+    use alloc::string::String;
+
+    let usage = || LuaError::new(ErrorCode::InvalidArgument, &alloc::format!("{} requires a string or table argument", func));
     let arg_type = lua_type(L, 1);
 
-    let (system, messages) = if arg_type == LUA_TSTRING {
+    if arg_type == LUA_TSTRING {
         // Simple mode: ask("prompt")
         let prompt = match lua_to_str(L, 1) {
             Some(b) => match core::str::from_utf8(b) {
                 Ok(s) => String::from(s),
-                Err(_) => {
-                    lua_pushnil(L);
-                    push_rust_string(L, "invalid UTF-8 in prompt");
-                    return 2;
-                }
+                Err(_) => return Err(LuaError::new(ErrorCode::InvalidArgument, "invalid UTF-8 in prompt")),
             },
-            None => {
-                lua_pushnil(L);
-                push_rust_string(L, "ask() requires a string or table argument");
-                return 2;
-            }
+            None => return Err(usage()),
         };
-        (None, vec![crate::api::Message::text("user", prompt)])
+        Ok(AskArgs {
+            system: None,
+            messages: vec![crate::api::Message::text("user", prompt)],
+            stream: None,
+            stop_sequences: Vec::new(),
+            batch: false,
+        })
     } else if arg_type == LUA_TTABLE {
         // Table mode: ask({system="...", messages={...}})
         let mut system = None;
         let mut messages = Vec::new();
 
         // Get system field
-        lua_getfield(L, 1, b"system\0".as_ptr() as *const c_char);
+        lua_getfield(L, 1, c"system".as_ptr());
         if !lua_isnil(L, -1) {
             if let Some(b) = lua_to_str(L, -1) {
                 if let Ok(s) = core::str::from_utf8(b) {
@@ -451,8 +911,27 @@ unsafe extern "C" fn lua_ask(L: *mut LuaState) -> c_int {
         }
         lua_pop(L, 1);
 
+        // Or a stored prompt by name: prompt="reviewer" → /prompts/system/reviewer
+        lua_getfield(L, 1, c"prompt".as_ptr());
+        let prompt_name = lua_to_str(L, -1).map(|b| String::from_utf8_lossy(b).into_owned());
+        lua_pop(L, 1);
+        if let Some(name) = prompt_name {
+            if system.is_some() {
+                let msg = alloc::format!("{} table takes 'system' or 'prompt', not both", func);
+                return Err(LuaError::new(ErrorCode::InvalidArgument, &msg));
+            }
+            let path = crate::sqlite::prompts::path_of(&name).map_err(|e| LuaError::new(ErrorCode::InvalidArgument, &e))?;
+            let guard = crate::sqlite::DB.lock();
+            let db = guard.as_ref().ok_or_else(|| LuaError::new(ErrorCode::Unavailable, "database not open"))?;
+            check_access(L, db, &path, Access::Read)?;
+            match crate::sqlite::prompts::read(db, &path).map_err(|e| LuaError::from_sql(&e))? {
+                Some(text) => system = Some(text),
+                None => return Err(LuaError::new(ErrorCode::NotFound, &alloc::format!("no such prompt: {}", path))),
+            }
+        }
+
         // Get messages array
-        lua_getfield(L, 1, b"messages\0".as_ptr() as *const c_char);
+        lua_getfield(L, 1, c"messages".as_ptr());
         if lua_type(L, -1) == LUA_TTABLE {
             let msg_table_idx = lua_gettop(L);
             messages = parse_messages_table(L, msg_table_idx);
@@ -460,68 +939,118 @@ unsafe extern "C" fn lua_ask(L: *mut LuaState) -> c_int {
         lua_pop(L, 1); // pop messages
 
         if messages.is_empty() {
-            lua_pushnil(L);
-            push_rust_string(L, "ask() table must contain 'messages' array");
-            return 2;
+            let msg = alloc::format!("{} table must contain 'messages' array", func);
+            return Err(LuaError::new(ErrorCode::InvalidArgument, &msg));
         }
 
-        (system, messages)
+        // Get stream flag
+        lua_getfield(L, 1, c"stream".as_ptr());
+        let stream = if lua_isnil(L, -1) { None } else { Some(lua_toboolean(L, -1) != 0) };
+        lua_pop(L, 1);
+
+        // Get stop sequences: one string or a list of them
+        lua_getfield(L, 1, c"stop".as_ptr());
+        let stop_sequences = stop_arg(L, lua_gettop(L), func);
+        lua_pop(L, 1);
+
+        lua_getfield(L, 1, c"batch".as_ptr());
+        let batch = lua_toboolean(L, -1) != 0;
+        lua_pop(L, 1);
+
+        Ok(AskArgs { system, messages, stream, stop_sequences: stop_sequences?, batch })
     } else {
-        lua_pushnil(L);
-        push_rust_string(L, "ask() requires a string or table argument");
-        return 2;
-    };
+        Err(usage())
+    }
+}
 
-    // Acquire network stack
-    let mut net_guard = crate::net::NET_STACK.lock();
-    let net = match net_guard.as_mut() {
-        Some(n) => n,
-        None => {
-            lua_pushnil(L);
-            push_rust_string(L, "network stack not initialized");
-            return 2;
-        }
-    };
+/// Put a request in the outbox for the calling agent, for a batch if `batch`.
+unsafe fn queue_ask(
+    L: *mut LuaState,
+    model: &str,
+    system: Option<&str>,
+    messages: &[crate::api::Message],
+    batch: bool,
+) -> Result<(i64, alloc::string::String), LuaError> {
+    let agent = get_agent_name(L);
+    let guard = crate::sqlite::DB.lock();
+    let db = guard.as_ref().ok_or_else(|| LuaError::new(ErrorCode::Unavailable, "database not open"))?;
+    let queued = crate::sqlite::outbox::enqueue(db, &agent, model, system, messages, batch).map_err(|e| LuaError::from_sql(&e))?;
+    drop(guard);
+    audit_log(L, "API_QUEUED", &queued.1);
+    Ok(queued)
+}
 
-    // Resolve API target IP
-    let target_ip = match crate::net::dns::resolve_a(net, "api.anthropic.com") {
-        Ok(ip) => ip,
-        Err(e) => {
-            lua_pushnil(L);
-            let msg = alloc::format!("DNS resolution failed: {}", e);
-            push_rust_string(L, &msg);
-            return 2;
+/// Queue the request if `ask.queue` is on; otherwise report `error`.
+unsafe fn queue_or_fail(
+    L: *mut LuaState,
+    model: &str,
+    system: Option<&str>,
+    messages: &[crate::api::Message],
+    error: LuaError,
+) -> c_int {
+    if !crate::sqlite::outbox::queue_on_failure() {
+        return fail_with(L, &error);
+    }
+    match queue_ask(L, model, system, messages, false) {
+        Ok((id, reply_to)) => {
+            // Already queued: a caller retrying would send it twice
+            let msg = alloc::format!("{}; queued as #{}, reply at {}", error.message, id, reply_to);
+            let mut queued = LuaError::new(ErrorCode::Network, &msg);
+            queued.retryable = false;
+            fail_with(L, &queued)
         }
-    };
+        Err(e) => fail_with(L, &e),
+    }
+}
 
-    // Build request
-    let request = crate::api::ClaudeRequest {
-        config: crate::api::ClaudeConfig {
-            api_key,
-            model: crate::api::get_model(),
-            ..crate::api::ClaudeConfig::direct_tls(target_ip)
-        },
-        system,
-        messages,
-        use_tools: false,
+/// The `stop` field at `idx`: nil, a string, or an array of strings,
+/// none of them blank (the API refuses those).
+unsafe fn stop_arg(L: *mut LuaState, idx: c_int, func: &str) -> Result<Vec<alloc::string::String>, LuaError> {
+    let invalid = || {
+        let msg = alloc::format!("{} 'stop' must be a string or list of strings, none blank", func);
+        LuaError::new(ErrorCode::InvalidArgument, &msg)
+    };
+    let string_at = |idx| match lua_type(L, idx) {
+        LUA_TSTRING => lua_to_str(L, idx)
+            .and_then(|b| core::str::from_utf8(b).ok())
+            .filter(|s| !s.trim().is_empty())
+            .map(alloc::string::String::from)
+            .ok_or_else(invalid),
+        _ => Err(invalid()),
     };
+    match lua_type(L, idx) {
+        LUA_TNIL => Ok(Vec::new()),
+        LUA_TTABLE => {
+            let mut stops = Vec::new();
+            for i in 1..=lua_rawlen(L, idx) as i64 {
+                lua_rawgeti(L, idx, i);
+                let stop = string_at(-1);
+                lua_pop(L, 1);
+                stops.push(stop?);
+            }
+            Ok(stops)
+        }
+        _ => Ok(vec![string_at(idx)?]),
+    }
+}
 
-    // Send request (no streaming to console for Lua — collect full response)
-    let result = crate::api::claude_request_multi(net, &request, |_| {});
-    drop(net_guard);
+// ask_queued(prompt) or ask_queued(table) → id, reply_path
 
-    match result {
-        Ok(text) => {
-            audit_log(L, "API_CALL", "ask()");
-            lua_pushlstring(L, text.as_ptr() as *const c_char, text.len());
-            1
-        }
-        Err(e) => {
-            let msg = alloc::format!("{}", e);
-            lua_pushnil(L);
-            push_rust_string(L, &msg);
+unsafe extern "C" fn lua_ask_queued(L: *mut LuaState) -> c_int {
+    let AskArgs { system, messages, stop_sequences, batch, .. } = match ask_args(L, "ask_queued()") {
+        Ok(args) => args,
+        Err(e) => return fail_with(L, &e),
+    };
+    if !stop_sequences.is_empty() {
+        return fail(L, ErrorCode::InvalidArgument, "ask_queued() does not take 'stop'");
+    }
+    match queue_ask(L, &crate::api::get_model(), system.as_deref(), &messages, batch) {
+        Ok((id, reply_to)) => {
+            lua_pushinteger(L, id);
+            lua_pushlstring(L, reply_to.as_ptr() as *const c_char, reply_to.len());
             2
         }
+        Err(e) => fail_with(L, &e),
     }
 }
 
@@ -543,7 +1072,7 @@ unsafe fn parse_messages_table(L: *mut LuaState, table_idx: c_int) -> Vec<crate:
             let mut content = String::new();
 
             // Get role
-            lua_getfield(L, msg_idx, b"role\0".as_ptr() as *const c_char);
+            lua_getfield(L, msg_idx, c"role".as_ptr());
             if let Some(b) = lua_to_str(L, -1) {
                 if let Ok(s) = core::str::from_utf8(b) {
                     role = String::from(s);
@@ -552,7 +1081,7 @@ unsafe fn parse_messages_table(L: *mut LuaState, table_idx: c_int) -> Vec<crate:
             lua_pop(L, 1);
 
             // Get content
-            lua_getfield(L, msg_idx, b"content\0".as_ptr() as *const c_char);
+            lua_getfield(L, msg_idx, c"content".as_ptr());
             if let Some(b) = lua_to_str(L, -1) {
                 if let Ok(s) = core::str::from_utf8(b) {
                     content = String::from(s);
@@ -574,6 +1103,286 @@ unsafe fn parse_messages_table(L: *mut LuaState, table_idx: c_int) -> Vec<crate:
     messages
 }
 
+// ============================================================
+// on_change(prefix, fn) → true
+//
+// Subscriptions live in the registry table _SUBSCRIPTIONS as
+// { prefix = "...", fn = function } entries. They take effect once the
+// script body returns: an agent with subscriptions stays resident and
+// receives fn(path, op) — op is "insert", "update" or "delete" — for every
+// committed namespace change whose path starts with prefix.
+// ============================================================
+
+unsafe extern "C" fn lua_on_change(L: *mut LuaState) -> c_int {
+    lua_getfield(L, LUA_REGISTRYINDEX, c"_CAN_SUBSCRIBE".as_ptr());
+    let allowed = lua_toboolean(L, -1) != 0;
+    lua_pop(L, 1);
+    if !allowed {
+        return fail(L, ErrorCode::InvalidArgument, "on_change() is only available to agents started with run");
+    }
+    if lua_type(L, 1) != LUA_TSTRING || lua_type(L, 2) != LUA_TFUNCTION {
+        return fail(L, ErrorCode::InvalidArgument, "on_change() requires (prefix, function)");
+    }
+
+    if lua_getfield(L, LUA_REGISTRYINDEX, c"_SUBSCRIPTIONS".as_ptr()) != LUA_TTABLE {
+        lua_pop(L, 1);
+        lua_createtable(L, 4, 0);
+        lua_pushvalue(L, -1);
+        lua_setfield(L, LUA_REGISTRYINDEX, c"_SUBSCRIPTIONS".as_ptr());
+    }
+    let n = lua_rawlen(L, -1) as i64;
+
+    lua_createtable(L, 0, 2);
+    lua_pushvalue(L, 1);
+    lua_setfield(L, -2, c"prefix".as_ptr());
+    lua_pushvalue(L, 2);
+    lua_setfield(L, -2, c"fn".as_ptr());
+    lua_rawseti(L, -2, n + 1);
+    lua_pop(L, 1); // _SUBSCRIPTIONS
+
+    lua_pushboolean(L, 1);
+    1
+}
+
+// ============================================================
+// defer(ms, fn) → id, every(ms, fn) → id, cancel(id) → boolean
+//
+// Timers live in the registry table _TIMERS as id → { due = ms, period =
+// ms (0 for defer), fn = function }. Like on_change they keep the agent
+// resident once its body returns; `agents::pump` calls fn() when due, so
+// a periodic agent needs no sleep loop. The pump runs only while the
+// console is idle, so a timer due during a long command fires after it.
+// An agent has at most MAX_TIMERS, and every() fires at most once per
+// MIN_PERIOD_MS.
+// ============================================================
+
+/// Timers one agent may have pending.
+const MAX_TIMERS: usize = 16;
+
+/// Shortest every() period.
+const MIN_PERIOD_MS: i64 = 100;
+
+/// Longest defer()/every() delay (one day).
+const MAX_DELAY_MS: i64 = 86_400_000;
+
+unsafe extern "C" fn lua_defer(L: *mut LuaState) -> c_int {
+    add_timer(L, "defer", false)
+}
+
+unsafe extern "C" fn lua_every(L: *mut LuaState) -> c_int {
+    add_timer(L, "every", true)
+}
+
+unsafe fn add_timer(L: *mut LuaState, name: &str, periodic: bool) -> c_int {
+    lua_getfield(L, LUA_REGISTRYINDEX, c"_CAN_SUBSCRIBE".as_ptr());
+    let allowed = lua_toboolean(L, -1) != 0;
+    lua_pop(L, 1);
+    if !allowed {
+        let msg = alloc::format!("{}() is only available to agents started with run", name);
+        return fail(L, ErrorCode::InvalidArgument, &msg);
+    }
+    if lua_isinteger(L, 1) == 0 || lua_type(L, 2) != LUA_TFUNCTION {
+        return fail(L, ErrorCode::InvalidArgument, &alloc::format!("{}() requires (ms, function)", name));
+    }
+    let ms = lua_tointegerx(L, 1, core::ptr::null_mut());
+    let min = if periodic { MIN_PERIOD_MS } else { 0 };
+    if !(min..=MAX_DELAY_MS).contains(&ms) {
+        let msg = alloc::format!("{}(): ms must be between {} and {}", name, min, MAX_DELAY_MS);
+        return fail(L, ErrorCode::InvalidArgument, &msg);
+    }
+
+    if super::agents::timers(L).0 >= MAX_TIMERS {
+        let msg = alloc::format!("{}(): at most {} timers per agent", name, MAX_TIMERS);
+        return fail(L, ErrorCode::QuotaExceeded, &msg);
+    }
+
+    lua_getfield(L, LUA_REGISTRYINDEX, c"_TIMER_SEQ".as_ptr());
+    let id = lua_tointegerx(L, -1, core::ptr::null_mut()) + 1;
+    lua_pop(L, 1);
+    lua_pushinteger(L, id);
+    lua_setfield(L, LUA_REGISTRYINDEX, c"_TIMER_SEQ".as_ptr());
+
+    if lua_getfield(L, LUA_REGISTRYINDEX, c"_TIMERS".as_ptr()) != LUA_TTABLE {
+        lua_pop(L, 1);
+        lua_createtable(L, 0, 4);
+        lua_pushvalue(L, -1);
+        lua_setfield(L, LUA_REGISTRYINDEX, c"_TIMERS".as_ptr());
+    }
+    let now = crate::time::monotonic_ms() as i64;
+    lua_createtable(L, 0, 3);
+    lua_pushinteger(L, now + ms);
+    lua_setfield(L, -2, c"due".as_ptr());
+    lua_pushinteger(L, if periodic { ms } else { 0 });
+    lua_setfield(L, -2, c"period".as_ptr());
+    lua_pushvalue(L, 2);
+    lua_setfield(L, -2, c"fn".as_ptr());
+    lua_rawseti(L, -2, id);
+    lua_pop(L, 1); // _TIMERS
+
+    lua_pushinteger(L, id);
+    1
+}
+
+unsafe extern "C" fn lua_cancel(L: *mut LuaState) -> c_int {
+    if lua_isinteger(L, 1) == 0 {
+        return fail(L, ErrorCode::InvalidArgument, "cancel() requires a timer id");
+    }
+    let id = lua_tointegerx(L, 1, core::ptr::null_mut());
+    let mut found = false;
+    if lua_getfield(L, LUA_REGISTRYINDEX, c"_TIMERS".as_ptr()) == LUA_TTABLE {
+        found = lua_rawgeti(L, -1, id) == LUA_TTABLE;
+        lua_pop(L, 1);
+        if found {
+            lua_pushnil(L);
+            lua_rawseti(L, -2, id);
+        }
+    }
+    lua_pop(L, 1); // _TIMERS
+    lua_pushboolean(L, found as c_int);
+    1
+}
+
+// ============================================================
+// diff(a, b [, context]) → unified diff string
+//
+// Compares two strings line by line; the result has "--- a"/"+++ b"
+// headers and `context` lines (default 3) around each change. Equal
+// inputs give "".
+// ============================================================
+
+unsafe extern "C" fn lua_diff(L: *mut LuaState) -> c_int {
+    let (a, b) = match (lua_to_str(L, 1).map(core::str::from_utf8), lua_to_str(L, 2).map(core::str::from_utf8)) {
+        (Some(Ok(a)), Some(Ok(b))) => (a, b),
+        _ => return fail(L, ErrorCode::InvalidArgument, "diff() requires (a, b [, context])"),
+    };
+    let context = if lua_type(L, 3) <= LUA_TNIL {
+        crate::diff::DEFAULT_CONTEXT
+    } else {
+        let mut isnum: c_int = 0;
+        let n = lua_tointegerx(L, 3, &mut isnum);
+        if isnum == 0 || n < 0 {
+            return fail(L, ErrorCode::InvalidArgument, "diff(): context must be a non-negative integer");
+        }
+        n as usize
+    };
+
+    let out = crate::diff::unified(a, b, "a", "b", context);
+    lua_pushlstring(L, out.as_ptr() as *const c_char, out.len());
+    1
+}
+
+// ============================================================
+// embed(text [, path]) → id
+// semantic_search(query [, k]) → { {id=, path=, text=, score=}, ... }
+//
+// Both call the provider configured with embed.url / embed.model (see
+// api::embed). embed stores the text, its vector and the optional source
+// path in the embeddings table; semantic_search embeds the query and
+// ranks the chunks stored by the same model by cosine similarity. Hits
+// from paths the caller can't read are left out.
+// ============================================================
+
+/// Most results semantic_search() returns.
+const SEARCH_MAX_K: i64 = 100;
+
+unsafe extern "C" fn lua_embed(L: *mut LuaState) -> c_int {
+    let text = match lua_to_str(L, 1).map(core::str::from_utf8) {
+        Some(Ok(s)) if !s.is_empty() => s,
+        _ => return fail(L, ErrorCode::InvalidArgument, "embed() requires (text [, path])"),
+    };
+    let path = match lua_type(L, 2) {
+        t if t <= LUA_TNIL => None,
+        _ => match lua_to_str(L, 2).map(core::str::from_utf8) {
+            Some(Ok(p)) => Some(p),
+            _ => return fail(L, ErrorCode::InvalidArgument, "embed(): path must be a string"),
+        },
+    };
+
+    let (vector, model) = match embed_text(text) {
+        Ok(v) => v,
+        Err(e) => return fail_with(L, &e),
+    };
+    audit_log(L, "API_CALL", "embed()");
+
+    let guard = crate::sqlite::DB.lock();
+    let result = match guard.as_ref() {
+        Some(db) => crate::sqlite::vector::store(db, path, text, &model, &vector).map_err(|e| LuaError::from_sql(&e)),
+        None => Err(LuaError::new(ErrorCode::Unavailable, "database not open")),
+    };
+    drop(guard);
+
+    match result {
+        Ok(id) => {
+            lua_pushinteger(L, id);
+            1
+        }
+        Err(e) => fail_with(L, &e),
+    }
+}
+
+unsafe extern "C" fn lua_semantic_search(L: *mut LuaState) -> c_int {
+    let query = match lua_to_str(L, 1).map(core::str::from_utf8) {
+        Some(Ok(s)) if !s.is_empty() => s,
+        _ => return fail(L, ErrorCode::InvalidArgument, "semantic_search() requires (query [, k])"),
+    };
+    let k = if lua_type(L, 2) <= LUA_TNIL {
+        5
+    } else {
+        let mut isnum: c_int = 0;
+        let k = lua_tointegerx(L, 2, &mut isnum);
+        if isnum == 0 || !(1..=SEARCH_MAX_K).contains(&k) {
+            return fail(L, ErrorCode::InvalidArgument, "semantic_search(): k must be 1-100");
+        }
+        k
+    };
+
+    let (vector, model) = match embed_text(query) {
+        Ok(v) => v,
+        Err(e) => return fail_with(L, &e),
+    };
+    audit_log(L, "API_CALL", "semantic_search()");
+
+    // Only chunks from files this principal may read, like read() itself
+    let who = principal(L);
+    let hits = match crate::sqlite::with_reader(|db| {
+        crate::sqlite::vector::search(db, &vector, &model, k as usize, |path| acl::check(db, &who, path, Access::Read).is_ok())
+    }) {
+        Ok(Ok(hits)) => hits,
+        Ok(Err(e)) | Err(e) => return fail_with(L, &LuaError::from_sql(&e)),
+    };
+
+    lua_createtable(L, hits.len() as c_int, 0);
+    for (i, hit) in hits.iter().enumerate() {
+        lua_createtable(L, 0, 4);
+        lua_pushinteger(L, hit.id);
+        lua_setfield(L, -2, c"id".as_ptr());
+        if let Some(path) = &hit.path {
+            lua_pushlstring(L, path.as_ptr() as *const c_char, path.len());
+            lua_setfield(L, -2, c"path".as_ptr());
+        }
+        lua_pushlstring(L, hit.text.as_ptr() as *const c_char, hit.text.len());
+        lua_setfield(L, -2, c"text".as_ptr());
+        lua_pushnumber(L, hit.score);
+        lua_setfield(L, -2, c"score".as_ptr());
+        lua_rawseti(L, -2, i as i64 + 1);
+    }
+    1
+}
+
+/// Embed `text` with the configured provider → (vector, model name).
+fn embed_text(text: &str) -> Result<(Vec<f32>, alloc::string::String), LuaError> {
+    let provider = crate::api::embed::Provider::from_config()
+        .map_err(|e| LuaError::new(ErrorCode::Unavailable, &e))?;
+    let mut net_guard = crate::net::NET_STACK
+        .try_lock()
+        .ok_or_else(|| LuaError::new(ErrorCode::Busy, "network stack in use"))?;
+    let net = net_guard
+        .as_mut()
+        .ok_or_else(|| LuaError::new(ErrorCode::Unavailable, "network stack not initialized"))?;
+    let vector = crate::api::embed::embed(net, &provider, text).map_err(|e| LuaError::from_api(&e))?;
+    Ok((vector, provider.model))
+}
+
 // ============================================================
 // Internal helpers
 // ============================================================
@@ -593,7 +1402,7 @@ fn starts_with_ignore_case(haystack: &[u8], needle: &[u8]) -> bool {
 
 /// Check if SQL is restricted to read-only for this Lua state.
 unsafe fn is_sql_restricted(L: *mut LuaState) -> bool {
-    lua_getfield(L, LUA_REGISTRYINDEX, b"_SQL_READONLY\0".as_ptr() as *const c_char);
+    lua_getfield(L, LUA_REGISTRYINDEX, c"_SQL_READONLY".as_ptr());
     let restricted = lua_toboolean(L, -1) != 0;
     lua_pop(L, 1);
     restricted
@@ -602,12 +1411,37 @@ unsafe fn is_sql_restricted(L: *mut LuaState) -> bool {
 /// Mark this Lua state as SQL-restricted (read-only).
 pub unsafe fn set_sql_readonly(L: *mut LuaState, readonly: bool) {
     lua_pushboolean(L, readonly as core::ffi::c_int);
-    lua_setfield(L, LUA_REGISTRYINDEX, b"_SQL_READONLY\0".as_ptr() as *const c_char);
+    lua_setfield(L, LUA_REGISTRYINDEX, c"_SQL_READONLY".as_ptr());
+}
+
+/// The ACL principal this state acts as: the REPL is the shell, anything
+/// else the agent named in the registry.
+unsafe fn principal(L: *mut LuaState) -> Principal {
+    match get_agent_name(L).as_str() {
+        "<repl>" => Principal::Shell,
+        name => Principal::Agent(alloc::string::String::from(name)),
+    }
+}
+
+/// Check `access` to `path` for this state's principal.
+unsafe fn check_access(L: *mut LuaState, db: &crate::sqlite::SqliteDb, path: &str, access: Access) -> Result<(), LuaError> {
+    acl::check(db, &principal(L), path, access).map_err(|e| access_error(&e))
+}
+
+/// Classify an error from `acl`: denial, quota or plain SQL failure.
+fn access_error(message: &str) -> LuaError {
+    if message.starts_with("permission denied") {
+        LuaError::new(ErrorCode::PermissionDenied, message)
+    } else if message.starts_with("quota exceeded") {
+        LuaError::new(ErrorCode::QuotaExceeded, message)
+    } else {
+        LuaError::from_sql(message)
+    }
 }
 
 /// Get the agent name from the Lua registry.
 unsafe fn get_agent_name(L: *mut LuaState) -> alloc::string::String {
-    lua_getfield(L, LUA_REGISTRYINDEX, b"_AGENT_NAME\0".as_ptr() as *const c_char);
+    lua_getfield(L, LUA_REGISTRYINDEX, c"_AGENT_NAME".as_ptr());
     let name = match lua_to_str(L, -1) {
         Some(b) => alloc::string::String::from_utf8_lossy(b).into_owned(),
         None => alloc::string::String::from("unknown"),
@@ -621,12 +1455,14 @@ unsafe fn audit_log(L: *mut LuaState, action: &str, target: &str) {
     let agent = get_agent_name(L);
     let guard = crate::sqlite::DB.lock();
     if let Some(db) = guard.as_ref() {
-        let query = alloc::format!(
-            "INSERT INTO audit (agent, action, target) VALUES ('{}', '{}', '{}')",
-            agent.replace('\'', "''"),
-            action.replace('\'', "''"),
-            target.replace('\'', "''"),
+        let _ = db.query_params(
+            "INSERT INTO audit (agent, action, target, request_id) VALUES (?, ?, ?, ?)",
+            &[
+                SqlValue::Text(agent),
+                SqlValue::Text(alloc::string::String::from(action)),
+                SqlValue::Text(alloc::string::String::from(target)),
+                crate::sqlite::audit::request_id(),
+            ],
         );
-        let _ = db.exec(&query);
     }
 }
@@ -0,0 +1,246 @@
+//! Structured errors returned by the OSqlite builtins.
+//!
+//! On failure a builtin returns `nil` (or `false` for write) followed by an
+//! error table:
+//!
+//!   { code = "not_found", message = "no such path: /x", retryable = false }
+//!
+//! plus `retry_after` (seconds) when the API supplied one. The table has a
+//! `__tostring` metamethod, so `print(err)` and `tostring(err)` give
+//! "code: message".
+//!
+//! Codes:
+//!   invalid_argument  wrong argument type or value
+//!   not_found         the namespace path doesn't exist
+//!   read_only         denied: agents may only run SELECT/EXPLAIN/PRAGMA
+//!   permission_denied the path's mode doesn't allow this agent (see sqlite::acl)
+//!   quota_exceeded    the write would break a subtree quota (see sqlite::quota)
+//!   unavailable       database or network stack not up
+//!   sql_error         SQLite rejected the statement
+//!   busy              database locked; try again          (retryable)
+//!   rate_limited      too many requests                   (retryable)
+//!   auth              API key missing or rejected
+//!   network           DNS, TCP or send failure            (retryable)
+//!   timeout           the peer didn't answer in time      (retryable)
+//!   tls               TLS handshake or pin check failed
+//!   api_error         the API returned an error (retryable for 5xx)
+//!
+//! Compatibility: with `config set lua.errors string`, builtins return the
+//! bare message string instead, as scripts written before this change
+//! expect. The setting is read when a Lua state is created.
+
+#![allow(non_snake_case)] // `L` for the Lua state, as in the C API
+
+use alloc::string::String;
+use core::ffi::{c_char, c_int};
+use core::fmt;
+use super::ffi::*;
+use crate::api::ApiError;
+
+/// Config key selecting the error style ("table" or "string").
+const ERROR_STYLE_KEY: &str = "lua.errors";
+
+/// Machine-readable error category.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    InvalidArgument,
+    NotFound,
+    ReadOnly,
+    PermissionDenied,
+    QuotaExceeded,
+    Unavailable,
+    SqlError,
+    Busy,
+    RateLimited,
+    Auth,
+    Network,
+    Timeout,
+    Tls,
+    ApiError,
+}
+
+impl ErrorCode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCode::InvalidArgument => "invalid_argument",
+            ErrorCode::NotFound => "not_found",
+            ErrorCode::ReadOnly => "read_only",
+            ErrorCode::PermissionDenied => "permission_denied",
+            ErrorCode::QuotaExceeded => "quota_exceeded",
+            ErrorCode::Unavailable => "unavailable",
+            ErrorCode::SqlError => "sql_error",
+            ErrorCode::Busy => "busy",
+            ErrorCode::RateLimited => "rate_limited",
+            ErrorCode::Auth => "auth",
+            ErrorCode::Network => "network",
+            ErrorCode::Timeout => "timeout",
+            ErrorCode::Tls => "tls",
+            ErrorCode::ApiError => "api_error",
+        }
+    }
+
+    /// Whether the same call may succeed if simply repeated later.
+    pub fn retryable(&self) -> bool {
+        matches!(
+            self,
+            ErrorCode::Busy | ErrorCode::RateLimited | ErrorCode::Network | ErrorCode::Timeout
+        )
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A builtin failure, ready to hand to Lua.
+pub struct LuaError {
+    pub code: ErrorCode,
+    pub message: String,
+    pub retryable: bool,
+    pub retry_after: Option<u64>,
+}
+
+impl LuaError {
+    pub fn new(code: ErrorCode, message: &str) -> Self {
+        Self {
+            code,
+            message: String::from(message),
+            retryable: code.retryable(),
+            retry_after: None,
+        }
+    }
+
+    /// Classify a SQLite error message.
+    pub fn from_sql(message: &str) -> Self {
+        let code = if message.contains("locked") || message.contains("busy") {
+            ErrorCode::Busy
+        } else if message.contains("readonly") || message.contains("read-only") {
+            ErrorCode::ReadOnly
+        } else {
+            ErrorCode::SqlError
+        };
+        Self::new(code, message)
+    }
+
+    /// Classify a Claude API failure.
+    pub fn from_api(e: &ApiError) -> Self {
+        let message = alloc::format!("{}", e);
+        match e {
+            ApiError::ConnectionFailed
+            | ApiError::LinkDown
+            | ApiError::SendFailed
+            | ApiError::EmptyResponse
+            | ApiError::DnsError(_) => Self::new(ErrorCode::Network, &message),
+            ApiError::ConnectionTimeout | ApiError::RequestTimeout => Self::new(ErrorCode::Timeout, &message),
+            ApiError::TlsHandshakeFailed => Self::new(ErrorCode::Tls, &message),
+            ApiError::HttpStatus(status, _, retry_after) => {
+                let mut err = match status {
+                    401 | 403 => Self::new(ErrorCode::Auth, &message),
+                    _ => {
+                        let mut err = Self::new(ErrorCode::ApiError, &message);
+                        err.retryable = *status >= 500;
+                        err
+                    }
+                };
+                err.retry_after = *retry_after;
+                err
+            }
+            ApiError::RateLimited(limits) => {
+                let mut err = Self::new(ErrorCode::RateLimited, &message);
+                err.retry_after = limits.retry_after;
+                err
+            }
+            ApiError::ApiError(_) | ApiError::Interrupted => Self::new(ErrorCode::ApiError, &message),
+        }
+    }
+}
+
+/// Create the shared error metatable and record the configured style.
+/// Called once per Lua state, before any builtin runs.
+///
+/// # Safety
+///
+/// `L` must be a valid Lua state with room for two more stack slots. It
+/// takes the database lock to read `lua.errors`, so the caller mustn't
+/// hold it.
+pub unsafe fn register_errors(L: *mut LuaState) {
+    lua_createtable(L, 0, 1);
+    lua_pushcclosure(L, error_tostring, 0);
+    lua_setfield(L, -2, c"__tostring".as_ptr());
+    lua_setfield(L, LUA_REGISTRYINDEX, c"_ERROR_MT".as_ptr());
+
+    let legacy = crate::sqlite::config_get(ERROR_STYLE_KEY).is_some_and(|v| v == "string");
+    lua_pushboolean(L, legacy as c_int);
+    lua_setfield(L, LUA_REGISTRYINDEX, c"_LEGACY_ERRORS".as_ptr());
+}
+
+/// Push the error value (table, or string in compatibility mode).
+///
+/// # Safety
+///
+/// `L` must be a valid Lua state with room for two more stack slots.
+pub unsafe fn push_error_value(L: *mut LuaState, err: &LuaError) {
+    lua_getfield(L, LUA_REGISTRYINDEX, c"_LEGACY_ERRORS".as_ptr());
+    let legacy = lua_toboolean(L, -1) != 0;
+    lua_pop(L, 1);
+
+    if legacy {
+        lua_pushlstring(L, err.message.as_ptr() as *const c_char, err.message.len());
+        return;
+    }
+
+    lua_createtable(L, 0, 4);
+    let code = err.code.as_str();
+    lua_pushlstring(L, code.as_ptr() as *const c_char, code.len());
+    lua_setfield(L, -2, c"code".as_ptr());
+    lua_pushlstring(L, err.message.as_ptr() as *const c_char, err.message.len());
+    lua_setfield(L, -2, c"message".as_ptr());
+    lua_pushboolean(L, err.retryable as c_int);
+    lua_setfield(L, -2, c"retryable".as_ptr());
+    if let Some(secs) = err.retry_after {
+        lua_pushinteger(L, secs as i64);
+        lua_setfield(L, -2, c"retry_after".as_ptr());
+    }
+
+    if lua_getfield(L, LUA_REGISTRYINDEX, c"_ERROR_MT".as_ptr()) == LUA_TTABLE {
+        lua_setmetatable(L, -2);
+    } else {
+        lua_pop(L, 1);
+    }
+}
+
+/// Push `nil, err` and return the result count.
+///
+/// # Safety
+///
+/// As for `push_error_value`, plus one slot for the `nil`.
+pub unsafe fn fail(L: *mut LuaState, code: ErrorCode, message: &str) -> c_int {
+    fail_with(L, &LuaError::new(code, message))
+}
+
+/// Push `nil, err` for a prepared LuaError.
+///
+/// # Safety
+///
+/// As for `push_error_value`, plus one slot for the `nil`.
+pub unsafe fn fail_with(L: *mut LuaState, err: &LuaError) -> c_int {
+    lua_pushnil(L);
+    push_error_value(L, err);
+    2
+}
+
+// __tostring(err) → "code: message"
+unsafe extern "C" fn error_tostring(L: *mut LuaState) -> c_int {
+    lua_getfield(L, 1, c"code".as_ptr());
+    lua_getfield(L, 1, c"message".as_ptr());
+    let code = lua_to_str(L, -2).unwrap_or(b"error");
+    let message = lua_to_str(L, -1).unwrap_or(b"");
+    let mut out = alloc::vec::Vec::with_capacity(code.len() + 2 + message.len());
+    out.extend_from_slice(code);
+    out.extend_from_slice(b": ");
+    out.extend_from_slice(message);
+    lua_pushlstring(L, out.as_ptr() as *const c_char, out.len());
+    1
+}
@@ -0,0 +1,119 @@
+//! Remote Lua evaluation: the `/lua/eval` Styx ctl file.
+//!
+//! Writing Lua to `/lua/eval` runs it in a fresh state and reading the
+//! file returns what it produced, so host-side tooling can script the
+//! kernel over 9P:
+//!
+//!   echo 'sql("SELECT count(*) AS n FROM audit")[1].n' > /n/heaven/lua/eval
+//!   cat /n/heaven/lua/eval
+//!
+//! The text is tried as an expression first (`return <text>`), then as a
+//! chunk, like the REPL. The state gets exactly what an agent gets —
+//! memory limit, read-only `sql()`, path ACLs as agent `/lua/eval`, the
+//! execution timeout — minus `on_change` and timers, and is closed before
+//! the write returns.
+
+#![allow(non_snake_case)] // `L` for the Lua state, as in the C API
+
+use ::alloc::string::String;
+use ::alloc::vec::Vec;
+
+use super::alloc::{heaven_lua_alloc, LuaAllocState, LUA_MEM_LIMIT};
+use super::ffi::*;
+
+/// Agent name the evaluated code runs as (audit rows, ACLs).
+const EVAL_AGENT: &str = "/lua/eval";
+
+/// Ctl handler for `/lua/eval`: the values, tab-separated, or `error: ...`.
+pub fn ctl(cmd: &[u8]) -> Vec<u8> {
+    let code = match core::str::from_utf8(cmd) {
+        Ok(code) => code.trim(),
+        Err(_) => return b"error: invalid UTF-8\n".to_vec(),
+    };
+    match eval(code) {
+        Ok(out) => ::alloc::format!("{}\n", out).into_bytes(),
+        Err(e) => ::alloc::format!("error: {}\n", e).into_bytes(),
+    }
+}
+
+/// Evaluate `code` in a short-lived sandboxed state and return its
+/// results as text.
+pub fn eval(code: &str) -> Result<String, String> {
+    if code.is_empty() {
+        return Ok(String::new());
+    }
+    let mut alloc_state = LuaAllocState::new(LUA_MEM_LIMIT);
+    let ud = &mut alloc_state as *mut LuaAllocState as *mut core::ffi::c_void;
+    unsafe {
+        let L = lua_newstate(heaven_lua_alloc, ud, 0);
+        if L.is_null() {
+            return Err(String::from("failed to create Lua state (out of memory)"));
+        }
+        luaL_openlibs(L);
+        super::builtins::register_builtins(L);
+        super::store_agent_name(L, EVAL_AGENT);
+        super::builtins::set_sql_readonly(L, true);
+        super::install_timeout_hook(L, super::EXEC_TIMEOUT_MS);
+
+        let result = run(L, code);
+        lua_close(L);
+        result
+    }
+}
+
+/// Load `code` as an expression, else as a chunk; run it and format what
+/// it returned.
+unsafe fn run(L: *mut LuaState, code: &str) -> Result<String, String> {
+    unsafe {
+        let expr = ::alloc::format!("return {}", code);
+        if load(L, &expr) != LUA_OK {
+            lua_pop(L, 1);
+            if load(L, code) != LUA_OK {
+                return Err(super::get_lua_error(L));
+            }
+        }
+        if lua_pcall(L, 0, LUA_MULTRET, 0) != LUA_OK {
+            return Err(super::get_lua_error(L));
+        }
+        let n = lua_gettop(L);
+        let out = format_values(L, n);
+        lua_pop(L, n);
+        Ok(out)
+    }
+}
+
+unsafe fn load(L: *mut LuaState, code: &str) -> core::ffi::c_int {
+    unsafe {
+        luaL_loadbufferx(
+            L,
+            code.as_ptr() as *const i8,
+            code.len(),
+            c"=eval".as_ptr(),
+            core::ptr::null(),
+        )
+    }
+}
+
+/// The top `n` stack values, tab-separated, as the REPL shows them.
+pub(super) unsafe fn format_values(L: *mut LuaState, n: core::ffi::c_int) -> String {
+    let mut out = String::new();
+    let top = unsafe { lua_gettop(L) };
+    for i in top - n + 1..=top {
+        if i > top - n + 1 {
+            out.push('\t');
+        }
+        match unsafe { lua_to_str(L, i) } {
+            Some(bytes) => out.push_str(&String::from_utf8_lossy(bytes)),
+            None => {
+                out.push_str(match unsafe { lua_type(L, i) } {
+                    LUA_TNIL => "nil",
+                    LUA_TBOOLEAN if unsafe { lua_toboolean(L, i) } != 0 => "true",
+                    LUA_TBOOLEAN => "false",
+                    LUA_TTABLE => "(table)",
+                    _ => "(value)",
+                });
+            }
+        }
+    }
+    out
+}
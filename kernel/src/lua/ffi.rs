@@ -23,6 +23,8 @@ extern "C" {
     pub fn lua_pushcclosure(L: *mut LuaState, f: LuaCFunction, n: c_int);
     pub fn lua_pushboolean(L: *mut LuaState, b: c_int);
     pub fn lua_pushlightuserdata(L: *mut LuaState, p: *mut c_void);
+    pub fn lua_pushvalue(L: *mut LuaState, idx: c_int);
+    pub fn lua_copy(L: *mut LuaState, fromidx: c_int, toidx: c_int);
 
     // === Getters ===
     pub fn lua_touserdata(L: *mut LuaState, idx: c_int) -> *mut c_void;
@@ -31,13 +33,17 @@ extern "C" {
     pub fn lua_tolstring(L: *mut LuaState, idx: c_int, len: *mut usize) -> *const c_char;
     pub fn lua_toboolean(L: *mut LuaState, idx: c_int) -> c_int;
     pub fn lua_type(L: *mut LuaState, idx: c_int) -> c_int;
+    pub fn lua_isinteger(L: *mut LuaState, idx: c_int) -> c_int;
 
     // === Tables ===
     pub fn lua_createtable(L: *mut LuaState, narr: c_int, nrec: c_int);
     pub fn lua_setfield(L: *mut LuaState, idx: c_int, k: *const c_char);
     pub fn lua_getfield(L: *mut LuaState, idx: c_int, k: *const c_char) -> c_int;
     pub fn lua_rawseti(L: *mut LuaState, idx: c_int, n: i64);
+    pub fn lua_rawgeti(L: *mut LuaState, idx: c_int, n: i64) -> c_int;
+    pub fn lua_rawlen(L: *mut LuaState, idx: c_int) -> u64;
     pub fn lua_next(L: *mut LuaState, idx: c_int) -> c_int;
+    pub fn lua_setmetatable(L: *mut LuaState, objindex: c_int) -> c_int;
 
     // === Globals ===
     pub fn lua_setglobal(L: *mut LuaState, name: *const c_char);
@@ -89,6 +95,7 @@ pub const LUA_TLIGHTUSERDATA: c_int = 2;
 pub const LUA_TNUMBER: c_int = 3;
 pub const LUA_TSTRING: c_int = 4;
 pub const LUA_TTABLE: c_int = 5;
+pub const LUA_TFUNCTION: c_int = 6;
 
 pub const LUA_MULTRET: c_int = -1;
 
@@ -136,6 +143,12 @@ pub unsafe fn lua_isnil(L: *mut LuaState, idx: c_int) -> bool {
     lua_type(L, idx) == LUA_TNIL
 }
 
+/// Pseudo-index of the current C closure's `i`-th upvalue (1-based).
+#[inline]
+pub const fn lua_upvalueindex(i: c_int) -> c_int {
+    LUA_REGISTRYINDEX - i
+}
+
 /// Wrapper matching the old `luaL_openlibs(L)` call.
 #[inline]
 pub unsafe fn luaL_openlibs(L: *mut LuaState) {
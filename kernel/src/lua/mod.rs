@@ -4,16 +4,28 @@
 //! - `run_agent(path)`: load a Lua script from the namespace table and execute it
 //! - `run_string(code, name)`: execute a Lua string directly
 //! - `repl()`: interactive Lua REPL over serial
+//! - `eval::ctl`: the `/lua/eval` Styx file, one-shot sandboxed evaluation
+//! - `autorun()`: run the `boot.autorun` agent at boot
 //!
 //! Each `run_agent` call creates a fresh Lua state, registers the
-//! OSqlite builtins (sql, read, write, ls, log, sleep, now, audit),
-//! executes the script, and tears down the state.
+//! OSqlite builtins (sql, read, write, ls, log, sleep, now, audit) and the
+//! `util` string helper table,
+//! executes the script, and tears down the state — unless the script
+//! subscribed to namespace changes with `on_change` or set a timer with
+//! `defer`/`every`, in which case the state stays resident (see `agents`).
+
+#![allow(non_snake_case)] // `L` for the Lua state, as in the C API
 
 pub mod ffi;
 pub mod alloc;
+pub mod agents;
 pub mod builtins;
+pub mod error;
+pub mod eval;
 pub mod repl;
+pub mod util;
 
+use ::alloc::boxed::Box;
 use ::alloc::string::String;
 use ::alloc::vec::Vec;
 
@@ -26,26 +38,50 @@ const EXEC_TIMEOUT_MS: u64 = 30_000;
 
 /// Run a Lua agent stored in the namespace table.
 ///
-/// 1. SELECT content FROM namespace WHERE path=? AND type='lua'
+/// 1. SELECT unpack(content, compressed) FROM namespace WHERE path=? AND type='lua'
 /// 2. Create Lua state, load libs, register builtins
 /// 3. Execute the script
 /// 4. Close state
 ///
 /// Returns Ok(()) on success, Err(message) on failure.
 pub fn run_agent(path: &str) -> Result<(), String> {
-    // 1. Load script from SQLite namespace table
-    let content = load_script_from_db(path)?;
+    let mut span = crate::span::begin("lua");
+    // Load the script from the namespace table and run it
+    let result = load_script_from_db(path).and_then(|content| run_string(&content, path));
+    if result.is_err() {
+        span.fail();
+    }
+    result
+}
 
-    // 2. Run it
-    run_string(&content, path)
+/// Run the agent named by config `boot.autorun`, if set, for appliances
+/// that work unattended. Its outcome goes to the events table; a failure
+/// is logged and boot carries on to the shell.
+pub fn autorun() {
+    use crate::sqlite::lifecycle::{self, Kind};
+
+    let Some(path) = crate::sqlite::config_get("boot.autorun") else { return };
+    let path = path.trim();
+    if path.is_empty() {
+        return;
+    }
+    crate::serial_println!("[lua] autorun: {}", path);
+    match run_agent(path) {
+        Ok(()) => lifecycle::record(Kind::Autorun, &::alloc::format!("{}: ok", path)),
+        Err(e) => {
+            crate::serial_println!("[lua] autorun {} failed: {}", path, e);
+            lifecycle::record(Kind::Autorun, &::alloc::format!("{}: {}", path, e));
+        }
+    }
 }
 
 /// Execute a Lua source string.
 pub fn run_string(code: &str, name: &str) -> Result<(), String> {
     unsafe {
         // 1. Create Lua state with our allocator (memory-limited)
-        let mut alloc_state = alloc::LuaAllocState::new(alloc::LUA_MEM_LIMIT);
-        let ud = &mut alloc_state as *mut alloc::LuaAllocState as *mut core::ffi::c_void;
+        // (boxed: a resident agent keeps the state, and this, alive)
+        let mut alloc_state = Box::new(alloc::LuaAllocState::new(alloc::LUA_MEM_LIMIT));
+        let ud = &mut *alloc_state as *mut alloc::LuaAllocState as *mut core::ffi::c_void;
         let L = lua_newstate(alloc::heaven_lua_alloc, ud, 0);
         if L.is_null() {
             return Err(String::from("failed to create Lua state (out of memory)"));
@@ -72,11 +108,27 @@ pub fn run_string(code: &str, name: &str) -> Result<(), String> {
         // 7. Install execution timeout hook (30 second limit for agents)
         install_timeout_hook(L, EXEC_TIMEOUT_MS);
 
-        // 7. Load and execute the script
-        let result = load_and_exec(L, code, name);
+        // Agents may subscribe to namespace changes and set timers
+        lua_pushboolean(L, 1);
+        lua_setfield(L, LUA_REGISTRYINDEX, c"_CAN_SUBSCRIBE".as_ptr());
 
-        // 7. Close state (frees all Lua memory)
-        lua_close(L);
+        // 7. Load and execute the script; only this run answers Ctrl-C
+        set_foreground(L, true);
+        let result = load_and_exec(L, code, name);
+        set_foreground(L, false);
+
+        // 7. Stay resident if subscribed, otherwise close (frees all Lua memory)
+        if result.is_ok() {
+            match agents::adopt(name, L, alloc_state) {
+                Ok(subs) => {
+                    crate::serial_println!("[lua] {} resident ({} subscription(s)/timer(s))", name, subs);
+                    return result;
+                }
+                Err((L, _alloc_state)) => lua_close(L),
+            }
+        } else {
+            lua_close(L);
+        }
 
         result
     }
@@ -91,7 +143,7 @@ fn load_script_from_db(path: &str) -> Result<String, String> {
 
     // Build the query with the path escaped
     let query = ::alloc::format!(
-        "SELECT content FROM namespace WHERE path='{}' AND type='lua'",
+        "SELECT unpack(content, compressed) FROM namespace WHERE path='{}' AND type='lua'",
         path.replace('\'', "''")
     );
 
@@ -108,7 +160,7 @@ unsafe fn store_agent_name(L: *mut LuaState, name: &str) {
     buf.extend_from_slice(name.as_bytes());
     buf.push(0);
     lua_pushlstring(L, buf.as_ptr() as *const i8, name.len());
-    lua_setfield(L, LUA_REGISTRYINDEX, b"_AGENT_NAME\0".as_ptr() as *const i8);
+    lua_setfield(L, LUA_REGISTRYINDEX, c"_AGENT_NAME".as_ptr());
 }
 
 /// Load a Lua chunk from a string and execute it with pcall.
@@ -159,34 +211,54 @@ unsafe fn get_lua_error(L: *mut LuaState) -> String {
 
 /// Install a Lua debug hook that aborts execution after a timeout.
 ///
-/// The hook fires every 10000 instructions and checks elapsed time via TSC.
-/// The deadline (in TSC ticks) is stored in the Lua registry as a light userdata.
+/// The hook fires every 10000 instructions and checks the deadline,
+/// and, in the foreground, whether Ctrl-C is pending (see
+/// `serial::interrupted`).
+/// The deadline (as a TSC value) is stored in the Lua registry as an integer.
 unsafe fn install_timeout_hook(L: *mut LuaState, timeout_ms: u64) {
-    let per_ms = crate::arch::x86_64::timer::tsc_per_ms();
-    let start = crate::arch::x86_64::cpu::rdtsc();
-    let deadline = if per_ms > 0 {
-        start.saturating_add(timeout_ms.saturating_mul(per_ms))
-    } else {
-        u64::MAX // no TSC calibration — no timeout
-    };
-
-    // Store deadline in registry as light userdata (pointer-sized integer)
-    lua_pushinteger(L, deadline as i64);
-    lua_setfield(L, LUA_REGISTRYINDEX, b"_DEADLINE\0".as_ptr() as *const i8);
+    set_deadline(L, timeout_ms);
 
     // Install count hook: fires every 10000 VM instructions
     lua_sethook(L, Some(timeout_hook), LUA_MASKCOUNT, 10000);
 }
 
-/// Lua debug hook callback — checks if execution has exceeded deadline.
+/// (Re)start the timeout clock: the hook fires `timeout_ms` from now.
+unsafe fn set_deadline(L: *mut LuaState, timeout_ms: u64) {
+    let deadline = crate::time::Deadline::after_ms(timeout_ms);
+
+    // Store deadline in registry as its TSC value
+    lua_pushinteger(L, deadline.at().tsc() as i64);
+    lua_setfield(L, LUA_REGISTRYINDEX, c"_DEADLINE".as_ptr());
+}
+
+/// Mark whether the state runs a script the console is waiting for. A
+/// Ctrl-C stops only that: resident agents' callbacks and `/lua/eval`
+/// run from the idle prompt, where a Ctrl-C cancels the line instead.
+unsafe fn set_foreground(L: *mut LuaState, foreground: bool) {
+    lua_pushboolean(L, foreground as c_int);
+    lua_setfield(L, LUA_REGISTRYINDEX, c"_FOREGROUND".as_ptr());
+}
+
+/// Does the state run in the foreground (see `set_foreground`)?
+pub(crate) unsafe fn foreground(L: *mut LuaState) -> bool {
+    lua_getfield(L, LUA_REGISTRYINDEX, c"_FOREGROUND".as_ptr());
+    let foreground = lua_toboolean(L, -1) != 0;
+    lua_pop(L, 1);
+    foreground
+}
+
+/// Lua debug hook callback — checks if execution has exceeded deadline,
+/// or, in the foreground, Ctrl-C has been typed.
 unsafe extern "C" fn timeout_hook(L: *mut LuaState, _ar: *mut c_void) {
-    lua_getfield(L, LUA_REGISTRYINDEX, b"_DEADLINE\0".as_ptr() as *const i8);
-    let deadline = lua_tointegerx(L, -1, core::ptr::null_mut()) as u64;
+    lua_getfield(L, LUA_REGISTRYINDEX, c"_DEADLINE".as_ptr());
+    let deadline = crate::time::Instant::from_tsc(lua_tointegerx(L, -1, core::ptr::null_mut()) as u64);
     lua_pop(L, 1);
 
-    let now = crate::arch::x86_64::cpu::rdtsc();
-    if now >= deadline {
-        luaL_error(L, b"execution timeout exceeded\0".as_ptr() as *const i8);
+    if crate::time::Instant::now() >= deadline {
+        luaL_error(L, c"execution timeout exceeded".as_ptr());
+    }
+    if foreground(L) && crate::arch::x86_64::serial::interrupted() {
+        luaL_error(L, c"interrupted".as_ptr());
     }
 }
 
@@ -2,12 +2,19 @@
 //!
 //! Creates a persistent Lua state and reads lines from the serial port.
 //! ^D (Ctrl-D) or `exit()` returns to the HeavenOS shell.
+//!
+//! `:watch <expr>` adds a watch expression, evaluated and printed after
+//! every line; `:watch` lists them and `:unwatch` clears them.
+
+#![allow(non_snake_case)] // `L` for the Lua state, as in the C API
 
 use crate::{serial_print, serial_println};
 use crate::shell::line::LineEditor;
 use super::ffi::*;
 use super::alloc::heaven_lua_alloc;
 use super::builtins::register_builtins;
+use ::alloc::string::String;
+use ::alloc::vec::Vec;
 use core::ffi::c_int;
 
 /// Run the interactive Lua REPL. Returns when the user types ^D.
@@ -33,13 +40,14 @@ pub fn run() {
         register_builtins(L);
 
         // Store agent name for audit
-        lua_pushlstring(L, b"<repl>\0".as_ptr() as *const i8, 6);
-        lua_setfield(L, LUA_REGISTRYINDEX, b"_AGENT_NAME\0".as_ptr() as *const i8);
+        lua_pushlstring(L, c"<repl>".as_ptr(), 6);
+        lua_setfield(L, LUA_REGISTRYINDEX, c"_AGENT_NAME".as_ptr());
 
         // Register exit() function
-        lua_register(L, b"exit\0".as_ptr() as _, lua_exit);
+        lua_register(L, c"exit".as_ptr(), lua_exit);
 
         let mut editor = LineEditor::new();
+        let mut watches: Vec<String> = Vec::new();
 
         loop {
             serial_print!("> ");
@@ -49,6 +57,11 @@ pub fn run() {
                     if trimmed.is_empty() {
                         continue;
                     }
+                    // (`::name::` is a Lua label, not a command)
+                    if let Some(cmd) = trimmed.strip_prefix(':').filter(|c| !c.starts_with(':')) {
+                        watch_command(&mut watches, cmd);
+                        continue;
+                    }
 
                     // Try as expression first (prepend "return ")
                     let expr_code = ::alloc::format!("return {}", trimmed);
@@ -56,7 +69,7 @@ pub fn run() {
                         L,
                         expr_code.as_ptr() as *const i8,
                         expr_code.len(),
-                        b"=stdin\0".as_ptr() as *const i8,
+                        c"=stdin".as_ptr(),
                         core::ptr::null(),
                     );
 
@@ -85,7 +98,7 @@ pub fn run() {
                             L,
                             trimmed.as_ptr() as *const i8,
                             trimmed.len(),
-                            b"=stdin\0".as_ptr() as *const i8,
+                            c"=stdin".as_ptr(),
                             core::ptr::null(),
                         );
 
@@ -102,6 +115,7 @@ pub fn run() {
                             print_error(L);
                         }
                     }
+                    show_watches(L, &watches);
                 }
                 None => {
                     // ^C or ^D — exit REPL
@@ -116,31 +130,45 @@ pub fn run() {
 
 /// Print values on the Lua stack (for REPL expression results).
 unsafe fn print_stack_values(L: *mut LuaState, n: c_int) {
-    for i in 1..=n {
-        if i > 1 {
-            serial_print!("\t");
-        }
-        match lua_to_str(L, i) {
-            Some(bytes) => {
-                if let Ok(s) = core::str::from_utf8(bytes) {
-                    serial_print!("{}", s);
-                }
-            }
-            None => {
-                let t = lua_type(L, i);
-                match t {
-                    LUA_TNIL => serial_print!("nil"),
-                    LUA_TBOOLEAN => {
-                        let b = lua_toboolean(L, i);
-                        serial_print!("{}", if b != 0 { "true" } else { "false" });
-                    }
-                    LUA_TTABLE => serial_print!("(table)"),
-                    _ => serial_print!("(value)"),
-                }
+    serial_println!("{}", super::eval::format_values(L, n));
+}
+
+/// `:watch [expr]` and `:unwatch`.
+fn watch_command(watches: &mut Vec<String>, cmd: &str) {
+    let (name, arg) = cmd.split_once(' ').unwrap_or((cmd, ""));
+    match (name, arg.trim()) {
+        ("watch", "") if watches.is_empty() => serial_println!("(no watches)"),
+        ("watch", "") => {
+            for (i, w) in watches.iter().enumerate() {
+                serial_println!("{}: {}", i + 1, w);
             }
         }
+        ("watch", expr) => watches.push(String::from(expr)),
+        ("unwatch", "") => watches.clear(),
+        _ => serial_println!("usage: :watch [expr] | :unwatch"),
+    }
+}
+
+/// Evaluate and print every watch expression.
+unsafe fn show_watches(L: *mut LuaState, watches: &[String]) {
+    for w in watches {
+        let code = ::alloc::format!("return {}", w);
+        let rc = luaL_loadbufferx(
+            L,
+            code.as_ptr() as *const i8,
+            code.len(),
+            c"=watch".as_ptr(),
+            core::ptr::null(),
+        );
+        if rc != LUA_OK || lua_pcall(L, 0, LUA_MULTRET, 0) != LUA_OK {
+            serial_print!("[watch] {} = ", w);
+            print_error(L);
+            continue;
+        }
+        let n = lua_gettop(L);
+        serial_println!("[watch] {} = {}", w, super::eval::format_values(L, n));
+        lua_pop(L, n);
     }
-    serial_println!();
 }
 
 /// Print a Lua error from the top of the stack.
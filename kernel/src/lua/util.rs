@@ -0,0 +1,325 @@
+//! The preloaded `util` table: string helpers implemented in Rust.
+//!
+//! util.split(s [, sep])        — split on a plain separator → table
+//!                                (no sep: split on runs of whitespace)
+//! util.trim(s)                 — strip leading/trailing whitespace
+//! util.starts_with(s, prefix)  — boolean
+//! util.ends_with(s, suffix)    — boolean
+//! util.base64_encode(s)        — standard alphabet, padded
+//! util.base64_decode(s)        — string, or nil + error
+//! util.hex_encode(s)           — lowercase hex
+//! util.hex_decode(s)           — string, or nil + error
+//! util.template(s, vars)       — replace `${name}` with tostring(vars.name)
+//!
+//! Everything is byte-oriented, so binary strings round-trip through the
+//! codecs unchanged. A missing or non-string argument, or one the
+//! function can't take (bad base64 or hex, an empty separator), gives
+//! `nil` and an `invalid_argument` error (see `error`), like the other
+//! builtins.
+
+#![allow(non_snake_case)] // `L` for the Lua state, as in the C API
+
+use alloc::format;
+use alloc::vec::Vec;
+use core::ffi::{c_char, c_int, CStr};
+use super::error::{fail, ErrorCode};
+use super::ffi::*;
+
+/// Create the `util` global table in a Lua state.
+///
+/// # Safety
+///
+/// `L` must be a valid Lua state with room for two more stack slots.
+pub unsafe fn register_util(L: *mut LuaState) {
+    let funcs: [(&CStr, LuaCFunction); 9] = [
+        (c"split", util_split),
+        (c"trim", util_trim),
+        (c"starts_with", util_starts_with),
+        (c"ends_with", util_ends_with),
+        (c"base64_encode", util_base64_encode),
+        (c"base64_decode", util_base64_decode),
+        (c"hex_encode", util_hex_encode),
+        (c"hex_decode", util_hex_decode),
+        (c"template", util_template),
+    ];
+
+    lua_createtable(L, 0, funcs.len() as c_int);
+    for (name, f) in funcs {
+        lua_pushcclosure(L, f, 0);
+        lua_setfield(L, -2, name.as_ptr());
+    }
+    lua_setglobal(L, c"util".as_ptr());
+}
+
+// ============================================================
+// Lua bindings
+// ============================================================
+
+/// A required string argument. Anything else pushes `nil, err`
+/// (`invalid_argument`) and gives back the count for the binding to
+/// return — raising a Lua error here would longjmp over Rust frames.
+unsafe fn check_bytes<'a>(L: *mut LuaState, idx: c_int, func: &str) -> Result<&'a [u8], c_int> {
+    lua_to_str(L, idx).ok_or_else(|| {
+        let message = format!("util.{}: argument #{} must be a string", func, idx);
+        fail(L, ErrorCode::InvalidArgument, &message)
+    })
+}
+
+/// `check_bytes`, returning from the calling binding on a bad argument.
+macro_rules! arg {
+    ($L:expr, $idx:expr, $func:expr) => {
+        match check_bytes($L, $idx, $func) {
+            Ok(bytes) => bytes,
+            Err(results) => return results,
+        }
+    };
+}
+
+unsafe fn push_bytes(L: *mut LuaState, b: &[u8]) {
+    lua_pushlstring(L, b.as_ptr() as *const c_char, b.len());
+}
+
+// util.split(s [, sep]) → table
+unsafe extern "C" fn util_split(L: *mut LuaState) -> c_int {
+    let s = arg!(L, 1, "split");
+    let parts = if lua_isnil(L, 2) {
+        split_whitespace(s)
+    } else {
+        let sep = arg!(L, 2, "split");
+        if sep.is_empty() {
+            return fail(L, ErrorCode::InvalidArgument, "util.split: empty separator");
+        }
+        split(s, sep)
+    };
+
+    lua_createtable(L, parts.len() as c_int, 0);
+    for (i, part) in parts.iter().enumerate() {
+        push_bytes(L, part);
+        lua_rawseti(L, -2, (i + 1) as i64);
+    }
+    1
+}
+
+// util.trim(s) → string
+unsafe extern "C" fn util_trim(L: *mut LuaState) -> c_int {
+    let s = arg!(L, 1, "trim");
+    push_bytes(L, s.trim_ascii());
+    1
+}
+
+// util.starts_with(s, prefix) → boolean
+unsafe extern "C" fn util_starts_with(L: *mut LuaState) -> c_int {
+    let s = arg!(L, 1, "starts_with");
+    let prefix = arg!(L, 2, "starts_with");
+    lua_pushboolean(L, s.starts_with(prefix) as c_int);
+    1
+}
+
+// util.ends_with(s, suffix) → boolean
+unsafe extern "C" fn util_ends_with(L: *mut LuaState) -> c_int {
+    let s = arg!(L, 1, "ends_with");
+    let suffix = arg!(L, 2, "ends_with");
+    lua_pushboolean(L, s.ends_with(suffix) as c_int);
+    1
+}
+
+// util.base64_encode(s) → string
+unsafe extern "C" fn util_base64_encode(L: *mut LuaState) -> c_int {
+    let s = arg!(L, 1, "base64_encode");
+    push_bytes(L, &base64_encode(s));
+    1
+}
+
+// util.base64_decode(s) → string or nil, err
+unsafe extern "C" fn util_base64_decode(L: *mut LuaState) -> c_int {
+    let s = arg!(L, 1, "base64_decode");
+    match base64_decode(s) {
+        Ok(out) => { push_bytes(L, &out); 1 }
+        Err(e) => fail(L, ErrorCode::InvalidArgument, &format!("util.base64_decode: {}", e)),
+    }
+}
+
+// util.hex_encode(s) → string
+unsafe extern "C" fn util_hex_encode(L: *mut LuaState) -> c_int {
+    let s = arg!(L, 1, "hex_encode");
+    push_bytes(L, &hex_encode(s));
+    1
+}
+
+// util.hex_decode(s) → string or nil, err
+unsafe extern "C" fn util_hex_decode(L: *mut LuaState) -> c_int {
+    let s = arg!(L, 1, "hex_decode");
+    match hex_decode(s) {
+        Ok(out) => { push_bytes(L, &out); 1 }
+        Err(e) => fail(L, ErrorCode::InvalidArgument, &format!("util.hex_decode: {}", e)),
+    }
+}
+
+// util.template(s, vars) → string
+//
+// `${name}` is replaced by vars[name] (strings and numbers; booleans become
+// "true"/"false"). Unknown names and non-scalar values leave the placeholder
+// untouched so mistakes are visible in the output.
+unsafe extern "C" fn util_template(L: *mut LuaState) -> c_int {
+    let s = arg!(L, 1, "template");
+    if lua_type(L, 2) != LUA_TTABLE {
+        return fail(L, ErrorCode::InvalidArgument, "util.template: argument #2 must be a table");
+    }
+
+    let out = expand_template(s, |name| {
+        let mut key = Vec::with_capacity(name.len() + 1);
+        key.extend_from_slice(name);
+        key.push(0);
+        let ty = lua_getfield(L, 2, key.as_ptr() as *const c_char);
+        let value = match ty {
+            LUA_TSTRING | LUA_TNUMBER => lua_to_str(L, -1).map(|b| b.to_vec()),
+            LUA_TBOOLEAN => Some(if lua_toboolean(L, -1) != 0 {
+                b"true".to_vec()
+            } else {
+                b"false".to_vec()
+            }),
+            _ => None,
+        };
+        lua_pop(L, 1);
+        value
+    });
+    push_bytes(L, &out);
+    1
+}
+
+// ============================================================
+// Byte-level helpers
+// ============================================================
+
+fn split<'a>(s: &'a [u8], sep: &[u8]) -> Vec<&'a [u8]> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut i = 0;
+    while i + sep.len() <= s.len() {
+        if &s[i..i + sep.len()] == sep {
+            parts.push(&s[start..i]);
+            i += sep.len();
+            start = i;
+        } else {
+            i += 1;
+        }
+    }
+    parts.push(&s[start..]);
+    parts
+}
+
+fn split_whitespace(s: &[u8]) -> Vec<&[u8]> {
+    s.split(|b| b.is_ascii_whitespace())
+        .filter(|part| !part.is_empty())
+        .collect()
+}
+
+const B64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub(crate) fn base64_encode(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let n = (chunk[0] as u32) << 16
+            | (*chunk.get(1).unwrap_or(&0) as u32) << 8
+            | *chunk.get(2).unwrap_or(&0) as u32;
+        out.push(B64[(n >> 18) as usize & 63]);
+        out.push(B64[(n >> 12) as usize & 63]);
+        out.push(if chunk.len() > 1 { B64[(n >> 6) as usize & 63] } else { b'=' });
+        out.push(if chunk.len() > 2 { B64[n as usize & 63] } else { b'=' });
+    }
+    out
+}
+
+fn base64_value(c: u8) -> Option<u32> {
+    match c {
+        b'A'..=b'Z' => Some((c - b'A') as u32),
+        b'a'..=b'z' => Some((c - b'a' + 26) as u32),
+        b'0'..=b'9' => Some((c - b'0' + 52) as u32),
+        b'+' => Some(62),
+        b'/' => Some(63),
+        _ => None,
+    }
+}
+
+/// Decode standard base64. Whitespace is ignored; padding is optional.
+pub(crate) fn base64_decode(text: &[u8]) -> Result<Vec<u8>, &'static str> {
+    let mut out = Vec::with_capacity(text.len() / 4 * 3);
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+    let mut padding = 0;
+
+    for &c in text {
+        if c.is_ascii_whitespace() {
+            continue;
+        }
+        if c == b'=' {
+            padding += 1;
+            continue;
+        }
+        if padding > 0 {
+            return Err("base64: data after padding");
+        }
+        let v = base64_value(c).ok_or("base64: invalid character")?;
+        acc = (acc << 6) | v;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+
+    // A lone trailing sextet can't encode a whole byte
+    if bits >= 6 || padding > 2 {
+        return Err("base64: truncated input");
+    }
+    Ok(out)
+}
+
+pub(crate) fn hex_encode(data: &[u8]) -> Vec<u8> {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+    let mut out = Vec::with_capacity(data.len() * 2);
+    for &b in data {
+        out.push(DIGITS[(b >> 4) as usize]);
+        out.push(DIGITS[(b & 0xf) as usize]);
+    }
+    out
+}
+
+pub(crate) fn hex_decode(text: &[u8]) -> Result<Vec<u8>, &'static str> {
+    if !text.len().is_multiple_of(2) {
+        return Err("hex: odd number of digits");
+    }
+    let (pairs, _) = text.as_chunks::<2>();
+    pairs
+        .iter()
+        .map(|pair| {
+            let hi = (pair[0] as char).to_digit(16).ok_or("hex: invalid digit")?;
+            let lo = (pair[1] as char).to_digit(16).ok_or("hex: invalid digit")?;
+            Ok((hi << 4 | lo) as u8)
+        })
+        .collect()
+}
+
+/// Expand `${name}` placeholders using `lookup`. A `None` lookup or an
+/// unterminated `${` is copied through verbatim.
+fn expand_template(s: &[u8], mut lookup: impl FnMut(&[u8]) -> Option<Vec<u8>>) -> Vec<u8> {
+    let mut out = Vec::with_capacity(s.len());
+    let mut i = 0;
+    while i < s.len() {
+        if s[i] == b'$' && s.get(i + 1) == Some(&b'{') {
+            if let Some(len) = s[i + 2..].iter().position(|&b| b == b'}') {
+                let name = &s[i + 2..i + 2 + len];
+                let end = i + 3 + len;
+                match lookup(name) {
+                    Some(value) => out.extend_from_slice(&value),
+                    None => out.extend_from_slice(&s[i..end]),
+                }
+                i = end;
+                continue;
+            }
+        }
+        out.push(s[i]);
+        i += 1;
+    }
+    out
+}
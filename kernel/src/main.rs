@@ -11,7 +11,7 @@ extern crate alloc;
 use limine::BaseRevision;
 use limine::memory_map::EntryType;
 use limine::request::{
-    HhdmRequest, MemoryMapRequest,
+    HhdmRequest, MemoryMapRequest, RsdpRequest,
     RequestsEndMarker, RequestsStartMarker,
 };
 
@@ -22,6 +22,8 @@ use heavenos_kernel::mem;
 use heavenos_kernel::storage;
 use heavenos_kernel::vfs;
 use heavenos_kernel::serial_println;
+use heavenos_kernel::sqlite::lifecycle;
+use heavenos_kernel::crypto::zeroize::Zeroizing;
 
 use core::panic::PanicInfo;
 
@@ -41,6 +43,10 @@ static HHDM_REQUEST: HhdmRequest = HhdmRequest::new();
 #[link_section = ".requests"]
 static MEMMAP_REQUEST: MemoryMapRequest = MemoryMapRequest::new();
 
+#[used]
+#[link_section = ".requests"]
+static RSDP_REQUEST: RsdpRequest = RsdpRequest::new();
+
 #[used]
 #[link_section = ".requests_start_marker"]
 static _START_MARKER: RequestsStartMarker = RequestsStartMarker::new();
@@ -49,6 +55,9 @@ static _START_MARKER: RequestsStartMarker = RequestsStartMarker::new();
 #[link_section = ".requests_end_marker"]
 static _END_MARKER: RequestsEndMarker = RequestsEndMarker::new();
 
+/// Passphrase attempts before booting without storage.
+const UNLOCK_TRIES: u32 = 3;
+
 /// Kernel entry point — called by Limine after setting up long mode,
 /// page tables (HHDM + kernel higher-half), and a stack.
 #[no_mangle]
@@ -68,6 +77,15 @@ pub extern "C" fn kmain() -> ! {
     mem::set_hhdm_offset(hhdm_offset);
     serial_println!("[boot] HHDM offset: {:#x}", hhdm_offset);
 
+    // 3b. ACPI root pointer (used for power-off)
+    match RSDP_REQUEST.get_response() {
+        Some(rsdp) => {
+            x86_64::acpi::set_rsdp(rsdp.address() as u64);
+            serial_println!("[acpi] RSDP at {:#x}", rsdp.address());
+        }
+        None => serial_println!("[acpi] No RSDP from bootloader"),
+    }
+
     // 4. Initialize GDT, PIC, and IDT (must be done before any exception can fire)
     unsafe { x86_64::gdt::init(); }
     serial_println!("[cpu] GDT loaded");
@@ -75,6 +93,10 @@ pub extern "C" fn kmain() -> ! {
     serial_println!("[cpu] PIC remapped (IRQs masked)");
     unsafe { x86_64::idt::init(); }
     serial_println!("[cpu] IDT loaded (exception handlers active)");
+    match unsafe { x86_64::fpu::init() } {
+        Ok(()) => serial_println!("[cpu] FPU/SSE initialized"),
+        Err(missing) => panic!("CPU lacks {}, required for SQLite and Lua floating point", missing),
+    }
 
     // 5. Initialize physical memory allocator from Limine memory map
     let memmap_response = MEMMAP_REQUEST.get_response()
@@ -161,11 +183,35 @@ fn continue_boot() -> ! {
     serial_println!("[cpu] RDRAND: {}", x86_64::cpu::has_rdrand());
     serial_println!("[cpu] CLFLUSHOPT: {}", x86_64::cpu::has_clflushopt());
     serial_println!("[cpu] Invariant TSC: {}", x86_64::cpu::has_invariant_tsc());
+    serial_println!("[cpu] FPU/SSE: {}", x86_64::fpu::describe());
 
     // 6b. Calibrate TSC using PIT channel 2
     x86_64::timer::calibrate_tsc();
     let freq_mhz = x86_64::timer::tsc_freq_hz() / 1_000_000;
     serial_println!("[timer] TSC frequency: {} MHz", freq_mhz);
+    let idle = x86_64::idle::init();
+    serial_println!("[idle] {} Hz tick, waiting with {}", x86_64::idle::TICK_HZ, idle.as_str());
+    if x86_64::lapic::init() {
+        serial_println!("[time] TSC-deadline timer (x2APIC) for waits");
+    }
+    lifecycle::record(lifecycle::Kind::Boot, concat!("HeavenOS ", env!("CARGO_PKG_VERSION")));
+
+    // 6c. Entropy: RDRAND, else virtio-rng or TSC jitter — or stop here
+    if let Some((bus, device, iobase)) = heavenos_kernel::drivers::virtio::rng::find_virtio_rng() {
+        serial_println!("[pci] Found virtio-rng at bus={} dev={} iobase={:#06x}", bus, device, iobase);
+        match unsafe { heavenos_kernel::drivers::virtio::rng::VirtioRng::new(iobase) } {
+            Ok(dev) => *heavenos_kernel::drivers::virtio::rng::VIRTIO_RNG.lock() = Some(dev),
+            Err(e) => serial_println!("[virtio-rng] Init failed: {}", e),
+        }
+    }
+    match heavenos_kernel::crypto::entropy::init() {
+        Ok(()) => {
+            let sources = heavenos_kernel::crypto::entropy::sources();
+            let names: alloc::vec::Vec<&str> = sources.iter().map(|s| s.as_str()).collect();
+            serial_println!("[entropy] Sources: {} (using {})", names.join(", "), names[0]);
+        }
+        Err(e) => panic!("{}: TLS keys and SQLite randomness would be predictable", e),
+    }
 
     // 7. Scan PCI for NVMe controller
     serial_println!("[pci] Scanning for NVMe controller...");
@@ -204,11 +250,20 @@ fn continue_boot() -> ! {
         Some(info) => {
             serial_println!("[pci] Found virtio-net: device={:#06x} iobase={:#06x}",
                 info.device_id, info.iobase);
-            match unsafe { heavenos_kernel::drivers::virtio::net::VirtioNet::new(info.iobase) } {
+            let mtu = heavenos_kernel::sqlite::config_get("net.mtu")
+                .and_then(|v| v.trim().parse().ok())
+                .unwrap_or(heavenos_kernel::drivers::virtio::net::DEFAULT_MTU);
+            let offload = heavenos_kernel::sqlite::config_get("net.offload").as_deref() != Some("off");
+            match unsafe { heavenos_kernel::drivers::virtio::net::VirtioNet::new(info.iobase, mtu, offload) } {
                 Ok(nic) => {
                     let mac = nic.mac();
                     serial_println!("[virtio-net] MAC: {:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
                         mac[0], mac[1], mac[2], mac[3], mac[4], mac[5]);
+                    let (tx_csum, rx_csum) = nic.checksum_offload();
+                    serial_println!("[virtio-net] MTU {}{}{}{}", nic.mtu(),
+                        if nic.mergeable_rx() { ", mergeable rx buffers" } else { "" },
+                        if tx_csum { ", tx checksum offload" } else { "" },
+                        if rx_csum { ", rx checksum offload" } else { "" });
                     *heavenos_kernel::drivers::virtio::net::VIRTIO_NET.lock() = Some(nic);
                     serial_println!("[virtio-net] Driver ready");
                 }
@@ -225,9 +280,27 @@ fn continue_boot() -> ! {
     // 11. Initialize TCP/IP stack (requires virtio-net)
     if heavenos_kernel::drivers::virtio::net::VIRTIO_NET.lock().is_some() {
         match heavenos_kernel::net::NetStack::new() {
-            Some(stack) => {
-                serial_println!("[net] TCP/IP stack ready (10.0.2.15, gw 10.0.2.2)");
+            Some(mut stack) => {
+                serial_println!("[net] TCP/IP stack ready ({}, {})", stack.ipv4(), stack.ipv6_link_local());
+                if let Some(family) = heavenos_kernel::sqlite::config_get("net.prefer")
+                    .and_then(|v| heavenos_kernel::net::dns::Family::parse(v.trim()))
+                {
+                    heavenos_kernel::net::dns::set_preference(family);
+                }
+                let mdns = heavenos_kernel::net::mdns::start(&mut stack);
                 *heavenos_kernel::net::NET_STACK.lock() = Some(stack);
+                match heavenos_kernel::net::mdns::local_name() {
+                    Some(name) if mdns => serial_println!("[net] mDNS responder for {}", name),
+                    _ => serial_println!("[net] mDNS responder failed to start"),
+                }
+                heavenos_kernel::net::syslog::configure();
+                if let Some(target) = heavenos_kernel::net::syslog::target() {
+                    serial_println!("[net] Forwarding logs to syslog at {}", target);
+                }
+                styx::listen::configure();
+                if let Some(port) = styx::listen::serving() {
+                    serial_println!("[styx] Serving 9P on TCP port {}", port);
+                }
             }
             None => {
                 serial_println!("[net] Failed to create TCP/IP stack");
@@ -235,74 +308,209 @@ fn continue_boot() -> ! {
         }
     }
 
-    // 12. Initialize Styx namespace
-    let root = styx::namespace::build_root();
-    let _server = styx::StyxServer::new(root);
-    serial_println!("[styx] Namespace ready");
+    // 12. Styx namespace: served per connection by styx::listen (above)
+
+    if heavenos_kernel::api::mock::enabled() {
+        serial_println!("[api] mockapi build: answering from {}", heavenos_kernel::api::mock::RULES_PREFIX);
+    }
 
     serial_println!("HeavenOS boot complete.");
 
+    // `config set boot.selftest on`: check the hardware before the shell
+    #[cfg(not(feature = "ktest"))]
+    if heavenos_kernel::sqlite::config_get("boot.selftest").as_deref() == Some("on") {
+        heavenos_kernel::selftest::run();
+    }
+
+    // `config set boot.autorun /agents/x.lua`: start an agent unattended
+    #[cfg(not(feature = "ktest"))]
+    heavenos_kernel::lua::autorun();
+
+    // ktest builds run the in-kernel suite and exit QEMU instead
+    #[cfg(feature = "ktest")]
+    heavenos_kernel::ktest::run();
+
     // Drop into interactive shell over serial console
+    #[cfg(not(feature = "ktest"))]
     heavenos_kernel::shell::run();
 }
 
 /// Initialize the storage subsystem — format or load from disk.
 fn init_storage() {
-    let mut nvme_guard = nvme::NVME.lock();
-    let nvme = match nvme_guard.as_mut() {
-        Some(n) => n,
+    let vfs = match mount_storage() {
+        Some(v) => v,
         None => return,
     };
 
+    // Leak the VFS: SQLite holds on to it for the lifetime of the kernel.
+    // The NVMe lock must be released first — every VFS I/O takes it.
+    let vfs: &'static vfs::HeavenVfs = alloc::boxed::Box::leak(alloc::boxed::Box::new(vfs));
+    match heavenos_kernel::sqlite::init(vfs) {
+        Ok(()) => serial_println!("[sqlite] heaven.db open ({})", vfs.mode()),
+        Err(e) => {
+            serial_println!("[sqlite] Init failed: {}", e);
+            return;
+        }
+    }
+
+    // Honour a persisted `storage.mode ro` on an otherwise healthy disk.
+    if vfs.mode() == storage::MountMode::ReadWrite {
+        let wanted = heavenos_kernel::sqlite::config_get("storage.mode")
+            .and_then(|v| storage::MountMode::parse(&v));
+        if wanted == Some(storage::MountMode::ReadOnly) {
+            match heavenos_kernel::sqlite::set_mount_mode(storage::MountMode::ReadOnly) {
+                Ok(()) => serial_println!("[storage] Mounted read-only (storage.mode=ro)"),
+                Err(e) => serial_println!("[storage] Failed to remount read-only: {}", e),
+            }
+        }
+    }
+
+    // `storage.iocap off` — for a device whose atomic-write claims are false
+    if heavenos_kernel::sqlite::config_get("storage.iocap").as_deref() == Some("off") {
+        heavenos_kernel::sqlite::set_device_caps(false);
+        serial_println!("[storage] IOCAP reporting disabled (storage.iocap=off)");
+    }
+
+    // `storage.readahead N` — blocks prefetched for sequential reads
+    if let Some(n) = heavenos_kernel::sqlite::config_get("storage.readahead").and_then(|v| v.parse().ok()) {
+        heavenos_kernel::sqlite::set_readahead(n);
+    }
+
+    // `storage.sync_warn_ms N` — log xSyncs slower than this
+    if let Some(ms) = heavenos_kernel::sqlite::config_get("storage.sync_warn_ms").and_then(|v| v.parse().ok()) {
+        heavenos_kernel::sqlite::set_sync_warn_ms(ms);
+    }
+
+    // Console baud rate and flow control (`config set serial.*`)
+    heavenos_kernel::shell::apply_serial_config();
+
+    // /tmp lives in RAM: empty at every boot, sized by `tmp.size`
+    heavenos_kernel::fs::tmpfs::configure();
+    serial_println!("[tmpfs] /tmp in RAM, up to {} bytes", heavenos_kernel::fs::tmpfs::limit());
+}
+
+/// Load (or format) the on-disk structures and build the VFS.
+/// Damage found by the consistency check yields a degraded (read-only) mount.
+/// An encrypted disk is unlocked first, with a passphrase typed at the console.
+fn mount_storage() -> Option<vfs::HeavenVfs> {
+    let mut nvme_guard = nvme::NVME.lock();
+    let nvme = nvme_guard.as_mut()?;
+
     let ns = nvme.namespace_info().unwrap().clone();
 
+    // Past the superblocks, an encrypted disk is unreadable without its key
+    let mut key = match storage::block_alloc::crypt_header(nvme) {
+        Ok(header) if header.is_encrypted() => Some(unlock_disk(&header)?),
+        _ => None,
+    };
+
+    // `encrypt on`/`off` cut short by a crash: finish it before reading anything
+    if let (Some(xts), Ok((_, conversion))) = (key.as_ref(), storage::block_alloc::conversion(nvme)) {
+        if let Some(direction) = conversion.direction() {
+            serial_println!("[storage] Finishing interrupted {:?} from block {}", direction, conversion.next);
+            if let Err(e) = storage::crypt::resume(nvme, xts, |_, _| {}) {
+                serial_println!("[storage] Conversion failed: {:?}; continuing without storage", e);
+                return None;
+            }
+            lifecycle::record(lifecycle::Kind::Encrypt, &alloc::format!("resumed {:?}", direction));
+            if direction == storage::crypt::Direction::Decrypt {
+                key = None;
+            }
+        }
+    }
+
+    let dev = &mut storage::crypt::Device::new(nvme, key.as_ref());
+
     // Try to load existing block allocator
-    match storage::BlockAllocator::load(nvme) {
+    let vfs = match storage::BlockAllocator::load(dev) {
         Ok(alloc) => {
             serial_println!("[storage] Loaded existing filesystem: {} free blocks",
                 alloc.free_count());
+            if alloc.superblock_repairs() > 0 {
+                serial_println!("[storage] Repaired {} superblock copies",
+                    alloc.superblock_repairs());
+                lifecycle::record(lifecycle::Kind::Repair,
+                    &alloc::format!("repaired {} superblock copies", alloc.superblock_repairs()));
+            }
 
             let sb_block_size = alloc.block_size();
             let ft_lba = alloc.data_start_lba() - 1; // file table is right before data
 
-            match storage::FileTable::load(nvme, ft_lba, sb_block_size) {
+            match storage::FileTable::load(dev, ft_lba, sb_block_size) {
                 Ok(ft) => {
                     serial_println!("[storage] File table loaded");
-                    let _vfs = vfs::HeavenVfs::new(alloc, ft);
+                    let damage = storage::check(&alloc, &ft);
+                    let mode = if damage.is_empty() {
+                        storage::MountMode::ReadWrite
+                    } else {
+                        for d in &damage {
+                            serial_println!("[storage] Damage: {}", d);
+                            lifecycle::record(lifecycle::Kind::Repair, &alloc::format!("damage: {}", d));
+                        }
+                        serial_println!("[storage] Mounting degraded (read-only)");
+                        storage::MountMode::Degraded
+                    };
                     serial_println!("[vfs] SQLite VFS ready");
+                    Some(vfs::HeavenVfs::new(alloc, ft, mode))
                 }
                 Err(e) => {
                     serial_println!("[storage] Failed to load file table: {}", e);
+                    None
                 }
             }
         }
         Err(_) => {
             // Blank disk — format
             serial_println!("[storage] No filesystem found, formatting...");
-            match storage::BlockAllocator::format(nvme, ns.block_count, ns.block_size) {
+            match storage::BlockAllocator::format(dev, ns.block_count, ns.block_size) {
                 Ok(alloc) => {
                     serial_println!("[storage] Formatted: {} data blocks available",
                         alloc.free_count());
+                    lifecycle::record(lifecycle::Kind::Format,
+                        &alloc::format!("{} blocks of {} bytes", ns.block_count, ns.block_size));
 
                     let sb_block_size = alloc.block_size();
                     let ft_lba = alloc.data_start_lba() - 1;
 
                     let ft = storage::FileTable::new(ft_lba, sb_block_size);
-                    let _vfs = vfs::HeavenVfs::new(alloc, ft);
                     serial_println!("[vfs] SQLite VFS ready (fresh format)");
+                    Some(vfs::HeavenVfs::new(alloc, ft, storage::MountMode::ReadWrite))
                 }
                 Err(e) => {
                     serial_println!("[storage] Format failed: {}", e);
+                    None
                 }
             }
         }
+    }?;
+    vfs.set_key(key);
+    Some(vfs)
+}
+
+/// Ask for an encrypted disk's passphrase, up to `UNLOCK_TRIES` times.
+fn unlock_disk(header: &storage::crypt::CryptHeader) -> Option<storage::crypt::Xts> {
+    serial_println!("[storage] Disk is encrypted ({:?})", header);
+    for _ in 0..UNLOCK_TRIES {
+        let passphrase = heavenos_kernel::shell::read_secret("Disk passphrase: ")?;
+        if let Some(key) = header.unseal(passphrase.as_bytes()) {
+            let key = Zeroizing::new(key);
+            serial_println!("[storage] Disk unlocked");
+            return Some(storage::crypt::Xts::new(&key));
+        }
+        serial_println!("[storage] Wrong passphrase");
     }
+    serial_println!("[storage] Disk stays locked; continuing without storage");
+    None
 }
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     serial_println!("!!! KERNEL PANIC !!!");
     serial_println!("{}", info);
+    lifecycle::record_panic(&alloc::format!("{}", info));
+    #[cfg(feature = "ktest")]
+    heavenos_kernel::ktest::exit_qemu(heavenos_kernel::ktest::QemuExit::Failed);
+    #[cfg(not(feature = "ktest"))]
     loop {
         x86_64::hlt();
     }
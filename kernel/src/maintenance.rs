@@ -0,0 +1,492 @@
+/// Periodic housekeeping, run from the console's idle loop.
+///
+/// There is no preemptive scheduler: `tick()` is called whenever the shell
+/// is waiting for input and runs at most one due task per call, and only
+/// once the console has been quiet for `IDLE_MS` — so a task never lands
+/// in the middle of someone typing. Tasks must still be short: they delay
+/// the next keystroke.
+///
+/// Tasks:
+///   audit-retention     prune the audit table (see sqlite::audit)
+///   quick-check         PRAGMA quick_check
+///   incremental-vacuum  return free pages to the VFS (auto_vacuum=INCREMENTAL)
+///   analyze             refresh query planner statistics
+///   outbox              send the oldest queued ask (see sqlite::outbox)
+///   batches             collect an ended batch of queued asks and submit
+///                       the next (see api::batch)
+///
+/// There is no checkpoint task: SQLite is built with SQLITE_OMIT_WAL
+/// (vendor/sqlite/sqlite_config.h), so every commit goes through the
+/// rollback journal straight into heaven.db and there is never a WAL to
+/// checkpoint. If WAL is ever enabled, a PASSIVE `wal_checkpoint` driven
+/// by the WAL's size belongs in this table.
+///
+/// Database task results are recorded in the audit table (agent
+/// "maintenance"); a task with nothing to report returns an empty string
+/// and leaves no row. `db maintain now` runs everything immediately.
+use alloc::string::String;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use crate::time;
+use crate::serial_println;
+use crate::sqlite::{SqlValue, DB};
+
+/// Console quiet time required before a scheduled task may run.
+const IDLE_MS: u64 = 30_000;
+
+/// Free pages released per incremental-vacuum run.
+const VACUUM_PAGES: u32 = 256;
+
+/// A scheduled maintenance task.
+struct Task {
+    name: &'static str,
+    interval_ms: u64,
+    /// Does it write to the database? Skipped on read-only mounts.
+    writes: bool,
+    run: fn() -> Result<String, String>,
+}
+
+const HOUR_MS: u64 = 60 * 60 * 1000;
+
+static TASKS: [Task; 6] = [
+    Task { name: "audit-retention", interval_ms: 5 * 60 * 1000, writes: true, run: audit_retention_task },
+    Task { name: "quick-check", interval_ms: 6 * HOUR_MS, writes: false, run: quick_check },
+    Task { name: "incremental-vacuum", interval_ms: HOUR_MS, writes: true, run: incremental_vacuum },
+    Task { name: "analyze", interval_ms: 24 * HOUR_MS, writes: true, run: analyze },
+    Task { name: "outbox", interval_ms: 60 * 1000, writes: true, run: deliver_outbox },
+    Task { name: "batches", interval_ms: 10 * 60 * 1000, writes: true, run: deliver_batches },
+];
+
+/// Monotonic time each task last ran (0 = never; first run after one interval).
+static LAST_RUN: [AtomicU64; 6] = [const { AtomicU64::new(0) }; 6];
+
+/// Last keystroke, for the idle guard.
+static LAST_INPUT_MS: AtomicU64 = AtomicU64::new(0);
+
+/// Held while a task runs, so nothing re-enters from the idle loop.
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Record console activity; scheduled tasks wait for `IDLE_MS` of quiet.
+pub fn note_input() {
+    LAST_INPUT_MS.store(time::monotonic_ms(), Ordering::Relaxed);
+}
+
+/// Run the first task that is due, if the console is idle. Cheap otherwise.
+pub fn tick() {
+    let now = time::monotonic_ms();
+    if now.saturating_sub(LAST_INPUT_MS.load(Ordering::Relaxed)) < IDLE_MS {
+        return;
+    }
+    let Some(i) = (0..TASKS.len())
+        .find(|&i| now.saturating_sub(LAST_RUN[i].load(Ordering::Relaxed)) >= TASKS[i].interval_ms)
+    else {
+        return;
+    };
+    if RUNNING.swap(true, Ordering::Acquire) {
+        return;
+    }
+
+    LAST_RUN[i].store(now, Ordering::Relaxed);
+    run_task(&TASKS[i], false);
+
+    RUNNING.store(false, Ordering::Release);
+}
+
+/// Run every task now, printing each result (`db maintain now`).
+pub fn run_all_now() {
+    if RUNNING.swap(true, Ordering::Acquire) {
+        serial_println!("maintenance already running");
+        return;
+    }
+    let now = time::monotonic_ms();
+    for (i, task) in TASKS.iter().enumerate() {
+        LAST_RUN[i].store(now, Ordering::Relaxed);
+        run_task(task, true);
+    }
+    RUNNING.store(false, Ordering::Release);
+}
+
+/// Print when each task last ran (`db maintain`).
+pub fn print_status() {
+    let now = time::monotonic_ms();
+    for (i, task) in TASKS.iter().enumerate() {
+        let last = LAST_RUN[i].load(Ordering::Relaxed);
+        let next_in = (last + task.interval_ms).saturating_sub(now) / 1000;
+        if last == 0 {
+            serial_println!("  {:<20} never run, due in {}s", task.name, next_in);
+        } else {
+            serial_println!(
+                "  {:<20} last {}s ago, due in {}s",
+                task.name, now.saturating_sub(last) / 1000, next_in
+            );
+        }
+    }
+}
+
+fn run_task(task: &Task, verbose: bool) {
+    let writable = crate::sqlite::mount_mode().is_some_and(|m| m.is_writable());
+    if task.writes && !writable {
+        if verbose {
+            serial_println!("[maint] {}: skipped (read-only mount)", task.name);
+        }
+        return;
+    }
+
+    let (level, detail) = match (task.run)() {
+        Ok(detail) => ("INFO", detail),
+        Err(e) => ("WARN", e),
+    };
+    if detail.is_empty() {
+        if verbose {
+            serial_println!("[maint] {}: nothing to do", task.name);
+        }
+        return;
+    }
+    if verbose || level != "INFO" {
+        serial_println!("[maint] {}: {}", task.name, detail);
+    }
+    if writable && task.name != "audit-retention" {
+        log_result(level, task.name, &detail);
+    }
+}
+
+fn log_result(level: &str, task: &str, detail: &str) {
+    let guard = DB.lock();
+    if let Some(db) = guard.as_ref() {
+        let _ = db.query_params(
+            "INSERT INTO audit (level, agent, action, target, detail, request_id) \
+             VALUES (?, 'maintenance', ?, 'heaven.db', ?, ?)",
+            &[
+                SqlValue::Text(String::from(level)),
+                SqlValue::Text(alloc::format!("DB_{}", task.to_ascii_uppercase().replace('-', "_"))),
+                SqlValue::Text(String::from(detail)),
+                crate::sqlite::audit::request_id(),
+            ],
+        );
+    }
+}
+
+/// Prune the audit table now. Returns the number of rows removed.
+pub fn audit_retention() -> u64 {
+    if !crate::sqlite::mount_mode().is_some_and(|m| m.is_writable()) {
+        return 0;
+    }
+    match crate::sqlite::audit::enforce_retention() {
+        Ok(0) => 0,
+        Ok(n) => {
+            serial_println!("[maint] audit: pruned {} row(s)", n);
+            n
+        }
+        Err(e) => {
+            serial_println!("[maint] audit retention failed: {}", e);
+            0
+        }
+    }
+}
+
+fn audit_retention_task() -> Result<String, String> {
+    crate::sqlite::audit::enforce_retention().map(|n| alloc::format!("pruned {} row(s)", n))
+}
+
+/// Longest `deliver_outbox` may spend on one request, retries included:
+/// the console stays busy meanwhile. Past it the request waits for the
+/// next pass.
+const OUTBOX_DEADLINE_SECS: u64 = 120;
+
+/// Earliest `monotonic_ms` the outbox task sends again, once the server
+/// has asked for a pause (Retry-After).
+static OUTBOX_RESUME_MS: AtomicU64 = AtomicU64::new(0);
+
+/// Send the oldest pending outbox request and deliver the reply to its
+/// mailbox. Without a key, network or connectivity the request just waits
+/// (empty result), as it does past `OUTBOX_DEADLINE_SECS`. A server error
+/// or rate limit defers it, and any Retry-After holds back the next send;
+/// a client (4xx) error fails it for good.
+pub fn deliver_outbox() -> Result<String, String> {
+    use crate::api::ApiError;
+    use crate::sqlite::outbox;
+
+    if time::monotonic_ms() < OUTBOX_RESUME_MS.load(Ordering::Relaxed) {
+        return Ok(String::new());
+    }
+    let item = {
+        let guard = DB.lock();
+        let db = guard.as_ref().ok_or_else(|| String::from("database not open"))?;
+        outbox::next_pending(db)?
+    };
+    let Some(item) = item else {
+        return Ok(String::new());
+    };
+    let Some(api_key) = crate::api::get_api_key() else {
+        return Ok(String::new());
+    };
+    // Read before the stack is taken: they need the database
+    let (stream, timeout_secs) = (crate::api::stream_default(), crate::api::timeout_default());
+    let deadline_ms = crate::time::monotonic_ms() + OUTBOX_DEADLINE_SECS * 1000;
+
+    let mut span = crate::span::begin("ask");
+    // `mockapi` builds answer without a network stack
+    let result = if crate::api::mock::enabled() {
+        crate::api::mock::respond(&item.api_messages(), &[], |_| {}).map(|r| r.text)
+    } else {
+        let mut net_guard = crate::net::NET_STACK.lock();
+        let Some(net) = net_guard.as_mut() else {
+            return Ok(String::new());
+        };
+        match crate::api::resolve_api_host(net) {
+            Err(e) => Err(crate::api::ApiError::DnsError(alloc::format!("{}", e))),
+            Ok(ip) => {
+                let request = crate::api::ClaudeRequest {
+                    config: crate::api::ClaudeConfig {
+                        api_key,
+                        model: item.model.clone(),
+                        stream,
+                        timeout_secs,
+                        deadline_ms: Some(deadline_ms),
+                        ..crate::api::ClaudeConfig::direct_tls(ip)
+                    },
+                    system: item.system.clone(),
+                    messages: item.api_messages(),
+                    use_tools: false,
+                    stop_sequences: alloc::vec::Vec::new(),
+                };
+                crate::api::claude_request_multi(net, &request, |_| {})
+            }
+        }
+    };
+
+    let guard = DB.lock();
+    let db = guard.as_ref().ok_or_else(|| String::from("database not open"))?;
+    match result {
+        Ok(text) => {
+            outbox::complete(db, &item, &text)?;
+            Ok(alloc::format!("#{} delivered to {}", item.id, item.reply_to))
+        }
+        Err(e) if e.is_network() => {
+            span.fail();
+            outbox::defer(db, item.id, &alloc::format!("{}", e))?;
+            Ok(String::new())
+        }
+        Err(e) => {
+            span.fail();
+            let message = alloc::format!("{}", e);
+            // Overloaded or rate limited: the request is fine, so it waits
+            let retry_after = match &e {
+                ApiError::RateLimited(limits) => Some(limits.retry_after),
+                ApiError::HttpStatus(status, _, after) if !(400..500).contains(status) => Some(*after),
+                _ => None,
+            };
+            if let Some(after) = retry_after {
+                if let Some(secs) = after {
+                    OUTBOX_RESUME_MS.store(time::monotonic_ms() + secs * 1000, Ordering::Relaxed);
+                }
+                outbox::defer(db, item.id, &message)?;
+                return Ok(alloc::format!("#{} deferred: {}", item.id, message));
+            }
+            outbox::fail(db, &item, &message)?;
+            Err(alloc::format!("#{} failed: {}", item.id, message))
+        }
+    }
+}
+
+/// Batch side of the outbox: if the batch holding the oldest submitted
+/// request has ended, deliver its results to their mailboxes and log the
+/// usage at the batch discount; then submit the requests queued since as
+/// a new batch. Without a key, network or connectivity nothing happens
+/// (empty result), as with `deliver_outbox`.
+pub fn deliver_batches() -> Result<String, String> {
+    use crate::api::batch;
+    use crate::sqlite::outbox;
+
+    let (in_flight, unbatched) = {
+        let guard = DB.lock();
+        let db = guard.as_ref().ok_or_else(|| String::from("database not open"))?;
+        let in_flight = match outbox::oldest_batch(db)? {
+            Some(id) => Some((outbox::in_batch(db, &id)?, id)),
+            None => None,
+        };
+        (in_flight, outbox::unbatched(db, batch::MAX_REQUESTS)?)
+    };
+    if in_flight.is_none() && unbatched.is_empty() {
+        return Ok(String::new());
+    }
+    if crate::api::mock::enabled() {
+        return deliver_batch_mock(&unbatched);
+    }
+    let Some(api_key) = crate::api::get_api_key() else {
+        return Ok(String::new());
+    };
+    // Read before the stack is taken: it needs the database
+    let timeout_secs = crate::api::timeout_default();
+
+    // Marked before the submit: should recording its batch id fail, they aren't sent again
+    let ids: alloc::vec::Vec<i64> = unbatched.iter().map(|item| item.id).collect();
+    {
+        let guard = DB.lock();
+        let db = guard.as_ref().ok_or_else(|| String::from("database not open"))?;
+        outbox::set_batch(db, &ids, Some(outbox::SUBMITTING))?;
+    }
+    let unmark = || -> Result<(), String> {
+        let guard = DB.lock();
+        let db = guard.as_ref().ok_or_else(|| String::from("database not open"))?;
+        outbox::set_batch(db, &ids, None)
+    };
+
+    let mut span = crate::span::begin("batch");
+    let (collected, submitted) = {
+        let mut net_guard = crate::net::NET_STACK.lock();
+        let Some(net) = net_guard.as_mut() else {
+            drop(net_guard);
+            unmark()?;
+            return Ok(String::new());
+        };
+        let Ok(ip) = crate::api::resolve_api_host(net) else {
+            drop(net_guard);
+            unmark()?;
+            return Ok(String::new());
+        };
+        let config = crate::api::ClaudeConfig { api_key, timeout_secs, ..crate::api::ClaudeConfig::direct_tls(ip) };
+
+        let collected = in_flight.map(|(items, batch_id)| {
+            let results = batch::status(net, &config, &batch_id)
+                .and_then(|b| if b.ended() { batch::results(net, &config, &batch_id).map(Some) } else { Ok(None) });
+            (items, batch_id, results)
+        });
+        let submitted = (!unbatched.is_empty()).then(|| {
+            let requests: alloc::vec::Vec<batch::Request> = unbatched
+                .iter()
+                .map(|item| batch::Request {
+                    custom_id: alloc::format!("outbox-{}", item.id),
+                    model: item.model.clone(),
+                    system: item.system.clone(),
+                    messages: item.api_messages(),
+                })
+                .collect();
+            batch::submit(net, &config, &requests)
+        });
+        (collected, submitted)
+    };
+
+    let guard = DB.lock();
+    let db = guard.as_ref().ok_or_else(|| String::from("database not open"))?;
+    // Record the new batch's id before anything below can fail: its
+    // requests would otherwise stay marked as submitting, the id lost
+    let mut submit_report = None;
+    match submitted {
+        None => {}
+        Some(Ok(submitted)) => {
+            outbox::set_batch(db, &ids, Some(&submitted.id))?;
+            submit_report = Some(alloc::format!("submitted {} request(s) as {}", ids.len(), submitted.id));
+        }
+        Some(Err(e)) => {
+            span.fail();
+            outbox::set_batch(db, &ids, None)?;
+            if !e.is_network() {
+                let message = alloc::format!("{}", e);
+                for item in &unbatched {
+                    outbox::defer(db, item.id, &message)?;
+                }
+                submit_report = Some(alloc::format!("submit: {}", message));
+            }
+        }
+    }
+    let mut report = alloc::vec::Vec::new();
+    match collected {
+        None => {}
+        Some((_, batch_id, Ok(None))) => report.push(alloc::format!("{} still running", batch_id)),
+        Some((items, batch_id, Ok(Some(results)))) => {
+            let (mut done, mut failed) = (0, 0);
+            for item in &items {
+                let custom_id = alloc::format!("outbox-{}", item.id);
+                match results.iter().find(|(id, _)| *id == custom_id).map(|(_, outcome)| outcome) {
+                    Some(batch::Outcome::Succeeded(message)) => {
+                        outbox::complete(db, item, &message.text)?;
+                        let discount = batch::DISCOUNT_PERCENT;
+                        crate::sqlite::audit::record_usage(db, &item.agent, &custom_id, &item.model, 1, &message.usage, discount)?;
+                        done += 1;
+                    }
+                    Some(batch::Outcome::Errored(e)) => {
+                        outbox::fail(db, item, e)?;
+                        failed += 1;
+                    }
+                    Some(batch::Outcome::Canceled) => {
+                        outbox::fail(db, item, "batch canceled")?;
+                        failed += 1;
+                    }
+                    Some(batch::Outcome::Expired) => outbox::unbatch(db, item.id, "batch expired")?,
+                    None => outbox::unbatch(db, item.id, "missing from the batch results")?,
+                }
+            }
+            report.push(alloc::format!("{} ended: {} delivered, {} failed", batch_id, done, failed));
+        }
+        // Poll again next time
+        Some((_, _, Err(e))) if e.is_network() => span.fail(),
+        Some((_, batch_id, Err(e))) => {
+            span.fail();
+            report.push(alloc::format!("{}: {}", batch_id, e));
+        }
+    }
+    report.extend(submit_report);
+    Ok(report.join("; "))
+}
+
+/// `mockapi` builds answer batch requests at once from the mock rules.
+fn deliver_batch_mock(items: &[crate::sqlite::outbox::Item]) -> Result<String, String> {
+    use crate::sqlite::outbox;
+
+    // The rules are read under the DB lock, so answer everything first
+    let answers: alloc::vec::Vec<_> =
+        items.iter().map(|item| crate::api::mock::respond(&item.api_messages(), &[], |_| {})).collect();
+    let guard = DB.lock();
+    let db = guard.as_ref().ok_or_else(|| String::from("database not open"))?;
+    for (item, answer) in items.iter().zip(answers) {
+        match answer {
+            Ok(message) => {
+                outbox::complete(db, item, &message.text)?;
+                let conversation = alloc::format!("outbox-{}", item.id);
+                let discount = crate::api::batch::DISCOUNT_PERCENT;
+                crate::sqlite::audit::record_usage(db, &item.agent, &conversation, &item.model, 1, &message.usage, discount)?;
+            }
+            Err(e) => outbox::fail(db, item, &alloc::format!("{}", e))?,
+        }
+    }
+    Ok(if items.is_empty() { String::new() } else { alloc::format!("{} mock batch request(s) answered", items.len()) })
+}
+
+fn quick_check() -> Result<String, String> {
+    let guard = DB.lock();
+    let db = guard.as_ref().ok_or_else(|| String::from("database not open"))?;
+    let lines = db.query_column("PRAGMA quick_check")?;
+    let report = lines.join("; ");
+    if report == "ok" { Ok(report) } else { Err(report) }
+}
+
+fn incremental_vacuum() -> Result<String, String> {
+    let guard = DB.lock();
+    let db = guard.as_ref().ok_or_else(|| String::from("database not open"))?;
+    let mode = db.query_value("PRAGMA auto_vacuum")?.unwrap_or_default();
+    if mode != "2" {
+        return Ok(String::from("skipped (auto_vacuum is not INCREMENTAL)"));
+    }
+    let before = freelist_count(db)?;
+    if before == 0 {
+        return Ok(String::from("no free pages"));
+    }
+    db.query(&alloc::format!("PRAGMA incremental_vacuum({})", VACUUM_PAGES))?;
+    let after = freelist_count(db)?;
+    Ok(alloc::format!("released {} page(s), {} still free", before - after.min(before), after))
+}
+
+fn freelist_count(db: &crate::sqlite::SqliteDb) -> Result<u64, String> {
+    Ok(db
+        .query_value("PRAGMA freelist_count")?
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0))
+}
+
+fn analyze() -> Result<String, String> {
+    let guard = DB.lock();
+    let db = guard.as_ref().ok_or_else(|| String::from("database not open"))?;
+    db.exec("ANALYZE")?;
+    Ok(String::from("statistics updated"))
+}
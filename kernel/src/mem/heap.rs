@@ -1,4 +1,4 @@
-/// Kernel heap allocator — slab-based.
+/// Kernel heap allocator — slab-based, one arena per subsystem.
 ///
 /// Provides `malloc`/`free`/`realloc` semantics needed by SQLite (via
 /// `SQLITE_CONFIG_MALLOC`) and by Rust's `alloc` crate.
@@ -8,8 +8,18 @@
 /// - Large allocations (> 4096) go directly to the page allocator
 /// - Each allocation has a hidden header storing the slab class (or size for large allocs)
 ///   so that `free(ptr)` works without a size argument — required by SQLite's xFree.
+///
+/// Arenas: Rust code (`HEAP`), SQLite (`SQLITE_HEAP`) and Lua (`LUA_HEAP`)
+/// each refill from their own pages, so a block of one is never handed to
+/// another. Every block is bracketed by canaries — one in the header, one
+/// just past the usable bytes — checked when it is freed, reallocated or
+/// handed out again. A block whose canaries are gone is counted against
+/// its arena and leaked rather than reused, so the damage stays where it
+/// happened; a block freed through the wrong arena goes back to its own.
+/// `arenas()` reports per-arena usage for the `heap` command.
 use core::alloc::{GlobalAlloc, Layout};
 use core::ptr;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use spin::Mutex;
 
 use super::phys::{PhysAddr, PAGE_SIZE, PHYS_ALLOCATOR, hhdm_offset};
@@ -21,11 +31,23 @@ struct AllocHeader {
     size: usize,
     /// Slab class index (0-9) or LARGE_ALLOC for page-backed allocations.
     class: u8,
+    /// Index of the owning arena in `ARENAS`.
+    arena: u8,
+    _pad: u16,
+    /// HEAD_CANARY while the header is intact.
+    canary: u32,
 }
 
 const HEADER_SIZE: usize = 16; // Aligned to 16 bytes
 const LARGE_ALLOC: u8 = 0xFF;
 
+/// Guard word in every header.
+const HEAD_CANARY: u32 = 0xC0DE_5AFE;
+/// Guard word right after every block's usable bytes.
+const TAIL_CANARY: u64 = 0xDEAD_BEEF_F00D_CAFE;
+/// Room for the tail canary; 16 keeps blocks of 16 and up 16-aligned.
+const TAIL_SIZE: usize = 16;
+
 const SLAB_CLASSES: [usize; 10] = [8, 16, 32, 64, 128, 256, 512, 1024, 2048, 4096];
 
 /// Per-class free list.
@@ -39,7 +61,19 @@ struct FreeNode {
 }
 
 pub struct SlabAllocator {
+    name: &'static str,
+    /// This arena's index in `ARENAS`, stamped into every header.
+    id: u8,
     inner: Mutex<SlabInner>,
+    /// Usable bytes of live blocks.
+    in_use: AtomicUsize,
+    peak: AtomicUsize,
+    live: AtomicUsize,
+    /// Pages taken from the page allocator (slabs and large blocks).
+    pages: AtomicUsize,
+    /// Blocks found with a broken canary, and the last one's address.
+    corrupt: AtomicUsize,
+    last_corrupt: AtomicU64,
 }
 
 struct SlabInner {
@@ -50,18 +84,39 @@ struct SlabInner {
 unsafe impl Send for SlabInner {}
 unsafe impl Sync for SlabAllocator {}
 
+/// One arena's counters, as reported by `arenas()`.
+#[derive(Debug, Clone, Copy)]
+pub struct ArenaStats {
+    pub name: &'static str,
+    pub in_use: usize,
+    pub peak: usize,
+    pub live: usize,
+    pub pages: usize,
+    pub corrupt: usize,
+    /// Address of the most recent corrupt block (0 = none).
+    pub last_corrupt: u64,
+}
+
 impl SlabAllocator {
-    pub const fn new() -> Self {
+    pub const fn new(name: &'static str, id: u8) -> Self {
         const EMPTY_LIST: FreeList = FreeList {
             head: ptr::null_mut(),
             slab_size: 0,
         };
 
         Self {
+            name,
+            id,
             inner: Mutex::new(SlabInner {
                 free_lists: [EMPTY_LIST; 10],
                 initialized: false,
             }),
+            in_use: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+            live: AtomicUsize::new(0),
+            pages: AtomicUsize::new(0),
+            corrupt: AtomicUsize::new(0),
+            last_corrupt: AtomicU64::new(0),
         }
     }
 
@@ -80,8 +135,8 @@ impl SlabAllocator {
     }
 
     /// Refill a slab class by allocating a page and splitting it.
-    fn refill_class(inner: &mut SlabInner, class: usize) -> bool {
-        let entry_size = SLAB_CLASSES[class] + HEADER_SIZE;
+    fn refill_class(&self, inner: &mut SlabInner, class: usize) -> bool {
+        let entry_size = HEADER_SIZE + SLAB_CLASSES[class] + TAIL_SIZE;
         let entries_per_page = PAGE_SIZE / entry_size;
 
         if entries_per_page == 0 {
@@ -92,6 +147,7 @@ impl SlabAllocator {
             Ok(p) => p,
             Err(_) => return false,
         };
+        self.pages.fetch_add(1, Ordering::Relaxed);
 
         let base = phys.as_ptr::<u8>();
         let list = &mut inner.free_lists[class];
@@ -102,12 +158,18 @@ impl SlabAllocator {
             // Write header
             let header = ptr as *mut AllocHeader;
             unsafe {
-                (*header).size = SLAB_CLASSES[class];
-                (*header).class = class as u8;
+                header.write(AllocHeader {
+                    size: SLAB_CLASSES[class],
+                    class: class as u8,
+                    arena: self.id,
+                    _pad: 0,
+                    canary: HEAD_CANARY,
+                });
             }
 
             // The usable pointer is after the header
             let usable = unsafe { ptr.add(HEADER_SIZE) };
+            unsafe { write_tail(usable, SLAB_CLASSES[class]) };
             let node = usable as *mut FreeNode;
             unsafe {
                 (*node).next = list.head;
@@ -117,71 +179,102 @@ impl SlabAllocator {
 
         true
     }
-}
 
-unsafe impl GlobalAlloc for SlabAllocator {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let size = layout.size().max(layout.align());
+    /// Allocate `size` usable bytes.
+    fn allocate(&self, size: usize) -> *mut u8 {
+        let p = match SlabAllocator::class_for_size(size) {
+            Some(class) => self.allocate_slab(class),
+            None => self.allocate_large(size),
+        };
+        if !p.is_null() {
+            let size = unsafe { (*header_of(p)).size };
+            let now = self.in_use.fetch_add(size, Ordering::Relaxed) + size;
+            self.peak.fetch_max(now, Ordering::Relaxed);
+            self.live.fetch_add(1, Ordering::Relaxed);
+        }
+        p
+    }
+
+    fn allocate_slab(&self, class: usize) -> *mut u8 {
         let mut inner = self.inner.lock();
         SlabAllocator::ensure_init(&mut inner);
 
-        match SlabAllocator::class_for_size(size) {
-            Some(class) => {
-                // Slab allocation
-                let list = &mut inner.free_lists[class];
-
-                if list.head.is_null() {
-                    if !SlabAllocator::refill_class(&mut inner, class) {
-                        return ptr::null_mut();
-                    }
-                }
-
-                let list = &mut inner.free_lists[class];
-                let node = list.head;
-                if node.is_null() {
-                    return ptr::null_mut();
-                }
-
-                list.head = unsafe { (*node).next };
-                node as *mut u8
+        loop {
+            if inner.free_lists[class].head.is_null() && !self.refill_class(&mut inner, class) {
+                return ptr::null_mut();
             }
-            None => {
-                // Large allocation: use pages directly
-                let total = size + HEADER_SIZE;
-                let pages = (total + PAGE_SIZE - 1) / PAGE_SIZE;
-
-                let phys = match PHYS_ALLOCATOR.alloc_pages_contiguous(pages, 1) {
-                    Ok(p) => p,
-                    Err(_) => return ptr::null_mut(),
-                };
-
-                let base = phys.as_ptr::<u8>();
-                let header = base as *mut AllocHeader;
-                unsafe {
-                    (*header).size = pages * PAGE_SIZE - HEADER_SIZE;
-                    (*header).class = LARGE_ALLOC;
-                }
-
-                unsafe { base.add(HEADER_SIZE) }
+
+            let list = &mut inner.free_lists[class];
+            let node = list.head;
+            if node.is_null() {
+                return ptr::null_mut();
+            }
+            // A free block whose guards were overwritten (by an overflow
+            // from its neighbour) is not handed out; nor is the rest of
+            // the list, since the block held the link to it
+            if unsafe { !intact(node as *mut u8) } {
+                list.head = ptr::null_mut();
+                self.note_corrupt(node as *mut u8);
+                continue;
             }
+
+            list.head = unsafe { (*node).next };
+            return node as *mut u8;
         }
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
-        if ptr.is_null() {
-            return;
+    fn allocate_large(&self, size: usize) -> *mut u8 {
+        // Large allocation: use pages directly
+        let total = size + HEADER_SIZE + TAIL_SIZE;
+        let pages = total.div_ceil(PAGE_SIZE);
+
+        let phys = match PHYS_ALLOCATOR.alloc_pages_contiguous(pages, 1) {
+            Ok(p) => p,
+            Err(_) => return ptr::null_mut(),
+        };
+        self.pages.fetch_add(pages, Ordering::Relaxed);
+
+        let base = phys.as_ptr::<u8>();
+        let usable_size = pages * PAGE_SIZE - HEADER_SIZE - TAIL_SIZE;
+        unsafe {
+            (base as *mut AllocHeader).write(AllocHeader {
+                size: usable_size,
+                class: LARGE_ALLOC,
+                arena: self.id,
+                _pad: 0,
+                canary: HEAD_CANARY,
+            });
+            let usable = base.add(HEADER_SIZE);
+            write_tail(usable, usable_size);
+            usable
         }
+    }
 
-        let header_ptr = unsafe { ptr.sub(HEADER_SIZE) } as *const AllocHeader;
-        let header = unsafe { &*header_ptr };
+    /// Return a block to its arena.
+    fn release(&self, ptr: *mut u8) {
+        if unsafe { !intact(ptr) } {
+            // Don't trust the header enough to reuse the block
+            self.note_corrupt(ptr);
+            return;
+        }
+        let header = unsafe { &*header_of(ptr) };
+        if header.arena != self.id {
+            if let Some(owner) = ARENAS.get(header.arena as usize) {
+                owner.release(ptr);
+                return;
+            }
+        }
+        self.in_use.fetch_sub(header.size, Ordering::Relaxed);
+        self.live.fetch_sub(1, Ordering::Relaxed);
 
         if header.class == LARGE_ALLOC {
             // Large allocation: free pages
-            // header_ptr is a virtual address (HHDM-mapped); convert back to physical.
-            let total = header.size + HEADER_SIZE;
-            let pages = (total + PAGE_SIZE - 1) / PAGE_SIZE;
-            let phys = PhysAddr::new(header_ptr as u64 - hhdm_offset());
+            // The header is at a virtual address (HHDM-mapped); convert back to physical.
+            let total = header.size + HEADER_SIZE + TAIL_SIZE;
+            let pages = total.div_ceil(PAGE_SIZE);
+            let phys = PhysAddr::new(header_of(ptr) as u64 - hhdm_offset());
             PHYS_ALLOCATOR.free_pages(phys, pages);
+            self.pages.fetch_sub(pages, Ordering::Relaxed);
         } else {
             // Slab: return to free list
             let class = header.class as usize;
@@ -195,77 +288,177 @@ unsafe impl GlobalAlloc for SlabAllocator {
             list.head = node;
         }
     }
+
+    fn note_corrupt(&self, ptr: *mut u8) {
+        self.corrupt.fetch_add(1, Ordering::Relaxed);
+        self.last_corrupt.store(ptr as u64, Ordering::Relaxed);
+    }
+
+    /// `malloc`: `size` bytes, 8-aligned; null for 0 or when out of memory.
+    pub fn malloc(&self, size: usize) -> *mut u8 {
+        if size == 0 {
+            return ptr::null_mut();
+        }
+        self.allocate(size)
+    }
+
+    /// `free`: no size argument needed (the header stores it).
+    ///
+    /// # Safety
+    /// `ptr` is null or a live block from one of the arenas.
+    pub unsafe fn free(&self, ptr: *mut u8) {
+        if !ptr.is_null() {
+            self.release(ptr);
+        }
+    }
+
+    /// `realloc`. A block whose canaries are broken isn't copied from;
+    /// the caller gets null, as if out of memory.
+    ///
+    /// # Safety
+    /// As for `free`.
+    pub unsafe fn realloc(&self, ptr: *mut u8, new_size: usize) -> *mut u8 {
+        if ptr.is_null() {
+            return self.malloc(new_size);
+        }
+        if new_size == 0 {
+            unsafe { self.free(ptr) };
+            return ptr::null_mut();
+        }
+        if unsafe { !intact(ptr) } {
+            self.note_corrupt(ptr);
+            return ptr::null_mut();
+        }
+
+        // Read old size from header
+        let old_size = unsafe { (*header_of(ptr)).size };
+
+        if new_size <= old_size {
+            // Shrink: just return the same pointer (slab class hasn't changed)
+            return ptr;
+        }
+
+        // Grow: allocate new, copy, free old
+        let new_ptr = self.malloc(new_size);
+        if new_ptr.is_null() {
+            return ptr::null_mut();
+        }
+        unsafe {
+            ptr::copy_nonoverlapping(ptr, new_ptr, old_size.min(new_size));
+        }
+        unsafe { self.free(ptr) };
+        new_ptr
+    }
+
+    /// Usable size of a block (for sqlite3_msize).
+    ///
+    /// # Safety
+    /// As for `free`.
+    pub unsafe fn usable_size(&self, ptr: *mut u8) -> usize {
+        if ptr.is_null() {
+            return 0;
+        }
+        unsafe { (*header_of(ptr)).size }
+    }
+
+    pub fn stats(&self) -> ArenaStats {
+        ArenaStats {
+            name: self.name,
+            in_use: self.in_use.load(Ordering::Relaxed),
+            peak: self.peak.load(Ordering::Relaxed),
+            live: self.live.load(Ordering::Relaxed),
+            pages: self.pages.load(Ordering::Relaxed),
+            corrupt: self.corrupt.load(Ordering::Relaxed),
+            last_corrupt: self.last_corrupt.load(Ordering::Relaxed),
+        }
+    }
+}
+
+fn header_of(ptr: *mut u8) -> *mut AllocHeader {
+    ptr.wrapping_sub(HEADER_SIZE) as *mut AllocHeader
+}
+
+/// Stamp the tail canary after `size` usable bytes at `usable`.
+unsafe fn write_tail(usable: *mut u8, size: usize) {
+    unsafe { (usable.add(size) as *mut u64).write_unaligned(TAIL_CANARY) };
+}
+
+/// Are both canaries of the block at `ptr` intact?
+unsafe fn intact(ptr: *mut u8) -> bool {
+    let header = unsafe { &*header_of(ptr) };
+    if header.canary != HEAD_CANARY {
+        return false;
+    }
+    let size_ok = match SLAB_CLASSES.get(header.class as usize) {
+        Some(&class_size) => header.size == class_size,
+        None => {
+            header.class == LARGE_ALLOC
+                && header.size < 1 << 32
+                && (header.size + HEADER_SIZE + TAIL_SIZE).is_multiple_of(PAGE_SIZE)
+        }
+    };
+    size_ok && unsafe { (ptr.add(header.size) as *const u64).read_unaligned() } == TAIL_CANARY
+}
+
+unsafe impl GlobalAlloc for SlabAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.allocate(layout.size().max(layout.align()))
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
+        unsafe { self.free(ptr) };
+    }
 }
 
 /// Global kernel heap allocator.
 #[global_allocator]
-pub static HEAP: SlabAllocator = SlabAllocator::new();
+pub static HEAP: SlabAllocator = SlabAllocator::new("kernel", 0);
+
+/// Arena behind SQLite's allocator and the C library stubs.
+pub static SQLITE_HEAP: SlabAllocator = SlabAllocator::new("sqlite", 1);
+
+/// Arena behind every Lua state.
+pub static LUA_HEAP: SlabAllocator = SlabAllocator::new("lua", 2);
+
+/// Every arena, indexed by the id stamped into block headers.
+static ARENAS: [&SlabAllocator; 3] = [&HEAP, &SQLITE_HEAP, &LUA_HEAP];
+
+/// Counters for every arena.
+pub fn arenas() -> [ArenaStats; 3] {
+    ARENAS.map(SlabAllocator::stats)
+}
 
 // --- C-compatible interface for SQLite ---
 
 /// `malloc` for SQLite's SQLITE_CONFIG_MALLOC.
 #[no_mangle]
 pub extern "C" fn heavenos_malloc(size: usize) -> *mut u8 {
-    if size == 0 {
-        return ptr::null_mut();
-    }
-    let layout = match Layout::from_size_align(size, 8) {
-        Ok(l) => l,
-        Err(_) => return ptr::null_mut(),
-    };
-    unsafe { HEAP.alloc(layout) }
+    SQLITE_HEAP.malloc(size)
 }
 
 /// `free` for SQLite — no size argument needed (header stores it).
+///
+/// # Safety
+/// `ptr` is null or came from `heavenos_malloc`/`heavenos_realloc`.
 #[no_mangle]
-pub extern "C" fn heavenos_free(ptr: *mut u8) {
-    if ptr.is_null() {
-        return;
-    }
-    // We need the layout for GlobalAlloc::dealloc, but our implementation
-    // ignores it (uses the header instead). Pass a dummy layout.
-    let layout = Layout::from_size_align(1, 1).unwrap();
-    unsafe { HEAP.dealloc(ptr, layout) };
+pub unsafe extern "C" fn heavenos_free(ptr: *mut u8) {
+    unsafe { SQLITE_HEAP.free(ptr) };
 }
 
 /// `realloc` for SQLite.
+///
+/// # Safety
+/// `ptr` is null or came from `heavenos_malloc`/`heavenos_realloc`.
 #[no_mangle]
-pub extern "C" fn heavenos_realloc(ptr: *mut u8, new_size: usize) -> *mut u8 {
-    if ptr.is_null() {
-        return heavenos_malloc(new_size);
-    }
-    if new_size == 0 {
-        heavenos_free(ptr);
-        return ptr::null_mut();
-    }
-
-    // Read old size from header
-    let header_ptr = unsafe { ptr.sub(HEADER_SIZE) } as *const AllocHeader;
-    let old_size = unsafe { (*header_ptr).size };
-
-    if new_size <= old_size {
-        // Shrink: just return the same pointer (slab class hasn't changed)
-        return ptr;
-    }
-
-    // Grow: allocate new, copy, free old
-    let new_ptr = heavenos_malloc(new_size);
-    if new_ptr.is_null() {
-        return ptr::null_mut();
-    }
-    unsafe {
-        ptr::copy_nonoverlapping(ptr, new_ptr, old_size.min(new_size));
-    }
-    heavenos_free(ptr);
-    new_ptr
+pub unsafe extern "C" fn heavenos_realloc(ptr: *mut u8, new_size: usize) -> *mut u8 {
+    unsafe { SQLITE_HEAP.realloc(ptr, new_size) }
 }
 
 /// Return the usable size of an allocation (for sqlite3_msize).
+///
+/// # Safety
+/// `ptr` is null or came from `heavenos_malloc`/`heavenos_realloc`.
 #[no_mangle]
-pub extern "C" fn heavenos_malloc_size(ptr: *mut u8) -> usize {
-    if ptr.is_null() {
-        return 0;
-    }
-    let header_ptr = unsafe { ptr.sub(HEADER_SIZE) } as *const AllocHeader;
-    unsafe { (*header_ptr).size }
+pub unsafe extern "C" fn heavenos_malloc_size(ptr: *mut u8) -> usize {
+    unsafe { SQLITE_HEAP.usable_size(ptr) }
 }
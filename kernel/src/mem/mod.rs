@@ -1,8 +1,8 @@
 pub mod phys;
 pub mod paging;
 mod dma;
-mod heap;
+pub mod heap;
 
 pub use phys::{PhysAddr, PhysPageAllocator, AllocError, set_hhdm_offset, hhdm_offset};
 pub use dma::DmaBuf;
-pub use heap::SlabAllocator;
+pub use heap::{SlabAllocator, ArenaStats};
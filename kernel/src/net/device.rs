@@ -3,22 +3,49 @@
 /// This bridges the gap between our virtio-net driver (which sends/receives
 /// raw Ethernet frames) and smoltcp (which expects a `Device` trait impl).
 use alloc::vec::Vec;
-use smoltcp::phy::{self, Device, DeviceCapabilities, Medium};
+use smoltcp::phy::{self, Checksum, ChecksumCapabilities, Device, DeviceCapabilities, Medium};
 use smoltcp::time::Instant;
 
-use crate::drivers::virtio::net::VIRTIO_NET;
+use crate::drivers::virtio::net::{DEFAULT_MTU, ETH_HDR_SIZE, VIRTIO_NET};
 
 /// Adapter that implements smoltcp's Device trait using virtio-net.
-pub struct SmoltcpDevice;
+pub struct SmoltcpDevice {
+    /// Largest Ethernet frame (MTU + header), fixed when the NIC came up.
+    max_frame: usize,
+    /// TCP/UDP checksums smoltcp still has to do itself.
+    checksum: ChecksumCapabilities,
+}
 
 impl SmoltcpDevice {
     pub fn new() -> Self {
-        Self
+        let nic = VIRTIO_NET.lock();
+        let mtu = nic.as_ref().map_or(DEFAULT_MTU, |nic| nic.mtu());
+        let (tx_offload, rx_offload) = nic.as_ref().map_or((false, false), |nic| nic.checksum_offload());
+        let l4 = match (tx_offload, rx_offload) {
+            (true, true) => Checksum::None,
+            (true, false) => Checksum::Rx,
+            (false, true) => Checksum::Tx,
+            (false, false) => Checksum::Both,
+        };
+        let mut checksum = ChecksumCapabilities::default();
+        checksum.tcp = l4;
+        checksum.udp = l4;
+        Self { max_frame: mtu + ETH_HDR_SIZE, checksum }
     }
 
     pub fn mac(&self) -> Option<[u8; 6]> {
         VIRTIO_NET.lock().as_ref().map(|nic| nic.mac())
     }
+
+    /// Send a frame built outside smoltcp. False if it couldn't be queued.
+    pub fn send_raw(&self, frame: &[u8]) -> bool {
+        VIRTIO_NET.lock().as_mut().is_some_and(|nic| nic.transmit(frame).is_ok())
+    }
+
+    /// Carrier state from the NIC (false if it went away).
+    pub fn link_up(&self) -> bool {
+        VIRTIO_NET.lock().as_ref().is_some_and(|nic| nic.link_up())
+    }
 }
 
 impl Device for SmoltcpDevice {
@@ -30,18 +57,23 @@ impl Device for SmoltcpDevice {
         let nic = nic.as_mut()?;
 
         let frame = nic.receive()?;
+        super::stats::observe_rx(&frame);
+        super::slaac::observe_rx(&frame);
         Some((RxToken { frame }, TxToken))
     }
 
     fn transmit(&mut self, _timestamp: Instant) -> Option<Self::TxToken<'_>> {
-        // Always ready to transmit (queue full handled in the driver)
-        Some(TxToken)
+        // No token while the tx ring is full: smoltcp keeps the packet
+        // (TCP retransmits, queued UDP waits) instead of losing it here.
+        let mut nic = VIRTIO_NET.lock();
+        nic.as_mut()?.tx_ready().then_some(TxToken)
     }
 
     fn capabilities(&self) -> DeviceCapabilities {
         let mut caps = DeviceCapabilities::default();
         caps.medium = Medium::Ethernet;
-        caps.max_transmission_unit = 1514;
+        caps.max_transmission_unit = self.max_frame;
+        caps.checksum = self.checksum.clone();
         caps.max_burst_size = Some(1);
         caps
     }
@@ -71,6 +103,7 @@ impl phy::TxToken for TxToken {
     {
         let mut buf = alloc::vec![0u8; len];
         let result = f(&mut buf);
+        super::stats::observe_tx(&buf);
 
         // Send the frame through virtio-net
         let mut nic = VIRTIO_NET.lock();
@@ -1,30 +1,84 @@
 /// Minimal DNS resolver over UDP.
 ///
-/// Sends A-record queries to the QEMU default DNS forwarder (10.0.2.3)
-/// and parses the response. Uses smoltcp's UDP socket support.
+/// Sends A and AAAA queries to the DNS server from the DHCP lease (QEMU's
+/// forwarder, 10.0.2.3, until there is one) or, when IPv6 is preferred
+/// or there is no lease, to the one a router advertised. Uses smoltcp's
+/// UDP socket support.
+///
+/// `resolve_all` asks for A and AAAA at once (AAAA only once the
+/// interface has a global IPv6 address) and, as RFC 8305 §3 suggests,
+/// waits `RESOLUTION_DELAY_MS` past the first answer for the other one.
+/// The addresses come back interleaved, preferred family first
+/// (`net.prefer`, IPv4 unless set to `ipv6`), which is the order
+/// `TlsClient` races connections in; `resolve` takes the first.
 ///
 /// The DNS packet format follows RFC 1035:
 /// - Header: 12 bytes (ID, flags, counts)
-/// - Question: encoded hostname + type (A) + class (IN)
-/// - Answer: name + type + class + TTL + rdlength + rdata (4 bytes for A)
+/// - Question: encoded hostname + type (A or AAAA) + class (IN)
+/// - Answer: name + type + class + TTL + rdlength + rdata (4 bytes for A,
+///   16 for AAAA)
 
 use alloc::string::String;
 use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
 use smoltcp::iface::SocketHandle;
 use smoltcp::socket::udp::{self, Socket as UdpSocket};
-use smoltcp::wire::{IpAddress, IpEndpoint, Ipv4Address};
+use smoltcp::wire::{IpAddress, IpEndpoint, Ipv4Address, Ipv6Address};
 
 use super::stack::NetStack;
+use crate::error::{KernelError, Layer};
 
 /// DNS query port.
 const DNS_PORT: u16 = 53;
 
-/// QEMU's default DNS forwarder IP.
-const QEMU_DNS: Ipv4Address = Ipv4Address::new(10, 0, 2, 3);
-
 /// Maximum time to wait for a DNS response (ms).
 const DNS_TIMEOUT_MS: u64 = 5_000;
 
+/// How long to wait for the other family's answer once one has arrived.
+const RESOLUTION_DELAY_MS: u64 = 50;
+
+/// Query ID of the first question; each further one adds 1.
+const QUERY_ID: u16 = 0x1234;
+
+/// Record types asked for.
+pub(crate) const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+
+/// Address family tried first by `resolve` (`net.prefer`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Family {
+    Ipv4,
+    Ipv6,
+}
+
+impl Family {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "ipv4" | "4" => Some(Family::Ipv4),
+            "ipv6" | "6" => Some(Family::Ipv6),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Family::Ipv4 => "ipv4",
+            Family::Ipv6 => "ipv6",
+        }
+    }
+}
+
+static PREFER_IPV6: AtomicBool = AtomicBool::new(false);
+
+pub fn set_preference(family: Family) {
+    PREFER_IPV6.store(family == Family::Ipv6, Ordering::Relaxed);
+}
+
+pub fn preference() -> Family {
+    if PREFER_IPV6.load(Ordering::Relaxed) { Family::Ipv6 } else { Family::Ipv4 }
+}
+
 /// DNS error types.
 #[derive(Debug)]
 pub enum DnsError {
@@ -38,6 +92,8 @@ pub enum DnsError {
     NoAnswer,
     /// Response packet is malformed.
     MalformedResponse,
+    /// The network link is down.
+    LinkDown,
 }
 
 impl core::fmt::Display for DnsError {
@@ -48,14 +104,23 @@ impl core::fmt::Display for DnsError {
             DnsError::Timeout => write!(f, "DNS query timeout"),
             DnsError::NoAnswer => write!(f, "no DNS answer"),
             DnsError::MalformedResponse => write!(f, "malformed DNS response"),
+            DnsError::LinkDown => write!(f, "network link down"),
         }
     }
 }
 
-/// Simple DNS A-record cache entry.
+impl From<DnsError> for KernelError {
+    fn from(e: DnsError) -> Self {
+        KernelError::new(Layer::Dns, alloc::format!("{:?}", e), e)
+    }
+}
+
+/// Simple DNS cache entry.
 struct CacheEntry {
     hostname: String,
-    ip: Ipv4Address,
+    rtype: u16,
+    /// Every address in the answer, in the server's order.
+    ips: Vec<IpAddress>,
     /// Absolute time (monotonic ms) when this entry expires.
     expires_ms: u64,
 }
@@ -65,23 +130,130 @@ const CACHE_SIZE: usize = 8;
 static DNS_CACHE: spin::Mutex<[Option<CacheEntry>; CACHE_SIZE]> =
     spin::Mutex::new([const { None }; CACHE_SIZE]);
 
-/// Resolve a hostname to an IPv4 address using DNS over UDP.
-///
-/// Checks the cache first, then sends a UDP query to QEMU's DNS forwarder.
+/// Resolve a hostname (or an address literal) to an address of either
+/// family, preferred family first. The preferred family's failure is
+/// reported if neither answers.
+pub fn resolve(net: &mut NetStack, hostname: &str) -> Result<IpAddress, DnsError> {
+    resolve_all(net, hostname).map(|ips| ips[0])
+}
+
+/// Every address of a hostname (or the address literal), interleaved
+/// preferred family first. Never empty.
+pub fn resolve_all(net: &mut NetStack, hostname: &str) -> Result<Vec<IpAddress>, DnsError> {
+    if let Ok(ip) = hostname.trim_start_matches('[').trim_end_matches(']').parse::<IpAddress>() {
+        return Ok(vec![ip]);
+    }
+    let order: &[u16] = match (preference(), net.ipv6().is_some()) {
+        (_, false) => &[TYPE_A],
+        (Family::Ipv4, true) => &[TYPE_A, TYPE_AAAA],
+        (Family::Ipv6, true) => &[TYPE_AAAA, TYPE_A],
+    };
+    let mut answers = Vec::new();
+    let mut first_err = None;
+    for result in lookup(net, hostname, order) {
+        match result {
+            Ok(ips) => answers.push(ips),
+            Err(e) => {
+                first_err.get_or_insert(e);
+            }
+        }
+    }
+    let ips = interleave(answers);
+    if ips.is_empty() {
+        return Err(first_err.unwrap_or(DnsError::NoAnswer));
+    }
+    Ok(ips)
+}
+
+/// Addresses of a hostname still in the cache (no query is sent),
+/// ordered as `resolve_all` orders them.
+pub fn cached(hostname: &str) -> Vec<IpAddress> {
+    let now_ms = crate::time::monotonic_ms();
+    let order = match preference() {
+        Family::Ipv4 => [TYPE_A, TYPE_AAAA],
+        Family::Ipv6 => [TYPE_AAAA, TYPE_A],
+    };
+    let cache = DNS_CACHE.lock();
+    let answers = order
+        .iter()
+        .filter_map(|&rtype| {
+            cache
+                .iter()
+                .flatten()
+                .find(|e| e.hostname == hostname && e.rtype == rtype && e.expires_ms > now_ms)
+                .map(|e| e.ips.clone())
+        })
+        .collect();
+    interleave(answers)
+}
+
+/// One address from each list in turn (RFC 8305 §4), so that a family
+/// that doesn't work costs one connection attempt, not all of them.
+pub(crate) fn interleave(answers: Vec<Vec<IpAddress>>) -> Vec<IpAddress> {
+    let longest = answers.iter().map(Vec::len).max().unwrap_or(0);
+    (0..longest).flat_map(|i| answers.iter().filter_map(move |ips| ips.get(i).copied())).collect()
+}
+
+/// Resolve a hostname to an IPv4 address.
 pub fn resolve_a(net: &mut NetStack, hostname: &str) -> Result<Ipv4Address, DnsError> {
+    let ips = lookup(net, hostname, &[TYPE_A]).remove(0)?;
+    match ips[0] {
+        IpAddress::Ipv4(ip) => Ok(ip),
+        _ => Err(DnsError::NoAnswer),
+    }
+}
+
+/// Resolve a hostname to an IPv6 address.
+pub fn resolve_aaaa(net: &mut NetStack, hostname: &str) -> Result<Ipv6Address, DnsError> {
+    let ips = lookup(net, hostname, &[TYPE_AAAA]).remove(0)?;
+    match ips[0] {
+        IpAddress::Ipv6(ip) => Ok(ip),
+        _ => Err(DnsError::NoAnswer),
+    }
+}
+
+/// Server to ask: the advertised IPv6 one when IPv6 is preferred or DHCP
+/// gave us nothing, else the IPv4 one.
+fn server(net: &NetStack) -> IpAddress {
+    match net.dns_server6() {
+        Some(v6) if net.ipv6().is_some() && (preference() == Family::Ipv6 || !net.dhcp_leased()) => {
+            IpAddress::Ipv6(v6)
+        }
+        _ => IpAddress::Ipv4(net.dns_server()),
+    }
+}
+
+/// Look up record types using DNS over UDP, one result per type.
+///
+/// Checks the cache first, then sends the remaining questions together
+/// to the stack's DNS server, one query each. Fails at once with
+/// `LinkDown` when there is no carrier.
+fn lookup(net: &mut NetStack, hostname: &str, rtypes: &[u16]) -> Vec<Result<Vec<IpAddress>, DnsError>> {
     // Check cache first
-    let now_ms = crate::arch::x86_64::timer::monotonic_ms();
-    {
+    let now_ms = crate::time::monotonic_ms();
+    let mut results: Vec<Option<Result<Vec<IpAddress>, DnsError>>> = {
         let cache = DNS_CACHE.lock();
-        for entry in cache.iter().flatten() {
-            if entry.hostname == hostname && entry.expires_ms > now_ms {
-                return Ok(entry.ip);
-            }
-        }
+        rtypes
+            .iter()
+            .map(|&rtype| {
+                cache
+                    .iter()
+                    .flatten()
+                    .find(|e| e.hostname == hostname && e.rtype == rtype && e.expires_ms > now_ms)
+                    .map(|e| Ok(e.ips.clone()))
+            })
+            .collect()
+    };
+    let finish = |results: Vec<Option<Result<Vec<IpAddress>, DnsError>>>, err: fn() -> DnsError| {
+        results.into_iter().map(|r| r.unwrap_or_else(|| Err(err()))).collect()
+    };
+    if results.iter().all(Option::is_some) {
+        return finish(results, || DnsError::NoAnswer);
     }
 
-    // Build DNS query packet
-    let query = build_query(hostname)?;
+    if !net.link_up() {
+        return finish(results, || DnsError::LinkDown);
+    }
 
     // Create UDP socket
     let rx_buf = udp::PacketBuffer::new(
@@ -99,70 +271,89 @@ pub fn resolve_a(net: &mut NetStack, hostname: &str) -> Result<Ipv4Address, DnsE
     let local_port = net.next_ephemeral_port();
     if net.udp_bind(handle, local_port).is_err() {
         net.remove_socket(handle);
-        return Err(DnsError::SocketError);
+        return finish(results, || DnsError::SocketError);
     }
 
-    // Send query
-    let endpoint = IpEndpoint::new(IpAddress::Ipv4(QEMU_DNS), DNS_PORT);
+    // Send a query for each type not answered from the cache
+    let endpoint = IpEndpoint::new(server(net), DNS_PORT);
     net.poll();
-    if net.udp_send(handle, &query, endpoint).is_err() {
-        net.remove_socket(handle);
-        return Err(DnsError::SocketError);
+    for (i, &rtype) in rtypes.iter().enumerate() {
+        if results[i].is_some() {
+            continue;
+        }
+        let sent = build_query(hostname, rtype, QUERY_ID + i as u16)
+            .and_then(|query| net.udp_send(handle, &query, endpoint).map_err(|_| DnsError::SocketError));
+        if let Err(e) = sent {
+            results[i] = Some(Err(e));
+        }
     }
 
-    // Wait for response
-    let start = crate::arch::x86_64::timer::monotonic_ms();
+    // Wait for responses. Once one type has its answer (from the cache
+    // counts), the rest get RESOLUTION_DELAY_MS more.
+    let start = crate::time::monotonic_ms();
+    let mut answered_ms = results.iter().any(|r| matches!(r, Some(Ok(_)))).then_some(start);
     let mut resp_buf = [0u8; 512];
-    let result = loop {
+    while results.iter().any(Option::is_none) {
         net.poll();
 
         if let Some(n) = net.udp_recv(handle, &mut resp_buf) {
-            break parse_response(&resp_buf[..n], hostname);
+            let id = u16::from_be_bytes([resp_buf[0], resp_buf[1]]);
+            let i = id.wrapping_sub(QUERY_ID) as usize;
+            if n >= 12 && i < rtypes.len() && results[i].is_none() {
+                let result = parse_response(&resp_buf[..n], rtypes[i]);
+                if let Ok((ips, ttl)) = &result {
+                    cache_insert(hostname, rtypes[i], ips, *ttl);
+                    // A failed type (NXDOMAIN, no records) doesn't cut the others short
+                    answered_ms.get_or_insert_with(crate::time::monotonic_ms);
+                }
+                results[i] = Some(result.map(|(ips, _)| ips));
+            }
+            continue;
         }
 
-        let elapsed = crate::arch::x86_64::timer::monotonic_ms() - start;
-        if elapsed > DNS_TIMEOUT_MS {
-            break Err(DnsError::Timeout);
+        let now = crate::time::monotonic_ms();
+        let delay_over = answered_ms.is_some_and(|t| now - t > RESOLUTION_DELAY_MS);
+        if delay_over || now - start > DNS_TIMEOUT_MS {
+            break;
         }
-        core::hint::spin_loop();
-    };
+        crate::arch::x86_64::idle::wait();
+    }
 
     net.remove_socket(handle);
+    finish(results, || DnsError::Timeout)
+}
 
-    // Cache the result
-    if let Ok((ip, ttl)) = result {
-        let expires = now_ms + (ttl as u64 * 1000).min(300_000); // cap at 5 min
-        let mut cache = DNS_CACHE.lock();
-        // Find best slot: prefer empty > same hostname > expired > oldest
-        let mut slot = 0;
-        let mut oldest_expires = u64::MAX;
-        for (i, entry) in cache.iter().enumerate() {
-            match entry {
-                None => { slot = i; break; }
-                Some(e) if e.hostname == hostname => { slot = i; break; }
-                Some(e) if e.expires_ms < now_ms => { slot = i; break; }
-                Some(e) => {
-                    if e.expires_ms < oldest_expires {
-                        oldest_expires = e.expires_ms;
-                        slot = i;
-                    }
+/// Remember an answer for up to its TTL (at most 5 minutes).
+fn cache_insert(hostname: &str, rtype: u16, ips: &[IpAddress], ttl: u32) {
+    let now_ms = crate::time::monotonic_ms();
+    let expires = now_ms + (ttl as u64 * 1000).min(300_000); // cap at 5 min
+    let mut cache = DNS_CACHE.lock();
+    // Find best slot: prefer empty > same hostname > expired > oldest
+    let mut slot = 0;
+    let mut oldest_expires = u64::MAX;
+    for (i, entry) in cache.iter().enumerate() {
+        match entry {
+            None => { slot = i; break; }
+            Some(e) if e.hostname == hostname && e.rtype == rtype => { slot = i; break; }
+            Some(e) if e.expires_ms < now_ms => { slot = i; break; }
+            Some(e) => {
+                if e.expires_ms < oldest_expires {
+                    oldest_expires = e.expires_ms;
+                    slot = i;
                 }
             }
         }
-        cache[slot] = Some(CacheEntry {
-            hostname: String::from(hostname),
-            ip,
-            expires_ms: expires,
-        });
-
-        Ok(ip)
-    } else {
-        result.map(|(ip, _)| ip)
     }
+    cache[slot] = Some(CacheEntry {
+        hostname: String::from(hostname),
+        rtype,
+        ips: ips.to_vec(),
+        expires_ms: expires,
+    });
 }
 
-/// Build a DNS query packet for an A record.
-fn build_query(hostname: &str) -> Result<alloc::vec::Vec<u8>, DnsError> {
+/// Build a DNS query packet for an A or AAAA record.
+fn build_query(hostname: &str, rtype: u16, id: u16) -> Result<alloc::vec::Vec<u8>, DnsError> {
     if hostname.is_empty() || hostname.len() > 253 {
         return Err(DnsError::InvalidHostname);
     }
@@ -170,8 +361,8 @@ fn build_query(hostname: &str) -> Result<alloc::vec::Vec<u8>, DnsError> {
     let mut pkt = alloc::vec::Vec::with_capacity(64);
 
     // Header (12 bytes)
-    // ID = 0x1234 (arbitrary, we only send one query at a time)
-    pkt.extend_from_slice(&[0x12, 0x34]);
+    // ID: tells the answers to simultaneous queries apart
+    pkt.extend_from_slice(&id.to_be_bytes());
     // Flags: standard query, recursion desired
     pkt.extend_from_slice(&[0x01, 0x00]);
     // QDCOUNT = 1
@@ -193,17 +384,17 @@ fn build_query(hostname: &str) -> Result<alloc::vec::Vec<u8>, DnsError> {
     }
     pkt.push(0x00); // root label
 
-    // QTYPE = A (1)
-    pkt.extend_from_slice(&[0x00, 0x01]);
+    // QTYPE = A (1) or AAAA (28)
+    pkt.extend_from_slice(&rtype.to_be_bytes());
     // QCLASS = IN (1)
     pkt.extend_from_slice(&[0x00, 0x01]);
 
     Ok(pkt)
 }
 
-/// Parse a DNS response and extract the first A record.
-/// Returns (ip, ttl_seconds).
-fn parse_response(data: &[u8], _hostname: &str) -> Result<(Ipv4Address, u32), DnsError> {
+/// Parse a DNS response and extract every record of type `rtype`.
+/// Returns (ips, lowest ttl_seconds).
+pub(crate) fn parse_response(data: &[u8], rtype: u16) -> Result<(Vec<IpAddress>, u32), DnsError> {
     if data.len() < 12 {
         return Err(DnsError::MalformedResponse);
     }
@@ -237,7 +428,9 @@ fn parse_response(data: &[u8], _hostname: &str) -> Result<(Ipv4Address, u32), Dn
         }
     }
 
-    // Parse answer section — find first A record
+    // Parse answer section — collect the records of the asked type
+    let mut ips = Vec::new();
+    let mut min_ttl = u32::MAX;
     for _ in 0..ancount {
         pos = skip_name(data, pos)?;
 
@@ -245,7 +438,7 @@ fn parse_response(data: &[u8], _hostname: &str) -> Result<(Ipv4Address, u32), Dn
             return Err(DnsError::MalformedResponse);
         }
 
-        let rtype = u16::from_be_bytes([data[pos], data[pos + 1]]);
+        let answer_type = u16::from_be_bytes([data[pos], data[pos + 1]]);
         let _rclass = u16::from_be_bytes([data[pos + 2], data[pos + 3]]);
         let ttl = u32::from_be_bytes([data[pos + 4], data[pos + 5], data[pos + 6], data[pos + 7]]);
         let rdlength = u16::from_be_bytes([data[pos + 8], data[pos + 9]]) as usize;
@@ -255,16 +448,27 @@ fn parse_response(data: &[u8], _hostname: &str) -> Result<(Ipv4Address, u32), Dn
             return Err(DnsError::MalformedResponse);
         }
 
-        if rtype == 1 && rdlength == 4 {
-            // A record
-            let ip = Ipv4Address::new(data[pos], data[pos + 1], data[pos + 2], data[pos + 3]);
-            return Ok((ip, ttl));
+        let ip = match (answer_type, rdlength) {
+            (TYPE_A, 4) if rtype == TYPE_A => Some(IpAddress::Ipv4(Ipv4Address::from_bytes(&data[pos..pos + 4]))),
+            (TYPE_AAAA, 16) if rtype == TYPE_AAAA => {
+                Some(IpAddress::Ipv6(Ipv6Address::from_bytes(&data[pos..pos + 16])))
+            }
+            _ => None,
+        };
+        if let Some(ip) = ip {
+            if !ips.contains(&ip) {
+                ips.push(ip);
+            }
+            min_ttl = min_ttl.min(ttl);
         }
 
         pos += rdlength;
     }
 
-    Err(DnsError::NoAnswer)
+    if ips.is_empty() {
+        return Err(DnsError::NoAnswer);
+    }
+    Ok((ips, min_ttl))
 }
 
 /// Skip a DNS name at the given position, handling compression pointers.
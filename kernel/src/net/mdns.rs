@@ -0,0 +1,498 @@
+/// mDNS responder (RFC 6762) with DNS-SD service records (RFC 6763).
+///
+/// Answers for `<hostname>.local` (A and AAAA) and for the services
+/// registered with `advertise`, so host-side tools can find the machine
+/// with `avahi-browse -r _heavenos._tcp` or `dns-sd -B _heavenos._tcp`
+/// instead of a hardcoded address. The name comes from the `hostname`
+/// config key (default `heavenos`).
+///
+/// `_heavenos._tcp` is always advertised, on port 0 like
+/// `_device-info._tcp`: it marks the machine and carries its version in
+/// TXT. Subsystems with a listening port (9P, httpd) add their own
+/// service type with `advertise`.
+///
+/// Only the IPv4 group is joined — smoltcp can't join IPv6 multicast
+/// groups — but AAAA answers carry the IPv6 addresses. Queries are read
+/// from the console's idle loop by `pump`. The records are announced at
+/// start and again when the name, an address or the service list
+/// changes; the name is not probed for, so two machines configured with
+/// the same hostname will both answer.
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use smoltcp::iface::SocketHandle;
+use smoltcp::socket::udp::{self, Socket as UdpSocket};
+use smoltcp::wire::{IpAddress, IpEndpoint, Ipv4Address, Ipv6Address};
+use spin::Mutex;
+
+use super::NetStack;
+
+pub const DEFAULT_HOSTNAME: &str = "heavenos";
+
+const MDNS_PORT: u16 = 5353;
+const MDNS_GROUP: Ipv4Address = Ipv4Address::new(224, 0, 0, 251);
+
+/// Minimum time between two `pump` runs.
+const POLL_MS: u64 = 20;
+
+/// Unsolicited announcements, one second apart (RFC 6762 §8.3).
+const ANNOUNCEMENTS: u8 = 2;
+const ANNOUNCE_INTERVAL_MS: u64 = 1000;
+
+/// Record TTLs: host-bound records vs. the rest (RFC 6762 §10), and the
+/// cap for answers to legacy unicast queries (§6.7).
+const HOST_TTL: u32 = 120;
+const OTHER_TTL: u32 = 4500;
+const LEGACY_TTL: u32 = 10;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+/// Class bit: in questions, "unicast response wanted"; in records,
+/// "flush cached records of this name and type".
+const CLASS_TOP_BIT: u16 = 0x8000;
+
+/// DNS-SD meta-query listing every service type.
+const SERVICES_META: &str = "_services._dns-sd._udp.local";
+
+/// A service type this machine offers.
+pub(crate) struct Service {
+    /// `_name._tcp` or `_name._udp`.
+    pub service: &'static str,
+    pub port: u16,
+    pub txt: Vec<String>,
+}
+
+static SERVICES: Mutex<Vec<Service>> = Mutex::new(Vec::new());
+
+struct Responder {
+    socket: SocketHandle,
+    hostname: String,
+    /// A previous hostname whose records still need a goodbye (TTL 0).
+    retired: Option<String>,
+    /// Addresses last announced.
+    announced: Vec<IpAddress>,
+    /// Announcements sent since the last change.
+    announcements: u8,
+    last_announce_ms: u64,
+    last_poll_ms: u64,
+}
+
+static RESPONDER: Mutex<Option<Responder>> = Mutex::new(None);
+
+/// Is `s` usable as the hostname: one DNS label of letters, digits and
+/// hyphens, not starting or ending with a hyphen?
+pub fn valid_hostname(s: &str) -> bool {
+    !s.is_empty()
+        && s.len() <= 63
+        && s.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        && !s.starts_with('-')
+        && !s.ends_with('-')
+}
+
+/// The configured hostname, or the default.
+pub fn hostname() -> String {
+    crate::sqlite::config_get("hostname")
+        .map(|v| String::from(v.trim()))
+        .filter(|v| valid_hostname(v))
+        .unwrap_or_else(|| String::from(DEFAULT_HOSTNAME))
+}
+
+/// Join the mDNS group, open the responder's socket and register
+/// `_heavenos._tcp`. False if the socket can't be bound.
+pub fn start(net: &mut NetStack) -> bool {
+    if !net.join_multicast(MDNS_GROUP) {
+        return false;
+    }
+    let rx_buf = udp::PacketBuffer::new(vec![udp::PacketMetadata::EMPTY; 4], vec![0u8; 4096]);
+    let tx_buf = udp::PacketBuffer::new(vec![udp::PacketMetadata::EMPTY; 4], vec![0u8; 4096]);
+    let mut socket = UdpSocket::new(rx_buf, tx_buf);
+    // Receivers drop mDNS packets that may have crossed a router
+    socket.set_hop_limit(Some(255));
+    let handle = net.add_udp_socket(socket);
+    if net.udp_bind(handle, MDNS_PORT).is_err() {
+        net.remove_socket(handle);
+        return false;
+    }
+    *RESPONDER.lock() = Some(Responder {
+        socket: handle,
+        hostname: hostname(),
+        retired: None,
+        announced: Vec::new(),
+        announcements: 0,
+        last_announce_ms: 0,
+        last_poll_ms: 0,
+    });
+    advertise("_heavenos._tcp", 0, vec![alloc::format!("version={}", env!("CARGO_PKG_VERSION"))]);
+    true
+}
+
+/// Pick up a changed `hostname` setting: say goodbye under the old name
+/// and announce the new one.
+pub fn configure() {
+    let name = hostname();
+    if let Some(r) = RESPONDER.lock().as_mut() {
+        if r.hostname != name {
+            r.retired = Some(core::mem::replace(&mut r.hostname, name));
+            r.announcements = 0;
+        }
+    }
+}
+
+/// The name answered for (`<hostname>.local`), if the responder runs.
+pub fn local_name() -> Option<String> {
+    RESPONDER.lock().as_ref().map(|r| alloc::format!("{}.local", r.hostname))
+}
+
+/// Offer a service type (`_name._tcp`) on `port`; replaces an earlier
+/// entry for the same type.
+pub fn advertise(service: &'static str, port: u16, txt: Vec<String>) {
+    {
+        let mut services = SERVICES.lock();
+        services.retain(|s| s.service != service);
+        services.push(Service { service, port, txt });
+    }
+    if let Some(r) = RESPONDER.lock().as_mut() {
+        r.announcements = 0;
+    }
+}
+
+/// Stop advertising `service` (a goodbye is not sent; its records age
+/// out of caches).
+pub fn withdraw(service: &'static str) {
+    let mut services = SERVICES.lock();
+    let before = services.len();
+    services.retain(|s| s.service != service);
+    if services.len() != before {
+        drop(services);
+        if let Some(r) = RESPONDER.lock().as_mut() {
+            r.announcements = 0;
+        }
+    }
+}
+
+/// Answer pending queries and send due announcements. Cheap when the
+/// responder isn't running or ran under `POLL_MS` ago; skipped while the
+/// network stack is in use.
+pub fn pump() {
+    let now = crate::time::monotonic_ms();
+    let Some(mut guard) = RESPONDER.try_lock() else { return };
+    let Some(r) = guard.as_mut() else { return };
+    if now.saturating_sub(r.last_poll_ms) < POLL_MS {
+        return;
+    }
+    r.last_poll_ms = now;
+    let Some(mut net_guard) = super::NET_STACK.try_lock() else { return };
+    let Some(net) = net_guard.as_mut() else { return };
+    net.poll();
+
+    let mut addrs = vec![IpAddress::Ipv4(net.ipv4().address())];
+    if let Some(cidr) = net.ipv6() {
+        addrs.push(IpAddress::Ipv6(cidr.address()));
+    }
+    addrs.push(IpAddress::Ipv6(net.ipv6_link_local()));
+    if addrs != r.announced {
+        r.announced = addrs.clone();
+        r.announcements = 0;
+    }
+
+    let services = SERVICES.lock();
+    let zone = Zone { hostname: &r.hostname, addrs: &addrs, services: &services };
+    let mut buf = [0u8; 1500];
+    while let Some((n, from)) = net.udp_recv_from(r.socket, &mut buf) {
+        if let Some((reply, to)) = answer(&zone, &buf[..n], from) {
+            let _ = net.udp_send(r.socket, &reply, to);
+        }
+    }
+
+    if !net.link_up() {
+        return;
+    }
+    let group = IpEndpoint::new(IpAddress::Ipv4(MDNS_GROUP), MDNS_PORT);
+    if let Some(old) = r.retired.take() {
+        let old_zone = Zone { hostname: &old, addrs: &addrs, services: &services };
+        let records = old_zone.records();
+        // The service types themselves are still offered
+        let all: Vec<&Record> = records.iter().filter(|rec| rec.name != SERVICES_META).collect();
+        let _ = net.udp_send(r.socket, &response(0, &[], &all, &[], Some(0), true), group);
+    }
+    if r.announcements < ANNOUNCEMENTS && now.saturating_sub(r.last_announce_ms) >= ANNOUNCE_INTERVAL_MS {
+        let records = zone.records();
+        let all: Vec<&Record> = records.iter().collect();
+        let _ = net.udp_send(r.socket, &response(0, &[], &all, &[], None, true), group);
+        r.announcements += 1;
+        r.last_announce_ms = now;
+    }
+}
+
+// ---- Records ----
+
+enum RData {
+    A(Ipv4Address),
+    Aaaa(Ipv6Address),
+    Ptr(String),
+    Srv { port: u16, target: String },
+    Txt(Vec<String>),
+}
+
+struct Record {
+    name: String,
+    rtype: u16,
+    /// Only this machine holds records of this name and type (sets the
+    /// cache-flush bit); PTRs are shared.
+    unique: bool,
+    ttl: u32,
+    data: RData,
+}
+
+/// Everything this machine answers for.
+pub(crate) struct Zone<'a> {
+    pub hostname: &'a str,
+    pub addrs: &'a [IpAddress],
+    pub services: &'a [Service],
+}
+
+impl Zone<'_> {
+    fn host(&self) -> String {
+        alloc::format!("{}.local", self.hostname)
+    }
+
+    fn records(&self) -> Vec<Record> {
+        let host = self.host();
+        let mut out = Vec::new();
+        for addr in self.addrs {
+            let data = match *addr {
+                IpAddress::Ipv4(a) => RData::A(a),
+                IpAddress::Ipv6(a) => RData::Aaaa(a),
+            };
+            let rtype = if matches!(data, RData::A(_)) { TYPE_A } else { TYPE_AAAA };
+            out.push(Record { name: host.clone(), rtype, unique: true, ttl: HOST_TTL, data });
+        }
+        for s in self.services {
+            let service = alloc::format!("{}.local", s.service);
+            let instance = alloc::format!("{}.{}", self.hostname, service);
+            out.push(Record {
+                name: String::from(SERVICES_META),
+                rtype: TYPE_PTR,
+                unique: false,
+                ttl: OTHER_TTL,
+                data: RData::Ptr(service.clone()),
+            });
+            out.push(Record {
+                name: service,
+                rtype: TYPE_PTR,
+                unique: false,
+                ttl: OTHER_TTL,
+                data: RData::Ptr(instance.clone()),
+            });
+            out.push(Record {
+                name: instance.clone(),
+                rtype: TYPE_SRV,
+                unique: true,
+                ttl: HOST_TTL,
+                data: RData::Srv { port: s.port, target: host.clone() },
+            });
+            out.push(Record {
+                name: instance,
+                rtype: TYPE_TXT,
+                unique: true,
+                ttl: OTHER_TTL,
+                data: RData::Txt(s.txt.clone()),
+            });
+        }
+        out
+    }
+}
+
+/// The reply to a query and where to send it; None if nothing in it is
+/// ours. Queries from a port other than 5353 are legacy unicast ones
+/// (RFC 6762 §6.7): answered directly, with the query's ID and questions.
+pub(crate) fn answer(zone: &Zone, msg: &[u8], from: IpEndpoint) -> Option<(Vec<u8>, IpEndpoint)> {
+    let query = parse_query(msg)?;
+    let records = zone.records();
+    let mut answers: Vec<usize> = Vec::new();
+    for q in &query.questions {
+        for (i, rec) in records.iter().enumerate() {
+            let wanted = q.qtype == rec.rtype || q.qtype == TYPE_ANY;
+            if wanted && rec.name.eq_ignore_ascii_case(&q.name) && !answers.contains(&i) {
+                answers.push(i);
+            }
+        }
+    }
+    if answers.is_empty() {
+        return None;
+    }
+
+    // What a browser asks next: an instance's SRV and TXT and the host's
+    // addresses after a PTR to it, the addresses after an SRV (RFC 6763 §12)
+    let host = zone.host();
+    let mut follow: Vec<&str> = Vec::new();
+    for &i in &answers {
+        match &records[i].data {
+            RData::Ptr(instance) if records[i].name != SERVICES_META => {
+                follow.push(instance);
+                follow.push(&host);
+            }
+            RData::Srv { target, .. } => follow.push(target),
+            _ => {}
+        }
+    }
+    let additional: Vec<&Record> = records
+        .iter()
+        .enumerate()
+        .filter(|(i, rec)| {
+            !answers.contains(i) && rec.rtype != TYPE_PTR && follow.iter().any(|f| rec.name.eq_ignore_ascii_case(f))
+        })
+        .map(|(_, rec)| rec)
+        .collect();
+    let answers: Vec<&Record> = answers.iter().map(|&i| &records[i]).collect();
+
+    if from.port != MDNS_PORT {
+        return Some((response(query.id, &query.questions, &answers, &[], Some(LEGACY_TTL), false), from));
+    }
+    let to = if query.unicast { from } else { IpEndpoint::new(IpAddress::Ipv4(MDNS_GROUP), MDNS_PORT) };
+    Some((response(0, &[], &answers, &additional, None, true), to))
+}
+
+// ---- Wire format ----
+
+struct Question {
+    name: String,
+    qtype: u16,
+}
+
+struct Query {
+    id: u16,
+    questions: Vec<Question>,
+    /// Some question asked for a unicast reply (the QU bit).
+    unicast: bool,
+}
+
+/// Parse the questions of a query; None for responses and junk.
+fn parse_query(msg: &[u8]) -> Option<Query> {
+    if msg.len() < 12 {
+        return None;
+    }
+    let id = u16::from_be_bytes([msg[0], msg[1]]);
+    let flags = u16::from_be_bytes([msg[2], msg[3]]);
+    // Responses, and queries with a non-zero opcode, are not for us
+    if flags & 0xF800 != 0 {
+        return None;
+    }
+    let qdcount = u16::from_be_bytes([msg[4], msg[5]]);
+    let mut pos = 12;
+    let mut query = Query { id, questions: Vec::new(), unicast: false };
+    for _ in 0..qdcount {
+        let (name, next) = read_name(msg, pos)?;
+        let fixed = msg.get(next..next + 4)?;
+        let qtype = u16::from_be_bytes([fixed[0], fixed[1]]);
+        let qclass = u16::from_be_bytes([fixed[2], fixed[3]]);
+        pos = next + 4;
+        query.unicast |= qclass & CLASS_TOP_BIT != 0;
+        if qclass & !CLASS_TOP_BIT == CLASS_IN || qclass & !CLASS_TOP_BIT == TYPE_ANY {
+            query.questions.push(Question { name, qtype });
+        }
+    }
+    Some(query)
+}
+
+/// Read a (possibly compressed) name at `pos`: the dotted name and the
+/// position after it.
+fn read_name(msg: &[u8], mut pos: usize) -> Option<(String, usize)> {
+    let mut name = String::new();
+    let mut end = None;
+    // Bounds pointer chains, including loops
+    for _ in 0..32 {
+        let len = *msg.get(pos)? as usize;
+        match len {
+            0 => return Some((name, end.unwrap_or(pos + 1))),
+            l if l & 0xC0 == 0xC0 => {
+                let target = (l & 0x3F) << 8 | *msg.get(pos + 1)? as usize;
+                end.get_or_insert(pos + 2);
+                pos = target;
+            }
+            l if l < 64 => {
+                let label = msg.get(pos + 1..pos + 1 + l)?;
+                if !name.is_empty() {
+                    name.push('.');
+                }
+                name.push_str(core::str::from_utf8(label).ok()?);
+                pos += 1 + l;
+            }
+            _ => return None,
+        }
+    }
+    None
+}
+
+fn put_name(out: &mut Vec<u8>, name: &str) {
+    for label in name.split('.').filter(|l| !l.is_empty()) {
+        let label = &label.as_bytes()[..label.len().min(63)];
+        out.push(label.len() as u8);
+        out.extend_from_slice(label);
+    }
+    out.push(0);
+}
+
+/// A response message. `ttl` overrides every record's TTL (0 = goodbye);
+/// `flush` sets the cache-flush bit on unique records.
+fn response(
+    id: u16,
+    questions: &[Question],
+    answers: &[&Record],
+    additional: &[&Record],
+    ttl: Option<u32>,
+    flush: bool,
+) -> Vec<u8> {
+    let mut out = Vec::with_capacity(512);
+    out.extend_from_slice(&id.to_be_bytes());
+    // QR (response) + AA (authoritative)
+    out.extend_from_slice(&0x8400u16.to_be_bytes());
+    out.extend_from_slice(&(questions.len() as u16).to_be_bytes());
+    out.extend_from_slice(&(answers.len() as u16).to_be_bytes());
+    out.extend_from_slice(&0u16.to_be_bytes());
+    out.extend_from_slice(&(additional.len() as u16).to_be_bytes());
+    for q in questions {
+        put_name(&mut out, &q.name);
+        out.extend_from_slice(&q.qtype.to_be_bytes());
+        out.extend_from_slice(&CLASS_IN.to_be_bytes());
+    }
+    for rec in answers.iter().chain(additional) {
+        put_name(&mut out, &rec.name);
+        out.extend_from_slice(&rec.rtype.to_be_bytes());
+        let class = if flush && rec.unique { CLASS_IN | CLASS_TOP_BIT } else { CLASS_IN };
+        out.extend_from_slice(&class.to_be_bytes());
+        out.extend_from_slice(&ttl.unwrap_or(rec.ttl).to_be_bytes());
+        let len_at = out.len();
+        out.extend_from_slice(&[0, 0]);
+        match &rec.data {
+            RData::A(a) => out.extend_from_slice(a.as_bytes()),
+            RData::Aaaa(a) => out.extend_from_slice(a.as_bytes()),
+            RData::Ptr(target) => put_name(&mut out, target),
+            RData::Srv { port, target } => {
+                // Priority, weight
+                out.extend_from_slice(&[0, 0, 0, 0]);
+                out.extend_from_slice(&port.to_be_bytes());
+                put_name(&mut out, target);
+            }
+            RData::Txt(strings) => {
+                // An empty TXT record still holds one empty string
+                if strings.is_empty() {
+                    out.push(0);
+                }
+                for s in strings {
+                    let s = &s.as_bytes()[..s.len().min(255)];
+                    out.push(s.len() as u8);
+                    out.extend_from_slice(s);
+                }
+            }
+        }
+        let rdlen = (out.len() - len_at - 2) as u16;
+        out[len_at..len_at + 2].copy_from_slice(&rdlen.to_be_bytes());
+    }
+    out
+}
@@ -8,10 +8,14 @@
 ///   smoltcp Interface (ARP, IP, TCP, UDP)
 ///       ↓ ↑
 ///   TCP sockets (used by HTTP client, TLS, etc.)
-///   UDP sockets (used by DNS resolver)
+///   UDP sockets (used by DNS resolver, syslog forwarding, mDNS responder)
 mod device;
 pub mod dns;
+pub mod mdns;
+pub mod slaac;
 pub mod stack;
+pub mod stats;
+pub mod syslog;
 pub mod tls;
 
 pub use stack::NetStack;
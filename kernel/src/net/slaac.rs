@@ -0,0 +1,156 @@
+/// IPv6 stateless address autoconfiguration (RFC 4862).
+///
+/// smoltcp answers neighbour discovery but ignores router advertisements,
+/// so they are picked out of received frames here: the device adapter
+/// passes every frame to `observe_rx`, which keeps the newest one for
+/// `NetStack::poll` to apply — an address from the advertised /64 and our
+/// EUI-64 interface identifier, a default route via the router, and the
+/// DNS server from its RDNSS option (RFC 8106). `router_solicit` builds
+/// the solicitation that asks for an advertisement instead of waiting
+/// for the next periodic one.
+use alloc::vec;
+use alloc::vec::Vec;
+use smoltcp::phy::ChecksumCapabilities;
+use smoltcp::wire::{
+    EthernetAddress, EthernetFrame, EthernetProtocol, EthernetRepr, Icmpv6Packet, Icmpv6Repr,
+    IpAddress, IpProtocol, Ipv6Address, Ipv6Packet, Ipv6Repr, NdiscRepr, RawHardwareAddress,
+};
+use spin::Mutex;
+
+use crate::drivers::virtio::net::ETH_HDR_SIZE;
+
+const IPV6_HDR_SIZE: usize = 40;
+const ICMPV6_ROUTER_ADVERT: u8 = 134;
+const OPT_PREFIX_INFO: u8 = 3;
+const OPT_RDNSS: u8 = 25;
+/// Prefix information flag: usable for autonomous configuration.
+const PREFIX_AUTONOMOUS: u8 = 0x40;
+
+/// What a router advertisement told us.
+#[derive(Debug, Clone, Copy)]
+pub struct RouterAdvert {
+    /// The router's (link-local) address.
+    pub router: Ipv6Address,
+    /// Seconds the router may be used as default router; 0 = not a default.
+    pub router_lifetime_s: u16,
+    /// Autoconfiguration /64 prefix and its valid lifetime in seconds.
+    pub prefix: Option<(Ipv6Address, u32)>,
+    /// Recursive DNS server.
+    pub dns: Option<Ipv6Address>,
+}
+
+static LATEST: Mutex<Option<RouterAdvert>> = Mutex::new(None);
+
+/// Keep a received frame if it is a router advertisement.
+pub(crate) fn observe_rx(frame: &[u8]) {
+    if let Some(ra) = parse_router_advert(frame) {
+        *LATEST.lock() = Some(ra);
+    }
+}
+
+/// The advertisement received since the last call, if any.
+pub(crate) fn take() -> Option<RouterAdvert> {
+    LATEST.lock().take()
+}
+
+/// Parse a router advertisement. Only ones sent with hop limit 255 from a
+/// link-local (fe80::/10) address are taken: a router can't send those
+/// from off-link (RFC 4861 §6.1.2).
+pub(crate) fn parse_router_advert(frame: &[u8]) -> Option<RouterAdvert> {
+    if frame.len() < ETH_HDR_SIZE + IPV6_HDR_SIZE + 16 || frame[12..14] != [0x86, 0xDD] {
+        return None;
+    }
+    let ip = &frame[ETH_HDR_SIZE..];
+    let payload_len = u16::from_be_bytes([ip[4], ip[5]]) as usize;
+    if ip[0] >> 4 != 6 || ip[6] != 58 || ip[7] != 255 || IPV6_HDR_SIZE + payload_len > ip.len() {
+        return None;
+    }
+    if ip[8] != 0xFE || ip[9] & 0xC0 != 0x80 {
+        return None;
+    }
+    let icmp = &ip[IPV6_HDR_SIZE..IPV6_HDR_SIZE + payload_len];
+    if icmp.len() < 16 || icmp[0] != ICMPV6_ROUTER_ADVERT || icmp[1] != 0 {
+        return None;
+    }
+    let mut ra = RouterAdvert {
+        router: Ipv6Address::from_bytes(&ip[8..24]),
+        router_lifetime_s: u16::from_be_bytes([icmp[6], icmp[7]]),
+        prefix: None,
+        dns: None,
+    };
+    let mut opts = &icmp[16..];
+    while opts.len() >= 8 {
+        let len = opts[1] as usize * 8;
+        if len == 0 || len > opts.len() {
+            break;
+        }
+        let opt = &opts[..len];
+        match opt[0] {
+            OPT_PREFIX_INFO if len >= 32 && ra.prefix.is_none() => {
+                let valid = u32::from_be_bytes([opt[4], opt[5], opt[6], opt[7]]);
+                if opt[2] == 64 && opt[3] & PREFIX_AUTONOMOUS != 0 && valid > 0 {
+                    ra.prefix = Some((Ipv6Address::from_bytes(&opt[16..32]), valid));
+                }
+            }
+            OPT_RDNSS if len >= 24 && ra.dns.is_none() => {
+                ra.dns = Some(Ipv6Address::from_bytes(&opt[8..24]));
+            }
+            _ => {}
+        }
+        opts = &opts[len..];
+    }
+    Some(ra)
+}
+
+/// Modified EUI-64 interface identifier of a MAC address.
+fn interface_id(mac: [u8; 6]) -> [u8; 8] {
+    [mac[0] ^ 0x02, mac[1], mac[2], 0xFF, 0xFE, mac[3], mac[4], mac[5]]
+}
+
+/// `prefix` (its first 64 bits) with our interface identifier.
+pub fn address(prefix: Ipv6Address, mac: [u8; 6]) -> Ipv6Address {
+    let mut bytes = [0u8; 16];
+    bytes[..8].copy_from_slice(&prefix.as_bytes()[..8]);
+    bytes[8..].copy_from_slice(&interface_id(mac));
+    Ipv6Address::from_bytes(&bytes)
+}
+
+/// fe80::/64 address for `mac`.
+pub fn link_local(mac: [u8; 6]) -> Ipv6Address {
+    address(Ipv6Address::new(0xFE80, 0, 0, 0, 0, 0, 0, 0), mac)
+}
+
+/// An Ethernet frame soliciting router advertisements from all routers.
+pub fn router_solicit(mac: [u8; 6]) -> Vec<u8> {
+    let src = link_local(mac);
+    let dst = Ipv6Address::LINK_LOCAL_ALL_ROUTERS;
+    let icmp = Icmpv6Repr::Ndisc(NdiscRepr::RouterSolicit {
+        lladdr: Some(RawHardwareAddress::from(EthernetAddress(mac))),
+    });
+    let ip = Ipv6Repr {
+        src_addr: src,
+        dst_addr: dst,
+        next_header: IpProtocol::Icmpv6,
+        payload_len: icmp.buffer_len(),
+        hop_limit: 255,
+    };
+    let eth = EthernetRepr {
+        src_addr: EthernetAddress(mac),
+        // 33:33 + the low 32 bits of ff02::2
+        dst_addr: EthernetAddress([0x33, 0x33, 0, 0, 0, 2]),
+        ethertype: EthernetProtocol::Ipv6,
+    };
+
+    let mut buf = vec![0u8; eth.buffer_len() + ip.buffer_len() + icmp.buffer_len()];
+    let mut frame = EthernetFrame::new_unchecked(&mut buf[..]);
+    eth.emit(&mut frame);
+    let mut packet = Ipv6Packet::new_unchecked(frame.payload_mut());
+    ip.emit(&mut packet);
+    icmp.emit(
+        &IpAddress::Ipv6(src),
+        &IpAddress::Ipv6(dst),
+        &mut Icmpv6Packet::new_unchecked(packet.payload_mut()),
+        &ChecksumCapabilities::default(),
+    );
+    buf
+}
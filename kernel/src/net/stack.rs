@@ -2,27 +2,109 @@
 ///
 /// Provides:
 /// - DHCP for automatic IP configuration
-/// - TCP socket creation and I/O
-/// - UDP socket creation and I/O (for DNS)
+/// - TCP socket creation and I/O, and listening sockets (for 9P)
+/// - UDP socket creation and I/O (for DNS, syslog, mDNS)
+/// - IPv4 multicast group membership (for mDNS)
+/// - IPv6 with stateless autoconfiguration (see `slaac`)
+/// - Link-state tracking
+///
+/// The interface starts on QEMU user-mode networking's static IPv4
+/// addresses and switches to a DHCP lease when one arrives. IPv6 starts
+/// with the fe80:: link-local address; a router advertisement adds a
+/// global address, a default route and a DNS server.
+///
+/// `poll` watches the NIC's carrier. When the link drops, the default
+/// route is removed and every open TCP socket is aborted; blocked readers
+/// and writers then fail with `TcpError::LinkDown` instead of waiting out
+/// their timeouts. When the link returns, the routes come back and DHCP
+/// and router solicitation start over, since the network on the other
+/// end may have changed.
+///
+/// TCP sockets are removed from the set once closed with `tcp_close` and
+/// finished (Closed or TimeWait); their counters live in `stats`.
 use alloc::vec;
+use alloc::vec::Vec;
 use core::sync::atomic::{AtomicU16, Ordering};
 
 use smoltcp::iface::{Config, Interface, SocketSet, SocketHandle};
-use smoltcp::socket::tcp::{self, Socket as TcpSocket};
+use smoltcp::socket::dhcpv4;
+use smoltcp::socket::tcp::{self, Socket as TcpSocket, State as TcpState};
 use smoltcp::socket::udp::Socket as UdpSocket;
+use smoltcp::socket::Socket;
 use smoltcp::time::Instant;
-use smoltcp::wire::{EthernetAddress, IpAddress, IpCidr, IpEndpoint, Ipv4Address, Ipv4Cidr};
+use smoltcp::wire::{
+    EthernetAddress, IpAddress, IpCidr, IpEndpoint, Ipv4Address, Ipv4Cidr, Ipv6Address, Ipv6Cidr,
+};
 
 use super::device::SmoltcpDevice;
+use super::slaac::{self, RouterAdvert};
+use super::stats::{self, ConnStats};
 
 /// Monotonic ephemeral port counter (wraps within 49152..65535 range).
 static EPHEMERAL_PORT: AtomicU16 = AtomicU16::new(49152);
 
+/// QEMU user-mode networking defaults, used until DHCP answers:
+/// guest = 10.0.2.15, gateway = 10.0.2.2, DNS = 10.0.2.3.
+const QEMU_ADDR: Ipv4Cidr = Ipv4Cidr::new(Ipv4Address::new(10, 0, 2, 15), 24);
+const QEMU_GATEWAY: Ipv4Address = Ipv4Address::new(10, 0, 2, 2);
+const QEMU_DNS: Ipv4Address = Ipv4Address::new(10, 0, 2, 3);
+
+/// How often `poll` reads the NIC's link state.
+const LINK_POLL_MS: u64 = 100;
+
+/// Router solicitations sent before waiting for periodic advertisements,
+/// and the gap between them (RFC 4861 §10).
+const MAX_SOLICITS: u8 = 3;
+const SOLICIT_INTERVAL_MS: u64 = 4_000;
+
 /// Network stack state.
 pub struct NetStack {
     device: SmoltcpDevice,
     iface: Interface,
     sockets: SocketSet<'static>,
+    /// DHCP client socket.
+    dhcp: SocketHandle,
+    /// Address, gateway and DNS server in use.
+    addr: Ipv4Cidr,
+    gateway: Option<Ipv4Address>,
+    dns_server: Ipv4Address,
+    /// The addresses above came from a DHCP lease.
+    leased: bool,
+    mac: [u8; 6],
+    /// SLAAC address and when it stops being valid (monotonic ms).
+    ipv6: Option<(Ipv6Cidr, u64)>,
+    /// Default IPv6 router, from router advertisements, and when its
+    /// advertised lifetime runs out (monotonic ms).
+    gateway6: Option<(Ipv6Address, u64)>,
+    /// IPv6 DNS server, from router advertisements.
+    dns_server6: Option<Ipv6Address>,
+    /// Router solicitations sent since boot or the link last came up.
+    solicits: u8,
+    solicit_at_ms: u64,
+    /// Carrier state as of the last check.
+    link: bool,
+    /// When the link state was last read (monotonic ms).
+    link_checked_ms: u64,
+    /// Link up/down transitions since boot.
+    link_changes: u32,
+    /// TCP sockets aborted because the link went down.
+    aborted: Vec<SocketHandle>,
+    /// TCP sockets closed by their owner, removed once finished.
+    closing: Vec<SocketHandle>,
+}
+
+/// A socket as `netstat` shows it.
+#[derive(Debug, Clone, Copy)]
+pub struct SocketInfo {
+    pub proto: &'static str,
+    pub local: Option<IpEndpoint>,
+    /// Bound port, for sockets without a full local endpoint (UDP).
+    pub local_port: u16,
+    pub remote: Option<IpEndpoint>,
+    /// TCP state; None for UDP.
+    pub state: Option<TcpState>,
+    /// TCP counters, if the connection is tracked.
+    pub stats: Option<ConnStats>,
 }
 
 impl NetStack {
@@ -35,48 +117,307 @@ impl NetStack {
         let config = Config::new(EthernetAddress(mac).into());
         let mut iface = Interface::new(config, &mut device, Self::now());
 
-        // Static IP for QEMU user-mode networking:
-        // QEMU's default: guest = 10.0.2.15, gateway = 10.0.2.2, DNS = 10.0.2.3
         iface.update_ip_addrs(|addrs| {
-            addrs.push(IpCidr::Ipv4(Ipv4Cidr::new(
-                Ipv4Address::new(10, 0, 2, 15),
-                24,
-            ))).ok();
+            addrs.push(IpCidr::Ipv4(QEMU_ADDR)).ok();
+            addrs.push(IpCidr::Ipv6(Ipv6Cidr::new(slaac::link_local(mac), 64))).ok();
         });
+        iface.routes_mut().add_default_ipv4_route(QEMU_GATEWAY).ok();
 
-        iface.routes_mut().add_default_ipv4_route(
-            Ipv4Address::new(10, 0, 2, 2),  // QEMU gateway
-        ).ok();
-
-        let sockets = SocketSet::new(vec![]);
+        let mut sockets = SocketSet::new(vec![]);
+        let dhcp = sockets.add(dhcpv4::Socket::new());
+        let link = device.link_up();
 
         Some(Self {
             device,
             iface,
             sockets,
+            dhcp,
+            addr: QEMU_ADDR,
+            gateway: Some(QEMU_GATEWAY),
+            dns_server: QEMU_DNS,
+            leased: false,
+            mac,
+            ipv6: None,
+            gateway6: None,
+            dns_server6: None,
+            solicits: 0,
+            solicit_at_ms: 0,
+            link,
+            link_checked_ms: crate::time::monotonic_ms(),
+            link_changes: 0,
+            aborted: Vec::new(),
+            closing: Vec::new(),
         })
     }
 
     /// Get the current timestamp for smoltcp (calibrated TSC).
     fn now() -> Instant {
-        let ms = crate::arch::x86_64::timer::monotonic_ms();
+        let ms = crate::time::monotonic_ms();
         Instant::from_millis(ms as i64)
     }
 
     /// Poll the network stack — process incoming packets and advance
     /// TCP state machines. Must be called regularly.
     pub fn poll(&mut self) {
+        let now_ms = crate::time::monotonic_ms();
+        if now_ms.saturating_sub(self.link_checked_ms) >= LINK_POLL_MS {
+            self.check_link();
+        }
         let timestamp = Self::now();
         self.iface.poll(timestamp, &mut self.device, &mut self.sockets);
+        self.poll_dhcp();
+        self.poll_ipv6(now_ms);
+        if !self.closing.is_empty() {
+            self.reap();
+        }
+    }
+
+    /// Remove closed TCP sockets that have finished shutting down.
+    fn reap(&mut self) {
+        let sockets = &mut self.sockets;
+        self.closing.retain(|&handle| {
+            let done = matches!(sockets.get::<TcpSocket>(handle).state(), TcpState::Closed | TcpState::TimeWait);
+            if done {
+                sockets.remove(handle);
+                stats::forget(handle);
+            }
+            !done
+        });
+    }
+
+    /// Every TCP and UDP socket, for `netstat`.
+    pub fn sockets(&self) -> Vec<SocketInfo> {
+        let mut out = Vec::new();
+        for (handle, socket) in self.sockets.iter() {
+            match socket {
+                Socket::Tcp(tcp) => out.push(SocketInfo {
+                    proto: "tcp",
+                    local: tcp.local_endpoint(),
+                    local_port: tcp.local_endpoint().map_or(0, |ep| ep.port),
+                    remote: tcp.remote_endpoint(),
+                    state: Some(tcp.state()),
+                    stats: stats::get(handle),
+                }),
+                Socket::Udp(udp) => out.push(SocketInfo {
+                    proto: "udp",
+                    local: None,
+                    local_port: udp.endpoint().port,
+                    remote: None,
+                    state: None,
+                    stats: None,
+                }),
+                _ => {}
+            }
+        }
+        out
+    }
+
+    // ---- Link state ----
+
+    /// Is the link up? Reads the NIC now rather than trusting the last poll.
+    pub fn link_up(&mut self) -> bool {
+        self.check_link();
+        self.link
+    }
+
+    /// Link up/down transitions since boot.
+    pub fn link_changes(&self) -> u32 {
+        self.link_changes
+    }
+
+    /// Read the carrier and act on a change.
+    fn check_link(&mut self) {
+        self.link_checked_ms = crate::time::monotonic_ms();
+        let up = self.device.link_up();
+        if up == self.link {
+            return;
+        }
+        self.link = up;
+        self.link_changes = self.link_changes.saturating_add(1);
+        if up {
+            crate::serial_println!("[net] link up, renewing DHCP lease");
+            if let Some(gw) = self.gateway {
+                self.iface.routes_mut().add_default_ipv4_route(gw).ok();
+            }
+            if let Some((gw, _)) = self.gateway6 {
+                self.iface.routes_mut().add_default_ipv6_route(gw).ok();
+            }
+            self.sockets.get_mut::<dhcpv4::Socket>(self.dhcp).reset();
+            self.solicits = 0;
+            self.solicit_at_ms = 0;
+        } else {
+            crate::serial_println!("[net] link down, aborting open connections");
+            self.iface.routes_mut().remove_default_ipv4_route();
+            self.iface.routes_mut().remove_default_ipv6_route();
+            for (handle, socket) in self.sockets.iter_mut() {
+                if let Socket::Tcp(tcp) = socket {
+                    if tcp.is_open() {
+                        tcp.abort();
+                        self.aborted.push(handle);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Was this socket aborted because the link went down?
+    pub fn tcp_aborted(&self, handle: SocketHandle) -> bool {
+        self.aborted.contains(&handle)
+    }
+
+    // ---- Addressing ----
+
+    /// Apply a DHCP lease, or fall back to the QEMU defaults when it ends.
+    fn poll_dhcp(&mut self) {
+        let lease = match self.sockets.get_mut::<dhcpv4::Socket>(self.dhcp).poll() {
+            None => return,
+            Some(dhcpv4::Event::Configured(config)) => {
+                Some((config.address, config.router, config.dns_servers.first().copied()))
+            }
+            Some(dhcpv4::Event::Deconfigured) => None,
+        };
+        let (addr, gateway, dns) = lease.unwrap_or((QEMU_ADDR, Some(QEMU_GATEWAY), Some(QEMU_DNS)));
+        self.leased = lease.is_some();
+        self.addr = addr;
+        self.gateway = gateway;
+        self.dns_server = dns.unwrap_or(QEMU_DNS);
+        self.iface.update_ip_addrs(|addrs| {
+            addrs.retain(|a| !matches!(a, IpCidr::Ipv4(_)));
+            addrs.push(IpCidr::Ipv4(addr)).ok();
+        });
+        self.iface.routes_mut().remove_default_ipv4_route();
+        if let (Some(gw), true) = (gateway, self.link) {
+            self.iface.routes_mut().add_default_ipv4_route(gw).ok();
+        }
+        if self.leased {
+            crate::serial_println!("[net] DHCP lease {} via {}", addr,
+                gateway.map_or(alloc::string::String::from("no gateway"), |gw| alloc::format!("{}", gw)));
+        }
+    }
+
+    /// Solicit routers while none has answered, and drop the SLAAC
+    /// address and the default router once their lifetimes run out.
+    fn poll_ipv6(&mut self, now_ms: u64) {
+        if let Some(ra) = slaac::take() {
+            self.apply_router_advert(ra, now_ms);
+        }
+        if self.link
+            && self.gateway6.is_none()
+            && self.solicits < MAX_SOLICITS
+            && (self.solicits == 0 || now_ms.saturating_sub(self.solicit_at_ms) >= SOLICIT_INTERVAL_MS)
+        {
+            self.device.send_raw(&slaac::router_solicit(self.mac));
+            self.solicits += 1;
+            self.solicit_at_ms = now_ms;
+        }
+        if let Some((addr, expires_ms)) = self.ipv6 {
+            if now_ms >= expires_ms {
+                crate::serial_println!("[net] IPv6 address {} expired", addr);
+                self.ipv6 = None;
+                self.set_ipv6_addr(None);
+            }
+        }
+        if let Some((gw, expires_ms)) = self.gateway6 {
+            if now_ms >= expires_ms {
+                crate::serial_println!("[net] IPv6 router {} expired", gw);
+                self.set_gateway6(None);
+                // Look for another
+                self.solicits = 0;
+            }
+        }
+    }
+
+    fn apply_router_advert(&mut self, ra: RouterAdvert, now_ms: u64) {
+        if let Some((prefix, valid_s)) = ra.prefix {
+            let addr = Ipv6Cidr::new(slaac::address(prefix, self.mac), 64);
+            let changed = self.ipv6.is_none_or(|(old, _)| old != addr);
+            self.ipv6 = Some((addr, now_ms.saturating_add(valid_s as u64 * 1000)));
+            if changed {
+                self.set_ipv6_addr(Some(addr));
+                crate::serial_println!("[net] IPv6 address {} via {}", addr, ra.router);
+            }
+        }
+        let expires_ms = now_ms.saturating_add(ra.router_lifetime_s as u64 * 1000);
+        self.set_gateway6((ra.router_lifetime_s > 0).then_some((ra.router, expires_ms)));
+        if ra.dns.is_some() {
+            self.dns_server6 = ra.dns;
+        }
+    }
+
+    /// Replace the default IPv6 router, re-routing only if it changed.
+    fn set_gateway6(&mut self, gateway: Option<(Ipv6Address, u64)>) {
+        let changed = gateway.map(|(gw, _)| gw) != self.gateway6.map(|(gw, _)| gw);
+        self.gateway6 = gateway;
+        if changed {
+            self.iface.routes_mut().remove_default_ipv6_route();
+            if let (Some((gw, _)), true) = (gateway, self.link) {
+                self.iface.routes_mut().add_default_ipv6_route(gw).ok();
+            }
+        }
+    }
+
+    /// Replace the global IPv6 address; the link-local one stays.
+    fn set_ipv6_addr(&mut self, addr: Option<Ipv6Cidr>) {
+        let link_local = slaac::link_local(self.mac);
+        self.iface.update_ip_addrs(|addrs| {
+            addrs.retain(|a| match a {
+                IpCidr::Ipv6(cidr) => cidr.address() == link_local,
+                _ => true,
+            });
+            if let Some(addr) = addr {
+                addrs.push(IpCidr::Ipv6(addr)).ok();
+            }
+        });
+    }
+
+    /// Interface address and prefix.
+    pub fn ipv4(&self) -> Ipv4Cidr {
+        self.addr
+    }
+
+    pub fn gateway(&self) -> Option<Ipv4Address> {
+        self.gateway
+    }
+
+    /// DNS server from the lease, or QEMU's forwarder.
+    pub fn dns_server(&self) -> Ipv4Address {
+        self.dns_server
+    }
+
+    /// Global IPv6 address from SLAAC, if a router has advertised a prefix.
+    pub fn ipv6(&self) -> Option<Ipv6Cidr> {
+        self.ipv6.map(|(addr, _)| addr)
+    }
+
+    pub fn ipv6_link_local(&self) -> Ipv6Address {
+        slaac::link_local(self.mac)
+    }
+
+    pub fn gateway6(&self) -> Option<Ipv6Address> {
+        self.gateway6.map(|(gw, _)| gw)
+    }
+
+    /// IPv6 DNS server from a router advertisement (RDNSS).
+    pub fn dns_server6(&self) -> Option<Ipv6Address> {
+        self.dns_server6
+    }
+
+    /// Did the addressing come from DHCP (rather than the static defaults)?
+    pub fn dhcp_leased(&self) -> bool {
+        self.leased
     }
 
     /// Open a TCP connection to the given IP and port.
-    /// Returns a socket handle for reading/writing.
+    /// Returns a socket handle for reading/writing, or None (also when the
+    /// link is down).
     pub fn tcp_connect(
         &mut self,
-        remote_ip: Ipv4Address,
+        remote_ip: IpAddress,
         remote_port: u16,
     ) -> Option<SocketHandle> {
+        if !self.link_up() {
+            return None;
+        }
         let rx_buf = tcp::SocketBuffer::new(vec![0u8; 65536]);
         let tx_buf = tcp::SocketBuffer::new(vec![0u8; 65536]);
         let socket = TcpSocket::new(rx_buf, tx_buf);
@@ -90,29 +431,45 @@ impl NetStack {
         let socket = self.sockets.get_mut::<TcpSocket>(handle);
         socket.connect(
             self.iface.context(),
-            (IpAddress::Ipv4(remote_ip), remote_port),
+            (remote_ip, remote_port),
             local_port,
         ).ok()?;
+        stats::open(handle, local_port);
+
+        Some(handle)
+    }
 
+    /// Open a TCP socket listening on `port`; it takes one connection.
+    pub fn tcp_listen(&mut self, port: u16) -> Option<SocketHandle> {
+        let rx_buf = tcp::SocketBuffer::new(vec![0u8; 65536]);
+        let tx_buf = tcp::SocketBuffer::new(vec![0u8; 65536]);
+        let mut socket = TcpSocket::new(rx_buf, tx_buf);
+        socket.listen(port).ok()?;
+        let handle = self.sockets.add(socket);
+        stats::open(handle, port);
         Some(handle)
     }
 
+    /// Is a listening socket still waiting for its connection?
+    pub fn tcp_is_listening(&mut self, handle: SocketHandle) -> bool {
+        let socket = self.sockets.get_mut::<TcpSocket>(handle);
+        socket.is_listening()
+    }
+
     /// Write data to a TCP socket.
     pub fn tcp_send(&mut self, handle: SocketHandle, data: &[u8]) -> usize {
         let socket = self.sockets.get_mut::<TcpSocket>(handle);
-        match socket.send_slice(data) {
-            Ok(n) => n,
-            Err(_) => 0,
-        }
+        let n = socket.send_slice(data).unwrap_or(0);
+        stats::sent(handle, n);
+        n
     }
 
     /// Read data from a TCP socket.
     pub fn tcp_recv(&mut self, handle: SocketHandle, buf: &mut [u8]) -> usize {
         let socket = self.sockets.get_mut::<TcpSocket>(handle);
-        match socket.recv_slice(buf) {
-            Ok(n) => n,
-            Err(_) => 0,
-        }
+        let n = socket.recv_slice(buf).unwrap_or(0);
+        stats::received(handle, n);
+        n
     }
 
     /// Check if a TCP socket is connected and ready for I/O.
@@ -133,10 +490,23 @@ impl NetStack {
         socket.can_recv()
     }
 
-    /// Close a TCP socket.
+    /// Close a TCP socket. The socket is removed once the close
+    /// completes, so the handle must not be used afterwards. Closing
+    /// again is a no-op, both while the close is under way and after the
+    /// socket is gone — unless a new socket has since taken its slot.
     pub fn tcp_close(&mut self, handle: SocketHandle) {
+        if self.closing.contains(&handle) || !self.has_tcp(handle) {
+            return;
+        }
+        self.aborted.retain(|&h| h != handle);
         let socket = self.sockets.get_mut::<TcpSocket>(handle);
         socket.close();
+        self.closing.push(handle);
+    }
+
+    /// Is `handle` a TCP socket still in the set (not yet reaped)?
+    fn has_tcp(&self, handle: SocketHandle) -> bool {
+        self.sockets.iter().any(|(h, socket)| h == handle && matches!(socket, Socket::Tcp(_)))
     }
 
     /// Poll until a condition is true, with a timeout.
@@ -155,7 +525,7 @@ impl NetStack {
             if elapsed as u64 > timeout_ms {
                 return false;
             }
-            core::hint::spin_loop();
+            crate::arch::x86_64::idle::wait();
         }
     }
 
@@ -187,15 +557,27 @@ impl NetStack {
 
     /// Receive a UDP datagram. Returns the number of bytes received, or None.
     pub fn udp_recv(&mut self, handle: SocketHandle, buf: &mut [u8]) -> Option<usize> {
+        self.udp_recv_from(handle, buf).map(|(n, _)| n)
+    }
+
+    /// Receive a UDP datagram and the endpoint it came from.
+    pub fn udp_recv_from(&mut self, handle: SocketHandle, buf: &mut [u8]) -> Option<(usize, IpEndpoint)> {
         let socket = self.sockets.get_mut::<UdpSocket>(handle);
         match socket.recv_slice(buf) {
-            Ok((n, _endpoint)) => Some(n),
+            Ok((n, meta)) => Some((n, meta.endpoint)),
             Err(_) => None,
         }
     }
 
+    /// Receive datagrams sent to an IPv4 multicast group (announced with
+    /// IGMP).
+    pub fn join_multicast(&mut self, group: Ipv4Address) -> bool {
+        self.iface.join_multicast_group(&mut self.device, group, Self::now()).is_ok()
+    }
+
     /// Remove a socket from the socket set.
     pub fn remove_socket(&mut self, handle: SocketHandle) {
+        self.aborted.retain(|&h| h != handle);
         self.sockets.remove(handle);
     }
 
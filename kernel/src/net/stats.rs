@@ -0,0 +1,291 @@
+/// Per-connection TCP statistics, for `netstat` and `/sys/sockets`.
+///
+/// smoltcp keeps its congestion state private, so retransmits and RTT are
+/// measured here from the wire: the device adapter shows every frame to
+/// `observe_tx`/`observe_rx`, which follow each connection's sequence
+/// space. A segment starting below the highest sequence number already
+/// sent is a retransmit; the time from sending new data to the ACK that
+/// covers it is an RTT sample (Karn's rule: none across a retransmit),
+/// smoothed as in RFC 6298.
+///
+/// What the connection is doing above TCP (TLS handshake, sending the
+/// request, reading the response) is reported by its owner with
+/// `set_phase`, so a stalled request shows where it stalled.
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use smoltcp::iface::SocketHandle;
+use spin::Mutex;
+
+use crate::drivers::virtio::net::ETH_HDR_SIZE;
+
+/// Connections tracked at most; the longest-idle one makes room.
+const MAX_CONNS: usize = 32;
+
+const TCP_SYN: u8 = 0x02;
+const TCP_FIN: u8 = 0x01;
+const TCP_ACK: u8 = 0x10;
+
+/// What a connection's owner is doing with it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    TlsHandshake,
+    Request,
+    Response,
+}
+
+impl Phase {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Phase::TlsHandshake => "tls",
+            Phase::Request => "request",
+            Phase::Response => "response",
+        }
+    }
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// One TCP connection's counters.
+#[derive(Debug, Clone, Copy)]
+pub struct ConnStats {
+    pub handle: SocketHandle,
+    pub local_port: u16,
+    pub opened_ms: u64,
+    pub phase: Option<Phase>,
+    /// Application bytes moved through the socket.
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    /// Segments sent again.
+    pub retransmits: u32,
+    /// Smoothed round-trip time.
+    pub srtt_ms: Option<u32>,
+    /// Last segment carrying data (or SYN/FIN) from the peer.
+    pub last_rx_ms: u64,
+    /// Highest sequence number sent + 1 (None until the first segment).
+    snd_max: Option<u32>,
+    /// Segment being timed: (sequence number that ACKs it, sent at).
+    sample: Option<(u32, u64)>,
+}
+
+static CONNS: Mutex<Vec<ConnStats>> = Mutex::new(Vec::new());
+
+fn now_ms() -> u64 {
+    crate::time::monotonic_ms()
+}
+
+/// Start tracking a connection from `local_port`.
+pub(crate) fn open(handle: SocketHandle, local_port: u16) {
+    let now = now_ms();
+    let mut conns = CONNS.lock();
+    conns.retain(|c| c.handle != handle && c.local_port != local_port);
+    if conns.len() >= MAX_CONNS {
+        if let Some(idle) = conns.iter().enumerate().min_by_key(|(_, c)| c.last_rx_ms).map(|(i, _)| i) {
+            conns.remove(idle);
+        }
+    }
+    conns.push(ConnStats {
+        handle,
+        local_port,
+        opened_ms: now,
+        phase: None,
+        bytes_in: 0,
+        bytes_out: 0,
+        retransmits: 0,
+        srtt_ms: None,
+        last_rx_ms: now,
+        snd_max: None,
+        sample: None,
+    });
+}
+
+/// Stop tracking a connection (its socket is gone).
+pub(crate) fn forget(handle: SocketHandle) {
+    CONNS.lock().retain(|c| c.handle != handle);
+}
+
+/// Record what the connection's owner is doing now.
+pub fn set_phase(handle: SocketHandle, phase: Phase) {
+    if let Some(c) = CONNS.lock().iter_mut().find(|c| c.handle == handle) {
+        c.phase = Some(phase);
+    }
+}
+
+pub(super) fn sent(handle: SocketHandle, n: usize) {
+    if let Some(c) = CONNS.lock().iter_mut().find(|c| c.handle == handle) {
+        c.bytes_out += n as u64;
+    }
+}
+
+pub(super) fn received(handle: SocketHandle, n: usize) {
+    if let Some(c) = CONNS.lock().iter_mut().find(|c| c.handle == handle) {
+        c.bytes_in += n as u64;
+    }
+}
+
+/// Counters of a connection.
+pub(crate) fn get(handle: SocketHandle) -> Option<ConnStats> {
+    CONNS.lock().iter().find(|c| c.handle == handle).copied()
+}
+
+/// The TCP header fields `observe_*` need.
+struct Segment {
+    src_port: u16,
+    dst_port: u16,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    /// Sequence space used: payload + SYN + FIN.
+    len: u32,
+}
+
+/// Parse the TCP segment of an IPv4 or IPv6 Ethernet frame.
+fn segment(frame: &[u8]) -> Option<Segment> {
+    if frame.len() < ETH_HDR_SIZE + 20 {
+        return None;
+    }
+    let ip = &frame[ETH_HDR_SIZE..];
+    let (hdr, total, proto) = match frame[12..14] {
+        [0x08, 0x00] => {
+            let ihl = (ip[0] & 0x0F) as usize * 4;
+            (ihl, u16::from_be_bytes([ip[2], ip[3]]) as usize, ip[9])
+        }
+        [0x86, 0xDD] if ip.len() >= 40 => (40, 40 + u16::from_be_bytes([ip[4], ip[5]]) as usize, ip[6]),
+        _ => return None,
+    };
+    let total = total.min(ip.len());
+    if proto != 6 || hdr < 20 || total < hdr + 20 {
+        return None;
+    }
+    let tcp = &ip[hdr..total];
+    let data_off = (tcp[12] >> 4) as usize * 4;
+    if data_off < 20 || data_off > tcp.len() {
+        return None;
+    }
+    let flags = tcp[13];
+    let len = (tcp.len() - data_off) as u32
+        + (flags & TCP_SYN != 0) as u32
+        + (flags & TCP_FIN != 0) as u32;
+    Some(Segment {
+        src_port: u16::from_be_bytes([tcp[0], tcp[1]]),
+        dst_port: u16::from_be_bytes([tcp[2], tcp[3]]),
+        seq: u32::from_be_bytes([tcp[4], tcp[5], tcp[6], tcp[7]]),
+        ack: u32::from_be_bytes([tcp[8], tcp[9], tcp[10], tcp[11]]),
+        flags,
+        len,
+    })
+}
+
+/// `a` comes after `b` in sequence space.
+fn seq_after(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) > 0
+}
+
+/// A frame we are sending.
+pub(crate) fn observe_tx(frame: &[u8]) {
+    let Some(seg) = segment(frame) else { return };
+    if seg.len == 0 {
+        return; // bare ACK: nothing to time or retransmit
+    }
+    let now = now_ms();
+    let mut conns = CONNS.lock();
+    let Some(c) = conns.iter_mut().find(|c| c.local_port == seg.src_port) else { return };
+    let end = seg.seq.wrapping_add(seg.len);
+    match c.snd_max {
+        // Starts below what was already sent: a retransmit
+        Some(max) if seq_after(max, seg.seq) => {
+            c.retransmits += 1;
+            c.sample = None;
+            if seq_after(end, max) {
+                c.snd_max = Some(end);
+            }
+        }
+        _ => {
+            c.snd_max = Some(end);
+            if c.sample.is_none() {
+                c.sample = Some((end, now));
+            }
+        }
+    }
+}
+
+/// A frame we received.
+pub(crate) fn observe_rx(frame: &[u8]) {
+    let Some(seg) = segment(frame) else { return };
+    let now = now_ms();
+    let mut conns = CONNS.lock();
+    let Some(c) = conns.iter_mut().find(|c| c.local_port == seg.dst_port) else { return };
+    if seg.len > 0 {
+        c.last_rx_ms = now;
+    }
+    if seg.flags & TCP_ACK == 0 {
+        return;
+    }
+    if let Some((until, sent)) = c.sample {
+        if !seq_after(until, seg.ack) {
+            let rtt = now.saturating_sub(sent).min(u32::MAX as u64) as u32;
+            c.srtt_ms = Some(match c.srtt_ms {
+                Some(srtt) => ((7 * srtt as u64 + rtt as u64) / 8) as u32,
+                None => rtt,
+            });
+            c.sample = None;
+        }
+    }
+}
+
+/// Every socket as a table, for `netstat` and `/sys/sockets`.
+pub fn report() -> Vec<u8> {
+    // Read over Styx while a request holds the stack (`listen::pump_held`)
+    let Some(guard) = super::NET_STACK.try_lock() else {
+        return b"network stack in use\n".to_vec();
+    };
+    let sockets = match guard.as_ref() {
+        Some(net) => net.sockets(),
+        None => return b"network not initialized\n".to_vec(),
+    };
+    drop(guard);
+    let now = now_ms();
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "{:<5} {:<21} {:<21} {:<12} {:<8} {:>9} {:>9} {:>4} {:>6} {:>7}",
+        "proto", "local", "remote", "state", "phase", "in", "out", "retx", "rtt", "idle"
+    );
+    for s in &sockets {
+        let local = match s.local {
+            Some(ep) => alloc::format!("{}", ep),
+            None => alloc::format!("*:{}", s.local_port),
+        };
+        let remote = s.remote.map_or(String::from("*"), |ep| alloc::format!("{}", ep));
+        let state = s.state.map_or(String::from("-"), |st| alloc::format!("{}", st));
+        let Some(c) = s.stats else {
+            let _ = writeln!(out, "{:<5} {:<21} {:<21} {:<12}", s.proto, local, remote, state);
+            continue;
+        };
+        let rtt = c.srtt_ms.map_or(String::from("-"), |ms| alloc::format!("{}ms", ms));
+        let idle_ms = now.saturating_sub(c.last_rx_ms);
+        let _ = writeln!(
+            out,
+            "{:<5} {:<21} {:<21} {:<12} {:<8} {:>9} {:>9} {:>4} {:>6} {:>5}.{}s",
+            s.proto,
+            local,
+            remote,
+            state,
+            c.phase.map_or("-", |p| p.as_str()),
+            c.bytes_in,
+            c.bytes_out,
+            c.retransmits,
+            rtt,
+            idle_ms / 1000,
+            idle_ms % 1000 / 100,
+        );
+    }
+    if sockets.is_empty() {
+        out.push_str("(no sockets)\n");
+    }
+    out.into_bytes()
+}
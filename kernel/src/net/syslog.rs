@@ -0,0 +1,303 @@
+/// Forward kernel log lines and audit rows to a syslog collector.
+///
+/// With `config set syslog.host 10.0.2.2` (or `host:port`, `[v6]:port`;
+/// default port 514) each line captured in the kernel log ring is sent as
+/// an RFC 5424 message over UDP (RFC 5426), so the QEMU host can collect
+/// logs without scraping the serial console. `syslog.audit on` forwards
+/// new audit-table rows as well.
+///
+/// Sending happens from the console's idle loop: `pump` runs at most every
+/// `PUMP_MS`, picks up what was logged since the last run and hands it to
+/// smoltcp. UDP gives no delivery guarantee and none is attempted —
+/// messages the socket has no room for are counted as dropped.
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use smoltcp::iface::SocketHandle;
+use smoltcp::socket::udp::{self, Socket as UdpSocket};
+use smoltcp::wire::{IpAddress, IpEndpoint};
+use spin::Mutex;
+
+use super::NetStack;
+use crate::sqlite::{SqlValue, DB};
+
+pub const DEFAULT_PORT: u16 = 514;
+
+/// Minimum time between two `pump` runs.
+const PUMP_MS: u64 = 1000;
+
+/// Audit rows forwarded per run, so a burst can't stall the console.
+const AUDIT_BATCH: usize = 32;
+
+/// Longest message text sent; collectors need only take 480 bytes and
+/// should take 2048 (RFC 5426 §3.2), so keep well inside that.
+const MAX_MSG: usize = 1024;
+
+/// Datagrams the socket can queue.
+const TX_PACKETS: usize = 16;
+
+/// Facilities (RFC 5424 §6.2.1).
+const FACILITY_KERN: u8 = 0;
+const FACILITY_AUDIT: u8 = 13;
+
+/// Severities.
+const SEVERITY_ERR: u8 = 3;
+const SEVERITY_WARNING: u8 = 4;
+const SEVERITY_INFO: u8 = 6;
+
+/// Forwarding state; None while `syslog.host` is unset.
+struct Forwarder {
+    target: IpEndpoint,
+    audit: bool,
+    socket: Option<SocketHandle>,
+    /// `klog::written()` up to which lines have been sent.
+    klog_cursor: usize,
+    /// Highest audit row id already sent.
+    audit_after: i64,
+}
+
+static FORWARDER: Mutex<Option<Forwarder>> = Mutex::new(None);
+
+static LAST_PUMP_MS: AtomicU64 = AtomicU64::new(0);
+static SENT: AtomicU64 = AtomicU64::new(0);
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/// Parse a `syslog.host` value: `ip`, `ip:port` or `[ipv6]:port`.
+pub fn parse_target(s: &str) -> Option<IpEndpoint> {
+    let s = s.trim();
+    let (host, port) = match s.rsplit_once(':') {
+        // A bare IPv6 address has colons but no port
+        Some((host, port)) if !s.ends_with(']') && (s.starts_with('[') || !host.contains(':')) => {
+            (host, Some(port))
+        }
+        _ => (s, None),
+    };
+    let addr: IpAddress = host.trim_start_matches('[').trim_end_matches(']').parse().ok()?;
+    let port = match port {
+        Some(p) => p.parse().ok().filter(|&p| p != 0)?,
+        None => DEFAULT_PORT,
+    };
+    if addr.is_unspecified() {
+        return None;
+    }
+    Some(IpEndpoint::new(addr, port))
+}
+
+/// (Re)read `syslog.host` and `syslog.audit`. Forwarding starts from
+/// the start of the kernel log and from the next audit row.
+pub fn configure() {
+    let target = crate::sqlite::config_get("syslog.host").and_then(|v| parse_target(&v));
+    let audit = crate::sqlite::config_get("syslog.audit").as_deref() == Some("on");
+    let old = FORWARDER.lock().take();
+    if let Some(handle) = old.and_then(|f| f.socket) {
+        if let Some(net) = super::NET_STACK.lock().as_mut() {
+            net.remove_socket(handle);
+        }
+    }
+    let Some(target) = target else { return };
+    *FORWARDER.lock() = Some(Forwarder {
+        target,
+        audit,
+        socket: None,
+        klog_cursor: 0,
+        audit_after: last_audit_id(),
+    });
+}
+
+/// Where messages go, if forwarding is on.
+pub fn target() -> Option<IpEndpoint> {
+    FORWARDER.lock().as_ref().map(|f| f.target)
+}
+
+/// Messages handed to the network, and messages dropped, since boot.
+pub fn counters() -> (u64, u64) {
+    (SENT.load(Ordering::Relaxed), DROPPED.load(Ordering::Relaxed))
+}
+
+/// Send what has been logged since the last run. Cheap when forwarding
+/// is off or the last run was under `PUMP_MS` ago; skipped while the
+/// network stack is in use.
+pub fn pump() {
+    let now = crate::time::monotonic_ms();
+    if now.saturating_sub(LAST_PUMP_MS.load(Ordering::Relaxed)) < PUMP_MS {
+        return;
+    }
+    LAST_PUMP_MS.store(now, Ordering::Relaxed);
+
+    let Some(mut guard) = FORWARDER.try_lock() else { return };
+    let Some(fwd) = guard.as_mut() else { return };
+    let Some(mut net_guard) = super::NET_STACK.try_lock() else { return };
+    let Some(net) = net_guard.as_mut() else { return };
+    if !net.link_up() {
+        return;
+    }
+
+    let mut messages = Vec::new();
+    let (lines, cursor) = crate::klog::lines_since(fwd.klog_cursor);
+    fwd.klog_cursor = cursor;
+    for line in &lines {
+        messages.push(kernel_message(line));
+    }
+    if fwd.audit {
+        for row in new_audit_rows(fwd.audit_after) {
+            fwd.audit_after = fwd.audit_after.max(row.id);
+            messages.push(audit_message(&row));
+        }
+    }
+    if messages.is_empty() {
+        return;
+    }
+
+    let handle = match fwd.socket {
+        Some(handle) => handle,
+        None => match open_socket(net) {
+            Some(handle) => *fwd.socket.insert(handle),
+            None => {
+                DROPPED.fetch_add(messages.len() as u64, Ordering::Relaxed);
+                return;
+            }
+        },
+    };
+    for msg in &messages {
+        match net.udp_send(handle, msg, fwd.target) {
+            Ok(()) => SENT.fetch_add(1, Ordering::Relaxed),
+            Err(()) => DROPPED.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+    // Put them on the wire now, not whenever the stack is next polled
+    net.poll();
+}
+
+fn open_socket(net: &mut NetStack) -> Option<SocketHandle> {
+    let rx_buf = udp::PacketBuffer::new(vec![udp::PacketMetadata::EMPTY; 1], vec![0u8; 64]);
+    let tx_buf = udp::PacketBuffer::new(
+        vec![udp::PacketMetadata::EMPTY; TX_PACKETS],
+        vec![0u8; TX_PACKETS * (MAX_MSG + 128)],
+    );
+    let handle = net.add_udp_socket(UdpSocket::new(rx_buf, tx_buf));
+    let port = net.next_ephemeral_port();
+    if net.udp_bind(handle, port).is_err() {
+        net.remove_socket(handle);
+        return None;
+    }
+    Some(handle)
+}
+
+/// An RFC 5424 message: `<PRI>1 TIMESTAMP HOST APP PROCID MSGID SD MSG`.
+/// `-` is the nil value; with no timestamp the collector stamps arrival.
+fn format(facility: u8, severity: u8, timestamp: &str, app: &str, msgid: &str, msg: &str) -> Vec<u8> {
+    let mut end = msg.len().min(MAX_MSG);
+    while !msg.is_char_boundary(end) {
+        end -= 1;
+    }
+    alloc::format!(
+        "<{}>1 {} heavenos {} - {} - {}",
+        facility as u16 * 8 + severity as u16,
+        timestamp,
+        header_field(app, 48),
+        header_field(msgid, 32),
+        &msg[..end],
+    )
+    .into_bytes()
+}
+
+/// A header field: printable ASCII without spaces, at most `max` long.
+fn header_field(s: &str, max: usize) -> String {
+    let field: String = s.chars().filter(|c| c.is_ascii_graphic()).take(max).collect();
+    if field.is_empty() { String::from("-") } else { field }
+}
+
+/// A kernel log line. Its `[tag]` prefix, if any, becomes the MSGID.
+fn kernel_message(line: &str) -> Vec<u8> {
+    let (msgid, text) = match line.strip_prefix('[').and_then(|l| l.split_once(']')) {
+        Some((tag, rest)) if !tag.contains(' ') => (tag, rest.trim_start()),
+        _ => ("-", line),
+    };
+    format(FACILITY_KERN, SEVERITY_INFO, "-", "kernel", msgid, text)
+}
+
+struct AuditRow {
+    id: i64,
+    ts: i64,
+    level: String,
+    agent: String,
+    action: String,
+    target: String,
+    detail: String,
+}
+
+/// An audit row: the agent is the app, the action the MSGID.
+fn audit_message(row: &AuditRow) -> Vec<u8> {
+    let severity = match row.level.as_str() {
+        "ERROR" => SEVERITY_ERR,
+        "WARN" | "WARNING" => SEVERITY_WARNING,
+        _ => SEVERITY_INFO,
+    };
+    let msg = if row.detail.is_empty() {
+        row.target.clone()
+    } else {
+        alloc::format!("{}: {}", row.target, row.detail)
+    };
+    format(FACILITY_AUDIT, severity, &timestamp(row.ts), &row.agent, &row.action, &msg)
+}
+
+fn last_audit_id() -> i64 {
+    let guard = DB.lock();
+    let Some(db) = guard.as_ref() else { return 0 };
+    db.query_value("SELECT MAX(id) FROM audit")
+        .ok()
+        .flatten()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0)
+}
+
+fn new_audit_rows(after: i64) -> Vec<AuditRow> {
+    let Some(guard) = DB.try_lock() else { return Vec::new() };
+    let Some(db) = guard.as_ref() else { return Vec::new() };
+    let sql = alloc::format!(
+        "SELECT id, ts, level, agent, action, target, detail FROM audit \
+         WHERE id > ? ORDER BY id LIMIT {}",
+        AUDIT_BATCH
+    );
+    let Ok(result) = db.query_params(&sql, &[SqlValue::Integer(after)]) else {
+        return Vec::new();
+    };
+    let text = |v: &SqlValue| String::from(v.as_str().unwrap_or(""));
+    result
+        .rows
+        .iter()
+        .filter(|r| r.len() == 7)
+        .map(|r| AuditRow {
+            id: r[0].as_integer().unwrap_or(after),
+            ts: r[1].as_integer().unwrap_or(0),
+            level: text(&r[2]),
+            agent: text(&r[3]),
+            action: text(&r[4]),
+            target: text(&r[5]),
+            detail: text(&r[6]),
+        })
+        .collect()
+}
+
+/// Unix seconds as an RFC 3339 UTC timestamp; `-` if unknown.
+fn timestamp(unix: i64) -> String {
+    if unix <= 0 {
+        return String::from("-");
+    }
+    let (days, secs) = (unix / 86_400, unix % 86_400);
+    // Civil date from days since 1970-01-01 (H. Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    alloc::format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year, month, day, secs / 3600, secs % 3600 / 60, secs % 60
+    )
+}
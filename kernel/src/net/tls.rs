@@ -2,15 +2,76 @@
 ///
 /// Bridges smoltcp's poll-based TCP API to the `embedded_io::Read` / `Write`
 /// traits required by `embedded-tls`.
+///
+/// `TlsClient` is the TLS side: one per server name, it connects, runs
+/// the handshake and hands back a `TlsSession`. When the server name
+/// resolved to several addresses, it races connections to them ("happy
+/// eyeballs", RFC 8305) and uses whichever completes first.
+///
+/// Each session needs two 16 KiB record buffers; `TlsBuffers` lends them
+/// from a small pool so back-to-back requests, to the same server or
+/// different ones, reuse the memory instead of going to the heap each
+/// time. Buffers are wiped before they go back — the write buffer held
+/// plaintext requests, API keys included.
+///
+/// embedded-tls offers exactly one cipher suite per ClientHello, so the
+/// suite is picked before connecting: `tls.cipher` if set, otherwise
+/// AES-128-GCM on CPUs with AES-NI and ChaCha20-Poly1305 (several times
+/// faster in software) on those without. embedded-tls brings the AES-GCM
+/// suites; ChaCha20-Poly1305 is plugged in through its `TlsCipherSuite`
+/// trait below.
+///
+/// Each `connect` leaves a `Handshake` summary (what was offered, what
+/// came of it, how long it took) for `api trace`.
+///
+/// ALPN is not supported, and there is no setting for it: embedded-tls
+/// 0.18 builds its ClientHello without the extension and offers no way
+/// to add one. A proxy that insists on ALPN "http/1.1" refuses the
+/// handshake; the summary's `alpn` line says none was sent, so a trace
+/// of the failure shows why.
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use embedded_tls::blocking::TlsConnection;
+use embedded_tls::{
+    Aes128GcmSha256, Aes256GcmSha384, TlsCipherSuite, TlsConfig, TlsContext, TlsError, UnsecureProvider,
+};
+use sha2::digest::typenum::{U12, U32, U54};
+use sha2::Sha256;
 use smoltcp::iface::SocketHandle;
+use smoltcp::wire::{IpAddress, IpEndpoint};
+use spin::Mutex;
 
 use super::stack::NetStack;
+use super::stats::{set_phase, Phase};
+use crate::crypto::zeroize::Zeroize;
+use crate::crypto::KernelRng;
+use crate::error::{KernelError, Layer};
+
+/// Record buffer size: the largest TLS record (16 KiB of plaintext plus
+/// header and AEAD expansion).
+pub const RECORD_BUF_SIZE: usize = 16640;
+
+/// Idle buffers kept for reuse (two per session).
+const POOL_MAX: usize = 4;
+
+/// How long the TCP handshake may take.
+const CONNECT_TIMEOUT_MS: u64 = 10_000;
+
+/// Time before the next address is tried while earlier attempts are
+/// still pending (RFC 8305 §5 recommends 250 ms).
+const ATTEMPT_DELAY_MS: u64 = 250;
 
 /// Error type for TCP stream operations.
 #[derive(Debug)]
 pub enum TcpError {
     Closed,
     Timeout,
+    /// The link went down and the connection was aborted.
+    LinkDown,
+    /// The stream's abort check said to stop (Ctrl-C).
+    Interrupted,
 }
 
 impl core::fmt::Display for TcpError {
@@ -18,17 +79,27 @@ impl core::fmt::Display for TcpError {
         match self {
             TcpError::Closed => write!(f, "connection closed"),
             TcpError::Timeout => write!(f, "connection timeout"),
+            TcpError::LinkDown => write!(f, "network link down"),
+            TcpError::Interrupted => write!(f, "interrupted"),
         }
     }
 }
 
 impl core::error::Error for TcpError {}
 
+impl From<TcpError> for KernelError {
+    fn from(e: TcpError) -> Self {
+        KernelError::new(Layer::Net, alloc::format!("{:?}", e), e)
+    }
+}
+
 impl embedded_io::Error for TcpError {
     fn kind(&self) -> embedded_io::ErrorKind {
         match self {
             TcpError::Closed => embedded_io::ErrorKind::ConnectionReset,
             TcpError::Timeout => embedded_io::ErrorKind::TimedOut,
+            TcpError::LinkDown => embedded_io::ErrorKind::ConnectionAborted,
+            TcpError::Interrupted => embedded_io::ErrorKind::Interrupted,
         }
     }
 }
@@ -40,11 +111,40 @@ impl embedded_io::Error for TcpError {
 pub struct TcpStream<'a> {
     pub(crate) net: &'a mut NetStack,
     pub(crate) handle: SocketHandle,
+    /// `monotonic_ms` past which reads and writes time out, however
+    /// recently data moved.
+    pub(crate) deadline: Option<u64>,
+    /// Polled while waiting; true fails the read or write with
+    /// `Interrupted`.
+    pub(crate) abort: Option<fn() -> bool>,
+    /// Run with the stack while waiting, before the abort check.
+    pub(crate) on_wait: Option<fn(&mut NetStack)>,
 }
 
 impl<'a> TcpStream<'a> {
     pub fn new(net: &'a mut NetStack, handle: SocketHandle) -> Self {
-        Self { net, handle }
+        Self { net, handle, deadline: None, abort: None, on_wait: None }
+    }
+
+    /// Has the abort check fired? Runs `on_wait` first.
+    fn aborted(&mut self) -> bool {
+        if let Some(on_wait) = self.on_wait {
+            on_wait(self.net);
+        }
+        self.abort.is_some_and(|abort| abort())
+    }
+
+    /// When to give up: 30 seconds without progress, or the deadline.
+    fn give_up_at(&self) -> u64 {
+        let idle = crate::time::monotonic_ms() + 30_000;
+        self.deadline.map_or(idle, |d| d.min(idle))
+    }
+}
+
+/// Closing on drop lets the stack reclaim the socket once the peer is done.
+impl Drop for TcpStream<'_> {
+    fn drop(&mut self) {
+        self.net.tcp_close(self.handle);
     }
 }
 
@@ -54,7 +154,7 @@ impl embedded_io::ErrorType for TcpStream<'_> {
 
 impl embedded_io::Read for TcpStream<'_> {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
-        let start = crate::arch::x86_64::timer::monotonic_ms();
+        let give_up = self.give_up_at();
         loop {
             self.net.poll();
             if self.net.tcp_can_recv(self.handle) {
@@ -63,22 +163,26 @@ impl embedded_io::Read for TcpStream<'_> {
                     return Ok(n);
                 }
             }
+            if self.net.tcp_aborted(self.handle) {
+                return Err(TcpError::LinkDown);
+            }
             if !self.net.tcp_is_active(self.handle) {
                 return Ok(0); // EOF
             }
-            // 30 second timeout
-            let elapsed = crate::arch::x86_64::timer::monotonic_ms() - start;
-            if elapsed > 30_000 {
+            if crate::time::monotonic_ms() > give_up {
                 return Err(TcpError::Timeout);
             }
-            core::hint::spin_loop();
+            if self.aborted() {
+                return Err(TcpError::Interrupted);
+            }
+            crate::arch::x86_64::idle::wait();
         }
     }
 }
 
 impl embedded_io::Write for TcpStream<'_> {
     fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
-        let start = crate::arch::x86_64::timer::monotonic_ms();
+        let give_up = self.give_up_at();
         loop {
             self.net.poll();
             if self.net.tcp_can_send(self.handle) {
@@ -87,14 +191,19 @@ impl embedded_io::Write for TcpStream<'_> {
                     return Ok(n);
                 }
             }
+            if self.net.tcp_aborted(self.handle) {
+                return Err(TcpError::LinkDown);
+            }
             if !self.net.tcp_is_active(self.handle) {
                 return Err(TcpError::Closed);
             }
-            let elapsed = crate::arch::x86_64::timer::monotonic_ms() - start;
-            if elapsed > 30_000 {
+            if crate::time::monotonic_ms() > give_up {
                 return Err(TcpError::Timeout);
             }
-            core::hint::spin_loop();
+            if self.aborted() {
+                return Err(TcpError::Interrupted);
+            }
+            crate::arch::x86_64::idle::wait();
         }
     }
 
@@ -103,3 +212,445 @@ impl embedded_io::Write for TcpStream<'_> {
         Ok(())
     }
 }
+
+// ---- TLS client ----
+
+/// Why `TlsClient::connect` failed.
+#[derive(Debug)]
+pub enum ConnectError {
+    /// No TCP socket (link down, or no route to the address).
+    NoSocket,
+    /// Every address refused or reset the connection.
+    Refused,
+    /// The TCP handshake did not complete in time.
+    Timeout,
+    /// The TLS handshake failed.
+    Handshake(TlsError),
+}
+
+impl core::fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ConnectError::NoSocket => write!(f, "TCP connection failed"),
+            ConnectError::Refused => write!(f, "connection refused"),
+            ConnectError::Timeout => write!(f, "connection timeout"),
+            ConnectError::Handshake(e) => write!(f, "TLS handshake failed: {:?}", e),
+        }
+    }
+}
+
+/// TLS 1.3 cipher suites we can offer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Suite {
+    Aes128Gcm,
+    Aes256Gcm,
+    Chacha20Poly1305,
+}
+
+impl Suite {
+    /// A `tls.cipher` value.
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "aes128-gcm" => Some(Suite::Aes128Gcm),
+            "aes256-gcm" => Some(Suite::Aes256Gcm),
+            "chacha20-poly1305" => Some(Suite::Chacha20Poly1305),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Suite::Aes128Gcm => "aes128-gcm",
+            Suite::Aes256Gcm => "aes256-gcm",
+            Suite::Chacha20Poly1305 => "chacha20-poly1305",
+        }
+    }
+
+    /// The IANA name, as servers and packet captures show it.
+    pub fn iana_name(&self) -> &'static str {
+        match self {
+            Suite::Aes128Gcm => "TLS_AES_128_GCM_SHA256",
+            Suite::Aes256Gcm => "TLS_AES_256_GCM_SHA384",
+            Suite::Chacha20Poly1305 => "TLS_CHACHA20_POLY1305_SHA256",
+        }
+    }
+
+    /// The fastest suite on this CPU.
+    pub fn for_cpu() -> Self {
+        if crate::arch::x86_64::cpu::has_aesni() {
+            Suite::Aes128Gcm
+        } else {
+            Suite::Chacha20Poly1305
+        }
+    }
+
+    /// The suite `tls.cipher` picks.
+    pub fn configured() -> Self {
+        Self::from_setting(crate::sqlite::config_get("tls.cipher").as_deref())
+    }
+
+    /// The suite for a `tls.cipher` value: `auto`, like no value (or one
+    /// `config` would have refused), picks `for_cpu`.
+    pub fn from_setting(value: Option<&str>) -> Self {
+        match value.map(str::trim) {
+            None | Some("auto") => Self::for_cpu(),
+            Some(name) => Self::parse(name).unwrap_or_else(Self::for_cpu),
+        }
+    }
+}
+
+/// TLS_CHACHA20_POLY1305_SHA256 (RFC 8446 §B.4).
+pub struct Chacha20Poly1305Sha256;
+
+impl TlsCipherSuite for Chacha20Poly1305Sha256 {
+    const CODE_POINT: u16 = 0x1303;
+    type Cipher = chacha20poly1305::ChaCha20Poly1305;
+    type KeyLen = U32;
+    type IvLen = U12;
+
+    type Hash = Sha256;
+    /// Hash output + the longest HKDF label (12) + label overhead (10),
+    /// as embedded-tls sizes it for its own suites.
+    type LabelBufferSize = U54;
+}
+
+static POOL: Mutex<Vec<Vec<u8>>> = Mutex::new(Vec::new());
+
+/// A read and a write record buffer, lent from the pool for one session.
+/// Dropping them wipes them and returns them to the pool.
+pub struct TlsBuffers {
+    read: Vec<u8>,
+    write: Vec<u8>,
+}
+
+impl TlsBuffers {
+    pub fn take() -> Self {
+        Self { read: Self::one(), write: Self::one() }
+    }
+
+    fn one() -> Vec<u8> {
+        match POOL.lock().pop() {
+            Some(mut buf) => {
+                buf.resize(RECORD_BUF_SIZE, 0);
+                buf
+            }
+            None => vec![0u8; RECORD_BUF_SIZE],
+        }
+    }
+
+    /// The read and write buffers.
+    pub fn slices(&mut self) -> (&mut [u8], &mut [u8]) {
+        (&mut self.read, &mut self.write)
+    }
+
+    /// Buffers idle in the pool.
+    pub fn pooled() -> usize {
+        POOL.lock().len()
+    }
+}
+
+impl Drop for TlsBuffers {
+    fn drop(&mut self) {
+        for buf in [&mut self.read, &mut self.write] {
+            let mut buf = core::mem::take(buf);
+            buf.zeroize();
+            let mut pool = POOL.lock();
+            if pool.len() < POOL_MAX {
+                pool.push(buf);
+            }
+        }
+    }
+}
+
+/// The parameters of one handshake, as far as embedded-tls lets us see
+/// them. Only one version and suite are offered, so those are what a
+/// successful handshake negotiated.
+#[derive(Debug, Clone)]
+pub struct Handshake {
+    pub server_name: String,
+    /// The address connected to (the first tried, if none was).
+    pub peer: IpEndpoint,
+    /// Addresses raced for the connection, in the order they were tried.
+    pub candidates: Vec<IpAddress>,
+    pub version: &'static str,
+    pub cipher_suite: &'static str,
+    /// TCP plus TLS handshake time.
+    pub elapsed_ms: u64,
+    /// Why it failed; None if it succeeded.
+    pub error: Option<String>,
+}
+
+impl core::fmt::Display for Handshake {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        writeln!(f, "server name: {}", self.server_name)?;
+        writeln!(f, "peer:        {}", self.peer)?;
+        if self.candidates.len() > 1 {
+            write!(f, "addresses:  ")?;
+            for ip in &self.candidates {
+                write!(f, " {}", ip)?;
+            }
+            writeln!(f)?;
+        }
+        writeln!(f, "version:     {}", self.version)?;
+        writeln!(f, "cipher:      {}", self.cipher_suite)?;
+        writeln!(f, "alpn:        not sent (unsupported)")?;
+        writeln!(f, "time:        {} ms", self.elapsed_ms)?;
+        match &self.error {
+            None => write!(f, "result:      ok"),
+            Some(e) => write!(f, "result:      failed: {}", e),
+        }
+    }
+}
+
+/// TLS to one server name (SNI, and the name the certificate is for).
+pub struct TlsClient {
+    server_name: String,
+    suite: Suite,
+    last: Option<Handshake>,
+    deadline: Option<u64>,
+    abort: Option<fn() -> bool>,
+    on_wait: Option<fn(&mut NetStack)>,
+}
+
+impl TlsClient {
+    /// A client offering the `Suite::configured` cipher suite.
+    pub fn new(server_name: &str) -> Self {
+        Self {
+            server_name: String::from(server_name),
+            suite: Suite::configured(),
+            last: None,
+            deadline: None,
+            abort: None,
+            on_wait: None,
+        }
+    }
+
+    /// Offer `suite` instead.
+    pub fn with_suite(mut self, suite: Suite) -> Self {
+        self.suite = suite;
+        self
+    }
+
+    /// Fail the handshake and every read and write of the session once
+    /// `monotonic_ms` passes `at_ms`.
+    pub fn with_deadline(mut self, at_ms: u64) -> Self {
+        self.deadline = Some(at_ms);
+        self
+    }
+
+    /// Poll `abort` while the handshake and the session wait on the
+    /// network; true fails them with `TcpError::Interrupted`.
+    pub fn with_abort(mut self, abort: Option<fn() -> bool>) -> Self {
+        self.abort = abort;
+        self
+    }
+
+    /// Run `on_wait` with the stack whenever the handshake or the session
+    /// waits on the network, e.g. to serve other sockets meanwhile.
+    pub fn with_on_wait(mut self, on_wait: Option<fn(&mut NetStack)>) -> Self {
+        self.on_wait = on_wait;
+        self
+    }
+
+    pub fn server_name(&self) -> &str {
+        &self.server_name
+    }
+
+    pub fn suite(&self) -> Suite {
+        self.suite
+    }
+
+    /// The last `connect`'s handshake, successful or not.
+    pub fn handshake(&self) -> Option<&Handshake> {
+        self.last.as_ref()
+    }
+
+    /// Open a TCP connection to `ip:port` and run the TLS handshake over
+    /// it, with record buffers from `bufs`. If `ip` is one of the
+    /// server name's addresses in the DNS cache, the others are raced
+    /// against it.
+    pub fn connect<'a>(
+        &mut self,
+        net: &'a mut NetStack,
+        ip: IpAddress,
+        port: u16,
+        bufs: &'a mut TlsBuffers,
+    ) -> Result<TlsSession<'a>, ConnectError> {
+        let start = crate::time::monotonic_ms();
+        let mut candidates = super::dns::cached(&self.server_name);
+        match candidates.iter().position(|&c| c == ip) {
+            Some(i) => {
+                let first = candidates.remove(i);
+                candidates.insert(0, first);
+            }
+            None => candidates = vec![ip],
+        }
+        let mut peer = IpEndpoint::new(ip, port);
+        let result = connect_any(net, &candidates, port).and_then(|(handle, ip)| {
+            peer = IpEndpoint::new(ip, port);
+            self.secure(net, handle, bufs)
+        });
+        self.last = Some(Handshake {
+            server_name: self.server_name.clone(),
+            peer,
+            candidates,
+            version: "TLS 1.3",
+            cipher_suite: self.suite.iana_name(),
+            elapsed_ms: crate::time::monotonic_ms().saturating_sub(start),
+            error: result.as_ref().err().map(|e| alloc::format!("{}", e)),
+        });
+        result
+    }
+
+    /// Run the TLS handshake over the connected socket `handle`.
+    fn secure<'a>(
+        &self,
+        net: &'a mut NetStack,
+        handle: SocketHandle,
+        bufs: &'a mut TlsBuffers,
+    ) -> Result<TlsSession<'a>, ConnectError> {
+        set_phase(handle, Phase::TlsHandshake);
+        let mut tcp = TcpStream::new(net, handle);
+        tcp.deadline = self.deadline;
+        tcp.abort = self.abort;
+        tcp.on_wait = self.on_wait;
+        let (read, write) = bufs.slices();
+        let conn = match self.suite {
+            Suite::Aes128Gcm => Conn::Aes128Gcm(Box::new(self.handshake_with(tcp, read, write)?)),
+            Suite::Aes256Gcm => Conn::Aes256Gcm(Box::new(self.handshake_with(tcp, read, write)?)),
+            Suite::Chacha20Poly1305 => Conn::Chacha20Poly1305(Box::new(self.handshake_with(tcp, read, write)?)),
+        };
+        Ok(TlsSession { conn, handle })
+    }
+
+    fn handshake_with<'a, S: TlsCipherSuite>(
+        &self,
+        tcp: TcpStream<'a>,
+        read: &'a mut [u8],
+        write: &'a mut [u8],
+    ) -> Result<TlsConnection<'a, TcpStream<'a>, S>, ConnectError> {
+        let mut conn = TlsConnection::new(tcp, read, write);
+        let config = TlsConfig::new().with_server_name(&self.server_name).enable_rsa_signatures();
+        conn.open(TlsContext::new(&config, UnsecureProvider::new::<S>(KernelRng::new())))
+            .map_err(|e| {
+                crate::serial_println!(
+                    "[TLS] Handshake with {} ({}) failed: {:?}",
+                    self.server_name,
+                    self.suite.iana_name(),
+                    e
+                );
+                ConnectError::Handshake(e)
+            })?;
+        Ok(conn)
+    }
+}
+
+/// Connect to the first of `candidates` to answer on `port`. Attempts
+/// start `ATTEMPT_DELAY_MS` apart, or at once when every earlier one has
+/// failed, and run side by side; the first to complete wins and the
+/// others are closed.
+fn connect_any(
+    net: &mut NetStack,
+    candidates: &[IpAddress],
+    port: u16,
+) -> Result<(SocketHandle, IpAddress), ConnectError> {
+    let now = crate::time::monotonic_ms;
+    let start = now();
+    let mut pending: Vec<(SocketHandle, IpAddress)> = Vec::new();
+    let mut next = 0;
+    let mut next_at = start;
+    let mut opened = false;
+    loop {
+        net.poll();
+
+        // Drop attempts that were refused or reset
+        pending.retain(|&(handle, _)| {
+            let alive = net.tcp_is_active(handle);
+            if !alive {
+                net.tcp_close(handle);
+            }
+            alive
+        });
+        if let Some(i) = pending.iter().position(|&(handle, _)| net.tcp_can_send(handle)) {
+            let won = pending.swap_remove(i);
+            for (handle, _) in pending {
+                net.tcp_close(handle);
+            }
+            return Ok(won);
+        }
+
+        if next < candidates.len() && (pending.is_empty() || now() >= next_at) {
+            if let Some(handle) = net.tcp_connect(candidates[next], port) {
+                pending.push((handle, candidates[next]));
+                opened = true;
+            }
+            next += 1;
+            next_at = now() + ATTEMPT_DELAY_MS;
+            continue;
+        }
+        if pending.is_empty() {
+            return Err(if opened { ConnectError::Refused } else { ConnectError::NoSocket });
+        }
+        if now() - start > CONNECT_TIMEOUT_MS {
+            for (handle, _) in pending {
+                net.tcp_close(handle);
+            }
+            return Err(ConnectError::Timeout);
+        }
+        crate::arch::x86_64::idle::wait();
+    }
+}
+
+/// The connection's type depends on its suite. Boxed: the AES-256 one
+/// is much the largest.
+enum Conn<'a> {
+    Aes128Gcm(Box<TlsConnection<'a, TcpStream<'a>, Aes128GcmSha256>>),
+    Aes256Gcm(Box<TlsConnection<'a, TcpStream<'a>, Aes256GcmSha384>>),
+    Chacha20Poly1305(Box<TlsConnection<'a, TcpStream<'a>, Chacha20Poly1305Sha256>>),
+}
+
+/// Run `$body` with `$c` bound to the connection, whatever its suite.
+macro_rules! with_conn {
+    ($conn:expr, $c:ident => $body:expr) => {
+        match $conn {
+            Conn::Aes128Gcm($c) => $body,
+            Conn::Aes256Gcm($c) => $body,
+            Conn::Chacha20Poly1305($c) => $body,
+        }
+    };
+}
+
+/// An open TLS connection. Dropping it without `close` skips the
+/// close_notify but still closes the TCP socket.
+pub struct TlsSession<'a> {
+    conn: Conn<'a>,
+    handle: SocketHandle,
+}
+
+impl TlsSession<'_> {
+    /// The TCP socket, for `stats::set_phase`.
+    pub fn handle(&self) -> SocketHandle {
+        self.handle
+    }
+
+    /// Send all of `data` and flush it to the network.
+    pub fn send(&mut self, mut data: &[u8]) -> Result<(), TlsError> {
+        while !data.is_empty() {
+            let n = with_conn!(&mut self.conn, c => c.write(data))?;
+            data = &data[n..];
+        }
+        with_conn!(&mut self.conn, c => c.flush())
+    }
+
+    /// Read decrypted data; Ok(0) at the end of the stream.
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, TlsError> {
+        with_conn!(&mut self.conn, c => c.read(buf))
+    }
+
+    /// Send close_notify and close the connection.
+    pub fn close(self) {
+        with_conn!(self.conn, c => {
+            let _ = c.close();
+        })
+    }
+}
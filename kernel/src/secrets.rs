@@ -0,0 +1,78 @@
+/// Secrets — keys made from passphrases, and wiping them afterwards.
+///
+/// A passphrase is stretched once with PBKDF2-HMAC-SHA256 and the result
+/// expanded with HKDF-Expand (RFC 5869) under a label naming what the key
+/// is for, so a passphrase never yields the same key for two purposes and
+/// a new use can't be confused with an old one. Everything that turns a
+/// passphrase into a key goes through `derive`.
+///
+/// Compiled for host tests as well: storage uses it for the disk key.
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Label of the key that wraps the disk key (`storage::crypt::CryptHeader`).
+pub const DISK_KEY_WRAP: &[u8] = b"HeavenOS disk key wrap v2";
+
+/// Longest key `derive` makes: HKDF-Expand stops at 255 hash blocks.
+pub const MAX_DERIVED_LEN: usize = 255 * 32;
+
+/// Fill `out` with the key for `label` from `passphrase`, `salt` and
+/// `iterations` PBKDF2 rounds.
+pub fn derive(passphrase: &[u8], salt: &[u8], iterations: u32, label: &[u8], out: &mut [u8]) {
+    let mut prk = [0u8; 32];
+    pbkdf2_sha256(passphrase, salt, iterations, &mut prk);
+    hkdf_expand(&prk, label, out);
+    wipe(&mut prk);
+}
+
+/// PBKDF2-HMAC-SHA256 (RFC 8018) of `passphrase` and `salt`, filling `out`.
+pub fn pbkdf2_sha256(passphrase: &[u8], salt: &[u8], iterations: u32, out: &mut [u8]) {
+    let prf = <HmacSha256 as Mac>::new_from_slice(passphrase).expect("HMAC takes any key length");
+    for (i, chunk) in out.chunks_mut(32).enumerate() {
+        let mut mac = prf.clone();
+        mac.update(salt);
+        mac.update(&(i as u32 + 1).to_be_bytes());
+        let mut u: [u8; 32] = mac.finalize().into_bytes().into();
+        let mut t = u;
+        for _ in 1..iterations {
+            let mut mac = prf.clone();
+            mac.update(&u);
+            u = mac.finalize().into_bytes().into();
+            for (t, u) in t.iter_mut().zip(&u) {
+                *t ^= u;
+            }
+        }
+        chunk.copy_from_slice(&t[..chunk.len()]);
+        wipe(&mut u);
+        wipe(&mut t);
+    }
+}
+
+/// HKDF-Expand (RFC 5869) with HMAC-SHA256: `out` from the pseudorandom
+/// key `prk` and `info`. At most `MAX_DERIVED_LEN` bytes.
+pub fn hkdf_expand(prk: &[u8; 32], info: &[u8], out: &mut [u8]) {
+    assert!(out.len() <= MAX_DERIVED_LEN, "HKDF output of {} bytes", out.len());
+    let prf = <HmacSha256 as Mac>::new_from_slice(prk).expect("HMAC takes any key length");
+    let mut t = [0u8; 32];
+    for (i, chunk) in out.chunks_mut(32).enumerate() {
+        let mut mac = prf.clone();
+        if i > 0 {
+            mac.update(&t);
+        }
+        mac.update(info);
+        mac.update(&[i as u8 + 1]);
+        t = mac.finalize().into_bytes().into();
+        chunk.copy_from_slice(&t[..chunk.len()]);
+    }
+    wipe(&mut t);
+}
+
+/// Overwrite key material that is going out of scope.
+pub fn wipe(buf: &mut [u8]) {
+    for byte in buf.iter_mut() {
+        unsafe { core::ptr::write_volatile(byte, 0) };
+    }
+    core::sync::atomic::compiler_fence(core::sync::atomic::Ordering::SeqCst);
+}
@@ -0,0 +1,208 @@
+/// Hardware and subsystem self-test, for a quick sanity check after
+/// changing hardware or QEMU flags.
+///
+/// `selftest` (or `config set boot.selftest on`, to run it at every boot
+/// just before the shell) exercises each piece the kernel depends on
+/// and prints one line per check:
+///
+///   rdrand   the DRNG answers and isn't stuck
+///   tsc      a fresh PIT measurement agrees with the boot calibration
+///   nvme     Identify Controller, a read of the superblock, and a
+///            write/read-back/compare of a free data block
+///   sqlite   create, insert into and select from a temp table
+///   json     parser vectors, including inputs it must reject
+///   dns      resolve the API host
+///
+/// A check whose subject is absent (no RDRAND, no network, read-only
+/// storage for the write) is skipped rather than failed.
+use alloc::format;
+use alloc::string::String;
+
+use crate::serial_println;
+
+/// What a check found.
+enum Outcome {
+    Pass(String),
+    Fail(String),
+    Skip(String),
+}
+
+struct Check {
+    name: &'static str,
+    run: fn() -> Outcome,
+}
+
+static CHECKS: [Check; 6] = [
+    Check { name: "rdrand", run: rdrand },
+    Check { name: "tsc", run: tsc },
+    Check { name: "nvme", run: nvme },
+    Check { name: "sqlite", run: sqlite },
+    Check { name: "json", run: json },
+    Check { name: "dns", run: dns },
+];
+
+/// Run every check and print PASS/FAIL/SKIP lines and a summary.
+/// Returns the number of failures.
+pub fn run() -> usize {
+    let mut failed = 0;
+    for check in CHECKS.iter() {
+        let (status, detail) = match (check.run)() {
+            Outcome::Pass(d) => ("PASS", d),
+            Outcome::Fail(d) => {
+                failed += 1;
+                ("FAIL", d)
+            }
+            Outcome::Skip(d) => ("SKIP", d),
+        };
+        serial_println!("[selftest] {} {:<7} {}", status, check.name, detail);
+    }
+    if failed == 0 {
+        serial_println!("[selftest] all checks passed");
+    } else {
+        serial_println!("[selftest] {} of {} checks FAILED", failed, CHECKS.len());
+    }
+    failed
+}
+
+fn rdrand() -> Outcome {
+    if !crate::arch::x86_64::cpu::has_rdrand() {
+        return Outcome::Skip(String::from("not in CPUID"));
+    }
+    if crate::crypto::entropy::rdrand_healthy() {
+        Outcome::Pass(String::from("answers, output varies"))
+    } else {
+        Outcome::Fail(String::from("output stuck or failing"))
+    }
+}
+
+fn tsc() -> Outcome {
+    use crate::arch::x86_64::timer;
+
+    let calibrated = timer::tsc_freq_hz();
+    if calibrated == 0 {
+        return Outcome::Fail(String::from("not calibrated"));
+    }
+    // An interrupt during a measurement can only make it read high
+    let measured = (0..3).map(|_| timer::measure_tsc_hz()).min().unwrap_or(0);
+    let off = calibrated.abs_diff(measured) * 1000 / calibrated;
+    let detail = format!("{} MHz at boot, {} MHz now", calibrated / 1_000_000, measured / 1_000_000);
+    if off <= crate::time::DRIFT_TOLERANCE_PERMILLE {
+        Outcome::Pass(detail)
+    } else {
+        Outcome::Fail(format!("{} ({}.{}% apart)", detail, off / 10, off % 10))
+    }
+}
+
+fn nvme() -> Outcome {
+    use crate::drivers::nvme::NVME;
+    use crate::mem::DmaBuf;
+
+    let (model, block_size) = {
+        let mut guard = NVME.lock();
+        let Some(nvme) = guard.as_mut() else {
+            return Outcome::Skip(String::from("no controller"));
+        };
+        let id = match nvme.identify() {
+            Ok(id) => id,
+            Err(e) => return Outcome::Fail(format!("identify: {}", e)),
+        };
+        let Some(block_size) = nvme.namespace_info().map(|ns| ns.block_size) else {
+            return Outcome::Fail(String::from("no namespace"));
+        };
+        let Ok(mut buf) = DmaBuf::alloc(block_size as usize) else {
+            return Outcome::Fail(String::from("no DMA memory"));
+        };
+        if let Err(e) = nvme.read_blocks(0, 1, &mut buf) {
+            return Outcome::Fail(format!("read LBA 0: {}", e));
+        }
+        (format!("{} (fw {})", id.model, id.firmware), block_size)
+    };
+
+    match crate::sqlite::mount_mode() {
+        None => return Outcome::Pass(format!("{}, read ok, write skipped (no filesystem)", model)),
+        Some(mode) if !mode.is_writable() => {
+            return Outcome::Pass(format!("{}, read ok, write skipped (storage {})", model, mode));
+        }
+        Some(_) => {}
+    }
+    let written = crate::sqlite::with_scratch_block(|nvme, lba| -> Result<u64, String> {
+        let mut out = DmaBuf::alloc(block_size as usize).map_err(|_| String::from("no DMA memory"))?;
+        let mut back = DmaBuf::alloc(block_size as usize).map_err(|_| String::from("no DMA memory"))?;
+        let seed = crate::arch::x86_64::cpu::rdtsc();
+        for (i, b) in out.as_mut_slice().iter_mut().enumerate() {
+            *b = (seed as usize).wrapping_add(i * 31) as u8;
+        }
+        nvme.write_blocks(lba, 1, &out).map_err(|e| format!("write LBA {}: {}", lba, e))?;
+        nvme.read_blocks(lba, 1, &mut back).map_err(|e| format!("read back LBA {}: {}", lba, e))?;
+        if out.as_slice() != back.as_slice() {
+            return Err(format!("LBA {} read back differs", lba));
+        }
+        // Leave nothing behind
+        out.as_mut_slice().fill(0);
+        nvme.write_blocks(lba, 1, &out).map_err(|e| format!("clear LBA {}: {}", lba, e))?;
+        Ok(lba)
+    });
+    match written {
+        Ok(Ok(lba)) => Outcome::Pass(format!("{}, read ok, write/read-back of LBA {} ok", model, lba)),
+        Ok(Err(e)) => Outcome::Fail(e),
+        Err(e) => Outcome::Fail(format!("scratch block: {}", e)),
+    }
+}
+
+fn sqlite() -> Outcome {
+    let guard = crate::sqlite::DB.lock();
+    let Some(db) = guard.as_ref() else {
+        return Outcome::Skip(String::from("database not open"));
+    };
+    let result = (|| -> Result<Option<String>, String> {
+        db.exec("DROP TABLE IF EXISTS temp.selftest")?;
+        db.exec("CREATE TEMP TABLE selftest (k TEXT PRIMARY KEY, v INTEGER)")?;
+        db.exec("INSERT INTO temp.selftest VALUES ('a', 1), ('b', 2), ('c', 39)")?;
+        let sum = db.query_value("SELECT sum(v) FROM temp.selftest")?;
+        db.exec("DROP TABLE temp.selftest")?;
+        Ok(sum)
+    })();
+    match result {
+        Ok(Some(sum)) if sum == "42" => Outcome::Pass(String::from("temp table create/insert/select ok")),
+        Ok(sum) => Outcome::Fail(format!("sum = {:?}, expected 42", sum)),
+        Err(e) => Outcome::Fail(e),
+    }
+}
+
+fn json() -> Outcome {
+    use crate::api::json;
+
+    let v = match json::parse(r#"{"s":"a\"é\n","n":-12.5e1,"a":[1,true,null],"o":{}}"#) {
+        Ok(v) => v,
+        Err(e) => return Outcome::Fail(format!("parse: {}", e)),
+    };
+    if v.get("s").and_then(|s| s.as_str()) != Some("a\"é\n") {
+        return Outcome::Fail(String::from("string escapes"));
+    }
+    if v.get("n").and_then(|n| n.as_number()) != Some(-125.0) {
+        return Outcome::Fail(String::from("number"));
+    }
+    if v.get("a").and_then(|a| a.as_array()).map(|a| a.len()) != Some(3) {
+        return Outcome::Fail(String::from("array"));
+    }
+    for bad in [r#"{"a":}"#, "[1,2", r#""unterminated"#, "tru"] {
+        if json::parse(bad).is_ok() {
+            return Outcome::Fail(format!("accepted {}", bad));
+        }
+    }
+    Outcome::Pass(String::from("escapes, numbers, arrays ok; 4 malformed inputs rejected"))
+}
+
+fn dns() -> Outcome {
+    let mut guard = crate::net::NET_STACK.lock();
+    let Some(net) = guard.as_mut() else {
+        return Outcome::Skip(String::from("no network"));
+    };
+    if !net.link_up() {
+        return Outcome::Skip(String::from("link down"));
+    }
+    match crate::net::dns::resolve_all(net, crate::api::API_HOST) {
+        Ok(ips) => Outcome::Pass(format!("{} -> {} ({} addresses)", crate::api::API_HOST, ips[0], ips.len())),
+        Err(e) => Outcome::Fail(format!("{}: {}", crate::api::API_HOST, e)),
+    }
+}
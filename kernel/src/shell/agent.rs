@@ -2,137 +2,635 @@
 ///
 /// Sends a prompt with tool definitions, executes tool calls locally,
 /// feeds results back, and repeats until Claude produces a final text
-/// response or the turn limit is reached.
+/// response or the run's budget (turns, seconds, tokens — see `Budget`)
+/// runs out, in which case it stops between turns and prints what it got
+/// done.
+///
+/// Writes made by the tools are journaled in `changes` under the run's
+/// conversation id, with the assistant text that preceded the tool call —
+/// except to `/tmp`, which is scratch space in RAM (`fs::tmpfs`). A write
+/// and its journal row commit together or not at all.
+///
+/// The system prompt comes from the namespace (see `sqlite::prompts`), so
+/// `config set agent.system_prompt <name>` swaps it without a rebuild.
+///
+/// Each tool call first passes the `tool_policy` table (`sqlite::policy`):
+/// a denied call, or one the operator refuses at the console, goes back to
+/// the model as an error result instead of running.
+///
+/// Every run is a session (`shell::sessions`): its output also goes to
+/// `/agents/<id>/log`, and `stop`/`pause`/`resume` written to
+/// `/agents/<id>/ctl` take effect between turns and tool calls; a stop
+/// also cuts a request short. The network stack is only held while a
+/// request is in flight, and Styx clients are served from its waits.
+///
+/// With `agent.route` set, turns that only carry tool results back —
+/// the model reading what it asked for — go to `agent.small_model`, and
+/// the configured model keeps the first turn and any turn after a failed
+/// call, where the planning happens (see `Route`).
+///
+/// Before each request the conversation is fitted to the context window
+/// (`shell::context`): the oldest turns give way to a note in the system
+/// prompt, so a long run doesn't fail on an oversized request.
+///
+/// A tool result longer than its cap (`agent.result_max.<tool>`, else
+/// `agent.result_max`, else `RESULT_MAX`) goes back as its head and tail
+/// around a marker counting the bytes left out; `read_range` lets the
+/// model page through a large file instead.
 
 use alloc::format;
 use alloc::string::String;
 use alloc::vec::Vec;
 
+use super::sessions::State;
 use crate::api::{self, ClaudeConfig, ClaudeRequest, ContentBlock, Message};
+use crate::error::{KernelError, Layer};
+use crate::fs::tmpfs;
 use crate::net::NetStack;
+use crate::sqlite::acl::{self, Access, Principal};
+use crate::term::Style;
 use crate::{serial_print, serial_println};
 
-/// Maximum number of agentic turns before stopping.
+/// Turn limit when neither a flag nor `agent.max_turns` sets one.
 const MAX_TURNS: usize = 20;
 
-/// System prompt for the agentic loop.
-const AGENT_SYSTEM: &str = "\
-You are an AI assistant running inside OSqlite, a bare-metal OS with an embedded SQLite database. \
-You have tools to read/write files in the namespace, execute SQL queries, and list directories. \
-Use tools to inspect and modify the system as needed. Be concise in your responses.";
+/// Tool result cap when config sets none.
+const RESULT_MAX: usize = 16 * 1024;
+
+/// Smallest cap honoured: room for the marker and some of each end.
+const RESULT_MIN: usize = 512;
+
+/// Small model when `agent.small_model` is unset.
+const SMALL_MODEL: &str = "claude-haiku-4-5-20251001";
+
+/// Tools that only read: their results need reading, not planning.
+const READ_TOOLS: &[&str] = &["read_file", "read_range", "list_dir", "sql_query"];
+
+/// Which turns go to the small model (config `agent.route`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Route {
+    /// None: every turn uses the configured model.
+    Off,
+    /// Turns following only successful read tools.
+    Reads,
+    /// Every turn following successful tool calls.
+    Followups,
+}
+
+impl Route {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.trim() {
+            "off" => Some(Route::Off),
+            "reads" => Some(Route::Reads),
+            "followups" => Some(Route::Followups),
+            _ => None,
+        }
+    }
+}
+
+/// What the previous turn's tool calls did, for routing the next turn.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LastCalls {
+    pub reads_only: bool,
+    pub failed: bool,
+}
+
+impl LastCalls {
+    fn record(&mut self, tool: &str, is_error: bool) {
+        self.reads_only &= READ_TOOLS.contains(&tool);
+        self.failed |= is_error;
+    }
+}
+
+/// The routing policy for one run.
+pub struct Routing {
+    pub route: Route,
+    pub small_model: String,
+}
+
+impl Routing {
+    /// `agent.route` (default off) and `agent.small_model`. Takes the DB lock.
+    pub fn from_config() -> Self {
+        Routing {
+            route: crate::sqlite::config_get("agent.route").and_then(|v| Route::parse(&v)).unwrap_or(Route::Off),
+            small_model: crate::sqlite::config_get("agent.small_model")
+                .map(|v| String::from(v.trim()))
+                .filter(|v| !v.is_empty())
+                .unwrap_or_else(|| String::from(SMALL_MODEL)),
+        }
+    }
+
+    /// The model for the next turn: `big`, or the small model when the
+    /// turn only follows up on `last` calls the route hands over. None for
+    /// `last` is the first turn.
+    pub fn model_for<'a>(&'a self, big: &'a str, last: Option<LastCalls>) -> &'a str {
+        let small = match (self.route, last) {
+            (_, None) => false,
+            (_, Some(calls)) if calls.failed => false,
+            (Route::Off, _) => false,
+            (Route::Reads, Some(calls)) => calls.reads_only,
+            (Route::Followups, Some(_)) => true,
+        };
+        if small { &self.small_model } else { big }
+    }
+}
+
+/// Limits on one run (None = unlimited).
+#[derive(Debug, Clone, Copy)]
+pub struct Budget {
+    pub max_turns: usize,
+    pub max_seconds: Option<u64>,
+    /// Input plus output tokens, summed over every request of the run.
+    pub max_tokens: Option<u64>,
+}
+
+impl Budget {
+    /// Defaults from config: `agent.max_turns` (20), `agent.max_seconds`
+    /// (a duration: "300", "10m") and `agent.max_tokens`. Unset or 0
+    /// means no limit. Takes the DB lock.
+    pub fn from_config() -> Self {
+        let number = |key| crate::sqlite::config_get(key).and_then(|v| v.trim().parse::<u64>().ok());
+        Budget {
+            max_turns: number("agent.max_turns").filter(|&n| n > 0).map_or(MAX_TURNS, |n| n as usize),
+            max_seconds: crate::sqlite::config_get("agent.max_seconds")
+                .and_then(|v| crate::sqlite::audit::parse_duration(&v))
+                .filter(|&n| n > 0),
+            max_tokens: number("agent.max_tokens").filter(|&n| n > 0),
+        }
+    }
+
+    /// Why the run must stop before another turn, if it must.
+    fn exhausted(&self, turns: usize, elapsed_secs: u64, tokens: u64) -> Option<String> {
+        if turns >= self.max_turns {
+            return Some(format!("turn limit ({}) reached", self.max_turns));
+        }
+        if let Some(max) = self.max_seconds.filter(|&max| elapsed_secs >= max) {
+            return Some(format!("time limit ({}s) reached", max));
+        }
+        if let Some(max) = self.max_tokens.filter(|&max| tokens >= max) {
+            return Some(format!("token limit ({}) reached", max));
+        }
+        None
+    }
+}
+
+/// The ACL principal the tools act as (`agent:claude`).
+fn tool_principal() -> Principal {
+    Principal::Agent(String::from("claude"))
+}
+
+/// Print a line on the console and append it to the session's log.
+macro_rules! report {
+    ($session:expr) => {
+        report!($session, "")
+    };
+    // Styled on the console only; the log keeps plain text
+    ($session:expr, $style:expr => $($arg:tt)*) => {{
+        let line = format!($($arg)*);
+        serial_println!("{}", crate::term::paint($style, &line));
+        super::sessions::log_line($session, &line);
+    }};
+    ($session:expr, $($arg:tt)*) => {{
+        let line = format!($($arg)*);
+        serial_println!("{}", line);
+        super::sessions::log_line($session, &line);
+    }};
+}
+
+/// Where a tool call came from, for the change journal.
+struct CallContext<'a> {
+    conversation: &'a str,
+    tool_use_id: &'a str,
+    /// Assistant text sent alongside the tool call.
+    reason: &'a str,
+    /// The call's input JSON.
+    input: &'a str,
+}
 
 /// Run the agentic loop for a user prompt.
 /// Returns the final text response.
-pub fn run_agent_loop(prompt: &str, use_tls: bool) -> Result<String, String> {
+pub fn run_agent_loop(prompt: &str, use_tls: bool, budget: &Budget) -> Result<String, String> {
+    let mut span = crate::span::begin("agent");
+    let result = start_agent_loop(prompt, use_tls, budget);
+    if result.is_err() {
+        span.fail();
+    }
+    result
+}
+
+fn start_agent_loop(prompt: &str, use_tls: bool, budget: &Budget) -> Result<String, String> {
     // Check API key
     let api_key = api::get_api_key()
         .ok_or_else(|| String::from("API key not set. Run: apikey sk-ant-..."))?;
 
-    // Acquire network stack
-    let mut net_guard = crate::net::NET_STACK.lock();
-    let net = net_guard.as_mut()
-        .ok_or_else(|| String::from("network stack not initialized"))?;
-
-    // Resolve target IP
-    let (_target_ip, config_base) = if use_tls {
-        let ip = resolve_api_ip(net)?;
-        serial_println!("[TLS to {}:443...]", ip);
-        (ip, ClaudeConfig::direct_tls(ip))
+    // Resolve target IP; `mockapi` builds answer without a network stack
+    let config_base = if api::mock::enabled() {
+        ClaudeConfig::default_proxy()
     } else {
-        serial_println!("[proxy mode: 10.0.2.2:8080...]");
-        let cfg = ClaudeConfig::default_proxy();
-        (cfg.target_ip, cfg)
+        let mut net_guard = crate::net::NET_STACK.lock();
+        let net = net_guard.as_mut()
+            .ok_or_else(|| String::from("network stack not initialized"))?;
+        if use_tls {
+            let ip = resolve_api_ip(net)?;
+            serial_println!("[TLS to {}:443...]", ip);
+            ClaudeConfig::direct_tls(ip)
+        } else {
+            serial_println!("[proxy mode: 10.0.2.2:8080...]");
+            ClaudeConfig::default_proxy()
+        }
     };
 
     let config = ClaudeConfig {
         api_key,
         model: api::get_model(),
+        stream: api::stream_default(),
+        timeout_secs: api::timeout_default(),
+        abort: Some(stop_requested),
+        on_wait: Some(crate::fs::styx::listen::pump_held),
         ..config_base
     };
 
-    // Initialize conversation
-    let mut messages: Vec<Message> = Vec::new();
-    messages.push(Message::text("user", String::from(prompt)));
-
-    let mut final_text = String::new();
-
-    for _turn in 0..MAX_TURNS {
-        serial_println!();
-
-        let request = ClaudeRequest {
-            config: ClaudeConfig {
-                api_key: config.api_key.clone(),
-                model: config.model.clone(),
-                target_ip: config.target_ip,
-                target_port: config.target_port,
-                use_tls: config.use_tls,
-            },
-            system: Some(String::from(AGENT_SYSTEM)),
-            messages: clone_messages(&messages),
-            use_tools: true,
-        };
+    let conversation = {
+        let guard = crate::sqlite::DB.lock();
+        let db = guard.as_ref().ok_or_else(|| String::from("database not open"))?;
+        crate::sqlite::changes::new_conversation_id(db)?
+    };
 
-        let response = api::claude_request_agentic(net, &request, |token| {
-            serial_print!("{}", token);
-        }).map_err(|e| format!("API error: {}", e))?;
+    let session = super::sessions::start(prompt);
+    serial_println!("[agent] session {}, conversation {}{}", session, conversation, crate::span::tag());
+    super::sessions::log_line(session, &format!("> {}", prompt));
+    let mut run = Run::new(session);
+    match run.agent_loop(prompt, &config, &conversation, budget) {
+        Ok(end) => {
+            super::sessions::finish(session, end.state, end.reason.as_deref());
+            Ok(end.text)
+        }
+        Err(e) => {
+            super::sessions::log_line(session, &format!("[agent] Error: {}{}", e, crate::span::tag()));
+            super::sessions::finish(session, State::Failed, Some(&e));
+            Err(e)
+        }
+    }
+}
 
-        if response.tool_calls.is_empty() {
-            // Final text response — done
-            final_text = response.text;
-            serial_println!();
-            return Ok(final_text);
+/// Ctrl-C, or a stop written to a session's ctl: ends the request in
+/// flight, and `Run::check_control` then says which.
+pub(crate) fn stop_requested() -> bool {
+    super::line::interrupt_pending() || super::sessions::stop_pending()
+}
+
+/// How a run ended without an error.
+struct End {
+    /// The last assistant text.
+    text: String,
+    state: State,
+    /// Why it stopped early, if it did.
+    reason: Option<String>,
+}
+
+/// Progress of one run, for budgets, the session status and the summary.
+pub(crate) struct Run {
+    session: u64,
+    started: u64,
+    turns: usize,
+    tokens: u64,
+    /// Descriptions of tool calls that succeeded.
+    completed: Vec<String>,
+    failed: usize,
+}
+
+impl Run {
+    pub(crate) fn new(session: u64) -> Self {
+        Run {
+            session,
+            started: crate::time::monotonic_ms(),
+            turns: 0,
+            tokens: 0,
+            completed: Vec::new(),
+            failed: 0,
         }
+    }
 
-        // We have tool calls — execute them
-        // First, record the assistant's response in conversation history
-        messages.push(Message::assistant_tool_use(
-            response.text.clone(),
-            response.tool_calls.clone(),
-        ));
+    fn elapsed_secs(&self) -> u64 {
+        (crate::time::monotonic_ms() - self.started) / 1000
+    }
 
-        // Execute each tool call and build tool_result messages
-        let mut result_blocks: Vec<ContentBlock> = Vec::new();
-        for tc in &response.tool_calls {
-            serial_println!();
-            serial_println!("[tool] {} ...", tc.name);
+    fn publish(&self) {
+        super::sessions::progress(self.session, self.turns, self.tokens, self.completed.len() + self.failed, self.failed);
+    }
 
-            let (result, is_error) = dispatch_tool(&tc.name, &tc.input_json);
+    /// Act on Ctrl-C or a pending ctl command. Returns the stop reason if
+    /// the run must end; a pause waits here until resumed or stopped.
+    /// Styx is served first, so a command written mid-turn is seen.
+    pub(crate) fn check_control(&self) -> Option<String> {
+        use super::sessions::{self, Control};
 
-            // Truncate display for long results
-            let display = if result.len() > 200 {
-                format!("{}... ({} bytes)", &result[..200], result.len())
-            } else {
-                result.clone()
+        crate::fs::styx::listen::pump();
+        if super::line::interrupt_pending() {
+            return Some(String::from("interrupted"));
+        }
+        match sessions::take_control(self.session)? {
+            Control::Stop => return Some(String::from("stop requested")),
+            Control::Resume => return None,
+            Control::Pause => {}
+        }
+        sessions::set_state(self.session, State::Paused);
+        report!(self.session, "[agent] Paused (session {}): write resume or stop to ctl; Ctrl-C stops", self.session);
+        let reason = loop {
+            match sessions::take_control(self.session) {
+                Some(Control::Stop) => break Some(String::from("stop requested")),
+                Some(Control::Resume) => break None,
+                _ => {}
+            }
+            if super::line::interrupt_pending() {
+                break Some(String::from("interrupted while paused"));
+            }
+            // Nothing is locked here, so let resident agents and
+            // maintenance run meanwhile
+            crate::lua::agents::pump();
+            crate::net::syslog::pump();
+            crate::net::mdns::pump();
+            crate::fs::styx::listen::pump();
+            crate::maintenance::tick();
+            crate::arch::x86_64::idle::wait();
+        };
+        sessions::set_state(self.session, State::Running);
+        if reason.is_none() {
+            report!(self.session, "[agent] Resumed");
+        }
+        reason
+    }
+
+    /// Print what got done and end the run early.
+    fn stop(&self, state: State, reason: String, text: String) -> End {
+        report!(self.session);
+        report!(self.session, "[agent] Stopped: {}", reason);
+        report!(
+            self.session,
+            "[agent] {} turn(s), {}s, {} tokens; {} tool call(s) completed, {} failed",
+            self.turns, self.elapsed_secs(), self.tokens, self.completed.len(), self.failed
+        );
+        for what in &self.completed {
+            report!(self.session, "  - {}", what);
+        }
+        End { text, state, reason: Some(reason) }
+    }
+
+    fn agent_loop(
+        &mut self,
+        prompt: &str,
+        config: &ClaudeConfig,
+        conversation: &str,
+        budget: &Budget,
+    ) -> Result<End, String> {
+        let session = self.session;
+        let mut messages: Vec<Message> = Vec::new();
+        messages.push(Message::text("user", String::from(prompt)));
+
+        let system = crate::sqlite::prompts::agent();
+        let mut context = super::context::Context::from_config();
+        let tools_bytes = api::tools::tools_json(true).len();
+        let routing = Routing::from_config();
+        let mut last_calls: Option<LastCalls> = None;
+        let mut final_text = String::new();
+
+        loop {
+            if let Some(reason) = self.check_control() {
+                return Ok(self.stop(State::Stopped, reason, final_text));
+            }
+            if let Some(reason) = budget.exhausted(self.turns, self.elapsed_secs(), self.tokens) {
+                return Ok(self.stop(State::Done, reason, final_text));
+            }
+            self.turns += 1;
+            self.publish();
+            report!(session);
+
+            if let Some((before, after, dropped)) = context.fit(&system, &mut messages, tools_bytes) {
+                report!(
+                    session, Style::Dim =>
+                    "[agent] Context ~{} tokens: {} earlier message(s) dropped into a note, ~{} left",
+                    before, dropped, after
+                );
+            }
+            let turn_system = context.system(&system);
+            let estimated = context.estimate(&turn_system, &messages, tools_bytes);
+            let model = routing.model_for(&config.model, last_calls);
+            if routing.route != Route::Off {
+                report!(session, Style::Dim => "[agent] turn {}: {}", self.turns, model);
+            }
+
+            let request = ClaudeRequest {
+                config: ClaudeConfig {
+                    api_key: config.api_key.clone(),
+                    host: config.host.clone(),
+                    model: String::from(model),
+                    target_ip: config.target_ip,
+                    target_port: config.target_port,
+                    use_tls: config.use_tls,
+                    stream: config.stream,
+                    timeout_secs: config.timeout_secs,
+                    abort: config.abort,
+                    on_wait: config.on_wait,
+                    deadline_ms: config.deadline_ms,
+                },
+                system: Some(turn_system),
+                messages: clone_messages(&messages),
+                use_tools: true,
+                stop_sequences: Vec::new(),
             };
-            if is_error {
-                serial_println!("[tool] ERROR: {}", display);
+
+            let on_token = |token: &str| {
+                serial_print!("{}", token);
+                super::sessions::log_text(session, token);
+            };
+            let response = if api::mock::enabled() {
+                api::mock::respond(&request.messages, &request.stop_sequences, on_token)
             } else {
-                serial_println!("[tool] -> {}", display);
+                let mut net_guard = crate::net::NET_STACK.lock();
+                let net = net_guard.as_mut()
+                    .ok_or_else(|| String::from("network stack not initialized"))?;
+                api::claude_request_agentic(net, &request, on_token)
+            };
+            let response = match response {
+                Ok(response) => response,
+                Err(api::ApiError::Interrupted) => {
+                    let reason = self.check_control().unwrap_or_else(|| String::from("interrupted"));
+                    return Ok(self.stop(State::Stopped, reason, final_text));
+                }
+                Err(e) => return Err(format!("{}", KernelError::from(e))),
+            };
+            self.tokens += response.usage.total();
+            context.calibrate(estimated, response.usage.prompt_tokens());
+            self.publish();
+            if let Some(db) = crate::sqlite::DB.lock().as_ref() {
+                let agent = format!("{}", tool_principal());
+                if let Err(e) =
+                    crate::sqlite::audit::record_usage(db, &agent, conversation, model, self.turns, &response.usage, 0)
+                {
+                    serial_println!("[agent] usage log: {}", e);
+                }
+            }
+
+            if response.stop_reason == "interrupted" {
+                // Ctrl-C or a ctl stop mid-response: keep what was said,
+                // run nothing
+                let reason = self.check_control().unwrap_or(response.stop_reason);
+                return Ok(self.stop(State::Stopped, reason, response.text));
+            }
+            if response.tool_calls.is_empty() {
+                // Final text response — done
+                report!(session);
+                return Ok(End { text: response.text, state: State::Done, reason: None });
+            }
+
+            // We have tool calls — execute them
+            final_text = response.text.clone();
+            // First, record the assistant's response in conversation history
+            messages.push(Message::assistant_tool_use(
+                response.text.clone(),
+                response.tool_calls.clone(),
+            ));
+
+            // Execute each tool call and build tool_result messages
+            let mut result_blocks: Vec<ContentBlock> = Vec::new();
+            let mut stop_reason = None;
+            let mut calls = LastCalls { reads_only: true, failed: false };
+            for tc in &response.tool_calls {
+                if !result_blocks.is_empty() && stop_reason.is_none() {
+                    stop_reason = self.check_control();
+                }
+                // A stop between calls still answers the remaining calls,
+                // so the conversation stays well-formed
+                let (result, is_error) = if stop_reason.is_some() {
+                    (String::from("not run: the session is stopping"), true)
+                } else {
+                    report!(session);
+                    report!(session, Style::Dim => "[tool] {} ...", tc.name);
+
+                    let ctx = CallContext {
+                        conversation,
+                        tool_use_id: &tc.id,
+                        reason: &response.text,
+                        input: &tc.input_json,
+                    };
+                    let (result, is_error) = match authorize(&tc.name, &tc.input_json) {
+                        Ok(()) => dispatch_tool(&tc.name, &tc.input_json, &ctx),
+                        Err(refusal) => (refusal, true),
+                    };
+                    let result = cap_result(&tc.name, result, result_max(&tc.name));
+
+                    // Truncate display for long results
+                    let display = if result.len() > 200 {
+                        format!("{}... ({} bytes)", &result[..200], result.len())
+                    } else {
+                        result.clone()
+                    };
+                    if is_error {
+                        report!(session, Style::Red => "[tool] ERROR: {}", display);
+                        self.failed += 1;
+                    } else {
+                        report!(session, Style::Dim => "[tool] -> {}", display);
+                        self.completed.push(describe_call(&tc.name, &tc.input_json));
+                    }
+                    self.publish();
+                    (result, is_error)
+                };
+
+                calls.record(&tc.name, is_error);
+                result_blocks.push(ContentBlock::ToolResult {
+                    tool_use_id: tc.id.clone(),
+                    content: result,
+                    is_error,
+                });
             }
 
-            result_blocks.push(ContentBlock::ToolResult {
-                tool_use_id: tc.id.clone(),
-                content: result,
-                is_error,
+            last_calls = Some(calls);
+
+            // Add all tool results as a single user message
+            messages.push(Message {
+                role: "user",
+                content: String::new(),
+                content_blocks: result_blocks,
             });
+            if let Some(reason) = stop_reason {
+                return Ok(self.stop(State::Stopped, reason, final_text));
+            }
         }
+    }
+}
+/// Apply the tool policy to a call: Ok to run it, or the error result to
+/// hand back to the model. Refusals and console decisions are audited.
+fn authorize(name: &str, input_json: &str) -> Result<(), String> {
+    use crate::sqlite::policy::{self, Action};
 
-        // Add all tool results as a single user message
-        messages.push(Message {
-            role: "user",
-            content: String::new(),
-            content_blocks: result_blocks,
-        });
+    let action = {
+        let guard = crate::sqlite::DB.lock();
+        let db = guard.as_ref().ok_or_else(|| String::from("database not open"))?;
+        policy::lookup(db, name).map_err(|e| format!("tool policy: {}", e))?
+    };
+    let what = describe_call(name, input_json);
+    let (allowed, audit_action) = match action {
+        Action::Allow => return Ok(()),
+        Action::Deny => (false, "TOOL_DENIED"),
+        Action::Confirm => {
+            serial_println!();
+            if super::line::confirm(&format!("allow {}?", what)) {
+                (true, "TOOL_CONFIRMED")
+            } else {
+                (false, "TOOL_REFUSED")
+            }
+        }
+    };
+
+    let guard = crate::sqlite::DB.lock();
+    if let Some(db) = guard.as_ref() {
+        let agent = format!("{}", tool_principal());
+        let _ = db.query_params(
+            "INSERT INTO audit (level, agent, action, target, detail, request_id) \
+             VALUES ('INFO', ?, ?, ?, ?, ?)",
+            &[
+                crate::sqlite::SqlValue::Text(agent),
+                crate::sqlite::SqlValue::Text(String::from(audit_action)),
+                crate::sqlite::SqlValue::Text(String::from(name)),
+                crate::sqlite::SqlValue::Text(what.clone()),
+                crate::sqlite::audit::request_id(),
+            ],
+        );
     }
+    drop(guard);
 
-    serial_println!();
-    serial_println!("[agent] Turn limit ({}) reached", MAX_TURNS);
-    Ok(final_text)
+    match (allowed, action) {
+        (true, _) => Ok(()),
+        (false, Action::Deny) => Err(format!("{} is denied by the tool policy", name)),
+        (false, _) => Err(format!("the operator declined: {}", what)),
+    }
+}
+
+/// One-line description of a tool call for the confirmation prompt.
+pub(super) fn describe_call(name: &str, input_json: &str) -> String {
+    let input = api::json::parse(input_json).ok();
+    let field = |key| {
+        input.as_ref().and_then(|v| v.get(key)).and_then(|v| v.as_str()).unwrap_or("?")
+    };
+    let what = match name {
+        "read_file" | "read_range" => format!("read of {}", field("path")),
+        "write_file" => format!("write to {}", field("path")),
+        "str_replace" => format!("edit of {}", field("path")),
+        "list_dir" => format!("listing of {}", field("path")),
+        "sql_query" => format!("SQL: {}", field("query")),
+        _ => format!("{} {}", name, input_json),
+    };
+    // Keep the prompt on one screen line
+    let mut line: String = what.chars().map(|c| if c.is_control() { ' ' } else { c }).take(120).collect();
+    if what.chars().count() > 120 {
+        line.push_str("...");
+    }
+    line
 }
 
 /// Dispatch a tool call to the appropriate handler.
 /// Returns (result_string, is_error).
-fn dispatch_tool(name: &str, input_json: &str) -> (String, bool) {
+fn dispatch_tool(name: &str, input_json: &str, ctx: &CallContext) -> (String, bool) {
     // Parse the input JSON
     let input = match api::json::parse(input_json) {
         Ok(v) => v,
@@ -141,19 +639,34 @@ fn dispatch_tool(name: &str, input_json: &str) -> (String, bool) {
 
     match name {
         "read_file" => tool_read_file(&input),
-        "write_file" => tool_write_file(&input),
+        "read_range" => tool_read_range(&input),
+        "write_file" => tool_write_file(&input, ctx),
         "sql_query" => tool_sql_query(&input),
         "list_dir" => tool_list_dir(&input),
-        "str_replace" => tool_str_replace(&input),
+        "str_replace" => tool_str_replace(&input, ctx),
         _ => (format!("Unknown tool: {}", name), true),
     }
 }
 
+/// The `path` parameter, canonicalized (see `sqlite::namespace::canonicalize`).
+fn path_param(input: &api::json::JsonValue) -> Result<String, String> {
+    let path = input.get("path").and_then(|v| v.as_str()).ok_or_else(|| String::from("missing 'path' parameter"))?;
+    crate::sqlite::namespace::canonicalize(path)
+}
+
 fn tool_read_file(input: &api::json::JsonValue) -> (String, bool) {
-    let path = match input.get("path").and_then(|v| v.as_str()) {
-        Some(p) => p,
-        None => return (String::from("missing 'path' parameter"), true),
+    let path = match path_param(input) {
+        Ok(p) => p,
+        Err(e) => return (e, true),
     };
+    let path = path.as_str();
+
+    if tmpfs::is_tmp(path) {
+        return match tmpfs::read(path) {
+            Some(content) => (String::from_utf8_lossy(&content).into_owned(), false),
+            None => (format!("file not found: {}", path), true),
+        };
+    }
 
     let guard = crate::sqlite::DB.lock();
     let db = match guard.as_ref() {
@@ -161,8 +674,12 @@ fn tool_read_file(input: &api::json::JsonValue) -> (String, bool) {
         None => return (String::from("database not open"), true),
     };
 
+    if let Err(e) = acl::check(db, &tool_principal(), path, Access::Read) {
+        return (e, true);
+    }
+
     let query = format!(
-        "SELECT content FROM namespace WHERE path='{}'",
+        "SELECT unpack(content, compressed) FROM namespace WHERE path='{}'",
         path.replace('\'', "''")
     );
 
@@ -173,11 +690,67 @@ fn tool_read_file(input: &api::json::JsonValue) -> (String, bool) {
     }
 }
 
-fn tool_write_file(input: &api::json::JsonValue) -> (String, bool) {
-    let path = match input.get("path").and_then(|v| v.as_str()) {
-        Some(p) => p,
-        None => return (String::from("missing 'path' parameter"), true),
+fn tool_read_range(input: &api::json::JsonValue) -> (String, bool) {
+    let path = match path_param(input) {
+        Ok(p) => p,
+        Err(e) => return (e, true),
+    };
+    let path = path.as_str();
+    let number = |key| input.get(key).and_then(|v| v.as_i64());
+    let offset = number("offset").unwrap_or(0);
+    // Leave room for the range line, so the cap doesn't cut the slice
+    let most = result_max("read_range").saturating_sub(64) as i64;
+    let length = number("length").unwrap_or(most).min(most);
+    if offset < 0 || length < 0 {
+        return (String::from("offset and length must not be negative"), true);
+    }
+
+    let (text, total) = if tmpfs::is_tmp(path) {
+        let Some(total) = tmpfs::len(path) else {
+            return (format!("file not found: {}", path), true);
+        };
+        let mut buf = alloc::vec![0u8; length as usize];
+        let n = tmpfs::read_at(path, offset as usize, &mut buf).unwrap_or(0);
+        (String::from_utf8_lossy(&buf[..n]).into_owned(), total as i64)
+    } else {
+        let guard = crate::sqlite::DB.lock();
+        let db = match guard.as_ref() {
+            Some(db) => db,
+            None => return (String::from("database not open"), true),
+        };
+        if let Err(e) = acl::check(db, &tool_principal(), path, Access::Read) {
+            return (e, true);
+        }
+        let result = db.query_params(
+            "SELECT substr(c, ?2 + 1, ?3), length(c) FROM \
+             (SELECT CAST(unpack(content, compressed) AS BLOB) AS c FROM namespace WHERE path = ?1)",
+            &[
+                crate::sqlite::SqlValue::Text(String::from(path)),
+                crate::sqlite::SqlValue::Integer(offset),
+                crate::sqlite::SqlValue::Integer(length),
+            ],
+        );
+        let row = match result {
+            Ok(result) => result.rows.into_iter().next(),
+            Err(e) => return (format!("read error: {}", e), true),
+        };
+        let Some(row) = row else {
+            return (format!("file not found: {}", path), true);
+        };
+        let text = String::from(row[0].as_str().unwrap_or(""));
+        (text, row[1].as_integer().unwrap_or(0))
+    };
+
+    let (start, end) = (offset.min(total), offset.saturating_add(length).min(total));
+    (format!("[bytes {}-{} of {}]\n{}", start, end, total, text), false)
+}
+
+fn tool_write_file(input: &api::json::JsonValue, ctx: &CallContext) -> (String, bool) {
+    let path = match path_param(input) {
+        Ok(p) => p,
+        Err(e) => return (e, true),
     };
+    let path = path.as_str();
     let content = match input.get("content").and_then(|v| v.as_str()) {
         Some(c) => c,
         None => return (String::from("missing 'content' parameter"), true),
@@ -189,14 +762,29 @@ fn tool_write_file(input: &api::json::JsonValue) -> (String, bool) {
         None => return (String::from("database not open"), true),
     };
 
-    let query = format!(
-        "INSERT OR REPLACE INTO namespace (path, type, content, mtime) \
-         VALUES ('{}', 'data', '{}', strftime('%s','now'))",
-        path.replace('\'', "''"),
-        content.replace('\'', "''")
-    );
+    // Scratch files in RAM stay out of the (persistent) change journal
+    if tmpfs::is_tmp(path) {
+        return match acl::write_file(db, &tool_principal(), path, "data", content) {
+            Ok(()) => (format!("wrote {} bytes to {}", content.len(), path), false),
+            Err(e) => (format!("write error: {}", e), true),
+        };
+    }
 
-    match db.exec(&query) {
+    let before = match db.query_params(
+        "SELECT unpack(content, compressed) FROM namespace WHERE path = ?",
+        &[crate::sqlite::SqlValue::Text(String::from(path))],
+    ) {
+        Ok(result) => result
+            .rows
+            .first()
+            .map(|row| String::from(row.first().and_then(crate::sqlite::SqlValue::as_str).unwrap_or(""))),
+        Err(e) => return (format!("read error: {}", e), true),
+    };
+
+    let written = journaled(db, ctx, "write_file", path, before.as_deref(), content, |db| {
+        acl::write_file(db, &tool_principal(), path, "data", content)
+    });
+    match written {
         Ok(()) => (format!("wrote {} bytes to {}", content.len(), path), false),
         Err(e) => (format!("write error: {}", e), true),
     }
@@ -217,17 +805,19 @@ fn tool_sql_query(input: &api::json::JsonValue) -> (String, bool) {
         return (String::from("only SELECT/EXPLAIN/PRAGMA allowed"), true);
     }
 
-    match crate::sqlite::exec_and_format(query) {
+    // File content goes through read_file and its ACL check
+    match crate::sqlite::exec_and_format_without_content(query) {
         Ok(output) => (output, false),
         Err(e) => (format!("SQL error: {}", e), true),
     }
 }
 
 fn tool_list_dir(input: &api::json::JsonValue) -> (String, bool) {
-    let path = match input.get("path").and_then(|v| v.as_str()) {
-        Some(p) => p,
-        None => return (String::from("missing 'path' parameter"), true),
+    let path = match path_param(input) {
+        Ok(p) => p,
+        Err(e) => return (e, true),
     };
+    let path = path.as_str();
 
     let prefix = if path.ends_with('/') {
         String::from(path)
@@ -242,13 +832,15 @@ fn tool_list_dir(input: &api::json::JsonValue) -> (String, bool) {
     };
 
     let query = format!(
-        "SELECT path FROM namespace WHERE substr(path, 1, {}) = '{}' ORDER BY path",
+        "SELECT path FROM namespace WHERE substr(path, 1, {}) = '{}' COLLATE NOCASE ORDER BY path",
         prefix.len(),
         prefix.replace('\'', "''")
     );
 
     match db.query_column(&query) {
-        Ok(paths) => {
+        Ok(mut paths) => {
+            paths.extend(tmpfs::list(&prefix));
+            paths.sort();
             if paths.is_empty() {
                 (format!("no entries under {}", path), false)
             } else {
@@ -259,11 +851,12 @@ fn tool_list_dir(input: &api::json::JsonValue) -> (String, bool) {
     }
 }
 
-fn tool_str_replace(input: &api::json::JsonValue) -> (String, bool) {
-    let path = match input.get("path").and_then(|v| v.as_str()) {
-        Some(p) => p,
-        None => return (String::from("missing 'path' parameter"), true),
+fn tool_str_replace(input: &api::json::JsonValue, ctx: &CallContext) -> (String, bool) {
+    let path = match path_param(input) {
+        Ok(p) => p,
+        Err(e) => return (e, true),
     };
+    let path = path.as_str();
     let old_str = match input.get("old_str").and_then(|v| v.as_str()) {
         Some(s) => s,
         None => return (String::from("missing 'old_str' parameter"), true),
@@ -280,8 +873,32 @@ fn tool_str_replace(input: &api::json::JsonValue) -> (String, bool) {
         None => return (String::from("database not open"), true),
     };
 
+    for access in [Access::Read, Access::Write] {
+        if let Err(e) = acl::check(db, &tool_principal(), path, access) {
+            return (e, true);
+        }
+    }
+
+    if tmpfs::is_tmp(path) {
+        let Some(content) = tmpfs::read(path) else {
+            return (format!("file not found: {}", path), true);
+        };
+        let content = String::from_utf8_lossy(&content);
+        if !content.contains(old_str) {
+            return (format!("old_str not found in {}", path), true);
+        }
+        let new_content = content.replacen(old_str, new_str, 1);
+        return match tmpfs::write(&tool_principal(), path, new_content.as_bytes()) {
+            Ok(()) => {
+                let diff = crate::diff::unified(&content, &new_content, path, path, 1);
+                (format!("replaced in {} ({} bytes -> {} bytes)\n{}", path, content.len(), new_content.len(), diff), false)
+            }
+            Err(e) => (format!("write error: {}", e), true),
+        };
+    }
+
     let read_query = format!(
-        "SELECT content FROM namespace WHERE path='{}'",
+        "SELECT unpack(content, compressed) FROM namespace WHERE path='{}'",
         path.replace('\'', "''")
     );
 
@@ -297,19 +914,100 @@ fn tool_str_replace(input: &api::json::JsonValue) -> (String, bool) {
     }
 
     let new_content = content.replacen(old_str, new_str, 1);
+    let packed = crate::sqlite::compress::pack(db, &new_content);
+    if let Err(e) = crate::sqlite::quota::check(db, path, packed.stored_len) {
+        return (e, true);
+    }
 
-    let write_query = format!(
-        "UPDATE namespace SET content='{}', mtime=strftime('%s','now') WHERE path='{}'",
-        new_content.replace('\'', "''"),
-        path.replace('\'', "''")
-    );
+    let written = journaled(db, ctx, "str_replace", path, Some(&content), &new_content, |db| {
+        db.query_params(
+            "UPDATE namespace SET content = CASE ?2 WHEN 1 THEN unhex(?1) ELSE ?1 END, \
+                 compressed = ?2, mtime = strftime('%s','now') WHERE path = ?3",
+            &[
+                packed.value,
+                crate::sqlite::SqlValue::Integer(i64::from(packed.compressed)),
+                crate::sqlite::SqlValue::Text(String::from(path)),
+            ],
+        )
+        .map(|_| ())
+    });
 
-    match db.exec(&write_query) {
-        Ok(()) => (format!("replaced in {} ({} bytes -> {} bytes)", path, content.len(), new_content.len()), false),
+    match written {
+        Ok(()) => {
+            let diff = crate::diff::unified(&content, &new_content, path, path, 1);
+            (format!("replaced in {} ({} bytes -> {} bytes)\n{}", path, content.len(), new_content.len(), diff), false)
+        }
         Err(e) => (format!("write error: {}", e), true),
     }
 }
 
+/// The result cap for `tool`, in bytes (see the module doc). Takes the DB lock.
+fn result_max(tool: &str) -> usize {
+    let size = |key: &str| crate::sqlite::config_get(key).and_then(|v| crate::sqlite::quota::parse_size(&v));
+    size(&format!("agent.result_max.{}", tool))
+        .or_else(|| size("agent.result_max"))
+        .map_or(RESULT_MAX, |n| (n as usize).max(RESULT_MIN))
+}
+
+/// `result` cut to about `max` bytes: three quarters from the start, the
+/// rest from the end, and a marker between them.
+pub(crate) fn cap_result(tool: &str, result: String, max: usize) -> String {
+    if result.len() <= max {
+        return result;
+    }
+    let hint = if tool == "read_file" { "; read_range reads the rest" } else { "" };
+    // The marker's length, with room for the counts
+    let room = max.saturating_sub(80 + hint.len());
+    let mut head = room * 3 / 4;
+    while !result.is_char_boundary(head) {
+        head -= 1;
+    }
+    let mut tail = result.len() - (room - room * 3 / 4);
+    while !result.is_char_boundary(tail) {
+        tail += 1;
+    }
+    format!(
+        "{}\n[... {} of {} bytes left out{} ...]\n{}",
+        &result[..head],
+        tail - head,
+        result.len(),
+        hint,
+        &result[tail..]
+    )
+}
+
+/// Make a tool write with `write` and record it in the change journal, in
+/// one savepoint: if either fails, neither is kept.
+fn journaled(
+    db: &crate::sqlite::SqliteDb,
+    ctx: &CallContext,
+    tool: &str,
+    path: &str,
+    before: Option<&str>,
+    after: &str,
+    write: impl FnOnce(&crate::sqlite::SqliteDb) -> Result<(), String>,
+) -> Result<(), String> {
+    let change = crate::sqlite::changes::Change {
+        conversation: ctx.conversation,
+        tool_use_id: ctx.tool_use_id,
+        tool,
+        path,
+        before,
+        after,
+        reason: ctx.reason,
+        input: ctx.input,
+    };
+    db.exec("SAVEPOINT tool_write")?;
+    let result = write(db).and_then(|()| {
+        crate::sqlite::changes::record(db, &change).map_err(|e| format!("change journal: {}", e))
+    });
+    if result.is_err() {
+        let _ = db.exec("ROLLBACK TO tool_write");
+    }
+    db.exec("RELEASE tool_write")?;
+    result
+}
+
 /// Case-insensitive prefix check.
 fn starts_with_ic(haystack: &[u8], needle: &[u8]) -> bool {
     if haystack.len() < needle.len() {
@@ -330,22 +1028,20 @@ fn clone_messages(messages: &[Message]) -> Vec<Message> {
 }
 
 /// Resolve api.anthropic.com IP with DNS, checking manual override first.
-fn resolve_api_ip(net: &mut NetStack) -> Result<smoltcp::wire::Ipv4Address, String> {
-    use smoltcp::wire::Ipv4Address;
-
+fn resolve_api_ip(net: &mut NetStack) -> Result<smoltcp::wire::IpAddress, String> {
     // Check manual override
     let manual = *super::commands::API_TARGET_IP_ACCESSOR.lock();
-    if manual != Ipv4Address::new(0, 0, 0, 0) {
+    if let Some(manual) = manual {
         serial_println!("[resolve: {} (manual)]", manual);
         return Ok(manual);
     }
 
     serial_println!("[DNS resolve: api.anthropic.com...]");
-    match crate::net::dns::resolve_a(net, "api.anthropic.com") {
+    match crate::api::resolve_api_host(net) {
         Ok(ip) => {
             serial_println!("[resolved: {}]", ip);
             Ok(ip)
         }
-        Err(e) => Err(format!("DNS resolution failed: {}", e)),
+        Err(e) => Err(format!("{}", KernelError::from(e).context(Layer::Api, "resolve api.anthropic.com"))),
     }
 }
@@ -7,172 +7,724 @@
 /// the HeavenOS namespace directly.
 use crate::{serial_print, serial_println};
 use crate::mem::phys::PHYS_ALLOCATOR;
-use crate::drivers::nvme::NVME;
+use crate::drivers::nvme::{self, NVME};
+use crate::error::{KernelError, Layer};
+use crate::term::{paint, Style};
+
+use super::registry::{self, Args, Command, Section};
 
 use spin::Mutex;
-use smoltcp::wire::Ipv4Address;
+use smoltcp::wire::IpAddress;
 
 /// Stored IP for api.anthropic.com (set via `resolve` command).
 /// With DNS resolver (17.1), this is used as a manual override.
-/// If 0.0.0.0, the `ask` command will try DNS resolution first.
-static API_TARGET_IP: Mutex<Ipv4Address> = Mutex::new(Ipv4Address::new(0, 0, 0, 0));
+/// If None, the `ask` command will try DNS resolution first.
+static API_TARGET_IP: Mutex<Option<IpAddress>> = Mutex::new(None);
 
 /// Public accessor for the agent module.
-pub(crate) static API_TARGET_IP_ACCESSOR: &Mutex<Ipv4Address> = &API_TARGET_IP;
+pub(crate) static API_TARGET_IP_ACCESSOR: &Mutex<Option<IpAddress>> = &API_TARGET_IP;
+
+/// Config key prefixes for `alias` and `bind`.
+const ALIAS_PREFIX: &str = "alias.";
+const BIND_PREFIX: &str = "bind.";
 
-/// Dispatch a command line to the appropriate handler.
+/// Dispatch a command line to the appropriate handler, expanding a
+/// leading alias first. Expansions are not expanded again, so an alias
+/// may wrap the command it shadows.
 pub fn dispatch(line: &str) {
+    let first = line.split_whitespace().next().unwrap_or("");
+    let expansion = crate::sqlite::config_get(&alloc::format!("{}{}", ALIAS_PREFIX, first));
+    match expansion {
+        Some(expansion) => {
+            let args = line.trim_start()[first.len()..].trim_start();
+            let expanded = if args.is_empty() { expansion } else { alloc::format!("{} {}", expansion, args) };
+            run_line(&expanded);
+        }
+        None => run_line(line),
+    }
+}
+
+/// Run one command line, without alias expansion.
+fn run_line(line: &str) {
     let mut parts = line.split_whitespace();
     let cmd = match parts.next() {
         Some(c) => c,
         None => return,
     };
 
-    match cmd {
-        "help" | "?" => cmd_help(),
-        "mem" | "meminfo" => cmd_meminfo(),
-        "nvme" | "disk" => cmd_nvme_info(),
-        "net" => cmd_net(),
-        "ls" => cmd_ls(parts.next().unwrap_or("/")),
-        "cat" => {
-            if let Some(path) = parts.next() {
-                cmd_cat(path);
-            } else {
-                serial_println!("usage: cat <path>");
-            }
-        }
-        "uptime" => cmd_uptime(),
-        "cpu" => cmd_cpu(),
-        "echo" => {
-            let rest: alloc::string::String = parts.collect::<alloc::vec::Vec<&str>>().join(" ");
-            serial_println!("{}", rest);
+    match registry::find(cmd) {
+        Some(command) => (command.run)(parts),
+        None => {
+            serial_println!("{}", paint(Style::Red, format_args!("unknown command: {}", cmd)));
+            serial_println!("type 'help' for available commands");
         }
-        "apikey" => {
-            let rest: alloc::string::String = parts.collect::<alloc::vec::Vec<&str>>().join("");
-            cmd_apikey(&rest);
+    }
+}
+
+/// The remaining words joined with `sep`.
+fn rest(args: Args<'_>, sep: &str) -> alloc::string::String {
+    args.collect::<alloc::vec::Vec<&str>>().join(sep)
+}
+
+/// `path` canonicalized (see `sqlite::namespace::canonicalize`), or None
+/// once `cmd` has said why not.
+pub(super) fn canonical(cmd: &str, path: &str) -> Option<alloc::string::String> {
+    match crate::sqlite::namespace::canonicalize(path) {
+        Ok(path) => Some(path),
+        Err(e) => {
+            serial_println!("{}: {}", cmd, e);
+            None
         }
-        "ask" => {
-            let rest: alloc::string::String = parts.collect::<alloc::vec::Vec<&str>>().join(" ");
-            if rest.is_empty() {
-                serial_println!("usage: ask <prompt>");
-            } else {
-                cmd_ask(&rest, true);
+    }
+}
+
+/// Report a failure with the layers it came through:
+/// `ask: api: request → tls: HandshakeFailed: TLS handshake failed`.
+fn print_error(what: &str, e: impl Into<KernelError>) {
+    serial_println!("{}", paint(Style::Red, format_args!("{}: {}{}", what, e.into(), crate::span::tag())));
+}
+
+/// Built-in commands, in `help` order within each section.
+pub(crate) static BUILTINS: &[Command] = &[
+    // ---- System ----
+    Command {
+        name: "help", aliases: &["?"], usage: "help [cmd]",
+        summary: "list commands, or describe one", details: &[],
+        section: Section::System, run: |mut args| registry::help(args.next()),
+    },
+    Command {
+        name: "mem", aliases: &["meminfo"], usage: "mem",
+        summary: "physical memory info", details: &[],
+        section: Section::System, run: |_| cmd_meminfo(),
+    },
+    Command {
+        name: "heap", aliases: &[], usage: "heap",
+        summary: "heap arenas (kernel, sqlite, lua): usage, canary faults", details: &[],
+        section: Section::System, run: |_| cmd_heap(),
+    },
+    Command {
+        name: "nvme", aliases: &["disk"], usage: "nvme",
+        summary: "NVMe controller info", details: &[],
+        section: Section::System, run: |_| cmd_nvme_info(),
+    },
+    Command {
+        name: "encrypt", aliases: &[], usage: "encrypt [status | on | off | passwd]",
+        summary: "encryption at rest (XTS-AES-256) of the whole disk",
+        details: &[
+            "on: encrypts every block under a new random key, wrapped with a",
+            "passphrase asked for twice; off: decrypts them again; passwd:",
+            "changes the passphrase only. on and off rewrite the whole disk:",
+            "interrupted, it won't mount. An encrypted disk asks for its",
+            "passphrase at boot and stays unmounted without it.",
+        ],
+        section: Section::System,
+        run: |mut args| cmd_encrypt(args.next().unwrap_or("status")),
+    },
+    Command {
+        name: "net", aliases: &[], usage: "net",
+        summary: "network interface info", details: &[],
+        section: Section::System, run: |_| cmd_net(),
+    },
+    Command {
+        name: "netstat", aliases: &[], usage: "netstat",
+        summary: "open sockets and their traffic",
+        details: &[
+            "One line per socket: state, what its owner is doing (tls, request,",
+            "response), bytes in/out, retransmits, smoothed RTT and time since",
+            "the peer last sent. Same as cat /sys/sockets.",
+        ],
+        section: Section::System, run: |_| cmd_netstat(),
+    },
+    Command {
+        name: "cpu", aliases: &[], usage: "cpu",
+        summary: "CPU features", details: &[],
+        section: Section::System, run: |_| cmd_cpu(),
+    },
+    Command {
+        name: "selftest", aliases: &[], usage: "selftest",
+        summary: "check RDRAND, TSC, NVMe, SQLite, JSON and DNS",
+        details: &[
+            "One PASS/FAIL/SKIP line per check. The NVMe check writes, reads",
+            "back and clears a free data block; it is skipped on read-only",
+            "storage. config set boot.selftest on runs it at every boot.",
+        ],
+        section: Section::System, run: |_| cmd_selftest(),
+    },
+    Command {
+        name: "uptime", aliases: &[], usage: "uptime",
+        summary: "system uptime and idle share", details: &["also prints the share of time since boot spent idle, as in /sys/cpu"],
+        section: Section::System, run: |_| cmd_uptime(),
+    },
+    Command {
+        name: "dmesg", aliases: &[], usage: "dmesg",
+        summary: "boot log (console output up to the shell prompt)", details: &[],
+        section: Section::System, run: |_| cmd_dmesg(),
+    },
+    Command {
+        name: "echo", aliases: &[], usage: "echo <text>",
+        summary: "print text", details: &[],
+        section: Section::System, run: |args| serial_println!("{}", rest(args, " ")),
+    },
+    Command {
+        name: "config", aliases: &[], usage: "config [list | get <key> | set <key> <value>]",
+        summary: "show or change settings",
+        details: &[
+            "storage.mode rw|ro  mount mode (ro = no writes)",
+            "storage.iocap on|off  report NVMe write guarantees to SQLite",
+            "storage.compress on|off  LZ4-compress files of 4K and up (default on)",
+            "storage.readahead N (0-256)  blocks read ahead of sequential reads (default 32)",
+            "storage.sync_warn_ms N  log xSyncs slower than N ms (0 = never; default 100)",
+            "sqlite.mmap_size BYTES  database read through xFetch, from next boot (default 0)",
+            "sql.page_size N  rows per page for sql (0 = no paging; default: screen)",
+            "lua.errors table|string  builtin error style",
+            "audit.max_rows / audit.max_age (e.g. 30d)  audit retention",
+            "serial.baud N (up to 1500000)  serial.clock Hz  console line rate",
+            "serial.flow none|xon|rts  console output flow control",
+            "term.color on|off|auto  ANSI colour (auto: if the peer is a terminal)",
+            "term.rows N  screen height for the pager (0 = never page)",
+            "net.mtu N (576-9000)  IP MTU, from next boot",
+            "net.offload on|off  checksum offload to virtio-net, from next boot",
+            "net.prefer ipv4|ipv6  address family tried first when resolving names",
+            "hostname NAME  name answered over mDNS as NAME.local (default heavenos)",
+            "syslog.host IP[:PORT]|off  forward kernel log lines to a syslog collector",
+            "syslog.audit on|off  forward audit rows as well",
+            "styx.port N|off  serve the namespace over 9P on TCP port N (no auth; ACL only)",
+            "boot.selftest on|off  run selftest at boot, before the shell",
+            "boot.autorun <path>   Lua agent run at boot, before the shell",
+            "tmp.size N (K/M/G)  RAM for /tmp files (default 4M)",
+            "agent.route off|reads|followups  turns after tool results on agent.small_model (default off)",
+            "agent.small_model NAME  model for routed turns (default claude-haiku-4-5-20251001)",
+            "agent.context_tokens N  model context window; older turns are dropped near it (default 200000)",
+            "agent.result_max N (K/M)  tool result cap, head and tail kept (default 16K)",
+            "agent.result_max.<tool> N  the cap for one tool, e.g. agent.result_max.sql_query",
+            "tls.cipher auto|aes128-gcm|aes256-gcm|chacha20-poly1305  suite offered (auto: by AES-NI)",
+            "api.timeout N  seconds an API request may take in all before it is retried (default 300)",
+            "tls.pin HEX  SPKI SHA-256 pinned for the API host (pin tofu sets it)",
+            "tls.pin_enforce on|off  refuse the API host unless its key matches tls.pin (not yet enforced)",
+        ],
+        section: Section::System,
+        run: |mut args| {
+            let sub = args.next().unwrap_or("list");
+            let key = args.next().unwrap_or("");
+            cmd_config(sub, key, &rest(args, " "));
+        },
+    },
+    Command {
+        name: "alias", aliases: &[], usage: "alias [<name> [\"<command line>\"]]",
+        summary: "list aliases, show one, or define one (kept in config)",
+        details: &[
+            "e.g. alias q \"sql select * from audit order by rowid desc limit 10\"",
+            "words typed after an alias are appended to its expansion",
+        ],
+        section: Section::System,
+        run: |mut args| match args.next() {
+            None => cmd_alias_list(),
+            Some(name) => cmd_alias(name, &rest(args, " ")),
+        },
+    },
+    Command {
+        name: "unalias", aliases: &[], usage: "unalias <name>",
+        summary: "remove an alias", details: &[],
+        section: Section::System,
+        run: |mut args| match args.next() {
+            Some(name) => cmd_unalias(name),
+            None => registry::usage("unalias"),
+        },
+    },
+    Command {
+        name: "bind", aliases: &[], usage: "bind [ctrl-<key> <action|command line|default>]",
+        summary: "list or change line-editor key bindings (kept in config)",
+        details: &[
+            "actions: clear-line delete-word redraw history-prev history-next complete eof",
+            "anything else is a command line, run when the key is pressed",
+            "e.g. bind ctrl-w delete-word   bind ctrl-t uptime   bind ctrl-t default",
+            "Ctrl-C, Ctrl-H (Backspace), Ctrl-I (Tab), Ctrl-J and Ctrl-M (Enter) are fixed",
+        ],
+        section: Section::System,
+        run: |mut args| match (args.next(), rest(args, " ")) {
+            (None, _) => cmd_bind_list(),
+            (Some(key), action) if !action.is_empty() => cmd_bind(key, &action),
+            _ => registry::usage("bind"),
+        },
+    },
+    Command {
+        name: "clear", aliases: &[], usage: "clear",
+        summary: "clear screen", details: &[],
+        section: Section::System, run: |_| cmd_clear(),
+    },
+    Command {
+        name: "panic", aliases: &[], usage: "panic",
+        summary: "trigger a kernel panic (for testing)", details: &[],
+        section: Section::System, run: |_| cmd_panic(),
+    },
+    // ---- Namespace files ----
+    Command {
+        name: "ls", aliases: &[], usage: "ls [path]",
+        summary: "list namespace entries", details: &[],
+        section: Section::Files, run: |mut args| cmd_ls(args.next().unwrap_or("/")),
+    },
+    Command {
+        name: "cat", aliases: &[], usage: "cat <path>",
+        summary: "read a namespace file", details: &[],
+        section: Section::Files,
+        run: |mut args| match args.next() {
+            Some(path) => cmd_cat(path),
+            None => registry::usage("cat"),
+        },
+    },
+    Command {
+        name: "rx", aliases: &[], usage: "rx <path>",
+        summary: "receive a file over serial (checksummed base64 lines)", details: &[],
+        section: Section::Files,
+        run: |mut args| match args.next() {
+            Some(path) => super::transfer::receive(path),
+            None => registry::usage("rx"),
+        },
+    },
+    Command {
+        name: "sx", aliases: &[], usage: "sx <path>",
+        summary: "send a file over serial (same framing)", details: &[],
+        section: Section::Files,
+        run: |mut args| match args.next() {
+            Some(path) => super::transfer::send(path),
+            None => registry::usage("sx"),
+        },
+    },
+    Command {
+        name: "edit", aliases: &[], usage: "edit <path>",
+        summary: "line editor for a namespace file (p/a/i/c/d/s/w/q, h for help)", details: &[],
+        section: Section::Files,
+        run: |mut args| match args.next() {
+            Some(path) => super::edit::run(path),
+            None => registry::usage("edit"),
+        },
+    },
+    Command {
+        name: "diff", aliases: &[], usage: "diff <path1> <path2>",
+        summary: "unified diff of two namespace files", details: &[],
+        section: Section::Files,
+        run: |mut args| match (args.next(), args.next()) {
+            (Some(a), Some(b)) => cmd_diff(a, b),
+            _ => registry::usage("diff"),
+        },
+    },
+    Command {
+        name: "mv", aliases: &[], usage: "mv [-r] <src> <dst>",
+        summary: "rename a file (-r: a whole subtree)", details: &[],
+        section: Section::Files, run: |args| super::files::mv(args),
+    },
+    Command {
+        name: "cp", aliases: &[], usage: "cp [-r] <src> <dst>",
+        summary: "copy a file (-r: a whole subtree)", details: &[],
+        section: Section::Files, run: |args| super::files::cp(args),
+    },
+    Command {
+        name: "rm", aliases: &[], usage: "rm [-r] <path>",
+        summary: "delete a file (-r: and everything under it)", details: &[],
+        section: Section::Files, run: |args| super::files::rm(args),
+    },
+    Command {
+        name: "du", aliases: &[], usage: "du [--compressed] [prefix]",
+        summary: "bytes stored under each child of prefix",
+        details: &["--compressed  also show original size and ratio"],
+        section: Section::Files,
+        run: |mut args| {
+            let mut arg = args.next();
+            let compressed = arg == Some("--compressed");
+            if compressed {
+                arg = args.next();
             }
-        }
-        "askp" => {
-            let rest: alloc::string::String = parts.collect::<alloc::vec::Vec<&str>>().join(" ");
-            if rest.is_empty() {
-                serial_println!("usage: askp <prompt>  (proxy mode)");
-            } else {
-                cmd_ask(&rest, false);
+            cmd_du(arg.unwrap_or("/"), compressed)
+        },
+    },
+    Command {
+        name: "quota", aliases: &[], usage: "quota [<prefix> <size|off>]",
+        summary: "list quotas, or set/clear one (K/M/G)",
+        details: &["e.g. quota /agents/foo 4M"],
+        section: Section::Files,
+        run: |mut args| match (args.next(), args.next()) {
+            (None, _) => cmd_quota_list(),
+            (Some(prefix), Some(size)) => cmd_quota_set(prefix, size),
+            _ => registry::usage("quota"),
+        },
+    },
+    Command {
+        name: "chmod", aliases: &[], usage: "chmod <mode> <path>",
+        summary: "set permission bits (octal, owner/kind/other)",
+        details: &["e.g. chmod 600 /notes/x"],
+        section: Section::Files,
+        run: |mut args| match (args.next(), args.next()) {
+            (Some(mode), Some(path)) => cmd_chmod(mode, path),
+            _ => registry::usage("chmod"),
+        },
+    },
+    Command {
+        name: "chown", aliases: &[], usage: "chown <who> <path>",
+        summary: "set owner: shell, agent:<path> or styx:<uname>", details: &[],
+        section: Section::Files,
+        run: |mut args| match (args.next(), args.next()) {
+            (Some(owner), Some(path)) => cmd_chown(owner, path),
+            _ => registry::usage("chown"),
+        },
+    },
+    Command {
+        name: "archive", aliases: &[], usage: "archive create <dest> [prefix] | extract <src>",
+        summary: "pack a namespace subtree into one file, or unpack one", details: &[],
+        section: Section::Files,
+        run: |mut args| {
+            let sub = args.next().unwrap_or("");
+            let path = args.next().unwrap_or("");
+            let prefix = args.next().unwrap_or("/");
+            cmd_archive(sub, path, prefix);
+        },
+    },
+    // ---- Database ----
+    Command {
+        name: "sql", aliases: &[], usage: "sql [-csv|-json] [--limit N] <stmt>",
+        summary: "execute SQL (paged; Ctrl-C interrupts)",
+        details: &["sql PRAGMA heaven_status  block allocator, file table, cache and xSync counters"],
+        section: Section::Database, run: |args| cmd_sql(&rest(args, " ")),
+    },
+    Command {
+        name: "sqlsh", aliases: &[], usage: "sqlsh",
+        summary: "interactive SQL prompt (multi-line, .tables/.schema/.mode)", details: &[],
+        section: Section::Database, run: |_| super::sqlsh::run(),
+    },
+    Command {
+        name: "db", aliases: &[], usage: "db maintain [now] | db info | db stats",
+        summary: "DB maintenance (check/vacuum/analyze); SQLite version, options, size",
+        details: &[
+            "db stats  rows, pages and KiB per table and index (largest first),",
+            "          index statistics from the last ANALYZE, largest namespace files",
+        ],
+        section: Section::Database,
+        run: |mut args| match (args.next(), args.next()) {
+            (Some("maintain"), Some("now")) => crate::maintenance::run_all_now(),
+            (Some("maintain"), None) => crate::maintenance::print_status(),
+            (Some("info"), None) => cmd_db_info(),
+            (Some("stats"), None) => cmd_db_stats(),
+            _ => registry::usage("db"),
+        },
+    },
+    Command {
+        name: "audit", aliases: &[], usage: "audit export <path> | audit prune",
+        summary: "write the audit log as NDJSON, or apply retention now", details: &[],
+        section: Section::Database,
+        run: |mut args| {
+            let sub = args.next().unwrap_or("");
+            let arg = args.next().unwrap_or("");
+            cmd_audit(sub, arg);
+        },
+    },
+    Command {
+        name: "events", aliases: &[], usage: "events [-k kind] [-n N]",
+        summary: "system lifecycle log: boots, shutdowns, panics, config changes",
+        details: &[
+            "Kinds: boot, shutdown, panic, format, repair, encrypt, config, autorun.",
+            "Events not yet written (database closed or storage read-only)",
+            "are listed after the table as pending.",
+        ],
+        section: Section::Database, run: |args| cmd_events(args),
+    },
+    Command {
+        name: "changes", aliases: &[], usage: "changes [-p prefix] [-c conv] [-n N]",
+        summary: "agent tool writes, newest first", details: &[],
+        section: Section::Database, run: |args| cmd_changes(args),
+    },
+    // ---- Lua ----
+    Command {
+        name: "lua", aliases: &[], usage: "lua",
+        summary: "interactive Lua REPL", details: &[],
+        section: Section::Lua, run: |_| cmd_lua_repl(),
+    },
+    Command {
+        name: "run", aliases: &[], usage: "run <path>",
+        summary: "execute a Lua agent from namespace", details: &[],
+        section: Section::Lua,
+        run: |mut args| match args.next() {
+            Some(path) => cmd_run(path),
+            None => registry::usage("run"),
+        },
+    },
+    Command {
+        name: "kill", aliases: &[], usage: "kill <path>",
+        summary: "stop a resident agent (ls /agents)", details: &[],
+        section: Section::Lua,
+        run: |mut args| match args.next() {
+            Some(name) if crate::lua::agents::kill(name) => serial_println!("[lua] {} stopped", name),
+            Some(name) => serial_println!("kill: {}: no such agent", name),
+            None => registry::usage("kill"),
+        },
+    },
+    Command {
+        name: "store", aliases: &[], usage: "store <path> <lua code>",
+        summary: "store Lua script at path", details: &[],
+        section: Section::Lua,
+        run: |mut args| {
+            let path = args.next();
+            let code = rest(args, " ");
+            match path {
+                Some(path) if !code.is_empty() => cmd_store(path, &code),
+                _ => registry::usage("store"),
             }
-        }
-        "resolve" => {
-            let rest: alloc::string::String = parts.collect::<alloc::vec::Vec<&str>>().join("");
-            cmd_resolve(&rest);
-        }
-        "model" => {
-            let rest: alloc::string::String = parts.collect::<alloc::vec::Vec<&str>>().join(" ");
-            cmd_model(&rest);
-        }
-        "pin" => {
-            let sub = parts.next().unwrap_or("show");
-            let rest: alloc::string::String = parts.collect::<alloc::vec::Vec<&str>>().join("");
-            cmd_pin(sub, &rest);
-        }
-        "sql" => {
-            let rest: alloc::string::String = parts.collect::<alloc::vec::Vec<&str>>().join(" ");
-            if rest.is_empty() {
-                serial_println!("usage: sql <statement>");
+        },
+    },
+    // ---- Claude API ----
+    Command {
+        name: "apikey", aliases: &[], usage: "apikey <key>",
+        summary: "set Anthropic API key", details: &[],
+        section: Section::Api, run: |args| cmd_apikey(&rest(args, "")),
+    },
+    Command {
+        name: "resolve", aliases: &[], usage: "resolve <ip>",
+        summary: "set api.anthropic.com IP (override DNS)",
+        details: &["<ip> is IPv4 (1.2.3.4) or IPv6 (2001:db8::1)"],
+        section: Section::Api, run: |args| cmd_resolve(&rest(args, "")),
+    },
+    Command {
+        name: "ask", aliases: &[], usage: "ask [-q | -b] <prompt>",
+        summary: "send message via TLS (auto-resolves DNS)",
+        details: &[
+            "-q  queue in the outbox; the reply lands in /mail/shell/<id>",
+            "-b  queue for the next Message Batches API batch: half price, answered",
+            "    within 24 hours, the reply landing in /mail/shell/<id>",
+        ],
+        section: Section::Api,
+        run: |args| {
+            let mut words = args.peekable();
+            let queued = words.next_if_eq(&"-q").is_some();
+            let batch = !queued && words.next_if_eq(&"-b").is_some();
+            let prompt = words.collect::<alloc::vec::Vec<&str>>().join(" ");
+            if prompt.is_empty() {
+                registry::usage("ask");
+            } else if queued || batch {
+                cmd_ask_queue(&prompt, batch);
             } else {
-                cmd_sql(&rest);
+                cmd_ask(&prompt, true);
             }
-        }
-        "run" => {
-            if let Some(path) = parts.next() {
-                cmd_run(path);
+        },
+    },
+    Command {
+        name: "askp", aliases: &[], usage: "askp <prompt>",
+        summary: "send message via proxy (plain HTTP)", details: &[],
+        section: Section::Api,
+        run: |args| {
+            let prompt = rest(args, " ");
+            if prompt.is_empty() {
+                registry::usage("askp");
             } else {
-                serial_println!("usage: run <path>   (execute a Lua agent from namespace)");
+                cmd_ask(&prompt, false);
             }
-        }
-        "store" => {
-            // store <path> <code...>
-            if let Some(path) = parts.next() {
-                let code: alloc::string::String = parts.collect::<alloc::vec::Vec<&str>>().join(" ");
-                if code.is_empty() {
-                    serial_println!("usage: store <path> <lua code>");
-                } else {
-                    cmd_store(path, &code);
-                }
-            } else {
-                serial_println!("usage: store <path> <lua code>");
+        },
+    },
+    Command {
+        name: "api", aliases: &[], usage: "api status | api trace [on | off | clear]",
+        summary: "API rate limits; keep API transcripts in /sys/api/trace/<n>",
+        details: &["status  quotas left from the last response, as in /sys/ratelimit"],
+        section: Section::Api,
+        run: |mut args| match (args.next(), args.next()) {
+            (Some("status"), None) => {
+                serial_print!("{}", alloc::string::String::from_utf8_lossy(&crate::api::ratelimit::report()));
             }
-        }
-        "agent" => {
-            let rest: alloc::string::String = parts.collect::<alloc::vec::Vec<&str>>().join(" ");
-            if rest.is_empty() {
-                serial_println!("usage: agent <prompt>");
-                serial_println!("  Starts an agentic loop with tool use (read, write, sql, etc.)");
-            } else {
-                cmd_agent(&rest, true);
+            (Some("trace"), None) => cmd_api_trace_list(),
+            (Some("trace"), Some(mode @ ("on" | "off"))) => match crate::api::trace::set_enabled(mode == "on") {
+                Ok(()) => serial_println!("api trace {}", mode),
+                Err(e) => serial_println!("api trace: {}", e),
+            },
+            (Some("trace"), Some("clear")) => match crate::api::trace::clear() {
+                Ok(n) => serial_println!("api trace: removed {} transcript(s)", n),
+                Err(e) => serial_println!("api trace: {}", e),
+            },
+            _ => registry::usage("api"),
+        },
+    },
+    Command {
+        name: "agent", aliases: &[], usage: "agent [--max-turns N] [--max-seconds S] [--max-tokens T] <prompt>",
+        summary: "agentic loop with tool use (read/write/sql)", details: &[],
+        section: Section::Api, run: |args| cmd_agent("agent", args),
+    },
+    Command {
+        name: "agentp", aliases: &[], usage: "agentp <prompt>",
+        summary: "agentic loop via proxy", details: &[],
+        section: Section::Api, run: |args| cmd_agent("agentp", args),
+    },
+    Command {
+        name: "model", aliases: &[], usage: "model <name>",
+        summary: "set model (default: claude-sonnet-4-6-20250514)", details: &[],
+        section: Section::Api, run: |args| cmd_model(&rest(args, " ")),
+    },
+    Command {
+        name: "pin", aliases: &[], usage: "pin [show | set <sha256> | clear | check <cert> [host] | tofu <cert>]",
+        summary: "manage TLS certificate SPKI pin", details: &[],
+        section: Section::Api,
+        run: |mut args| {
+            let sub = args.next().unwrap_or("show");
+            cmd_pin(sub, &rest(args, if sub == "set" { "" } else { " " }));
+        },
+    },
+    Command {
+        name: "policy", aliases: &[], usage: "policy [<tool|*> allow|deny|confirm|default]",
+        summary: "agent tool approvals", details: &[],
+        section: Section::Api,
+        run: |mut args| match (args.next(), args.next()) {
+            (None, _) => cmd_policy_list(),
+            (Some(tool), Some(action)) => cmd_policy_set(tool, action),
+            _ => registry::usage("policy"),
+        },
+    },
+    Command {
+        name: "outbox", aliases: &[], usage: "outbox [send | batch | cancel <id> | clear]",
+        summary: "queued asks (ask -q, ask -b; config ask.queue on)",
+        details: &["batch  collect an ended batch and submit the asks queued with -b now"],
+        section: Section::Api,
+        run: |mut args| match (args.next(), args.next()) {
+            (None, _) => cmd_outbox_list(),
+            (Some("send"), None) => match crate::maintenance::deliver_outbox() {
+                Ok(detail) if detail.is_empty() => serial_println!("outbox: nothing sent (empty, no API key or no network)"),
+                Ok(detail) => serial_println!("outbox: {}", detail),
+                Err(e) => serial_println!("outbox: {}", e),
+            },
+            (Some("batch"), None) => match crate::maintenance::deliver_batches() {
+                Ok(detail) if detail.is_empty() => serial_println!("outbox: no batch work (none queued, no API key or no network)"),
+                Ok(detail) => serial_println!("outbox: {}", detail),
+                Err(e) => serial_println!("outbox: {}", e),
+            },
+            (Some("cancel"), Some(id)) => cmd_outbox_cancel(id),
+            (Some("clear"), None) => cmd_outbox_clear(),
+            _ => registry::usage("outbox"),
+        },
+    },
+    Command {
+        name: "cache", aliases: &[], usage: "cache [clear [expired]]",
+        summary: "list or clear cached ask() responses",
+        details: &["enable with: config set cache.ttl 1h"],
+        section: Section::Api,
+        run: |mut args| match (args.next(), args.next()) {
+            (None, _) => cmd_cache_list(),
+            (Some("clear"), None) => cmd_cache_clear(false),
+            (Some("clear"), Some("expired")) => cmd_cache_clear(true),
+            _ => registry::usage("cache"),
+        },
+    },
+    // ---- Power ----
+    Command {
+        name: "reboot", aliases: &[], usage: "reboot",
+        summary: "flush storage and reset the system", details: &[],
+        section: Section::Power, run: |_| cmd_reboot(),
+    },
+    Command {
+        name: "shutdown", aliases: &["poweroff"], usage: "shutdown",
+        summary: "flush storage and power off (ACPI S5)", details: &[],
+        section: Section::Power, run: |_| cmd_shutdown(),
+    },
+    Command {
+        name: "halt", aliases: &[], usage: "halt",
+        summary: "flush storage and stop the CPU", details: &[],
+        section: Section::Power, run: |_| cmd_halt(),
+    },
+];
+
+fn cmd_alias_list() {
+    match crate::sqlite::config_list() {
+        Ok(rows) => {
+            let mut any = false;
+            for (key, value) in rows {
+                if let Some(name) = key.strip_prefix(ALIAS_PREFIX) {
+                    serial_println!("alias {} \"{}\"", name, value);
+                    any = true;
+                }
             }
-        }
-        "agentp" => {
-            let rest: alloc::string::String = parts.collect::<alloc::vec::Vec<&str>>().join(" ");
-            if rest.is_empty() {
-                serial_println!("usage: agentp <prompt>  (proxy mode)");
-            } else {
-                cmd_agent(&rest, false);
+            if !any {
+                serial_println!("(no aliases)");
             }
         }
-        "lua" => cmd_lua_repl(),
-        "clear" => cmd_clear(),
-        "panic" => cmd_panic(),
-        "reboot" => cmd_reboot(),
-        _ => {
-            serial_println!("unknown command: {}", cmd);
-            serial_println!("type 'help' for available commands");
+        Err(e) => serial_println!("alias: {}", e),
+    }
+}
+
+/// `alias <name>` shows it; `alias <name> <expansion>` defines it. The
+/// expansion may be wrapped in double quotes.
+fn cmd_alias(name: &str, expansion: &str) {
+    let key = alloc::format!("{}{}", ALIAS_PREFIX, name);
+    if expansion.is_empty() {
+        match crate::sqlite::config_get(&key) {
+            Some(value) => serial_println!("alias {} \"{}\"", name, value),
+            None => serial_println!("alias: {}: not defined", name),
         }
+        return;
+    }
+    let expansion = expansion
+        .strip_prefix('"')
+        .and_then(|e| e.strip_suffix('"'))
+        .unwrap_or(expansion)
+        .trim();
+    if expansion.is_empty() || name.contains(['"', '.', '/']) {
+        registry::usage("alias");
+        return;
+    }
+    match crate::sqlite::config_set(&key, expansion) {
+        Ok(()) => serial_println!("alias {} \"{}\"", name, expansion),
+        Err(e) => serial_println!("alias: {}", e),
     }
 }
 
-fn cmd_help() {
-    serial_println!("HeavenOS shell commands:");
-    serial_println!();
-    serial_println!("  help          show this help");
-    serial_println!("  mem           physical memory info");
-    serial_println!("  nvme          NVMe controller info");
-    serial_println!("  net           network interface info");
-    serial_println!("  cpu           CPU features");
-    serial_println!("  uptime        system uptime");
-    serial_println!("  ls [path]     list namespace entries");
-    serial_println!("  cat <path>    read a namespace file");
-    serial_println!("  echo <text>   print text");
-    serial_println!("  sql <stmt>    execute SQL on the system database");
-    serial_println!();
-    serial_println!("Lua:");
-    serial_println!("  lua             interactive Lua REPL");
-    serial_println!("  run <path>      execute a Lua agent from namespace");
-    serial_println!("  store <p> <c>   store Lua script at path");
-    serial_println!();
-    serial_println!("Claude API:");
-    serial_println!("  apikey <key>     set Anthropic API key");
-    serial_println!("  resolve <ip>     set api.anthropic.com IP (override DNS)");
-    serial_println!("  ask <prompt>     send message via TLS (auto-resolves DNS)");
-    serial_println!("  askp <prompt>    send message via proxy (plain HTTP)");
-    serial_println!("  agent <prompt>   agentic loop with tool use (read/write/sql)");
-    serial_println!("  agentp <prompt>  agentic loop via proxy");
-    serial_println!("  model <name>     set model (default: claude-sonnet-4-6-20250514)");
-    serial_println!("  pin [show|set]   manage TLS certificate SPKI pin");
-    serial_println!();
-    serial_println!("  clear         clear screen");
-    serial_println!("  panic         trigger a kernel panic (for testing)");
-    serial_println!("  reboot        reset the system");
-    serial_println!();
-    serial_println!("Line editing:");
-    serial_println!("  Backspace     delete character");
-    serial_println!("  Ctrl-C        cancel line");
-    serial_println!("  Ctrl-U        clear line");
+fn cmd_unalias(name: &str) {
+    if crate::sqlite::config_get(&alloc::format!("{}{}", ALIAS_PREFIX, name)).is_none() {
+        serial_println!("unalias: {}: not defined", name);
+        return;
+    }
+    match crate::sqlite::config_delete(&alloc::format!("{}{}", ALIAS_PREFIX, name)) {
+        Ok(()) => serial_println!("alias {} removed", name),
+        Err(e) => serial_println!("unalias: {}", e),
+    }
+}
+
+fn cmd_bind_list() {
+    let bound = super::line::bindings();
+    if bound.is_empty() {
+        serial_println!("(defaults only: Ctrl-U clear-line, Ctrl-L redraw, Ctrl-D eof)");
+    }
+    for (key, binding) in bound {
+        serial_println!("bind {} {}", super::line::key_name(key), binding.describe());
+    }
+}
+
+fn cmd_bind(key_name: &str, action: &str) {
+    use super::line::{self, Binding};
+
+    let Some(key) = line::parse_key(key_name) else {
+        serial_println!("bind: {}: not a bindable key (ctrl-a .. ctrl-z)", key_name);
+        return;
+    };
+    let name = line::key_name(key);
+    let config_key = alloc::format!("{}{}", BIND_PREFIX, name);
+    let result = if action == "default" {
+        line::set_binding(key, None);
+        crate::sqlite::config_delete(&config_key)
+    } else {
+        line::set_binding(key, Some(Binding::parse(action)));
+        crate::sqlite::config_set(&config_key, action)
+    };
+    match result {
+        Ok(()) => serial_println!("bind {} {}", name, action),
+        Err(e) => serial_println!("bind: {} (in effect until reboot)", e),
+    }
+}
+
+/// Install the key bindings saved in config (called when the shell starts).
+pub fn load_bindings() {
+    let Ok(rows) = crate::sqlite::config_list() else {
+        return;
+    };
+    for (key, value) in rows {
+        let Some(name) = key.strip_prefix(BIND_PREFIX) else {
+            continue;
+        };
+        match super::line::parse_key(name) {
+            Some(byte) => super::line::set_binding(byte, Some(super::line::Binding::parse(&value))),
+            None => serial_println!("[shell] Ignoring binding for {}", name),
+        }
+    }
 }
 
 fn cmd_meminfo() {
@@ -189,6 +741,191 @@ fn cmd_meminfo() {
     serial_println!("  free:   {} pages ({} MB)", free, free_mb);
 }
 
+fn cmd_db_info() {
+    let info = match crate::sqlite::info() {
+        Ok(info) => info,
+        Err(e) => {
+            serial_println!("db info: {}", e);
+            return;
+        }
+    };
+    serial_println!("SQLite {}", info.version);
+    serial_println!(
+        "  database: {} pages x {} bytes = {} KiB ({} free pages), journal {}",
+        info.page_count,
+        info.page_size,
+        info.page_count * info.page_size / 1024,
+        info.freelist_count,
+        info.journal_mode
+    );
+    serial_println!(
+        "  temp:     {} ({} RAM file(s), {} KiB)",
+        info.temp_store,
+        info.temp_files,
+        info.temp_bytes / 1024
+    );
+    serial_println!(
+        "  mmap:     {} KiB limit ({} mapped file(s), {} KiB)",
+        info.mmap_size / 1024,
+        info.mapped_files,
+        info.mapped_bytes / 1024
+    );
+    serial_println!("  writer:   {}", crate::sqlite::DB.stats());
+    serial_println!("  busy:     {}", crate::sqlite::writer::busy_stats());
+    let modules = info.modules();
+    serial_println!("  modules:  {}", if modules.is_empty() { alloc::string::String::from("none") } else { modules.join(" ") });
+    serial_println!("  compile options:");
+    for option in &info.compile_options {
+        serial_println!("    {}", option);
+    }
+}
+
+fn cmd_db_stats() {
+    let stats = match crate::sqlite::stats() {
+        Ok(stats) => stats,
+        Err(e) => {
+            serial_println!("db stats: {}", e);
+            return;
+        }
+    };
+    let opt = |n: Option<u64>| n.map_or(alloc::string::String::from("-"), |n| alloc::format!("{}", n));
+    serial_println!("{:<32} {:<5} {:>9} {:>7} {:>9}  index stat", "name", "type", "rows", "pages", "KiB");
+    for o in &stats.objects {
+        let stat = match (&o.stat, o.kind.as_str()) {
+            (Some(stat), _) => alloc::format!("on {}: {}", o.table, stat),
+            (None, "index") => alloc::format!("on {}", o.table),
+            _ => alloc::string::String::new(),
+        };
+        serial_println!(
+            "{:<32} {:<5} {:>9} {:>7} {:>9}  {}",
+            o.name, o.kind, opt(o.rows), opt(o.pages), opt(o.bytes.map(|b| b.div_ceil(1024))), stat
+        );
+    }
+    if !stats.sized {
+        serial_println!("(no sizes: this build has no dbstat table)");
+    }
+    serial_println!("largest namespace files:");
+    for f in &stats.largest {
+        serial_println!("  {:>9} KiB stored {:>9} KiB  {}", f.stored.div_ceil(1024), f.length.div_ceil(1024), f.path);
+    }
+}
+
+fn cmd_heap() {
+    serial_println!("{:<8} {:>10} {:>10} {:>8} {:>6} {:>7}", "arena", "in use", "peak", "blocks", "pages", "corrupt");
+    for a in crate::mem::heap::arenas() {
+        serial_println!(
+            "{:<8} {:>10} {:>10} {:>8} {:>6} {:>7}",
+            a.name, a.in_use, a.peak, a.live, a.pages, a.corrupt
+        );
+        if a.corrupt > 0 {
+            serial_println!("  {}: last corrupt block at {:#x} (leaked, not reused)", a.name, a.last_corrupt);
+        }
+    }
+}
+
+fn cmd_encrypt(sub: &str) {
+    use crate::crypto::zeroize::Zeroizing;
+    use crate::sqlite::lifecycle::{self, Kind};
+    use crate::storage::crypt::{CryptHeader, Xts, DEFAULT_ITERATIONS, KEY_LEN};
+
+    let header = match crate::sqlite::encryption() {
+        Ok(h) => h,
+        Err(e) => {
+            serial_println!("error: {}", e);
+            return;
+        }
+    };
+    // The disk key, for the passphrase that unseals `header`
+    let unseal = || -> Option<Zeroizing<[u8; KEY_LEN]>> {
+        let passphrase = super::line::read_secret("Current passphrase: ")?;
+        let key = header.unseal(passphrase.as_bytes()).map(Zeroizing::new);
+        if key.is_none() {
+            serial_println!("encrypt: wrong passphrase");
+        }
+        key
+    };
+    let seal = |key: &[u8; KEY_LEN]| -> Option<CryptHeader> {
+        let passphrase = new_passphrase()?;
+        let mut salt = [0u8; 16];
+        crate::crypto::entropy::fill(&mut salt);
+        Some(CryptHeader::seal(key, passphrase.as_bytes(), salt, DEFAULT_ITERATIONS))
+    };
+    let done = core::cell::Cell::new(0);
+    let progress = |blocks: u64, total: u64| {
+        let percent = blocks * 100 / total;
+        if percent >= done.get() + 10 || blocks == total {
+            done.set(percent);
+            serial_print!("\r  {}% ({} of {} blocks)", percent, blocks, total);
+        }
+    };
+
+    match sub {
+        "status" => {
+            serial_println!("encryption: {:?}", header);
+        }
+        "on" if header.is_encrypted() => serial_println!("encrypt: the disk is already encrypted"),
+        "on" => {
+            let mut key = Zeroizing::new([0u8; KEY_LEN]);
+            crate::crypto::entropy::fill(&mut *key);
+            let Some(new) = seal(&key) else { return };
+            if !super::line::confirm("Rewrite every block of the disk now?") {
+                return;
+            }
+            let result = crate::sqlite::reencrypt(Some(Xts::new(&key)), &new, progress);
+            serial_println!();
+            match result {
+                Ok(()) => {
+                    serial_println!("encrypt: disk encrypted ({:?})", new);
+                    lifecycle::record(Kind::Encrypt, "on");
+                }
+                Err(e) => serial_println!("error: {}", e),
+            }
+        }
+        "off" | "passwd" if !header.is_encrypted() => serial_println!("encrypt: the disk isn't encrypted"),
+        "off" => {
+            if unseal().is_none() || !super::line::confirm("Decrypt every block of the disk now?") {
+                return;
+            }
+            let result = crate::sqlite::reencrypt(None, &CryptHeader::NONE, progress);
+            serial_println!();
+            match result {
+                Ok(()) => {
+                    serial_println!("encrypt: disk decrypted");
+                    lifecycle::record(Kind::Encrypt, "off");
+                }
+                Err(e) => serial_println!("error: {}", e),
+            }
+        }
+        "passwd" => {
+            let Some(key) = unseal() else { return };
+            let Some(new) = seal(&key) else { return };
+            match crate::sqlite::rewrap_key(&new) {
+                Ok(()) => {
+                    serial_println!("encrypt: passphrase changed");
+                    lifecycle::record(Kind::Encrypt, "passphrase changed");
+                }
+                Err(e) => serial_println!("error: {}", e),
+            }
+        }
+        _ => registry::usage("encrypt"),
+    }
+}
+
+/// A new passphrase, typed twice.
+fn new_passphrase() -> Option<crate::crypto::zeroize::Zeroizing<alloc::string::String>> {
+    let first = super::line::read_secret("New passphrase: ")?;
+    if first.is_empty() {
+        serial_println!("encrypt: empty passphrase");
+        return None;
+    }
+    let again = super::line::read_secret("Again: ")?;
+    if *first != *again {
+        serial_println!("encrypt: passphrases differ");
+        return None;
+    }
+    Some(first)
+}
+
 fn cmd_nvme_info() {
     let guard = NVME.lock();
     match guard.as_ref() {
@@ -200,6 +937,18 @@ fn cmd_nvme_info() {
                     serial_println!("  blocks:     {}", ns.block_count);
                     serial_println!("  block size: {} bytes", ns.block_size);
                     serial_println!("  capacity:   {} MB", cap_mb);
+                    serial_println!("  resets:     {}", driver.reset_count());
+                    let errors: alloc::vec::Vec<alloc::string::String> = nvme::ErrorClass::ALL
+                        .iter()
+                        .map(|&c| (c, nvme::error_count(c)))
+                        .filter(|&(_, n)| n > 0)
+                        .map(|(c, n)| alloc::format!("{} {}", c.name(), n))
+                        .collect();
+                    if errors.is_empty() {
+                        serial_println!("  errors:     none");
+                    } else {
+                        serial_println!("  errors:     {}", errors.join(", "));
+                    }
                 }
                 None => serial_println!("NVMe: no namespace identified"),
             }
@@ -215,46 +964,86 @@ fn cmd_cpu() {
     serial_println!("  RDRAND:        {}", cpu::has_rdrand());
     serial_println!("  CLFLUSHOPT:    {}", cpu::has_clflushopt());
     serial_println!("  Invariant TSC: {}", cpu::has_invariant_tsc());
+    let sources = crate::crypto::entropy::sources();
+    let names: alloc::vec::Vec<&str> = sources.iter().map(|s| s.as_str()).collect();
+    serial_println!("  Entropy:       {} (details: cat /sys/entropy)", names.join(", "));
+}
+
+fn cmd_selftest() {
+    crate::selftest::run();
 }
 
 fn cmd_uptime() {
-    let total_secs = crate::arch::x86_64::timer::uptime_secs();
+    let total_secs = crate::time::uptime_secs();
     let hours = total_secs / 3600;
     let mins = (total_secs % 3600) / 60;
     let secs = total_secs % 60;
     serial_println!("up {}h {:02}m {:02}s", hours, mins, secs);
+    let idle = crate::arch::x86_64::idle::mode();
+    serial_println!("idle {}% ({})", crate::arch::x86_64::idle::idle_percent(), idle.as_str());
+}
+
+fn cmd_dmesg() {
+    super::pager::Pager::new().text(&crate::klog::contents());
 }
 
 fn cmd_ls(path: &str) {
+    let Some(path) = canonical("ls", path) else {
+        return;
+    };
     // Map well-known paths to static listings.
     // When the Styx server is wired in, this will walk the namespace.
-    match path {
+    match path.as_str() {
         "/" => {
             serial_println!("db/");
             serial_println!("sys/");
             serial_println!("hw/");
             serial_println!("agents/");
+            serial_println!("tmp/");
+        }
+        "/tmp" => {
+            use crate::fs::tmpfs;
+            for e in tmpfs::entries() {
+                serial_println!("{:>10}  {:<32} {}", e.len, e.path, e.owner);
+            }
+            let (files, bytes) = tmpfs::usage();
+            serial_println!("{} file(s), {} of {} bytes (RAM, cleared at boot)", files, bytes, tmpfs::limit());
         }
-        "/db" | "db" => {
+        "/db" => {
             serial_println!("ctl");
             serial_println!("schema");
         }
-        "/sys" | "sys" => {
+        "/sys" => {
             serial_println!("uptime");
             serial_println!("meminfo");
             serial_println!("log");
+            serial_println!("audit");
+            serial_println!("quota");
+            serial_println!("sockets");
+            serial_println!("spans");
+            serial_println!("ratelimit");
+            serial_println!("cpu");
         }
-        "/hw" | "hw" => {
+        "/hw" => {
             serial_println!("nvme/");
             serial_println!("gpu/");
         }
-        "/hw/nvme" | "hw/nvme" => {
+        "/hw/nvme" => {
             serial_println!("info");
             serial_println!("smart");
             serial_println!("stats");
         }
-        "/agents" | "agents" => {
-            serial_println!("(no agents running)");
+        "/agents" => {
+            let agents = crate::lua::agents::list();
+            if agents.is_empty() {
+                serial_println!("(no agents running)");
+            }
+            for a in agents {
+                serial_println!(
+                    "{}  subs={} timers={} queued={} dropped={}",
+                    a.name, a.subscriptions, a.timers, a.queued, a.dropped
+                );
+            }
         }
         _ => {
             serial_println!("ls: {}: not found", path);
@@ -263,16 +1052,41 @@ fn cmd_ls(path: &str) {
 }
 
 fn cmd_cat(path: &str) {
+    let Some(path) = canonical("cat", path) else {
+        return;
+    };
+    let path = path.as_str();
     // Map well-known paths to synthetic content
     match path {
-        "/sys/meminfo" | "sys/meminfo" => { cmd_meminfo(); return; }
-        "/sys/uptime" | "sys/uptime" => { cmd_uptime(); return; }
-        "/hw/nvme/info" | "hw/nvme/info" => { cmd_nvme_info(); return; }
-        "/db/schema" | "db/schema" => {
+        "/sys/meminfo" => { cmd_meminfo(); return; }
+        "/sys/uptime" => { cmd_uptime(); return; }
+        "/sys/log" => { cmd_dmesg(); return; }
+        "/sys/audit" => { cmd_cat_audit(); return; }
+        "/sys/sockets" => { cmd_netstat(); return; }
+        "/sys/quota" => {
+            serial_print!("{}", alloc::string::String::from_utf8_lossy(&crate::sqlite::quota::report()));
+            return;
+        }
+        "/sys/spans" => {
+            serial_print!("{}", alloc::string::String::from_utf8_lossy(&crate::span::report()));
+            return;
+        }
+        "/sys/ratelimit" => {
+            serial_print!("{}", alloc::string::String::from_utf8_lossy(&crate::api::ratelimit::report()));
+            return;
+        }
+        "/sys/cpu" => {
+            serial_print!("{}", alloc::string::String::from_utf8_lossy(&crate::arch::x86_64::idle::report()));
+            return;
+        }
+        "/hw/nvme/info" => { cmd_nvme_info(); return; }
+        "/db/schema" => {
             match crate::sqlite::exec_and_format(
                 "SELECT sql FROM sqlite_master WHERE type='table' ORDER BY name"
             ) {
-                Ok(out) => serial_print!("{}", out),
+                Ok(out) => {
+                    super::pager::Pager::new().text(&out);
+                }
                 Err(e) => serial_println!("error: {}", e),
             }
             return;
@@ -280,16 +1094,26 @@ fn cmd_cat(path: &str) {
         _ => {}
     }
 
+    if crate::fs::tmpfs::is_tmp(path) {
+        match crate::fs::tmpfs::read(path) {
+            Some(content) => {
+                super::pager::Pager::new().text(&alloc::string::String::from_utf8_lossy(&content));
+            }
+            None => serial_println!("cat: {}: not found", path),
+        }
+        return;
+    }
+
     // Try reading from the namespace table (structured query — handles all content)
     let guard = crate::sqlite::DB.lock();
     if let Some(db) = guard.as_ref() {
         let query = alloc::format!(
-            "SELECT content FROM namespace WHERE path='{}'",
+            "SELECT unpack(content, compressed) FROM namespace WHERE path='{}'",
             path.replace('\'', "''")
         );
         if let Ok(Some(content)) = db.query_value(&query) {
             drop(guard);
-            serial_println!("{}", content);
+            super::pager::Pager::new().text(&content);
             return;
         }
     }
@@ -308,6 +1132,16 @@ fn cmd_panic() {
 
 fn cmd_net() {
     use crate::drivers::virtio::net::VIRTIO_NET;
+    // The stack locks the NIC while polling: take them in that order
+    let (addressing, addressing6, stack_link, link_changes) = match crate::net::NET_STACK.lock().as_mut() {
+        Some(net) => (
+            Some((net.ipv4(), net.gateway(), net.dns_server(), net.dhcp_leased())),
+            Some((net.ipv6(), net.ipv6_link_local(), net.gateway6(), net.dns_server6())),
+            Some(net.link_up()),
+            net.link_changes(),
+        ),
+        None => (None, None, None, 0),
+    };
     let guard = VIRTIO_NET.lock();
     match guard.as_ref() {
         Some(nic) => {
@@ -315,9 +1149,54 @@ fn cmd_net() {
             serial_println!("Network interface: virtio-net");
             serial_println!("  MAC:    {:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
                 mac[0], mac[1], mac[2], mac[3], mac[4], mac[5]);
-            serial_println!("  IP:     10.0.2.15 (QEMU default)");
-            serial_println!("  GW:     10.0.2.2");
-            serial_println!("  Status: up");
+            match addressing {
+                Some((addr, gateway, dns, leased)) => {
+                    serial_println!("  IP:     {} ({})", addr, if leased { "DHCP" } else { "QEMU default" });
+                    match gateway {
+                        Some(gw) => serial_println!("  GW:     {}", gw),
+                        None => serial_println!("  GW:     none"),
+                    }
+                    serial_println!("  DNS:    {}", dns);
+                }
+                None => serial_println!("  IP:     (network stack not running)"),
+            }
+            if let Some((global, link_local, gateway6, dns6)) = addressing6 {
+                match global {
+                    Some(cidr) => serial_println!("  IPv6:   {} (SLAAC)", cidr),
+                    None => serial_println!("  IPv6:   none (no router advertisement)"),
+                }
+                serial_println!("          {}/64 (link-local)", link_local);
+                if let Some(gw) = gateway6 {
+                    serial_println!("  GW6:    {}", gw);
+                }
+                if let Some(dns) = dns6 {
+                    serial_println!("  DNS6:   {}", dns);
+                }
+                serial_println!("  Prefer: {}", crate::net::dns::preference().as_str());
+            }
+            let up = stack_link.unwrap_or_else(|| nic.link_up());
+            serial_println!("  Link:   {}{}, {} changes since boot",
+                if up { paint(Style::Green, "up") } else { paint(Style::Red, "down") },
+                if nic.reports_link() { "" } else { " (not reported by device)" },
+                link_changes);
+            serial_println!("  MTU:    {}{}", nic.mtu(),
+                if nic.mergeable_rx() { " (mergeable rx buffers)" } else { "" });
+            serial_println!("  RX:     {} frames dropped", nic.rx_dropped());
+            let (tx_csum, rx_csum) = nic.checksum_offload();
+            serial_println!("  Csum:   tx by {}, rx by {}",
+                if tx_csum { "device" } else { "software" }, if rx_csum { "device" } else { "software" });
+            serial_println!("  TLS:    {} (CPU {} AES-NI)", crate::net::tls::Suite::configured().iana_name(),
+                if crate::arch::x86_64::cpu::has_aesni() { "has" } else { "lacks" });
+            let tx = nic.tx_stats();
+            serial_println!("  TX:     {}/{} descriptors in flight, ring full {} times",
+                tx.in_flight, tx.capacity, tx.ring_full);
+            if let Some(name) = crate::net::mdns::local_name() {
+                serial_println!("  mDNS:   {}", name);
+            }
+            if let Some(target) = crate::net::syslog::target() {
+                let (sent, dropped) = crate::net::syslog::counters();
+                serial_println!("  Syslog: {} ({} sent, {} dropped)", target, sent, dropped);
+            }
         }
         None => {
             serial_println!("Network: not initialized");
@@ -326,6 +1205,11 @@ fn cmd_net() {
     }
 }
 
+fn cmd_netstat() {
+    let report = crate::net::stats::report();
+    super::pager::Pager::new().text(&alloc::string::String::from_utf8_lossy(&report));
+}
+
 fn cmd_apikey(key: &str) {
     if key.is_empty() {
         match crate::api::get_api_key() {
@@ -347,34 +1231,24 @@ fn cmd_apikey(key: &str) {
 
 fn cmd_resolve(ip_str: &str) {
     if ip_str.is_empty() {
-        let current = *API_TARGET_IP.lock();
-        if current == Ipv4Address::new(0, 0, 0, 0) {
-            serial_println!("API target: not set (will use DNS)");
-            serial_println!("usage: resolve <ip>  (manual override)");
-        } else {
-            serial_println!("API target: {} (manual override)", current);
+        match *API_TARGET_IP.lock() {
+            None => {
+                serial_println!("API target: not set (will use DNS)");
+                serial_println!("usage: resolve <ip>  (manual override)");
+            }
+            Some(current) => serial_println!("API target: {} (manual override)", current),
         }
         return;
     }
 
-    // Parse IPv4 address
-    let octets: alloc::vec::Vec<&str> = ip_str.split('.').collect();
-    if octets.len() != 4 {
-        serial_println!("Invalid IP format. Use: resolve 1.2.3.4");
-        return;
-    }
-    let mut bytes = [0u8; 4];
-    for (i, octet) in octets.iter().enumerate() {
-        match octet.parse::<u8>() {
-            Ok(b) => bytes[i] = b,
-            Err(_) => {
-                serial_println!("Invalid IP octet: {}", octet);
-                return;
-            }
+    let ip = match ip_str.trim_start_matches('[').trim_end_matches(']').parse::<IpAddress>() {
+        Ok(ip) if !ip.is_unspecified() => ip,
+        _ => {
+            serial_println!("Invalid IP address. Use: resolve 1.2.3.4 or resolve 2001:db8::1");
+            return;
         }
-    }
-    let ip = Ipv4Address::new(bytes[0], bytes[1], bytes[2], bytes[3]);
-    *API_TARGET_IP.lock() = ip;
+    };
+    *API_TARGET_IP.lock() = Some(ip);
     serial_println!("API target set to: {}", ip);
 }
 
@@ -390,6 +1264,7 @@ fn cmd_model(name: &str) {
 }
 
 fn cmd_ask(prompt: &str, use_tls: bool) {
+    let mut span = crate::span::begin("ask");
     // Check API key
     let api_key = match crate::api::get_api_key() {
         Some(k) => k,
@@ -399,6 +1274,20 @@ fn cmd_ask(prompt: &str, use_tls: bool) {
         }
     };
 
+    // `mockapi` builds answer without a network stack
+    if crate::api::mock::enabled() {
+        let messages = [crate::api::Message::text("user", alloc::string::String::from(prompt))];
+        match crate::api::mock::respond(&messages, &[], |token| serial_print!("{}", token)) {
+            Ok(_) => serial_println!(),
+            Err(e) => {
+                span.fail();
+                serial_println!();
+                print_error("ask", e);
+            }
+        }
+        return;
+    }
+
     // Check network stack
     let mut net_guard = crate::net::NET_STACK.lock();
     let net = match net_guard.as_mut() {
@@ -415,19 +1304,25 @@ fn cmd_ask(prompt: &str, use_tls: bool) {
         // Check manual IP override first, then try DNS
         let target_ip = {
             let manual = *API_TARGET_IP.lock();
-            if manual != Ipv4Address::new(0, 0, 0, 0) {
+            if let Some(manual) = manual {
                 serial_println!("[resolve: {} (manual)]", manual);
                 manual
             } else {
                 // Try DNS resolution
                 serial_println!("[DNS resolve: api.anthropic.com...]");
-                match crate::net::dns::resolve_a(net, "api.anthropic.com") {
+                match crate::api::resolve_api_host(net) {
                     Ok(ip) => {
                         serial_println!("[resolved: {}]", ip);
                         ip
                     }
                     Err(e) => {
-                        serial_println!("Error: DNS resolution failed: {}", e);
+                        span.fail();
+                        print_error("ask", KernelError::from(e).context(Layer::Api, "resolve api.anthropic.com"));
+                        if crate::sqlite::outbox::queue_on_failure() {
+                            drop(net_guard);
+                            cmd_ask_queue(prompt, false);
+                            return;
+                        }
                         serial_println!("  Fallback: resolve <ip>  (manual)");
                         serial_println!("  Get IP on host: dig +short api.anthropic.com");
                         return;
@@ -440,6 +1335,9 @@ fn cmd_ask(prompt: &str, use_tls: bool) {
         crate::api::ClaudeConfig {
             api_key,
             model: crate::api::get_model(),
+            stream: crate::api::stream_default(),
+            timeout_secs: crate::api::timeout_default(),
+            abort: Some(super::line::interrupt_pending),
             ..crate::api::ClaudeConfig::direct_tls(target_ip)
         }
     } else {
@@ -447,10 +1345,14 @@ fn cmd_ask(prompt: &str, use_tls: bool) {
         crate::api::ClaudeConfig {
             api_key,
             model: crate::api::get_model(),
+            stream: crate::api::stream_default(),
+            timeout_secs: crate::api::timeout_default(),
+            abort: Some(super::line::interrupt_pending),
             ..crate::api::ClaudeConfig::default_proxy()
         }
     };
 
+    serial_println!("[request {}]", span.id());
     serial_println!();
 
     // Send request and stream response
@@ -459,10 +1361,26 @@ fn cmd_ask(prompt: &str, use_tls: bool) {
     }) {
         Ok(_) => {
             serial_println!();
+            // Ctrl-C mid-stream keeps the partial answer above
+            if super::line::interrupt_pending() {
+                serial_println!("{}", paint(Style::Dim, "[interrupted]"));
+            }
+        }
+        Err(crate::api::ApiError::Interrupted) => {
+            span.fail();
+            serial_println!();
+            serial_println!("{}", paint(Style::Dim, "[interrupted]"));
         }
         Err(e) => {
+            span.fail();
             serial_println!();
-            serial_println!("[API error: {}]", e);
+            let network = e.is_network();
+            print_error("ask", e);
+            if network && crate::sqlite::outbox::queue_on_failure() {
+                drop(net_guard);
+                cmd_ask_queue(prompt, false);
+                return;
+            }
             if use_tls {
                 serial_println!();
                 serial_println!("TLS troubleshooting:");
@@ -479,86 +1397,450 @@ fn cmd_ask(prompt: &str, use_tls: bool) {
     }
 }
 
-fn cmd_pin(sub: &str, arg: &str) {
-    match sub {
-        "show" | "" => {
-            if let Some(pin) = crate::crypto::pin_verifier::get_pin_override() {
-                serial_println!("SPKI pin (runtime override):");
-                serial_print!("  ");
-                for b in &pin {
-                    serial_print!("{:02x}", b);
-                }
-                serial_println!();
-            } else {
-                serial_println!("SPKI pin: using compiled-in pins");
-                serial_println!("  Pinning enforcement: {}", if crate::api::ENFORCE_PINNING { "ON" } else { "OFF" });
+fn cmd_api_trace_list() {
+    serial_println!("api trace {}", if crate::api::trace::enabled() { "on" } else { "off" });
+    match crate::api::trace::list() {
+        Ok(traces) => {
+            for (path, bytes) in traces {
+                serial_println!("  {:<24} {:>8} bytes", path, bytes);
             }
         }
-        "set" => {
-            if arg.is_empty() {
-                serial_println!("usage: pin set <64-hex-chars>");
-                serial_println!("  Get pin: openssl s_client -connect api.anthropic.com:443 \\");
-                serial_println!("    | openssl x509 -pubkey -noout \\");
-                serial_println!("    | openssl pkey -pubin -outform der \\");
-                serial_println!("    | openssl dgst -sha256 -binary | xxd -p -c32");
-                return;
-            }
-            match parse_hex_hash(arg) {
-                Some(hash) => {
-                    crate::crypto::pin_verifier::set_pin_override(hash);
-                    serial_println!("SPKI pin override set ({} bytes)", hash.len());
-                }
-                None => {
-                    serial_println!("Invalid hex hash. Expected 64 hex characters (32 bytes SHA-256).");
-                }
+        Err(e) => serial_println!("api trace: {}", e),
+    }
+}
+
+fn cmd_policy_list() {
+    let guard = crate::sqlite::DB.lock();
+    let Some(db) = guard.as_ref() else {
+        serial_println!("error: database not open");
+        return;
+    };
+    match crate::sqlite::policy::list(db) {
+        Ok(rows) if rows.is_empty() => serial_println!("no tool policy set (every tool allowed)"),
+        Ok(rows) => {
+            for (tool, action) in rows {
+                serial_println!("  {:<16} {}", tool, action.as_str());
             }
         }
-        "clear" => {
-            crate::crypto::pin_verifier::clear_pin_override();
-            serial_println!("SPKI pin override cleared. Using compiled-in pins.");
-        }
-        _ => {
-            serial_println!("usage: pin [show|set <hex>|clear]");
-        }
+        Err(e) => serial_println!("policy: {}", e),
     }
 }
 
-/// Parse a 64-character hex string into a 32-byte array.
-fn parse_hex_hash(hex: &str) -> Option<[u8; 32]> {
-    let hex = hex.trim();
-    if hex.len() != 64 {
-        return None;
+fn cmd_policy_set(tool: &str, action: &str) {
+    use crate::sqlite::policy::{self, Action};
+
+    let action = match action {
+        "default" => None,
+        other => match Action::parse(other) {
+            Some(action) => Some(action),
+            None => {
+                serial_println!("policy: {}: expected allow, deny, confirm or default", other);
+                return;
+            }
+        },
+    };
+    let known = tool == "*" || crate::api::tools::TOOLS.iter().any(|t| t.name == tool);
+    if !known {
+        serial_println!("policy: note: {} is not a current agent tool", tool);
     }
-    let mut result = [0u8; 32];
-    for i in 0..32 {
-        let byte_str = &hex[i * 2..i * 2 + 2];
-        result[i] = u8::from_str_radix(byte_str, 16).ok()?;
+    let guard = crate::sqlite::DB.lock();
+    let Some(db) = guard.as_ref() else {
+        serial_println!("error: database not open");
+        return;
+    };
+    match policy::set(db, tool, action) {
+        Ok(()) => serial_println!("policy: {} -> {}", tool, action.map_or("default", |a| a.as_str())),
+        Err(e) => serial_println!("policy: {}", e),
     }
-    Some(result)
 }
 
-fn cmd_sql(query: &str) {
-    match crate::sqlite::exec_and_format(query) {
-        Ok(output) => {
-            serial_print!("{}", output);
-        }
-        Err(e) => {
-            serial_println!("SQL error: {}", e);
-        }
+fn cmd_ask_queue(prompt: &str, batch: bool) {
+    let model = crate::api::get_model();
+    let guard = crate::sqlite::DB.lock();
+    let Some(db) = guard.as_ref() else {
+        serial_println!("error: database not open");
+        return;
+    };
+    let messages = [crate::api::Message::text("user", alloc::string::String::from(prompt))];
+    match crate::sqlite::outbox::enqueue(db, "shell", &model, None, &messages, batch) {
+        Ok((id, reply_to)) => serial_println!("[queued as #{}; the reply will be written to {}]", id, reply_to),
+        Err(e) => serial_println!("outbox: {}", e),
     }
 }
 
-fn cmd_reboot() {
-    serial_println!("Rebooting...");
-    // Write 0xFE to keyboard controller port 0x64 = CPU reset
-    crate::arch::x86_64::outb(0x64, 0xFE);
-    // If that didn't work, triple fault
-    loop {
+fn cmd_outbox_list() {
+    let guard = crate::sqlite::DB.lock();
+    let Some(db) = guard.as_ref() else {
+        serial_println!("error: database not open");
+        return;
+    };
+    let items = match crate::sqlite::outbox::list(db) {
+        Ok(items) => items,
+        Err(e) => {
+            serial_println!("outbox: {}", e);
+            return;
+        }
+    };
+    drop(guard);
+
+    if items.is_empty() {
+        serial_println!("outbox empty");
+        return;
+    }
+    for item in &items {
+        let prompt = item.messages.last().map(|(_, text)| text.as_str()).unwrap_or("");
+        let preview: alloc::string::String = prompt.chars().take(40).filter(|c| !c.is_control()).collect();
+        serial_println!(
+            "  #{:<4} {:<8} {:<20} {} attempt(s)  -> {}  \"{}\"",
+            item.id, item.status, item.agent, item.attempts, item.reply_to, preview
+        );
+        if item.batch && item.status == "pending" {
+            match &item.batch_id {
+                Some(id) if id == crate::sqlite::outbox::SUBMITTING => {
+                    serial_println!("         submitted, batch id not recorded (not resent)")
+                }
+                Some(id) => serial_println!("         in batch {}", id),
+                None => serial_println!("         waiting for the next batch"),
+            }
+        }
+        if let Some(e) = &item.last_error {
+            serial_println!("         last error: {}", e);
+        }
+    }
+}
+
+fn cmd_outbox_cancel(id: &str) {
+    let Ok(id) = id.trim_start_matches('#').parse::<i64>() else {
+        serial_println!("outbox: {}: not a request id", id);
+        return;
+    };
+    let guard = crate::sqlite::DB.lock();
+    let Some(db) = guard.as_ref() else {
+        serial_println!("error: database not open");
+        return;
+    };
+    match crate::sqlite::outbox::cancel(db, id) {
+        Ok(true) => serial_println!("outbox: #{} cancelled", id),
+        Ok(false) => serial_println!("outbox: no request #{}", id),
+        Err(e) => serial_println!("outbox: {}", e),
+    }
+}
+
+fn cmd_outbox_clear() {
+    let guard = crate::sqlite::DB.lock();
+    let Some(db) = guard.as_ref() else {
+        serial_println!("error: database not open");
+        return;
+    };
+    match crate::sqlite::outbox::clear_finished(db) {
+        Ok(n) => serial_println!("outbox: removed {} finished request(s)", n),
+        Err(e) => serial_println!("outbox: {}", e),
+    }
+}
+
+fn cmd_pin(sub: &str, arg: &str) {
+    use crate::crypto::pin_verifier;
+
+    match sub {
+        "show" | "" => {
+            if let Some(pin) = pin_verifier::get_pin_override() {
+                serial_println!("SPKI pin (runtime override):");
+                serial_println!("  {}", pin_verifier::hex(&pin));
+            } else if let Some(pin) = pin_verifier::pin() {
+                serial_println!("SPKI pin (config tls.pin):");
+                serial_println!("  {}", pin_verifier::hex(&pin));
+            } else {
+                serial_println!("SPKI pin: using compiled-in pins");
+            }
+            let enforcement = match (pin_verifier::enforcing(), pin_verifier::enforce_requested()) {
+                (true, _) => "ON",
+                (false, true) => "requested, but the TLS stack can't show the certificate yet",
+                (false, false) => "OFF",
+            };
+            serial_println!("  Pinning enforcement: {}", enforcement);
+            if let Some(seen) = pin_verifier::observed() {
+                let hex = pin_verifier::hex(&seen.spki_hash);
+                serial_println!("Observed SPKI for {}:", seen.host);
+                serial_println!("  {}", hex);
+                if pin_verifier::pin() != Some(seen.spki_hash) {
+                    serial_println!("  to pin it: pin set {}", hex);
+                }
+            }
+        }
+        "set" => {
+            if arg.is_empty() {
+                serial_println!("usage: pin set <64-hex-chars>");
+                serial_println!("  Get pin: openssl s_client -connect api.anthropic.com:443 \\");
+                serial_println!("    | openssl x509 -pubkey -noout \\");
+                serial_println!("    | openssl pkey -pubin -outform der \\");
+                serial_println!("    | openssl dgst -sha256 -binary | xxd -p -c32");
+                return;
+            }
+            match pin_verifier::parse_hex(arg) {
+                Some(hash) => {
+                    pin_verifier::set_pin_override(hash);
+                    serial_println!("SPKI pin override set ({} bytes)", hash.len());
+                }
+                None => {
+                    serial_println!("Invalid hex hash. Expected 64 hex characters (32 bytes SHA-256).");
+                }
+            }
+        }
+        "clear" => {
+            pin_verifier::clear_pin_override();
+            let cleared = crate::sqlite::config_delete("tls.pin")
+                .and_then(|_| crate::sqlite::config_delete("tls.pin_enforce"));
+            match cleared {
+                Ok(()) => serial_println!("SPKI pin override cleared. Using compiled-in pins."),
+                Err(e) => serial_println!("pin: override cleared, but tls.pin stays: {}", e),
+            }
+        }
+        "check" => {
+            let mut words = arg.split_whitespace();
+            let Some(path) = words.next() else {
+                serial_println!("usage: pin check <cert.pem|cert.der> [host]");
+                serial_println!("  Save the server's cert on the host: openssl s_client \\");
+                serial_println!("    -connect api.anthropic.com:443 </dev/null | openssl x509 > cert.pem");
+                return;
+            };
+            let host = words.next().unwrap_or(crate::api::API_HOST);
+            if let Some(hash) = pin_check(path, host) {
+                if pin_verifier::pin() != Some(hash) {
+                    serial_println!("  to pin it: pin set {}", pin_verifier::hex(&hash));
+                }
+            }
+        }
+        "tofu" => cmd_pin_tofu(arg.trim()),
+        _ => {
+            serial_println!("usage: pin [show|set <hex>|clear|check <cert> [host]|tofu <cert>]");
+        }
+    }
+}
+
+/// `pin check`: inspect a saved certificate as if `host` had presented
+/// it, and remember its SPKI hash as the observed one.
+fn pin_check(path: &str, host: &str) -> Option<[u8; 32]> {
+    use crate::crypto::pin_verifier;
+
+    let path = canonical("pin", path)?;
+    let raw = if crate::fs::tmpfs::is_tmp(&path) {
+        crate::fs::tmpfs::read(&path).ok_or_else(|| alloc::format!("{}: no such file", path))
+    } else {
+        super::transfer::read_file(&path)
+    };
+    let cert = match raw.and_then(|raw| pem_or_der(&raw)) {
+        Ok(cert) => cert,
+        Err(e) => {
+            serial_println!("pin: {}", e);
+            return None;
+        }
+    };
+    let now = crate::vfs::sqlite_vfs::unix_time();
+    let report = match pin_verifier::inspect(&cert, host, now) {
+        Ok(report) => report,
+        Err(e) => {
+            serial_println!("pin: {}: not an X.509 certificate ({:?})", path, e);
+            return None;
+        }
+    };
+
+    serial_println!("notAfter: {} ({} day(s) left)", report.not_after, (report.expires - now) / 86_400);
+    serial_println!("DNS names: {}", report.dns_names.join(", "));
+    for warning in &report.warnings {
+        serial_println!("{}", paint(Style::Yellow, &alloc::format!("warning: {}", warning)));
+    }
+    serial_println!("SPKI sha256: {}", pin_verifier::hex(&report.spki_hash));
+    pin_verifier::record_observed(host, report.spki_hash);
+    Some(report.spki_hash)
+}
+
+/// `pin tofu <cert>`: trust the key of a certificate saved from the API
+/// host. Shows what `pin check` shows and, if confirmed, persists its SPKI
+/// hash as the pin and asks for enforcement.
+///
+/// Not trust on first use in the strict sense: embedded-tls 0.18 doesn't
+/// hand over the server's certificate (see crypto/pin_verifier.rs), so the
+/// key can't be taken from a live handshake — the saved copy stands in for
+/// it. Enforcement stays off until `ENFORCE_PINNING` can be turned on, and
+/// the command says so.
+fn cmd_pin_tofu(cert: &str) {
+    use crate::crypto::pin_verifier;
+    let host = crate::api::API_HOST;
+
+    if cert.is_empty() {
+        serial_println!("pin: the handshake doesn't expose {}'s certificate; save it and pass it:", host);
+        serial_println!("  openssl s_client -connect {}:443 </dev/null | openssl x509 > cert.pem", host);
+        serial_println!("  then: pin tofu cert.pem");
+        return;
+    }
+    let Some(hash) = pin_check(cert, host) else { return };
+
+    if pin_verifier::pin() == Some(hash) && pin_verifier::enforce_requested() {
+        serial_println!("pin: already pinned");
+    } else {
+        if !super::line::confirm(&alloc::format!("Trust this key for {} from now on?", host)) {
+            serial_println!("pin: nothing changed");
+            return;
+        }
+        if let Err(e) = pin_verifier::trust(hash) {
+            serial_println!("pin: {}", e);
+            return;
+        }
+        serial_println!("tls.pin = {}", pin_verifier::hex(&hash));
+        serial_println!("tls.pin_enforce = on");
+    }
+    if !pin_verifier::enforcing() {
+        serial_println!("pin: not enforced yet: this TLS stack can't show the server's certificate");
+    }
+}
+
+/// The DER of a certificate given either as DER or as PEM.
+fn pem_or_der(raw: &[u8]) -> Result<alloc::vec::Vec<u8>, alloc::string::String> {
+    const BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+    const END: &str = "-----END CERTIFICATE-----";
+    let Ok(text) = core::str::from_utf8(raw) else {
+        return Ok(raw.to_vec());
+    };
+    let Some((_, body)) = text.split_once(BEGIN) else {
+        return Ok(raw.to_vec());
+    };
+    let body = body.split_once(END).map(|(body, _)| body).ok_or("PEM: no END CERTIFICATE line")?;
+    crate::lua::util::base64_decode(body.as_bytes()).map_err(alloc::string::String::from)
+}
+
+/// `sql [-csv|-json] [--limit N] <stmt>` — stream rows to the console a
+/// page at a time. Ctrl-C interrupts the statement; `q` at the page
+/// prompt stops output.
+fn cmd_sql(args: &str) {
+    use crate::sqlite::OutputMode;
+    const USAGE: &str = "usage: sql [-csv|-json] [--limit N] <statement>";
+
+    let mut query = args.trim_start();
+    let mut limit = None;
+    let mut mode = OutputMode::List;
+    while query.starts_with('-') {
+        let (flag, rest) = query.split_at(query.find(' ').unwrap_or(query.len()));
+        let rest = rest.trim_start();
+        match flag {
+            "-csv" => mode = OutputMode::Csv,
+            "-json" => mode = OutputMode::Json,
+            "--limit" => {
+                let (n, tail) = rest.split_at(rest.find(' ').unwrap_or(rest.len()));
+                match n.parse::<u64>() {
+                    Ok(n) => limit = Some(n),
+                    Err(_) => {
+                        serial_println!("{}", USAGE);
+                        return;
+                    }
+                }
+                query = tail.trim_start();
+                continue;
+            }
+            _ => break, // e.g. a "--" SQL comment
+        }
+        query = rest;
+    }
+    if query.is_empty() {
+        serial_println!("{}", USAGE);
+        return;
+    }
+
+    run_sql(query, mode, limit);
+}
+
+/// Run one statement with paged output (shared by `sql` and `sqlsh`).
+pub(crate) fn run_sql(query: &str, mode: crate::sqlite::OutputMode, limit: Option<u64>) {
+    let mut span = crate::span::begin("sql");
+    // `sql.page_size` overrides the screen height
+    let mut out = match crate::sqlite::config_get("sql.page_size").and_then(|v| v.trim().parse().ok()) {
+        Some(n) => super::pager::Pager::with_page(Some(n)),
+        None => super::pager::Pager::new(),
+    };
+
+    let abort = Some(super::line::interrupt_pending as fn() -> bool);
+    let mut rows = 0u64;
+    let result = crate::sqlite::exec_streamed(query, mode, limit, abort, |line, is_row| {
+        // Only the list-mode column header is styled; CSV/JSON stay verbatim
+        let header = !is_row && rows == 0 && mode == crate::sqlite::OutputMode::List && line != "OK";
+        if is_row {
+            rows += 1;
+        }
+        if header {
+            out.line(paint(Style::Bold, line))
+        } else {
+            out.line(line)
+        }
+    });
+
+    match result {
+        Ok(rows) if limit == Some(rows) && !out.quit() => {
+            serial_println!("(--limit {} reached)", rows);
+        }
+        Ok(_) => {}
+        // The rows already shown are the partial result
+        Err(_) if super::line::interrupt_pending() => {
+            span.fail();
+            serial_println!("{}", paint(Style::Dim, format_args!("[interrupted after {} row(s)]", rows)));
+        }
+        Err(e) => {
+            span.fail();
+            serial_println!("{}", paint(Style::Red, format_args!("SQL error: {}{}", e, crate::span::tag())));
+        }
+    }
+}
+
+/// Log the shutdown, then close the database and flush storage so the
+/// next boot finds a clean disk. Failures are reported but never block
+/// the power transition.
+fn flush_storage(how: &str) {
+    if crate::sqlite::mount_mode().is_none() {
+        return; // No storage mounted
+    }
+    crate::sqlite::lifecycle::record(crate::sqlite::lifecycle::Kind::Shutdown, how);
+    serial_println!("Closing database and flushing storage...");
+    match crate::sqlite::shutdown() {
+        Ok(()) => serial_println!("Storage flushed."),
+        Err(e) => serial_println!("warning: storage flush failed: {}", e),
+    }
+}
+
+fn cmd_shutdown() {
+    flush_storage("poweroff");
+    serial_println!("Powering off...");
+    crate::arch::x86_64::acpi::poweroff();
+    serial_println!("Power-off failed. It is now safe to turn off the machine.");
+    halt_forever();
+}
+
+fn cmd_halt() {
+    flush_storage("halt");
+    serial_println!("System halted. It is now safe to turn off the machine.");
+    halt_forever();
+}
+
+fn halt_forever() -> ! {
+    crate::arch::x86_64::cli();
+    loop {
+        crate::arch::x86_64::hlt();
+    }
+}
+
+fn cmd_reboot() {
+    flush_storage("reboot");
+    serial_println!("Rebooting...");
+    // Write 0xFE to keyboard controller port 0x64 = CPU reset
+    crate::arch::x86_64::outb(0x64, 0xFE);
+    // If that didn't work, triple fault
+    loop {
         unsafe { core::arch::asm!("hlt"); }
     }
 }
 
 fn cmd_run(path: &str) {
+    let Some(path) = canonical("run", path) else {
+        return;
+    };
+    let path = path.as_str();
     serial_println!("[lua] running agent: {}", path);
     match crate::lua::run_agent(path) {
         Ok(()) => serial_println!("[lua] agent finished."),
@@ -567,6 +1849,9 @@ fn cmd_run(path: &str) {
 }
 
 fn cmd_store(path: &str, code: &str) {
+    let Some(path) = canonical("store", path) else {
+        return;
+    };
     let guard = crate::sqlite::DB.lock();
     let db = match guard.as_ref() {
         Some(db) => db,
@@ -589,9 +1874,672 @@ fn cmd_store(path: &str, code: &str) {
     }
 }
 
-fn cmd_agent(prompt: &str, use_tls: bool) {
+fn cmd_diff(a: &str, b: &str) {
+    let (Some(a), Some(b)) = (canonical("diff", a), canonical("diff", b)) else {
+        return;
+    };
+    let (a, b) = (a.as_str(), b.as_str());
+    let guard = crate::sqlite::DB.lock();
+    let Some(db) = guard.as_ref() else {
+        serial_println!("error: database not open");
+        return;
+    };
+    let read = |path: &str| -> Result<alloc::string::String, alloc::string::String> {
+        let result = db.query_params(
+            "SELECT unpack(content, compressed) FROM namespace WHERE path = ?",
+            &[crate::sqlite::SqlValue::Text(alloc::string::String::from(path))],
+        )?;
+        match result.rows.first() {
+            Some(row) => Ok(alloc::string::String::from(
+                row.first().and_then(crate::sqlite::SqlValue::as_str).unwrap_or(""),
+            )),
+            None => Err(alloc::format!("{}: not found", path)),
+        }
+    };
+    let (old, new) = match (read(a), read(b)) {
+        (Ok(old), Ok(new)) => (old, new),
+        (Err(e), _) | (_, Err(e)) => {
+            serial_println!("diff: {}", e);
+            return;
+        }
+    };
+    drop(guard);
+
+    let out = crate::diff::unified(&old, &new, a, b, crate::diff::DEFAULT_CONTEXT);
+    if out.is_empty() {
+        serial_println!("files are identical");
+    } else {
+        for line in out.lines() {
+            let style = match line.as_bytes().first() {
+                _ if line.starts_with("---") || line.starts_with("+++") => Some(Style::Bold),
+                Some(b'-') => Some(Style::Red),
+                Some(b'+') => Some(Style::Green),
+                Some(b'@') => Some(Style::Cyan),
+                _ => None,
+            };
+            match style {
+                Some(style) => serial_println!("{}", paint(style, line)),
+                None => serial_println!("{}", line),
+            }
+        }
+    }
+}
+
+fn cmd_du(prefix: &str, compressed: bool) {
+    let guard = crate::sqlite::DB.lock();
+    let Some(db) = guard.as_ref() else {
+        serial_println!("error: database not open");
+        return;
+    };
+    if compressed {
+        match crate::sqlite::quota::du_compressed(db, prefix) {
+            Ok(entries) => {
+                let stored: u64 = entries.iter().map(|(_, s, _)| s).sum();
+                let original: u64 = entries.iter().map(|(_, _, o)| o).sum();
+                serial_println!("{:>10}  {:>10}  {:>5}  path", "stored", "original", "ratio");
+                for (child, s, o) in &entries {
+                    serial_println!("{:>10}  {:>10}  {:>5}  {}", s, o, ratio(*s, *o), child);
+                }
+                let total = crate::sqlite::quota::normalize(prefix);
+                serial_println!("{:>10}  {:>10}  {:>5}  {}", stored, original, ratio(stored, original), total);
+            }
+            Err(e) => serial_println!("du: {}", e),
+        }
+        return;
+    }
+    match crate::sqlite::quota::du(db, prefix) {
+        Ok(entries) => {
+            let total: u64 = entries.iter().map(|(_, bytes)| bytes).sum();
+            for (child, bytes) in &entries {
+                serial_println!("{:>10}  {}", bytes, child);
+            }
+            serial_println!("{:>10}  {}", total, crate::sqlite::quota::normalize(prefix));
+        }
+        Err(e) => serial_println!("du: {}", e),
+    }
+}
+
+/// Stored size as a percentage of the original, for `du --compressed`.
+fn ratio(stored: u64, original: u64) -> alloc::string::String {
+    match (stored * 100).checked_div(original) {
+        Some(pct) => alloc::format!("{}%", pct),
+        None => alloc::string::String::from("-"),
+    }
+}
+
+fn cmd_quota_list() {
+    let guard = crate::sqlite::DB.lock();
+    let Some(db) = guard.as_ref() else {
+        serial_println!("error: database not open");
+        return;
+    };
+    match crate::sqlite::quota::list(db) {
+        Ok(quotas) if quotas.is_empty() => serial_println!("no quotas set"),
+        Ok(quotas) => {
+            for q in quotas {
+                match q.limit {
+                    Some(limit) => serial_println!("  {:<24} {:>10} / {} bytes", q.prefix, q.used, limit),
+                    None => serial_println!("  {:<24} {:>10} / ? (unparseable limit)", q.prefix, q.used),
+                }
+            }
+        }
+        Err(e) => serial_println!("quota: {}", e),
+    }
+}
+
+fn cmd_quota_set(prefix: &str, size: &str) {
+    let limit = match size {
+        "off" => None,
+        _ => match crate::sqlite::quota::parse_size(size) {
+            Some(bytes) => Some(bytes),
+            None => {
+                serial_println!("quota: {}: expected bytes with optional K/M/G, or off", size);
+                return;
+            }
+        },
+    };
+    let guard = crate::sqlite::DB.lock();
+    let Some(db) = guard.as_ref() else {
+        serial_println!("error: database not open");
+        return;
+    };
+    let prefix = crate::sqlite::quota::normalize(prefix);
+    match crate::sqlite::quota::set(db, &prefix, limit) {
+        Ok(()) => match limit {
+            Some(bytes) => serial_println!("quota: {} limited to {} bytes", prefix, bytes),
+            None => serial_println!("quota: {} unlimited", prefix),
+        },
+        Err(e) => serial_println!("quota: {}", e),
+    }
+}
+
+fn cmd_chmod(mode: &str, path: &str) {
+    let Ok(mode) = i64::from_str_radix(mode, 8) else {
+        serial_println!("chmod: {}: not an octal mode", mode);
+        return;
+    };
+    if mode > 0o777 {
+        serial_println!("chmod: mode must be at most 777");
+        return;
+    }
+    let Some(path) = canonical("chmod", path) else {
+        return;
+    };
+    let path = path.as_str();
+    let guard = crate::sqlite::DB.lock();
+    match guard.as_ref() {
+        Some(db) => match crate::sqlite::acl::chmod(db, path, mode) {
+            Ok(()) => serial_println!("{}: mode {:03o}", path, mode),
+            Err(e) => serial_println!("chmod: {}", e),
+        },
+        None => serial_println!("error: database not open"),
+    }
+}
+
+fn cmd_chown(owner: &str, path: &str) {
+    let Some(principal) = crate::sqlite::acl::Principal::parse(owner) else {
+        serial_println!("chown: {}: expected shell, agent:<path> or styx:<uname>", owner);
+        return;
+    };
+    let Some(path) = canonical("chown", path) else {
+        return;
+    };
+    let path = path.as_str();
+    let guard = crate::sqlite::DB.lock();
+    match guard.as_ref() {
+        Some(db) => match crate::sqlite::acl::chown(db, path, &principal) {
+            Ok(()) => serial_println!("{}: owner {}", path, principal),
+            Err(e) => serial_println!("chown: {}", e),
+        },
+        None => serial_println!("error: database not open"),
+    }
+}
+
+fn cmd_archive(sub: &str, path: &str, prefix: &str) {
+    match sub {
+        "create" if !path.is_empty() => match crate::sqlite::archive::create(path, prefix) {
+            Ok((entries, bytes)) => {
+                serial_println!("archive: {} entr{} from {} -> {} ({} bytes)",
+                    entries, if entries == 1 { "y" } else { "ies" }, prefix, path, bytes);
+            }
+            Err(e) => serial_println!("error: {}", e),
+        },
+        "extract" if !path.is_empty() => match crate::sqlite::archive::extract(path) {
+            Ok(entries) => serial_println!("archive: extracted {} entr{} from {}",
+                entries, if entries == 1 { "y" } else { "ies" }, path),
+            Err(e) => serial_println!("error: {}", e),
+        },
+        _ => serial_println!("usage: archive create <dest> [prefix] | archive extract <src>"),
+    }
+}
+
+fn cmd_audit(sub: &str, arg: &str) {
+    match sub {
+        "export" if !arg.is_empty() => match crate::sqlite::audit::export(arg) {
+            Ok(rows) => serial_println!("audit: exported {} row(s) to {}", rows, arg),
+            Err(e) => serial_println!("error: {}", e),
+        },
+        "prune" => {
+            let n = crate::maintenance::audit_retention();
+            serial_println!("audit: pruned {} row(s)", n);
+        }
+        _ => serial_println!("usage: audit export <path> | audit prune"),
+    }
+}
+
+/// Stream /sys/audit to the console a batch at a time.
+fn cmd_cat_audit() {
+    let mut cursor = 0;
+    loop {
+        match crate::sqlite::audit::ndjson_after(cursor, 4096) {
+            Ok((chunk, _)) if chunk.is_empty() => break,
+            Ok((chunk, next)) => {
+                serial_print!("{}", core::str::from_utf8(&chunk).unwrap_or(""));
+                cursor = next;
+            }
+            Err(e) => {
+                serial_println!("error: {}", e);
+                break;
+            }
+        }
+    }
+}
+
+fn cmd_events<'a>(mut args: impl Iterator<Item = &'a str>) {
+    use crate::sqlite::lifecycle;
+
+    let mut kind = None;
+    let mut limit = 20;
+    while let Some(flag) = args.next() {
+        let ok = match (flag, args.next()) {
+            ("-k", Some(k)) => {
+                kind = Some(k);
+                true
+            }
+            ("-n", Some(n)) => n.parse().map(|n| limit = n).is_ok(),
+            _ => false,
+        };
+        if !ok {
+            serial_println!("usage: events [-k kind] [-n count]");
+            return;
+        }
+    }
+
+    // Try to write out what is queued first
+    lifecycle::flush();
+    match lifecycle::recent(limit, kind) {
+        Ok(events) => {
+            for e in &events {
+                serial_println!("#{} {} +{}s {:<8} {}", e.id, e.time, e.uptime_ms / 1000, e.kind, e.detail);
+            }
+            if events.is_empty() {
+                serial_println!("no events recorded");
+            }
+        }
+        Err(e) => serial_println!("events: {}", e),
+    }
+    for p in lifecycle::pending().iter().filter(|p| kind.is_none_or(|k| k == p.kind.as_str())) {
+        serial_println!("pending +{}s {:<8} {}", p.uptime_ms / 1000, p.kind, p.detail);
+    }
+    let dropped = lifecycle::dropped();
+    if dropped > 0 {
+        serial_println!("({} events dropped before they could be written)", dropped);
+    }
+}
+
+fn cmd_changes<'a>(mut args: impl Iterator<Item = &'a str>) {
+    let mut filter = crate::sqlite::changes::Filter::default();
+    while let Some(flag) = args.next() {
+        let ok = match (flag, args.next()) {
+            ("-p", Some(prefix)) => {
+                filter.prefix = Some(alloc::string::String::from(prefix));
+                true
+            }
+            ("-c", Some(conv)) => {
+                filter.conversation = Some(alloc::string::String::from(conv));
+                true
+            }
+            ("-n", Some(n)) => n.parse().map(|n| filter.limit = n).is_ok(),
+            _ => false,
+        };
+        if !ok {
+            serial_println!("usage: changes [-p prefix] [-c conversation] [-n count]");
+            return;
+        }
+    }
+
+    let guard = crate::sqlite::DB.lock();
+    let Some(db) = guard.as_ref() else {
+        serial_println!("error: database not open");
+        return;
+    };
+    let entries = match crate::sqlite::changes::query(db, &filter) {
+        Ok(entries) => entries,
+        Err(e) => {
+            serial_println!("changes: {}", e);
+            return;
+        }
+    };
+    drop(guard);
+
+    if entries.is_empty() {
+        serial_println!("no changes recorded");
+        return;
+    }
+    for e in &entries {
+        let before = e.before_hash.as_deref().map_or("new", |h| &h[..h.len().min(12)]);
+        serial_println!(
+            "#{} {} conv {} {} {} +{} -{} {}..{} ({})",
+            e.id, e.ts, e.conversation, e.tool, e.path, e.added, e.removed,
+            before, &e.after_hash[..e.after_hash.len().min(12)], e.tool_use_id
+        );
+        if !e.reason.is_empty() {
+            for line in e.reason.lines() {
+                serial_println!("    {}", line);
+            }
+        }
+    }
+}
+
+fn cmd_cache_list() {
+    match crate::sqlite::cache::ttl() {
+        Some(secs) => serial_println!("cache: on, entries live {}s", secs),
+        None => serial_println!("cache: off (config set cache.ttl <duration> to enable)"),
+    }
+    let entries = match crate::sqlite::cache::list() {
+        Ok(entries) => entries,
+        Err(e) => {
+            serial_println!("cache: {}", e);
+            return;
+        }
+    };
+    let now = crate::sqlite::DB
+        .lock()
+        .as_ref()
+        .and_then(|db| db.query_value("SELECT strftime('%s','now')").ok().flatten())
+        .and_then(|v| v.parse::<i64>().ok())
+        .unwrap_or(0);
+    for e in &entries {
+        let preview: alloc::string::String = e.response.chars().take(40).filter(|c| !c.is_control()).collect();
+        let state = if e.expires > now {
+            alloc::format!("expires in {}s", e.expires - now)
+        } else {
+            alloc::string::String::from("expired")
+        };
+        serial_println!(
+            "  {}  {}  {} hits  {} bytes  {}  {}",
+            &e.key[..e.key.len().min(12)], e.model, e.hits, e.response.len(), state, preview
+        );
+    }
+    serial_println!("{} entr{}", entries.len(), if entries.len() == 1 { "y" } else { "ies" });
+}
+
+fn cmd_cache_clear(expired_only: bool) {
+    match crate::sqlite::cache::clear("", expired_only) {
+        Ok(n) => serial_println!("cache: removed {} entr{}", n, if n == 1 { "y" } else { "ies" }),
+        Err(e) => serial_println!("cache: {}", e),
+    }
+}
+
+fn cmd_config(sub: &str, key: &str, value: &str) {
+    use crate::storage::MountMode;
+
+    match sub {
+        "list" => {
+            if let Some(mode) = crate::sqlite::mount_mode() {
+                serial_println!("storage.mode (effective) = {}", mode);
+            }
+            match crate::sqlite::config_list() {
+                Ok(rows) => {
+                    for (k, v) in rows {
+                        serial_println!("{} = {}", k, v);
+                    }
+                }
+                Err(e) => serial_println!("error: {}", e),
+            }
+        }
+        "get" if !key.is_empty() => {
+            match crate::sqlite::config_get(key) {
+                Some(v) => serial_println!("{}", v),
+                None => serial_println!("config: {}: not set", key),
+            }
+        }
+        "set" if !key.is_empty() && !value.is_empty() => {
+            if key == "storage.mode" {
+                let mode = match MountMode::parse(value) {
+                    Some(m) => m,
+                    None => {
+                        serial_println!("config: storage.mode must be 'rw' or 'ro'");
+                        return;
+                    }
+                };
+                // Persist while still writable, then switch. Going back to
+                // rw switches first so the row can be rewritten.
+                let result = if mode.is_writable() {
+                    crate::sqlite::set_mount_mode(mode)
+                        .and_then(|_| crate::sqlite::config_set(key, value))
+                } else {
+                    let persisted = crate::sqlite::config_set(key, value);
+                    crate::sqlite::set_mount_mode(mode).and(persisted)
+                };
+                match result {
+                    Ok(()) => serial_println!("storage.mode = {}", mode),
+                    Err(e) => serial_println!("error: {}", e),
+                }
+                return;
+            }
+            if key == "storage.iocap" {
+                match value {
+                    "on" => crate::sqlite::set_device_caps(true),
+                    "off" => crate::sqlite::set_device_caps(false),
+                    _ => {
+                        serial_println!("config: storage.iocap must be 'on' or 'off'");
+                        return;
+                    }
+                }
+            }
+            if key == "storage.readahead" {
+                match value.parse::<u16>() {
+                    Ok(n) if n <= crate::storage::cache::MAX_READAHEAD => crate::sqlite::set_readahead(n),
+                    _ => {
+                        serial_println!(
+                            "config: storage.readahead must be 0 to {} blocks",
+                            crate::storage::cache::MAX_READAHEAD
+                        );
+                        return;
+                    }
+                }
+            }
+            if key == "storage.sync_warn_ms" {
+                match value.parse::<u64>() {
+                    Ok(ms) => crate::sqlite::set_sync_warn_ms(ms),
+                    Err(_) => {
+                        serial_println!("config: storage.sync_warn_ms must be a number of milliseconds");
+                        return;
+                    }
+                }
+            }
+            if key == "sqlite.mmap_size" && value.parse::<u64>().is_err() {
+                serial_println!("config: sqlite.mmap_size must be a number of bytes");
+                return;
+            }
+            if key == "storage.compress" && value != "on" && value != "off" {
+                serial_println!("config: storage.compress must be 'on' or 'off'");
+                return;
+            }
+            if key == "term.color" {
+                match crate::term::ColorMode::parse(value) {
+                    Some(mode) => crate::term::set_mode(mode),
+                    None => {
+                        serial_println!("config: term.color must be 'on', 'off' or 'auto'");
+                        return;
+                    }
+                }
+                if crate::term::mode() == crate::term::ColorMode::Auto {
+                    crate::term::detect();
+                }
+            }
+            if key == "net.mtu" {
+                use crate::drivers::virtio::net::{MAX_MTU, MIN_MTU};
+                match value.parse::<usize>() {
+                    Ok(mtu) if (MIN_MTU..=MAX_MTU).contains(&mtu) => {
+                        serial_println!("config: net.mtu takes effect at next boot");
+                    }
+                    _ => {
+                        serial_println!("config: net.mtu must be between {} and {}", MIN_MTU, MAX_MTU);
+                        return;
+                    }
+                }
+            }
+            if key == "net.offload" {
+                if value != "on" && value != "off" {
+                    serial_println!("config: net.offload must be 'on' or 'off'");
+                    return;
+                }
+                serial_println!("config: net.offload takes effect at next boot");
+            }
+            if key == "net.prefer" {
+                match crate::net::dns::Family::parse(value) {
+                    Some(family) => crate::net::dns::set_preference(family),
+                    None => {
+                        serial_println!("config: net.prefer must be 'ipv4' or 'ipv6'");
+                        return;
+                    }
+                }
+            }
+            if key == "hostname" && !crate::net::mdns::valid_hostname(value) {
+                serial_println!("config: hostname must be letters, digits and '-' (up to 63)");
+                return;
+            }
+            if key == "syslog.host" && value != "off" && crate::net::syslog::parse_target(value).is_none() {
+                serial_println!("config: syslog.host must be 'off', IP, IP:PORT or [IPv6]:PORT");
+                return;
+            }
+            if key == "syslog.audit" && value != "on" && value != "off" {
+                serial_println!("config: syslog.audit must be 'on' or 'off'");
+                return;
+            }
+            if key == "styx.port" && value != "off" && crate::fs::styx::listen::parse_port(value).is_none() {
+                serial_println!("config: styx.port must be 'off' or a port number");
+                return;
+            }
+            if key == "boot.selftest" && value != "on" && value != "off" {
+                serial_println!("config: boot.selftest must be 'on' or 'off'");
+                return;
+            }
+            if key == "agent.route" && super::agent::Route::parse(value).is_none() {
+                serial_println!("config: agent.route must be off, reads or followups");
+                return;
+            }
+            if key == "agent.context_tokens"
+                && !value.parse::<u64>().is_ok_and(|n| n >= super::context::MIN_CONTEXT_TOKENS)
+            {
+                serial_println!(
+                    "config: agent.context_tokens must be at least {} tokens",
+                    super::context::MIN_CONTEXT_TOKENS
+                );
+                return;
+            }
+            if (key == "agent.result_max" || key.starts_with("agent.result_max."))
+                && crate::sqlite::quota::parse_size(value).is_none()
+            {
+                serial_println!("config: {} must be bytes with optional K/M/G", key);
+                return;
+            }
+            if key == "tmp.size" && crate::sqlite::quota::parse_size(value).is_none() {
+                serial_println!("config: tmp.size must be bytes with optional K/M/G");
+                return;
+            }
+            if key == "boot.autorun" && !value.starts_with('/') {
+                serial_println!("config: boot.autorun must be a namespace path, e.g. /agents/init.lua");
+                return;
+            }
+            if key == "api.timeout"
+                && !value.parse::<u64>().is_ok_and(|n| n >= crate::api::MIN_REQUEST_TIMEOUT_SECS)
+            {
+                serial_println!("config: api.timeout must be at least {} seconds", crate::api::MIN_REQUEST_TIMEOUT_SECS);
+                return;
+            }
+            if key == "tls.cipher" && value != "auto" && crate::net::tls::Suite::parse(value).is_none() {
+                serial_println!("config: tls.cipher must be auto, aes128-gcm, aes256-gcm or chacha20-poly1305");
+                return;
+            }
+            if key == "tls.pin" && crate::crypto::pin_verifier::parse_hex(value).is_none() {
+                serial_println!("config: tls.pin must be 64 hex characters (SHA-256 of the SPKI)");
+                return;
+            }
+            if key == "tls.pin_enforce" && value != "on" && value != "off" {
+                serial_println!("config: tls.pin_enforce must be 'on' or 'off'");
+                return;
+            }
+            if key.starts_with("serial.") {
+                if let Err(e) = apply_serial_setting(key, value) {
+                    serial_println!("config: {}: {}", key, e);
+                    return;
+                }
+            }
+            match crate::sqlite::config_set(key, value) {
+                Ok(()) => {
+                    serial_println!("{} = {}", key, value);
+                    if key.starts_with("syslog.") {
+                        crate::net::syslog::configure();
+                    }
+                    if key == "hostname" {
+                        crate::net::mdns::configure();
+                    }
+                    if key == "styx.port" {
+                        crate::fs::styx::listen::configure();
+                    }
+                    if key == "tmp.size" {
+                        crate::fs::tmpfs::configure();
+                    }
+                }
+                Err(e) => serial_println!("error: {}", e),
+            }
+        }
+        _ => {
+            serial_println!("usage: config [list]");
+            serial_println!("       config get <key>");
+            serial_println!("       config set <key> <value>");
+        }
+    }
+}
+
+/// Apply one `serial.*` setting to the console now. Persisted values are
+/// re-applied at boot by `apply_serial_config`.
+fn apply_serial_setting(key: &str, value: &str) -> Result<(), &'static str> {
+    use crate::arch::x86_64::serial::{FlowControl, SERIAL};
+
+    let mut serial = SERIAL.lock();
+    match key {
+        "serial.baud" => {
+            let baud = value.parse::<u32>().map_err(|_| "expected a number of bits per second")?;
+            let clock = serial.clock();
+            serial.set_baud(baud, clock)
+        }
+        "serial.clock" => {
+            let clock = value.parse::<u32>().map_err(|_| "expected the UART clock in Hz")?;
+            let baud = serial.baud();
+            serial.set_baud(baud, clock)
+        }
+        "serial.flow" => {
+            let flow = FlowControl::parse(value).ok_or("must be none, xon or rts")?;
+            serial.set_flow(flow);
+            Ok(())
+        }
+        _ => Err("unknown serial setting (serial.baud, serial.clock, serial.flow)"),
+    }
+}
+
+/// Re-apply the persisted `serial.*` settings (called once the database is
+/// open). A bad value is reported and skipped; the console stays usable.
+pub fn apply_serial_config() {
+    // Clock first: the baud divisor depends on it
+    for key in ["serial.clock", "serial.baud", "serial.flow"] {
+        if let Some(value) = crate::sqlite::config_get(key) {
+            if let Err(e) = apply_serial_setting(key, &value) {
+                serial_println!("[serial] Ignoring {}={}: {}", key, value, e);
+            }
+        }
+    }
+    let serial = crate::arch::x86_64::serial::SERIAL.lock();
+    let (baud, flow) = (serial.baud(), serial.flow());
+    drop(serial);
+    serial_println!("[serial] {} baud, flow control {}", baud, flow.as_str());
+}
+
+fn cmd_agent<'a>(cmd: &str, mut words: impl Iterator<Item = &'a str>) {
+    let use_tls = cmd == "agent";
+    let mut budget = super::agent::Budget::from_config();
+    let mut prompt: alloc::vec::Vec<&str> = alloc::vec::Vec::new();
+    while let Some(word) = words.next() {
+        if !prompt.is_empty() || !word.starts_with("--") {
+            prompt.push(word);
+            continue;
+        }
+        let value = words.next().unwrap_or("");
+        let ok = match word {
+            "--max-turns" => value.parse().ok().filter(|&n| n > 0).map(|n| budget.max_turns = n).is_some(),
+            "--max-seconds" => crate::sqlite::audit::parse_duration(value).map(|n| budget.max_seconds = (n > 0).then_some(n)).is_some(),
+            "--max-tokens" => value.parse::<u64>().map(|n| budget.max_tokens = (n > 0).then_some(n)).is_ok(),
+            _ => false,
+        };
+        if !ok {
+            serial_println!("{}: bad option {} {}", cmd, word, value);
+            prompt.clear();
+            break;
+        }
+    }
+    if prompt.is_empty() {
+        serial_println!("usage: {} [--max-turns N] [--max-seconds S] [--max-tokens T] <prompt>", cmd);
+        serial_println!("  Starts an agentic loop with tool use (read, write, sql, etc.){}", if use_tls { "" } else { " via proxy" });
+        serial_println!("  Defaults: config agent.max_turns (20), agent.max_seconds, agent.max_tokens (0 = no limit)");
+        return;
+    }
+    let prompt = prompt.join(" ");
+
     serial_println!("[agent] Starting agentic loop...");
-    match super::agent::run_agent_loop(prompt, use_tls) {
+    match super::agent::run_agent_loop(&prompt, use_tls, &budget) {
         Ok(_) => {
             serial_println!("[agent] Done.");
         }
@@ -0,0 +1,182 @@
+/// Keeping an agent run's conversation inside the model's context window.
+///
+/// Each message is sized at `BYTES_PER_TOKEN` bytes a token, scaled by how
+/// far the previous request's estimate was from the `input_tokens` the API
+/// billed for it. Before a request whose estimate passes `COMPACT_PERCENT`
+/// of the window (`agent.context_tokens`, default `CONTEXT_TOKENS`),
+/// `fit` drops the oldest exchanges — an assistant message and the tool
+/// results answering it — until the estimate is under half the window,
+/// always keeping the first prompt and the last `KEEP_MESSAGES` messages.
+/// What the dropped turns said and did is kept as one line each in a note
+/// appended to the system prompt, so the model knows its own history and
+/// the loop can run for as long as the budget allows.
+///
+/// Compaction is rule-based: summarizing through the model would spend a
+/// request, and its tokens, every time the window fills.
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::api::{ContentBlock, Message};
+
+/// Context window when `agent.context_tokens` is unset.
+pub const CONTEXT_TOKENS: u64 = 200_000;
+
+/// Smallest `agent.context_tokens` accepted.
+pub const MIN_CONTEXT_TOKENS: u64 = 4_000;
+
+/// Compact before a request estimated over this share of the window.
+const COMPACT_PERCENT: u64 = 75;
+
+/// Messages never dropped from the end: the last exchange.
+const KEEP_MESSAGES: usize = 2;
+
+/// Unscaled estimate: text is around four bytes a token.
+const BYTES_PER_TOKEN: u64 = 4;
+
+/// Longest line in the note for one dropped call or reply.
+const NOTE_LINE_MAX: usize = 160;
+
+/// Lines of note kept; the oldest go first.
+const NOTE_LINES_MAX: usize = 60;
+
+/// The window and what has been dropped from it, for one run.
+pub struct Context {
+    window: u64,
+    /// Billed over estimated tokens, in thousandths, from the last request.
+    scale_milli: u64,
+    /// One line per dropped reply or tool call, oldest first.
+    notes: Vec<String>,
+}
+
+impl Context {
+    /// The window from config `agent.context_tokens`. Takes the DB lock.
+    pub fn from_config() -> Self {
+        let window = crate::sqlite::config_get("agent.context_tokens")
+            .and_then(|v| v.trim().parse::<u64>().ok())
+            .filter(|&n| n >= MIN_CONTEXT_TOKENS)
+            .unwrap_or(CONTEXT_TOKENS);
+        Self::with_window(window)
+    }
+
+    pub fn with_window(window: u64) -> Self {
+        Context { window, scale_milli: 1000, notes: Vec::new() }
+    }
+
+    /// The system prompt to send: `base`, then the note on dropped turns.
+    pub fn system(&self, base: &str) -> String {
+        if self.notes.is_empty() {
+            return String::from(base);
+        }
+        let mut out = format!(
+            "{}\n\nEarlier turns of this run were dropped to fit the context window. What they did:\n",
+            base
+        );
+        for line in &self.notes {
+            out.push_str("- ");
+            out.push_str(line);
+            out.push('\n');
+        }
+        out
+    }
+
+    /// Estimated tokens of a request with this system prompt and messages
+    /// (`overhead` bytes for the rest, e.g. the tool definitions).
+    pub fn estimate(&self, system: &str, messages: &[Message], overhead: usize) -> u64 {
+        let bytes = system.len() + overhead + messages.iter().map(message_bytes).sum::<usize>();
+        (bytes as u64 / BYTES_PER_TOKEN) * self.scale_milli / 1000
+    }
+
+    /// Learn from a request estimated at `estimated` tokens that was
+    /// billed `billed` input tokens.
+    pub fn calibrate(&mut self, estimated: u64, billed: u64) {
+        let unscaled = estimated * 1000 / self.scale_milli.max(1);
+        if unscaled > 0 && billed > 0 {
+            self.scale_milli = (billed * 1000 / unscaled).clamp(250, 4000);
+        }
+    }
+
+    /// Drop the oldest exchanges if the next request would come too close
+    /// to the window. Returns (estimate before, after, messages dropped),
+    /// or None if nothing was dropped.
+    pub fn fit(&mut self, base: &str, messages: &mut Vec<Message>, overhead: usize) -> Option<(u64, u64, usize)> {
+        let before = self.estimate(&self.system(base), messages, overhead);
+        if before <= self.window * COMPACT_PERCENT / 100 {
+            return None;
+        }
+        // messages[0] is the prompt; assistant messages sit at odd indexes,
+        // so cutting just before one keeps the roles alternating
+        let last_cut = messages.len().saturating_sub(KEEP_MESSAGES);
+        let target = self.window / 2;
+        let mut cut = 1;
+        let mut size = before;
+        while cut + 2 <= last_cut && size > target {
+            size = size.saturating_sub(self.estimate("", &messages[cut..cut + 2], 0));
+            cut += 2;
+        }
+        if cut == 1 {
+            return None;
+        }
+        let dropped: Vec<Message> = messages.drain(1..cut).collect();
+        self.note(&dropped);
+        let after = self.estimate(&self.system(base), messages, overhead);
+        Some((before, after, dropped.len()))
+    }
+
+    /// Add a line per reply and tool call in `dropped` to the note.
+    fn note(&mut self, dropped: &[Message]) {
+        let failed: BTreeMap<&str, bool> = dropped
+            .iter()
+            .flat_map(|m| m.content_blocks.iter())
+            .filter_map(|b| match b {
+                ContentBlock::ToolResult { tool_use_id, is_error, .. } => Some((tool_use_id.as_str(), *is_error)),
+                _ => None,
+            })
+            .collect();
+        for message in dropped.iter().filter(|m| m.role == "assistant") {
+            if !message.content.is_empty() {
+                self.notes.push(format!("you said: {}", clip(&message.content)));
+            }
+            for block in &message.content_blocks {
+                match block {
+                    ContentBlock::Text(text) if !text.trim().is_empty() => {
+                        self.notes.push(format!("you said: {}", clip(text)));
+                    }
+                    ContentBlock::ToolUse { id, name, input_json } => {
+                        let outcome = if failed.get(id.as_str()).copied().unwrap_or(false) { " (failed)" } else { "" };
+                        let what = super::agent::describe_call(name, input_json);
+                        self.notes.push(format!("{}{}", what, outcome));
+                    }
+                    _ => {}
+                }
+            }
+        }
+        if self.notes.len() > NOTE_LINES_MAX {
+            let excess = self.notes.len() - NOTE_LINES_MAX;
+            self.notes.drain(..excess);
+        }
+    }
+}
+
+fn message_bytes(m: &Message) -> usize {
+    m.content.len()
+        + m.content_blocks
+            .iter()
+            .map(|b| match b {
+                ContentBlock::Text(text) => text.len(),
+                ContentBlock::ToolUse { id, name, input_json } => id.len() + name.len() + input_json.len(),
+                ContentBlock::ToolResult { tool_use_id, content, .. } => tool_use_id.len() + content.len(),
+            })
+            .sum::<usize>()
+}
+
+/// `text` on one line, cut to `NOTE_LINE_MAX` chars.
+fn clip(text: &str) -> String {
+    let flat: String = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    let mut out: String = flat.chars().take(NOTE_LINE_MAX).collect();
+    if flat.chars().count() > NOTE_LINE_MAX {
+        out.push_str("...");
+    }
+    out
+}
@@ -0,0 +1,274 @@
+/// `edit <path>` — a small ed-style line editor for namespace files.
+///
+/// The file is loaded into memory (a missing path starts empty) and edited
+/// with one-letter commands; line numbers start at 1, `$` is the last line
+/// and a range is `n,m`:
+///
+///   p [range]          print lines with numbers (default: all)
+///   a [n]              append after line n (default: end), until a lone "."
+///   i [n]              insert before line n, until a lone "."
+///   c range            replace the lines, until a lone "."
+///   d range            delete lines
+///   s n/old/new/       replace the first "old" on line n
+///   w                  write back; q quits (q! discards changes), wq both
+///   h                  this list
+///
+/// `w` stores the whole buffer in one statement, so readers see the old
+/// file or the new one, never a mix. If the file changed since it was
+/// loaded, `w` refuses — `w!` overwrites anyway. Mode and owner are kept.
+/// Only text can be edited: a file holding a blob is refused.
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::serial_print;
+use crate::serial_println;
+use crate::sqlite::acl::{self, Principal};
+use crate::sqlite::{SqlValue, DB};
+use super::line::LineEditor;
+
+/// An open file.
+struct Buffer {
+    path: String,
+    lines: Vec<String>,
+    /// Namespace type to store with (kept from the file, or guessed).
+    kind: String,
+    /// Content as loaded or last written (None = new file), to catch
+    /// concurrent writers — compared whole, as mtime only counts seconds.
+    stored: Option<String>,
+    dirty: bool,
+}
+
+/// Edit `path` until `q`, `wq` or Ctrl-D.
+pub fn run(path: &str) {
+    let Some(path) = super::commands::canonical("edit", path) else {
+        return;
+    };
+    let path = path.as_str();
+    let mut buf = match load(path) {
+        Ok(buf) => buf,
+        Err(e) => {
+            serial_println!("edit: {}", e);
+            return;
+        }
+    };
+    match buf.stored {
+        Some(_) => serial_println!("{}: {} lines (h for help)", path, buf.lines.len()),
+        None => serial_println!("{}: new file (h for help)", path),
+    }
+
+    let mut editor = LineEditor::new();
+    loop {
+        serial_print!("* ");
+        let Some(line) = editor.read_line() else {
+            if buf.dirty {
+                serial_println!("unsaved changes — w to write, q! to discard");
+                continue;
+            }
+            return;
+        };
+        let line = String::from(line.trim());
+        if line.is_empty() {
+            continue;
+        }
+        let (cmd, arg) = split_command(&line);
+        let result = match cmd {
+            "p" | "n" => print(&buf, arg),
+            "a" => address(&buf, arg, buf.lines.len()).map(|n| insert(&mut buf, &mut editor, n)),
+            "i" => insert_point(&buf, arg).map(|n| insert(&mut buf, &mut editor, n)),
+            "c" => range(&buf, arg).map(|(from, to)| {
+                buf.lines.drain(from - 1..to);
+                insert(&mut buf, &mut editor, from - 1);
+                buf.dirty = true;
+            }),
+            "d" => range(&buf, arg).map(|(from, to)| {
+                buf.lines.drain(from - 1..to);
+                buf.dirty = true;
+                serial_println!("deleted {} line(s)", to - from + 1);
+            }),
+            "s" => substitute(&mut buf, arg),
+            "w" => write(&mut buf, false),
+            "w!" => write(&mut buf, true),
+            "wq" => match write(&mut buf, false) {
+                Ok(()) => return,
+                Err(e) => Err(e),
+            },
+            "q" if buf.dirty => Err(String::from("unsaved changes — w to write, q! to discard")),
+            "q" | "q!" => return,
+            "h" => {
+                help();
+                Ok(())
+            }
+            _ => Err(alloc::format!("unknown command: {} (h for help)", cmd)),
+        };
+        if let Err(e) = result {
+            serial_println!("? {}", e);
+        }
+    }
+}
+
+fn load(path: &str) -> Result<Buffer, String> {
+    let guard = DB.lock();
+    let db = guard.as_ref().ok_or_else(|| String::from("database not open"))?;
+    let result = db.query_params(
+        "SELECT type, unpack(content, compressed), typeof(unpack(content, compressed)) \
+         FROM namespace WHERE path = ?",
+        &[SqlValue::Text(String::from(path))],
+    )?;
+    let Some(row) = result.rows.first() else {
+        let kind = if path.ends_with(".lua") { "lua" } else { "data" };
+        return Ok(Buffer { path: String::from(path), lines: Vec::new(), kind: String::from(kind), stored: None, dirty: false });
+    };
+    let kind = row.first().and_then(SqlValue::as_str).unwrap_or("data");
+    if kind == "dir" || kind == "ctl" {
+        return Err(alloc::format!("{}: {} entries can't be edited", path, kind));
+    }
+    // A blob would load as mangled text and go back as text on `w`
+    if row.get(2).and_then(SqlValue::as_str) == Some("blob") {
+        return Err(alloc::format!("{}: binary content can't be edited", path));
+    }
+    let content = row.get(1).and_then(SqlValue::as_str).unwrap_or("");
+    let mut lines: Vec<String> = content.split('\n').map(String::from).collect();
+    if content.ends_with('\n') || content.is_empty() {
+        lines.pop(); // the empty string after the final newline
+    }
+    Ok(Buffer {
+        path: String::from(path),
+        lines,
+        kind: String::from(kind),
+        stored: Some(String::from(content)),
+        dirty: false,
+    })
+}
+
+fn write(buf: &mut Buffer, force: bool) -> Result<(), String> {
+    let mut content = buf.lines.join("\n");
+    if !buf.lines.is_empty() {
+        content.push('\n');
+    }
+
+    let guard = DB.lock();
+    let db = guard.as_ref().ok_or_else(|| String::from("database not open"))?;
+    if !force {
+        let now = db
+            .query_params(
+                "SELECT unpack(content, compressed) FROM namespace WHERE path = ?",
+                &[SqlValue::Text(buf.path.clone())],
+            )?
+            .rows
+            .first()
+            .map(|r| String::from(r.first().and_then(SqlValue::as_str).unwrap_or("")));
+        if now != buf.stored {
+            return Err(String::from("file changed since it was loaded — w! to overwrite"));
+        }
+    }
+    acl::write_file(db, &Principal::Shell, &buf.path, &buf.kind, &content)?;
+    drop(guard);
+
+    serial_println!("wrote {} ({} lines, {} bytes)", buf.path, buf.lines.len(), content.len());
+    buf.stored = Some(content);
+    buf.dirty = false;
+    Ok(())
+}
+
+/// Read lines until a lone "." and insert them after line `after`.
+fn insert(buf: &mut Buffer, editor: &mut LineEditor, after: usize) {
+    let mut at = after.min(buf.lines.len());
+    while let Some(line) = editor.read_line() {
+        if line == "." {
+            break;
+        }
+        buf.lines.insert(at, String::from(line));
+        at += 1;
+        buf.dirty = true;
+    }
+}
+
+fn print(buf: &Buffer, arg: &str) -> Result<(), String> {
+    if buf.lines.is_empty() {
+        return Err(String::from("empty buffer"));
+    }
+    let (from, to) = if arg.is_empty() { (1, buf.lines.len()) } else { range(buf, arg)? };
+    for (i, line) in buf.lines[from - 1..to].iter().enumerate() {
+        serial_println!("{:>4}  {}", from + i, line);
+    }
+    Ok(())
+}
+
+/// `s n/old/new/` — any delimiter works, as in ed.
+fn substitute(buf: &mut Buffer, arg: &str) -> Result<(), String> {
+    let digits = arg.find(|c: char| !c.is_ascii_digit() && c != '$').unwrap_or(arg.len());
+    let n = address(buf, &arg[..digits], 0)?;
+    let rest = &arg[digits..];
+    let delim = rest.chars().next().ok_or_else(|| String::from("usage: s n/old/new/"))?;
+    let mut parts = rest[delim.len_utf8()..].splitn(3, delim);
+    let (Some(old), Some(new)) = (parts.next(), parts.next()) else {
+        return Err(String::from("usage: s n/old/new/"));
+    };
+    if n == 0 || old.is_empty() {
+        return Err(String::from("usage: s n/old/new/"));
+    }
+    let line = &mut buf.lines[n - 1];
+    if !line.contains(old) {
+        return Err(alloc::format!("line {}: no match", n));
+    }
+    *line = line.replacen(old, new, 1);
+    serial_println!("{:>4}  {}", n, line);
+    buf.dirty = true;
+    Ok(())
+}
+
+/// One line number (`n` or `$`), or `default` when empty. 0 is allowed
+/// for `a` (append before the first line).
+fn address(buf: &Buffer, arg: &str, default: usize) -> Result<usize, String> {
+    let n = match arg.trim() {
+        "" => default,
+        "$" => buf.lines.len(),
+        s => s.parse().map_err(|_| alloc::format!("bad line number: {}", s))?,
+    };
+    if n > buf.lines.len() {
+        return Err(alloc::format!("line {} out of range (1-{})", n, buf.lines.len()));
+    }
+    Ok(n)
+}
+
+/// Where `i [n]` inserts: before line n (default 1). An empty buffer
+/// takes its first line at 1.
+fn insert_point(buf: &Buffer, arg: &str) -> Result<usize, String> {
+    if buf.lines.is_empty() && matches!(arg.trim(), "" | "1" | "$") {
+        return Ok(0);
+    }
+    address(buf, arg, 1).map(|n| n.saturating_sub(1))
+}
+
+/// `n` or `n,m`, both within the buffer.
+fn range(buf: &Buffer, arg: &str) -> Result<(usize, usize), String> {
+    let (from, to) = match arg.split_once(',') {
+        Some((a, b)) => (address(buf, a, 1)?, address(buf, b, buf.lines.len())?),
+        None => {
+            let n = address(buf, arg, 0)?;
+            (n, n)
+        }
+    };
+    if from == 0 || from > to {
+        return Err(String::from("bad range"));
+    }
+    Ok((from, to))
+}
+
+/// "p1,3" and "p 1,3" both split into ("p", "1,3"); "w!" and "wq" stay whole.
+fn split_command(line: &str) -> (&str, &str) {
+    let end = line
+        .char_indices()
+        .find(|&(i, c)| i > 0 && !matches!(c, '!' | 'q') || c == ' ')
+        .map(|(i, _)| i)
+        .unwrap_or(line.len());
+    let end = if line.starts_with('s') { 1 } else { end };
+    (&line[..end], line[end..].trim())
+}
+
+fn help() {
+    serial_println!("p [n,m]     print lines        a [n]     append after n (end with .)");
+    serial_println!("i [n]       insert before n    c n[,m]   change lines (end with .)");
+    serial_println!("d n[,m]     delete lines       s n/a/b/  replace first a with b on line n");
+    serial_println!("w / w!      write (force)      q / q! / wq  quit");
+}
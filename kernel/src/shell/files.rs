@@ -0,0 +1,282 @@
+/// `mv`, `cp` and `rm` — namespace file management.
+///
+///   mv [-r] <src> <dst>   rename a file, or with -r src and every path under src/
+///   cp [-r] <src> <dst>   copy a file (or src and its subtree); mode and type are kept
+///   rm [-r] <path>        delete a file, or with -r path and everything under it
+///
+/// Each runs in one transaction: a failure leaves the namespace untouched.
+/// `rm` also removes files from the RAM-backed `/tmp` (`fs::tmpfs`); those
+/// leave no audit row, and `mv`/`cp` don't reach them.
+/// An existing destination is replaced. Moved and copied rows get a fresh
+/// mtime, copies belong to the shell, and every operation adds an audit row
+/// (agent "shell": FILE_MOVE, FILE_COPY, FILE_DELETE). Paths are
+/// case-insensitive (see `sqlite::namespace`): `mv` to a change of case
+/// renames in place.
+use alloc::string::String;
+
+use super::commands::canonical;
+use crate::serial_println;
+use crate::sqlite::{SqliteDb, SqlValue, DB};
+
+/// What a command applies to.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Scope {
+    File,
+    Tree,
+}
+
+/// Split `[-r] args...` into the scope and the remaining words.
+fn parse_args<'a>(args: &mut impl Iterator<Item = &'a str>) -> (Scope, alloc::vec::Vec<&'a str>) {
+    let mut scope = Scope::File;
+    let mut rest = alloc::vec::Vec::new();
+    for arg in args {
+        match arg {
+            "-r" | "-R" => scope = Scope::Tree,
+            _ => rest.push(arg),
+        }
+    }
+    (scope, rest)
+}
+
+pub fn mv<'a>(mut args: impl Iterator<Item = &'a str>) {
+    let (scope, paths) = parse_args(&mut args);
+    let [src, dst] = paths[..] else {
+        serial_println!("usage: mv [-r] <src> <dst>");
+        return;
+    };
+    let (Some(src), Some(dst)) = (canonical("mv", src), canonical("mv", dst)) else {
+        return;
+    };
+    let (src, dst) = (src.as_str(), dst.as_str());
+    report("mv", transaction(|db| {
+        let n = match scope {
+            Scope::File => {
+                require_file(db, src)?;
+                if src == dst {
+                    return Err(alloc::format!("{} and {} are the same file", src, dst));
+                }
+                // Paths are case-insensitive: a change of case is a rename in place
+                if !src.eq_ignore_ascii_case(dst) {
+                    db.query_params("DELETE FROM namespace WHERE path = ?", &[text(dst)])?;
+                }
+                db.query_params(
+                    "UPDATE namespace SET path = ?, mtime = strftime('%s','now') WHERE path = ?",
+                    &[text(dst), text(src)],
+                )?;
+                1
+            }
+            Scope::Tree => {
+                let (src, dst) = (src.trim_end_matches('/'), dst.trim_end_matches('/'));
+                let recase = src.eq_ignore_ascii_case(dst);
+                if src == dst || (!recase && is_under(&dir(dst), &dir(src))) {
+                    return Err(alloc::format!("cannot move {} into itself", dir(src)));
+                }
+                // The renamed paths would land on the source's own rows
+                if !recase && is_under(&dir(src), &dir(dst)) {
+                    return Err(alloc::format!("cannot move {} onto {}, which holds it", dir(src), dir(dst)));
+                }
+                if dst.is_empty() {
+                    return Err(String::from("cannot move a tree onto /"));
+                }
+                let n = require_tree(db, src)?;
+                // Clear whatever the renamed paths would collide with, then rename
+                if !recase {
+                    db.query_params(
+                        "DELETE FROM namespace WHERE path IN \
+                         (SELECT ?2 || substr(path, length(?1) + 1) FROM namespace \
+                          WHERE path = ?1 COLLATE NOCASE \
+                             OR substr(path, 1, length(?1) + 1) = ?1 || '/' COLLATE NOCASE)",
+                        &[text(src), text(dst)],
+                    )?;
+                }
+                db.query_params(
+                    "UPDATE namespace SET path = ?2 || substr(path, length(?1) + 1), \
+                         mtime = strftime('%s','now') \
+                     WHERE path = ?1 COLLATE NOCASE \
+                        OR substr(path, 1, length(?1) + 1) = ?1 || '/' COLLATE NOCASE",
+                    &[text(src), text(dst)],
+                )?;
+                n
+            }
+        };
+        audit(db, "FILE_MOVE", src, &alloc::format!("-> {} ({} entries)", dst, n))?;
+        Ok(alloc::format!("moved {} entr{} {} -> {}", n, plural(n), src, dst))
+    }));
+}
+
+pub fn cp<'a>(mut args: impl Iterator<Item = &'a str>) {
+    let (scope, paths) = parse_args(&mut args);
+    let [src, dst] = paths[..] else {
+        serial_println!("usage: cp [-r] <src> <dst>");
+        return;
+    };
+    let (Some(src), Some(dst)) = (canonical("cp", src), canonical("cp", dst)) else {
+        return;
+    };
+    let (src, dst) = (src.as_str(), dst.as_str());
+    report("cp", transaction(|db| {
+        let n = match scope {
+            Scope::File => {
+                require_file(db, src)?;
+                if src.eq_ignore_ascii_case(dst) {
+                    return Err(alloc::format!("{} and {} are the same file", src, dst));
+                }
+                db.query_params(
+                    "INSERT OR REPLACE INTO namespace (path, type, content, compressed, mode, mtime) \
+                     SELECT ?2, type, content, compressed, mode, strftime('%s','now') FROM namespace WHERE path = ?1",
+                    &[text(src), text(dst)],
+                )?;
+                1
+            }
+            Scope::Tree => {
+                let (src, dst) = (src.trim_end_matches('/'), dst.trim_end_matches('/'));
+                if is_under(&dir(dst), &dir(src)) {
+                    return Err(alloc::format!("cannot copy {} into itself", dir(src)));
+                }
+                if dst.is_empty() {
+                    return Err(String::from("cannot copy a tree onto /"));
+                }
+                let n = require_tree(db, src)?;
+                db.query_params(
+                    "INSERT OR REPLACE INTO namespace (path, type, content, compressed, mode, mtime) \
+                     SELECT ?2 || substr(path, length(?1) + 1), type, content, compressed, mode, \
+                            strftime('%s','now') \
+                     FROM namespace WHERE path = ?1 COLLATE NOCASE \
+                        OR substr(path, 1, length(?1) + 1) = ?1 || '/' COLLATE NOCASE",
+                    &[text(src), text(dst)],
+                )?;
+                n
+            }
+        };
+        audit(db, "FILE_COPY", src, &alloc::format!("-> {} ({} entries)", dst, n))?;
+        Ok(alloc::format!("copied {} entr{} {} -> {}", n, plural(n), src, dst))
+    }));
+}
+
+pub fn rm<'a>(mut args: impl Iterator<Item = &'a str>) {
+    let (scope, paths) = parse_args(&mut args);
+    let [path] = paths[..] else {
+        serial_println!("usage: rm [-r] <path>");
+        return;
+    };
+    let Some(path) = canonical("rm", path) else {
+        return;
+    };
+    let path = path.as_str();
+    if crate::fs::tmpfs::is_tmp(path) {
+        let removed = crate::fs::tmpfs::remove(&crate::sqlite::acl::Principal::Shell, path, scope == Scope::Tree);
+        report("rm", removed.map(|n| alloc::format!("removed {} entr{}", n, plural(n))));
+        return;
+    }
+    report("rm", transaction(|db| {
+        let n = match scope {
+            Scope::File => {
+                require_file(db, path)?;
+                db.query_params("DELETE FROM namespace WHERE path = ?", &[text(path)])?;
+                1
+            }
+            Scope::Tree => {
+                let prefix = dir(path);
+                if prefix == "/" {
+                    return Err(String::from("refusing to remove the whole namespace"));
+                }
+                let exact = path.trim_end_matches('/');
+                db.query_params(
+                    "DELETE FROM namespace WHERE path = ?2 OR substr(path, 1, length(?1)) = ?1 COLLATE NOCASE",
+                    &[text(&prefix), text(exact)],
+                )?;
+                let n = changes(db)?;
+                if n == 0 {
+                    return Err(alloc::format!("{}: no such file or directory", path));
+                }
+                n
+            }
+        };
+        audit(db, "FILE_DELETE", path, &alloc::format!("{} entries", n))?;
+        Ok(alloc::format!("removed {} entr{}", n, plural(n)))
+    }));
+}
+
+/// Run `f` inside BEGIN IMMEDIATE / COMMIT, rolling back on error.
+fn transaction(f: impl FnOnce(&SqliteDb) -> Result<String, String>) -> Result<String, String> {
+    let guard = DB.lock();
+    let db = guard.as_ref().ok_or_else(|| String::from("database not open"))?;
+    db.exec("BEGIN IMMEDIATE")?;
+    match f(db) {
+        Ok(message) => {
+            db.exec("COMMIT")?;
+            Ok(message)
+        }
+        Err(e) => {
+            let _ = db.exec("ROLLBACK");
+            Err(e)
+        }
+    }
+}
+
+fn report(cmd: &str, result: Result<String, String>) {
+    match result {
+        Ok(message) => serial_println!("{}", message),
+        Err(e) => serial_println!("{}: {}", cmd, e),
+    }
+}
+
+fn require_file(db: &SqliteDb, path: &str) -> Result<(), String> {
+    let found = db.query_params("SELECT 1 FROM namespace WHERE path = ?", &[text(path)])?;
+    if found.rows.is_empty() {
+        let under = count_tree(db, path)?;
+        if under > 0 {
+            return Err(alloc::format!("{}: is a directory ({} entries; use -r)", path, under));
+        }
+        return Err(alloc::format!("{}: no such file", path));
+    }
+    Ok(())
+}
+
+/// Rows at `path` (given without a trailing `/`) and below it.
+fn count_tree(db: &SqliteDb, path: &str) -> Result<u64, String> {
+    let result = db.query_params(
+        "SELECT count(*) FROM namespace WHERE path = ?1 COLLATE NOCASE \
+            OR substr(path, 1, length(?1) + 1) = ?1 || '/' COLLATE NOCASE",
+        &[text(path)],
+    )?;
+    Ok(result.rows.first().and_then(|r| r.first()).and_then(SqlValue::as_integer).unwrap_or(0) as u64)
+}
+
+/// Entries at and under `path`, failing if there are none.
+fn require_tree(db: &SqliteDb, path: &str) -> Result<u64, String> {
+    match count_tree(db, path)? {
+        0 => Err(alloc::format!("{}: no such file or directory", path)),
+        n => Ok(n),
+    }
+}
+
+fn changes(db: &SqliteDb) -> Result<u64, String> {
+    Ok(db.query_value("SELECT changes()")?.and_then(|v| v.parse().ok()).unwrap_or(0))
+}
+
+fn audit(db: &SqliteDb, action: &str, target: &str, detail: &str) -> Result<(), String> {
+    db.query_params(
+        "INSERT INTO audit (agent, action, target, detail, request_id) VALUES ('shell', ?, ?, ?, ?)",
+        &[text(action), text(target), text(detail), crate::sqlite::audit::request_id()],
+    )?;
+    Ok(())
+}
+
+/// Is `path` `prefix` or below it? Case-insensitive, like the namespace.
+fn is_under(path: &str, prefix: &str) -> bool {
+    path.len() >= prefix.len() && path.as_bytes()[..prefix.len()].eq_ignore_ascii_case(prefix.as_bytes())
+}
+
+/// A path as a directory prefix (one trailing `/`).
+fn dir(path: &str) -> String {
+    alloc::format!("{}/", path.trim_end_matches('/'))
+}
+
+fn text(s: &str) -> SqlValue {
+    SqlValue::Text(String::from(s))
+}
+
+fn plural(n: u64) -> &'static str {
+    if n == 1 { "y" } else { "ies" }
+}
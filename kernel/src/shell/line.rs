@@ -7,10 +7,25 @@
 /// - Ctrl-C (0x03) — cancel current line
 /// - Ctrl-U (0x15) — clear line
 /// - Ctrl-L (0x0C) — redraw line
+/// - Up / Down arrows — step through previously entered lines
+/// - Tab — complete the first word, when the editor has a completer
+///
+/// The shell's editor also honours `bind`: most Ctrl keys can be given an
+/// editing action or a command line to run at once. A bound key replaces
+/// its default meaning; Ctrl-C, Backspace, Tab and Enter can't be bound.
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Mutex;
+
 use crate::arch::x86_64::serial::SERIAL;
+use crate::crypto::zeroize::Zeroizing;
 
 const MAX_LINE: usize = 256;
 
+/// Entered lines remembered per editor.
+const HISTORY_LEN: usize = 32;
+
 /// Try to read a byte from serial within a spin-loop timeout.
 /// `timeout_iters` is the approximate number of spin iterations to wait.
 fn spin_try_read(timeout_iters: u32) -> Option<u8> {
@@ -23,9 +38,198 @@ fn spin_try_read(timeout_iters: u32) -> Option<u8> {
     None
 }
 
+/// Has Ctrl-C been typed? Consumes pending input; anything else typed
+/// while a command runs is discarded. Once seen, stays true until the
+/// next line is read (see `serial::interrupted`).
+pub fn interrupt_pending() -> bool {
+    while SERIAL.lock().try_read_byte().is_some() {}
+    crate::arch::x86_64::serial::interrupted()
+}
+
+/// Block until a byte arrives, delivering resident agents' namespace
+/// events, forwarding logs and running due maintenance while the console
+/// is idle. Nothing runs in the foreground here, so any Ctrl-C is spent:
+/// it cancels the line, not the agents.
+pub fn wait_byte() -> u8 {
+    use crate::arch::x86_64::serial::clear_interrupt;
+
+    clear_interrupt();
+    loop {
+        if let Some(b) = SERIAL.lock().try_read_byte() {
+            clear_interrupt();
+            crate::maintenance::note_input();
+            return b;
+        }
+        crate::lua::agents::pump();
+        crate::net::syslog::pump();
+        crate::net::mdns::pump();
+        crate::fs::styx::listen::pump();
+        crate::maintenance::tick();
+        crate::arch::x86_64::idle::wait();
+    }
+}
+
+/// Block until a byte arrives, running nothing else meanwhile: unlike
+/// `wait_byte`, safe while the caller holds the database or another lock
+/// that agents or maintenance would take.
+pub fn wait_key() -> u8 {
+    let byte = loop {
+        if let Some(b) = SERIAL.lock().try_read_byte() {
+            break b;
+        }
+        crate::arch::x86_64::idle::wait();
+    };
+    crate::maintenance::note_input();
+    byte
+}
+
+/// Ask a yes/no question on the console; only `y` or `Y` is yes. Nothing
+/// else runs while the question is open — no agent events, no
+/// maintenance — since the caller may hold locks they would need.
+pub fn confirm(question: &str) -> bool {
+    crate::serial_print!("{} [y/N] ", question);
+    // Drop anything typed before the question appeared
+    while SERIAL.lock().try_read_byte().is_some() {}
+    let byte = wait_key();
+    let yes = matches!(byte, b'y' | b'Y');
+    crate::serial_println!("{}", if yes { "y" } else { "n" });
+    yes
+}
+
+/// Read a passphrase on the console without echoing it. Backspace and
+/// Ctrl-U edit as usual; Ctrl-C gives None. Like `confirm`, nothing else
+/// runs meanwhile — this also serves the boot prompt, before the shell.
+pub fn read_secret(prompt: &str) -> Option<Zeroizing<String>> {
+    crate::serial_print!("{}", prompt);
+    while SERIAL.lock().try_read_byte().is_some() {}
+    let mut secret = Zeroizing::new(String::with_capacity(MAX_LINE));
+    loop {
+        let Some(byte) = SERIAL.lock().try_read_byte() else {
+            crate::arch::x86_64::idle::wait();
+            continue;
+        };
+        crate::maintenance::note_input();
+        match byte {
+            b'\r' | b'\n' => break,
+            0x03 => {
+                crate::serial_println!("^C");
+                return None;
+            }
+            0x08 | 0x7F => {
+                secret.pop();
+            }
+            0x15 => secret.clear(),
+            0x20..=0x7E if secret.len() < MAX_LINE => secret.push(byte as char),
+            _ => {}
+        }
+    }
+    crate::serial_println!();
+    Some(secret)
+}
+
+/// What a bound Ctrl key does.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Binding {
+    ClearLine,
+    DeleteWord,
+    Redraw,
+    HistoryPrev,
+    HistoryNext,
+    Complete,
+    /// End of input on an empty line (like Ctrl-D).
+    Eof,
+    /// Replace the line with this command and submit it.
+    Run(String),
+}
+
+impl Binding {
+    /// An action name, or else a command line to run.
+    pub fn parse(s: &str) -> Self {
+        match s {
+            "clear-line" => Binding::ClearLine,
+            "delete-word" => Binding::DeleteWord,
+            "redraw" => Binding::Redraw,
+            "history-prev" => Binding::HistoryPrev,
+            "history-next" => Binding::HistoryNext,
+            "complete" => Binding::Complete,
+            "eof" => Binding::Eof,
+            _ => Binding::Run(String::from(s)),
+        }
+    }
+
+    pub fn describe(&self) -> &str {
+        match self {
+            Binding::ClearLine => "clear-line",
+            Binding::DeleteWord => "delete-word",
+            Binding::Redraw => "redraw",
+            Binding::HistoryPrev => "history-prev",
+            Binding::HistoryNext => "history-next",
+            Binding::Complete => "complete",
+            Binding::Eof => "eof",
+            Binding::Run(cmd) => cmd,
+        }
+    }
+}
+
+/// User bindings for Ctrl-A ..= Ctrl-Z.
+static BINDINGS: Mutex<[Option<Binding>; 26]> = Mutex::new([const { None }; 26]);
+
+/// The control byte for `ctrl-x` / `^X`, if that key may be bound.
+pub fn parse_key(name: &str) -> Option<u8> {
+    let letter = name
+        .strip_prefix("ctrl-")
+        .or_else(|| name.strip_prefix("C-"))
+        .or_else(|| name.strip_prefix('^'))?;
+    let &[c] = letter.as_bytes() else {
+        return None;
+    };
+    let byte = c.to_ascii_uppercase().wrapping_sub(b'@');
+    let fixed = [0x03, 0x08, 0x09, 0x0A, 0x0D]; // Ctrl-C, Backspace, Tab, LF, Enter
+    ((1..=26).contains(&byte) && !fixed.contains(&byte)).then_some(byte)
+}
+
+/// `ctrl-x` for a control byte.
+pub fn key_name(byte: u8) -> String {
+    alloc::format!("ctrl-{}", (byte + b'`') as char)
+}
+
+/// Bind a key (from `parse_key`), or restore its default with None.
+pub fn set_binding(key: u8, binding: Option<Binding>) {
+    BINDINGS.lock()[(key - 1) as usize] = binding;
+}
+
+/// Current user bindings as (control byte, binding).
+pub fn bindings() -> Vec<(u8, Binding)> {
+    BINDINGS
+        .lock()
+        .iter()
+        .enumerate()
+        .filter_map(|(i, b)| b.clone().map(|b| (i as u8 + 1, b)))
+        .collect()
+}
+
+fn binding_for(byte: u8) -> Option<Binding> {
+    if !(1..=26).contains(&byte) {
+        return None;
+    }
+    BINDINGS.lock()[(byte - 1) as usize].clone()
+}
+
+/// Candidates for completing the first word of a line.
+pub type Completer = fn(&str) -> Vec<&'static str>;
+
 pub struct LineEditor {
     buf: [u8; MAX_LINE],
     len: usize,
+    /// Oldest first.
+    history: VecDeque<Vec<u8>>,
+    completer: Option<Completer>,
+    /// Reprinted after listing completions.
+    prompt: &'static str,
+    /// Honour `bind` (the shell's editor only).
+    use_bindings: bool,
+    /// Did the last `read_line` end on Ctrl-C (rather than end of input)?
+    cancelled: bool,
 }
 
 impl LineEditor {
@@ -33,26 +237,94 @@ impl LineEditor {
         Self {
             buf: [0u8; MAX_LINE],
             len: 0,
+            history: VecDeque::new(),
+            completer: None,
+            prompt: "",
+            use_bindings: false,
+            cancelled: false,
         }
     }
 
+    /// An editor that completes the first word with `completer` on Tab.
+    pub fn with_completion(prompt: &'static str, completer: Completer) -> Self {
+        Self { completer: Some(completer), prompt, ..Self::new() }
+    }
+
+    /// Was the last `read_line` given up with Ctrl-C?
+    pub fn cancelled(&self) -> bool {
+        self.cancelled
+    }
+
+    /// Apply the user's `bind` settings to this editor's Ctrl keys.
+    pub fn with_bindings(self) -> Self {
+        Self { use_bindings: true, ..self }
+    }
+
     /// Read a line from serial input. Returns the line content on Enter,
-    /// or None on Ctrl-C.
+    /// or None on Ctrl-C or end of input (`cancelled` tells them apart).
     pub fn read_line(&mut self) -> Option<&str> {
         self.len = 0;
+        self.cancelled = false;
+        // Position while browsing history; history.len() = the new line
+        let mut hist_pos = self.history.len();
 
         loop {
-            let byte = SERIAL.lock().read_byte();
+            let byte = wait_byte();
+
+            if let Some(binding) = binding_for(byte).filter(|_| self.use_bindings) {
+                match binding {
+                    Binding::ClearLine => {
+                        self.erase_line();
+                        self.len = 0;
+                    }
+                    Binding::DeleteWord => {
+                        let line = &self.buf[..self.len];
+                        let end = line.iter().rposition(|b| *b != b' ').map_or(0, |i| i + 1);
+                        let start = line[..end].iter().rposition(|b| *b == b' ').map_or(0, |i| i + 1);
+                        self.erase_line();
+                        self.len = start;
+                        self.redraw();
+                    }
+                    Binding::Redraw => {
+                        self.erase_line();
+                        self.redraw();
+                    }
+                    Binding::HistoryPrev if hist_pos > 0 => {
+                        hist_pos -= 1;
+                        self.recall(hist_pos);
+                    }
+                    Binding::HistoryNext if hist_pos < self.history.len() => {
+                        hist_pos += 1;
+                        self.recall(hist_pos);
+                    }
+                    Binding::HistoryPrev | Binding::HistoryNext => {}
+                    Binding::Complete => self.complete(),
+                    Binding::Eof if self.len == 0 => return None,
+                    Binding::Eof => {}
+                    Binding::Run(cmd) => {
+                        self.erase_line();
+                        let n = cmd.len().min(MAX_LINE - 1);
+                        self.buf[..n].copy_from_slice(&cmd.as_bytes()[..n]);
+                        self.len = n;
+                        self.redraw();
+                        crate::serial_println!();
+                        self.remember();
+                        return Some(core::str::from_utf8(&self.buf[..self.len]).unwrap_or(""));
+                    }
+                }
+                continue;
+            }
 
             match byte {
                 // Enter (CR)
                 b'\r' | b'\n' => {
                     // Echo newline
-                    let serial = SERIAL.lock();
+                    let mut serial = SERIAL.lock();
                     serial.write_byte(b'\r');
                     serial.write_byte(b'\n');
                     drop(serial);
 
+                    self.remember();
                     // Return the line as a str
                     let s = core::str::from_utf8(&self.buf[..self.len]).unwrap_or("");
                     return Some(s);
@@ -60,7 +332,7 @@ impl LineEditor {
 
                 // Ctrl-C — cancel
                 0x03 => {
-                    let serial = SERIAL.lock();
+                    let mut serial = SERIAL.lock();
                     serial.write_byte(b'^');
                     serial.write_byte(b'C');
                     serial.write_byte(b'\r');
@@ -68,6 +340,7 @@ impl LineEditor {
                     drop(serial);
 
                     self.len = 0;
+                    self.cancelled = true;
                     return None;
                 }
 
@@ -84,6 +357,9 @@ impl LineEditor {
                     self.len = 0;
                 }
 
+                // Tab — complete
+                0x09 => self.complete(),
+
                 // Ctrl-L — redraw
                 0x0C => {
                     self.erase_line();
@@ -95,7 +371,7 @@ impl LineEditor {
                     if self.len > 0 {
                         self.len -= 1;
                         // Erase character on terminal: backspace, space, backspace
-                        let serial = SERIAL.lock();
+                        let mut serial = SERIAL.lock();
                         serial.write_byte(0x08);
                         serial.write_byte(b' ');
                         serial.write_byte(0x08);
@@ -111,6 +387,13 @@ impl LineEditor {
                         // CSI sequence — read until a letter or ~ (max 8 params)
                         for _ in 0..8 {
                             if let Some(c) = spin_try_read(500_000) {
+                                if c == b'A' && hist_pos > 0 {
+                                    hist_pos -= 1;
+                                    self.recall(hist_pos);
+                                } else if c == b'B' && hist_pos < self.history.len() {
+                                    hist_pos += 1;
+                                    self.recall(hist_pos);
+                                }
                                 if c.is_ascii_alphabetic() || c == b'~' {
                                     break;
                                 }
@@ -137,9 +420,74 @@ impl LineEditor {
         }
     }
 
+    /// Add the line just entered to the history (skipping blanks and repeats).
+    fn remember(&mut self) {
+        let line = &self.buf[..self.len];
+        if line.iter().all(|b| b.is_ascii_whitespace()) {
+            return;
+        }
+        if self.history.back().is_some_and(|last| last.as_slice() == line) {
+            return;
+        }
+        if self.history.len() >= HISTORY_LEN {
+            self.history.pop_front();
+        }
+        self.history.push_back(line.to_vec());
+    }
+
+    /// Replace the line being edited with history entry `pos` (or an empty
+    /// line past the newest entry).
+    fn recall(&mut self, pos: usize) {
+        self.erase_line();
+        let entry = self.history.get(pos).map(|e| e.as_slice()).unwrap_or(&[]);
+        let n = entry.len().min(MAX_LINE - 1);
+        self.buf[..n].copy_from_slice(&entry[..n]);
+        self.len = n;
+        self.redraw();
+    }
+
+    /// Complete the first word: fill in a unique match (plus a space), or
+    /// the longest common prefix; list the candidates when that adds nothing.
+    fn complete(&mut self) {
+        let Some(completer) = self.completer else {
+            return;
+        };
+        let typed = &self.buf[..self.len];
+        if typed.contains(&b' ') {
+            return;
+        }
+        let prefix = core::str::from_utf8(typed).unwrap_or("");
+        let matches = completer(prefix);
+        let Some(first) = matches.first() else {
+            return;
+        };
+        let common = matches.iter().fold(first.len(), |n, m| {
+            first.bytes().zip(m.bytes()).take(n).take_while(|(a, b)| a == b).count()
+        });
+        let mut fill: Vec<u8> = first.as_bytes()[self.len..common].to_vec();
+        if matches.len() == 1 {
+            fill.push(b' ');
+        }
+        if fill.is_empty() {
+            crate::serial_println!();
+            crate::serial_println!("{}", matches.join("  "));
+            crate::serial_print!("{}", self.prompt);
+            self.redraw();
+            return;
+        }
+        let mut serial = SERIAL.lock();
+        for b in fill {
+            if self.len < MAX_LINE - 1 {
+                self.buf[self.len] = b;
+                self.len += 1;
+                serial.write_byte(b);
+            }
+        }
+    }
+
     /// Erase the current line on the terminal.
     fn erase_line(&self) {
-        let serial = SERIAL.lock();
+        let mut serial = SERIAL.lock();
         // Move cursor back to start of input
         for _ in 0..self.len {
             serial.write_byte(0x08);
@@ -156,7 +504,7 @@ impl LineEditor {
 
     /// Redraw the current line content.
     fn redraw(&self) {
-        let serial = SERIAL.lock();
+        let mut serial = SERIAL.lock();
         for i in 0..self.len {
             serial.write_byte(self.buf[i]);
         }
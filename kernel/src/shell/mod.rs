@@ -1,28 +1,48 @@
 /// HeavenOS interactive shell over serial console.
 ///
 /// This is a minimal kernel shell for debugging and system interaction.
-/// It reads from COM1, provides line editing (backspace, Ctrl-C, Ctrl-U),
-/// and dispatches commands to built-in handlers.
+/// It reads from COM1, provides line editing (backspace, Ctrl-C, Ctrl-U,
+/// Up/Down history), and dispatches commands to built-in handlers.
 ///
 /// Think of it as a Plan 9 `rc` that speaks to the kernel directly —
 /// not a POSIX shell, not bash. Commands map to Styx namespace operations.
 pub(crate) mod line;
 pub(crate) mod agent;
 pub(crate) mod commands;
+pub(crate) mod context;
+pub(crate) mod sessions;
+mod edit;
+pub(crate) mod files;
+mod pager;
+pub(crate) mod registry;
+mod sqlsh;
+mod transfer;
 
 use crate::{serial_print, serial_println};
+use crate::term::{self, ColorMode};
 
 use line::LineEditor;
 use commands::dispatch;
+pub use commands::apply_serial_config;
+pub use line::read_secret;
 
 const PROMPT: &str = "heaven% ";
 
 /// Run the interactive shell. This function never returns.
 pub fn run() -> ! {
+    let color = crate::sqlite::config_get("term.color")
+        .and_then(|v| ColorMode::parse(v.trim()))
+        .unwrap_or(ColorMode::Auto);
+    term::set_mode(color);
+    // Also measures the screen for the pager, so it runs whatever the mode
+    term::detect();
+    crate::klog::stop_capture();
+
     serial_println!();
     serial_println!("HeavenOS shell ready. Type 'help' for commands.");
 
-    let mut editor = LineEditor::new();
+    commands::load_bindings();
+    let mut editor = LineEditor::with_completion(PROMPT, registry::complete).with_bindings();
 
     loop {
         serial_print!("{}", PROMPT);
@@ -0,0 +1,79 @@
+/// `less`-lite: page long command output on the serial console.
+///
+/// Commands print through a `Pager` instead of `serial_println!`. After a
+/// screenful it stops at a prompt:
+///
+///   Space      next page
+///   Enter      one more line
+///   q, Ctrl-C  stop — later lines are dropped, `line` returns false
+///
+/// While the prompt waits, agents and maintenance are held off (see
+/// `line::wait_key`).
+///
+/// The screen height comes from `term::rows()`; when it is unknown (the
+/// peer did not answer the terminal probe, or `term.rows 0`) output is
+/// never held, so a script driving the console can't get stuck.
+use core::fmt;
+
+use crate::{serial_print, serial_println};
+
+const PROMPT: &str = "-- more (Space: page, Enter: line, q: quit) --";
+
+pub struct Pager {
+    /// Lines per page, prompt excluded; None = no paging.
+    page: Option<usize>,
+    /// Lines left before the next prompt.
+    left: usize,
+    quit: bool,
+}
+
+impl Pager {
+    /// A pager sized to the screen.
+    pub fn new() -> Self {
+        Self::with_page(crate::term::rows().map(|rows| rows.saturating_sub(1).max(1)))
+    }
+
+    /// A pager holding after `page` lines (None or Some(0) = never).
+    pub fn with_page(page: Option<usize>) -> Self {
+        let page = page.filter(|&n| n > 0);
+        Self { page, left: page.unwrap_or(0), quit: false }
+    }
+
+    /// Has the reader asked to stop?
+    pub fn quit(&self) -> bool {
+        self.quit
+    }
+
+    /// Print one line, waiting at the prompt first if the page is full.
+    /// Returns false (and prints nothing) once the reader has quit.
+    pub fn line(&mut self, text: impl fmt::Display) -> bool {
+        if self.quit {
+            return false;
+        }
+        if let Some(page) = self.page {
+            if self.left == 0 {
+                serial_print!("{}", PROMPT);
+                // Callers may page from inside a database connection
+                // (`run_sql`), so nothing that could take its lock runs here
+                let key = super::line::wait_key();
+                serial_print!("\r{:width$}\r", "", width = PROMPT.len());
+                match key {
+                    b'q' | b'Q' | 0x03 => {
+                        self.quit = true;
+                        return false;
+                    }
+                    b'\r' | b'\n' => self.left = 1,
+                    _ => self.left = page,
+                }
+            }
+            self.left -= 1;
+        }
+        serial_println!("{}", text);
+        true
+    }
+
+    /// Print multi-line text, one `line` per `\n`.
+    pub fn text(&mut self, text: &str) -> bool {
+        text.lines().all(|l| self.line(l))
+    }
+}
@@ -0,0 +1,172 @@
+/// Shell command registry.
+///
+/// Every command is a `Command` — name, aliases, usage, one-line summary,
+/// optional detail lines and a handler. `dispatch`, `help`, `help <cmd>`
+/// and Tab completion all read the same table, so a command's help can't
+/// drift from what the shell accepts.
+///
+/// The built-in table is `commands::BUILTINS`. Other subsystems add theirs
+/// at boot with `register`; a registered name replaces a built-in one.
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use crate::term::{paint, Style};
+
+/// A command's arguments: the words after its name.
+pub type Args<'a> = core::str::SplitWhitespace<'a>;
+
+/// Help section a command is listed under.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Section {
+    System,
+    Files,
+    Database,
+    Lua,
+    Api,
+    Power,
+}
+
+impl Section {
+    const ALL: [Section; 6] = [
+        Section::System,
+        Section::Files,
+        Section::Database,
+        Section::Lua,
+        Section::Api,
+        Section::Power,
+    ];
+
+    fn title(&self) -> &'static str {
+        match self {
+            Section::System => "System:",
+            Section::Files => "Namespace files:",
+            Section::Database => "Database:",
+            Section::Lua => "Lua:",
+            Section::Api => "Claude API:",
+            Section::Power => "Power:",
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Command {
+    pub name: &'static str,
+    pub aliases: &'static [&'static str],
+    /// Synopsis, starting with the name: `cat <path>`.
+    pub usage: &'static str,
+    pub summary: &'static str,
+    /// Extra lines shown by `help <name>`.
+    pub details: &'static [&'static str],
+    pub section: Section,
+    pub run: fn(Args<'_>),
+}
+
+impl Command {
+    fn answers_to(&self, word: &str) -> bool {
+        self.name == word || self.aliases.contains(&word)
+    }
+}
+
+/// Commands registered at run time.
+static EXTRA: Mutex<Vec<Command>> = Mutex::new(Vec::new());
+
+/// Add a command (or replace the one with the same name).
+pub fn register(cmd: Command) {
+    let mut extra = EXTRA.lock();
+    extra.retain(|c| c.name != cmd.name);
+    extra.push(cmd);
+}
+
+/// Remove a command registered at run time; true if there was one.
+/// Built-ins can't be removed.
+pub fn unregister(name: &str) -> bool {
+    let mut extra = EXTRA.lock();
+    let before = extra.len();
+    extra.retain(|c| c.name != name);
+    extra.len() != before
+}
+
+/// Every command, registered ones overriding built-ins, in table order.
+pub fn all() -> Vec<Command> {
+    let extra = EXTRA.lock();
+    let mut cmds: Vec<Command> = super::commands::BUILTINS
+        .iter()
+        .filter(|b| !extra.iter().any(|e| e.name == b.name))
+        .copied()
+        .collect();
+    cmds.extend(extra.iter().copied());
+    cmds
+}
+
+/// The command a word names, by name or alias.
+pub fn find(word: &str) -> Option<Command> {
+    if let Some(cmd) = EXTRA.lock().iter().find(|c| c.answers_to(word)) {
+        return Some(*cmd);
+    }
+    super::commands::BUILTINS.iter().find(|c| c.answers_to(word)).copied()
+}
+
+/// Command names (not aliases) starting with `prefix`, sorted.
+pub fn complete(prefix: &str) -> Vec<&'static str> {
+    let mut names: Vec<&'static str> =
+        all().iter().map(|c| c.name).filter(|n| n.starts_with(prefix)).collect();
+    names.sort_unstable();
+    names
+}
+
+/// Print `usage: <synopsis>` for a command.
+pub fn usage(name: &str) {
+    match find(name) {
+        Some(cmd) => crate::serial_println!("usage: {}", cmd.usage),
+        None => crate::serial_println!("usage: see help"),
+    }
+}
+
+/// Usage column width in the overview.
+const USAGE_WIDTH: usize = 30;
+
+/// `help`: every command by section. `help <cmd>`: that command in full.
+pub fn help(topic: Option<&str>) {
+    let mut out = super::pager::Pager::new();
+    if let Some(word) = topic {
+        let Some(cmd) = find(word) else {
+            out.line(format_args!("help: {}: no such command", word));
+            return;
+        };
+        out.line(format_args!("usage: {}", cmd.usage));
+        out.line(format_args!("  {}", cmd.summary));
+        for line in cmd.details {
+            out.line(format_args!("  {}", line));
+        }
+        if !cmd.aliases.is_empty() {
+            out.line(format_args!("  aliases: {}", cmd.aliases.join(", ")));
+        }
+        return;
+    }
+
+    let cmds = all();
+    out.line(paint(Style::Bold, "HeavenOS shell commands (help <cmd> for details):"));
+    for section in Section::ALL {
+        out.line("");
+        out.line(paint(Style::Bold, section.title()));
+        for cmd in cmds.iter().filter(|c| c.section == section) {
+            let shown = if cmd.usage.len() < USAGE_WIDTH {
+                out.line(format_args!("  {:width$} {}", cmd.usage, cmd.summary, width = USAGE_WIDTH))
+            } else {
+                out.line(format_args!("  {}", cmd.usage))
+                    && out.line(format_args!("  {:width$} {}", "", cmd.summary, width = USAGE_WIDTH))
+            };
+            if !shown {
+                return;
+            }
+        }
+    }
+    out.line("");
+    out.line(paint(Style::Bold, "Line editing:"));
+    out.line("  Backspace     delete character");
+    out.line("  Tab           complete a command name");
+    out.line("  Up/Down       previous/next line from history");
+    out.line("  Ctrl-C        cancel line");
+    out.line("  Ctrl-U        clear line");
+    out.line("  other Ctrl keys: see help bind");
+}
@@ -0,0 +1,241 @@
+/// Registry of agent loop sessions, exposed over Styx as
+///
+///   /agents/<id>/status   state, prompt, turns, tokens, tool calls, elapsed
+///   /agents/<id>/log      the session's console output, as a stream — a
+///                         reader keeps up with a running session by reading
+///                         again from where it left off
+///   /agents/<id>/ctl      reads the state; write "stop", "pause" or "resume"
+///
+/// Commands written to ctl are picked up by the loop between turns and
+/// between tool calls, and a stop also cuts a request short; a paused
+/// session waits there until resumed or stopped. Finished sessions stay listed (the newest `KEEP`) so their
+/// log can still be read.
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+/// Finished sessions kept around for their status and log.
+const KEEP: usize = 8;
+
+/// Log bytes kept per session; older output is dropped from the front.
+const MAX_LOG: usize = 64 * 1024;
+
+/// Where a session is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Running,
+    Paused,
+    /// Finished with a final answer or a budget stop.
+    Done,
+    /// Stopped via ctl (or Ctrl-C while paused).
+    Stopped,
+    /// Ended with an error.
+    Failed,
+}
+
+impl State {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            State::Running => "running",
+            State::Paused => "paused",
+            State::Done => "done",
+            State::Stopped => "stopped",
+            State::Failed => "failed",
+        }
+    }
+
+    fn finished(&self) -> bool {
+        matches!(self, State::Done | State::Stopped | State::Failed)
+    }
+}
+
+/// A command from ctl, waiting for the loop to act on it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Control {
+    Stop,
+    Pause,
+    Resume,
+}
+
+struct Session {
+    prompt: String,
+    state: State,
+    started_ms: u64,
+    finished_ms: Option<u64>,
+    turns: usize,
+    tokens: u64,
+    tool_calls: usize,
+    failed_calls: usize,
+    /// Error or stop reason once finished.
+    outcome: Option<String>,
+    control: Option<Control>,
+    log: Vec<u8>,
+    /// Stream offset of `log[0]` (bytes dropped from the front so far).
+    log_base: u64,
+}
+
+struct Registry {
+    next_id: u64,
+    sessions: BTreeMap<u64, Session>,
+}
+
+static SESSIONS: Mutex<Registry> = Mutex::new(Registry { next_id: 1, sessions: BTreeMap::new() });
+
+fn now_ms() -> u64 {
+    crate::time::monotonic_ms()
+}
+
+/// Register a running session. Returns its id.
+pub fn start(prompt: &str) -> u64 {
+    let mut reg = SESSIONS.lock();
+    let id = reg.next_id;
+    reg.next_id += 1;
+    reg.sessions.insert(id, Session {
+        prompt: String::from(prompt),
+        state: State::Running,
+        started_ms: now_ms(),
+        finished_ms: None,
+        turns: 0,
+        tokens: 0,
+        tool_calls: 0,
+        failed_calls: 0,
+        outcome: None,
+        control: None,
+        log: Vec::new(),
+        log_base: 0,
+    });
+
+    // Forget the oldest finished sessions beyond KEEP
+    let finished: Vec<u64> = reg.sessions.iter().filter(|(_, s)| s.state.finished()).map(|(&id, _)| id).collect();
+    for old in finished.iter().take(finished.len().saturating_sub(KEEP)) {
+        reg.sessions.remove(old);
+    }
+    id
+}
+
+/// Append a line to the session's log.
+pub fn log_line(id: u64, line: &str) {
+    log_text(id, line);
+    log_text(id, "\n");
+}
+
+/// Append text (e.g. a streamed token) to the session's log.
+pub fn log_text(id: u64, text: &str) {
+    let mut reg = SESSIONS.lock();
+    let Some(s) = reg.sessions.get_mut(&id) else {
+        return;
+    };
+    s.log.extend_from_slice(text.as_bytes());
+    if s.log.len() > MAX_LOG {
+        let excess = s.log.len() - MAX_LOG;
+        s.log.drain(..excess);
+        s.log_base += excess as u64;
+    }
+}
+
+/// Record progress: turns started, tokens used, tool calls made and failed.
+pub fn progress(id: u64, turns: usize, tokens: u64, tool_calls: usize, failed_calls: usize) {
+    if let Some(s) = SESSIONS.lock().sessions.get_mut(&id) {
+        s.turns = turns;
+        s.tokens = tokens;
+        s.tool_calls = tool_calls;
+        s.failed_calls = failed_calls;
+    }
+}
+
+/// Mark the session finished.
+pub fn finish(id: u64, state: State, outcome: Option<&str>) {
+    if let Some(s) = SESSIONS.lock().sessions.get_mut(&id) {
+        s.state = state;
+        s.outcome = outcome.map(String::from);
+        s.finished_ms = Some(now_ms());
+        s.control = None;
+    }
+}
+
+/// Set the session's state while it runs (running ↔ paused).
+pub fn set_state(id: u64, state: State) {
+    if let Some(s) = SESSIONS.lock().sessions.get_mut(&id) {
+        s.state = state;
+    }
+}
+
+/// Take the pending ctl command, if any.
+pub fn take_control(id: u64) -> Option<Control> {
+    SESSIONS.lock().sessions.get_mut(&id).and_then(|s| s.control.take())
+}
+
+/// Is a stop waiting for a running session? Peeks without taking it,
+/// so the loop can cut its request short and still see why.
+pub fn stop_pending() -> bool {
+    SESSIONS.lock().sessions.values().any(|s| s.control == Some(Control::Stop) && !s.state.finished())
+}
+
+/// Queue a command for a running session.
+pub fn control(id: u64, command: Control) -> Result<(), String> {
+    let mut reg = SESSIONS.lock();
+    let s = reg.sessions.get_mut(&id).ok_or_else(|| alloc::format!("no agent session {}", id))?;
+    if s.state.finished() {
+        return Err(alloc::format!("session {} is {}", id, s.state.as_str()));
+    }
+    s.control = Some(command);
+    Ok(())
+}
+
+/// Ids of every listed session, oldest first.
+pub fn ids() -> Vec<u64> {
+    SESSIONS.lock().sessions.keys().copied().collect()
+}
+
+/// Styx read of /agents/<id>/ctl: the state word.
+pub fn ctl_read(id: u64) -> Vec<u8> {
+    match SESSIONS.lock().sessions.get(&id) {
+        Some(s) => alloc::format!("{}\n", s.state.as_str()).into_bytes(),
+        None => Vec::new(),
+    }
+}
+
+/// Styx write of /agents/<id>/ctl.
+pub fn ctl_write(id: u64, data: &[u8]) -> Result<(), String> {
+    let command = match core::str::from_utf8(data).map(str::trim) {
+        Ok("stop") => Control::Stop,
+        Ok("pause") => Control::Pause,
+        Ok("resume") => Control::Resume,
+        _ => return Err(String::from("ctl: expected stop, pause or resume")),
+    };
+    control(id, command)
+}
+
+/// Styx read of /agents/<id>/status.
+pub fn status(id: u64) -> Vec<u8> {
+    let reg = SESSIONS.lock();
+    let Some(s) = reg.sessions.get(&id) else {
+        return Vec::new();
+    };
+    let elapsed = (s.finished_ms.unwrap_or_else(now_ms) - s.started_ms) / 1000;
+    let mut out = alloc::format!(
+        "state: {}\nprompt: {}\nturns: {}\ntokens: {}\ntool_calls: {}\nfailed_calls: {}\nelapsed: {}s\n",
+        s.state.as_str(),
+        s.prompt.replace('\n', " "),
+        s.turns,
+        s.tokens,
+        s.tool_calls,
+        s.failed_calls,
+        elapsed
+    );
+    if let Some(outcome) = &s.outcome {
+        out.push_str(&alloc::format!("outcome: {}\n", outcome));
+    }
+    out.into_bytes()
+}
+
+/// Styx stream read of /agents/<id>/log: up to `count` bytes from stream
+/// offset `cursor`. Output dropped from the front is skipped over.
+pub fn log_after(id: u64, cursor: u64, count: usize) -> Result<(Vec<u8>, u64), String> {
+    let reg = SESSIONS.lock();
+    let s = reg.sessions.get(&id).ok_or_else(|| alloc::format!("no agent session {}", id))?;
+    let start = cursor.saturating_sub(s.log_base).min(s.log.len() as u64) as usize;
+    let end = (start + count).min(s.log.len());
+    Ok((s.log[start..end].to_vec(), s.log_base + end as u64))
+}
@@ -0,0 +1,157 @@
+/// `sqlsh` — an interactive SQL prompt in the style of the sqlite3 CLI.
+///
+/// Lines accumulate until they form a complete statement (a `;` outside
+/// strings, comments and trigger bodies), so statements can span lines.
+/// Several statements on one line run in order. Lines starting with `.`
+/// at the start of a statement are dot-commands:
+///
+///   .tables            list tables
+///   .schema [table]    show CREATE statements
+///   .mode [list|csv|json]  show or set the output format
+///   .limit [N|off]     cap rows per statement
+///   .help              this list
+///   .quit / .exit      back to the shell (Ctrl-D also works)
+///
+/// Ctrl-C discards the line and any half-typed statement, even at an
+/// empty prompt; only `.quit` or Ctrl-D leave. Up/Down recall earlier lines.
+use alloc::string::String;
+
+use crate::serial_println;
+use crate::serial_print;
+use crate::sqlite::OutputMode;
+use super::commands::run_sql;
+use super::line::LineEditor;
+
+const PROMPT: &str = "sql> ";
+const CONTINUATION: &str = "...> ";
+
+/// Session settings changed by dot-commands.
+struct Session {
+    mode: OutputMode,
+    limit: Option<u64>,
+}
+
+/// Run the SQL prompt until `.quit` or Ctrl-D.
+pub fn run() {
+    serial_println!("sqlsh — end statements with ';', .help for commands, Ctrl-D to exit");
+
+    let mut editor = LineEditor::new();
+    let mut session = Session { mode: OutputMode::List, limit: None };
+    let mut pending = String::new();
+
+    loop {
+        serial_print!("{}", if pending.is_empty() { PROMPT } else { CONTINUATION });
+        let line = match editor.read_line().map(String::from) {
+            Some(line) => line,
+            None if editor.cancelled() => {
+                // Ctrl-C: drop the unfinished statement, stay in sqlsh
+                pending.clear();
+                continue;
+            }
+            None => {
+                serial_println!();
+                return;
+            }
+        };
+
+        if pending.is_empty() {
+            let trimmed = line.trim();
+            if trimmed.is_empty() {
+                continue;
+            }
+            if trimmed.starts_with('.') {
+                if !dot_command(&mut session, trimmed) {
+                    return;
+                }
+                continue;
+            }
+        }
+
+        pending.push_str(&line);
+        pending.push('\n');
+        if !crate::sqlite::is_complete(&pending) {
+            continue;
+        }
+
+        for statement in split_statements(&pending) {
+            run_sql(statement, session.mode, session.limit);
+        }
+        pending.clear();
+    }
+}
+
+/// Split a complete buffer into statements, each ending at a `;` that
+/// SQLite considers a statement terminator.
+fn split_statements(buf: &str) -> alloc::vec::Vec<&str> {
+    let mut statements = alloc::vec::Vec::new();
+    let mut start = 0;
+    for (i, _) in buf.match_indices(';') {
+        let candidate = &buf[start..=i];
+        if crate::sqlite::is_complete(candidate) {
+            if !candidate.trim().trim_end_matches(';').trim().is_empty() {
+                statements.push(candidate.trim());
+            }
+            start = i + 1;
+        }
+    }
+    statements
+}
+
+/// Handle a dot-command. Returns false when the session should end.
+fn dot_command(session: &mut Session, line: &str) -> bool {
+    let mut parts = line.split_whitespace();
+    let cmd = parts.next().unwrap_or("");
+    let arg = parts.next();
+
+    match cmd {
+        ".quit" | ".exit" => return false,
+        ".tables" => run_sql(
+            "SELECT name FROM sqlite_master WHERE type='table' AND name NOT LIKE 'sqlite_%' ORDER BY name",
+            OutputMode::List,
+            None,
+        ),
+        ".schema" => match arg {
+            Some(table) if table.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_') => run_sql(
+                &alloc::format!(
+                    "SELECT sql FROM sqlite_master WHERE tbl_name='{}' AND sql IS NOT NULL",
+                    table
+                ),
+                OutputMode::List,
+                None,
+            ),
+            Some(_) => serial_println!(".schema: table names are letters, digits and '_'"),
+            None => run_sql(
+                "SELECT sql FROM sqlite_master WHERE sql IS NOT NULL ORDER BY tbl_name, type DESC",
+                OutputMode::List,
+                None,
+            ),
+        },
+        ".mode" => match arg {
+            None => serial_println!("{}", session.mode),
+            Some(name) => match OutputMode::parse(name) {
+                Some(mode) => session.mode = mode,
+                None => serial_println!(".mode: expected list, csv or json"),
+            },
+        },
+        ".limit" => match arg {
+            None => match session.limit {
+                Some(n) => serial_println!("{}", n),
+                None => serial_println!("off"),
+            },
+            Some("off") => session.limit = None,
+            Some(n) => match n.parse() {
+                Ok(n) => session.limit = Some(n),
+                Err(_) => serial_println!(".limit: expected a number or 'off'"),
+            },
+        },
+        ".help" => {
+            serial_println!(".tables                list tables");
+            serial_println!(".schema [table]        show CREATE statements");
+            serial_println!(".mode [list|csv|json]  show or set the output format");
+            serial_println!(".limit [N|off]         cap rows per statement");
+            serial_println!(".quit                  back to the shell");
+        }
+        _ => serial_println!("unknown command: {} (try .help)", cmd),
+    }
+    true
+}
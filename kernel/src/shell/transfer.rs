@@ -0,0 +1,243 @@
+/// `rx` / `sx` — move namespace files over the serial console.
+///
+/// A line-oriented protocol: every chunk is base64 with its own CRC-32
+/// (IEEE, as in zlib), so a host script of a few lines can drive it and a
+/// corrupted line is caught and resent rather than stored.
+///
+/// Receive (`rx <path>`, host → kernel). The kernel prints `RX READY`, then
+/// for each line the host sends
+///
+///   <seq> <base64> <crc32>     seq counts from 0, at most 192 bytes per chunk
+///
+/// it answers `ACK <seq>`, or `NAK <seq> <reason>` to ask for that line
+/// again. A repeat of the previous seq (its ACK was lost) is acknowledged
+/// and ignored. The host finishes with
+///
+///   end <bytes> <crc32>        totals over the whole file
+///
+/// and the kernel stores the file and answers `DONE <bytes>`, or `FAIL
+/// <reason>`. `abort`, Ctrl-C or 30 s of silence cancel the transfer.
+/// Any bytes go: UTF-8 content is stored as TEXT, anything else as a BLOB.
+///
+/// Send (`sx <path>`, kernel → host), no acknowledgements:
+///
+///   SX BEGIN <path> <bytes> <crc32>
+///   <seq> <base64> <crc32>     57 bytes per chunk
+///   SX END <bytes> <crc32>
+///
+/// CRCs are 8 lowercase hex digits.
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::arch::x86_64::serial::SERIAL;
+use crate::lua::util::{base64_decode, base64_encode, hex_decode, hex_encode};
+use crate::serial_println;
+use crate::time;
+use crate::sqlite::{SqlValue, DB};
+
+/// Largest file `rx` accepts.
+const MAX_FILE: usize = 1024 * 1024;
+
+/// Largest decoded chunk `rx` accepts.
+const MAX_RX_CHUNK: usize = 192;
+
+/// Decoded bytes per `sx` line (76 base64 characters, as in MIME).
+const SX_CHUNK: usize = 57;
+
+/// Longest protocol line (a full chunk, seq and CRC, with slack).
+const MAX_LINE: usize = 512;
+
+/// Silence after which `rx` gives up.
+const IDLE_TIMEOUT_MS: u64 = 30_000;
+
+/// CRC-32 (IEEE 802.3 polynomial, reflected) — bitwise, no table.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+/// `sx <path>` — print the file as checksummed base64 lines.
+pub fn send(path: &str) {
+    let Some(path) = super::commands::canonical("sx", path) else {
+        return;
+    };
+    let path = path.as_str();
+    let data = match read_file(path) {
+        Ok(data) => data,
+        Err(e) => {
+            serial_println!("sx: {}", e);
+            return;
+        }
+    };
+
+    serial_println!("SX BEGIN {} {} {:08x}", path, data.len(), crc32(&data));
+    for (seq, chunk) in data.chunks(SX_CHUNK).enumerate() {
+        let b64 = base64_encode(chunk);
+        serial_println!(
+            "{} {} {:08x}",
+            seq,
+            core::str::from_utf8(&b64).unwrap_or(""),
+            crc32(chunk)
+        );
+    }
+    serial_println!("SX END {} {:08x}", data.len(), crc32(&data));
+}
+
+/// `rx <path>` — receive a file and store it in the namespace.
+pub fn receive(path: &str) {
+    let Some(path) = super::commands::canonical("rx", path) else {
+        return;
+    };
+    let path = path.as_str();
+    serial_println!("RX READY");
+    match receive_data() {
+        Ok(data) => {
+            let len = data.len();
+            match store_file(path, data) {
+                Ok(()) => serial_println!("DONE {}", len),
+                Err(e) => serial_println!("FAIL {}", e),
+            }
+        }
+        Err(e) => serial_println!("FAIL {}", e),
+    }
+}
+
+fn receive_data() -> Result<Vec<u8>, String> {
+    let mut data = Vec::new();
+    let mut next_seq: u64 = 0;
+
+    loop {
+        let line = read_raw_line()?;
+        let line = core::str::from_utf8(&line).map_err(|_| String::from("non-ASCII line"))?;
+        let fields: Vec<&str> = line.split_whitespace().collect();
+
+        match fields.as_slice() {
+            [] => continue,
+            ["abort"] => return Err(String::from("aborted by sender")),
+            ["end", bytes, crc] => {
+                let bytes: usize = bytes.parse().map_err(|_| String::from("bad end line"))?;
+                if bytes != data.len() {
+                    return Err(alloc::format!("length mismatch: got {} of {} bytes", data.len(), bytes));
+                }
+                if parse_crc(crc) != Some(crc32(&data)) {
+                    return Err(String::from("file checksum mismatch"));
+                }
+                return Ok(data);
+            }
+            [seq, b64, crc] => {
+                let Ok(seq) = seq.parse::<u64>() else {
+                    serial_println!("NAK ? bad sequence number");
+                    continue;
+                };
+                if seq + 1 == next_seq {
+                    serial_println!("ACK {}", seq); // duplicate — our ACK was lost
+                    continue;
+                }
+                if seq != next_seq {
+                    serial_println!("NAK {} expected {}", seq, next_seq);
+                    continue;
+                }
+                let chunk = match base64_decode(b64.as_bytes()) {
+                    Ok(chunk) if chunk.len() <= MAX_RX_CHUNK => chunk,
+                    Ok(_) => {
+                        serial_println!("NAK {} chunk too large", seq);
+                        continue;
+                    }
+                    Err(e) => {
+                        serial_println!("NAK {} {}", seq, e);
+                        continue;
+                    }
+                };
+                if parse_crc(crc) != Some(crc32(&chunk)) {
+                    serial_println!("NAK {} checksum", seq);
+                    continue;
+                }
+                if data.len() + chunk.len() > MAX_FILE {
+                    return Err(alloc::format!("file larger than {} bytes", MAX_FILE));
+                }
+                data.extend_from_slice(&chunk);
+                next_seq += 1;
+                serial_println!("ACK {}", seq);
+            }
+            _ => serial_println!("NAK {} malformed line", next_seq),
+        }
+    }
+}
+
+fn parse_crc(s: &str) -> Option<u32> {
+    u32::from_str_radix(s, 16).ok()
+}
+
+/// Read one line without echo. Fails on Ctrl-C, overlong lines or
+/// `IDLE_TIMEOUT_MS` of silence.
+fn read_raw_line() -> Result<Vec<u8>, String> {
+    let mut line = Vec::new();
+    let mut last = time::monotonic_ms();
+    loop {
+        let byte = SERIAL.lock().try_read_byte();
+        match byte {
+            Some(b'\r') | Some(b'\n') => {
+                if !line.is_empty() {
+                    return Ok(line);
+                }
+            }
+            Some(0x03) => return Err(String::from("interrupted")),
+            Some(b) => {
+                if line.len() >= MAX_LINE {
+                    return Err(String::from("line too long"));
+                }
+                line.push(b);
+                last = time::monotonic_ms();
+            }
+            None => {
+                if time::monotonic_ms().saturating_sub(last) > IDLE_TIMEOUT_MS {
+                    return Err(String::from("timed out"));
+                }
+                core::hint::spin_loop();
+            }
+        }
+    }
+}
+
+/// Raw bytes of a namespace file (hex round-trip keeps BLOBs intact).
+pub(super) fn read_file(path: &str) -> Result<Vec<u8>, String> {
+    let guard = DB.lock();
+    let db = guard.as_ref().ok_or_else(|| String::from("database not open"))?;
+    let result = db.query_params(
+        "SELECT hex(unpack(content, compressed)) FROM namespace WHERE path = ?",
+        &[SqlValue::Text(String::from(path))],
+    )?;
+    let hex = match result.rows.first().and_then(|r| r.first()) {
+        Some(value) => value.as_str().map(String::from).unwrap_or_default(),
+        None => return Err(alloc::format!("{}: no such file", path)),
+    };
+    hex_decode(hex.as_bytes()).map_err(String::from)
+}
+
+/// Store `data` at `path`, as TEXT if it is UTF-8 and as a BLOB if not
+/// (hex through `unhex()`: SqlValue has no BLOB).
+fn store_file(path: &str, data: Vec<u8>) -> Result<(), String> {
+    let text = core::str::from_utf8(&data).is_ok();
+    let hex = String::from_utf8(hex_encode(&data)).unwrap_or_default();
+    let kind = if path.ends_with(".lua") { "lua" } else { "data" };
+    let guard = DB.lock();
+    let db = guard.as_ref().ok_or_else(|| String::from("database not open"))?;
+    db.query_params(
+        "INSERT OR REPLACE INTO namespace (path, type, content, mtime) \
+         VALUES (?1, ?2, CASE ?3 WHEN 1 THEN CAST(unhex(?4) AS TEXT) ELSE unhex(?4) END, \
+                 strftime('%s','now'))",
+        &[
+            SqlValue::Text(String::from(path)),
+            SqlValue::Text(String::from(kind)),
+            SqlValue::Integer(i64::from(text)),
+            SqlValue::Text(hex),
+        ],
+    )?;
+    Ok(())
+}
@@ -0,0 +1,196 @@
+/// Request ids and timing spans.
+///
+/// Each top-level operation — a shell `ask`, an agent run (shell or Lua),
+/// an SQL statement, a Styx message — opens a span with `begin`, which
+/// gives it a request id like `5e1c09a2-00002a` (a per-boot nonce and a
+/// sequence number). Whatever it causes is tagged with `current()`: the
+/// audit rows it writes, the `X-Request-Id` header and trace transcript
+/// of each API call, its error and retry log lines. So "everything this
+/// request did" is one `WHERE request_id = ...` away. A span opened
+/// inside another joins the outer request rather than starting a new one,
+/// so an agent's SQL and API calls carry the agent's id.
+///
+/// A finished span goes into a ring of the last `RECENT` and into
+/// per-kind counters (count, failures, total and slowest time), which
+/// `/sys/spans` reports.
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use spin::Mutex;
+
+/// Finished spans kept for `/sys/spans`.
+const RECENT: usize = 64;
+
+/// A request id: the boot nonce in the high half, a sequence number in
+/// the low half.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RequestId(u64);
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:08x}-{:06x}", self.0 >> 32, self.0 as u32)
+    }
+}
+
+/// A finished span.
+#[derive(Debug, Clone, Copy)]
+pub struct Finished {
+    pub id: RequestId,
+    pub kind: &'static str,
+    pub start_ms: u64,
+    pub elapsed_ms: u64,
+    pub ok: bool,
+}
+
+/// Totals for one kind of span.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Stats {
+    pub count: u64,
+    pub failed: u64,
+    pub total_ms: u64,
+    pub max_ms: u64,
+}
+
+/// The request in progress (0 = none).
+static CURRENT: AtomicU64 = AtomicU64::new(0);
+
+/// Distinguishes this boot's ids from earlier boots' in the audit table.
+static NONCE: AtomicU32 = AtomicU32::new(0);
+static SEQ: AtomicU32 = AtomicU32::new(0);
+
+static FINISHED: Mutex<Vec<Finished>> = Mutex::new(Vec::new());
+static STATS: Mutex<Vec<(&'static str, Stats)>> = Mutex::new(Vec::new());
+
+/// An open span; ends when dropped.
+pub struct Span {
+    id: RequestId,
+    kind: &'static str,
+    /// CURRENT when this span began, restored when it ends.
+    outer: u64,
+    start_ms: u64,
+    ok: bool,
+}
+
+impl Span {
+    pub fn id(&self) -> RequestId {
+        self.id
+    }
+
+    /// Count this span as failed.
+    pub fn fail(&mut self) {
+        self.ok = false;
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        CURRENT.store(self.outer, Ordering::Relaxed);
+        let now = crate::time::monotonic_ms();
+        let done = Finished {
+            id: self.id,
+            kind: self.kind,
+            start_ms: self.start_ms,
+            elapsed_ms: now.saturating_sub(self.start_ms),
+            ok: self.ok,
+        };
+        // A span ending while these are held (a panic mid-report) goes unrecorded
+        if let Some(mut finished) = FINISHED.try_lock() {
+            if finished.len() >= RECENT {
+                finished.remove(0);
+            }
+            finished.push(done);
+        }
+        if let Some(mut stats) = STATS.try_lock() {
+            let i = match stats.iter().position(|(k, _)| *k == done.kind) {
+                Some(i) => i,
+                None => {
+                    stats.push((done.kind, Stats::default()));
+                    stats.len() - 1
+                }
+            };
+            let s = &mut stats[i].1;
+            s.count += 1;
+            s.failed += u64::from(!done.ok);
+            s.total_ms += done.elapsed_ms;
+            s.max_ms = s.max_ms.max(done.elapsed_ms);
+        }
+    }
+}
+
+/// Open a span of `kind` ("ask", "agent", "sql", "styx", ...), under the
+/// current request if there is one, else under a new request id.
+pub fn begin(kind: &'static str) -> Span {
+    let outer = CURRENT.load(Ordering::Relaxed);
+    let id = if outer != 0 { outer } else { next_id() };
+    CURRENT.store(id, Ordering::Relaxed);
+    Span {
+        id: RequestId(id),
+        kind,
+        outer,
+        start_ms: crate::time::monotonic_ms(),
+        ok: true,
+    }
+}
+
+fn next_id() -> u64 {
+    let mut nonce = NONCE.load(Ordering::Relaxed);
+    if nonce == 0 {
+        nonce = (crate::arch::x86_64::cpu::rdtsc() as u32) | 1;
+        NONCE.store(nonce, Ordering::Relaxed);
+    }
+    let seq = SEQ.fetch_add(1, Ordering::Relaxed).wrapping_add(1);
+    (u64::from(nonce) << 32) | u64::from(seq)
+}
+
+/// The request in progress, if any.
+pub fn current() -> Option<RequestId> {
+    match CURRENT.load(Ordering::Relaxed) {
+        0 => None,
+        id => Some(RequestId(id)),
+    }
+}
+
+/// The current request id as text, for SQL parameters and headers.
+pub fn current_str() -> Option<String> {
+    current().map(|id| alloc::format!("{}", id))
+}
+
+/// " (request <id>)" while a request is in progress, else "": a suffix
+/// for log lines.
+pub fn tag() -> String {
+    current().map_or_else(String::new, |id| alloc::format!(" (request {})", id))
+}
+
+/// Finished spans, oldest first.
+pub fn recent() -> Vec<Finished> {
+    FINISHED.lock().clone()
+}
+
+/// Totals per kind, in order of first use.
+pub fn stats() -> Vec<(&'static str, Stats)> {
+    STATS.lock().clone()
+}
+
+/// `/sys/spans`: totals per kind, then the recent spans.
+pub fn report() -> Vec<u8> {
+    let mut out = String::new();
+    let _ = writeln!(out, "{:<8} {:>7} {:>7} {:>8} {:>8}", "kind", "count", "failed", "avg_ms", "max_ms");
+    for (kind, s) in stats() {
+        let avg = s.total_ms.checked_div(s.count).unwrap_or(0);
+        let _ = writeln!(out, "{:<8} {:>7} {:>7} {:>8} {:>8}", kind, s.count, s.failed, avg, s.max_ms);
+    }
+    let _ = writeln!(out, "\n{:<16} {:<8} {:>10} {:>8}  status", "request", "kind", "start_ms", "ms");
+    for f in recent() {
+        let _ = writeln!(
+            out,
+            "{:<16} {:<8} {:>10} {:>8}  {}",
+            f.id,
+            f.kind,
+            f.start_ms,
+            f.elapsed_ms,
+            if f.ok { "ok" } else { "failed" }
+        );
+    }
+    out.into_bytes()
+}
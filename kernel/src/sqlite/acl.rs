@@ -0,0 +1,212 @@
+/// Access control on namespace paths.
+///
+/// Every namespace row has a `mode` (Unix-style permission bits) and an
+/// `owner` principal. Principals are written as:
+///
+///   shell          the console operator (superuser: never denied)
+///   agent:<path>   a Lua agent, named by its script path (the `agent`
+///                  command's tools run as agent:claude)
+///   styx:<uname>   a 9P client, by the uname it attached with
+///
+/// A principal's group is its kind (`agent`, `styx`). Against a row, the
+/// owner uses bits 0o600, principals of the owner's kind 0o060, and
+/// everyone else 0o006 (read 4, write 2; execute is unused). Rows without
+/// an owner (written before ownership existed, or by the shell) belong to
+/// `shell`, so the default mode 0o644 lets agents read them but not write.
+///
+/// A path with no row may be created by anyone; the creator becomes the
+/// owner. SQL can't ask the ACL per row, so SQL written by an agent
+/// (Lua `sql()`, the `sql_query` tool) may not read file content at all:
+/// `SqliteDb::without_content` installs an authorizer that refuses
+/// `namespace.content` and `embeddings.chunk`. Paths, modes and the other
+/// columns stay visible.
+use alloc::string::String;
+use core::fmt;
+
+use super::{SqliteDb, SqlValue};
+
+/// Who is asking.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Principal {
+    Shell,
+    Agent(String),
+    Styx(String),
+}
+
+impl Principal {
+    /// Parse "shell", "agent:<path>" or "styx:<uname>".
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.split_once(':') {
+            None if s == "shell" => Some(Principal::Shell),
+            Some(("agent", name)) if !name.is_empty() => Some(Principal::Agent(String::from(name))),
+            Some(("styx", name)) if !name.is_empty() => Some(Principal::Styx(String::from(name))),
+            _ => None,
+        }
+    }
+
+    /// The principal's group: its kind.
+    fn kind(&self) -> &'static str {
+        match self {
+            Principal::Shell => "shell",
+            Principal::Agent(_) => "agent",
+            Principal::Styx(_) => "styx",
+        }
+    }
+}
+
+impl fmt::Display for Principal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Principal::Shell => write!(f, "shell"),
+            Principal::Agent(name) => write!(f, "agent:{}", name),
+            Principal::Styx(name) => write!(f, "styx:{}", name),
+        }
+    }
+}
+
+/// What the principal wants to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+impl Access {
+    /// The "other" permission bit; shifted by 3 for group, 6 for owner.
+    fn bit(self) -> i64 {
+        match self {
+            Access::Read => 0o4,
+            Access::Write => 0o2,
+        }
+    }
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Access::Read => write!(f, "read"),
+            Access::Write => write!(f, "write"),
+        }
+    }
+}
+
+/// Mode given to rows that have none.
+pub const DEFAULT_MODE: i64 = 0o644;
+
+/// May `who` perform `access` on `path`? A missing path is permitted
+/// (reads then fail as not found; writes create it).
+pub fn permits(db: &SqliteDb, who: &Principal, path: &str, access: Access) -> Result<bool, String> {
+    if *who == Principal::Shell {
+        return Ok(true);
+    }
+    let Some((mode, owner)) = lookup(db, path)? else {
+        return Ok(true);
+    };
+
+    let shift = if owner == *who {
+        6
+    } else if owner.kind() == who.kind() {
+        3
+    } else {
+        0
+    };
+    Ok(mode & (access.bit() << shift) != 0)
+}
+
+/// `permits`, as an error message naming the principal on denial.
+pub fn check(db: &SqliteDb, who: &Principal, path: &str, access: Access) -> Result<(), String> {
+    if permits(db, who, path, access)? {
+        Ok(())
+    } else {
+        Err(denied(who, path, access))
+    }
+}
+
+/// The message for a denied access ("permission denied: ...").
+pub fn denied(who: &Principal, path: &str, access: Access) -> String {
+    alloc::format!("permission denied: {} may not {} {}", who, access, path)
+}
+
+/// Create or overwrite a file on behalf of `who`, keeping the mode and
+/// owner of an existing row. A new row is owned by `who`. Quotas apply to
+/// everyone but the shell, on the bytes stored: large content is
+/// compressed (see `compress`). A `/tmp` path goes to `fs::tmpfs` instead.
+pub fn write_file(db: &SqliteDb, who: &Principal, path: &str, kind: &str, content: &str) -> Result<(), String> {
+    if crate::fs::tmpfs::is_tmp(path) {
+        return crate::fs::tmpfs::write(who, path, content.as_bytes());
+    }
+    check(db, who, path, Access::Write)?;
+    let packed = super::compress::pack(db, content);
+    if *who != Principal::Shell {
+        super::quota::check(db, path, packed.stored_len)?;
+    }
+    let owner = match who {
+        Principal::Shell => SqlValue::Null,
+        other => SqlValue::Text(alloc::format!("{}", other)),
+    };
+    db.query_params(
+        "INSERT INTO namespace (path, type, content, compressed, mtime, owner) \
+         VALUES (?1, ?2, CASE ?4 WHEN 1 THEN unhex(?3) ELSE ?3 END, ?4, strftime('%s','now'), ?5) \
+         ON CONFLICT(path) DO UPDATE SET type = excluded.type, \
+             content = excluded.content, compressed = excluded.compressed, mtime = excluded.mtime",
+        &[
+            SqlValue::Text(String::from(path)),
+            SqlValue::Text(String::from(kind)),
+            packed.value,
+            SqlValue::Integer(i64::from(packed.compressed)),
+            owner,
+        ],
+    )?;
+    Ok(())
+}
+
+/// Set the permission bits of an existing path.
+pub fn chmod(db: &SqliteDb, path: &str, mode: i64) -> Result<(), String> {
+    update(db, "UPDATE namespace SET mode = ? WHERE path = ?", SqlValue::Integer(mode & 0o777), path)
+}
+
+/// Give an existing path to another principal.
+pub fn chown(db: &SqliteDb, path: &str, owner: &Principal) -> Result<(), String> {
+    let owner = match owner {
+        Principal::Shell => SqlValue::Null,
+        other => SqlValue::Text(alloc::format!("{}", other)),
+    };
+    update(db, "UPDATE namespace SET owner = ? WHERE path = ?", owner, path)
+}
+
+fn update(db: &SqliteDb, sql: &str, value: SqlValue, path: &str) -> Result<(), String> {
+    db.query_params(sql, &[value, SqlValue::Text(String::from(path))])?;
+    match db.query_value("SELECT changes()")?.as_deref() {
+        Some("0") => Err(alloc::format!("{}: no such file", path)),
+        _ => Ok(()),
+    }
+}
+
+/// Mode and owner of a path, or None if it has no row.
+pub fn lookup(db: &SqliteDb, path: &str) -> Result<Option<(i64, Principal)>, String> {
+    let params = [SqlValue::Text(String::from(path))];
+    // A read-only mount of a database from before `migrate` has no owner
+    // column; everything there belongs to the shell.
+    let result = db
+        .query_params("SELECT mode, owner FROM namespace WHERE path = ?", &params)
+        .or_else(|_| db.query_params("SELECT mode, NULL FROM namespace WHERE path = ?", &params))?;
+    let Some(row) = result.rows.first() else {
+        return Ok(None);
+    };
+    let mode = row.first().and_then(SqlValue::as_integer).unwrap_or(DEFAULT_MODE);
+    let owner = row
+        .get(1)
+        .and_then(SqlValue::as_str)
+        .and_then(Principal::parse)
+        .unwrap_or(Principal::Shell);
+    Ok(Some((mode, owner)))
+}
+
+/// Add the `owner` column to a namespace table created before it existed.
+pub(super) fn migrate(db: &SqliteDb) -> Result<(), String> {
+    let columns = db.query_column("SELECT name FROM pragma_table_info('namespace')")?;
+    if !columns.iter().any(|c| c == "owner") {
+        db.exec("ALTER TABLE namespace ADD COLUMN owner TEXT")?;
+    }
+    Ok(())
+}
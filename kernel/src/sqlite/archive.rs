@@ -0,0 +1,179 @@
+/// Namespace archives: a subtree of the `namespace` table packed into one
+/// file, for moving agent collections between machines (`sx`/`rx` or 9P).
+///
+/// Format (version 1). Header lines are ASCII and end in `\n`; content is
+/// raw bytes, so text and BLOBs both survive:
+///
+///   HEAVENARC 1
+///   F <type> <mode> <mtime> <class> <len> <path>
+///   <len bytes of content>\n
+///   ...                                  one F record per entry
+///   E <count>
+///
+/// `mode` is octal, `mtime` Unix seconds. `class` is the SQLite storage
+/// class of the content: `t` text, `b` blob, `-` NULL (with `len` 0).
+/// The path runs to the end of its line; paths containing a newline are
+/// not archived. The `E` trailer carries the entry count so a truncated
+/// archive is detected rather than half-applied.
+///
+/// The archive is itself stored as a `data` file. Extracting replaces
+/// entries with the same path and runs in one transaction.
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use super::{SqlValue, DB};
+use crate::lua::util::{hex_decode, hex_encode};
+
+const MAGIC: &[u8] = b"HEAVENARC 1\n";
+
+/// Create an archive of every entry at or below `prefix` (`/` for all)
+/// and store it at `dest`. Returns the number of entries and the archive
+/// size in bytes.
+pub fn create(dest: &str, prefix: &str) -> Result<(u64, usize), String> {
+    let mut out = Vec::from(MAGIC);
+    let mut count = 0u64;
+    {
+        let guard = DB.lock();
+        let db = guard.as_ref().ok_or_else(|| String::from("database not open"))?;
+        // `path` compares NOCASE, so the range takes every spelling of the prefix
+        let top = prefix.trim_end_matches('/');
+        let result = db.query_params(
+            "SELECT path, type, mode, mtime, typeof(c), hex(c) FROM \
+             (SELECT *, unpack(content, compressed) AS c FROM namespace \
+              WHERE path <> ?1 AND instr(path, char(10)) = 0 \
+                AND (?2 = '' OR path = ?2 OR (path >= ?2 || '/' AND path < ?2 || '0'))) \
+             ORDER BY path",
+            &[SqlValue::Text(String::from(dest)), SqlValue::Text(String::from(top))],
+        )?;
+
+        for row in &result.rows {
+            let path = row.first().and_then(SqlValue::as_str).unwrap_or("");
+            let kind = row.get(1).and_then(SqlValue::as_str).unwrap_or("data");
+            let mode = row.get(2).and_then(SqlValue::as_integer).unwrap_or(0o644);
+            let mtime = row.get(3).and_then(SqlValue::as_integer).unwrap_or(0);
+            let class = match row.get(4).and_then(SqlValue::as_str) {
+                Some("null") => "-",
+                Some("blob") => "b",
+                _ => "t",
+            };
+            let content = hex_decode(row.get(5).and_then(SqlValue::as_str).unwrap_or("").as_bytes())?;
+
+            out.extend_from_slice(
+                alloc::format!("F {} {:o} {} {} {} {}\n", kind, mode, mtime, class, content.len(), path)
+                    .as_bytes(),
+            );
+            out.extend_from_slice(&content);
+            out.push(b'\n');
+            count += 1;
+        }
+    }
+    out.extend_from_slice(alloc::format!("E {}\n", count).as_bytes());
+
+    let size = out.len();
+    let hex = String::from_utf8(hex_encode(&out)).unwrap_or_default();
+    let guard = DB.lock();
+    let db = guard.as_ref().ok_or_else(|| String::from("database not open"))?;
+    db.query_params(
+        "INSERT OR REPLACE INTO namespace (path, type, content, mtime) \
+         VALUES (?, 'data', unhex(?), strftime('%s','now'))",
+        &[SqlValue::Text(String::from(dest)), SqlValue::Text(hex)],
+    )?;
+    Ok((count, size))
+}
+
+/// Unpack the archive stored at `src` into the namespace. Returns the
+/// number of entries written.
+pub fn extract(src: &str) -> Result<u64, String> {
+    let data = {
+        let guard = DB.lock();
+        let db = guard.as_ref().ok_or_else(|| String::from("database not open"))?;
+        let result = db.query_params(
+            "SELECT hex(unpack(content, compressed)) FROM namespace WHERE path = ?",
+            &[SqlValue::Text(String::from(src))],
+        )?;
+        let Some(value) = result.rows.first().and_then(|r| r.first()) else {
+            return Err(alloc::format!("{}: no such file", src));
+        };
+        hex_decode(value.as_str().unwrap_or("").as_bytes())?
+    };
+    let entries = parse(&data)?;
+
+    let guard = DB.lock();
+    let db = guard.as_ref().ok_or_else(|| String::from("database not open"))?;
+    db.exec("BEGIN")?;
+    for entry in &entries {
+        let hex = String::from_utf8(hex_encode(entry.content)).unwrap_or_default();
+        let stored = db.query_params(
+            "INSERT OR REPLACE INTO namespace (path, type, content, mode, mtime) \
+             VALUES (?, ?, CASE ? WHEN 't' THEN CAST(unhex(?) AS TEXT) \
+                                  WHEN 'b' THEN unhex(?) END, ?, ?)",
+            &[
+                SqlValue::Text(String::from(entry.path)),
+                SqlValue::Text(String::from(entry.kind)),
+                SqlValue::Text(String::from(entry.class)),
+                SqlValue::Text(hex.clone()),
+                SqlValue::Text(hex),
+                SqlValue::Integer(entry.mode),
+                SqlValue::Integer(entry.mtime),
+            ],
+        );
+        if let Err(e) = stored {
+            let _ = db.exec("ROLLBACK");
+            return Err(alloc::format!("{}: {}", entry.path, e));
+        }
+    }
+    db.exec("COMMIT")?;
+    Ok(entries.len() as u64)
+}
+
+/// One archived namespace row, borrowing from the archive bytes.
+struct Entry<'a> {
+    path: &'a str,
+    kind: &'a str,
+    mode: i64,
+    mtime: i64,
+    class: &'a str,
+    content: &'a [u8],
+}
+
+/// Parse and validate a whole archive before anything is written.
+fn parse(data: &[u8]) -> Result<Vec<Entry<'_>>, String> {
+    let mut rest = data
+        .strip_prefix(MAGIC)
+        .ok_or_else(|| String::from("not a namespace archive (bad magic)"))?;
+    let mut entries = Vec::new();
+
+    loop {
+        let (line, after) = split_line(rest).ok_or_else(|| String::from("archive truncated"))?;
+        rest = after;
+        let line = core::str::from_utf8(line).map_err(|_| String::from("archive: bad header line"))?;
+
+        if let Some(count) = line.strip_prefix("E ") {
+            if count.parse::<usize>().ok() != Some(entries.len()) {
+                return Err(alloc::format!("archive: trailer says {} entries, found {}", count, entries.len()));
+            }
+            return Ok(entries);
+        }
+
+        let bad = || alloc::format!("archive: bad entry header: {}", line);
+        let header = line.strip_prefix("F ").ok_or_else(bad)?;
+        let mut fields = header.splitn(6, ' ');
+        let kind = fields.next().ok_or_else(bad)?;
+        let mode = fields.next().and_then(|m| i64::from_str_radix(m, 8).ok()).ok_or_else(bad)?;
+        let mtime = fields.next().and_then(|m| m.parse().ok()).ok_or_else(bad)?;
+        let class = fields.next().filter(|c| matches!(*c, "t" | "b" | "-")).ok_or_else(bad)?;
+        let len: usize = fields.next().and_then(|l| l.parse().ok()).ok_or_else(bad)?;
+        let path = fields.next().filter(|p| !p.is_empty()).ok_or_else(bad)?;
+
+        if rest.len() < len + 1 || rest[len] != b'\n' {
+            return Err(alloc::format!("archive: {}: content truncated", path));
+        }
+        entries.push(Entry { path, kind, mode, mtime, class, content: &rest[..len] });
+        rest = &rest[len + 1..];
+    }
+}
+
+fn split_line(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    let end = data.iter().position(|&b| b == b'\n')?;
+    Some((&data[..end], &data[end + 1..]))
+}
@@ -0,0 +1,246 @@
+/// Audit table retention and NDJSON export.
+///
+/// Retention is configured with two config keys, both optional:
+///
+///   audit.max_rows   keep at most this many of the newest rows
+///   audit.max_age    drop rows older than this ("3600", "90m", "12h", "30d")
+///
+/// `enforce_retention` applies them; the maintenance task calls it
+/// periodically. Export writes one JSON object per line:
+///
+///   {"id":1,"ts":1760000000,"level":"INFO","agent":"…","action":"…",
+///    "target":"…","detail":null,"request_id":"5e1c09a2-00002a"}
+///
+/// `request_id` is the request (`crate::span`) that wrote the row, null
+/// for rows written outside one or before the column existed.
+///
+/// The agent loop also logs each turn's token counts as an `API_USAGE` row
+/// (`record_usage`), with the counts as JSON in `detail`; the `token_usage`
+/// and `conversation_usage` views read them back (`sqlite-json` builds).
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use super::{SqlValue, DB};
+
+/// Rows fetched per query while streaming.
+const BATCH_ROWS: i64 = 64;
+
+const MAX_ROWS_KEY: &str = "audit.max_rows";
+const MAX_AGE_KEY: &str = "audit.max_age";
+
+/// Parsed retention settings. `None` means unlimited.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RetentionPolicy {
+    pub max_rows: Option<u64>,
+    pub max_age_secs: Option<u64>,
+}
+
+impl RetentionPolicy {
+    /// Read the policy from config. Unparseable values are ignored.
+    pub fn from_config() -> Self {
+        Self {
+            max_rows: super::config_get(MAX_ROWS_KEY).and_then(|v| v.trim().parse().ok()),
+            max_age_secs: super::config_get(MAX_AGE_KEY).and_then(|v| parse_duration(&v)),
+        }
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        self.max_rows.is_none() && self.max_age_secs.is_none()
+    }
+}
+
+/// Parse "3600", "90s", "15m", "12h" or "30d" into seconds.
+pub fn parse_duration(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let (digits, unit) = match s.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+        Some((i, _)) => (&s[..i], &s[i..]),
+        None => (s, ""),
+    };
+    let n: u64 = digits.parse().ok()?;
+    let scale = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        _ => return None,
+    };
+    n.checked_mul(scale)
+}
+
+/// Delete rows outside the configured policy. Returns how many went.
+pub fn enforce_retention() -> Result<u64, String> {
+    let policy = RetentionPolicy::from_config();
+    if policy.is_unlimited() {
+        return Ok(0);
+    }
+
+    let guard = DB.lock();
+    let db = guard.as_ref().ok_or_else(|| String::from("database not open"))?;
+    let mut deleted = 0;
+
+    if let Some(age) = policy.max_age_secs {
+        db.query_params(
+            "DELETE FROM audit WHERE ts < CAST(strftime('%s','now') AS INTEGER) - ?",
+            &[SqlValue::Integer(age as i64)],
+        )?;
+        deleted += changes(db)?;
+    }
+    if let Some(rows) = policy.max_rows {
+        db.query_params(
+            "DELETE FROM audit WHERE id <= \
+             (SELECT id FROM audit ORDER BY id DESC LIMIT 1 OFFSET ?)",
+            &[SqlValue::Integer(rows as i64)],
+        )?;
+        deleted += changes(db)?;
+    }
+    Ok(deleted)
+}
+
+/// Add the `request_id` column to an audit table created before it existed.
+pub(super) fn migrate(db: &super::SqliteDb) -> Result<(), String> {
+    let columns = db.query_column("SELECT name FROM pragma_table_info('audit')")?;
+    if !columns.iter().any(|c| c == "request_id") {
+        db.exec("ALTER TABLE audit ADD COLUMN request_id TEXT")?;
+    }
+    Ok(())
+}
+
+/// The current request id as a parameter for an audit INSERT.
+pub fn request_id() -> SqlValue {
+    crate::span::current_str().map_or(SqlValue::Null, SqlValue::Text)
+}
+
+fn changes(db: &super::SqliteDb) -> Result<u64, String> {
+    Ok(db
+        .query_value("SELECT changes()")?
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0))
+}
+
+/// Log the tokens one agent turn used. `target` is the conversation id,
+/// `detail` a JSON object:
+///
+///   {"conversation":"…","model":"…","turn":1,"input_tokens":812,"output_tokens":96,
+///    "cache_write_tokens":0,"cache_read_tokens":2310,"discount_percent":0}
+///
+/// `discount_percent` is what the request saved on the usual price: 50
+/// for one answered through the Message Batches API.
+pub fn record_usage(
+    db: &super::SqliteDb,
+    agent: &str,
+    conversation: &str,
+    model: &str,
+    turn: usize,
+    usage: &crate::api::Usage,
+    discount_percent: u8,
+) -> Result<(), String> {
+    // Built here rather than with json_object(): JSON1 is optional
+    let detail = alloc::format!(
+        "{{\"conversation\":\"{}\",\"model\":\"{}\",\"turn\":{},\"input_tokens\":{},\"output_tokens\":{},\
+         \"cache_write_tokens\":{},\"cache_read_tokens\":{},\"discount_percent\":{}}}",
+        crate::api::escape_json(conversation),
+        crate::api::escape_json(model),
+        turn,
+        usage.input_tokens,
+        usage.output_tokens,
+        usage.cache_write_tokens,
+        usage.cache_read_tokens,
+        discount_percent,
+    );
+    db.query_params(
+        "INSERT INTO audit (level, agent, action, target, detail, request_id) \
+         VALUES ('INFO', ?, 'API_USAGE', ?, ?, ?)",
+        &[
+            SqlValue::Text(String::from(agent)),
+            SqlValue::Text(String::from(conversation)),
+            SqlValue::Text(detail),
+            request_id(),
+        ],
+    )?;
+    Ok(())
+}
+
+/// Serialize rows with `id > after_id` as NDJSON, stopping before
+/// `max_bytes` would be exceeded. Returns the bytes and the last id
+/// included (`after_id` again when there is nothing new).
+///
+/// A single line larger than `max_bytes` is still returned on its own so
+/// a reader can't stall on it.
+///
+/// Runs on a reader connection, so tailing the log never waits on a writer.
+pub fn ndjson_after(after_id: u64, max_bytes: usize) -> Result<(Vec<u8>, u64), String> {
+    super::with_reader(|db| ndjson_batch(db, after_id, max_bytes))?
+}
+
+fn ndjson_batch(db: &super::SqliteDb, after_id: u64, max_bytes: usize) -> Result<(Vec<u8>, u64), String> {
+    let mut out = Vec::new();
+    let mut last = after_id;
+    loop {
+        // Every column: a read-only mount of an old database has no request_id
+        let result = db.query_params(
+            "SELECT * FROM audit WHERE id > ? ORDER BY id LIMIT ?",
+            &[SqlValue::Integer(last as i64), SqlValue::Integer(BATCH_ROWS)],
+        )?;
+        if result.rows.is_empty() {
+            return Ok((out, last));
+        }
+        for row in &result.rows {
+            let line = ndjson_line(&result.columns, row);
+            if !out.is_empty() && out.len() + line.len() > max_bytes {
+                return Ok((out, last));
+            }
+            out.extend_from_slice(line.as_bytes());
+            last = row.first().and_then(SqlValue::as_integer).unwrap_or(last as i64) as u64;
+            if out.len() >= max_bytes {
+                return Ok((out, last));
+            }
+        }
+    }
+}
+
+/// Write the whole audit table as NDJSON to a namespace data file.
+/// Returns the number of rows exported.
+pub fn export(path: &str) -> Result<u64, String> {
+    let mut data = Vec::new();
+    let mut cursor = 0;
+    let mut rows = 0;
+    loop {
+        let (chunk, last) = ndjson_after(cursor, 64 * 1024)?;
+        if chunk.is_empty() {
+            break;
+        }
+        rows += chunk.iter().filter(|&&b| b == b'\n').count() as u64;
+        data.extend_from_slice(&chunk);
+        cursor = last;
+    }
+
+    let content = String::from_utf8(data).map_err(|_| String::from("audit export: invalid UTF-8"))?;
+    let guard = DB.lock();
+    let db = guard.as_ref().ok_or_else(|| String::from("database not open"))?;
+    super::acl::write_file(db, &super::acl::Principal::Shell, path, "data", &content)?;
+    Ok(rows)
+}
+
+fn ndjson_line(columns: &[String], row: &[SqlValue]) -> String {
+    let mut line = String::from("{");
+    for (i, (name, value)) in columns.iter().zip(row).enumerate() {
+        if i > 0 {
+            line.push(',');
+        }
+        line.push('"');
+        line.push_str(name);
+        line.push_str("\":");
+        match value {
+            SqlValue::Null => line.push_str("null"),
+            SqlValue::Integer(n) => line.push_str(&alloc::format!("{}", n)),
+            SqlValue::Real(n) => line.push_str(&alloc::format!("{}", n)),
+            SqlValue::Text(s) => {
+                line.push('"');
+                line.push_str(&crate::api::escape_json(s));
+                line.push('"');
+            }
+        }
+    }
+    line.push_str("}\n");
+    line
+}
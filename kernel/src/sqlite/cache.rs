@@ -0,0 +1,193 @@
+/// Model response cache.
+///
+/// Opt-in: nothing is cached until the `cache.ttl` config key holds a
+/// duration ("3600", "15m", "12h", "1d"; "off" or 0 disables it again).
+/// Entries are keyed by SHA-256 of the model, system prompt, messages and
+/// stop sequences, so an `ask()` that repeats an earlier request word for
+/// word — a scheduled agent summarizing data that hasn't changed — is
+/// answered from the table instead of the network until the entry
+/// expires. The stop reason and sequence are kept with the text, so a
+/// cached answer reads the same as the live one.
+///
+/// Expired rows are dropped whenever a new response is stored; `cache
+/// clear` in the shell drops the rest.
+use alloc::string::String;
+use alloc::vec::Vec;
+use sha2::{Digest, Sha256};
+
+use super::{SqlValue, DB};
+use crate::api::{ContentBlock, Message};
+
+const TTL_KEY: &str = "cache.ttl";
+
+/// A cached response, as `list` reports it.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub key: String,
+    pub model: String,
+    pub created: i64,
+    pub expires: i64,
+    pub hits: i64,
+    pub response: String,
+}
+
+/// A response as the cache keeps it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Answer {
+    pub text: String,
+    /// Why the model stopped ("end_turn", "stop_sequence", ...).
+    pub stop_reason: String,
+    /// The stop sequence that ended the text, if one did.
+    pub stop_sequence: Option<String>,
+}
+
+/// Lifetime of new entries, or None while caching is off.
+pub fn ttl() -> Option<u64> {
+    super::config_get(TTL_KEY)
+        .and_then(|v| super::audit::parse_duration(&v))
+        .filter(|&secs| secs > 0)
+}
+
+/// Cache key for a request: hex SHA-256 over every field that reaches
+/// the model, each length-prefixed so no two requests share an encoding.
+pub fn key(model: &str, system: Option<&str>, messages: &[Message], stop_sequences: &[String]) -> String {
+    let mut hasher = Sha256::new();
+    let mut field = |tag: &[u8], value: &str| {
+        hasher.update(tag);
+        hasher.update((value.len() as u64).to_le_bytes());
+        hasher.update(value.as_bytes());
+    };
+    field(b"model", model);
+    if let Some(system) = system {
+        field(b"system", system);
+    }
+    for message in messages {
+        field(b"role", message.role);
+        if message.content_blocks.is_empty() {
+            field(b"text", &message.content);
+        }
+        for block in &message.content_blocks {
+            match block {
+                ContentBlock::Text(text) => field(b"text", text),
+                ContentBlock::ToolUse { id, name, input_json } => {
+                    field(b"tool_use", id);
+                    field(b"name", name);
+                    field(b"input", input_json);
+                }
+                ContentBlock::ToolResult { tool_use_id, content, is_error } => {
+                    field(b"tool_result", tool_use_id);
+                    field(if *is_error { b"error" } else { b"result" }, content);
+                }
+            }
+        }
+    }
+    for stop in stop_sequences {
+        field(b"stop", stop);
+    }
+
+    let mut out = String::with_capacity(64);
+    for byte in hasher.finalize() {
+        out.push_str(&alloc::format!("{:02x}", byte));
+    }
+    out
+}
+
+/// The unexpired response stored under `key`, counting the hit.
+pub fn lookup(key: &str) -> Option<Answer> {
+    let guard = DB.lock();
+    let db = guard.as_ref()?;
+    let params = [SqlValue::Text(String::from(key))];
+    let result = db
+        .query_params(
+            "SELECT response, stop_reason, stop_sequence FROM response_cache \
+             WHERE key = ? AND expires > CAST(strftime('%s','now') AS INTEGER)",
+            &params,
+        )
+        .ok()?;
+    let row = result.rows.first()?;
+    let text = |i: usize| row.get(i).and_then(SqlValue::as_str).map(String::from);
+    let answer = Answer { text: text(0)?, stop_reason: text(1)?, stop_sequence: text(2) };
+    let _ = db.query_params("UPDATE response_cache SET hits = hits + 1 WHERE key = ?", &params);
+    Some(answer)
+}
+
+/// Store `answer` under `key` for `ttl_secs`, pruning expired entries.
+pub fn store(key: &str, model: &str, answer: &Answer, ttl_secs: u64) -> Result<(), String> {
+    let guard = DB.lock();
+    let db = guard.as_ref().ok_or_else(|| String::from("database not open"))?;
+    db.exec("DELETE FROM response_cache WHERE expires <= CAST(strftime('%s','now') AS INTEGER)")?;
+    db.query_params(
+        "INSERT OR REPLACE INTO response_cache \
+             (key, model, created, expires, hits, response, stop_reason, stop_sequence) \
+         VALUES (?1, ?2, CAST(strftime('%s','now') AS INTEGER), \
+                 CAST(strftime('%s','now') AS INTEGER) + ?3, 0, ?4, ?5, ?6)",
+        &[
+            SqlValue::Text(String::from(key)),
+            SqlValue::Text(String::from(model)),
+            SqlValue::Integer(ttl_secs as i64),
+            SqlValue::Text(answer.text.clone()),
+            SqlValue::Text(answer.stop_reason.clone()),
+            answer.stop_sequence.clone().map_or(SqlValue::Null, SqlValue::Text),
+        ],
+    )?;
+    Ok(())
+}
+
+/// Entries, newest first.
+pub fn list() -> Result<Vec<Entry>, String> {
+    let guard = DB.lock();
+    let db = guard.as_ref().ok_or_else(|| String::from("database not open"))?;
+    let result = db.query(
+        "SELECT key, model, created, expires, hits, response FROM response_cache \
+         ORDER BY created DESC, key",
+    )?;
+    let text = |row: &[SqlValue], i: usize| String::from(row.get(i).and_then(SqlValue::as_str).unwrap_or(""));
+    let int = |row: &[SqlValue], i: usize| row.get(i).and_then(SqlValue::as_integer).unwrap_or(0);
+    Ok(result
+        .rows
+        .iter()
+        .map(|row| Entry {
+            key: text(row, 0),
+            model: text(row, 1),
+            created: int(row, 2),
+            expires: int(row, 3),
+            hits: int(row, 4),
+            response: text(row, 5),
+        })
+        .collect())
+}
+
+/// Delete every entry whose key starts with `prefix` ("" for all), or only
+/// the expired ones. Returns how many went.
+pub fn clear(prefix: &str, expired_only: bool) -> Result<u64, String> {
+    let guard = DB.lock();
+    let db = guard.as_ref().ok_or_else(|| String::from("database not open"))?;
+    let expired = if expired_only { " AND expires <= CAST(strftime('%s','now') AS INTEGER)" } else { "" };
+    db.query_params(
+        &alloc::format!("DELETE FROM response_cache WHERE substr(key, 1, length(?1)) = ?1{}", expired),
+        &[SqlValue::Text(String::from(prefix))],
+    )?;
+    Ok(db.query_value("SELECT changes()")?.and_then(|v| v.parse().ok()).unwrap_or(0))
+}
+
+/// Create the `response_cache` table.
+pub(super) fn create_table(db: &super::SqliteDb) -> Result<(), String> {
+    db.exec(
+        "CREATE TABLE IF NOT EXISTS response_cache (\
+            key           TEXT PRIMARY KEY, \
+            model         TEXT NOT NULL, \
+            created       INTEGER NOT NULL, \
+            expires       INTEGER NOT NULL, \
+            hits          INTEGER NOT NULL DEFAULT 0, \
+            response      TEXT NOT NULL, \
+            stop_reason   TEXT NOT NULL DEFAULT 'end_turn', \
+            stop_sequence TEXT\
+        )",
+    )?;
+    let columns = db.query_column("SELECT name FROM pragma_table_info('response_cache')")?;
+    if !columns.iter().any(|c| c == "stop_reason") {
+        db.exec("ALTER TABLE response_cache ADD COLUMN stop_reason TEXT NOT NULL DEFAULT 'end_turn'")?;
+        db.exec("ALTER TABLE response_cache ADD COLUMN stop_sequence TEXT")?;
+    }
+    Ok(())
+}
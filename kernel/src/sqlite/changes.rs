@@ -0,0 +1,197 @@
+/// Change journal for agent tool writes.
+///
+/// Every namespace write made by an `agent` tool (write_file, str_replace)
+/// adds a row to `changes`: the conversation and tool_use id it came from,
+/// SHA-256 of the content before (NULL for a new file) and after, the
+/// lines added and removed, and the assistant text that accompanied the
+/// tool call — the model's own account of why. The call's input JSON is
+/// kept too (bulky string fields dropped, see `INPUT_MAX`) for the
+/// `tool_inputs` view. `changes` in the shell lists them, filtered by path
+/// prefix or conversation.
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use super::{SqliteDb, SqlValue};
+
+/// Longest `reason` kept per row; the rest is cut at a char boundary.
+const REASON_MAX: usize = 500;
+
+/// Longest tool input kept whole. A larger one is stored without its
+/// `content`, `old_str` and `new_str` fields, which the hashes and line
+/// counts already summarize.
+const INPUT_MAX: usize = 4096;
+
+/// The `input` column from ?10, the call's input, and ?11, `INPUT_MAX`.
+#[cfg(feature = "sqlite-json")]
+const INPUT_SQL: &str = "CASE WHEN NOT json_valid(?10) THEN NULL \
+                              WHEN length(?10) <= ?11 THEN json(?10) \
+                              ELSE json_remove(?10, '$.content', '$.old_str', '$.new_str') END";
+
+/// Without JSON1 a large input can't be trimmed, so only a short one is kept.
+#[cfg(not(feature = "sqlite-json"))]
+const INPUT_SQL: &str = "CASE WHEN length(?10) <= ?11 THEN ?10 END";
+
+/// One write as the tool saw it.
+pub struct Change<'a> {
+    pub conversation: &'a str,
+    pub tool_use_id: &'a str,
+    pub tool: &'a str,
+    pub path: &'a str,
+    /// Content before the write (None = the file did not exist).
+    pub before: Option<&'a str>,
+    pub after: &'a str,
+    pub reason: &'a str,
+    /// The tool call's input JSON.
+    pub input: &'a str,
+}
+
+/// A journal row.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub id: i64,
+    pub ts: i64,
+    pub conversation: String,
+    pub tool_use_id: String,
+    pub tool: String,
+    pub path: String,
+    pub before_hash: Option<String>,
+    pub after_hash: String,
+    pub added: i64,
+    pub removed: i64,
+    pub reason: String,
+}
+
+/// Which rows `query` returns, newest first.
+#[derive(Debug, Clone)]
+pub struct Filter {
+    /// Only paths starting with this.
+    pub prefix: Option<String>,
+    /// Only this conversation (or an id prefix of it).
+    pub conversation: Option<String>,
+    pub limit: usize,
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Filter { prefix: None, conversation: None, limit: 20 }
+    }
+}
+
+/// Create the `changes` table.
+pub(super) fn create_table(db: &SqliteDb) -> Result<(), String> {
+    db.exec(
+        "CREATE TABLE IF NOT EXISTS changes (\
+            id           INTEGER PRIMARY KEY AUTOINCREMENT, \
+            ts           INTEGER DEFAULT (strftime('%s','now')), \
+            conversation TEXT NOT NULL, \
+            tool_use_id  TEXT NOT NULL, \
+            tool         TEXT NOT NULL, \
+            path         TEXT NOT NULL, \
+            before_hash  TEXT, \
+            after_hash   TEXT NOT NULL, \
+            added        INTEGER NOT NULL, \
+            removed      INTEGER NOT NULL, \
+            reason       TEXT, \
+            input        TEXT\
+        )",
+    )?;
+    let columns = db.query_column("SELECT name FROM pragma_table_info('changes')")?;
+    if !columns.iter().any(|c| c == "input") {
+        db.exec("ALTER TABLE changes ADD COLUMN input TEXT")?;
+    }
+    Ok(())
+}
+
+/// A fresh conversation id: 12 hex digits from SQLite's randomness.
+pub fn new_conversation_id(db: &SqliteDb) -> Result<String, String> {
+    db.query_value("SELECT lower(hex(randomblob(6)))")?
+        .ok_or_else(|| String::from("randomblob returned nothing"))
+}
+
+/// Journal one write.
+pub fn record(db: &SqliteDb, change: &Change) -> Result<(), String> {
+    let stat = crate::diff::stat(change.before.unwrap_or(""), change.after);
+    let before_hash = match change.before {
+        Some(text) => SqlValue::Text(hash(text)),
+        None => SqlValue::Null,
+    };
+    db.query_params(
+        &alloc::format!(
+            "INSERT INTO changes (conversation, tool_use_id, tool, path, before_hash, after_hash, \
+                                  added, removed, reason, input) \
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, {})",
+            INPUT_SQL
+        ),
+        &[
+            SqlValue::Text(String::from(change.conversation)),
+            SqlValue::Text(String::from(change.tool_use_id)),
+            SqlValue::Text(String::from(change.tool)),
+            SqlValue::Text(String::from(change.path)),
+            before_hash,
+            SqlValue::Text(hash(change.after)),
+            SqlValue::Integer(stat.added as i64),
+            SqlValue::Integer(stat.removed as i64),
+            SqlValue::Text(String::from(truncate(change.reason.trim(), REASON_MAX))),
+            SqlValue::Text(String::from(change.input)),
+            SqlValue::Integer(INPUT_MAX as i64),
+        ],
+    )?;
+    Ok(())
+}
+
+/// Journal rows matching `filter`, newest first.
+pub fn query(db: &SqliteDb, filter: &Filter) -> Result<Vec<Entry>, String> {
+    let result = db.query_params(
+        "SELECT id, ts, conversation, tool_use_id, tool, path, before_hash, after_hash, \
+                added, removed, coalesce(reason, '') \
+         FROM changes \
+         WHERE (?1 IS NULL OR substr(path, 1, length(?1)) = ?1) \
+           AND (?2 IS NULL OR substr(conversation, 1, length(?2)) = ?2) \
+         ORDER BY id DESC LIMIT ?3",
+        &[
+            filter.prefix.clone().map_or(SqlValue::Null, SqlValue::Text),
+            filter.conversation.clone().map_or(SqlValue::Null, SqlValue::Text),
+            SqlValue::Integer(filter.limit as i64),
+        ],
+    )?;
+    let text = |row: &[SqlValue], i: usize| String::from(row.get(i).and_then(SqlValue::as_str).unwrap_or(""));
+    let int = |row: &[SqlValue], i: usize| row.get(i).and_then(SqlValue::as_integer).unwrap_or(0);
+    Ok(result
+        .rows
+        .iter()
+        .map(|row| Entry {
+            id: int(row, 0),
+            ts: int(row, 1),
+            conversation: text(row, 2),
+            tool_use_id: text(row, 3),
+            tool: text(row, 4),
+            path: text(row, 5),
+            before_hash: row.get(6).and_then(SqlValue::as_str).map(String::from),
+            after_hash: text(row, 7),
+            added: int(row, 8),
+            removed: int(row, 9),
+            reason: text(row, 10),
+        })
+        .collect())
+}
+
+/// Lowercase hex SHA-256 of `text`.
+fn hash(text: &str) -> String {
+    let digest = crate::crypto::pin_verifier::sha256_hash(text.as_bytes());
+    let mut out = String::with_capacity(64);
+    for byte in digest {
+        out.push_str(&alloc::format!("{:02x}", byte));
+    }
+    out
+}
+
+fn truncate(s: &str, max: usize) -> &str {
+    if s.len() <= max {
+        return s;
+    }
+    let mut end = max;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
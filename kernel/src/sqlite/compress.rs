@@ -0,0 +1,377 @@
+/// Transparent compression of namespace file contents.
+///
+/// Every write through `acl::write_file` (and the `str_replace` tool) of
+/// at least `MIN_SIZE` bytes is compressed with LZ4 — the block format,
+/// implemented here since nothing else in the kernel needs it — and kept
+/// only if that saves space. Such a row has `compressed = 1` and stores
+/// a BLOB: the original length (u32 LE) followed by the LZ4 block.
+/// Readers select `unpack(content, compressed)` instead of `content`;
+/// every connection has these SQL functions:
+///
+///   unpack(content, compressed)          the original text (`content`
+///                                        unchanged when not compressed)
+///   unpacked_length(content, compressed) its length in bytes
+///
+/// Incremental blob I/O can't seek inside a compressed row, so Lua
+/// `read_range` and `lines` unpack the whole file instead. `du
+/// --compressed` shows stored against original sizes. Config
+/// `storage.compress off` stops compressing new writes; rows already
+/// compressed stay readable.
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ffi::{c_char, c_int, c_void};
+
+use super::ffi::{sqlite3, sqlite3_value, SQLITE_OK};
+use super::vector::sqlite3_context;
+use super::{SqliteDb, SqlValue, CONFIG_PREFIX};
+
+/// Contents shorter than this are stored as they are.
+pub const MIN_SIZE: usize = 4096;
+
+/// Config key; anything but `off` compresses.
+pub const CONFIG_KEY: &str = "storage.compress";
+
+/// Bytes of the original-length header in front of the LZ4 block.
+const HEADER_LEN: usize = 4;
+
+const MIN_MATCH: usize = 4;
+/// The last bytes of a block are always literals...
+const LAST_LITERALS: usize = 5;
+/// ...and no match starts this close to the end.
+const MF_LIMIT: usize = 12;
+const MAX_OFFSET: usize = 65535;
+const HASH_LOG: u32 = 12;
+
+const SQLITE_UTF8: c_int = 1;
+const SQLITE_DETERMINISTIC: c_int = 0x800;
+const SQLITE_INNOCUOUS: c_int = 0x200000;
+const SQLITE_TRANSIENT: isize = -1;
+
+type ScalarFn = unsafe extern "C" fn(*mut sqlite3_context, c_int, *mut *mut sqlite3_value);
+
+extern "C" {
+    fn sqlite3_create_function_v2(
+        db: *mut sqlite3,
+        zFunctionName: *const c_char,
+        nArg: c_int,
+        eTextRep: c_int,
+        pApp: *mut c_void,
+        xFunc: Option<ScalarFn>,
+        xStep: Option<ScalarFn>,
+        xFinal: Option<unsafe extern "C" fn(*mut sqlite3_context)>,
+        xDestroy: Option<unsafe extern "C" fn(*mut c_void)>,
+    ) -> c_int;
+    fn sqlite3_value_blob(value: *mut sqlite3_value) -> *const c_void;
+    fn sqlite3_value_bytes(value: *mut sqlite3_value) -> c_int;
+    fn sqlite3_value_int64(value: *mut sqlite3_value) -> i64;
+    fn sqlite3_result_value(ctx: *mut sqlite3_context, value: *mut sqlite3_value);
+    fn sqlite3_result_text(ctx: *mut sqlite3_context, text: *const c_char, n: c_int, xDel: isize);
+    fn sqlite3_result_int64(ctx: *mut sqlite3_context, value: i64);
+    fn sqlite3_result_error(ctx: *mut sqlite3_context, msg: *const c_char, n: c_int);
+}
+
+/// Content ready to bind: `value` is hex when `compressed`, to go through
+/// `unhex()` (SqlValue has no BLOB).
+pub struct Packed {
+    pub value: SqlValue,
+    pub compressed: bool,
+    /// Bytes the row will take, for quota checks.
+    pub stored_len: u64,
+}
+
+/// Register `unpack` and `unpacked_length` on a connection.
+pub(super) unsafe fn install(db: *mut sqlite3) -> Result<(), String> {
+    let functions: [(&[u8], ScalarFn); 2] =
+        [(b"unpack\0", sql_unpack), (b"unpacked_length\0", sql_unpacked_length)];
+    for (name, func) in functions {
+        let rc = unsafe {
+            sqlite3_create_function_v2(
+                db,
+                name.as_ptr() as *const c_char,
+                2,
+                SQLITE_UTF8 | SQLITE_DETERMINISTIC | SQLITE_INNOCUOUS,
+                core::ptr::null_mut(),
+                Some(func),
+                None,
+                None,
+                None,
+            )
+        };
+        if rc != SQLITE_OK {
+            let name = core::str::from_utf8(&name[..name.len() - 1]).unwrap_or("?");
+            return Err(alloc::format!("{}: sqlite3_create_function_v2 failed: {}", name, rc));
+        }
+    }
+    Ok(())
+}
+
+/// Add the `compressed` column to a namespace table created before it.
+pub(super) fn migrate(db: &SqliteDb) -> Result<(), String> {
+    if !has_column(db)? {
+        db.exec("ALTER TABLE namespace ADD COLUMN compressed INTEGER NOT NULL DEFAULT 0")?;
+    }
+    Ok(())
+}
+
+/// On a read-only mount of a database from before `migrate`, shadow
+/// `namespace` with a view that has the column, so readers' queries work.
+pub(super) fn legacy_view(db: &SqliteDb) -> Result<(), String> {
+    if !has_column(db)? {
+        db.exec("CREATE TEMP VIEW IF NOT EXISTS namespace AS SELECT *, 0 AS compressed FROM main.namespace")?;
+    }
+    Ok(())
+}
+
+fn has_column(db: &SqliteDb) -> Result<bool, String> {
+    Ok(db
+        .query_value("SELECT count(*) FROM pragma_table_info('namespace', 'main') WHERE name = 'compressed'")?
+        .as_deref()
+        == Some("1"))
+}
+
+/// Is compression on? Read through `db`, since writers hold the lock.
+pub fn enabled(db: &SqliteDb) -> bool {
+    let configured = db
+        .query_params(
+            "SELECT content FROM namespace WHERE path = ? AND type = 'config'",
+            &[SqlValue::Text(alloc::format!("{}{}", CONFIG_PREFIX, CONFIG_KEY))],
+        )
+        .ok()
+        .and_then(|r| r.rows.into_iter().next())
+        .and_then(|row| row.into_iter().next());
+    !matches!(configured, Some(SqlValue::Text(v)) if v.trim() == "off")
+}
+
+/// What to store for `content`.
+pub fn pack(db: &SqliteDb, content: &str) -> Packed {
+    let plain = || Packed {
+        value: SqlValue::Text(String::from(content)),
+        compressed: false,
+        stored_len: content.len() as u64,
+    };
+    if content.len() < MIN_SIZE || content.len() > u32::MAX as usize || !enabled(db) {
+        return plain();
+    }
+    let packed = encode(content.as_bytes());
+    if packed.len() >= content.len() {
+        return plain();
+    }
+    Packed {
+        value: SqlValue::Text(hex(&packed)),
+        compressed: true,
+        stored_len: packed.len() as u64,
+    }
+}
+
+/// Stored form: original length, then the LZ4 block.
+pub fn encode(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(HEADER_LEN + data.len() / 2);
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    compress_block(data, &mut out);
+    out
+}
+
+/// Inverse of `encode`.
+pub fn decode(stored: &[u8]) -> Result<Vec<u8>, String> {
+    let Some((header, block)) = stored.split_first_chunk::<HEADER_LEN>() else {
+        return Err(String::from("compressed content: missing header"));
+    };
+    decompress_block(block, u32::from_le_bytes(*header) as usize)
+}
+
+/// Original length of a stored form, from its header.
+pub fn original_len(stored: &[u8]) -> Option<u64> {
+    stored.first_chunk::<HEADER_LEN>().map(|h| u64::from(u32::from_le_bytes(*h)))
+}
+
+/// LZ4-compress `input` as one block, appended to `out`. Greedy matching
+/// over a hash of the next four bytes.
+pub fn compress_block(input: &[u8], out: &mut Vec<u8>) {
+    let mut table = vec![0u32; 1 << HASH_LOG]; // position + 1, 0 = empty
+    let mut anchor = 0;
+    let mut i = 0;
+    if input.len() > MF_LIMIT {
+        let match_limit = input.len() - MF_LIMIT;
+        let end_limit = input.len() - LAST_LITERALS;
+        while i < match_limit {
+            let seq = read_u32(input, i);
+            let slot = &mut table[hash(seq)];
+            let candidate = *slot as usize;
+            *slot = (i + 1) as u32;
+            if candidate == 0 || i - (candidate - 1) > MAX_OFFSET || read_u32(input, candidate - 1) != seq {
+                i += 1;
+                continue;
+            }
+            let from = candidate - 1;
+            let mut len = MIN_MATCH;
+            while i + len < end_limit && input[from + len] == input[i + len] {
+                len += 1;
+            }
+            push_sequence(out, &input[anchor..i], Some((i - from, len)));
+            i += len;
+            anchor = i;
+        }
+    }
+    push_sequence(out, &input[anchor..], None);
+}
+
+/// Decompress one LZ4 block that must come to exactly `original` bytes.
+pub fn decompress_block(input: &[u8], original: usize) -> Result<Vec<u8>, String> {
+    let corrupt = |what: &str| Err(alloc::format!("compressed content: {}", what));
+    // No sequence expands more than 255-fold; don't trust a bigger header
+    if original > input.len().saturating_mul(256) {
+        return corrupt("length header too large");
+    }
+    let mut out = Vec::with_capacity(original);
+    let mut i = 0;
+    loop {
+        let Some(&token) = input.get(i) else { return corrupt("truncated") };
+        i += 1;
+        let mut literals = usize::from(token >> 4);
+        if literals == 15 {
+            literals += read_length(input, &mut i)?;
+        }
+        let Some(bytes) = i.checked_add(literals).and_then(|end| input.get(i..end)) else {
+            return corrupt("truncated literals");
+        };
+        if out.len() + literals > original {
+            return corrupt("longer than its header says");
+        }
+        out.extend_from_slice(bytes);
+        i += literals;
+        if i == input.len() {
+            break;
+        }
+
+        let Some(&[lo, hi]) = input.get(i..i + 2) else { return corrupt("truncated offset") };
+        i += 2;
+        let offset = usize::from(u16::from_le_bytes([lo, hi]));
+        if offset == 0 || offset > out.len() {
+            return corrupt("bad match offset");
+        }
+        let mut len = usize::from(token & 0x0f);
+        if len == 15 {
+            len += read_length(input, &mut i)?;
+        }
+        len += MIN_MATCH;
+        if out.len() + len > original {
+            return corrupt("longer than its header says");
+        }
+        // Byte by byte: a match may overlap the bytes it produces
+        let start = out.len() - offset;
+        for k in 0..len {
+            let b = out[start + k];
+            out.push(b);
+        }
+    }
+    if out.len() != original {
+        return corrupt("shorter than its header says");
+    }
+    Ok(out)
+}
+
+fn push_sequence(out: &mut Vec<u8>, literals: &[u8], matched: Option<(usize, usize)>) {
+    let match_code = matched.map_or(0, |(_, len)| len - MIN_MATCH);
+    let token = ((literals.len().min(15) as u8) << 4) | match_code.min(15) as u8;
+    out.push(token);
+    if literals.len() >= 15 {
+        push_length(out, literals.len() - 15);
+    }
+    out.extend_from_slice(literals);
+    if let Some((offset, _)) = matched {
+        out.extend_from_slice(&(offset as u16).to_le_bytes());
+        if match_code >= 15 {
+            push_length(out, match_code - 15);
+        }
+    }
+}
+
+fn push_length(out: &mut Vec<u8>, mut n: usize) {
+    while n >= 255 {
+        out.push(255);
+        n -= 255;
+    }
+    out.push(n as u8);
+}
+
+fn read_length(input: &[u8], i: &mut usize) -> Result<usize, String> {
+    let mut n = 0usize;
+    loop {
+        let Some(&b) = input.get(*i) else { return Err(String::from("compressed content: truncated length")) };
+        *i += 1;
+        n = n.saturating_add(usize::from(b));
+        if b != 255 {
+            return Ok(n);
+        }
+    }
+}
+
+fn read_u32(input: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([input[at], input[at + 1], input[at + 2], input[at + 3]])
+}
+
+fn hash(seq: u32) -> usize {
+    (seq.wrapping_mul(2654435761) >> (32 - HASH_LOG)) as usize
+}
+
+fn hex(bytes: &[u8]) -> String {
+    const DIGITS: &[u8; 16] = b"0123456789ABCDEF";
+    let mut out = String::with_capacity(bytes.len() * 2);
+    for &b in bytes {
+        out.push(DIGITS[usize::from(b >> 4)] as char);
+        out.push(DIGITS[usize::from(b & 0x0f)] as char);
+    }
+    out
+}
+
+/// The stored bytes of argument `i` (text or blob).
+unsafe fn value_bytes<'a>(argv: *mut *mut sqlite3_value, i: usize) -> &'a [u8] {
+    unsafe {
+        let value = *argv.add(i);
+        let ptr = sqlite3_value_blob(value) as *const u8;
+        let len = sqlite3_value_bytes(value);
+        if ptr.is_null() || len <= 0 {
+            &[]
+        } else {
+            core::slice::from_raw_parts(ptr, len as usize)
+        }
+    }
+}
+
+unsafe extern "C" fn sql_unpack(ctx: *mut sqlite3_context, argc: c_int, argv: *mut *mut sqlite3_value) {
+    unsafe {
+        if argc != 2 {
+            return;
+        }
+        if sqlite3_value_int64(*argv.add(1)) == 0 {
+            sqlite3_result_value(ctx, *argv);
+            return;
+        }
+        match decode(value_bytes(argv, 0)) {
+            Ok(data) if data.len() <= c_int::MAX as usize => {
+                sqlite3_result_text(ctx, data.as_ptr() as *const c_char, data.len() as c_int, SQLITE_TRANSIENT);
+            }
+            Ok(_) => {
+                let msg = "unpack: content too large";
+                sqlite3_result_error(ctx, msg.as_ptr() as *const c_char, msg.len() as c_int);
+            }
+            Err(e) => sqlite3_result_error(ctx, e.as_ptr() as *const c_char, e.len() as c_int),
+        }
+    }
+}
+
+unsafe extern "C" fn sql_unpacked_length(ctx: *mut sqlite3_context, argc: c_int, argv: *mut *mut sqlite3_value) {
+    unsafe {
+        if argc != 2 {
+            return;
+        }
+        let stored = value_bytes(argv, 0);
+        let len = if sqlite3_value_int64(*argv.add(1)) == 0 {
+            stored.len() as u64
+        } else {
+            original_len(stored).unwrap_or(0)
+        };
+        sqlite3_result_int64(ctx, len as i64);
+    }
+}
@@ -0,0 +1,211 @@
+/// Change notifications for the `namespace` table.
+///
+/// Built on SQLite's pre-update hook (the variant of the update hook that
+/// can see the row being changed), so deletes still carry their path.
+/// Changes are staged per transaction and only published when the commit
+/// hook fires; a rollback discards them.
+///
+/// Recording is off until something subscribes (`set_listening`), so
+/// ordinary writes pay a single atomic load. The published queue is
+/// bounded: if nobody drains it, the oldest changes are dropped.
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ffi::{c_char, c_int, c_void, CStr};
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+
+use super::ffi::{sqlite3, sqlite3_value};
+
+/// Maximum published-but-undelivered changes kept.
+const MAX_QUEUED: usize = 1024;
+
+// Pre-update hook opcodes
+const SQLITE_DELETE: c_int = 9;
+const SQLITE_INSERT: c_int = 18;
+const SQLITE_UPDATE: c_int = 23;
+
+extern "C" {
+    fn sqlite3_preupdate_hook(
+        db: *mut sqlite3,
+        xPreUpdate: Option<
+            unsafe extern "C" fn(*mut c_void, *mut sqlite3, c_int, *const c_char, *const c_char, i64, i64),
+        >,
+        pArg: *mut c_void,
+    ) -> *mut c_void;
+    fn sqlite3_preupdate_old(db: *mut sqlite3, iCol: c_int, ppValue: *mut *mut sqlite3_value) -> c_int;
+    fn sqlite3_preupdate_new(db: *mut sqlite3, iCol: c_int, ppValue: *mut *mut sqlite3_value) -> c_int;
+    fn sqlite3_commit_hook(
+        db: *mut sqlite3,
+        xCallback: Option<unsafe extern "C" fn(*mut c_void) -> c_int>,
+        pArg: *mut c_void,
+    ) -> *mut c_void;
+    fn sqlite3_rollback_hook(
+        db: *mut sqlite3,
+        xCallback: Option<unsafe extern "C" fn(*mut c_void)>,
+        pArg: *mut c_void,
+    ) -> *mut c_void;
+    fn sqlite3_value_text(value: *mut sqlite3_value) -> *const c_char;
+}
+
+/// What happened to a namespace path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeOp {
+    Insert,
+    Update,
+    Delete,
+}
+
+impl fmt::Display for ChangeOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChangeOp::Insert => write!(f, "insert"),
+            ChangeOp::Update => write!(f, "update"),
+            ChangeOp::Delete => write!(f, "delete"),
+        }
+    }
+}
+
+/// One committed change to a namespace row.
+#[derive(Debug, Clone)]
+pub struct Change {
+    pub op: ChangeOp,
+    pub path: String,
+}
+
+static LISTENING: AtomicBool = AtomicBool::new(false);
+static READY: AtomicBool = AtomicBool::new(false);
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/// Changes made by the open transaction.
+static STAGED: Mutex<Vec<Change>> = Mutex::new(Vec::new());
+/// Committed changes waiting to be drained.
+static PUBLISHED: Mutex<VecDeque<Change>> = Mutex::new(VecDeque::new());
+
+/// Install the hooks on a read-write connection.
+pub(super) unsafe fn install(db: *mut sqlite3) {
+    unsafe {
+        sqlite3_preupdate_hook(db, Some(preupdate_hook), core::ptr::null_mut());
+        sqlite3_commit_hook(db, Some(commit_hook), core::ptr::null_mut());
+        sqlite3_rollback_hook(db, Some(rollback_hook), core::ptr::null_mut());
+    }
+}
+
+/// Start or stop recording changes. Stopping discards anything queued.
+pub fn set_listening(on: bool) {
+    LISTENING.store(on, Ordering::Release);
+    if !on {
+        STAGED.lock().clear();
+        PUBLISHED.lock().clear();
+        READY.store(false, Ordering::Release);
+    }
+}
+
+/// Cheap check for published changes (or a re-armed wakeup).
+pub fn pending() -> bool {
+    READY.load(Ordering::Acquire)
+}
+
+/// Request another delivery pass even if no new changes arrive.
+pub fn rearm() {
+    READY.store(true, Ordering::Release);
+}
+
+/// Take every published change, oldest first.
+pub fn drain() -> Vec<Change> {
+    READY.store(false, Ordering::Release);
+    PUBLISHED.lock().drain(..).collect()
+}
+
+/// Changes discarded because the queue was full.
+pub fn dropped() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// Read column 0 (`path`) from the old or new image of the row.
+unsafe fn row_path(db: *mut sqlite3, new: bool) -> Option<String> {
+    let mut value: *mut sqlite3_value = core::ptr::null_mut();
+    let rc = unsafe {
+        if new {
+            sqlite3_preupdate_new(db, 0, &mut value)
+        } else {
+            sqlite3_preupdate_old(db, 0, &mut value)
+        }
+    };
+    if rc != super::ffi::SQLITE_OK || value.is_null() {
+        return None;
+    }
+    let text = unsafe { sqlite3_value_text(value) };
+    if text.is_null() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(unsafe { CStr::from_ptr(text) }.to_bytes()).into_owned())
+}
+
+unsafe extern "C" fn preupdate_hook(
+    _arg: *mut c_void,
+    db: *mut sqlite3,
+    op: c_int,
+    z_db: *const c_char,
+    z_table: *const c_char,
+    _old_rowid: i64,
+    _new_rowid: i64,
+) {
+    if !LISTENING.load(Ordering::Acquire) {
+        return;
+    }
+    let (db_name, table) = unsafe { (CStr::from_ptr(z_db), CStr::from_ptr(z_table)) };
+    if db_name.to_bytes() != b"main" || table.to_bytes() != b"namespace" {
+        return;
+    }
+
+    let mut staged = STAGED.lock();
+    match op {
+        SQLITE_INSERT => {
+            if let Some(path) = unsafe { row_path(db, true) } {
+                staged.push(Change { op: ChangeOp::Insert, path });
+            }
+        }
+        SQLITE_DELETE => {
+            if let Some(path) = unsafe { row_path(db, false) } {
+                staged.push(Change { op: ChangeOp::Delete, path });
+            }
+        }
+        SQLITE_UPDATE => {
+            let old = unsafe { row_path(db, false) };
+            let new = unsafe { row_path(db, true) };
+            match (old, new) {
+                // A rename looks like the old path vanishing and a new one appearing
+                (Some(old), Some(new)) if old != new => {
+                    staged.push(Change { op: ChangeOp::Delete, path: old });
+                    staged.push(Change { op: ChangeOp::Insert, path: new });
+                }
+                (_, Some(path)) => staged.push(Change { op: ChangeOp::Update, path }),
+                _ => {}
+            }
+        }
+        _ => {}
+    }
+}
+
+unsafe extern "C" fn commit_hook(_arg: *mut c_void) -> c_int {
+    let mut staged = STAGED.lock();
+    if staged.is_empty() {
+        return 0;
+    }
+    let mut published = PUBLISHED.lock();
+    for change in staged.drain(..) {
+        if published.len() >= MAX_QUEUED {
+            published.pop_front();
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+        published.push_back(change);
+    }
+    READY.store(true, Ordering::Release);
+    0 // let the commit proceed
+}
+
+unsafe extern "C" fn rollback_hook(_arg: *mut c_void) {
+    STAGED.lock().clear();
+}
@@ -6,6 +6,8 @@ use core::ffi::{c_char, c_int, c_void, CStr};
 use alloc::string::String;
 use alloc::vec::Vec;
 
+use super::format::{Cell, OutputMode, RowFormatter};
+
 // ---- SQLite return codes ----
 
 pub const SQLITE_OK: c_int = 0;
@@ -24,6 +26,16 @@ pub struct sqlite3_stmt {
     _opaque: [u8; 0],
 }
 
+#[repr(C)]
+pub struct sqlite3_value {
+    _opaque: [u8; 0],
+}
+
+#[repr(C)]
+pub struct sqlite3_blob {
+    _opaque: [u8; 0],
+}
+
 #[repr(C)]
 pub struct sqlite3_vfs {
     _opaque: [u8; 0],
@@ -60,6 +72,27 @@ extern "C" {
 
     pub fn sqlite3_errmsg(db: *mut sqlite3) -> *const c_char;
 
+    pub fn sqlite3_stmt_readonly(stmt: *mut sqlite3_stmt) -> c_int;
+
+    pub fn sqlite3_complete(sql: *const c_char) -> c_int;
+
+    pub fn sqlite3_progress_handler(
+        db: *mut sqlite3,
+        nOps: c_int,
+        xProgress: Option<unsafe extern "C" fn(*mut c_void) -> c_int>,
+        pArg: *mut c_void,
+    );
+
+    pub fn sqlite3_set_authorizer(
+        db: *mut sqlite3,
+        xAuth: Option<
+            unsafe extern "C" fn(*mut c_void, c_int, *const c_char, *const c_char, *const c_char, *const c_char) -> c_int,
+        >,
+        pUserData: *mut c_void,
+    ) -> c_int;
+
+    pub fn sqlite3_malloc(n: c_int) -> *mut c_void;
+
     pub fn sqlite3_free(ptr: *mut c_void);
 
     pub fn sqlite3_prepare_v2(
@@ -87,12 +120,60 @@ extern "C" {
     pub fn sqlite3_column_bytes(stmt: *mut sqlite3_stmt, iCol: c_int) -> c_int;
 
     pub fn sqlite3_finalize(stmt: *mut sqlite3_stmt) -> c_int;
+
+    pub fn sqlite3_bind_parameter_count(stmt: *mut sqlite3_stmt) -> c_int;
+
+    pub fn sqlite3_bind_null(stmt: *mut sqlite3_stmt, idx: c_int) -> c_int;
+
+    pub fn sqlite3_bind_int64(stmt: *mut sqlite3_stmt, idx: c_int, val: i64) -> c_int;
+
+    pub fn sqlite3_bind_double(stmt: *mut sqlite3_stmt, idx: c_int, val: f64) -> c_int;
+
+    /// `xDel` is a destructor pointer; we always pass SQLITE_TRANSIENT.
+    pub fn sqlite3_bind_text(
+        stmt: *mut sqlite3_stmt,
+        idx: c_int,
+        text: *const c_char,
+        nByte: c_int,
+        xDel: isize,
+    ) -> c_int;
+
+    pub fn sqlite3_blob_open(
+        db: *mut sqlite3,
+        zDb: *const c_char,
+        zTable: *const c_char,
+        zColumn: *const c_char,
+        iRow: i64,
+        flags: c_int,
+        ppBlob: *mut *mut sqlite3_blob,
+    ) -> c_int;
+
+    pub fn sqlite3_blob_bytes(blob: *mut sqlite3_blob) -> c_int;
+
+    pub fn sqlite3_blob_read(blob: *mut sqlite3_blob, z: *mut c_void, n: c_int, iOffset: c_int) -> c_int;
+
+    pub fn sqlite3_blob_close(blob: *mut sqlite3_blob) -> c_int;
 }
 
+/// Destructor sentinel telling SQLite to copy bound data immediately.
+const SQLITE_TRANSIENT: isize = -1;
+
 // Open flags
+const SQLITE_OPEN_READONLY: c_int = 0x00000001;
 const SQLITE_OPEN_READWRITE: c_int = 0x00000002;
 const SQLITE_OPEN_CREATE: c_int = 0x00000004;
 
+/// VM instructions between progress-handler calls.
+const PROGRESS_OPS: c_int = 1000;
+
+// Authorizer action and answer
+const SQLITE_READ: c_int = 20;
+const SQLITE_DENY: c_int = 1;
+
+/// Columns `without_content` keeps from a statement: file content, and
+/// the chunks of it kept for semantic search.
+const CONTENT_COLUMNS: &[(&str, &str)] = &[("namespace", "content"), ("embeddings", "chunk")];
+
 // Column types
 pub const SQLITE_INTEGER: c_int = 1;
 pub const SQLITE_FLOAT: c_int = 2;
@@ -109,6 +190,17 @@ unsafe impl Send for SqliteDb {}
 impl SqliteDb {
     /// Open a database file using our "heaven" VFS.
     pub fn open(name: &str) -> Result<Self, String> {
+        let conn = Self::open_with_flags(name, SQLITE_OPEN_READWRITE | SQLITE_OPEN_CREATE)?;
+        unsafe { super::events::install(conn.db); }
+        Ok(conn)
+    }
+
+    /// Open an existing database file read-only (no journal, no writes).
+    pub fn open_readonly(name: &str) -> Result<Self, String> {
+        Self::open_with_flags(name, SQLITE_OPEN_READONLY)
+    }
+
+    fn open_with_flags(name: &str, flags: c_int) -> Result<Self, String> {
         let mut db: *mut sqlite3 = core::ptr::null_mut();
 
         // Null-terminated filename
@@ -123,7 +215,7 @@ impl SqliteDb {
             sqlite3_open_v2(
                 name_buf.as_ptr() as *const c_char,
                 &mut db,
-                SQLITE_OPEN_READWRITE | SQLITE_OPEN_CREATE,
+                flags,
                 vfs_name.as_ptr() as *const c_char,
             )
         };
@@ -140,9 +232,24 @@ impl SqliteDb {
             return Err(msg);
         }
 
+        // Another connection may hold the lock; retry for a while before failing
+        unsafe { super::writer::install(db); }
+
+        // SQL functions available on every connection
+        let installed = unsafe {
+            super::vector::install(db)
+                .and_then(|()| super::compress::install(db))
+                .and_then(|()| super::geo::install(db))
+        };
+        if let Err(e) = installed {
+            unsafe { sqlite3_close(db); }
+            return Err(e);
+        }
+
         Ok(Self { db })
     }
 
+
     /// Execute a SQL statement (no results expected).
     pub fn exec(&self, sql: &str) -> Result<(), String> {
         let mut sql_buf = Vec::with_capacity(sql.len() + 1);
@@ -177,80 +284,149 @@ impl SqliteDb {
 
     /// Execute a SQL statement and return formatted results.
     pub fn exec_with_results(&self, sql: &str) -> Result<String, String> {
-        let mut sql_buf = Vec::with_capacity(sql.len() + 1);
-        sql_buf.extend_from_slice(sql.as_bytes());
-        sql_buf.push(0);
+        self.run_formatted(self.prepare(sql)?)
+    }
 
-        let mut stmt: *mut sqlite3_stmt = core::ptr::null_mut();
+    /// `exec_with_results` for a statement already prepared.
+    pub fn run_formatted(&self, stmt: Statement<'_>) -> Result<String, String> {
+        let mut output = String::new();
+        self.run_streamed(stmt, OutputMode::List, None, None, |line, _| {
+            output.push_str(line);
+            output.push('\n');
+            true
+        })?;
+        Ok(output)
+    }
 
-        let rc = unsafe {
-            sqlite3_prepare_v2(
-                self.db,
-                sql_buf.as_ptr() as *const c_char,
-                sql_buf.len() as c_int,
-                &mut stmt,
-                core::ptr::null_mut(),
-            )
-        };
+    /// Execute a SQL statement, handing each formatted line to `on_line`
+    /// along with whether it is a row (as opposed to a header, footer or
+    /// the "OK" shown for DDL/DML). Stops early when `on_line` returns
+    /// false or after `limit` rows. Returns the number of rows produced.
+    ///
+    /// `abort` is polled from SQLite's progress handler while the
+    /// statement runs; returning true interrupts it ("interrupted").
+    pub fn exec_streamed(
+        &self,
+        sql: &str,
+        mode: OutputMode,
+        limit: Option<u64>,
+        abort: Option<fn() -> bool>,
+        on_line: impl FnMut(&str, bool) -> bool,
+    ) -> Result<u64, String> {
+        self.run_streamed(self.prepare(sql)?, mode, limit, abort, on_line)
+    }
 
-        if rc != SQLITE_OK {
-            return Err(unsafe { errmsg_string(self.db) });
+    /// `exec_streamed` for a statement already prepared.
+    pub fn run_streamed(
+        &self,
+        stmt: Statement<'_>,
+        mode: OutputMode,
+        limit: Option<u64>,
+        abort: Option<fn() -> bool>,
+        mut on_line: impl FnMut(&str, bool) -> bool,
+    ) -> Result<u64, String> {
+        if stmt.stmt.is_null() {
+            on_line("OK", false); // empty or comment-only
+            return Ok(0);
         }
 
+        if let Some(check) = abort {
+            unsafe {
+                sqlite3_progress_handler(self.db, PROGRESS_OPS, Some(progress_abort), check as *mut c_void);
+            }
+        }
+        let result = self.stream_rows(stmt.stmt, mode, limit, &mut on_line);
+        if abort.is_some() {
+            unsafe { sqlite3_progress_handler(self.db, 0, None, core::ptr::null_mut()); }
+        }
+        result
+    }
+
+    /// Run `f` with reads of `CONTENT_COLUMNS` refused: a statement that
+    /// touches one, directly or through a view, fails to prepare with
+    /// "access to namespace.content is prohibited". For SQL written by an
+    /// agent, whose file reads go through the namespace ACL instead.
+    pub fn without_content<R>(&self, f: impl FnOnce(&Self) -> R) -> R {
+        unsafe { sqlite3_set_authorizer(self.db, Some(deny_content), core::ptr::null_mut()); }
+        let result = f(self);
+        unsafe { sqlite3_set_authorizer(self.db, None, core::ptr::null_mut()); }
+        result
+    }
+
+    fn stream_rows(
+        &self,
+        stmt: *mut sqlite3_stmt,
+        mode: OutputMode,
+        limit: Option<u64>,
+        on_line: &mut impl FnMut(&str, bool) -> bool,
+    ) -> Result<u64, String> {
         let ncols = unsafe { sqlite3_column_count(stmt) };
-        let mut output = String::new();
+        let columns = (0..ncols)
+            .map(|i| {
+                let name = unsafe { sqlite3_column_name(stmt, i) };
+                if name.is_null() { String::new() } else { unsafe { cstr_to_string(name) } }
+            })
+            .collect();
+        let mut formatter = RowFormatter::new(mode, columns);
 
-        // Print column headers
         if ncols > 0 {
-            for i in 0..ncols {
-                if i > 0 {
-                    output.push('|');
-                }
-                let name = unsafe { sqlite3_column_name(stmt, i) };
-                if !name.is_null() {
-                    output.push_str(&unsafe { cstr_to_string(name) });
+            if let Some(header) = formatter.header() {
+                if !on_line(&header, false) {
+                    return Ok(0);
                 }
             }
-            output.push('\n');
         }
 
-        // Print rows
-        loop {
+        let mut rows = 0u64;
+        let mut values: Vec<(c_int, String)> = Vec::with_capacity(ncols as usize);
+        while limit.is_none_or(|max| rows < max) {
             let step_rc = unsafe { sqlite3_step(stmt) };
             if step_rc == SQLITE_DONE {
                 break;
             }
             if step_rc != SQLITE_ROW {
-                let msg = unsafe { errmsg_string(self.db) };
-                unsafe { sqlite3_finalize(stmt); }
-                return Err(msg);
+                let error = unsafe { errmsg_string(self.db) };
+                // Close what the rows opened (the JSON array) before failing
+                if rows > 0 {
+                    if let Some(footer) = formatter.footer() {
+                        on_line(footer, false);
+                    }
+                }
+                return Err(error);
             }
 
+            values.clear();
             for i in 0..ncols {
-                if i > 0 {
-                    output.push('|');
-                }
                 let col_type = unsafe { sqlite3_column_type(stmt, i) };
-                if col_type == SQLITE_NULL {
-                    output.push_str("NULL");
+                let text = unsafe { sqlite3_column_text(stmt, i) };
+                let text = if col_type == SQLITE_NULL || text.is_null() {
+                    String::new()
                 } else {
-                    let text = unsafe { sqlite3_column_text(stmt, i) };
-                    if !text.is_null() {
-                        output.push_str(&unsafe { cstr_to_string(text) });
-                    }
-                }
+                    unsafe { cstr_to_string(text) }
+                };
+                values.push((col_type, text));
+            }
+            let cells: Vec<Cell<'_>> = values
+                .iter()
+                .map(|(col_type, text)| match *col_type {
+                    SQLITE_NULL => Cell::Null,
+                    SQLITE_INTEGER | SQLITE_FLOAT => Cell::Number(text),
+                    _ => Cell::Text(text),
+                })
+                .collect();
+            let line = formatter.row(&cells);
+            rows += 1;
+            if !on_line(&line, true) {
+                break;
             }
-            output.push('\n');
         }
 
-        unsafe { sqlite3_finalize(stmt); }
-
-        // If no columns (DDL/DML), just show OK
         if ncols == 0 {
-            output.push_str("OK\n");
+            on_line("OK", false);
+        } else if let Some(footer) = formatter.footer() {
+            on_line(footer, false);
         }
-
-        Ok(output)
+        Ok(rows)
     }
 }
 
@@ -293,6 +469,22 @@ impl SqliteDb {
     /// Unlike exec_with_results(), this handles values containing | and \n
     /// correctly because it reads column values directly via sqlite3_column_*.
     pub fn query(&self, sql: &str) -> Result<QueryResult, String> {
+        self.query_params(sql, &[])
+    }
+
+    /// Execute a query with `?` placeholders bound to `params` in order.
+    ///
+    /// Values are bound by type, never spliced into the SQL text, so this is
+    /// the safe way to pass untrusted strings. The number of params must
+    /// match the number of placeholders.
+    pub fn query_params(&self, sql: &str, params: &[SqlValue]) -> Result<QueryResult, String> {
+        let mut stmt = self.prepare(sql)?;
+        stmt.bind_all(params)?;
+        stmt.collect()
+    }
+
+    /// Compile a single SQL statement.
+    pub fn prepare(&self, sql: &str) -> Result<Statement<'_>, String> {
         let mut sql_buf = Vec::with_capacity(sql.len() + 1);
         sql_buf.extend_from_slice(sql.as_bytes());
         sql_buf.push(0);
@@ -313,6 +505,194 @@ impl SqliteDb {
             return Err(unsafe { errmsg_string(self.db) });
         }
 
+        Ok(Statement { db: self, stmt })
+    }
+
+    /// Execute a query and return the first column of the first row as a String.
+    ///
+    /// Returns Ok(None) if no rows are returned.
+    pub fn query_value(&self, sql: &str) -> Result<Option<String>, String> {
+        let result = self.query(sql)?;
+        if let Some(row) = result.rows.first() {
+            if let Some(val) = row.first() {
+                return Ok(match val {
+                    SqlValue::Null => None,
+                    SqlValue::Integer(n) => Some(alloc::format!("{}", n)),
+                    SqlValue::Real(n) => Some(alloc::format!("{}", n)),
+                    SqlValue::Text(s) => Some(s.clone()),
+                });
+            }
+        }
+        Ok(None)
+    }
+
+    /// Execute a query and return the first column of all rows as strings.
+    pub fn query_column(&self, sql: &str) -> Result<Vec<String>, String> {
+        let result = self.query(sql)?;
+        let mut out = Vec::with_capacity(result.rows.len());
+        for row in &result.rows {
+            if let Some(val) = row.first() {
+                match val {
+                    SqlValue::Null => {}
+                    SqlValue::Integer(n) => out.push(alloc::format!("{}", n)),
+                    SqlValue::Real(n) => out.push(alloc::format!("{}", n)),
+                    SqlValue::Text(s) => out.push(s.clone()),
+                }
+            }
+        }
+        Ok(out)
+    }
+}
+
+impl SqliteDb {
+    /// Open one cell of `table.column` (in `main`) for incremental reading.
+    ///
+    /// Works for TEXT as well as BLOB values. Lets callers walk a large
+    /// value in pieces instead of materializing it.
+    pub fn open_blob(&self, table: &str, column: &str, rowid: i64) -> Result<Blob<'_>, String> {
+        let mut table_buf = Vec::with_capacity(table.len() + 1);
+        table_buf.extend_from_slice(table.as_bytes());
+        table_buf.push(0);
+        let mut column_buf = Vec::with_capacity(column.len() + 1);
+        column_buf.extend_from_slice(column.as_bytes());
+        column_buf.push(0);
+
+        let mut blob: *mut sqlite3_blob = core::ptr::null_mut();
+        let rc = unsafe {
+            sqlite3_blob_open(
+                self.db,
+                c"main".as_ptr(),
+                table_buf.as_ptr() as *const c_char,
+                column_buf.as_ptr() as *const c_char,
+                rowid,
+                0, // read-only
+                &mut blob,
+            )
+        };
+        if rc != SQLITE_OK {
+            let msg = unsafe { errmsg_string(self.db) };
+            if !blob.is_null() {
+                unsafe { sqlite3_blob_close(blob); }
+            }
+            return Err(msg);
+        }
+        Ok(Blob { db: self, blob })
+    }
+}
+
+/// A read-only incremental blob handle, closed on drop.
+pub struct Blob<'db> {
+    db: &'db SqliteDb,
+    blob: *mut sqlite3_blob,
+}
+
+impl Blob<'_> {
+    /// Size of the value in bytes.
+    pub fn len(&self) -> usize {
+        unsafe { sqlite3_blob_bytes(self.blob) as usize }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Read up to `buf.len()` bytes starting at `offset`, clamped to the end
+    /// of the value. Returns the number of bytes read (0 at or past the end).
+    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize, String> {
+        let total = self.len();
+        if offset >= total {
+            return Ok(0);
+        }
+        let n = buf.len().min(total - offset);
+        let rc = unsafe {
+            sqlite3_blob_read(self.blob, buf.as_mut_ptr() as *mut c_void, n as c_int, offset as c_int)
+        };
+        if rc != SQLITE_OK {
+            return Err(unsafe { errmsg_string(self.db.db) });
+        }
+        Ok(n)
+    }
+}
+
+impl Drop for Blob<'_> {
+    fn drop(&mut self) {
+        unsafe { sqlite3_blob_close(self.blob); }
+    }
+}
+
+/// A prepared statement, finalized on drop.
+///
+/// An empty statement (only whitespace or comments) has a null handle;
+/// it binds nothing and yields no rows, as sqlite3_step would.
+pub struct Statement<'db> {
+    db: &'db SqliteDb,
+    stmt: *mut sqlite3_stmt,
+}
+
+impl Statement<'_> {
+    /// Is this a query that returns rows without changing anything?
+    /// BEGIN/COMMIT count as read-only to SQLite but return no rows, so
+    /// they stay on the connection that issued them.
+    pub fn is_read_only_query(&self) -> bool {
+        if self.stmt.is_null() {
+            return false; // empty or comment-only
+        }
+        unsafe { sqlite3_stmt_readonly(self.stmt) != 0 && sqlite3_column_count(self.stmt) > 0 }
+    }
+
+    /// Number of `?` placeholders in the statement.
+    pub fn parameter_count(&self) -> usize {
+        if self.stmt.is_null() {
+            return 0;
+        }
+        unsafe { sqlite3_bind_parameter_count(self.stmt) as usize }
+    }
+
+    /// Bind one value to placeholder `idx` (1-based).
+    pub fn bind(&mut self, idx: usize, value: &SqlValue) -> Result<(), String> {
+        let i = idx as c_int;
+        let rc = unsafe {
+            match value {
+                SqlValue::Null => sqlite3_bind_null(self.stmt, i),
+                SqlValue::Integer(n) => sqlite3_bind_int64(self.stmt, i, *n),
+                SqlValue::Real(n) => sqlite3_bind_double(self.stmt, i, *n),
+                SqlValue::Text(s) => sqlite3_bind_text(
+                    self.stmt,
+                    i,
+                    s.as_ptr() as *const c_char,
+                    s.len() as c_int,
+                    SQLITE_TRANSIENT,
+                ),
+            }
+        };
+        if rc != SQLITE_OK {
+            return Err(unsafe { errmsg_string(self.db.db) });
+        }
+        Ok(())
+    }
+
+    /// Bind every placeholder in order, checking the count matches.
+    pub fn bind_all(&mut self, params: &[SqlValue]) -> Result<(), String> {
+        let expected = self.parameter_count();
+        if params.len() != expected {
+            return Err(alloc::format!(
+                "expected {} bind parameter(s), got {}",
+                expected,
+                params.len()
+            ));
+        }
+        for (i, value) in params.iter().enumerate() {
+            self.bind(i + 1, value)?;
+        }
+        Ok(())
+    }
+
+    /// Run the statement to completion and gather its rows.
+    pub fn collect(self) -> Result<QueryResult, String> {
+        if self.stmt.is_null() {
+            return Ok(QueryResult { columns: Vec::new(), rows: Vec::new() });
+        }
+        let stmt = self.stmt;
         let ncols = unsafe { sqlite3_column_count(stmt) };
 
         // Read column names
@@ -334,9 +714,7 @@ impl SqliteDb {
                 break;
             }
             if step_rc != SQLITE_ROW {
-                let msg = unsafe { errmsg_string(self.db) };
-                unsafe { sqlite3_finalize(stmt); }
-                return Err(msg);
+                return Err(unsafe { errmsg_string(self.db.db) });
             }
 
             let mut row = Vec::with_capacity(ncols as usize);
@@ -365,44 +743,15 @@ impl SqliteDb {
             rows.push(row);
         }
 
-        unsafe { sqlite3_finalize(stmt); }
-
         Ok(QueryResult { columns, rows })
     }
+}
 
-    /// Execute a query and return the first column of the first row as a String.
-    ///
-    /// Returns Ok(None) if no rows are returned.
-    pub fn query_value(&self, sql: &str) -> Result<Option<String>, String> {
-        let result = self.query(sql)?;
-        if let Some(row) = result.rows.first() {
-            if let Some(val) = row.first() {
-                return Ok(match val {
-                    SqlValue::Null => None,
-                    SqlValue::Integer(n) => Some(alloc::format!("{}", n)),
-                    SqlValue::Real(n) => Some(alloc::format!("{}", n)),
-                    SqlValue::Text(s) => Some(s.clone()),
-                });
-            }
-        }
-        Ok(None)
-    }
-
-    /// Execute a query and return the first column of all rows as strings.
-    pub fn query_column(&self, sql: &str) -> Result<Vec<String>, String> {
-        let result = self.query(sql)?;
-        let mut out = Vec::with_capacity(result.rows.len());
-        for row in &result.rows {
-            if let Some(val) = row.first() {
-                match val {
-                    SqlValue::Null => {}
-                    SqlValue::Integer(n) => out.push(alloc::format!("{}", n)),
-                    SqlValue::Real(n) => out.push(alloc::format!("{}", n)),
-                    SqlValue::Text(s) => out.push(s.clone()),
-                }
-            }
+impl Drop for Statement<'_> {
+    fn drop(&mut self) {
+        if !self.stmt.is_null() {
+            unsafe { sqlite3_finalize(self.stmt); }
         }
-        Ok(out)
     }
 }
 
@@ -414,6 +763,31 @@ impl Drop for SqliteDb {
     }
 }
 
+/// Progress handler for `exec_streamed`: `arg` is the abort check.
+unsafe extern "C" fn progress_abort(arg: *mut c_void) -> c_int {
+    let check: fn() -> bool = unsafe { core::mem::transmute(arg) };
+    check() as c_int // non-zero interrupts the statement
+}
+
+/// Authorizer for `without_content`: deny reads of `CONTENT_COLUMNS`.
+unsafe extern "C" fn deny_content(
+    _arg: *mut c_void,
+    action: c_int,
+    table: *const c_char,
+    column: *const c_char,
+    _db: *const c_char,
+    _trigger: *const c_char,
+) -> c_int {
+    if action != SQLITE_READ || table.is_null() || column.is_null() {
+        return SQLITE_OK;
+    }
+    let (table, column) = unsafe { (CStr::from_ptr(table).to_bytes(), CStr::from_ptr(column).to_bytes()) };
+    let hidden = CONTENT_COLUMNS
+        .iter()
+        .any(|(t, c)| t.as_bytes().eq_ignore_ascii_case(table) && c.as_bytes().eq_ignore_ascii_case(column));
+    if hidden { SQLITE_DENY } else { SQLITE_OK }
+}
+
 /// Convert a C string pointer to a Rust String.
 unsafe fn cstr_to_string(ptr: *const c_char) -> String {
     let cstr = unsafe { CStr::from_ptr(ptr) };
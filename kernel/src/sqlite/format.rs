@@ -0,0 +1,162 @@
+/// Result formatting for the `sql` command: list, CSV and JSON.
+///
+///   list  a|b|c       header line, `|` between columns, NULL shown as NULL
+///   csv   "a","b,c"   RFC 4180: fields with `,` `"` CR or LF are quoted,
+///                     quotes doubled; NULL is an empty field
+///   json  [{"a":1},   one object per row inside a single array,
+///          {"a":2}]   numbers unquoted, NULL as null
+///
+/// Each row is formatted on its own line so output can be paged.
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+/// How `exec_streamed` renders rows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputMode {
+    #[default]
+    List,
+    Csv,
+    Json,
+}
+
+impl OutputMode {
+    /// Parse a mode name ("list", "csv", "json").
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "list" => Some(OutputMode::List),
+            "csv" => Some(OutputMode::Csv),
+            "json" => Some(OutputMode::Json),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for OutputMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OutputMode::List => write!(f, "list"),
+            OutputMode::Csv => write!(f, "csv"),
+            OutputMode::Json => write!(f, "json"),
+        }
+    }
+}
+
+/// One column value as SQLite rendered it.
+pub enum Cell<'a> {
+    Null,
+    /// INTEGER or REAL, in SQLite's text form.
+    Number(&'a str),
+    /// TEXT or BLOB.
+    Text(&'a str),
+}
+
+/// Formats a result set line by line.
+pub struct RowFormatter {
+    mode: OutputMode,
+    columns: Vec<String>,
+    rows: u64,
+}
+
+impl RowFormatter {
+    pub fn new(mode: OutputMode, columns: Vec<String>) -> Self {
+        Self { mode, columns, rows: 0 }
+    }
+
+    /// The header line, if the mode has one.
+    pub fn header(&self) -> Option<String> {
+        match self.mode {
+            OutputMode::List => Some(self.columns.join("|")),
+            OutputMode::Csv => {
+                let mut line = String::new();
+                for (i, name) in self.columns.iter().enumerate() {
+                    if i > 0 {
+                        line.push(',');
+                    }
+                    push_csv_field(&mut line, name);
+                }
+                Some(line)
+            }
+            OutputMode::Json => None,
+        }
+    }
+
+    /// Format one row.
+    pub fn row(&mut self, cells: &[Cell<'_>]) -> String {
+        let mut line = String::new();
+        match self.mode {
+            OutputMode::List => {
+                for (i, cell) in cells.iter().enumerate() {
+                    if i > 0 {
+                        line.push('|');
+                    }
+                    match cell {
+                        Cell::Null => line.push_str("NULL"),
+                        Cell::Number(s) | Cell::Text(s) => line.push_str(s),
+                    }
+                }
+            }
+            OutputMode::Csv => {
+                for (i, cell) in cells.iter().enumerate() {
+                    if i > 0 {
+                        line.push(',');
+                    }
+                    match cell {
+                        Cell::Null => {}
+                        Cell::Number(s) => line.push_str(s),
+                        Cell::Text(s) => push_csv_field(&mut line, s),
+                    }
+                }
+            }
+            OutputMode::Json => {
+                line.push_str(if self.rows == 0 { "[{" } else { ",{" });
+                for (i, (name, cell)) in self.columns.iter().zip(cells).enumerate() {
+                    if i > 0 {
+                        line.push(',');
+                    }
+                    line.push('"');
+                    line.push_str(&crate::api::escape_json(name));
+                    line.push_str("\":");
+                    match cell {
+                        Cell::Null => line.push_str("null"),
+                        // SQLite writes infinities as "Inf"; JSON has no such number
+                        Cell::Number(s) if s.contains("Inf") || s.contains("NaN") => line.push_str("null"),
+                        Cell::Number(s) => line.push_str(s),
+                        Cell::Text(s) => {
+                            line.push('"');
+                            line.push_str(&crate::api::escape_json(s));
+                            line.push('"');
+                        }
+                    }
+                }
+                line.push('}');
+            }
+        }
+        self.rows += 1;
+        line
+    }
+
+    /// The closing line, if the mode has one.
+    pub fn footer(&self) -> Option<&'static str> {
+        match self.mode {
+            OutputMode::Json if self.rows == 0 => Some("[]"),
+            OutputMode::Json => Some("]"),
+            _ => None,
+        }
+    }
+}
+
+fn push_csv_field(line: &mut String, s: &str) {
+    if !s.contains([',', '"', '\r', '\n']) {
+        line.push_str(s);
+        return;
+    }
+    line.push('"');
+    for c in s.chars() {
+        if c == '"' {
+            line.push('"');
+        }
+        line.push(c);
+    }
+    line.push('"');
+}
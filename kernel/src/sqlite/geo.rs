@@ -0,0 +1,117 @@
+/// Bounding-box helpers for spatial data.
+///
+/// Every connection gets two scalar SQL functions, taking boxes in R-Tree
+/// column order (minX, maxX, minY, maxY):
+///
+///   bbox_contains(minX, maxX, minY, maxY, x, y)
+///       1 if the point is in the box (edges included), else 0
+///   bbox_overlaps(minX1, maxX1, minY1, maxY1, minX2, maxX2, minY2, maxY2)
+///       1 if the boxes share any point, else 0
+///
+/// Either is NULL if an argument is. To find rows through an R-Tree index
+/// (the `sqlite-rtree` feature, on by default) constrain its columns
+/// directly — `WHERE minX <= :x AND maxX >= :x AND ...` — as a function
+/// call is not an index constraint; the helpers are for tables without one
+/// and for comparing boxes already selected.
+use alloc::string::String;
+use core::ffi::{c_char, c_int, c_void};
+
+use super::ffi::{sqlite3, sqlite3_value, SQLITE_OK};
+use super::vector::sqlite3_context;
+
+const SQLITE_UTF8: c_int = 1;
+const SQLITE_DETERMINISTIC: c_int = 0x800;
+const SQLITE_INNOCUOUS: c_int = 0x200000;
+const SQLITE_NULL: c_int = 5;
+
+type ScalarFn = unsafe extern "C" fn(*mut sqlite3_context, c_int, *mut *mut sqlite3_value);
+
+extern "C" {
+    fn sqlite3_create_function_v2(
+        db: *mut sqlite3,
+        zFunctionName: *const c_char,
+        nArg: c_int,
+        eTextRep: c_int,
+        pApp: *mut c_void,
+        xFunc: Option<ScalarFn>,
+        xStep: Option<ScalarFn>,
+        xFinal: Option<unsafe extern "C" fn(*mut sqlite3_context)>,
+        xDestroy: Option<unsafe extern "C" fn(*mut c_void)>,
+    ) -> c_int;
+    fn sqlite3_value_type(value: *mut sqlite3_value) -> c_int;
+    fn sqlite3_value_double(value: *mut sqlite3_value) -> f64;
+    fn sqlite3_result_int(ctx: *mut sqlite3_context, value: c_int);
+    fn sqlite3_result_null(ctx: *mut sqlite3_context);
+}
+
+/// Register `bbox_contains` and `bbox_overlaps` on a connection.
+pub(super) unsafe fn install(db: *mut sqlite3) -> Result<(), String> {
+    let functions: [(&[u8], c_int, ScalarFn); 2] =
+        [(b"bbox_contains\0", 6, sql_bbox_contains), (b"bbox_overlaps\0", 8, sql_bbox_overlaps)];
+    for (name, args, func) in functions {
+        let rc = unsafe {
+            sqlite3_create_function_v2(
+                db,
+                name.as_ptr() as *const c_char,
+                args,
+                SQLITE_UTF8 | SQLITE_DETERMINISTIC | SQLITE_INNOCUOUS,
+                core::ptr::null_mut(),
+                Some(func),
+                None,
+                None,
+                None,
+            )
+        };
+        if rc != SQLITE_OK {
+            let name = core::str::from_utf8(&name[..name.len() - 1]).unwrap_or("?");
+            return Err(alloc::format!("{}: sqlite3_create_function_v2 failed: {}", name, rc));
+        }
+    }
+    Ok(())
+}
+
+/// A box, as (minX, maxX, minY, maxY).
+type BBox = [f64; 4];
+
+fn contains(b: BBox, x: f64, y: f64) -> bool {
+    b[0] <= x && x <= b[1] && b[2] <= y && y <= b[3]
+}
+
+fn overlaps(a: BBox, b: BBox) -> bool {
+    a[0] <= b[1] && b[0] <= a[1] && a[2] <= b[3] && b[2] <= a[3]
+}
+
+/// The `N` arguments as doubles, or None if any is NULL.
+unsafe fn doubles<const N: usize>(argc: c_int, argv: *mut *mut sqlite3_value) -> Option<[f64; N]> {
+    if argc as usize != N {
+        return None;
+    }
+    let mut out = [0.0; N];
+    for (i, v) in out.iter_mut().enumerate() {
+        let value = unsafe { *argv.add(i) };
+        if unsafe { sqlite3_value_type(value) } == SQLITE_NULL {
+            return None;
+        }
+        *v = unsafe { sqlite3_value_double(value) };
+    }
+    Some(out)
+}
+
+unsafe fn result(ctx: *mut sqlite3_context, answer: Option<bool>) {
+    match answer {
+        Some(yes) => unsafe { sqlite3_result_int(ctx, c_int::from(yes)) },
+        None => unsafe { sqlite3_result_null(ctx) },
+    }
+}
+
+unsafe extern "C" fn sql_bbox_contains(ctx: *mut sqlite3_context, argc: c_int, argv: *mut *mut sqlite3_value) {
+    let args = unsafe { doubles::<6>(argc, argv) };
+    let answer = args.map(|a| contains([a[0], a[1], a[2], a[3]], a[4], a[5]));
+    unsafe { result(ctx, answer) };
+}
+
+unsafe extern "C" fn sql_bbox_overlaps(ctx: *mut sqlite3_context, argc: c_int, argv: *mut *mut sqlite3_value) {
+    let args = unsafe { doubles::<8>(argc, argv) };
+    let answer = args.map(|a| overlaps([a[0], a[1], a[2], a[3]], [a[4], a[5], a[6], a[7]]));
+    unsafe { result(ctx, answer) };
+}
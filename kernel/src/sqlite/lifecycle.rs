@@ -0,0 +1,214 @@
+/// System lifecycle log: the `events` table.
+///
+/// Boots, shutdowns, panics, storage formats, repairs and encryption
+/// changes, config changes and the outcome of the boot autorun agent are
+/// recorded here, apart from the agents' `audit` table, so
+/// "what happened to this machine" can be answered after the fact.
+///
+/// Several of these happen before the database is open (a format, a
+/// superblock repair) or while it can't be written (a degraded mount), so
+/// `record` never needs SQLite: events go to a small in-memory queue that
+/// is written out as soon as the writer connection is free and storage is
+/// writable — at the end of `sqlite::init` at the latest. A buffered event
+/// keeps its uptime; its wall-clock time is back-dated by its age when it
+/// is written. If more than `MAX_PENDING` wait, the oldest are dropped.
+///
+///   events(id, ts, uptime_ms, kind, detail)
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+use super::{SqliteDb, SqlValue, DB};
+
+/// Events held in memory at most while the database can't take them.
+const MAX_PENDING: usize = 64;
+
+/// What happened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Boot,
+    Shutdown,
+    Panic,
+    Format,
+    Repair,
+    Encrypt,
+    Config,
+    Autorun,
+}
+
+impl Kind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Kind::Boot => "boot",
+            Kind::Shutdown => "shutdown",
+            Kind::Panic => "panic",
+            Kind::Format => "format",
+            Kind::Repair => "repair",
+            Kind::Encrypt => "encrypt",
+            Kind::Config => "config",
+            Kind::Autorun => "autorun",
+        }
+    }
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// An event not yet in the table.
+#[derive(Debug, Clone)]
+pub struct Pending {
+    pub uptime_ms: u64,
+    pub kind: Kind,
+    pub detail: String,
+}
+
+/// A row of the table.
+#[derive(Debug, Clone)]
+pub struct Event {
+    pub id: i64,
+    /// `YYYY-MM-DD HH:MM:SS`, UTC.
+    pub time: String,
+    pub uptime_ms: i64,
+    pub kind: String,
+    pub detail: String,
+}
+
+static PENDING: Mutex<Vec<Pending>> = Mutex::new(Vec::new());
+
+/// Events dropped from a full queue since boot.
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/// Create the `events` table.
+pub(super) fn create_table(db: &SqliteDb) -> Result<(), String> {
+    db.exec(
+        "CREATE TABLE IF NOT EXISTS events (\
+            id        INTEGER PRIMARY KEY AUTOINCREMENT, \
+            ts        INTEGER DEFAULT (strftime('%s','now')), \
+            uptime_ms INTEGER NOT NULL, \
+            kind      TEXT NOT NULL, \
+            detail    TEXT NOT NULL DEFAULT ''\
+        )",
+    )
+}
+
+/// Record an event. Written at once if the database is free and writable,
+/// queued otherwise (so calling this with `DB` held is safe).
+pub fn record(kind: Kind, detail: &str) {
+    push(&mut PENDING.lock(), kind, detail);
+    flush();
+}
+
+/// Record a panic. Only tries locks, since the panicking code may hold
+/// them; the event is lost if it can't be queued, and stays queued if
+/// the NVMe driver is busy (the write would wait on it forever).
+pub fn record_panic(detail: &str) {
+    if let Some(mut pending) = PENDING.try_lock() {
+        push(&mut pending, Kind::Panic, detail);
+    }
+    if !crate::drivers::nvme::NVME.is_locked() {
+        flush();
+    }
+}
+
+/// Config change `key = value` (or `key` unset), with the value of a
+/// secret key withheld.
+pub fn record_config(key: &str, value: Option<&str>) {
+    let detail = match value {
+        None => alloc::format!("{} unset", key),
+        Some(_) if is_secret(key) => alloc::format!("{} = <redacted>", key),
+        Some(v) => alloc::format!("{} = {}", key, v),
+    };
+    record(Kind::Config, &detail);
+}
+
+/// Keys whose values are credentials.
+fn is_secret(key: &str) -> bool {
+    key.ends_with(".key") || key.contains("token") || key.contains("password") || key.contains("secret")
+}
+
+fn push(pending: &mut Vec<Pending>, kind: Kind, detail: &str) {
+    if pending.len() >= MAX_PENDING {
+        pending.remove(0);
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+    pending.push(Pending {
+        uptime_ms: crate::time::monotonic_ms(),
+        kind,
+        detail: String::from(detail),
+    });
+}
+
+/// Write queued events to the table, if the writer connection is free and
+/// storage is writable. Returns how many were written.
+pub fn flush() -> usize {
+    if !super::mount_mode().is_some_and(|m| m.is_writable()) {
+        return 0;
+    }
+    let Some(guard) = DB.try_lock() else { return 0 };
+    let Some(db) = guard.as_ref() else { return 0 };
+    let Some(mut pending) = PENDING.try_lock() else { return 0 };
+    let now = crate::time::monotonic_ms();
+    let mut written = 0;
+    for ev in pending.iter() {
+        let age_secs = (now.saturating_sub(ev.uptime_ms) / 1000) as i64;
+        let inserted = db.query_params(
+            "INSERT INTO events (ts, uptime_ms, kind, detail) \
+             VALUES (strftime('%s','now') - ?1, ?2, ?3, ?4)",
+            &[
+                SqlValue::Integer(age_secs),
+                SqlValue::Integer(ev.uptime_ms as i64),
+                SqlValue::Text(String::from(ev.kind.as_str())),
+                SqlValue::Text(ev.detail.clone()),
+            ],
+        );
+        if inserted.is_err() {
+            break;
+        }
+        written += 1;
+    }
+    pending.drain(..written);
+    written
+}
+
+/// Events still waiting to be written, oldest first.
+pub fn pending() -> Vec<Pending> {
+    PENDING.lock().clone()
+}
+
+/// Events dropped because the queue was full, since boot.
+pub fn dropped() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// The newest `limit` rows, oldest first; `kind` filters by kind.
+pub fn recent(limit: usize, kind: Option<&str>) -> Result<Vec<Event>, String> {
+    let guard = DB.lock();
+    let db = guard.as_ref().ok_or_else(|| String::from("database not open"))?;
+    let result = db.query_params(
+        "SELECT id, datetime(ts, 'unixepoch'), uptime_ms, kind, detail FROM (\
+            SELECT * FROM events WHERE ?1 IS NULL OR kind = ?1 ORDER BY id DESC LIMIT ?2\
+         ) ORDER BY id",
+        &[
+            kind.map_or(SqlValue::Null, |k| SqlValue::Text(String::from(k))),
+            SqlValue::Integer(limit as i64),
+        ],
+    )?;
+    let text = |v: &SqlValue| String::from(v.as_str().unwrap_or(""));
+    Ok(result
+        .rows
+        .iter()
+        .filter(|r| r.len() == 5)
+        .map(|r| Event {
+            id: r[0].as_integer().unwrap_or(0),
+            time: text(&r[1]),
+            uptime_ms: r[2].as_integer().unwrap_or(0),
+            kind: text(&r[3]),
+            detail: text(&r[4]),
+        })
+        .collect())
+}
@@ -4,21 +4,90 @@
 /// - Raw FFI bindings to the C SQLite library
 /// - A safe Rust wrapper for executing SQL
 /// - VFS registration that connects SQLite to NVMe via our VFS
+/// - One writer connection (`DB`) plus read-only connections for queries
+///   (`with_reader`), with a busy handler on all of them and a FIFO queue
+///   for the writer (`writer`)
+/// - The namespace table, with case-insensitive paths (`namespace`)
+/// - Change events for the namespace table (`events`)
+/// - Audit table retention and export (`audit`)
+/// - System lifecycle log: boots, shutdowns, panics, config changes (`lifecycle`)
+/// - Journal of agent tool writes (`changes`)
+/// - Opt-in cache of model responses for `ask()` (`cache`)
+/// - Embedding vectors and cosine-similarity search (`vector`)
+/// - Bounding-box SQL functions for R-Tree style data (`geo`)
+/// - Model requests queued until the network is back (`outbox`)
+/// - System prompts stored under /prompts/system/ (`prompts`)
+/// - Allow/deny/confirm policy for agent tool calls (`policy`)
+/// - Per-principal access control on namespace paths (`acl`)
+/// - Byte quotas on namespace subtrees (`quota`)
+/// - Transparent LZ4 compression of large namespace files (`compress`)
+/// - Namespace subtree archives for bulk export/import (`archive`)
+/// - list/CSV/JSON result formatting (`format`)
+/// - JSON helper views over audit and changes (`views`, `sqlite-json`)
 ///
 /// The VFS is registered at init time. After that, sqlite3_open_v2()
 /// with zVfs="heaven" opens the system database backed by NVMe blocks.
 mod ffi;
+mod format;
 mod vfs_bridge;
+#[cfg(feature = "sqlite-json")]
+mod views;
+pub mod acl;
+pub mod archive;
+pub mod audit;
+pub mod cache;
+pub mod changes;
+pub mod compress;
+pub mod events;
+pub mod geo;
+pub mod lifecycle;
+pub mod namespace;
+pub mod outbox;
+pub mod policy;
+pub mod prompts;
+pub mod quota;
+pub mod vector;
+pub mod writer;
 
 use alloc::string::String;
 use spin::Mutex;
 
+use crate::storage::MountMode;
 use crate::vfs::HeavenVfs;
 
-pub use ffi::{SqliteDb, SqlValue, QueryResult};
+pub use ffi::{Blob, SqliteDb, SqlValue, QueryResult, Statement};
+pub use format::OutputMode;
 
-/// Global SQLite database instance (opened once at boot).
-pub static DB: Mutex<Option<SqliteDb>> = Mutex::new(None);
+/// Global SQLite database instance (opened once at boot). This is the
+/// only connection that writes; callers queue for it in order.
+pub static DB: writer::Writer = writer::Writer::new();
+
+/// Number of read-only connections kept beside `DB`.
+const READER_COUNT: usize = 2;
+
+/// Read-only connections, so monitoring queries don't queue behind a long
+/// write on `DB`. The VFS locks keep them consistent with the writer.
+///
+/// Each query already reads one stable state: its read transaction holds a
+/// SHARED lock, so the writer cannot commit under it. There is no
+/// sqlite3_snapshot binding to pin a state across queries — snapshots are
+/// WAL read marks, and SQLite is built with SQLITE_OMIT_WAL (see
+/// maintenance.rs). A reader wanting several queries to agree runs them in
+/// one `BEGIN` ... `COMMIT` on the same connection.
+static READERS: [Mutex<Option<SqliteDb>>; READER_COUNT] = [const { Mutex::new(None) }; READER_COUNT];
+
+/// Name of the system database file in the file table.
+const DB_NAME: &str = "heaven.db";
+
+/// Namespace prefix for `config` keys (`/config/<key>`).
+const CONFIG_PREFIX: &str = "/config/";
+
+/// Config key for where temp tables and sorts go; see `apply_temp_store`.
+const TEMP_STORE_KEY: &str = "sqlite.temp_store";
+
+/// Config key for the bytes of a database file read through xFetch; see
+/// `apply_mmap_size`.
+const MMAP_SIZE_KEY: &str = "sqlite.mmap_size";
 
 extern "C" {
     fn heaven_configure_malloc() -> core::ffi::c_int;
@@ -31,6 +100,8 @@ extern "C" {
 /// SQLite I/O.
 ///
 /// Must be called after the VFS (block allocator + file table) is ready.
+/// On a read-only or degraded mount the database is opened read-only and
+/// the schema is not touched.
 pub fn init(vfs: &'static HeavenVfs) -> Result<(), String> {
     // 1. Configure our memory allocator (must happen BEFORE sqlite3_initialize)
     let rc = unsafe { heaven_configure_malloc() };
@@ -51,18 +122,28 @@ pub fn init(vfs: &'static HeavenVfs) -> Result<(), String> {
     vfs_bridge::register_vfs()?;
 
     // 5. Open the system database
-    let db = SqliteDb::open("heaven.db")?;
+    if vfs.is_read_only() {
+        let db = SqliteDb::open_readonly(DB_NAME)?;
+        apply_temp_store(&db)?;
+        apply_mmap_size(&db)?;
+        compress::legacy_view(&db)?;
+        *DB.lock() = Some(db);
+        open_readers();
+        return Ok(());
+    }
+    let db = SqliteDb::open(DB_NAME)?;
+
+    // Let maintenance hand free pages back to the VFS. Only takes effect on
+    // a fresh database (before the first table exists).
+    db.exec("PRAGMA auto_vacuum=INCREMENTAL")?;
 
     // 6. Create the namespace table if it doesn't exist
-    db.exec(
-        "CREATE TABLE IF NOT EXISTS namespace (\
-            path    TEXT PRIMARY KEY, \
-            type    TEXT NOT NULL CHECK(type IN ('data','lua','dir','config','ctl','log')), \
-            content BLOB, \
-            mode    INTEGER DEFAULT 420, \
-            mtime   INTEGER DEFAULT (strftime('%s','now'))\
-        )",
-    )?;
+    namespace::create_table(&db)?;
+    acl::migrate(&db)?;
+    compress::migrate(&db)?;
+    namespace::migrate(&db)?;
+    apply_temp_store(&db)?;
+    apply_mmap_size(&db)?;
 
     // 7. Create the audit table for Lua agent logging
     db.exec(
@@ -73,17 +154,553 @@ pub fn init(vfs: &'static HeavenVfs) -> Result<(), String> {
             agent   TEXT, \
             action  TEXT, \
             target  TEXT, \
-            detail  TEXT\
+            detail  TEXT, \
+            request_id TEXT\
         )",
     )?;
+    audit::migrate(&db)?;
+
+    // 8. Journal of agent tool writes
+    changes::create_table(&db)?;
+
+    // 9. Model response cache
+    cache::create_table(&db)?;
+
+    // 10. Embedding vectors for semantic search
+    vector::create_table(&db)?;
+
+    // 11. Requests waiting for the network
+    outbox::create_table(&db)?;
+
+    // 12. Default system prompts
+    prompts::seed(&db)?;
+
+    // 13. Agent tool policy
+    policy::create_table(&db)?;
+
+    // 14. JSON helper views over audit and changes
+    #[cfg(feature = "sqlite-json")]
+    views::create(&db)?;
+
+    // 15. System lifecycle log
+    lifecycle::create_table(&db)?;
 
     *DB.lock() = Some(db);
+    open_readers();
+
+    // Events recorded before the database was open
+    lifecycle::flush();
     Ok(())
 }
 
+/// Set `PRAGMA temp_store` from config `sqlite.temp_store`: `memory` (the
+/// default) or `file`. Read when a connection opens, so a change applies
+/// from the next boot. FILE temp files still stay in RAM (see vfs_bridge),
+/// just through the VFS instead of SQLite's own memory pager.
+fn apply_temp_store(db: &SqliteDb) -> Result<(), String> {
+    let configured = db
+        .query_value(&alloc::format!(
+            "SELECT content FROM namespace WHERE path='{}{}' AND type='config'",
+            CONFIG_PREFIX, TEMP_STORE_KEY
+        ))
+        .ok()
+        .flatten();
+    let mode = match configured.as_deref().map(str::trim) {
+        Some("file") => "FILE",
+        Some("memory") | None => "MEMORY",
+        Some(other) => {
+            crate::serial_println!("[sqlite] {}: unknown value {:?}, using memory", TEMP_STORE_KEY, other);
+            "MEMORY"
+        }
+    };
+    db.exec(&alloc::format!("PRAGMA temp_store={}", mode))
+}
+
+/// Set `PRAGMA mmap_size` from config `sqlite.mmap_size` (bytes, default
+/// 0 = off), from the next boot. Mapped pages are served from a RAM image
+/// of the file (see vfs_bridge), so this much heap per connection.
+fn apply_mmap_size(db: &SqliteDb) -> Result<(), String> {
+    let bytes = db
+        .query_value(&alloc::format!(
+            "SELECT content FROM namespace WHERE path='{}{}' AND type='config'",
+            CONFIG_PREFIX, MMAP_SIZE_KEY
+        ))
+        .ok()
+        .flatten()
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .unwrap_or(0);
+    if bytes == 0 {
+        return Ok(());
+    }
+    db.exec(&alloc::format!("PRAGMA mmap_size={}", bytes))
+}
+
+/// Open the read-only connections. Failure is not fatal: `with_reader`
+/// falls back to the writer.
+fn open_readers() {
+    for reader in READERS.iter() {
+        let opened = SqliteDb::open_readonly(DB_NAME)
+            .and_then(|db| {
+                apply_temp_store(&db)
+                    .and_then(|()| apply_mmap_size(&db))
+                    .and_then(|()| compress::legacy_view(&db))
+                    .map(|()| db)
+            });
+        match opened {
+            Ok(db) => *reader.lock() = Some(db),
+            Err(e) => crate::serial_println!("[sqlite] reader connection failed: {}", e),
+        }
+    }
+}
+
+/// Run `f` on an idle read-only connection, or on the writer if every
+/// reader is in use (or none could be opened).
+pub fn with_reader<R>(f: impl FnOnce(&SqliteDb) -> R) -> Result<R, String> {
+    for reader in READERS.iter() {
+        if let Some(guard) = reader.try_lock() {
+            if let Some(db) = guard.as_ref() {
+                return Ok(f(db));
+            }
+        }
+    }
+    let guard = DB.lock();
+    let db = guard.as_ref().ok_or_else(|| String::from("database not open"))?;
+    Ok(f(db))
+}
+
+/// Prepare `sql` and hand the statement to `f`: on a reader if it is a
+/// read-only query, otherwise on the writer. A read runs the statement
+/// the reader checked, so it is prepared once. With `hide_content` the
+/// statement can't read file content (see `SqliteDb::without_content`).
+///
+/// The outer error is a missing database, the inner one the statement's.
+pub fn with_connection_for<R, F>(sql: &str, hide_content: bool, f: F) -> Result<Result<R, String>, String>
+where
+    F: FnOnce(&SqliteDb, Statement<'_>) -> Result<R, String>,
+{
+    let read = with_reader(|db| {
+        if hide_content { db.without_content(|db| run_read_only(db, sql, f)) } else { run_read_only(db, sql, f) }
+    })?;
+    let f = match read {
+        Ok(result) => return Ok(result),
+        Err(f) => f,
+    };
+    let guard = DB.lock();
+    let db = guard.as_ref().ok_or_else(|| String::from("database not open"))?;
+    let write = |db: &SqliteDb| db.prepare(sql).and_then(|stmt| f(db, stmt));
+    Ok(if hide_content { db.without_content(write) } else { write(db) })
+}
+
+/// Run `f` on `sql` if it is a read-only query, or hand `f` back.
+fn run_read_only<R, F>(db: &SqliteDb, sql: &str, f: F) -> Result<Result<R, String>, F>
+where
+    F: FnOnce(&SqliteDb, Statement<'_>) -> Result<R, String>,
+{
+    match db.prepare(sql) {
+        Ok(stmt) if stmt.is_read_only_query() => Ok(f(db, stmt)),
+        // A statement that fails to prepare goes to the writer, which reports the error
+        _ => Err(f),
+    }
+}
+
+/// What the embedded SQLite is and how big the database is, for `db info`.
+pub struct Info {
+    pub version: String,
+    /// `PRAGMA compile_options`, without the `SQLITE_` prefix.
+    pub compile_options: alloc::vec::Vec<String>,
+    pub page_size: u64,
+    pub page_count: u64,
+    pub freelist_count: u64,
+    pub journal_mode: String,
+    /// `PRAGMA temp_store`: "default", "file" or "memory".
+    pub temp_store: String,
+    /// RAM temp files open in the VFS, and their bytes.
+    pub temp_files: usize,
+    pub temp_bytes: usize,
+    /// `PRAGMA mmap_size`, in bytes.
+    pub mmap_size: u64,
+    /// Database files mapped for xFetch across all connections, and bytes.
+    pub mapped_files: usize,
+    pub mapped_bytes: usize,
+}
+
+impl Info {
+    /// Was the build compiled with `option` (e.g. "ENABLE_FTS5")?
+    pub fn has(&self, option: &str) -> bool {
+        self.compile_options.iter().any(|o| o == option || o.starts_with(&alloc::format!("{}=", option)))
+    }
+
+    /// Optional modules this build includes (see the Cargo features).
+    pub fn modules(&self) -> alloc::vec::Vec<&'static str> {
+        let mut out = alloc::vec::Vec::new();
+        if !self.has("OMIT_JSON") {
+            out.push("json");
+        }
+        if self.has("ENABLE_FTS5") {
+            out.push("fts5");
+        }
+        if self.has("ENABLE_RTREE") {
+            out.push("rtree");
+        }
+        if self.has("ENABLE_DBSTAT_VTAB") {
+            out.push("dbstat");
+        }
+        out
+    }
+}
+
+/// Build and size facts about the system database.
+pub fn info() -> Result<Info, String> {
+    with_reader(|db| {
+        let number = |sql| -> Result<u64, String> {
+            Ok(db.query_value(sql)?.and_then(|v| v.parse().ok()).unwrap_or(0))
+        };
+        let (temp_files, temp_bytes) = vfs_bridge::temp_usage();
+        let (mapped_files, mapped_bytes) = vfs_bridge::map_usage();
+        Ok(Info {
+            version: db.query_value("SELECT sqlite_version()")?.unwrap_or_default(),
+            compile_options: db.query_column("PRAGMA compile_options")?,
+            page_size: number("PRAGMA page_size")?,
+            page_count: number("PRAGMA page_count")?,
+            freelist_count: number("PRAGMA freelist_count")?,
+            journal_mode: db.query_value("PRAGMA journal_mode")?.unwrap_or_default(),
+            temp_store: String::from(match number("PRAGMA temp_store")? {
+                1 => "file",
+                2 => "memory",
+                _ => "default",
+            }),
+            temp_files,
+            temp_bytes,
+            mmap_size: number("PRAGMA mmap_size")?,
+            mapped_files,
+            mapped_bytes,
+        })
+    })?
+}
+
+/// A table or index of the system database, for `db stats`.
+pub struct ObjectStats {
+    pub name: String,
+    /// "table" or "index".
+    pub kind: String,
+    /// The table an index belongs to (the table itself for a table).
+    pub table: String,
+    /// Rows, for tables (virtual tables are not counted).
+    pub rows: Option<u64>,
+    /// Pages and bytes on disk, when the dbstat table is compiled in.
+    pub pages: Option<u64>,
+    pub bytes: Option<u64>,
+    /// `sqlite_stat1` for an index, from the last ANALYZE: rows, then
+    /// average rows per distinct key prefix. SQLite keeps no usage counts.
+    pub stat: Option<String>,
+}
+
+/// A namespace file, for the largest-files list of `db stats`.
+pub struct FileSize {
+    pub path: String,
+    /// Bytes in the row (after compression)...
+    pub stored: u64,
+    /// ...and once unpacked.
+    pub length: u64,
+}
+
+/// What is taking space in the system database.
+pub struct Stats {
+    /// Largest on disk first (by rows without dbstat).
+    pub objects: alloc::vec::Vec<ObjectStats>,
+    /// Was dbstat available to size the objects?
+    pub sized: bool,
+    pub largest: alloc::vec::Vec<FileSize>,
+}
+
+/// Namespace files listed by `db stats`.
+const LARGEST_FILES: usize = 10;
+
+/// Row counts, on-disk sizes and index statistics of every table and
+/// index, and the largest namespace files.
+pub fn stats() -> Result<Stats, String> {
+    with_reader(|db| {
+        let int = |v: &SqlValue| v.as_integer().map(|n| n.max(0) as u64);
+        let text = |v: &SqlValue| String::from(v.as_str().unwrap_or(""));
+
+        let schema = db.query(
+            "SELECT type, name, tbl_name, sql LIKE 'CREATE VIRTUAL%' FROM sqlite_schema \
+             WHERE type IN ('table', 'index') ORDER BY name",
+        )?;
+        let mut objects = alloc::vec::Vec::new();
+        for r in &schema.rows {
+            let (kind, name) = (text(&r[0]), text(&r[1]));
+            let rows = if kind == "table" && r[3].as_integer() != Some(1) {
+                let sql = alloc::format!("SELECT count(*) FROM \"{}\"", name.replace('"', "\"\""));
+                db.query_value(&sql)?.and_then(|v| v.parse().ok())
+            } else {
+                None
+            };
+            objects.push(ObjectStats { kind, name, table: text(&r[2]), rows, pages: None, bytes: None, stat: None });
+        }
+
+        // dbstat is compiled in (sqlite_config.h), but a build may drop it
+        let sized = match db.query("SELECT name, count(*), sum(pgsize) FROM dbstat GROUP BY name") {
+            Ok(sizes) => {
+                for r in &sizes.rows {
+                    let name = text(&r[0]);
+                    if let Some(o) = objects.iter_mut().find(|o| o.name == name) {
+                        o.pages = int(&r[1]);
+                        o.bytes = int(&r[2]);
+                    }
+                }
+                true
+            }
+            Err(_) => false,
+        };
+
+        // sqlite_stat1 only exists once ANALYZE has run
+        if let Ok(stat1) = db.query("SELECT idx, stat FROM sqlite_stat1 WHERE idx IS NOT NULL") {
+            for r in &stat1.rows {
+                let name = text(&r[0]);
+                if let Some(o) = objects.iter_mut().find(|o| o.name == name) {
+                    o.stat = Some(text(&r[1]));
+                }
+            }
+        }
+
+        if sized {
+            objects.sort_by_key(|o| core::cmp::Reverse(o.bytes));
+        } else {
+            objects.sort_by_key(|o| core::cmp::Reverse(o.rows));
+        }
+
+        let largest = db
+            .query(&alloc::format!(
+                "SELECT path, length(CAST(content AS BLOB)), unpacked_length(content, compressed) \
+                 FROM namespace WHERE content IS NOT NULL ORDER BY 2 DESC LIMIT {}",
+                LARGEST_FILES
+            ))?
+            .rows
+            .iter()
+            .map(|r| FileSize {
+                path: text(&r[0]),
+                stored: int(&r[1]).unwrap_or(0),
+                length: int(&r[2]).unwrap_or(0),
+            })
+            .collect();
+
+        Ok(Stats { objects, sized, largest })
+    })?
+}
+
 /// Execute a SQL statement and return results as formatted text.
+/// Read-only statements run on a reader connection.
 pub fn exec_and_format(sql: &str) -> Result<String, String> {
+    with_connection_for(sql, false, |db, stmt| db.run_formatted(stmt))?
+}
+
+/// `exec_and_format` for SQL written by an agent: file content can't be
+/// read (see `SqliteDb::without_content`).
+pub fn exec_and_format_without_content(sql: &str) -> Result<String, String> {
+    with_connection_for(sql, true, |db, stmt| db.run_formatted(stmt))?
+}
+
+/// Stream a statement's formatted lines to `on_line`, on a reader when it
+/// is a read-only query. See `SqliteDb::exec_streamed`.
+pub fn exec_streamed(
+    sql: &str,
+    mode: OutputMode,
+    limit: Option<u64>,
+    abort: Option<fn() -> bool>,
+    on_line: impl FnMut(&str, bool) -> bool,
+) -> Result<u64, String> {
+    with_connection_for(sql, false, |db, stmt| db.run_streamed(stmt, mode, limit, abort, on_line))?
+}
+
+/// Does `sql` end with a complete statement (terminating `;` outside any
+/// string, comment or trigger body)?
+pub fn is_complete(sql: &str) -> bool {
+    let mut buf = alloc::vec::Vec::with_capacity(sql.len() + 1);
+    buf.extend_from_slice(sql.as_bytes());
+    buf.push(0);
+    unsafe { ffi::sqlite3_complete(buf.as_ptr() as *const core::ffi::c_char) != 0 }
+}
+
+/// Switch the storage mount mode and reopen the database to match.
+///
+/// The connections are closed before the VFS mode changes so no
+/// half-written transaction straddles the switch; the readers are opened
+/// again afterwards, whether or not the switch took.
+pub fn set_mount_mode(mode: MountMode) -> Result<(), String> {
+    let vfs = vfs_bridge::vfs_instance().ok_or_else(|| String::from("VFS not initialized"))?;
+    for reader in READERS.iter() {
+        reader.lock().take();
+    }
+    let switched = switch_mount_mode(vfs, mode);
+    open_readers();
+    switched
+}
+
+/// Reopen the writer in `mode`, or back in the old mode if that fails.
+fn switch_mount_mode(vfs: &HeavenVfs, mode: MountMode) -> Result<(), String> {
+    let mut guard = DB.lock();
+    let previous = vfs.mode();
+    guard.take(); // Drop closes the connection
+    if let Err(e) = vfs.set_mode(mode) {
+        *guard = reopen(vfs).ok();
+        return Err(String::from(e));
+    }
+    match reopen(vfs) {
+        Ok(db) => {
+            *guard = Some(db);
+            Ok(())
+        }
+        Err(e) => {
+            let _ = vfs.set_mode(previous);
+            *guard = reopen(vfs).ok();
+            Err(e)
+        }
+    }
+}
+
+/// Close the system database and flush all storage metadata to the medium.
+///
+/// Journal mode is rollback (WAL is compiled out), so closing the
+/// connection is the checkpoint: SQLite finishes or rolls back any open
+/// transaction and deletes its journal. After this returns the database
+/// stays closed.
+pub fn shutdown() -> Result<(), String> {
+    let vfs = vfs_bridge::vfs_instance().ok_or_else(|| String::from("VFS not initialized"))?;
+    for reader in READERS.iter() {
+        reader.lock().take();
+    }
+    DB.lock().take(); // Drop closes the connection
+    vfs.flush_all().map_err(|e| alloc::format!("{}", e))
+}
+
+/// Enable or disable IOCAP reporting to SQLite (`storage.iocap`).
+/// Applies to the next sync decision; no reopen needed.
+pub fn set_device_caps(enabled: bool) {
+    if let Some(vfs) = vfs_bridge::vfs_instance() {
+        vfs.set_device_caps(enabled);
+    }
+}
+
+/// Set how many blocks the VFS reads ahead of sequential reads
+/// (`storage.readahead`).
+pub fn set_readahead(blocks: u16) {
+    if let Some(vfs) = vfs_bridge::vfs_instance() {
+        vfs.set_readahead(blocks);
+    }
+}
+
+/// Log xSyncs slower than `ms` milliseconds, 0 for never
+/// (`storage.sync_warn_ms`).
+pub fn set_sync_warn_ms(ms: u64) {
+    if let Some(vfs) = vfs_bridge::vfs_instance() {
+        vfs.set_sync_warn_ms(ms);
+    }
+}
+
+/// Run `f` on a free data block, by LBA (see `HeavenVfs::with_scratch_block`).
+pub fn with_scratch_block<R>(
+    f: impl FnOnce(&mut crate::drivers::nvme::NvmeDriver, u64) -> R,
+) -> Result<R, String> {
+    let vfs = vfs_bridge::vfs_instance().ok_or_else(|| String::from("VFS not initialized"))?;
+    vfs.with_scratch_block(f).map_err(|e| alloc::format!("{}", e))
+}
+
+/// The disk's encryption header (see `storage::crypt`).
+pub fn encryption() -> Result<crate::storage::crypt::CryptHeader, String> {
+    let vfs = vfs_bridge::vfs_instance().ok_or_else(|| String::from("VFS not initialized"))?;
+    vfs.encryption().map_err(|e| alloc::format!("{}", e))
+}
+
+/// Rewrite the whole device under `key` and store `header` (see
+/// `HeavenVfs::reencrypt`). `progress` gets (blocks done, total).
+pub fn reencrypt(
+    key: Option<crate::storage::crypt::Xts>,
+    header: &crate::storage::crypt::CryptHeader,
+    progress: impl Fn(u64, u64),
+) -> Result<(), String> {
+    let vfs = vfs_bridge::vfs_instance().ok_or_else(|| String::from("VFS not initialized"))?;
+    vfs.reencrypt(key, header, progress).map_err(|e| alloc::format!("{}", e))
+}
+
+/// Store a header wrapping the current disk key under a new passphrase.
+pub fn rewrap_key(header: &crate::storage::crypt::CryptHeader) -> Result<(), String> {
+    let vfs = vfs_bridge::vfs_instance().ok_or_else(|| String::from("VFS not initialized"))?;
+    vfs.rewrap(header).map_err(|e| alloc::format!("{}", e))
+}
+
+/// Current storage mount mode, if the VFS is up.
+pub fn mount_mode() -> Option<MountMode> {
+    vfs_bridge::vfs_instance().map(|vfs| vfs.mode())
+}
+
+fn reopen(vfs: &HeavenVfs) -> Result<SqliteDb, String> {
+    if vfs.is_read_only() {
+        SqliteDb::open_readonly(DB_NAME)
+    } else {
+        SqliteDb::open(DB_NAME)
+    }
+}
+
+// ---- Config keys (stored as namespace rows of type 'config') ----
+
+/// Read a config value from `/config/<key>`.
+pub fn config_get(key: &str) -> Option<String> {
+    let guard = DB.lock();
+    let db = guard.as_ref()?;
+    let query = alloc::format!(
+        "SELECT content FROM namespace WHERE path='{}{}' AND type='config'",
+        CONFIG_PREFIX,
+        key.replace('\'', "''")
+    );
+    db.query_value(&query).ok().flatten()
+}
+
+/// Write a config value to `/config/<key>` and log the change.
+pub fn config_set(key: &str, value: &str) -> Result<(), String> {
+    {
+        let guard = DB.lock();
+        let db = guard.as_ref().ok_or_else(|| String::from("database not open"))?;
+        let query = alloc::format!(
+            "INSERT OR REPLACE INTO namespace (path, type, content, mtime) \
+             VALUES ('{}{}', 'config', '{}', strftime('%s','now'))",
+            CONFIG_PREFIX,
+            key.replace('\'', "''"),
+            value.replace('\'', "''")
+        );
+        db.exec(&query)?;
+    }
+    lifecycle::record_config(key, Some(value));
+    Ok(())
+}
+
+/// Remove `/config/<key>` (no error if it isn't set) and log the change.
+pub fn config_delete(key: &str) -> Result<(), String> {
+    {
+        let guard = DB.lock();
+        let db = guard.as_ref().ok_or_else(|| String::from("database not open"))?;
+        let query = alloc::format!(
+            "DELETE FROM namespace WHERE path='{}{}' AND type='config'",
+            CONFIG_PREFIX,
+            key.replace('\'', "''")
+        );
+        db.exec(&query)?;
+    }
+    lifecycle::record_config(key, None);
+    Ok(())
+}
+
+/// All config rows as (key, value), sorted by key.
+pub fn config_list() -> Result<alloc::vec::Vec<(String, String)>, String> {
     let guard = DB.lock();
     let db = guard.as_ref().ok_or_else(|| String::from("database not open"))?;
-    db.exec_with_results(sql)
+    let result = db.query(
+        "SELECT substr(path, 9), content FROM namespace \
+         WHERE type='config' AND path LIKE '/config/%' ORDER BY path"
+    )?;
+    Ok(result.rows.iter().map(|row| {
+        let key = row.first().and_then(|v| v.as_str()).unwrap_or("");
+        let val = row.get(1).and_then(|v| v.as_str()).unwrap_or("");
+        (String::from(key), String::from(val))
+    }).collect())
 }
@@ -0,0 +1,156 @@
+/// The `namespace` table: every file, script and config key, by path.
+///
+/// Paths are case-insensitive and case-preserving: the `path` column is
+/// `COLLATE NOCASE`, so `/Agents/foo` and `/agents/foo` name the same row.
+/// Lookups, range scans and the primary key all compare that way, which
+/// covers every write path — an `INSERT OR REPLACE` under different case
+/// replaces the row (taking the new spelling), and a plain `INSERT` fails
+/// as a duplicate. SQLite's NOCASE folds ASCII only (there is no ICU in
+/// the kernel): `/Ä` and `/ä` remain two paths.
+///
+/// Expressions lose the column's collation, so a comparison on one —
+/// `substr(path, 1, n) = ?` for a subtree — must say `COLLATE NOCASE`
+/// itself. Code comparing paths in Rust uses `eq_ignore_ascii_case`.
+///
+/// Every path from outside — Lua builtins, agent tools, Styx walks, shell
+/// commands — goes through `canonicalize` before it is looked up, checked
+/// against the ACL or written, so `/tmp/../agents/x` can't reach a row as
+/// one path and pass the checks as another.
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use super::{SqliteDb, SqlValue};
+
+/// Longest canonical path, in bytes.
+pub const MAX_PATH_LEN: usize = 1024;
+
+/// Most components in a canonical path.
+pub const MAX_DEPTH: usize = 32;
+
+/// The canonical form of `path`: one leading `/`, no empty, `.` or `..`
+/// components and no trailing `/` (the root is `/`). A path without a
+/// leading `/` is taken from the root. Fails on a NUL byte, a `..` above
+/// the root, or a result over `MAX_PATH_LEN` bytes or `MAX_DEPTH` deep.
+pub fn canonicalize(path: &str) -> Result<String, String> {
+    if path.contains('\0') {
+        return Err(String::from("path contains a NUL byte"));
+    }
+    let mut parts: Vec<&str> = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                if parts.pop().is_none() {
+                    return Err(alloc::format!("{}: `..` above the root", path));
+                }
+            }
+            _ => parts.push(part),
+        }
+    }
+    if parts.len() > MAX_DEPTH {
+        return Err(alloc::format!("{}: deeper than {} levels", path, MAX_DEPTH));
+    }
+    let canonical = alloc::format!("/{}", parts.join("/"));
+    if canonical.len() > MAX_PATH_LEN {
+        return Err(alloc::format!("path longer than {} bytes", MAX_PATH_LEN));
+    }
+    Ok(canonical)
+}
+
+/// Does `path` start with `prefix`, compared as the namespace compares
+/// paths (ASCII case folded)?
+pub fn has_prefix(path: &str, prefix: &str) -> bool {
+    path.len() >= prefix.len() && path.as_bytes()[..prefix.len()].eq_ignore_ascii_case(prefix.as_bytes())
+}
+
+/// The columns, for `create_table` and the rebuild in `migrate`.
+const COLUMNS: &str = "\
+    path    TEXT PRIMARY KEY COLLATE NOCASE, \
+    type    TEXT NOT NULL CHECK(type IN ('data','lua','dir','config','ctl','log')), \
+    content BLOB, \
+    mode    INTEGER DEFAULT 420, \
+    mtime   INTEGER DEFAULT (strftime('%s','now')), \
+    owner   TEXT, \
+    compressed INTEGER NOT NULL DEFAULT 0";
+
+pub(super) fn create_table(db: &SqliteDb) -> Result<(), String> {
+    db.exec(&alloc::format!("CREATE TABLE IF NOT EXISTS namespace ({})", COLUMNS))
+}
+
+/// Rebuild a namespace table from before NOCASE paths. Rows that differ
+/// only in case can't all keep their path: the most recently modified
+/// keeps it, and each of the others is renamed `<path>~<rowid>` and
+/// reported. Runs after the column migrations, so every column exists.
+pub(super) fn migrate(db: &SqliteDb) -> Result<(), String> {
+    if is_nocase(db)? {
+        return Ok(());
+    }
+    db.exec("BEGIN IMMEDIATE")?;
+    match rebuild(db) {
+        Ok(renamed) => {
+            db.exec("COMMIT")?;
+            for (from, to) in &renamed {
+                crate::serial_println!("[sqlite] namespace: {} renamed {} (differs only in case)", from, to);
+            }
+            crate::serial_println!("[sqlite] namespace: paths are now case-insensitive");
+            Ok(())
+        }
+        Err(e) => {
+            let _ = db.exec("ROLLBACK");
+            Err(alloc::format!("namespace NOCASE migration: {}", e))
+        }
+    }
+}
+
+fn is_nocase(db: &SqliteDb) -> Result<bool, String> {
+    Ok(db
+        .query_value("SELECT coll FROM pragma_index_xinfo('sqlite_autoindex_namespace_1') WHERE cid = 0")?
+        .is_some_and(|c| c.eq_ignore_ascii_case("NOCASE")))
+}
+
+/// Rename case collisions, then copy everything into a NOCASE table.
+/// Views and triggers (which may name the table, and would stop the
+/// rename) are dropped first, and they and the table's own indexes are
+/// recreated from their saved SQL afterwards.
+fn rebuild(db: &SqliteDb) -> Result<Vec<(String, String)>, String> {
+    let losers = db.query(
+        "SELECT rowid, path FROM namespace AS n WHERE EXISTS \
+         (SELECT 1 FROM namespace AS m WHERE m.path = n.path COLLATE NOCASE AND m.rowid <> n.rowid \
+          AND (m.mtime > n.mtime OR (m.mtime IS n.mtime AND m.rowid > n.rowid)))",
+    )?;
+    let mut renamed = Vec::new();
+    for row in &losers.rows {
+        let (Some(rowid), Some(path)) = (row[0].as_integer(), row[1].as_str()) else {
+            continue;
+        };
+        let to = alloc::format!("{}~{}", path, rowid);
+        db.query_params(
+            "UPDATE namespace SET path = ? WHERE rowid = ?",
+            &[SqlValue::Text(to.clone()), SqlValue::Integer(rowid)],
+        )?;
+        renamed.push((String::from(path), to));
+    }
+    let dependents = db.query(
+        "SELECT type, name, sql FROM main.sqlite_schema WHERE sql IS NOT NULL \
+         AND (type IN ('view', 'trigger') OR (type = 'index' AND tbl_name = 'namespace')) \
+         ORDER BY CASE type WHEN 'index' THEN 0 WHEN 'view' THEN 1 ELSE 2 END, rowid",
+    )?;
+    for row in &dependents.rows {
+        if let (Some(kind @ ("view" | "trigger")), Some(name)) = (row[0].as_str(), row[1].as_str()) {
+            db.exec(&alloc::format!("DROP {} main.\"{}\"", kind.to_ascii_uppercase(), name.replace('"', "\"\"")))?;
+        }
+    }
+    db.exec(&alloc::format!("CREATE TABLE namespace_nocase ({})", COLUMNS))?;
+    db.exec(
+        "INSERT INTO namespace_nocase (rowid, path, type, content, mode, mtime, owner, compressed) \
+         SELECT rowid, path, type, content, mode, mtime, owner, compressed FROM namespace",
+    )?;
+    db.exec("DROP TABLE namespace")?;
+    db.exec("ALTER TABLE namespace_nocase RENAME TO namespace")?;
+    for row in &dependents.rows {
+        if let Some(sql) = row[2].as_str() {
+            db.exec(sql)?;
+        }
+    }
+    Ok(renamed)
+}
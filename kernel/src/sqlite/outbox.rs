@@ -0,0 +1,314 @@
+/// Outbox of model requests waiting for the network.
+///
+/// `ask -q`, Lua `ask_queued()` and — when the `ask.queue` config key is
+/// "on" — any ask whose DNS lookup or connection fails are stored here
+/// instead of being lost. The maintenance `outbox` task sends the oldest
+/// pending request whenever the console is idle; a network failure, a
+/// server error or a rate limit leaves it pending for a later pass, and
+/// any other failure marks it failed.
+///
+/// The outcome is written to the requester's mailbox, a namespace file
+///
+///   /mail/<agent>/<id>      the response text, or "error: ..." on failure
+///
+/// (`<agent>` is the Lua script path without its leading `/`, or `shell`),
+/// so a resident agent picks it up with `on_change("/mail/<agent>/", fn)`.
+///
+/// Requests queued for a batch (`ask -b`, `ask_queued{..., batch=true}`)
+/// are left to the maintenance `batches` task instead, which submits them
+/// together through the Message Batches API (`api::batch`) and sets their
+/// `batch_id`; they stay pending until the batch ends. One that expires
+/// unanswered goes back to waiting for the next batch. Requests are marked
+/// `SUBMITTING` before the submit goes out, so a failure to record the
+/// batch id afterwards can't send (and bill) them a second time.
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use super::acl::{self, Principal};
+use super::{SqliteDb, SqlValue};
+use crate::api::{json, messages_json, parse_messages, ContentBlock, Message};
+
+/// Namespace directory holding every mailbox.
+pub const MAIL_PREFIX: &str = "/mail/";
+
+/// `batch_id` of requests being submitted. Left behind only if the batch
+/// id couldn't be recorded: the batch may exist, so they are not sent
+/// again, and `outbox cancel` is left to decide.
+pub const SUBMITTING: &str = "submitting";
+
+/// A queued request.
+#[derive(Debug, Clone)]
+pub struct Item {
+    pub id: i64,
+    pub created: i64,
+    pub agent: String,
+    pub model: String,
+    pub system: Option<String>,
+    /// (role, text) pairs, oldest first: the text of each message, for
+    /// display. `api_messages` has the whole conversation.
+    pub messages: Vec<(String, String)>,
+    /// The conversation as stored (`api::messages_json`), tool_use and
+    /// tool_result blocks included.
+    pub encoded: String,
+    pub reply_to: String,
+    /// "pending", "done" or "failed".
+    pub status: String,
+    pub attempts: i64,
+    pub last_error: Option<String>,
+    /// Sent through the Message Batches API rather than on its own.
+    pub batch: bool,
+    /// The batch it was submitted in, while that batch runs.
+    pub batch_id: Option<String>,
+}
+
+impl Item {
+    /// The conversation as API messages, content blocks and all.
+    pub fn api_messages(&self) -> Vec<Message> {
+        json::parse(&self.encoded).map(|v| parse_messages(&v)).unwrap_or_default()
+    }
+}
+
+/// Create the `outbox` table.
+pub(super) fn create_table(db: &SqliteDb) -> Result<(), String> {
+    db.exec(
+        "CREATE TABLE IF NOT EXISTS outbox (\
+            id         INTEGER PRIMARY KEY AUTOINCREMENT, \
+            created    INTEGER DEFAULT (strftime('%s','now')), \
+            agent      TEXT NOT NULL, \
+            model      TEXT NOT NULL, \
+            system     TEXT, \
+            messages   TEXT NOT NULL, \
+            reply_to   TEXT NOT NULL DEFAULT '', \
+            status     TEXT NOT NULL DEFAULT 'pending' \
+                       CHECK(status IN ('pending','done','failed')), \
+            attempts   INTEGER NOT NULL DEFAULT 0, \
+            last_error TEXT, \
+            finished   INTEGER, \
+            batch      INTEGER NOT NULL DEFAULT 0, \
+            batch_id   TEXT\
+        )",
+    )?;
+    let columns = db.query_column("SELECT name FROM pragma_table_info('outbox')")?;
+    if !columns.iter().any(|c| c == "batch") {
+        db.exec("ALTER TABLE outbox ADD COLUMN batch INTEGER NOT NULL DEFAULT 0")?;
+        db.exec("ALTER TABLE outbox ADD COLUMN batch_id TEXT")?;
+    }
+    Ok(())
+}
+
+/// Is `ask.queue` on (queue asks that fail for lack of network)?
+pub fn queue_on_failure() -> bool {
+    super::config_get("ask.queue").is_some_and(|v| v.trim() == "on")
+}
+
+/// The mailbox directory of `agent` (ends in `/`).
+pub fn mailbox(agent: &str) -> String {
+    let name = agent.trim_start_matches('/').trim_matches(|c| c == '<' || c == '>');
+    alloc::format!("{}{}/", MAIL_PREFIX, if name.is_empty() { "shell" } else { name })
+}
+
+/// Queue a request, for a batch if `batch`. Returns its id and the path
+/// the reply will be written to.
+pub fn enqueue(
+    db: &SqliteDb,
+    agent: &str,
+    model: &str,
+    system: Option<&str>,
+    messages: &[Message],
+    batch: bool,
+) -> Result<(i64, String), String> {
+    let encoded = messages_json(messages);
+    db.query_params(
+        "INSERT INTO outbox (agent, model, system, messages, batch) VALUES (?, ?, ?, ?, ?)",
+        &[
+            SqlValue::Text(String::from(agent)),
+            SqlValue::Text(String::from(model)),
+            system.map_or(SqlValue::Null, |s| SqlValue::Text(String::from(s))),
+            SqlValue::Text(encoded),
+            SqlValue::Integer(batch as i64),
+        ],
+    )?;
+    let id: i64 = db
+        .query_value("SELECT last_insert_rowid()")?
+        .and_then(|v| v.parse().ok())
+        .ok_or_else(|| String::from("no row id"))?;
+    let reply_to = alloc::format!("{}{}", mailbox(agent), id);
+    db.query_params(
+        "UPDATE outbox SET reply_to = ? WHERE id = ?",
+        &[SqlValue::Text(reply_to.clone()), SqlValue::Integer(id)],
+    )?;
+    Ok((id, reply_to))
+}
+
+/// The oldest pending request sent on its own.
+pub fn next_pending(db: &SqliteDb) -> Result<Option<Item>, String> {
+    Ok(select(db, "WHERE status = 'pending' AND batch = 0 ORDER BY id LIMIT 1", &[])?.into_iter().next())
+}
+
+/// Up to `limit` pending batch requests not yet submitted, oldest first.
+pub fn unbatched(db: &SqliteDb, limit: usize) -> Result<Vec<Item>, String> {
+    select(
+        db,
+        "WHERE status = 'pending' AND batch = 1 AND batch_id IS NULL ORDER BY id LIMIT ?",
+        &[SqlValue::Integer(limit as i64)],
+    )
+}
+
+/// The batch with the oldest request still waiting on it.
+pub fn oldest_batch(db: &SqliteDb) -> Result<Option<String>, String> {
+    db.query_params(
+        "SELECT batch_id FROM outbox WHERE status = 'pending' AND batch_id IS NOT NULL AND batch_id <> ? \
+         ORDER BY id LIMIT 1",
+        &[SqlValue::Text(String::from(SUBMITTING))],
+    )
+    .map(|r| r.rows.first().and_then(|row| row.first()).and_then(SqlValue::as_str).map(String::from))
+}
+
+/// Pending requests waiting on batch `batch_id`.
+pub fn in_batch(db: &SqliteDb, batch_id: &str) -> Result<Vec<Item>, String> {
+    select(db, "WHERE status = 'pending' AND batch_id = ? ORDER BY id", &[SqlValue::Text(String::from(batch_id))])
+}
+
+/// Record that `ids` were submitted in batch `batch_id` (or are being:
+/// `SUBMITTING`), or with None put them back in line. One statement, so
+/// all of them change or none.
+pub fn set_batch(db: &SqliteDb, ids: &[i64], batch_id: Option<&str>) -> Result<(), String> {
+    if ids.is_empty() {
+        return Ok(());
+    }
+    let list: Vec<String> = ids.iter().map(|id| alloc::format!("{}", id)).collect();
+    db.query_params(
+        &alloc::format!("UPDATE outbox SET batch_id = ? WHERE id IN ({})", list.join(",")),
+        &[batch_id.map_or(SqlValue::Null, |b| SqlValue::Text(String::from(b)))],
+    )?;
+    Ok(())
+}
+
+/// Put a batch request back in line for the next batch, noting why.
+pub fn unbatch(db: &SqliteDb, id: i64, error: &str) -> Result<(), String> {
+    db.query_params(
+        "UPDATE outbox SET batch_id = NULL, attempts = attempts + 1, last_error = ? WHERE id = ?",
+        &[SqlValue::Text(String::from(error)), SqlValue::Integer(id)],
+    )?;
+    Ok(())
+}
+
+/// Every request, oldest first.
+pub fn list(db: &SqliteDb) -> Result<Vec<Item>, String> {
+    select(db, "ORDER BY id", &[])
+}
+
+/// Deliver `response` to the mailbox and mark the request done.
+pub fn complete(db: &SqliteDb, item: &Item, response: &str) -> Result<(), String> {
+    finish(db, item, "done", response, None)
+}
+
+/// Deliver the error to the mailbox and mark the request failed.
+pub fn fail(db: &SqliteDb, item: &Item, error: &str) -> Result<(), String> {
+    finish(db, item, "failed", &alloc::format!("error: {}\n", error), Some(error))
+}
+
+/// Note a failed attempt; the request stays pending.
+pub fn defer(db: &SqliteDb, id: i64, error: &str) -> Result<(), String> {
+    db.query_params(
+        "UPDATE outbox SET attempts = attempts + 1, last_error = ? WHERE id = ?",
+        &[SqlValue::Text(String::from(error)), SqlValue::Integer(id)],
+    )?;
+    Ok(())
+}
+
+/// Drop one request (any status). False if there was none.
+pub fn cancel(db: &SqliteDb, id: i64) -> Result<bool, String> {
+    db.query_params("DELETE FROM outbox WHERE id = ?", &[SqlValue::Integer(id)])?;
+    Ok(db.query_value("SELECT changes()")?.as_deref() != Some("0"))
+}
+
+/// Drop finished (done or failed) requests. Returns how many went.
+pub fn clear_finished(db: &SqliteDb) -> Result<u64, String> {
+    db.exec("DELETE FROM outbox WHERE status != 'pending'")?;
+    Ok(db.query_value("SELECT changes()")?.and_then(|v| v.parse().ok()).unwrap_or(0))
+}
+
+/// Pending requests.
+pub fn pending_count(db: &SqliteDb) -> Result<u64, String> {
+    Ok(db
+        .query_value("SELECT count(*) FROM outbox WHERE status = 'pending'")?
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0))
+}
+
+fn finish(db: &SqliteDb, item: &Item, status: &str, mail: &str, error: Option<&str>) -> Result<(), String> {
+    db.exec("BEGIN IMMEDIATE")?;
+    let result = acl::write_file(db, &Principal::Shell, &item.reply_to, "data", mail).and_then(|()| {
+        db.query_params(
+            "UPDATE outbox SET status = ?, attempts = attempts + 1, last_error = ?, \
+                 finished = strftime('%s','now') WHERE id = ?",
+            &[
+                SqlValue::Text(String::from(status)),
+                error.map_or(SqlValue::Null, |e| SqlValue::Text(String::from(e))),
+                SqlValue::Integer(item.id),
+            ],
+        )
+        .map(|_| ())
+    });
+    match result {
+        Ok(()) => db.exec("COMMIT"),
+        Err(e) => {
+            let _ = db.exec("ROLLBACK");
+            Err(e)
+        }
+    }
+}
+
+fn select(db: &SqliteDb, tail: &str, params: &[SqlValue]) -> Result<Vec<Item>, String> {
+    let result = db.query_params(&alloc::format!(
+        "SELECT id, created, agent, model, system, messages, reply_to, status, attempts, last_error, \
+                batch, batch_id \
+         FROM outbox {}",
+        tail
+    ), params)?;
+    let text = |row: &[SqlValue], i: usize| String::from(row.get(i).and_then(SqlValue::as_str).unwrap_or(""));
+    let opt = |row: &[SqlValue], i: usize| row.get(i).and_then(SqlValue::as_str).map(String::from);
+    let int = |row: &[SqlValue], i: usize| row.get(i).and_then(SqlValue::as_integer).unwrap_or(0);
+    Ok(result
+        .rows
+        .iter()
+        .map(|row| Item {
+            id: int(row, 0),
+            created: int(row, 1),
+            agent: text(row, 2),
+            model: text(row, 3),
+            system: opt(row, 4),
+            messages: decode_messages(&text(row, 5)),
+            encoded: text(row, 5),
+            reply_to: text(row, 6),
+            status: text(row, 7),
+            attempts: int(row, 8),
+            last_error: opt(row, 9),
+            batch: int(row, 10) != 0,
+            batch_id: opt(row, 11),
+        })
+        .collect())
+}
+
+/// (role, text) for each stored message; blocks give their text, and a
+/// tool call its name.
+fn decode_messages(encoded: &str) -> Vec<(String, String)> {
+    let Ok(parsed) = json::parse(encoded) else {
+        return Vec::new();
+    };
+    parse_messages(&parsed)
+        .into_iter()
+        .map(|m| {
+            let mut text = m.content;
+            for block in &m.content_blocks {
+                match block {
+                    ContentBlock::Text(t) => text.push_str(t),
+                    ContentBlock::ToolUse { name, .. } => text.push_str(&alloc::format!("[{}]", name)),
+                    ContentBlock::ToolResult { content, .. } => text.push_str(content),
+                }
+            }
+            (String::from(m.role), text)
+        })
+        .collect()
+}
@@ -0,0 +1,92 @@
+/// Per-tool policy for the agent loop.
+///
+/// The `tool_policy` table maps a tool name (`write_file`, `sql_query`,
+/// ...) or `*` (every tool without a row of its own) to
+///
+///   allow     run the call (the default when no row matches)
+///   deny      refuse it; the model gets an error result
+///   confirm   ask on the serial console first ("allow write to /etc/rc? [y/N]")
+///
+/// Denials and console decisions are written to `audit`.
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use super::{SqliteDb, SqlValue};
+
+/// What to do with a tool call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Allow,
+    Deny,
+    Confirm,
+}
+
+impl Action {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "allow" => Some(Action::Allow),
+            "deny" => Some(Action::Deny),
+            "confirm" => Some(Action::Confirm),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Action::Allow => "allow",
+            Action::Deny => "deny",
+            Action::Confirm => "confirm",
+        }
+    }
+}
+
+/// Create the `tool_policy` table.
+pub(super) fn create_table(db: &SqliteDb) -> Result<(), String> {
+    db.exec(
+        "CREATE TABLE IF NOT EXISTS tool_policy (\
+            tool   TEXT PRIMARY KEY, \
+            action TEXT NOT NULL CHECK(action IN ('allow','deny','confirm'))\
+        )",
+    )
+}
+
+/// The action for `tool`: its own row, else the `*` row, else allow.
+pub fn lookup(db: &SqliteDb, tool: &str) -> Result<Action, String> {
+    let result = db.query_params(
+        "SELECT action FROM tool_policy WHERE tool IN (?, '*') ORDER BY tool = '*' LIMIT 1",
+        &[SqlValue::Text(String::from(tool))],
+    )?;
+    Ok(result
+        .rows
+        .first()
+        .and_then(|row| row.first())
+        .and_then(SqlValue::as_str)
+        .and_then(Action::parse)
+        .unwrap_or(Action::Allow))
+}
+
+/// Set the action for `tool` (or `*`); None removes its row.
+pub fn set(db: &SqliteDb, tool: &str, action: Option<Action>) -> Result<(), String> {
+    match action {
+        Some(action) => db.query_params(
+            "INSERT OR REPLACE INTO tool_policy (tool, action) VALUES (?, ?)",
+            &[SqlValue::Text(String::from(tool)), SqlValue::Text(String::from(action.as_str()))],
+        )?,
+        None => db.query_params("DELETE FROM tool_policy WHERE tool = ?", &[SqlValue::Text(String::from(tool))])?,
+    };
+    Ok(())
+}
+
+/// Every row as (tool, action), sorted by tool.
+pub fn list(db: &SqliteDb) -> Result<Vec<(String, Action)>, String> {
+    let result = db.query("SELECT tool, action FROM tool_policy ORDER BY tool")?;
+    Ok(result
+        .rows
+        .iter()
+        .filter_map(|row| {
+            let tool = row.first().and_then(SqlValue::as_str)?;
+            let action = row.get(1).and_then(SqlValue::as_str).and_then(Action::parse)?;
+            Some((String::from(tool), action))
+        })
+        .collect())
+}
@@ -0,0 +1,85 @@
+/// System prompts kept in the namespace.
+///
+/// Prompts are plain files under `/prompts/system/`, so they can be
+/// edited with `write`/`edit` and take effect on the next request:
+///
+///   /prompts/system/agent    the `agent` loop's prompt (seeded at init)
+///   /prompts/system/<name>   anything else, e.g. for Lua
+///                            `ask({prompt="<name>", messages=...})`
+///
+/// The `agent.system_prompt` config key picks a different prompt for the
+/// agent loop, by name or by full namespace path. A name never leaves
+/// `/prompts/system/`; a full path may point anywhere the reader may read.
+use alloc::string::String;
+
+use super::{SqliteDb, SqlValue, DB};
+
+/// Namespace directory holding the prompts.
+pub const PROMPT_DIR: &str = "/prompts/system/";
+
+/// Config key naming the agent loop's prompt.
+const AGENT_KEY: &str = "agent.system_prompt";
+
+/// The agent loop's built-in prompt: seeded as `/prompts/system/agent`,
+/// and used as is when the configured prompt can't be read.
+pub const DEFAULT_AGENT: &str = "\
+You are an AI assistant running inside OSqlite, a bare-metal OS with an embedded SQLite database. \
+You have tools to read/write files in the namespace, execute SQL queries, and list directories. \
+Use tools to inspect and modify the system as needed. Be concise in your responses.";
+
+/// Write the default agent prompt unless one is already there.
+pub(super) fn seed(db: &SqliteDb) -> Result<(), String> {
+    db.query_params(
+        "INSERT OR IGNORE INTO namespace (path, type, content) VALUES (?, 'data', ?)",
+        &[
+            SqlValue::Text(alloc::format!("{}agent", PROMPT_DIR)),
+            SqlValue::Text(String::from(DEFAULT_AGENT)),
+        ],
+    )?;
+    Ok(())
+}
+
+/// The namespace path of a prompt given by name or full path.
+pub fn path_of(name: &str) -> Result<String, String> {
+    let name = name.trim();
+    if name.starts_with('/') {
+        return Ok(String::from(name));
+    }
+    if name.is_empty() || name.contains('/') {
+        return Err(alloc::format!("{:?}: not a prompt name", name));
+    }
+    Ok(alloc::format!("{}{}", PROMPT_DIR, name))
+}
+
+/// The prompt at `path` (see `path_of`), or None if there is none. No
+/// access check: callers acting for an agent check read access first.
+pub fn read(db: &SqliteDb, path: &str) -> Result<Option<String>, String> {
+    let result = db.query_params(
+        "SELECT unpack(content, compressed) FROM namespace WHERE path = ? AND type = 'data'",
+        &[SqlValue::Text(String::from(path))],
+    )?;
+    Ok(result
+        .rows
+        .first()
+        .and_then(|row| row.first())
+        .map(|value| String::from(value.as_str().unwrap_or(""))))
+}
+
+/// The agent loop's system prompt: the one `agent.system_prompt` names,
+/// else `/prompts/system/agent`, else `DEFAULT_AGENT`. Takes the DB lock.
+pub fn agent() -> String {
+    let name = super::config_get(AGENT_KEY).unwrap_or_else(|| String::from("agent"));
+    let prompt = path_of(&name).and_then(|path| {
+        let guard = DB.lock();
+        let db = guard.as_ref().ok_or_else(|| String::from("database not open"))?;
+        read(db, &path)?.ok_or_else(|| alloc::format!("{}: no such prompt", path))
+    });
+    match prompt {
+        Ok(prompt) if !prompt.trim().is_empty() => prompt,
+        Ok(_) => String::from(DEFAULT_AGENT),
+        Err(e) => {
+            crate::serial_println!("[agent] {}: {}; using the built-in prompt", AGENT_KEY, e);
+            String::from(DEFAULT_AGENT)
+        }
+    }
+}
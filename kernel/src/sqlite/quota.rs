@@ -0,0 +1,222 @@
+/// Byte quotas on namespace subtrees.
+///
+/// A quota is a config key naming a path prefix:
+///
+///   quota./agents/foo/   4M      content under /agents/foo/ ≤ 4 MiB
+///
+/// (`quota <prefix> <size|off>` in the shell sets or clears one.) Sizes
+/// take a K, M or G suffix. Usage is the summed length of `content` for
+/// every path under the prefix, read through the primary-key index, so a
+/// check costs a range scan of that subtree only.
+///
+/// Agent writes (Lua `write`, the `agent` tools) are checked against every
+/// quota whose prefix contains the path; the shell is exempt, as it is
+/// from permissions. `du` and `/sys/quota` report usage.
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use super::{SqliteDb, SqlValue, CONFIG_PREFIX};
+
+/// Config key prefix for quotas: `quota.<path prefix>`.
+const KEY_PREFIX: &str = "quota.";
+
+/// A configured quota and the bytes currently used under it.
+#[derive(Debug, Clone)]
+pub struct Usage {
+    pub prefix: String,
+    pub used: u64,
+    pub limit: Option<u64>,
+}
+
+/// Parse "4096", "64K", "4M" or "1G" into bytes.
+pub fn parse_size(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let (digits, scale) = match s.as_bytes().last()? {
+        b'K' | b'k' => (&s[..s.len() - 1], 1024),
+        b'M' | b'm' => (&s[..s.len() - 1], 1024 * 1024),
+        b'G' | b'g' => (&s[..s.len() - 1], 1024 * 1024 * 1024),
+        _ => (s, 1),
+    };
+    digits.parse::<u64>().ok()?.checked_mul(scale)
+}
+
+/// A prefix as quotas store it: leading and trailing `/`.
+pub fn normalize(prefix: &str) -> String {
+    let trimmed = prefix.trim_matches('/');
+    if trimmed.is_empty() {
+        String::from("/")
+    } else {
+        alloc::format!("/{}/", trimmed)
+    }
+}
+
+/// Bytes of content stored under `prefix` (which ends in `/`).
+pub fn used(db: &SqliteDb, prefix: &str) -> Result<u64, String> {
+    let (low, high) = range(prefix);
+    let result = db.query_params(
+        "SELECT coalesce(sum(octet_length(content)), 0) FROM namespace WHERE path >= ? AND path < ?",
+        &[SqlValue::Text(low), SqlValue::Text(high)],
+    )?;
+    Ok(result
+        .rows
+        .first()
+        .and_then(|r| r.first())
+        .and_then(SqlValue::as_integer)
+        .unwrap_or(0) as u64)
+}
+
+/// Every configured quota with its usage, sorted by prefix.
+pub fn list(db: &SqliteDb) -> Result<Vec<Usage>, String> {
+    let result = db.query_params(
+        "SELECT substr(path, ?), content FROM namespace \
+         WHERE type = 'config' AND substr(path, 1, ?) = ? COLLATE NOCASE ORDER BY path",
+        &[
+            SqlValue::Integer((CONFIG_PREFIX.len() + KEY_PREFIX.len() + 1) as i64),
+            SqlValue::Integer((CONFIG_PREFIX.len() + KEY_PREFIX.len()) as i64),
+            SqlValue::Text(alloc::format!("{}{}", CONFIG_PREFIX, KEY_PREFIX)),
+        ],
+    )?;
+    let mut quotas = Vec::new();
+    for row in &result.rows {
+        let prefix = normalize(row.first().and_then(SqlValue::as_str).unwrap_or(""));
+        let limit = row.get(1).and_then(SqlValue::as_str).and_then(parse_size);
+        let used = used(db, &prefix)?;
+        quotas.push(Usage { prefix, used, limit });
+    }
+    Ok(quotas)
+}
+
+/// Would replacing `path`'s content with `new_len` bytes exceed a quota?
+/// The error names the first quota broken ("quota exceeded: ...").
+pub fn check(db: &SqliteDb, path: &str, new_len: u64) -> Result<(), String> {
+    let old_len = db
+        .query_params(
+            "SELECT coalesce(octet_length(content), 0) FROM namespace WHERE path = ?",
+            &[SqlValue::Text(String::from(path))],
+        )?
+        .rows
+        .first()
+        .and_then(|r| r.first())
+        .and_then(SqlValue::as_integer)
+        .unwrap_or(0) as u64;
+
+    for quota in list(db)? {
+        let Some(limit) = quota.limit else { continue };
+        if !super::namespace::has_prefix(path, &quota.prefix) {
+            continue;
+        }
+        let after = quota.used.saturating_sub(old_len) + new_len;
+        if after > limit {
+            return Err(alloc::format!(
+                "quota exceeded: {} would use {} of {} bytes",
+                quota.prefix, after, limit
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Set (`Some`) or remove (`None`) the quota on `prefix`.
+pub fn set(db: &SqliteDb, prefix: &str, limit: Option<u64>) -> Result<(), String> {
+    let path = alloc::format!("{}{}{}", CONFIG_PREFIX, KEY_PREFIX, normalize(prefix));
+    match limit {
+        Some(bytes) => db.query_params(
+            "INSERT OR REPLACE INTO namespace (path, type, content, mtime) \
+             VALUES (?, 'config', ?, strftime('%s','now'))",
+            &[SqlValue::Text(path), SqlValue::Text(alloc::format!("{}", bytes))],
+        )?,
+        None => db.query_params(
+            "DELETE FROM namespace WHERE path = ? AND type = 'config'",
+            &[SqlValue::Text(path)],
+        )?,
+    };
+    Ok(())
+}
+
+/// Bytes per child of `prefix`: one entry per immediate subdirectory
+/// (ending in `/`) or file, largest first.
+pub fn du(db: &SqliteDb, prefix: &str) -> Result<Vec<(String, u64)>, String> {
+    let (low, high) = range(prefix);
+    let result = db.query_params(
+        "SELECT CASE WHEN instr(substr(path, ?1 + 1), '/') > 0 \
+                     THEN substr(path, 1, ?1 + instr(substr(path, ?1 + 1), '/')) \
+                     ELSE path END AS child, \
+                coalesce(sum(octet_length(content)), 0) \
+         FROM namespace WHERE path >= ?2 AND path < ?3 \
+         GROUP BY child ORDER BY 2 DESC, child",
+        &[
+            SqlValue::Integer(low.len() as i64),
+            SqlValue::Text(low),
+            SqlValue::Text(high),
+        ],
+    )?;
+    Ok(result
+        .rows
+        .iter()
+        .map(|row| {
+            let child = row.first().and_then(SqlValue::as_str).unwrap_or("");
+            let bytes = row.get(1).and_then(SqlValue::as_integer).unwrap_or(0);
+            (String::from(child), bytes as u64)
+        })
+        .collect())
+}
+
+/// Like `du`, with each child's original (uncompressed) size next to the
+/// bytes stored: (child, stored, original), largest stored first.
+pub fn du_compressed(db: &SqliteDb, prefix: &str) -> Result<Vec<(String, u64, u64)>, String> {
+    let (low, high) = range(prefix);
+    let result = db.query_params(
+        "SELECT CASE WHEN instr(substr(path, ?1 + 1), '/') > 0 \
+                     THEN substr(path, 1, ?1 + instr(substr(path, ?1 + 1), '/')) \
+                     ELSE path END AS child, \
+                coalesce(sum(octet_length(content)), 0), \
+                coalesce(sum(unpacked_length(content, compressed)), 0) \
+         FROM namespace WHERE path >= ?2 AND path < ?3 \
+         GROUP BY child ORDER BY 2 DESC, child",
+        &[
+            SqlValue::Integer(low.len() as i64),
+            SqlValue::Text(low),
+            SqlValue::Text(high),
+        ],
+    )?;
+    Ok(result
+        .rows
+        .iter()
+        .map(|row| {
+            let child = row.first().and_then(SqlValue::as_str).unwrap_or("");
+            let stored = row.get(1).and_then(SqlValue::as_integer).unwrap_or(0);
+            let original = row.get(2).and_then(SqlValue::as_integer).unwrap_or(0);
+            (String::from(child), stored as u64, original as u64)
+        })
+        .collect())
+}
+
+/// `/sys/quota`: one "prefix used limit" line per quota (`-` = none).
+pub fn report() -> Vec<u8> {
+    let guard = super::DB.lock();
+    let Some(db) = guard.as_ref() else {
+        return b"database not open\n".to_vec();
+    };
+    let mut out = String::new();
+    match list(db) {
+        Ok(quotas) => {
+            for q in quotas {
+                match q.limit {
+                    Some(limit) => out.push_str(&alloc::format!("{} {} {}\n", q.prefix, q.used, limit)),
+                    None => out.push_str(&alloc::format!("{} {} -\n", q.prefix, q.used)),
+                }
+            }
+        }
+        Err(e) => out.push_str(&alloc::format!("error: {}\n", e)),
+    }
+    out.into_bytes()
+}
+
+/// Index range [low, high) covering every path that starts with `prefix`:
+/// `/` sorts just below `0`, so bumping the trailing `/` bounds the subtree.
+fn range(prefix: &str) -> (String, String) {
+    let low = normalize(prefix);
+    let mut high = String::from(&low[..low.len() - 1]);
+    high.push('0');
+    (low, high)
+}
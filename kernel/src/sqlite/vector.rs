@@ -0,0 +1,234 @@
+/// Embedding vectors and similarity search.
+///
+/// Vectors are stored as BLOBs of little-endian f32s in the `embeddings`
+/// table, next to the text they were computed from and the namespace path
+/// it came from (if any). Every connection gets a scalar SQL function
+///
+///   cosine_similarity(a, b)   -1.0 … 1.0, NULL if the blobs are not
+///                             same-length f32 vectors
+///
+/// so results can be ranked in plain SQL. `search` does exactly that over
+/// every row from the query's model — a linear scan, which is fine for
+/// the few thousand chunks a local agent store holds.
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ffi::{c_char, c_int, c_void};
+
+use super::ffi::{sqlite3, sqlite3_value, SQLITE_OK};
+use super::{SqliteDb, SqlValue};
+
+const SQLITE_UTF8: c_int = 1;
+const SQLITE_DETERMINISTIC: c_int = 0x800;
+const SQLITE_INNOCUOUS: c_int = 0x200000;
+
+#[repr(C)]
+pub struct sqlite3_context {
+    _opaque: [u8; 0],
+}
+
+extern "C" {
+    fn sqlite3_create_function_v2(
+        db: *mut sqlite3,
+        zFunctionName: *const c_char,
+        nArg: c_int,
+        eTextRep: c_int,
+        pApp: *mut c_void,
+        xFunc: Option<unsafe extern "C" fn(*mut sqlite3_context, c_int, *mut *mut sqlite3_value)>,
+        xStep: Option<unsafe extern "C" fn(*mut sqlite3_context, c_int, *mut *mut sqlite3_value)>,
+        xFinal: Option<unsafe extern "C" fn(*mut sqlite3_context)>,
+        xDestroy: Option<unsafe extern "C" fn(*mut c_void)>,
+    ) -> c_int;
+    fn sqlite3_value_blob(value: *mut sqlite3_value) -> *const c_void;
+    fn sqlite3_value_bytes(value: *mut sqlite3_value) -> c_int;
+    fn sqlite3_result_double(ctx: *mut sqlite3_context, value: f64);
+    fn sqlite3_result_null(ctx: *mut sqlite3_context);
+}
+
+/// A stored chunk ranked against a query.
+#[derive(Debug, Clone)]
+pub struct Hit {
+    pub id: i64,
+    pub path: Option<String>,
+    pub text: String,
+    pub score: f64,
+}
+
+/// Register `cosine_similarity` on a connection.
+pub(super) unsafe fn install(db: *mut sqlite3) -> Result<(), String> {
+    let rc = unsafe {
+        sqlite3_create_function_v2(
+            db,
+            c"cosine_similarity".as_ptr(),
+            2,
+            SQLITE_UTF8 | SQLITE_DETERMINISTIC | SQLITE_INNOCUOUS,
+            core::ptr::null_mut(),
+            Some(cosine_similarity),
+            None,
+            None,
+            None,
+        )
+    };
+    if rc != SQLITE_OK {
+        return Err(alloc::format!("cosine_similarity: sqlite3_create_function_v2 failed: {}", rc));
+    }
+    Ok(())
+}
+
+/// Create the `embeddings` table.
+pub(super) fn create_table(db: &SqliteDb) -> Result<(), String> {
+    db.exec(
+        "CREATE TABLE IF NOT EXISTS embeddings (\
+            id      INTEGER PRIMARY KEY AUTOINCREMENT, \
+            path    TEXT, \
+            chunk   TEXT NOT NULL, \
+            model   TEXT NOT NULL, \
+            dims    INTEGER NOT NULL, \
+            vector  BLOB NOT NULL, \
+            created INTEGER DEFAULT (strftime('%s','now'))\
+        )",
+    )?;
+    db.exec("CREATE INDEX IF NOT EXISTS embeddings_path ON embeddings(path)")
+}
+
+/// Little-endian f32 bytes of `v`.
+pub fn encode(v: &[f32]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(v.len() * 4);
+    for x in v {
+        out.extend_from_slice(&x.to_le_bytes());
+    }
+    out
+}
+
+/// The f32s in `bytes`, or None if its length isn't a multiple of 4.
+pub fn decode(bytes: &[u8]) -> Option<Vec<f32>> {
+    if !bytes.len().is_multiple_of(4) {
+        return None;
+    }
+    Some(bytes.as_chunks::<4>().0.iter().map(|c| f32::from_le_bytes(*c)).collect())
+}
+
+/// Cosine of the angle between two encoded vectors. None when the lengths
+/// differ, are not whole f32s, or either vector is zero.
+pub fn cosine(a: &[u8], b: &[u8]) -> Option<f64> {
+    if a.len() != b.len() || a.is_empty() || !a.len().is_multiple_of(4) {
+        return None;
+    }
+    let (mut dot, mut norm_a, mut norm_b) = (0f64, 0f64, 0f64);
+    for (x, y) in a.as_chunks::<4>().0.iter().zip(b.as_chunks::<4>().0) {
+        let x = f32::from_le_bytes(*x) as f64;
+        let y = f32::from_le_bytes(*y) as f64;
+        dot += x * y;
+        norm_a += x * x;
+        norm_b += y * y;
+    }
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return None;
+    }
+    Some(dot / sqrt(norm_a * norm_b))
+}
+
+/// Square root by Newton's method (no libm here); exact enough for scores.
+fn sqrt(x: f64) -> f64 {
+    if x <= 0.0 {
+        return 0.0;
+    }
+    // Halving the exponent gives a first guess within a factor of two
+    let mut guess = f64::from_bits((x.to_bits() >> 1) + (1023u64 << 51));
+    for _ in 0..6 {
+        guess = 0.5 * (guess + x / guess);
+    }
+    guess
+}
+
+/// `vector` as a hex literal for `unhex(?)` — SqlValue has no blob variant.
+fn hex(vector: &[f32]) -> String {
+    let mut out = String::with_capacity(vector.len() * 8);
+    for byte in encode(vector) {
+        out.push_str(&alloc::format!("{:02X}", byte));
+    }
+    out
+}
+
+/// Save a chunk and its vector. Returns the new row id.
+pub fn store(db: &SqliteDb, path: Option<&str>, text: &str, model: &str, vector: &[f32]) -> Result<i64, String> {
+    db.query_params(
+        "INSERT INTO embeddings (path, chunk, model, dims, vector) VALUES (?, ?, ?, ?, unhex(?))",
+        &[
+            path.map_or(SqlValue::Null, |p| SqlValue::Text(String::from(p))),
+            SqlValue::Text(String::from(text)),
+            SqlValue::Text(String::from(model)),
+            SqlValue::Integer(vector.len() as i64),
+            SqlValue::Text(hex(vector)),
+        ],
+    )?;
+    db.query_value("SELECT last_insert_rowid()")?
+        .and_then(|v| v.parse().ok())
+        .ok_or_else(|| String::from("no row id"))
+}
+
+/// The `k` chunks embedded by `model` most similar to `query`, best
+/// first: vectors from another model aren't comparable even at the same
+/// dimension. Chunks from a path `readable` refuses are skipped, and their
+/// text is never read.
+pub fn search(
+    db: &SqliteDb,
+    query: &[f32],
+    model: &str,
+    k: usize,
+    mut readable: impl FnMut(&str) -> bool,
+) -> Result<Vec<Hit>, String> {
+    let ranked = db.query_params(
+        "SELECT id, path, cosine_similarity(vector, unhex(?1)) AS score FROM embeddings \
+         WHERE model = ?3 AND dims = ?2 AND score IS NOT NULL ORDER BY score DESC",
+        &[
+            SqlValue::Text(hex(query)),
+            SqlValue::Integer(query.len() as i64),
+            SqlValue::Text(String::from(model)),
+        ],
+    )?;
+    let mut hits = Vec::new();
+    for row in &ranked.rows {
+        if hits.len() == k {
+            break;
+        }
+        let path = row.get(1).and_then(SqlValue::as_str).map(String::from);
+        if path.as_deref().is_some_and(|p| !readable(p)) {
+            continue;
+        }
+        let id = row.first().and_then(SqlValue::as_integer).unwrap_or(0);
+        let text = db.query_params("SELECT chunk FROM embeddings WHERE id = ?", &[SqlValue::Integer(id)])?;
+        hits.push(Hit {
+            id,
+            path,
+            text: String::from(text.rows.first().and_then(|r| r.first()).and_then(SqlValue::as_str).unwrap_or("")),
+            score: match row.get(2) {
+                Some(SqlValue::Real(x)) => *x,
+                _ => 0.0,
+            },
+        });
+    }
+    Ok(hits)
+}
+
+unsafe extern "C" fn cosine_similarity(ctx: *mut sqlite3_context, argc: c_int, argv: *mut *mut sqlite3_value) {
+    if argc != 2 {
+        unsafe { sqlite3_result_null(ctx) };
+        return;
+    }
+    let (a, b) = unsafe { (blob(*argv), blob(*argv.add(1))) };
+    match cosine(a, b) {
+        Some(score) => unsafe { sqlite3_result_double(ctx, score) },
+        None => unsafe { sqlite3_result_null(ctx) },
+    }
+}
+
+/// The bytes of a function argument (empty for NULL). Valid until the
+/// function returns.
+unsafe fn blob<'a>(value: *mut sqlite3_value) -> &'a [u8] {
+    let (ptr, len) = unsafe { (sqlite3_value_blob(value) as *const u8, sqlite3_value_bytes(value)) };
+    if ptr.is_null() || len <= 0 {
+        &[]
+    } else {
+        unsafe { core::slice::from_raw_parts(ptr, len as usize) }
+    }
+}
@@ -7,12 +7,32 @@
 ///
 /// The VFS global is stored in a static Mutex and set up during boot
 /// (after NVMe + block allocator + file table are ready).
+///
+/// Temp files — temp databases, sorter spill, statement and temp journals
+/// (SQLITE_OPEN_TEMP_DB and friends, usually opened with a NULL name) —
+/// never reach the block device. They are RAM files (`MEM_IO_METHODS`): a
+/// Vec owned by the handle and freed on close, so they cost no file-table
+/// entry and no NVMe writes. With `temp_store=MEMORY` (our default) SQLite
+/// rarely opens them at all; this is the fallback for `temp_store=FILE`.
+///
+/// Memory-mapped I/O (`PRAGMA mmap_size`, config `sqlite.mmap_size`): with
+/// one address space there is nothing to map, so xFetch hands out pointers
+/// into a RAM image of the file instead (`Mapping`), loaded through the VFS
+/// and its block cache on the first fetch and patched by every xWrite on
+/// the handle. Pages read that way skip SQLite's page cache and its copy.
+/// Another connection's writes are not seen, but SQLite unmaps (xUnfetch
+/// with a null page) whenever it sees the file change under it, as it must
+/// for a real mmap whose file was truncated.
 use core::cell::UnsafeCell;
 use core::ffi::{c_char, c_int, c_void};
 use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use alloc::boxed::Box;
 use alloc::string::String;
+use alloc::vec::Vec;
 
 use crate::vfs::HeavenVfs;
+use crate::vfs::sqlite_vfs::SQLITE_LOCK_NONE;
 
 // ---- SQLite VFS structures (must match sqlite3.h exactly) ----
 
@@ -61,6 +81,14 @@ struct Sqlite3IoMethods {
     xFileControl: Option<unsafe extern "C" fn(*mut Sqlite3File, c_int, *mut c_void) -> c_int>,
     xSectorSize: Option<unsafe extern "C" fn(*mut Sqlite3File) -> c_int>,
     xDeviceCharacteristics: Option<unsafe extern "C" fn(*mut Sqlite3File) -> c_int>,
+    // v2
+    xShmMap: Option<unsafe extern "C" fn(*mut Sqlite3File, c_int, c_int, c_int, *mut *mut c_void) -> c_int>,
+    xShmLock: Option<unsafe extern "C" fn(*mut Sqlite3File, c_int, c_int, c_int) -> c_int>,
+    xShmBarrier: Option<unsafe extern "C" fn(*mut Sqlite3File)>,
+    xShmUnmap: Option<unsafe extern "C" fn(*mut Sqlite3File, c_int) -> c_int>,
+    // v3
+    xFetch: Option<unsafe extern "C" fn(*mut Sqlite3File, i64, c_int, *mut *mut c_void) -> c_int>,
+    xUnfetch: Option<unsafe extern "C" fn(*mut Sqlite3File, i64, *mut c_void) -> c_int>,
 }
 
 /// sqlite3_file header — the first field of every open file handle.
@@ -79,15 +107,63 @@ struct HeavenSqliteFile {
     block_count: u64,
     byte_length: u64,
     block_size: u32,
+    chunk_blocks: u64,
+    /// Current SQLITE_LOCK_* level held by this handle.
+    lock_level: c_int,
+    /// Opened without write access; never writes metadata back.
+    read_only: bool,
+    /// Contents of a RAM temp file (`MEM_IO_METHODS`); null for disk files.
+    mem: *mut Vec<u8>,
+    /// Bytes xFetch may map (SQLITE_FCNTL_MMAP_SIZE); 0 = none.
+    mmap_limit: i64,
+    /// What xFetch hands out; null until the first fetch.
+    map: *mut Mapping,
+}
+
+/// The RAM image behind xFetch: the file's first `len` bytes, then
+/// `MAP_PAD` zeros (SQLite may overread a corrupt page slightly).
+struct Mapping {
+    data: Vec<u8>,
+    len: usize,
+    /// Pages handed out and not yet released. `data` must not move while
+    /// there are any.
+    refs: usize,
 }
 
+/// Largest mapping, whatever `PRAGMA mmap_size` asks for. Must match
+/// SQLITE_MAX_MMAP_SIZE in vendor/sqlite/sqlite_config.h.
+const MAX_MMAP_SIZE: i64 = 0x1000_0000;
+
+/// Addressable zeros after a mapping, as unix mmap guarantees SQLite.
+const MAP_PAD: usize = 256;
+
+/// Bytes read per VFS call while loading a mapping.
+const MAP_CHUNK: usize = 256 * 1024;
+
 // ---- SQLite constants ----
 
 const SQLITE_OK: c_int = 0;
 const SQLITE_IOERR: c_int = 10;
+const SQLITE_IOERR_SHORT_READ: c_int = SQLITE_IOERR | (2 << 8);
 const SQLITE_NOTFOUND: c_int = 12;
+const SQLITE_NOMEM: c_int = 7;
 const SQLITE_CANTOPEN: c_int = 14;
 const SQLITE_OPEN_CREATE: c_int = 0x00000004;
+const SQLITE_OPEN_READONLY: c_int = 0x00000001;
+const SQLITE_OPEN_READWRITE: c_int = 0x00000002;
+const SQLITE_OPEN_TEMP_DB: c_int = 0x00000200;
+const SQLITE_OPEN_TRANSIENT_DB: c_int = 0x00000400;
+const SQLITE_OPEN_TEMP_JOURNAL: c_int = 0x00001000;
+const SQLITE_OPEN_SUBJOURNAL: c_int = 0x00002000;
+
+/// Open flags of the file classes kept in RAM.
+const TEMP_FILE_FLAGS: c_int =
+    SQLITE_OPEN_TEMP_DB | SQLITE_OPEN_TRANSIENT_DB | SQLITE_OPEN_TEMP_JOURNAL | SQLITE_OPEN_SUBJOURNAL;
+
+const SQLITE_FCNTL_SIZE_HINT: c_int = 5;
+const SQLITE_FCNTL_CHUNK_SIZE: c_int = 6;
+const SQLITE_FCNTL_PRAGMA: c_int = 14;
+const SQLITE_FCNTL_MMAP_SIZE: c_int = 18;
 
 // ---- Static VFS and I/O methods ----
 
@@ -96,11 +172,10 @@ static VFS_NAME: &[u8] = b"heaven\0";
 
 /// The I/O methods table — shared by all open files.
 ///
-/// iVersion=1: basic file I/O only. WAL (v2: xShmMap/Lock/Barrier/Unmap)
-/// and mmap (v3: xFetch/xUnfetch) are not needed because we compile SQLite
-/// with SQLITE_OMIT_WAL and don't support memory-mapped I/O.
+/// iVersion=3 for xFetch/xUnfetch (see `Mapping`). The v2 shared-memory
+/// methods stay None: SQLite is compiled with SQLITE_OMIT_WAL.
 static IO_METHODS: Sqlite3IoMethods = Sqlite3IoMethods {
-    iVersion: 1,
+    iVersion: 3,
     xClose: Some(heaven_close),
     xRead: Some(heaven_read),
     xWrite: Some(heaven_write),
@@ -113,8 +188,55 @@ static IO_METHODS: Sqlite3IoMethods = Sqlite3IoMethods {
     xFileControl: Some(heaven_file_control),
     xSectorSize: Some(heaven_sector_size),
     xDeviceCharacteristics: Some(heaven_device_characteristics),
+    xShmMap: None,
+    xShmLock: None,
+    xShmBarrier: None,
+    xShmUnmap: None,
+    xFetch: Some(heaven_fetch),
+    xUnfetch: Some(heaven_unfetch),
 };
 
+/// I/O methods for RAM temp files.
+static MEM_IO_METHODS: Sqlite3IoMethods = Sqlite3IoMethods {
+    iVersion: 1,
+    xClose: Some(mem_close),
+    xRead: Some(mem_read),
+    xWrite: Some(mem_write),
+    xTruncate: Some(mem_truncate),
+    xSync: Some(mem_sync),
+    xFileSize: Some(mem_file_size),
+    xLock: Some(mem_lock),
+    xUnlock: Some(mem_lock),
+    xCheckReservedLock: Some(mem_check_reserved_lock),
+    xFileControl: Some(mem_file_control),
+    xSectorSize: Some(mem_sector_size),
+    xDeviceCharacteristics: Some(mem_device_characteristics),
+    xShmMap: None,
+    xShmLock: None,
+    xShmBarrier: None,
+    xShmUnmap: None,
+    xFetch: None,
+    xUnfetch: None,
+};
+
+/// RAM temp files open now, and the bytes they hold.
+static TEMP_FILES: AtomicUsize = AtomicUsize::new(0);
+static TEMP_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+/// (open RAM temp files, bytes in them), for `db info`.
+pub fn temp_usage() -> (usize, usize) {
+    (TEMP_FILES.load(Ordering::Relaxed), TEMP_BYTES.load(Ordering::Relaxed))
+}
+
+/// Disk files mapped for xFetch now, and the bytes mapped.
+static MAPPED_FILES: AtomicUsize = AtomicUsize::new(0);
+static MAPPED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+/// (mapped files, bytes mapped), for `db info`.
+pub fn map_usage() -> (usize, usize) {
+    (MAPPED_FILES.load(Ordering::Relaxed), MAPPED_BYTES.load(Ordering::Relaxed))
+}
+
 /// Wrapper to allow a static Sqlite3Vfs in an UnsafeCell (SQLite modifies pNext).
 struct SyncVfs(UnsafeCell<Sqlite3Vfs>);
 unsafe impl Sync for SyncVfs {}
@@ -179,6 +301,11 @@ where
 /// Global HeavenVfs pointer — initialized once via spin::Once.
 static VFS_INSTANCE: spin::Once<&'static HeavenVfs> = spin::Once::new();
 
+/// The global VFS, if `set_vfs_instance` has run.
+pub fn vfs_instance() -> Option<&'static HeavenVfs> {
+    VFS_INSTANCE.get().copied()
+}
+
 /// Set the global VFS instance. Called from init code before sqlite::init().
 ///
 /// # Safety
@@ -215,8 +342,11 @@ unsafe extern "C" fn heaven_open(
     zName: *const c_char,
     pFile: *mut Sqlite3File,
     flags: c_int,
-    _pOutFlags: *mut c_int,
+    pOutFlags: *mut c_int,
 ) -> c_int {
+    if flags & TEMP_FILE_FLAGS != 0 || zName.is_null() {
+        return unsafe { mem_open(pFile, flags, pOutFlags) };
+    }
     let name = unsafe { cstr_to_bytes(zName) };
     if name.is_empty() {
         return SQLITE_CANTOPEN;
@@ -233,6 +363,21 @@ unsafe extern "C" fn heaven_open(
                 (*file).block_count = hfile.block_count;
                 (*file).byte_length = hfile.byte_length;
                 (*file).block_size = hfile.block_size;
+                (*file).chunk_blocks = hfile.chunk_blocks;
+                (*file).lock_level = SQLITE_LOCK_NONE;
+                (*file).read_only = flags & SQLITE_OPEN_READONLY != 0;
+                (*file).mem = ptr::null_mut();
+                (*file).mmap_limit = 0;
+                (*file).map = ptr::null_mut();
+            }
+            // Tell SQLite the file is read-only so it never tries to write it.
+            if !pOutFlags.is_null() {
+                let out = if with_vfs(|vfs| vfs.is_read_only()) {
+                    (flags & !(SQLITE_OPEN_CREATE | SQLITE_OPEN_READWRITE)) | SQLITE_OPEN_READONLY
+                } else {
+                    flags
+                };
+                unsafe { *pOutFlags = out; }
             }
             SQLITE_OK
         }
@@ -242,6 +387,15 @@ unsafe extern "C" fn heaven_open(
 
 unsafe extern "C" fn heaven_close(pFile: *mut Sqlite3File) -> c_int {
     let file = pFile as *mut HeavenSqliteFile;
+    unsafe {
+        unmap(file);
+        let index = (*file).file_table_index;
+        with_vfs(|vfs| vfs.unlock(index, &mut (*file).lock_level, SQLITE_LOCK_NONE));
+        // A reader's cached length may be stale; never write it back
+        if (*file).read_only {
+            return SQLITE_OK;
+        }
+    }
     let hfile = unsafe { heaven_file_to_vfs_file(&*file) };
     with_vfs(|vfs| vfs.close(&hfile))
 }
@@ -274,6 +428,14 @@ unsafe extern "C" fn heaven_write(
         (*file).block_count = hfile.block_count;
         (*file).start_lba = hfile.start_lba;
     }
+    // Keep the mapped bytes current, as a shared mapping would be
+    if rc == SQLITE_OK {
+        let start = iOfst as usize;
+        if let Some(map) = unsafe { (*file).map.as_mut() }.filter(|m| start < m.len) {
+            let n = data.len().min(map.len - start);
+            map.data[start..start + n].copy_from_slice(&data[..n]);
+        }
+    }
     rc
 }
 
@@ -287,6 +449,14 @@ unsafe extern "C" fn heaven_truncate(pFile: *mut Sqlite3File, size: i64) -> c_in
         (*file).block_count = hfile.block_count;
         (*file).start_lba = hfile.start_lba;
     }
+    // Bytes past the new end are no longer mapped; the memory stays put
+    // until nothing points into it
+    if let Some(map) = unsafe { (*file).map.as_mut() } {
+        let len = (size.max(0) as usize).min(map.len);
+        MAPPED_BYTES.fetch_sub(map.len - len, Ordering::Relaxed);
+        map.data[len..map.len].fill(0);
+        map.len = len;
+    }
     rc
 }
 
@@ -308,28 +478,203 @@ unsafe extern "C" fn heaven_file_size(pFile: *mut Sqlite3File, pSize: *mut i64)
     }
 }
 
-unsafe extern "C" fn heaven_lock(_pFile: *mut Sqlite3File, _level: c_int) -> c_int {
-    SQLITE_OK // Single process — locking is a no-op
+unsafe extern "C" fn heaven_lock(pFile: *mut Sqlite3File, level: c_int) -> c_int {
+    let file = pFile as *mut HeavenSqliteFile;
+    let index = unsafe { (*file).file_table_index };
+    let was = unsafe { (*file).lock_level };
+    let rc = with_vfs(|vfs| vfs.lock(index, unsafe { &mut (*file).lock_level }, level));
+
+    // First lock of a transaction: pick up changes other connections made
+    if rc == SQLITE_OK && was == SQLITE_LOCK_NONE {
+        let mut hfile = unsafe { heaven_file_to_vfs_file(&*file) };
+        with_vfs(|vfs| vfs.refresh(&mut hfile));
+        unsafe {
+            (*file).byte_length = hfile.byte_length;
+            (*file).block_count = hfile.block_count;
+            (*file).start_lba = hfile.start_lba;
+        }
+    }
+    rc
 }
 
-unsafe extern "C" fn heaven_unlock(_pFile: *mut Sqlite3File, _level: c_int) -> c_int {
-    SQLITE_OK
+unsafe extern "C" fn heaven_unlock(pFile: *mut Sqlite3File, level: c_int) -> c_int {
+    let file = pFile as *mut HeavenSqliteFile;
+    let index = unsafe { (*file).file_table_index };
+    with_vfs(|vfs| vfs.unlock(index, unsafe { &mut (*file).lock_level }, level))
 }
 
 unsafe extern "C" fn heaven_check_reserved_lock(
-    _pFile: *mut Sqlite3File,
+    pFile: *mut Sqlite3File,
     pResOut: *mut c_int,
 ) -> c_int {
-    unsafe { *pResOut = 0; }
+    let file = pFile as *const HeavenSqliteFile;
+    let index = unsafe { (*file).file_table_index };
+    let reserved = with_vfs(|vfs| vfs.check_reserved(index));
+    unsafe { *pResOut = reserved as c_int; }
     SQLITE_OK
 }
 
 unsafe extern "C" fn heaven_file_control(
-    _pFile: *mut Sqlite3File,
-    _op: c_int,
-    _pArg: *mut c_void,
+    pFile: *mut Sqlite3File,
+    op: c_int,
+    pArg: *mut c_void,
+) -> c_int {
+    let file = pFile as *mut HeavenSqliteFile;
+    match op {
+        // Preallocate so a growing file relocates once, not once per write
+        SQLITE_FCNTL_SIZE_HINT => {
+            let size = unsafe { *(pArg as *const i64) };
+            if size <= 0 {
+                return SQLITE_OK;
+            }
+            let mut hfile = unsafe { heaven_file_to_vfs_file(&*file) };
+            let rc = with_vfs(|vfs| vfs.size_hint(&mut hfile, size as u64));
+            unsafe {
+                (*file).block_count = hfile.block_count;
+                (*file).start_lba = hfile.start_lba;
+            }
+            rc
+        }
+        // Grow and truncate in multiples of this many bytes
+        SQLITE_FCNTL_CHUNK_SIZE => {
+            let bytes = unsafe { *(pArg as *const c_int) };
+            unsafe {
+                let bs = (*file).block_size.max(1) as u64;
+                (*file).chunk_blocks = if bytes > 0 { (bytes as u64).div_ceil(bs) } else { 0 };
+            }
+            SQLITE_OK
+        }
+        // azArg[1] is the pragma name; a result string goes in azArg[0]
+        SQLITE_FCNTL_PRAGMA => {
+            let azArg = pArg as *mut *mut c_char;
+            let name = unsafe { cstr_to_bytes(*azArg.add(1)) };
+            if !name.eq_ignore_ascii_case(b"heaven_status") {
+                return SQLITE_NOTFOUND;
+            }
+            let report = alloc::format!("{}", with_vfs(|vfs| vfs.status()));
+            let out = unsafe { super::ffi::sqlite3_malloc(report.len() as c_int + 1) } as *mut u8;
+            if out.is_null() {
+                return SQLITE_NOMEM;
+            }
+            unsafe {
+                ptr::copy_nonoverlapping(report.as_ptr(), out, report.len());
+                *out.add(report.len()) = 0;
+                *azArg = out as *mut c_char;
+            }
+            SQLITE_OK
+        }
+        // Set the mapping limit (negative: just query); returns the old one
+        SQLITE_FCNTL_MMAP_SIZE => {
+            let arg = pArg as *mut i64;
+            unsafe {
+                let limit = (*arg).min(MAX_MMAP_SIZE);
+                *arg = (*file).mmap_limit;
+                let busy = (*file).map.as_ref().is_some_and(|m| m.refs > 0);
+                if limit >= 0 && limit != (*file).mmap_limit && !busy {
+                    (*file).mmap_limit = limit;
+                    unmap(file);
+                }
+            }
+            SQLITE_OK
+        }
+        _ => SQLITE_NOTFOUND,
+    }
+}
+
+/// Point `*pp` at `iAmt` bytes of the file at `iOfst`, or leave it null to
+/// have SQLite use xRead: past the mapping limit or the end of the file,
+/// or when the mapping would have to grow while pages are out.
+unsafe extern "C" fn heaven_fetch(
+    pFile: *mut Sqlite3File,
+    iOfst: i64,
+    iAmt: c_int,
+    pp: *mut *mut c_void,
 ) -> c_int {
-    SQLITE_NOTFOUND // We don't handle any FCNTL
+    let file = pFile as *mut HeavenSqliteFile;
+    unsafe { *pp = ptr::null_mut(); }
+    let (limit, length) = unsafe { ((*file).mmap_limit.max(0) as u64, (*file).byte_length) };
+    let end = iOfst as u64 + iAmt as u64;
+    if end > limit.min(length) {
+        return SQLITE_OK;
+    }
+    let Some(map) = (unsafe { map_file(file, end as usize) }) else {
+        return SQLITE_OK;
+    };
+    map.refs += 1;
+    unsafe { *pp = map.data.as_mut_ptr().add(iOfst as usize) as *mut c_void; }
+    SQLITE_OK
+}
+
+/// Release a page from `heaven_fetch`; a null page drops the mapping.
+unsafe extern "C" fn heaven_unfetch(pFile: *mut Sqlite3File, _iOfst: i64, p: *mut c_void) -> c_int {
+    let file = pFile as *mut HeavenSqliteFile;
+    let Some(map) = (unsafe { (*file).map.as_mut() }) else {
+        return SQLITE_OK;
+    };
+    if !p.is_null() {
+        map.refs = map.refs.saturating_sub(1);
+    } else if map.refs == 0 {
+        unsafe { unmap(file) };
+    }
+    SQLITE_OK
+}
+
+/// The handle's mapping, covering at least `want` bytes. It is grown to
+/// the whole file (up to the limit) at once, since it can only move while
+/// no pages are out; None if that can't happen now or the read fails.
+///
+/// # Safety
+/// `file` must be an open disk file handle.
+unsafe fn map_file<'a>(file: *mut HeavenSqliteFile, want: usize) -> Option<&'a mut Mapping> {
+    let file = unsafe { &mut *file };
+    if file.map.is_null() {
+        file.map = Box::into_raw(Box::new(Mapping { data: alloc::vec![0; MAP_PAD], len: 0, refs: 0 }));
+        MAPPED_FILES.fetch_add(1, Ordering::Relaxed);
+    }
+    let map = unsafe { &mut *file.map };
+    if map.len >= want {
+        return Some(map);
+    }
+    if map.refs > 0 {
+        return None;
+    }
+
+    let old = map.len;
+    let new = (file.byte_length.min(file.mmap_limit.max(0) as u64) as usize).max(want);
+    map.data.truncate(old);
+    if map.data.try_reserve_exact(new + MAP_PAD - old).is_err() {
+        map.data.resize(old + MAP_PAD, 0);
+        return None;
+    }
+    map.data.resize(new + MAP_PAD, 0);
+    let hfile = heaven_file_to_vfs_file(file);
+    let mut offset = old;
+    while offset < new {
+        let n = (new - offset).min(MAP_CHUNK);
+        let rc = with_vfs(|vfs| vfs.read(&hfile, &mut map.data[offset..offset + n], offset as u64));
+        if rc != SQLITE_OK {
+            map.data.truncate(old);
+            map.data.resize(old + MAP_PAD, 0);
+            return None;
+        }
+        offset += n;
+    }
+    map.len = new;
+    MAPPED_BYTES.fetch_add(new - old, Ordering::Relaxed);
+    Some(map)
+}
+
+/// Free the handle's mapping, if it has one.
+///
+/// # Safety
+/// `file` must be an open disk file handle with no fetched pages out.
+unsafe fn unmap(file: *mut HeavenSqliteFile) {
+    let map = unsafe { core::mem::take(&mut (*file).map) };
+    if !map.is_null() {
+        let map = unsafe { Box::from_raw(map) };
+        MAPPED_FILES.fetch_sub(1, Ordering::Relaxed);
+        MAPPED_BYTES.fetch_sub(map.len, Ordering::Relaxed);
+    }
 }
 
 unsafe extern "C" fn heaven_sector_size(pFile: *mut Sqlite3File) -> c_int {
@@ -338,8 +683,10 @@ unsafe extern "C" fn heaven_sector_size(pFile: *mut Sqlite3File) -> c_int {
     if bs > 0 { bs as c_int } else { 4096 }
 }
 
-unsafe extern "C" fn heaven_device_characteristics(_pFile: *mut Sqlite3File) -> c_int {
-    0 // No special characteristics
+unsafe extern "C" fn heaven_device_characteristics(pFile: *mut Sqlite3File) -> c_int {
+    let file = pFile as *const HeavenSqliteFile;
+    let bs = unsafe { (*file).block_size };
+    with_vfs(|vfs| vfs.device_characteristics(bs))
 }
 
 unsafe extern "C" fn heaven_delete(
@@ -421,6 +768,121 @@ unsafe extern "C" fn heaven_get_last_error(
     SQLITE_OK
 }
 
+// ---- RAM temp files ----
+
+/// Open a RAM temp file. Temp files are private to their connection and
+/// deleted on close, so there is nothing to lock or sync.
+unsafe fn mem_open(pFile: *mut Sqlite3File, flags: c_int, pOutFlags: *mut c_int) -> c_int {
+    let file = pFile as *mut HeavenSqliteFile;
+    unsafe {
+        (*file).base.pMethods = &MEM_IO_METHODS;
+        (*file).mem = Box::into_raw(Box::new(Vec::new()));
+        if !pOutFlags.is_null() {
+            *pOutFlags = flags;
+        }
+    }
+    TEMP_FILES.fetch_add(1, Ordering::Relaxed);
+    SQLITE_OK
+}
+
+/// The contents of a RAM temp file.
+///
+/// # Safety
+/// `pFile` must be a handle opened by `mem_open` and not yet closed.
+unsafe fn mem_data<'a>(pFile: *mut Sqlite3File) -> &'a mut Vec<u8> {
+    unsafe { &mut *(*(pFile as *mut HeavenSqliteFile)).mem }
+}
+
+/// Resize a RAM file, keeping TEMP_BYTES in step.
+fn mem_resize(data: &mut Vec<u8>, len: usize) {
+    if len > data.len() {
+        TEMP_BYTES.fetch_add(len - data.len(), Ordering::Relaxed);
+    } else {
+        TEMP_BYTES.fetch_sub(data.len() - len, Ordering::Relaxed);
+    }
+    data.resize(len, 0);
+}
+
+unsafe extern "C" fn mem_close(pFile: *mut Sqlite3File) -> c_int {
+    let file = pFile as *mut HeavenSqliteFile;
+    unsafe {
+        let data = Box::from_raw((*file).mem);
+        TEMP_BYTES.fetch_sub(data.len(), Ordering::Relaxed);
+        (*file).mem = ptr::null_mut();
+    }
+    TEMP_FILES.fetch_sub(1, Ordering::Relaxed);
+    SQLITE_OK
+}
+
+unsafe extern "C" fn mem_read(pFile: *mut Sqlite3File, buf: *mut c_void, iAmt: c_int, iOfst: i64) -> c_int {
+    let data = unsafe { mem_data(pFile) };
+    let out = unsafe { core::slice::from_raw_parts_mut(buf as *mut u8, iAmt as usize) };
+    let start = (iOfst as usize).min(data.len());
+    let n = (data.len() - start).min(out.len());
+    out[..n].copy_from_slice(&data[start..start + n]);
+    if n < out.len() {
+        // SQLite requires the unread tail zeroed on a short read
+        out[n..].fill(0);
+        return SQLITE_IOERR_SHORT_READ;
+    }
+    SQLITE_OK
+}
+
+unsafe extern "C" fn mem_write(pFile: *mut Sqlite3File, buf: *const c_void, iAmt: c_int, iOfst: i64) -> c_int {
+    let data = unsafe { mem_data(pFile) };
+    let src = unsafe { core::slice::from_raw_parts(buf as *const u8, iAmt as usize) };
+    let start = iOfst as usize;
+    let end = start + src.len();
+    if end > data.len() {
+        if data.try_reserve(end - data.len()).is_err() {
+            return SQLITE_NOMEM;
+        }
+        mem_resize(data, end);
+    }
+    data[start..end].copy_from_slice(src);
+    SQLITE_OK
+}
+
+unsafe extern "C" fn mem_truncate(pFile: *mut Sqlite3File, size: i64) -> c_int {
+    let data = unsafe { mem_data(pFile) };
+    let size = size.max(0) as usize;
+    if size < data.len() {
+        mem_resize(data, size);
+        data.shrink_to_fit();
+    }
+    SQLITE_OK
+}
+
+unsafe extern "C" fn mem_sync(_pFile: *mut Sqlite3File, _flags: c_int) -> c_int {
+    SQLITE_OK
+}
+
+unsafe extern "C" fn mem_file_size(pFile: *mut Sqlite3File, pSize: *mut i64) -> c_int {
+    unsafe { *pSize = mem_data(pFile).len() as i64 };
+    SQLITE_OK
+}
+
+unsafe extern "C" fn mem_lock(_pFile: *mut Sqlite3File, _level: c_int) -> c_int {
+    SQLITE_OK
+}
+
+unsafe extern "C" fn mem_check_reserved_lock(_pFile: *mut Sqlite3File, pResOut: *mut c_int) -> c_int {
+    unsafe { *pResOut = 0 };
+    SQLITE_OK
+}
+
+unsafe extern "C" fn mem_file_control(_pFile: *mut Sqlite3File, _op: c_int, _pArg: *mut c_void) -> c_int {
+    SQLITE_NOTFOUND
+}
+
+unsafe extern "C" fn mem_sector_size(_pFile: *mut Sqlite3File) -> c_int {
+    4096
+}
+
+unsafe extern "C" fn mem_device_characteristics(_pFile: *mut Sqlite3File) -> c_int {
+    0
+}
+
 // ---- Helper: convert HeavenSqliteFile fields → HeavenFile ----
 
 use crate::vfs::sqlite_vfs::HeavenFile;
@@ -432,5 +894,6 @@ fn heaven_file_to_vfs_file(file: &HeavenSqliteFile) -> HeavenFile {
         block_count: file.block_count,
         byte_length: file.byte_length,
         block_size: file.block_size,
+        chunk_blocks: file.chunk_blocks,
     }
 }
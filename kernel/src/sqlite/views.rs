@@ -0,0 +1,73 @@
+/// Helper views over the audit log and the change journal.
+///
+///   tool_inputs          one row per top-level field of each journaled
+///                        tool call's input (`json_each` over changes.input)
+///   token_usage          the agent's per-turn API_USAGE audit rows, with
+///                        the JSON detail pulled apart (`json_extract`)
+///   conversation_usage   token_usage summed per conversation, plus the
+///                        paths it wrote as a JSON array; saved_*_tokens
+///                        are the tokens' worth of price discounts
+///                        (batches bill half)
+///
+/// They are plain SQL, so `sql SELECT * FROM conversation_usage` works from
+/// the shell and from Lua. They need the JSON1 functions, so they exist
+/// only with the `sqlite-json` feature (on by default); `create` still
+/// fails early if the functions are missing rather than leaving views
+/// that error on every read.
+use alloc::string::String;
+
+use super::SqliteDb;
+
+/// (name, SELECT) for every view.
+const VIEWS: &[(&str, &str)] = &[
+    (
+        "tool_inputs",
+        "SELECT c.id AS change_id, c.ts, c.conversation, c.tool_use_id, c.tool, c.path, \
+                j.key, j.type, j.value \
+         FROM changes AS c, json_each(c.input) AS j \
+         WHERE json_valid(c.input)",
+    ),
+    (
+        "token_usage",
+        "SELECT id, ts, agent, \
+                json_extract(detail, '$.conversation') AS conversation, \
+                json_extract(detail, '$.model') AS model, \
+                json_extract(detail, '$.turn') AS turn, \
+                json_extract(detail, '$.input_tokens') AS input_tokens, \
+                json_extract(detail, '$.output_tokens') AS output_tokens, \
+                ifnull(json_extract(detail, '$.cache_write_tokens'), 0) AS cache_write_tokens, \
+                ifnull(json_extract(detail, '$.cache_read_tokens'), 0) AS cache_read_tokens, \
+                ifnull(json_extract(detail, '$.discount_percent'), 0) AS discount_percent \
+         FROM audit \
+         WHERE action = 'API_USAGE' AND json_valid(detail)",
+    ),
+    (
+        "conversation_usage",
+        "SELECT u.conversation, min(u.ts) AS started, max(u.ts) AS last, \
+                count(*) AS turns, group_concat(DISTINCT u.model) AS models, \
+                sum(u.input_tokens) AS input_tokens, sum(u.output_tokens) AS output_tokens, \
+                sum(u.cache_write_tokens) AS cache_write_tokens, sum(u.cache_read_tokens) AS cache_read_tokens, \
+                sum(u.input_tokens * u.discount_percent) / 100 AS saved_input_tokens, \
+                sum(u.output_tokens * u.discount_percent) / 100 AS saved_output_tokens, \
+                (SELECT json_group_array(DISTINCT c.path) FROM changes AS c \
+                 WHERE c.conversation = u.conversation) AS paths \
+         FROM token_usage AS u \
+         GROUP BY u.conversation",
+    ),
+];
+
+/// Check the JSON functions and (re)create the views. Views are dropped
+/// first so a changed definition replaces the stored one.
+pub(super) fn create(db: &SqliteDb) -> Result<(), String> {
+    let probe = db
+        .query_value("SELECT json_extract('{\"a\":[1,2]}', '$.a[1]')")
+        .map_err(|e| alloc::format!("JSON1 functions unavailable: {}", e))?;
+    if probe.as_deref() != Some("2") {
+        return Err(String::from("SQLite built without JSON1 functions"));
+    }
+    for (name, select) in VIEWS {
+        db.exec(&alloc::format!("DROP VIEW IF EXISTS {}", name))?;
+        db.exec(&alloc::format!("CREATE VIEW {} AS {}", name, select))?;
+    }
+    Ok(())
+}
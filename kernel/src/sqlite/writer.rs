@@ -0,0 +1,191 @@
+/// The writer queue and SQLITE_BUSY handling.
+///
+/// `DB` is a `Writer`: the one read-write connection behind a ticket lock,
+/// so callers get it in the order they asked for it instead of whoever
+/// spins luckiest. `lock()` counts the callers waiting and how long each
+/// waited.
+///
+/// Every connection also gets `busy_handler` in place of a plain busy
+/// timeout: when another connection holds a lock SQLite needs, it retries
+/// with the same backoff as sqlite3_busy_timeout, up to `BUSY_TIMEOUT_MS`,
+/// and counts each episode, its retries and its wait, and those that gave
+/// up with SQLITE_BUSY. `db info` reports both.
+use core::ffi::{c_int, c_void};
+use core::fmt;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use spin::mutex::{TicketMutex, TicketMutexGuard};
+
+use super::ffi::sqlite3;
+use super::SqliteDb;
+use crate::time;
+
+/// How long a connection retries a locked database before SQLITE_BUSY.
+const BUSY_TIMEOUT_MS: u64 = 2000;
+
+/// Sleep before each retry, as sqlite3_busy_timeout does; the last repeats.
+const BUSY_DELAYS_MS: [u64; 12] = [1, 2, 5, 10, 15, 20, 25, 25, 25, 50, 50, 100];
+
+extern "C" {
+    fn sqlite3_busy_handler(
+        db: *mut sqlite3,
+        handler: Option<unsafe extern "C" fn(*mut c_void, c_int) -> c_int>,
+        arg: *mut c_void,
+    ) -> c_int;
+}
+
+/// The read-write connection, handed out first come, first served.
+pub struct Writer {
+    conn: TicketMutex<Option<SqliteDb>>,
+    /// Callers in `lock` now.
+    waiting: AtomicUsize,
+    acquired: AtomicU64,
+    waited: AtomicU64,
+    wait_ms: AtomicU64,
+    max_wait_ms: AtomicU64,
+    max_depth: AtomicUsize,
+}
+
+/// Writer queue counters, for `db info`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct QueueStats {
+    /// Callers waiting now.
+    pub depth: usize,
+    pub max_depth: usize,
+    pub acquired: u64,
+    /// Of those, how many found the writer in use...
+    pub waited: u64,
+    /// ...and how long they waited, in total and at worst.
+    pub wait_ms: u64,
+    pub max_wait_ms: u64,
+}
+
+impl fmt::Display for QueueStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} waiting (max {}), {} of {} locks waited, {} ms total, {} ms max",
+            self.depth, self.max_depth, self.waited, self.acquired, self.wait_ms, self.max_wait_ms,
+        )
+    }
+}
+
+impl Writer {
+    pub const fn new() -> Self {
+        Writer {
+            conn: TicketMutex::new(None),
+            waiting: AtomicUsize::new(0),
+            acquired: AtomicU64::new(0),
+            waited: AtomicU64::new(0),
+            wait_ms: AtomicU64::new(0),
+            max_wait_ms: AtomicU64::new(0),
+            max_depth: AtomicUsize::new(0),
+        }
+    }
+
+    /// The connection, once every earlier caller is done with it.
+    pub fn lock(&self) -> TicketMutexGuard<'_, Option<SqliteDb>> {
+        self.acquired.fetch_add(1, Ordering::Relaxed);
+        if let Some(guard) = self.conn.try_lock() {
+            return guard;
+        }
+        let depth = self.waiting.fetch_add(1, Ordering::Relaxed) + 1;
+        self.max_depth.fetch_max(depth, Ordering::Relaxed);
+        let start = time::monotonic_ms();
+        let guard = self.conn.lock();
+        let waited = time::monotonic_ms().saturating_sub(start);
+        self.waiting.fetch_sub(1, Ordering::Relaxed);
+        self.waited.fetch_add(1, Ordering::Relaxed);
+        self.wait_ms.fetch_add(waited, Ordering::Relaxed);
+        self.max_wait_ms.fetch_max(waited, Ordering::Relaxed);
+        guard
+    }
+
+    /// The connection if nobody holds it or is queued for it.
+    pub fn try_lock(&self) -> Option<TicketMutexGuard<'_, Option<SqliteDb>>> {
+        let guard = self.conn.try_lock()?;
+        self.acquired.fetch_add(1, Ordering::Relaxed);
+        Some(guard)
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        QueueStats {
+            depth: self.waiting.load(Ordering::Relaxed),
+            max_depth: self.max_depth.load(Ordering::Relaxed),
+            acquired: self.acquired.load(Ordering::Relaxed),
+            waited: self.waited.load(Ordering::Relaxed),
+            wait_ms: self.wait_ms.load(Ordering::Relaxed),
+            max_wait_ms: self.max_wait_ms.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Default for Writer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// SQLITE_BUSY counters across all connections, for `db info`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BusyStats {
+    /// Times a statement found the database locked.
+    pub episodes: u64,
+    pub retries: u64,
+    /// Episodes that ran out of time and returned SQLITE_BUSY.
+    pub timeouts: u64,
+    /// Time spent sleeping between retries.
+    pub wait_ms: u64,
+}
+
+impl fmt::Display for BusyStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} locked, {} retries, {} ms waited, {} gave up after {} ms",
+            self.episodes, self.retries, self.wait_ms, self.timeouts, BUSY_TIMEOUT_MS,
+        )
+    }
+}
+
+static BUSY_EPISODES: AtomicU64 = AtomicU64::new(0);
+static BUSY_RETRIES: AtomicU64 = AtomicU64::new(0);
+static BUSY_TIMEOUTS: AtomicU64 = AtomicU64::new(0);
+static BUSY_WAIT_MS: AtomicU64 = AtomicU64::new(0);
+
+pub fn busy_stats() -> BusyStats {
+    BusyStats {
+        episodes: BUSY_EPISODES.load(Ordering::Relaxed),
+        retries: BUSY_RETRIES.load(Ordering::Relaxed),
+        timeouts: BUSY_TIMEOUTS.load(Ordering::Relaxed),
+        wait_ms: BUSY_WAIT_MS.load(Ordering::Relaxed),
+    }
+}
+
+/// Install `busy_handler` on a connection.
+pub(super) unsafe fn install(db: *mut sqlite3) {
+    unsafe { sqlite3_busy_handler(db, Some(busy_handler), core::ptr::null_mut()); }
+}
+
+/// Called by SQLite with the number of earlier calls for the same lock
+/// attempt. Sleep and return 1 to retry, or 0 to give up with SQLITE_BUSY.
+unsafe extern "C" fn busy_handler(_arg: *mut c_void, count: c_int) -> c_int {
+    let count = count.max(0) as usize;
+    if count == 0 {
+        BUSY_EPISODES.fetch_add(1, Ordering::Relaxed);
+    }
+    let last = BUSY_DELAYS_MS.len() - 1;
+    let slept: u64 = if count <= last {
+        BUSY_DELAYS_MS[..count].iter().sum()
+    } else {
+        BUSY_DELAYS_MS.iter().sum::<u64>() + BUSY_DELAYS_MS[last] * (count - last - 1) as u64
+    };
+    let delay = BUSY_DELAYS_MS[count.min(last)].min(BUSY_TIMEOUT_MS.saturating_sub(slept));
+    if delay == 0 {
+        BUSY_TIMEOUTS.fetch_add(1, Ordering::Relaxed);
+        return 0;
+    }
+    time::delay(time::Duration::from_millis(delay));
+    BUSY_RETRIES.fetch_add(1, Ordering::Relaxed);
+    BUSY_WAIT_MS.fetch_add(delay, Ordering::Relaxed);
+    1
+}
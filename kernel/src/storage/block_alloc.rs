@@ -5,20 +5,31 @@
 ///   LBA 1..M:      Bitmap (1 bit per data block: 0=free, 1=used)
 ///   LBA M+1..M+K:  File table (fixed-size entries)
 ///   LBA M+K+1..:   Data blocks
+///   LBA N/2:       Backup superblock (reserved in the bitmap)
+///   LBA N-1:       Backup superblock (outside the data region)
 ///
 /// The bitmap and file table are cached in RAM and flushed to disk on sync.
+/// `load` checks every superblock copy and rewrites any that are damaged
+/// from a valid one, so a single bad LBA 0 no longer loses the filesystem.
 use alloc::vec;
 use alloc::vec::Vec;
 
 use crate::drivers::nvme::NvmeError;
 use crate::mem::DmaBuf;
 use super::block_device::BlockDevice;
+use super::crypt::{Conversion, CryptHeader};
 
 /// Superblock magic: "HVNOS\x01\x00\x00" in little-endian.
 const SUPERBLOCK_MAGIC: u64 = 0x0000_01_534F4E5648; // "HVNOS\x01"
 
-/// Superblock version.
-const SUPERBLOCK_VERSION: u32 = 1;
+/// Superblock version. Version 2 adds the checksum and backup copies.
+const SUPERBLOCK_VERSION: u32 = 2;
+
+/// Legacy superblock version — no checksum, no backups. Still loadable.
+const SUPERBLOCK_VERSION_V1: u32 = 1;
+
+/// Byte offset of `Superblock::checksum` — the checksum covers everything before it.
+const SUPERBLOCK_CHECKSUM_OFFSET: usize = 72;
 
 /// On-disk superblock at LBA 0.
 #[repr(C)]
@@ -34,16 +45,164 @@ pub struct Superblock {
     pub file_table_block_count: u64,
     pub data_start_lba: u64,      // first usable data LBA
     pub data_block_count: u64,    // number of data blocks
-    _padding: [u8; 4008],         // pad to 4096 bytes
+    pub checksum: u64,            // FNV-1a over the fields above (v2+)
+    pub crypt: CryptHeader,       // disk key, wrapped (all zeros: not encrypted)
+    pub conversion: Conversion,   // encryption being turned on or off (all zeros: none)
+    _padding: [u8; 3592],         // pad to 4096 bytes
 }
 
 static_assertions::const_assert!(core::mem::size_of::<Superblock>() <= 4096);
+static_assertions::const_assert_eq!(
+    core::mem::offset_of!(Superblock, checksum),
+    SUPERBLOCK_CHECKSUM_OFFSET
+);
 
 impl Superblock {
-    /// Check if this superblock has valid magic.
+    /// Check if this superblock has valid magic, version and checksum.
     pub fn is_valid(&self) -> bool {
-        self.magic == SUPERBLOCK_MAGIC && self.version == SUPERBLOCK_VERSION
+        if self.magic != SUPERBLOCK_MAGIC {
+            return false;
+        }
+        match self.version {
+            SUPERBLOCK_VERSION => self.checksum == self.compute_checksum(),
+            SUPERBLOCK_VERSION_V1 => true,
+            _ => false,
+        }
+    }
+
+    /// Whether this superblock layout reserves backup copies.
+    pub fn has_backups(&self) -> bool {
+        self.version >= SUPERBLOCK_VERSION
+    }
+
+    /// FNV-1a 64 over the header bytes preceding the checksum field.
+    pub fn compute_checksum(&self) -> u64 {
+        fnv1a64(&self.as_bytes()[..SUPERBLOCK_CHECKSUM_OFFSET])
+    }
+
+    /// Whether two superblocks describe the same geometry, encryption and
+    /// conversion (ignores padding).
+    fn same_header(&self, other: &Superblock) -> bool {
+        let len = core::mem::offset_of!(Superblock, _padding);
+        self.as_bytes()[..len] == other.as_bytes()[..len]
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        unsafe {
+            core::slice::from_raw_parts(
+                self as *const Superblock as *const u8,
+                core::mem::size_of::<Superblock>(),
+            )
+        }
+    }
+}
+
+/// FNV-1a 64-bit hash — cheap corruption detection for on-disk metadata.
+pub(super) fn fnv1a64(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &b in bytes {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash
+}
+
+/// Fixed LBAs of the backup superblocks for a namespace of `total_blocks`:
+/// one in the middle of the disk and one in the last block.
+pub fn backup_superblock_lbas(total_blocks: u64) -> [u64; 2] {
+    [total_blocks / 2, total_blocks - 1]
+}
+
+/// Write a superblock copy to `lba`.
+fn write_superblock(
+    dev: &mut dyn BlockDevice,
+    lba: u64,
+    sb: &Superblock,
+    block_size: u32,
+) -> Result<(), NvmeError> {
+    let len = (block_size as usize).max(core::mem::size_of::<Superblock>());
+    let mut buf = DmaBuf::alloc(len).map_err(|_| NvmeError::OutOfMemory)?;
+    buf.copy_from_slice(sb.as_bytes());
+    dev.write_blocks(lba, 1, &buf)
+}
+
+/// Read the superblock copy at `lba`. Returns `None` if it fails validation
+/// or the read itself fails — a bad copy is not fatal while others exist.
+fn read_superblock(dev: &mut dyn BlockDevice, lba: u64, block_size: u32) -> Option<Superblock> {
+    let len = (block_size as usize).max(core::mem::size_of::<Superblock>());
+    let mut buf = DmaBuf::alloc(len).ok()?;
+    dev.read_blocks(lba, 1, &mut buf).ok()?;
+    let sb = unsafe { core::ptr::read_unaligned(buf.as_ptr() as *const Superblock) };
+    if sb.is_valid() { Some(sb) } else { None }
+}
+
+/// The superblock chosen, then the primary and the backups as read.
+type SuperblockCopies = (Superblock, Option<Superblock>, [Option<Superblock>; 2]);
+
+/// Read every superblock copy and pick the first valid one. Returns it with
+/// the primary and the backups as read (None where damaged); fails with
+/// `MediaError` if the device isn't formatted.
+fn choose_superblock(dev: &mut dyn BlockDevice) -> Result<SuperblockCopies, NvmeError> {
+    let block_size = dev.block_size();
+    let total_blocks = dev.total_blocks();
+
+    let primary = read_superblock(dev, 0, block_size);
+    let mut backups = [None, None];
+    for (slot, lba) in backups.iter_mut().zip(backup_superblock_lbas(total_blocks)) {
+        // A backup is only trustworthy if it describes this device.
+        *slot = read_superblock(dev, lba, block_size)
+            .filter(|sb| sb.has_backups() && sb.total_blocks == total_blocks);
+    }
+
+    match primary.or(backups[0]).or(backups[1]) {
+        Some(sb) => Ok((sb, primary, backups)),
+        None => Err(NvmeError::MediaError), // Not formatted
+    }
+}
+
+/// The encryption header of a formatted device — `CryptHeader::NONE` if
+/// it isn't encrypted. Read before `load`, to know whether a key is needed.
+pub fn crypt_header(dev: &mut dyn BlockDevice) -> Result<CryptHeader, NvmeError> {
+    choose_superblock(dev).map(|(sb, _, _)| sb.crypt)
+}
+
+/// Can this device be encrypted? Only a v2 layout reserves the backup
+/// superblock LBAs that `crypt::Device` leaves in the clear.
+pub fn supports_encryption(dev: &mut dyn BlockDevice) -> Result<bool, NvmeError> {
+    let total_blocks = dev.total_blocks();
+    choose_superblock(dev).map(|(sb, _, _)| sb.has_backups() && sb.total_blocks == total_blocks)
+}
+
+/// The encryption header and the conversion under way, if any.
+pub fn conversion(dev: &mut dyn BlockDevice) -> Result<(CryptHeader, Conversion), NvmeError> {
+    choose_superblock(dev).map(|(sb, _, _)| (sb.crypt, sb.conversion))
+}
+
+/// Store `header` in every superblock copy and flush.
+pub fn set_crypt_header(dev: &mut dyn BlockDevice, header: &CryptHeader) -> Result<(), NvmeError> {
+    set_conversion(dev, header, &Conversion::NONE)
+}
+
+/// Store `header` and `conversion` in every superblock copy, primary
+/// first, and flush.
+pub fn set_conversion(
+    dev: &mut dyn BlockDevice,
+    header: &CryptHeader,
+    conversion: &Conversion,
+) -> Result<(), NvmeError> {
+    let block_size = dev.block_size();
+    let total_blocks = dev.total_blocks();
+    if !supports_encryption(dev)? {
+        return Err(NvmeError::MediaError);
     }
+    let (mut sb, _, _) = choose_superblock(dev)?;
+    sb.crypt = *header;
+    sb.conversion = *conversion;
+    write_superblock(dev, 0, &sb, block_size)?;
+    for lba in backup_superblock_lbas(total_blocks) {
+        write_superblock(dev, lba, &sb, block_size)?;
+    }
+    dev.flush()
 }
 
 /// In-memory block allocator, backed by the on-disk bitmap.
@@ -56,6 +215,8 @@ pub struct BlockAllocator {
     block_size: u32,
     free_count: u64,
     dirty: bool,
+    superblock_repairs: u32,      // copies rewritten by the last `load`
+    deferred: Vec<(u64, u64)>,    // freed, but reusable only after `release_deferred`
 }
 
 impl BlockAllocator {
@@ -70,6 +231,8 @@ impl BlockAllocator {
             block_size: 4096,
             free_count: 0,
             dirty: false,
+            superblock_repairs: 0,
+            deferred: Vec::new(),
         }
     }
 
@@ -85,10 +248,12 @@ impl BlockAllocator {
         self.block_size = block_size;
         self.free_count = data_blocks;
         self.dirty = false;
+        self.superblock_repairs = 0;
+        self.deferred.clear();
     }
 
-    /// Format a blank NVMe namespace — write superblock, zeroed bitmap,
-    /// zeroed file table.
+    /// Format a blank NVMe namespace — write superblock and its backups,
+    /// zeroed bitmap, zeroed file table.
     pub fn format(
         dev: &mut dyn BlockDevice,
         total_blocks: u64,
@@ -101,16 +266,16 @@ impl BlockAllocator {
         // But data_blocks depends on bitmap size... iterate to fixed point.
         let overhead = 1u64; // superblock
         let file_table_blocks = 1u64; // one block for file table (fits ~50 entries)
+        let tail_reserved = 1u64; // end-of-disk backup superblock
 
         // First approximation: all blocks are data
-        let approx_data = total_blocks - overhead - file_table_blocks;
+        let approx_data = total_blocks - overhead - file_table_blocks - tail_reserved;
         let bitmap_blocks = (approx_data + data_bits_per_block - 1) / data_bits_per_block;
 
         let data_start = overhead + bitmap_blocks + file_table_blocks;
-        let data_blocks = total_blocks.saturating_sub(data_start);
+        let data_blocks = total_blocks.saturating_sub(data_start + tail_reserved);
 
-        // Write superblock
-        let sb = Superblock {
+        let mut sb = Superblock {
             magic: SUPERBLOCK_MAGIC,
             version: SUPERBLOCK_VERSION,
             block_size,
@@ -121,61 +286,94 @@ impl BlockAllocator {
             file_table_block_count: file_table_blocks,
             data_start_lba: data_start,
             data_block_count: data_blocks,
-            _padding: [0u8; 4008],
-        };
-
-        let mut buf = DmaBuf::alloc(block_size as usize)
-            .map_err(|_| NvmeError::OutOfMemory)?;
-        let sb_bytes = unsafe {
-            core::slice::from_raw_parts(
-                &sb as *const Superblock as *const u8,
-                core::mem::size_of::<Superblock>(),
-            )
+            checksum: 0,
+            crypt: CryptHeader::NONE,
+            conversion: Conversion::NONE,
+            _padding: [0u8; 3592],
         };
-        buf.copy_from_slice(sb_bytes);
-        dev.write_blocks(0, 1, &buf)?;
+        sb.checksum = sb.compute_checksum();
 
-        // Write zeroed bitmap (all free)
-        let zero_buf = DmaBuf::alloc(block_size as usize)
-            .map_err(|_| NvmeError::OutOfMemory)?;
-        for i in 0..bitmap_blocks {
-            dev.write_blocks(1 + i, 1, &zero_buf)?;
+        // Write primary superblock, then the backups
+        write_superblock(dev, 0, &sb, block_size)?;
+        for lba in backup_superblock_lbas(total_blocks) {
+            write_superblock(dev, lba, &sb, block_size)?;
         }
 
         // Write zeroed file table
+        let zero_buf = DmaBuf::alloc(block_size as usize)
+            .map_err(|_| NvmeError::OutOfMemory)?;
         dev.write_blocks(1 + bitmap_blocks, 1, &zero_buf)?;
 
-        // Flush to make everything durable
-        dev.flush()?;
-
-        // Build in-memory state
+        // Build in-memory state: all free except the mid-disk backup
         let bitmap_words = ((data_blocks + 63) / 64) as usize;
-        let allocator = Self {
-            bitmap: vec![0u64; bitmap_words], // all free
+        let mut allocator = Self {
+            bitmap: vec![0u64; bitmap_words],
             data_block_count: data_blocks,
             data_start_lba: data_start,
             bitmap_start_lba: 1,
             bitmap_on_disk_blocks: bitmap_blocks,
             block_size,
             free_count: data_blocks,
-            dirty: false,
+            dirty: true,
+            superblock_repairs: 0,
+            deferred: Vec::new(),
         };
+        allocator.reserve_backup_blocks(total_blocks);
+
+        // Write the bitmap and flush to make everything durable
+        allocator.flush(dev)?;
+        dev.flush()?;
 
         Ok(allocator)
     }
 
+    /// Mark backup superblock LBAs that fall inside the data region as used.
+    fn reserve_backup_blocks(&mut self, total_blocks: u64) {
+        for lba in backup_superblock_lbas(total_blocks) {
+            if lba < self.data_start_lba || lba >= self.data_start_lba + self.data_block_count {
+                continue;
+            }
+            let idx = lba - self.data_start_lba;
+            let word = (idx / 64) as usize;
+            let bit = (idx % 64) as u32;
+            if self.bitmap[word] & (1u64 << bit) == 0 {
+                self.bitmap[word] |= 1u64 << bit;
+                self.free_count -= 1;
+                self.dirty = true;
+            }
+        }
+    }
+
     /// Load an existing allocator from a formatted NVMe namespace.
+    ///
+    /// The primary superblock and both backups are checked. The first valid
+    /// copy wins; every other copy that is damaged (or stale) is rewritten
+    /// from it and flushed before returning.
+    ///
+    /// On an encrypted disk, `dev` must be a `crypt::Device` with the key.
     pub fn load(dev: &mut dyn BlockDevice) -> Result<Self, NvmeError> {
-        // Read superblock
         let block_size = dev.block_size();
-
-        let mut buf = DmaBuf::alloc(block_size as usize)
-            .map_err(|_| NvmeError::OutOfMemory)?;
-        dev.read_blocks(0, 1, &mut buf)?;
-
-        let sb = unsafe { &*(buf.as_ptr() as *const Superblock) };
-        if !sb.is_valid() {
-            return Err(NvmeError::MediaError); // Not formatted
+        let total_blocks = dev.total_blocks();
+        let backup_lbas = backup_superblock_lbas(total_blocks);
+        let (sb, primary, backups) = choose_superblock(dev)?;
+
+        // Repair: rewrite any copy that doesn't match the chosen superblock.
+        // Legacy (v1) layouts never reserved the backup LBAs, so leave them be.
+        let mut repairs = 0u32;
+        if !primary.as_ref().is_some_and(|p| p.same_header(&sb)) {
+            write_superblock(dev, 0, &sb, block_size)?;
+            repairs += 1;
+        }
+        if sb.has_backups() && sb.total_blocks == total_blocks {
+            for (i, &lba) in backup_lbas.iter().enumerate() {
+                if !backups[i].as_ref().is_some_and(|b| b.same_header(&sb)) {
+                    write_superblock(dev, lba, &sb, block_size)?;
+                    repairs += 1;
+                }
+            }
+        }
+        if repairs > 0 {
+            dev.flush()?;
         }
 
         // Read bitmap from disk into memory
@@ -224,6 +422,8 @@ impl BlockAllocator {
             block_size,
             free_count,
             dirty: false,
+            superblock_repairs: repairs,
+            deferred: Vec::new(),
         })
     }
 
@@ -288,6 +488,29 @@ impl BlockAllocator {
         self.dirty = true;
     }
 
+    /// Free blocks that the on-disk file table may still reference.
+    ///
+    /// They stay marked used — so no other file can overwrite them — until
+    /// `release_deferred` is called once the file table that no longer
+    /// references them is durable. A crash in between only leaks them.
+    pub fn free_deferred(&mut self, start: u64, count: u64) {
+        if count > 0 {
+            self.deferred.push((start, count));
+        }
+    }
+
+    /// Actually free everything passed to `free_deferred` so far.
+    pub fn release_deferred(&mut self) {
+        for (start, count) in core::mem::take(&mut self.deferred) {
+            self.free(start, count);
+        }
+    }
+
+    /// Blocks waiting in `free_deferred`.
+    pub fn deferred_count(&self) -> u64 {
+        self.deferred.iter().map(|&(_, count)| count).sum()
+    }
+
     /// Convert a data-block index to an absolute LBA.
     pub fn to_lba(&self, data_block: u64) -> u64 {
         self.data_start_lba + data_block
@@ -339,6 +562,23 @@ impl BlockAllocator {
     pub fn data_start_lba(&self) -> u64 {
         self.data_start_lba
     }
+
+    pub fn data_block_count(&self) -> u64 {
+        self.data_block_count
+    }
+
+    /// Whether data-block index `idx` is marked used in the bitmap.
+    pub fn is_allocated(&self, idx: u64) -> bool {
+        if idx >= self.data_block_count {
+            return false;
+        }
+        self.bitmap[(idx / 64) as usize] & (1u64 << (idx % 64)) != 0
+    }
+
+    /// Number of superblock copies rewritten during `load`.
+    pub fn superblock_repairs(&self) -> u32 {
+        self.superblock_repairs
+    }
 }
 
 /// Block allocation errors.
@@ -22,3 +22,27 @@ pub trait BlockDevice {
     /// Total number of blocks on device.
     fn total_blocks(&self) -> u64;
 }
+
+/// A borrowed device, so layers that own the device below them
+/// (`cache::Device`) can also sit on one they don't.
+impl<T: BlockDevice + ?Sized> BlockDevice for &mut T {
+    fn read_blocks(&mut self, lba: u64, block_count: u16, buf: &mut DmaBuf) -> Result<(), NvmeError> {
+        (**self).read_blocks(lba, block_count, buf)
+    }
+
+    fn write_blocks(&mut self, lba: u64, block_count: u16, buf: &DmaBuf) -> Result<(), NvmeError> {
+        (**self).write_blocks(lba, block_count, buf)
+    }
+
+    fn flush(&mut self) -> Result<(), NvmeError> {
+        (**self).flush()
+    }
+
+    fn block_size(&self) -> u32 {
+        (**self).block_size()
+    }
+
+    fn total_blocks(&self) -> u64 {
+        (**self).total_blocks()
+    }
+}
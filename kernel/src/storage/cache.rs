@@ -0,0 +1,279 @@
+/// Read-ahead and write coalescing between the VFS and the block device.
+///
+/// SQLite reads and writes one page at a time, so a table scan costs one
+/// NVMe command per page and a commit one per dirty page. `BlockCache`
+/// keeps two kinds of blocks in RAM, by LBA:
+///
+/// - Read-ahead: once reads are sequential (one starts where the last
+///   ended), a miss reads `readahead` more blocks than asked for, and the
+///   reads that follow are served from RAM. Only the latest window is kept.
+/// - Dirty: writes are held instead of submitted, and written back at the
+///   next `flush` — runs of adjacent blocks as one command each — before
+///   the device's own flush. Reads see them.
+///
+/// Holding writes until the flush is what the device's volatile write
+/// cache may do anyway, so nothing SQLite relies on changes: each xSync
+/// barrier still puts the data before the bitmap before the file table.
+/// More than `MAX_DIRTY` held blocks are written back early.
+///
+/// A flush with nothing written since the last one is not passed on, so an
+/// xSync after a read-only transaction costs no NVMe command at all.
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::drivers::nvme::NvmeError;
+use crate::mem::DmaBuf;
+use super::block_device::BlockDevice;
+
+/// Blocks read ahead when `storage.readahead` is unset.
+pub const DEFAULT_READAHEAD: u16 = 32;
+
+/// Largest `storage.readahead`.
+pub const MAX_READAHEAD: u16 = 256;
+
+/// Dirty blocks held before an early write-back.
+const MAX_DIRTY: usize = 256;
+
+/// Counters, for `PRAGMA heaven_status`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CacheStats {
+    /// Reads served entirely from RAM.
+    pub hits: u64,
+    pub misses: u64,
+    /// Blocks read ahead of a sequential reader.
+    pub prefetched: u64,
+    /// Blocks written by the VFS...
+    pub written: u64,
+    /// ...and the write commands they took.
+    pub submitted: u64,
+    /// Device flushes issued, and flushes skipped as nothing was written.
+    pub flushes: u64,
+    pub flushes_skipped: u64,
+}
+
+impl fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "hits={} misses={} prefetched={} written={} submitted={} flushes={} flushes_skipped={}",
+            self.hits, self.misses, self.prefetched, self.written, self.submitted,
+            self.flushes, self.flushes_skipped,
+        )
+    }
+}
+
+pub struct BlockCache {
+    /// The current read-ahead window.
+    clean: BTreeMap<u64, Vec<u8>>,
+    /// Written, not yet submitted.
+    dirty: BTreeMap<u64, Vec<u8>>,
+    /// The LBA just past the last read, to spot sequential access.
+    next_read: u64,
+    /// Written to the device since its last flush.
+    unflushed: bool,
+    readahead: u16,
+    stats: CacheStats,
+}
+
+impl Default for BlockCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BlockCache {
+    pub const fn new() -> Self {
+        Self {
+            clean: BTreeMap::new(),
+            dirty: BTreeMap::new(),
+            next_read: u64::MAX,
+            // Whatever ran before the cache existed may not have flushed
+            unflushed: true,
+            readahead: DEFAULT_READAHEAD,
+            stats: CacheStats {
+                hits: 0,
+                misses: 0,
+                prefetched: 0,
+                written: 0,
+                submitted: 0,
+                flushes: 0,
+                flushes_skipped: 0,
+            },
+        }
+    }
+
+    /// Blocks to read ahead of a sequential reader (0: none).
+    pub fn set_readahead(&mut self, blocks: u16) {
+        self.readahead = blocks.min(MAX_READAHEAD);
+        if blocks == 0 {
+            self.clean.clear();
+        }
+    }
+
+    pub fn readahead(&self) -> u16 {
+        self.readahead
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    /// Blocks waiting for write-back.
+    pub fn dirty_count(&self) -> usize {
+        self.dirty.len()
+    }
+
+    /// Forget the read-ahead window, for I/O that went around the cache,
+    /// and flush at the next chance in case it wrote. Dirty blocks stay.
+    pub fn invalidate(&mut self) {
+        self.clean.clear();
+        self.next_read = u64::MAX;
+        self.unflushed = true;
+    }
+
+    /// Would a flush have anything to do?
+    pub fn needs_flush(&self) -> bool {
+        self.unflushed || !self.dirty.is_empty()
+    }
+
+    fn cached(&self, lba: u64) -> Option<&Vec<u8>> {
+        self.dirty.get(&lba).or_else(|| self.clean.get(&lba))
+    }
+
+    /// Copy held writes over `count` blocks just read from the medium.
+    fn overlay_dirty(&self, lba: u64, data: &mut [u8], count: u16, bs: usize) {
+        for (i, b) in (lba..lba + u64::from(count)).enumerate() {
+            if let Some(block) = self.dirty.get(&b) {
+                data[i * bs..(i + 1) * bs].copy_from_slice(block);
+            }
+        }
+    }
+
+    /// Submit every dirty block, adjacent ones as one command. A block is
+    /// forgotten once its command succeeds, so a failure leaves the rest
+    /// for the next try.
+    fn write_back(&mut self, dev: &mut dyn BlockDevice) -> Result<(), NvmeError> {
+        let bs = dev.block_size() as usize;
+        let mut runs: Vec<(u64, u16)> = Vec::new();
+        for &lba in self.dirty.keys() {
+            match runs.last_mut() {
+                Some((start, len)) if *start + u64::from(*len) == lba && *len < u16::MAX => *len += 1,
+                _ => runs.push((lba, 1)),
+            }
+        }
+        for (start, len) in runs {
+            let mut buf = DmaBuf::alloc(usize::from(len) * bs).map_err(|_| NvmeError::OutOfMemory)?;
+            for (i, lba) in (start..start + u64::from(len)).enumerate() {
+                buf.as_mut_slice()[i * bs..(i + 1) * bs].copy_from_slice(&self.dirty[&lba]);
+            }
+            dev.write_blocks(start, len, &buf)?;
+            self.stats.submitted += 1;
+            self.unflushed = true;
+            for lba in start..start + u64::from(len) {
+                self.dirty.remove(&lba);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A block device seen through a `BlockCache`.
+pub struct Device<'a, D: BlockDevice> {
+    dev: D,
+    cache: &'a mut BlockCache,
+}
+
+impl<'a, D: BlockDevice> Device<'a, D> {
+    pub fn new(dev: D, cache: &'a mut BlockCache) -> Self {
+        Device { dev, cache }
+    }
+
+    /// Submit the held writes without flushing the device.
+    pub fn write_back(&mut self) -> Result<(), NvmeError> {
+        self.cache.write_back(&mut self.dev)
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for Device<'_, D> {
+    fn read_blocks(&mut self, lba: u64, block_count: u16, buf: &mut DmaBuf) -> Result<(), NvmeError> {
+        let bs = self.dev.block_size() as usize;
+        let count = u64::from(block_count);
+        let sequential = lba == self.cache.next_read;
+        self.cache.next_read = lba + count;
+
+        if (lba..lba + count).all(|b| self.cache.cached(b).is_some()) {
+            for (i, b) in (lba..lba + count).enumerate() {
+                let block = self.cache.cached(b).expect("checked above");
+                buf.as_mut_slice()[i * bs..(i + 1) * bs].copy_from_slice(block);
+            }
+            self.cache.stats.hits += 1;
+            return Ok(());
+        }
+        self.cache.stats.misses += 1;
+
+        let extra = if sequential {
+            let room = self.dev.total_blocks().saturating_sub(lba + count);
+            u64::from(self.cache.readahead).min(room).min(u64::from(u16::MAX - block_count))
+        } else {
+            0
+        };
+        if extra == 0 {
+            self.dev.read_blocks(lba, block_count, buf)?;
+            self.cache.overlay_dirty(lba, buf.as_mut_slice(), block_count, bs);
+            return Ok(());
+        }
+
+        let total = block_count + extra as u16;
+        let mut window = DmaBuf::alloc(usize::from(total) * bs).map_err(|_| NvmeError::OutOfMemory)?;
+        self.dev.read_blocks(lba, total, &mut window)?;
+        self.cache.overlay_dirty(lba, window.as_mut_slice(), total, bs);
+        self.cache.clean.clear();
+        for (i, b) in (lba..lba + u64::from(total)).enumerate().skip(usize::from(block_count)) {
+            self.cache.clean.insert(b, Vec::from(&window.as_slice()[i * bs..(i + 1) * bs]));
+        }
+        self.cache.stats.prefetched += extra;
+        let len = usize::from(block_count) * bs;
+        buf.as_mut_slice()[..len].copy_from_slice(&window.as_slice()[..len]);
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, lba: u64, block_count: u16, buf: &DmaBuf) -> Result<(), NvmeError> {
+        let bs = self.dev.block_size() as usize;
+        for (i, b) in (lba..lba + u64::from(block_count)).enumerate() {
+            let block = &buf.as_slice()[i * bs..(i + 1) * bs];
+            self.cache.clean.remove(&b);
+            match self.cache.dirty.get_mut(&b) {
+                Some(held) => held.copy_from_slice(block),
+                None => {
+                    self.cache.dirty.insert(b, Vec::from(block));
+                }
+            }
+        }
+        self.cache.stats.written += u64::from(block_count);
+        if self.cache.dirty.len() > MAX_DIRTY {
+            self.cache.write_back(&mut self.dev)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), NvmeError> {
+        self.cache.write_back(&mut self.dev)?;
+        if !self.cache.unflushed {
+            self.cache.stats.flushes_skipped += 1;
+            return Ok(());
+        }
+        self.dev.flush()?;
+        self.cache.unflushed = false;
+        self.cache.stats.flushes += 1;
+        Ok(())
+    }
+
+    fn block_size(&self) -> u32 {
+        self.dev.block_size()
+    }
+
+    fn total_blocks(&self) -> u64 {
+        self.dev.total_blocks()
+    }
+}
@@ -0,0 +1,143 @@
+/// Fault-injecting block device for crash-consistency testing.
+///
+/// Models a device with a volatile write cache: writes land in the cache
+/// and only become durable on `flush()`. After `budget` writes the device
+/// "loses power" — later writes and flushes are silently dropped, exactly
+/// like a machine that died mid-operation. `crash_image` then returns what
+/// survived on the medium, according to a `LossPolicy` for the writes that
+/// were still sitting in the cache.
+use alloc::vec::Vec;
+
+use crate::drivers::nvme::NvmeError;
+use crate::mem::DmaBuf;
+use super::block_device::BlockDevice;
+use super::mock_device::RamDisk;
+
+/// What happens to cached (unflushed) writes at power loss.
+#[derive(Debug, Clone, Copy)]
+pub enum LossPolicy {
+    /// Every unflushed write is lost.
+    DropUnflushed,
+    /// Every unflushed write made it to the medium anyway.
+    KeepUnflushed,
+    /// An arbitrary subset survived (the device may reorder). Seeded.
+    Random(u64),
+}
+
+/// Block device that can simulate power loss.
+pub struct CrashDisk {
+    durable: RamDisk,
+    cache: Vec<(u64, Vec<u8>)>,
+    budget: u64,
+    writes: u64,
+    powered: bool,
+}
+
+impl CrashDisk {
+    /// Wrap a (typically freshly formatted) RAM disk. Power is lost after
+    /// `budget` block writes; `u64::MAX` never crashes.
+    pub fn new(durable: RamDisk, budget: u64) -> Self {
+        Self {
+            durable,
+            cache: Vec::new(),
+            budget,
+            writes: 0,
+            powered: true,
+        }
+    }
+
+    /// Number of write commands accepted so far (for sizing crash sweeps).
+    pub fn writes(&self) -> u64 {
+        self.writes
+    }
+
+    /// Whether power has been lost.
+    pub fn crashed(&self) -> bool {
+        !self.powered
+    }
+
+    /// Pull the plug now and return the surviving medium.
+    pub fn crash_image(mut self, policy: LossPolicy) -> RamDisk {
+        let bs = self.durable.block_size() as usize;
+        let mut state = match policy {
+            LossPolicy::Random(seed) => seed | 1,
+            _ => 0,
+        };
+        for (lba, data) in core::mem::take(&mut self.cache) {
+            let survive = match policy {
+                LossPolicy::DropUnflushed => false,
+                LossPolicy::KeepUnflushed => true,
+                LossPolicy::Random(_) => {
+                    state ^= state << 13;
+                    state ^= state >> 7;
+                    state ^= state << 17;
+                    state & 1 == 1
+                }
+            };
+            if survive {
+                self.durable.write_raw(lba as usize * bs, &data);
+            }
+        }
+        self.durable
+    }
+}
+
+impl BlockDevice for CrashDisk {
+    fn read_blocks(&mut self, lba: u64, block_count: u16, buf: &mut DmaBuf) -> Result<(), NvmeError> {
+        self.durable.read_blocks(lba, block_count, buf)?;
+
+        // Overlay cached writes, oldest first, so reads see the latest data.
+        let bs = self.durable.block_size() as u64;
+        let end = lba + block_count as u64;
+        let dst = buf.as_mut_slice();
+        for (w_lba, data) in &self.cache {
+            let w_end = w_lba + data.len() as u64 / bs;
+            let lo = lba.max(*w_lba);
+            let hi = end.min(w_end);
+            for blk in lo..hi {
+                let d = ((blk - lba) * bs) as usize;
+                let s = ((blk - w_lba) * bs) as usize;
+                let n = (bs as usize).min(dst.len().saturating_sub(d));
+                dst[d..d + n].copy_from_slice(&data[s..s + n]);
+            }
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, lba: u64, block_count: u16, buf: &DmaBuf) -> Result<(), NvmeError> {
+        if !self.powered {
+            return Ok(()); // Dead device: the host never hears about it
+        }
+        if self.writes >= self.budget {
+            self.powered = false;
+            return Ok(());
+        }
+        self.writes += 1;
+
+        let len = block_count as usize * self.durable.block_size() as usize;
+        if lba + block_count as u64 > self.durable.total_blocks() {
+            return Err(NvmeError::MediaError);
+        }
+        self.cache.push((lba, buf.as_slice()[..len].to_vec()));
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), NvmeError> {
+        if !self.powered {
+            return Ok(());
+        }
+        let bs = self.durable.block_size() as usize;
+        for (lba, data) in core::mem::take(&mut self.cache) {
+            self.durable.write_raw(lba as usize * bs, &data);
+        }
+        self.durable.flush()
+    }
+
+    fn block_size(&self) -> u32 {
+        self.durable.block_size()
+    }
+
+    fn total_blocks(&self) -> u64 {
+        self.durable.total_blocks()
+    }
+}
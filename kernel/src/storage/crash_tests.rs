@@ -0,0 +1,397 @@
+/// Crash-consistency tests over `crash_device::CrashDisk`.
+///
+/// Each scenario is a fixed sequence of SQLite-like file operations. It is
+/// run once to count the device writes it issues, then re-run with power
+/// lost after every possible write, under several cache-loss policies —
+/// straight on the device and through the VFS's `cache::Device`. After
+/// each simulated crash the medium is remounted and must satisfy:
+///
+/// - the superblock, bitmap and file table load, and `check()` is clean
+///   (no entry references a free block, no overlaps) — leaked blocks are
+///   acceptable, corruption is not;
+/// - data covered by a completed `sync` reads back exactly, for as much of
+///   it as the surviving file table says the file still holds. This is the
+///   property SQLite's journal and WAL recovery rely on.
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::*;
+use super::cache::{self, BlockCache};
+use super::crash_device::{CrashDisk, LossPolicy};
+use super::file_ops::{self, INITIAL_ALLOC_BLOCKS};
+use super::mock_device::RamDisk;
+
+const BLOCK_SIZE: u32 = 4096;
+const TOTAL_BLOCKS: u64 = 256; // 1 MiB
+const BS: usize = BLOCK_SIZE as usize;
+
+/// What a completed sync guarantees about one file.
+#[derive(Clone)]
+struct Promise {
+    data: Vec<u8>,
+    /// The file must exist and hold at least `data`. When false the file may
+    /// since have been deleted or truncated without a sync, so it may be
+    /// absent or shorter — but whatever length survives must still match.
+    settled: bool,
+}
+
+/// Storage stack on a crashable device, plus what has been made durable.
+struct Fs {
+    dev: CrashDisk,
+    /// Held writes and read-ahead, as in the VFS; None: straight I/O.
+    cache: Option<BlockCache>,
+    alloc: BlockAllocator,
+    ft: FileTable,
+    handles: BTreeMap<&'static [u8], HeavenFile>,
+    promises: BTreeMap<&'static [u8], Promise>,
+}
+
+impl Fs {
+    fn new(budget: u64, cached: bool) -> Self {
+        let mut disk = RamDisk::new(TOTAL_BLOCKS, BLOCK_SIZE);
+        let alloc = BlockAllocator::format(&mut disk, TOTAL_BLOCKS, BLOCK_SIZE).unwrap();
+        let ft = FileTable::new(alloc.data_start_lba() - 1, BLOCK_SIZE);
+        Fs {
+            dev: CrashDisk::new(disk, budget),
+            cache: cached.then(BlockCache::new),
+            alloc,
+            ft,
+            handles: BTreeMap::new(),
+            promises: BTreeMap::new(),
+        }
+    }
+
+    fn write(&mut self, name: &'static [u8], offset: u64, data: &[u8]) {
+        if !self.handles.contains_key(name) {
+            let f = file_ops::open(&mut self.alloc, &mut self.ft, name, true).unwrap();
+            self.handles.insert(name, f);
+        }
+        let mut file = self.handles.remove(name).unwrap();
+        self.io(|dev, alloc, ft| file_ops::write(dev, alloc, ft, &mut file, data, offset)).unwrap();
+        self.handles.insert(name, file);
+    }
+
+    /// Sync `name`; if the device was still powered at the end, everything
+    /// written to it so far is promised to survive.
+    fn sync(&mut self, name: &'static [u8], contents: &[u8]) {
+        let file = self.handles.remove(name).unwrap();
+        self.io(|dev, alloc, ft| file_ops::sync(dev, alloc, ft, &file)).unwrap();
+        self.handles.insert(name, file);
+        if !self.dev.crashed() {
+            self.promises.insert(name, Promise { data: contents.to_vec(), settled: true });
+        }
+    }
+
+    /// Run `f` on the storage stack, through the cache if there is one.
+    fn io<R>(&mut self, f: impl FnOnce(&mut dyn BlockDevice, &mut BlockAllocator, &mut FileTable) -> R) -> R {
+        match &mut self.cache {
+            Some(cache) => f(&mut cache::Device::new(&mut self.dev, cache), &mut self.alloc, &mut self.ft),
+            None => f(&mut self.dev, &mut self.alloc, &mut self.ft),
+        }
+    }
+
+    fn unsettle(&mut self, name: &'static [u8]) {
+        if let Some(p) = self.promises.get_mut(name) {
+            p.settled = false;
+        }
+    }
+
+    fn truncate(&mut self, name: &'static [u8], size: u64) {
+        let file = self.handles.get_mut(name).unwrap();
+        file_ops::truncate(&mut self.alloc, &mut self.ft, file, size);
+        self.unsettle(name);
+    }
+
+    fn delete(&mut self, name: &'static [u8]) {
+        if let Some(file) = self.handles.remove(name) {
+            file_ops::close(&mut self.ft, &file);
+        }
+        file_ops::delete(&mut self.alloc, &mut self.ft, name);
+        self.unsettle(name);
+    }
+}
+
+fn pattern(tag: u8, len: usize) -> Vec<u8> {
+    (0..len).map(|i| tag ^ (i / BS) as u8 ^ (i as u8)).collect()
+}
+
+fn show(name: &[u8]) -> String {
+    String::from_utf8_lossy(name).into_owned()
+}
+
+/// Remount the surviving medium and check every invariant.
+fn verify(image: RamDisk, promises: &BTreeMap<&'static [u8], Promise>, ctx: &str) {
+    let mut dev = image;
+    let mut alloc = BlockAllocator::load(&mut dev)
+        .unwrap_or_else(|e| panic!("{}: superblock lost: {:?}", ctx, e));
+    let mut ft = FileTable::load(&mut dev, alloc.data_start_lba() - 1, BLOCK_SIZE).unwrap();
+
+    let damage = check(&alloc, &ft);
+    assert!(damage.is_empty(), "{}: damage {:?}", ctx, damage);
+
+    for (&name, p) in promises {
+        let file = match file_ops::open(&mut alloc, &mut ft, name, false) {
+            Ok(f) => f,
+            Err(_) => {
+                assert!(!p.settled, "{}: synced file {} lost", ctx, show(name));
+                continue;
+            }
+        };
+        let len = (file.byte_length as usize).min(p.data.len());
+        if p.settled {
+            assert_eq!(len, p.data.len(), "{}: synced length of {} lost", ctx, show(name));
+        }
+        let mut buf = vec![0u8; len];
+        if len > 0 {
+            file_ops::read(&mut dev, &file, &mut buf, 0).unwrap();
+        }
+        assert!(buf == p.data[..len], "{}: synced data of {} corrupted", ctx, show(name));
+    }
+}
+
+/// Run `scenario` with power lost after every write, under each policy,
+/// with and without the cache.
+fn sweep(scenario: fn(&mut Fs)) {
+    for cached in [false, true] {
+        let total = {
+            let mut fs = Fs::new(u64::MAX, cached);
+            scenario(&mut fs);
+            fs.dev.writes()
+        };
+        assert!(total > 0);
+
+        let mut policies = vec![LossPolicy::DropUnflushed, LossPolicy::KeepUnflushed];
+        policies.extend((1..=8).map(LossPolicy::Random));
+
+        for budget in 0..=total {
+            for &policy in &policies {
+                let mut fs = Fs::new(budget, cached);
+                scenario(&mut fs);
+                let promises = core::mem::take(&mut fs.promises);
+                let ctx = alloc::format!(
+                    "crash after write {}/{} ({:?}{})",
+                    budget,
+                    total,
+                    policy,
+                    if cached { ", cached" } else { "" }
+                );
+                verify(fs.dev.crash_image(policy), &promises, &ctx);
+            }
+        }
+    }
+}
+
+// ---- Scenarios ----
+
+/// Commit, then relocate main.db and let a new journal land in the region
+/// it just left. Until the next sync the on-disk file table still points
+/// main.db at that region, so it must not be reused.
+fn relocate_then_reuse(fs: &mut Fs) {
+    let n = INITIAL_ALLOC_BLOCKS as usize;
+    let mut db = pattern(0xA0, n * BS);
+    fs.write(b"main.db", 0, &db);
+    fs.sync(b"main.db", &db);
+
+    let page = pattern(0xB0, BS);
+    fs.write(b"main.db", db.len() as u64, &page);
+    db.extend_from_slice(&page);
+
+    let journal = pattern(0xC0, n * BS);
+    fs.write(b"main.db-journal", 0, &journal);
+    fs.sync(b"main.db-journal", &journal);
+    fs.sync(b"main.db", &db);
+}
+
+/// Commit a journal, delete it (journal_mode=DELETE) and grow main.db into
+/// its blocks. A crash before the delete is durable leaves a hot journal on
+/// disk, which SQLite will roll back — so its contents must be intact.
+fn delete_then_reuse(fs: &mut Fs) {
+    let db = pattern(0x10, 4 * BS);
+    fs.write(b"main.db", 0, &db);
+    let journal = pattern(0x20, 8 * BS);
+    fs.write(b"main.db-journal", 0, &journal);
+    fs.sync(b"main.db-journal", &journal);
+    fs.sync(b"main.db", &db);
+
+    fs.delete(b"main.db-journal");
+
+    let big = pattern(0x30, (INITIAL_ALLOC_BLOCKS as usize + 4) * BS);
+    fs.write(b"main.db", 0, &big);
+    fs.sync(b"main.db", &big);
+}
+
+/// Commit a journal, truncate it (journal_mode=TRUNCATE) and let another
+/// file allocate the freed tail before the truncate is durable.
+fn truncate_then_reuse(fs: &mut Fs) {
+    let journal = pattern(0x40, INITIAL_ALLOC_BLOCKS as usize * BS);
+    fs.write(b"main.db-journal", 0, &journal);
+    fs.sync(b"main.db-journal", &journal);
+
+    fs.truncate(b"main.db-journal", 0);
+
+    // Fill the disk from the front so the next allocation must land in the
+    // journal's released tail.
+    let wal = pattern(0x50, 2 * BS);
+    fs.write(b"main.db-wal", 0, &wal);
+    fs.write(b"etilqs_1", 0, &wal);
+    fs.sync(b"main.db-wal", &wal);
+    fs.sync(b"main.db-journal", &[]);
+}
+
+/// Several files growing and syncing in turn: exercises metadata ordering
+/// (bitmap vs. file table) under device reordering.
+fn interleaved_growth(fs: &mut Fs) {
+    let mut a = Vec::new();
+    let mut b = Vec::new();
+    for round in 0..3u8 {
+        let pa = pattern(0x60 + round, 10 * BS);
+        let pb = pattern(0x70 + round, 7 * BS);
+        fs.write(b"main.db", a.len() as u64, &pa);
+        a.extend_from_slice(&pa);
+        fs.write(b"main.db-wal", b.len() as u64, &pb);
+        b.extend_from_slice(&pb);
+        fs.sync(b"main.db-wal", &b);
+        fs.sync(b"main.db", &a);
+    }
+}
+
+#[test]
+fn crash_during_relocation_preserves_committed_data() {
+    sweep(relocate_then_reuse);
+}
+
+#[test]
+fn crash_after_delete_preserves_hot_journal() {
+    sweep(delete_then_reuse);
+}
+
+#[test]
+fn crash_after_truncate_preserves_journal() {
+    sweep(truncate_then_reuse);
+}
+
+#[test]
+fn crash_during_interleaved_growth_is_consistent() {
+    sweep(interleaved_growth);
+}
+
+#[test]
+fn crash_disk_drops_writes_after_budget() {
+    let disk = RamDisk::new(8, BLOCK_SIZE);
+    let mut dev = CrashDisk::new(disk, 1);
+    let mut buf = crate::mem::DmaBuf::alloc(BS).unwrap();
+    buf.as_mut_slice().fill(0x11);
+    dev.write_blocks(1, 1, &buf).unwrap();
+    dev.flush().unwrap();
+    buf.as_mut_slice().fill(0x22);
+    dev.write_blocks(2, 1, &buf).unwrap(); // Power lost here
+    assert!(dev.crashed());
+
+    let image = dev.crash_image(LossPolicy::KeepUnflushed);
+    assert!(image.read_raw(BS, BS).iter().all(|&b| b == 0x11));
+    assert!(image.read_raw(2 * BS, BS).iter().all(|&b| b == 0));
+}
+
+#[test]
+fn crash_disk_reads_see_cached_writes() {
+    let disk = RamDisk::new(8, BLOCK_SIZE);
+    let mut dev = CrashDisk::new(disk, u64::MAX);
+    let mut buf = crate::mem::DmaBuf::alloc(2 * BS).unwrap();
+    buf.as_mut_slice().fill(0x33);
+    dev.write_blocks(3, 2, &buf).unwrap();
+
+    let mut out = crate::mem::DmaBuf::alloc(3 * BS).unwrap();
+    dev.read_blocks(2, 3, &mut out).unwrap();
+    assert!(out.as_slice()[..BS].iter().all(|&b| b == 0));
+    assert!(out.as_slice()[BS..].iter().all(|&b| b == 0x33));
+
+    // Unflushed: gone after power loss.
+    let image = dev.crash_image(LossPolicy::DropUnflushed);
+    assert!(image.read_raw(3 * BS, BS).iter().all(|&b| b == 0));
+}
+
+// ---- Turning encryption on and off ----
+
+const KEY: [u8; crypt::KEY_LEN] = [0x42; crypt::KEY_LEN];
+
+fn sealed() -> crypt::CryptHeader {
+    crypt::CryptHeader::seal(&KEY, b"pw", [1; 16], 10)
+}
+
+/// A formatted disk holding a synced `main.db`, encrypted under `KEY` or not.
+fn disk_with_file(encrypted: bool) -> RamDisk {
+    let mut disk = RamDisk::new(TOTAL_BLOCKS, BLOCK_SIZE);
+    let xts = crypt::Xts::new(&KEY);
+    let mut dev = crypt::Device::new(&mut disk, encrypted.then_some(&xts));
+    let mut alloc = BlockAllocator::format(&mut dev, TOTAL_BLOCKS, BLOCK_SIZE).unwrap();
+    if encrypted {
+        block_alloc::set_crypt_header(&mut dev, &sealed()).unwrap();
+    }
+    let mut ft = FileTable::new(alloc.data_start_lba() - 1, BLOCK_SIZE);
+    let mut file = file_ops::open(&mut alloc, &mut ft, b"main.db", true).unwrap();
+    file_ops::write(&mut dev, &mut alloc, &mut ft, &mut file, &pattern(7, 3 * BS), 0).unwrap();
+    file_ops::sync(&mut dev, &mut alloc, &mut ft, &file).unwrap();
+    disk
+}
+
+/// Mount `image` the way boot does — unseal, finish any conversion — and
+/// check `main.db`. Returns whether the disk ended up encrypted.
+fn mount_converted(mut image: RamDisk, ctx: &str) -> bool {
+    let (header, conversion) = block_alloc::conversion(&mut image).unwrap();
+    if conversion.direction().is_some() {
+        let key = header.unseal(b"pw").unwrap_or_else(|| panic!("{}: conversion without a key", ctx));
+        crypt::resume(&mut image, &crypt::Xts::new(&key), |_, _| {}).unwrap();
+        assert!(block_alloc::conversion(&mut image).unwrap().1.direction().is_none(), "{}", ctx);
+    }
+    let header = block_alloc::crypt_header(&mut image).unwrap();
+    let xts = header.unseal(b"pw").map(|key| crypt::Xts::new(&key));
+    let mut dev = crypt::Device::new(&mut image, xts.as_ref());
+    let mut alloc = BlockAllocator::load(&mut dev).unwrap_or_else(|e| panic!("{}: superblock: {:?}", ctx, e));
+    let mut ft = FileTable::load(&mut dev, alloc.data_start_lba() - 1, BLOCK_SIZE)
+        .unwrap_or_else(|e| panic!("{}: file table: {:?}", ctx, e));
+    assert!(check(&alloc, &ft).is_empty(), "{}: damage after conversion", ctx);
+    let file = file_ops::open(&mut alloc, &mut ft, b"main.db", false).unwrap();
+    let mut buf = vec![0u8; 3 * BS];
+    file_ops::read(&mut dev, &file, &mut buf, 0).unwrap();
+    assert!(buf == pattern(7, 3 * BS), "{}: main.db garbled", ctx);
+    header.is_encrypted()
+}
+
+/// Lose power after every write of a conversion; mounting must finish it
+/// (or find it never started) with the data intact.
+fn sweep_conversion(direction: crypt::Direction) {
+    let encrypted = direction == crypt::Direction::Decrypt;
+    let xts = crypt::Xts::new(&KEY);
+    let run = |dev: &mut CrashDisk| {
+        // After the crash writes vanish without an error
+        let _ = crypt::convert(dev, &xts, direction, &sealed(), |_, _| {});
+    };
+
+    let mut dev = CrashDisk::new(disk_with_file(encrypted), u64::MAX);
+    run(&mut dev);
+    let writes = dev.writes();
+    assert_eq!(mount_converted(dev.crash_image(LossPolicy::DropUnflushed), "complete"), !encrypted);
+
+    for budget in 0..writes {
+        let mut policies = vec![LossPolicy::DropUnflushed, LossPolicy::KeepUnflushed];
+        policies.extend((1..=4).map(LossPolicy::Random));
+        for policy in policies {
+            let mut dev = CrashDisk::new(disk_with_file(encrypted), budget);
+            run(&mut dev);
+            let ctx = alloc::format!("{:?} crash after {} of {} writes, {:?}", direction, budget, writes, policy);
+            mount_converted(dev.crash_image(policy), &ctx);
+        }
+    }
+}
+
+#[test]
+fn crash_while_encrypting_is_finished_at_mount() {
+    sweep_conversion(crypt::Direction::Encrypt);
+}
+
+#[test]
+fn crash_while_decrypting_is_finished_at_mount() {
+    sweep_conversion(crypt::Direction::Decrypt);
+}
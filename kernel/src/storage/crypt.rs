@@ -0,0 +1,404 @@
+/// Encryption at rest: XTS-AES-256 between the VFS and the block device.
+///
+/// Every block except the superblock copies (LBA 0 and the two backups,
+/// which must stay readable to find the key) is encrypted with XTS-AES-256
+/// — the bitmap, the file table and all data blocks alike — using the
+/// block's LBA as the tweak, so each block is its own data unit and a
+/// block write stays atomic. `Device` wraps a `BlockDevice` and does this
+/// on every read and write; with no key it passes I/O through unchanged.
+///
+/// The 64-byte disk key is random. The superblock holds it wrapped under a
+/// passphrase (`CryptHeader`): XOR-ed with a key `secrets::derive` makes
+/// from the passphrase and a per-header salt, plus an HMAC of the key to
+/// tell a wrong passphrase from a right one. Changing the passphrase
+/// rewrites the header only.
+///
+/// Turning encryption on or off rewrites every block (`convert`). Each
+/// chunk is announced in the superblocks (`Conversion`) before it is
+/// written, with a hash of every block's new bytes, so a conversion cut
+/// short by a crash is finished by `resume` at the next mount: blocks
+/// below the watermark are done, blocks past the chunk are untouched, and
+/// each block of the chunk is whichever form its hash says.
+use aes::cipher::generic_array::GenericArray;
+use aes::cipher::{BlockDecrypt, BlockEncrypt, KeyInit};
+use aes::Aes256;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::drivers::nvme::NvmeError;
+use crate::mem::DmaBuf;
+use crate::secrets::{self, wipe};
+use super::block_alloc::{self, backup_superblock_lbas, fnv1a64};
+use super::block_device::BlockDevice;
+
+/// Disk key: two AES-256 keys, for the data and for the tweak.
+pub const KEY_LEN: usize = 64;
+
+/// PBKDF2 rounds for a new header.
+pub const DEFAULT_ITERATIONS: u32 = 100_000;
+
+/// `CryptHeader::magic` of an encrypted disk: "HVNXTS02".
+const CRYPT_MAGIC: u64 = 0x3230_5354_584E_5648;
+
+/// `CryptHeader::magic` before key derivation went through `secrets`:
+/// "HVNXTS01", the key wrapped under bare PBKDF2. Still unsealed;
+/// `encrypt passwd` rewrites it in the current form.
+const LEGACY_CRYPT_MAGIC: u64 = 0x3130_5354_584E_5648;
+
+/// Blocks rewritten per step of a conversion.
+pub const CONVERSION_CHUNK: usize = 32;
+
+/// `Conversion::magic` while one is under way: "HVNCONV1".
+const CONVERSION_MAGIC: u64 = 0x3156_4E4F_434E_5648;
+
+/// What `CryptHeader::check` is the HMAC of.
+const CHECK_LABEL: &[u8] = b"HeavenOS disk key check";
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// The key description kept in every superblock copy. All zeros on a disk
+/// that isn't encrypted (and on every disk formatted before this existed).
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct CryptHeader {
+    pub magic: u64,
+    pub iterations: u32,
+    _reserved: u32,
+    pub salt: [u8; 16],
+    /// The disk key XOR `secrets::derive(passphrase, salt, iterations)`.
+    pub wrapped_key: [u8; KEY_LEN],
+    /// HMAC-SHA256(disk key, CHECK_LABEL).
+    pub check: [u8; 32],
+}
+
+static_assertions::const_assert_eq!(core::mem::size_of::<CryptHeader>(), 128);
+
+impl CryptHeader {
+    /// No encryption.
+    pub const NONE: CryptHeader = CryptHeader {
+        magic: 0,
+        iterations: 0,
+        _reserved: 0,
+        salt: [0; 16],
+        wrapped_key: [0; KEY_LEN],
+        check: [0; 32],
+    };
+
+    pub fn is_encrypted(&self) -> bool {
+        self.magic == CRYPT_MAGIC || self.is_legacy()
+    }
+
+    /// Wrapped the old way, under bare PBKDF2.
+    pub fn is_legacy(&self) -> bool {
+        self.magic == LEGACY_CRYPT_MAGIC
+    }
+
+    /// Wrap `key` under `passphrase`. `salt` must be fresh for every header.
+    pub fn seal(key: &[u8; KEY_LEN], passphrase: &[u8], salt: [u8; 16], iterations: u32) -> Self {
+        let mut wrapped_key = [0u8; KEY_LEN];
+        secrets::derive(passphrase, &salt, iterations, secrets::DISK_KEY_WRAP, &mut wrapped_key);
+        for (w, k) in wrapped_key.iter_mut().zip(key) {
+            *w ^= k;
+        }
+        CryptHeader {
+            magic: CRYPT_MAGIC,
+            iterations,
+            _reserved: 0,
+            salt,
+            wrapped_key,
+            check: key_check(key),
+        }
+    }
+
+    /// The disk key, if `passphrase` is the right one.
+    pub fn unseal(&self, passphrase: &[u8]) -> Option<[u8; KEY_LEN]> {
+        if !self.is_encrypted() {
+            return None;
+        }
+        let mut key = [0u8; KEY_LEN];
+        if self.is_legacy() {
+            secrets::pbkdf2_sha256(passphrase, &self.salt, self.iterations, &mut key);
+        } else {
+            secrets::derive(passphrase, &self.salt, self.iterations, secrets::DISK_KEY_WRAP, &mut key);
+        }
+        for (k, w) in key.iter_mut().zip(&self.wrapped_key) {
+            *k ^= w;
+        }
+        if key_check(&key) == self.check {
+            Some(key)
+        } else {
+            wipe(&mut key);
+            None
+        }
+    }
+}
+
+impl core::fmt::Debug for CryptHeader {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        if self.is_legacy() {
+            write!(f, "XTS-AES-256, legacy PBKDF2-HMAC-SHA256 x{} (run `encrypt passwd`)", self.iterations)
+        } else if self.is_encrypted() {
+            write!(f, "XTS-AES-256, PBKDF2-HMAC-SHA256 x{} + HKDF", self.iterations)
+        } else {
+            f.write_str("none")
+        }
+    }
+}
+
+fn key_check(key: &[u8; KEY_LEN]) -> [u8; 32] {
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(key).expect("HMAC takes any key length");
+    mac.update(CHECK_LABEL);
+    mac.finalize().into_bytes().into()
+}
+
+/// XTS-AES-256 (IEEE 1619) over whole data units of 16-byte multiples.
+/// The AES key schedules are wiped when this drops.
+pub struct Xts {
+    data: Aes256,
+    tweak: Aes256,
+}
+
+impl Xts {
+    pub fn new(key: &[u8; KEY_LEN]) -> Self {
+        Xts {
+            data: Aes256::new(GenericArray::from_slice(&key[..32])),
+            tweak: Aes256::new(GenericArray::from_slice(&key[32..])),
+        }
+    }
+
+    /// Encrypt data unit number `unit` in place.
+    pub fn encrypt(&self, unit: u64, buf: &mut [u8]) {
+        self.apply(unit, buf, true);
+    }
+
+    /// Decrypt data unit number `unit` in place.
+    pub fn decrypt(&self, unit: u64, buf: &mut [u8]) {
+        self.apply(unit, buf, false);
+    }
+
+    fn apply(&self, unit: u64, buf: &mut [u8], encrypt: bool) {
+        debug_assert!(buf.len().is_multiple_of(16), "XTS data unit of {} bytes", buf.len());
+        let mut t = [0u8; 16];
+        t[..8].copy_from_slice(&unit.to_le_bytes());
+        self.tweak.encrypt_block(GenericArray::from_mut_slice(&mut t));
+        for block in buf.as_chunks_mut::<16>().0 {
+            for (b, t) in block.iter_mut().zip(&t) {
+                *b ^= t;
+            }
+            let block_ga = GenericArray::from_mut_slice(block);
+            if encrypt {
+                self.data.encrypt_block(block_ga);
+            } else {
+                self.data.decrypt_block(block_ga);
+            }
+            for (b, t) in block.iter_mut().zip(&t) {
+                *b ^= t;
+            }
+            // Next tweak: multiply by x in GF(2^128), little-endian
+            let carry = t[15] >> 7;
+            for i in (1..16).rev() {
+                t[i] = (t[i] << 1) | (t[i - 1] >> 7);
+            }
+            t[0] = (t[0] << 1) ^ (0x87 * carry);
+        }
+    }
+}
+
+/// A block device seen through the disk key, if there is one.
+pub struct Device<'a> {
+    dev: &'a mut dyn BlockDevice,
+    xts: Option<&'a Xts>,
+    /// Superblock copies, stored in the clear.
+    plain: [u64; 3],
+}
+
+impl<'a> Device<'a> {
+    pub fn new(dev: &'a mut dyn BlockDevice, xts: Option<&'a Xts>) -> Self {
+        let [mid, last] = backup_superblock_lbas(dev.total_blocks());
+        Device { dev, xts, plain: [0, mid, last] }
+    }
+
+    /// Run `f` on each encrypted block of an I/O: its LBA and bytes.
+    fn each_block(&self, lba: u64, block_count: u16, buf: &mut [u8], f: impl Fn(&Xts, u64, &mut [u8])) {
+        let Some(xts) = self.xts else { return };
+        let bs = self.dev.block_size() as usize;
+        for (i, block) in buf.chunks_exact_mut(bs).take(usize::from(block_count)).enumerate() {
+            let lba = lba + i as u64;
+            if !self.plain.contains(&lba) {
+                f(xts, lba, block);
+            }
+        }
+    }
+}
+
+impl BlockDevice for Device<'_> {
+    fn read_blocks(&mut self, lba: u64, block_count: u16, buf: &mut DmaBuf) -> Result<(), NvmeError> {
+        self.dev.read_blocks(lba, block_count, buf)?;
+        self.each_block(lba, block_count, buf.as_mut_slice(), |xts, lba, block| xts.decrypt(lba, block));
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, lba: u64, block_count: u16, buf: &DmaBuf) -> Result<(), NvmeError> {
+        if self.xts.is_none() {
+            return self.dev.write_blocks(lba, block_count, buf);
+        }
+        // Encrypt a copy: the caller's buffer is plaintext it may reuse
+        let len = usize::from(block_count) * self.dev.block_size() as usize;
+        let mut sealed = DmaBuf::alloc(len).map_err(|_| NvmeError::OutOfMemory)?;
+        sealed.as_mut_slice()[..len].copy_from_slice(&buf.as_slice()[..len]);
+        self.each_block(lba, block_count, sealed.as_mut_slice(), |xts, lba, block| xts.encrypt(lba, block));
+        self.dev.write_blocks(lba, block_count, &sealed)
+    }
+
+    fn flush(&mut self) -> Result<(), NvmeError> {
+        self.dev.flush()
+    }
+
+    fn block_size(&self) -> u32 {
+        self.dev.block_size()
+    }
+
+    fn total_blocks(&self) -> u64 {
+        self.dev.total_blocks()
+    }
+}
+
+/// Which way a conversion rewrites the disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Plaintext to encrypted.
+    Encrypt = 1,
+    /// Encrypted to plaintext.
+    Decrypt = 2,
+}
+
+/// Progress of turning encryption on or off, kept in every superblock copy
+/// next to the `CryptHeader`, which wraps the disk key until the end in
+/// both directions. All zeros when no conversion is under way.
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Conversion {
+    pub magic: u64,
+    /// `Direction` as a number.
+    pub direction: u32,
+    /// Blocks in flight, from `next`.
+    pub count: u32,
+    /// The watermark: blocks below are in their new form; from
+    /// `next + count` on, in their old one.
+    pub next: u64,
+    /// FNV-1a of each in-flight block's new on-disk bytes.
+    pub written: [u64; CONVERSION_CHUNK],
+}
+
+static_assertions::const_assert_eq!(core::mem::size_of::<Conversion>(), 280);
+
+impl Conversion {
+    /// None under way.
+    pub const NONE: Conversion = Conversion {
+        magic: 0,
+        direction: 0,
+        count: 0,
+        next: 0,
+        written: [0; CONVERSION_CHUNK],
+    };
+
+    /// One about to start at LBA 1 (LBA 0 is a superblock copy).
+    fn start(direction: Direction) -> Self {
+        Conversion {
+            magic: CONVERSION_MAGIC,
+            direction: direction as u32,
+            next: 1,
+            ..Conversion::NONE
+        }
+    }
+
+    /// The conversion under way, if any.
+    pub fn direction(&self) -> Option<Direction> {
+        match (self.magic, self.direction) {
+            (CONVERSION_MAGIC, 1) => Some(Direction::Encrypt),
+            (CONVERSION_MAGIC, 2) => Some(Direction::Decrypt),
+            _ => None,
+        }
+    }
+}
+
+/// Rewrite every block of `dev` (the raw device) so it is encrypted under
+/// `xts`, or decrypted from it, then store the final header: `sealed` when
+/// encrypting, `CryptHeader::NONE` when decrypting. `sealed` must wrap the
+/// key of `xts`; it stays in the superblocks until the conversion ends, so
+/// an interrupted one can be finished by `resume` with the same passphrase.
+pub fn convert(
+    dev: &mut dyn BlockDevice,
+    xts: &Xts,
+    direction: Direction,
+    sealed: &CryptHeader,
+    progress: impl Fn(u64, u64),
+) -> Result<(), NvmeError> {
+    block_alloc::set_conversion(dev, sealed, &Conversion::start(direction))?;
+    resume(dev, xts, progress)
+}
+
+/// Finish the conversion recorded on `dev`, if there is one; `xts` holds
+/// the key its header wraps. Safe to interrupt and call again.
+pub fn resume(dev: &mut dyn BlockDevice, xts: &Xts, progress: impl Fn(u64, u64)) -> Result<(), NvmeError> {
+    let (header, mut conversion) = block_alloc::conversion(dev)?;
+    let Some(direction) = conversion.direction() else { return Ok(()) };
+    let (from, to) = match direction {
+        Direction::Encrypt => (None, Some(xts)),
+        Direction::Decrypt => (Some(xts), None),
+    };
+    let recode = |lba: u64, block: &mut [u8]| {
+        if let Some(from) = from {
+            from.decrypt(lba, block);
+        }
+        if let Some(to) = to {
+            to.encrypt(lba, block);
+        }
+    };
+    let bs = dev.block_size() as usize;
+    let total = dev.total_blocks();
+    let [mid, last] = backup_superblock_lbas(total);
+    let mut buf = DmaBuf::alloc(CONVERSION_CHUNK * bs).map_err(|_| NvmeError::OutOfMemory)?;
+
+    // The chunk in flight when it stopped: each block is in one form or the other
+    for (i, written) in conversion.written.iter().take(conversion.count as usize).enumerate() {
+        let lba = conversion.next + i as u64;
+        dev.read_blocks(lba, 1, &mut buf)?;
+        let block = &mut buf.as_mut_slice()[..bs];
+        if fnv1a64(block) != *written {
+            recode(lba, block);
+            dev.write_blocks(lba, 1, &buf)?;
+        }
+    }
+    dev.flush()?;
+
+    let mut lba = conversion.next + u64::from(conversion.count);
+    while lba < total {
+        // Superblock copies stay in the clear, and are rewritten by the header updates
+        if lba == mid || lba == last {
+            lba += 1;
+            continue;
+        }
+        let end = (lba + CONVERSION_CHUNK as u64).min(if lba < mid { mid } else { last });
+        let count = (end - lba) as usize;
+        dev.read_blocks(lba, count as u16, &mut buf)?;
+        for (i, block) in buf.as_mut_slice().chunks_exact_mut(bs).take(count).enumerate() {
+            recode(lba + i as u64, block);
+            conversion.written[i] = fnv1a64(block);
+        }
+        conversion.next = lba;
+        conversion.count = count as u32;
+        // Announce the chunk before writing it; both reach the medium before the next
+        block_alloc::set_conversion(dev, &header, &conversion)?;
+        dev.write_blocks(lba, count as u16, &buf)?;
+        dev.flush()?;
+        lba = end;
+        progress(lba, total);
+    }
+
+    let done = match direction {
+        Direction::Encrypt => header,
+        Direction::Decrypt => CryptHeader::NONE,
+    };
+    block_alloc::set_conversion(dev, &done, &Conversion::NONE)
+}
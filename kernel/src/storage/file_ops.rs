@@ -0,0 +1,545 @@
+/// File operations over a BlockDevice — the device-independent half of the VFS.
+///
+/// `vfs::HeavenVfs` owns the locks and maps `FileError` to SQLite result
+/// codes; everything that touches blocks lives here so it can run against
+/// `mock_device::RamDisk` in host tests.
+///
+/// Callers must hold the device, allocator and file table for the whole
+/// call. Lock order: device → allocator → file_table.
+use crate::drivers::nvme::NvmeError;
+use crate::error::{KernelError, Layer};
+use crate::mem::DmaBuf;
+use super::block_device::BlockDevice;
+use super::{BlockAllocator, FileTable};
+
+/// Maximum blocks per single I/O command (u16::MAX).
+const MAX_BLOCKS_PER_IO: u64 = u16::MAX as u64;
+
+/// Default initial allocation for a new file (in blocks).
+pub const INITIAL_ALLOC_BLOCKS: u64 = 16; // 64 KiB at 4096 block size
+
+/// Per-open-file state. Stored alongside the sqlite3_file header.
+pub struct HeavenFile {
+    /// Index into the file table.
+    pub file_table_index: usize,
+    /// Cached start LBA (absolute, not data-block index).
+    pub start_lba: u64,
+    /// Cached block count.
+    pub block_count: u64,
+    /// Cached byte length.
+    pub byte_length: u64,
+    /// Block size (from NVMe).
+    pub block_size: u32,
+    /// Growth granularity in blocks, set by SQLITE_FCNTL_CHUNK_SIZE.
+    /// 0 grows to exactly what the write needs.
+    pub chunk_blocks: u64,
+}
+
+/// File operation errors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileError {
+    /// File does not exist and creation was not requested.
+    NotFound,
+    /// No space in the allocator or the file table.
+    Full,
+    /// DMA buffer allocation failed.
+    NoMem,
+    /// Read past end-of-file; the buffer tail was zero-filled.
+    ShortRead,
+    /// Device read failed.
+    Read(NvmeError),
+    /// Device write failed.
+    Write(NvmeError),
+    /// Metadata flush or device flush failed.
+    Flush(NvmeError),
+}
+
+impl FileError {
+    /// The device error behind a failed read, write or flush.
+    pub fn device_error(&self) -> Option<NvmeError> {
+        match *self {
+            FileError::Read(e) | FileError::Write(e) | FileError::Flush(e) => Some(e),
+            _ => None,
+        }
+    }
+
+    fn code(&self) -> &'static str {
+        match self {
+            FileError::NotFound => "NotFound",
+            FileError::Full => "Full",
+            FileError::NoMem => "NoMem",
+            FileError::ShortRead => "ShortRead",
+            FileError::Read(_) => "Read",
+            FileError::Write(_) => "Write",
+            FileError::Flush(_) => "Flush",
+        }
+    }
+}
+
+impl core::fmt::Display for FileError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            FileError::NotFound => write!(f, "file not found"),
+            FileError::Full => write!(f, "no space left"),
+            FileError::NoMem => write!(f, "out of DMA memory"),
+            FileError::ShortRead => write!(f, "short read"),
+            FileError::Read(_) => write!(f, "device read failed"),
+            FileError::Write(_) => write!(f, "device write failed"),
+            FileError::Flush(_) => write!(f, "flush failed"),
+        }
+    }
+}
+
+/// Device failures keep the NVMe error as their origin:
+/// `storage: write → nvme: CommandFailed(0x2): ...`.
+impl From<FileError> for KernelError {
+    fn from(e: FileError) -> Self {
+        match e {
+            FileError::Read(d) => KernelError::from(d).context(Layer::Storage, "read"),
+            FileError::Write(d) => KernelError::from(d).context(Layer::Storage, "write"),
+            FileError::Flush(d) => KernelError::from(d).context(Layer::Storage, "flush"),
+            e => KernelError::new(Layer::Storage, e.code(), e),
+        }
+    }
+}
+
+/// Read blocks, splitting into chunks if block_count exceeds u16::MAX.
+fn chunked_read(
+    dev: &mut dyn BlockDevice,
+    start_lba: u64,
+    block_count: u64,
+    dma: &mut DmaBuf,
+    block_size: u32,
+) -> Result<(), FileError> {
+    let mut remaining = block_count;
+    let mut lba = start_lba;
+    let mut byte_offset = 0usize;
+
+    while remaining > 0 {
+        let chunk = remaining.min(MAX_BLOCKS_PER_IO) as u16;
+        let chunk_bytes = chunk as usize * block_size as usize;
+
+        if remaining == block_count && chunk as u64 == block_count {
+            // Single chunk — use the DMA buffer directly
+            dev.read_blocks(lba, chunk, dma).map_err(FileError::Read)?;
+        } else {
+            // Multiple chunks — read into a temporary buffer and copy
+            let mut tmp = DmaBuf::alloc(chunk_bytes).map_err(|_| FileError::NoMem)?;
+            dev.read_blocks(lba, chunk, &mut tmp).map_err(FileError::Read)?;
+            dma.as_mut_slice()[byte_offset..byte_offset + chunk_bytes]
+                .copy_from_slice(&tmp.as_slice()[..chunk_bytes]);
+        }
+
+        remaining -= chunk as u64;
+        lba += chunk as u64;
+        byte_offset += chunk_bytes;
+    }
+    Ok(())
+}
+
+/// Write blocks, splitting into chunks if block_count exceeds u16::MAX.
+fn chunked_write(
+    dev: &mut dyn BlockDevice,
+    start_lba: u64,
+    block_count: u64,
+    dma: &DmaBuf,
+    block_size: u32,
+) -> Result<(), FileError> {
+    let mut remaining = block_count;
+    let mut lba = start_lba;
+    let mut byte_offset = 0usize;
+
+    while remaining > 0 {
+        let chunk = remaining.min(MAX_BLOCKS_PER_IO) as u16;
+        let chunk_bytes = chunk as usize * block_size as usize;
+
+        if remaining == block_count && chunk as u64 == block_count {
+            // Single chunk — use the DMA buffer directly
+            dev.write_blocks(lba, chunk, dma).map_err(FileError::Write)?;
+        } else {
+            // Multiple chunks — copy slice into a temporary buffer and write
+            let mut tmp = DmaBuf::alloc(chunk_bytes).map_err(|_| FileError::NoMem)?;
+            tmp.as_mut_slice()[..chunk_bytes]
+                .copy_from_slice(&dma.as_slice()[byte_offset..byte_offset + chunk_bytes]);
+            dev.write_blocks(lba, chunk, &tmp).map_err(FileError::Write)?;
+        }
+
+        remaining -= chunk as u64;
+        lba += chunk as u64;
+        byte_offset += chunk_bytes;
+    }
+    Ok(())
+}
+
+/// Open a file. Creates it with `INITIAL_ALLOC_BLOCKS` if `create` is set
+/// and it doesn't exist.
+pub fn open(
+    alloc: &mut BlockAllocator,
+    ft: &mut FileTable,
+    name: &[u8],
+    create: bool,
+) -> Result<HeavenFile, FileError> {
+    let block_size = alloc.block_size();
+
+    // Look up existing file
+    if let Some((idx, entry)) = ft.lookup(name) {
+        let start_lba = alloc.data_start_lba() + entry.start_block;
+        return Ok(HeavenFile {
+            file_table_index: idx,
+            start_lba,
+            block_count: entry.block_count,
+            byte_length: entry.byte_length,
+            block_size,
+            chunk_blocks: 0,
+        });
+    }
+
+    if !create {
+        return Err(FileError::NotFound);
+    }
+
+    // Allocate initial blocks
+    let start_block = alloc.alloc(INITIAL_ALLOC_BLOCKS)
+        .map_err(|_| FileError::Full)?;
+
+    let idx = match ft.create(name, start_block, INITIAL_ALLOC_BLOCKS) {
+        Some(idx) => idx,
+        None => {
+            alloc.free(start_block, INITIAL_ALLOC_BLOCKS);
+            return Err(FileError::Full);
+        }
+    };
+
+    Ok(HeavenFile {
+        file_table_index: idx,
+        start_lba: alloc.data_start_lba() + start_block,
+        block_count: INITIAL_ALLOC_BLOCKS,
+        byte_length: 0,
+        block_size,
+        chunk_blocks: 0,
+    })
+}
+
+/// Sync the file table entry with the cached byte_length.
+pub fn close(ft: &mut FileTable, file: &HeavenFile) {
+    if let Some(entry) = ft.get_mut(file.file_table_index) {
+        entry.byte_length = file.byte_length;
+    }
+}
+
+/// Read `buf.len()` bytes at `offset`.
+///
+/// Strategy: read full blocks, copy the requested byte range. Reads past
+/// end-of-file zero-fill the tail and return `ShortRead`.
+pub fn read(
+    dev: &mut dyn BlockDevice,
+    file: &HeavenFile,
+    buf: &mut [u8],
+    offset: u64,
+) -> Result<(), FileError> {
+    let amount = buf.len();
+    let bs = file.block_size as u64;
+
+    // Short read: if reading past end-of-file, zero-fill
+    if offset >= file.byte_length {
+        buf.fill(0);
+        return Err(FileError::ShortRead);
+    }
+
+    let available = (file.byte_length - offset) as usize;
+    let to_read = amount.min(available);
+
+    let start_block = offset / bs;
+    let end_block = (offset + to_read as u64 - 1) / bs;
+    let block_count = end_block - start_block + 1;
+
+    // Bounds check
+    if start_block + block_count > file.block_count {
+        buf.fill(0);
+        return Err(FileError::ShortRead);
+    }
+
+    let start_lba = file.start_lba + start_block;
+
+    let dma_size = (block_count as usize) * file.block_size as usize;
+    let mut dma = DmaBuf::alloc(dma_size).map_err(|_| FileError::NoMem)?;
+
+    // Device read (chunked for large I/O that exceeds u16::MAX blocks)
+    chunked_read(dev, start_lba, block_count, &mut dma, file.block_size)?;
+
+    // Copy the requested byte range
+    let byte_offset_in_first_block = (offset % bs) as usize;
+    dma.copy_to_slice(&mut buf[..to_read], byte_offset_in_first_block, to_read);
+
+    // Zero-fill remainder if short read
+    if to_read < amount {
+        buf[to_read..].fill(0);
+        return Err(FileError::ShortRead);
+    }
+
+    Ok(())
+}
+
+/// Write `data` at `offset`, growing the file if needed.
+///
+/// Strategy:
+/// - Aligned writes: DMA directly
+/// - Partial-block writes: Read-Modify-Write
+/// - Growth: relocate to a new contiguous region (crash-safe ordering below)
+/// - Writes past EOF: the gap is zero-filled first, so stale blocks left by
+///   earlier truncates or other files never show through as file data
+pub fn write(
+    dev: &mut dyn BlockDevice,
+    alloc: &mut BlockAllocator,
+    ft: &mut FileTable,
+    file: &mut HeavenFile,
+    data: &[u8],
+    offset: u64,
+) -> Result<(), FileError> {
+    let amount = data.len();
+    if amount == 0 {
+        return Ok(());
+    }
+    let bs = file.block_size as u64;
+
+    if offset > file.byte_length {
+        let zeros = alloc::vec![0u8; bs as usize];
+        while file.byte_length < offset {
+            let pos = file.byte_length;
+            let n = (bs - pos % bs).min(offset - pos) as usize;
+            write(dev, alloc, ft, file, &zeros[..n], pos)?;
+        }
+    }
+
+    let start_block = offset / bs;
+    let end_block = (offset + amount as u64 - 1) / bs;
+    let block_count = end_block - start_block + 1;
+
+    if start_block + block_count > file.block_count {
+        grow(dev, alloc, ft, file, start_block + block_count)?;
+    }
+
+    let start_lba = file.start_lba + start_block;
+    let byte_offset_in_first_block = (offset % bs) as usize;
+    let is_aligned = byte_offset_in_first_block == 0 && amount.is_multiple_of(bs as usize);
+
+    let dma_size = (block_count as usize) * file.block_size as usize;
+    let mut dma = DmaBuf::alloc(dma_size).map_err(|_| FileError::NoMem)?;
+
+    if is_aligned {
+        // Fast path: direct write
+        dma.copy_from_slice(data);
+    } else {
+        // Slow path: Read-Modify-Write
+        // 1. READ existing blocks (chunked for large I/O)
+        chunked_read(dev, start_lba, block_count, &mut dma, file.block_size)?;
+
+        // 2. MODIFY: overlay the new data
+        let dst = dma.as_mut_slice();
+        dst[byte_offset_in_first_block..byte_offset_in_first_block + amount]
+            .copy_from_slice(data);
+    }
+
+    // 3. WRITE back (chunked for large I/O)
+    chunked_write(dev, start_lba, block_count, &dma, file.block_size)?;
+
+    // Update file byte length
+    let new_end = offset + amount as u64;
+    if new_end > file.byte_length {
+        file.byte_length = new_end;
+    }
+
+    Ok(())
+}
+
+/// Make room for `size` bytes without changing the file length, so a
+/// file SQLite is about to extend (SQLITE_FCNTL_SIZE_HINT) relocates once
+/// instead of once per write. Never shrinks the allocation.
+pub fn reserve(
+    dev: &mut dyn BlockDevice,
+    alloc: &mut BlockAllocator,
+    ft: &mut FileTable,
+    file: &mut HeavenFile,
+    size: u64,
+) -> Result<(), FileError> {
+    let bs = file.block_size as u64;
+    let needed = size.div_ceil(bs);
+    if needed > file.block_count {
+        grow(dev, alloc, ft, file, needed)?;
+    }
+    Ok(())
+}
+
+/// Round a block count up to the file's chunk size.
+fn round_to_chunk(file: &HeavenFile, blocks: u64) -> u64 {
+    match file.chunk_blocks {
+        0 | 1 => blocks,
+        chunk => blocks.div_ceil(chunk) * chunk,
+    }
+}
+
+/// Relocate `file` to a new contiguous region of at least `needed` blocks
+/// (rounded up to the chunk size).
+///
+/// Crash-safe ordering:
+///   1. Alloc new region
+///   2. Copy old data → new region
+///   3. Device flush (new data durable)
+///   4. Update file table to point to new region
+///   5. Defer-free old blocks: the on-disk file table still points at them
+///      until the next `sync`, so they must not be reused before then
+///
+/// If the disk is full only because of deferred frees, commit metadata
+/// early to release them and retry once.
+fn grow(
+    dev: &mut dyn BlockDevice,
+    alloc: &mut BlockAllocator,
+    ft: &mut FileTable,
+    file: &mut HeavenFile,
+    needed: u64,
+) -> Result<(), FileError> {
+    let needed = round_to_chunk(file, needed);
+    let new_start_block = match alloc.alloc(needed) {
+        Ok(start) => start,
+        Err(_) if alloc.deferred_count() > 0 => {
+            commit_metadata(dev, alloc, ft)?;
+            alloc.alloc(needed).map_err(|_| FileError::Full)?
+        }
+        Err(_) => return Err(FileError::Full),
+    };
+
+    let old_data_start = file.start_lba;
+    let old_start_block = file.start_lba - alloc.data_start_lba();
+    let old_block_count = file.block_count;
+    let new_data_start = alloc.data_start_lba() + new_start_block;
+
+    // Copy existing blocks to new region
+    let mut tmp = match DmaBuf::alloc(file.block_size as usize) {
+        Ok(t) => t,
+        Err(_) => {
+            alloc.free(new_start_block, needed);
+            return Err(FileError::NoMem);
+        }
+    };
+    for blk in 0..old_block_count {
+        if let Err(e) = dev.read_blocks(old_data_start + blk, 1, &mut tmp) {
+            alloc.free(new_start_block, needed);
+            return Err(FileError::Read(e));
+        }
+        if let Err(e) = dev.write_blocks(new_data_start + blk, 1, &tmp) {
+            alloc.free(new_start_block, needed);
+            return Err(FileError::Write(e));
+        }
+    }
+
+    // Flush to ensure new copies are durable
+    if let Err(e) = dev.flush() {
+        alloc.free(new_start_block, needed);
+        return Err(FileError::Flush(e));
+    }
+
+    // Update metadata BEFORE freeing old blocks
+    file.start_lba = new_data_start;
+    file.block_count = needed;
+
+    if let Some(entry) = ft.get_mut(file.file_table_index) {
+        entry.start_block = new_start_block;
+        entry.block_count = needed;
+    }
+
+    alloc.free_deferred(old_start_block, old_block_count);
+    Ok(())
+}
+
+/// Make the in-memory bitmap and file table durable, in that order.
+///
+/// The barrier between them matters: a file table entry that reaches the
+/// medium before its blocks are marked used would let a crash hand those
+/// blocks to another file. The reverse only leaks blocks. Frees deferred
+/// until now become reusable once the file table is durable.
+///
+/// Also used on shutdown, after the last file is closed.
+pub fn commit_metadata(
+    dev: &mut dyn BlockDevice,
+    alloc: &mut BlockAllocator,
+    ft: &mut FileTable,
+) -> Result<(), FileError> {
+    alloc.flush(dev).map_err(FileError::Flush)?;
+    dev.flush().map_err(FileError::Flush)?;
+    ft.flush(dev).map_err(FileError::Flush)?;
+    dev.flush().map_err(FileError::Flush)?;
+    alloc.release_deferred();
+    Ok(())
+}
+
+/// Flush all dirty metadata and issue a device flush.
+///
+/// This is what makes SQLite's commit durable. Without the flush command,
+/// the device's volatile write cache may reorder or lose writes on power loss.
+/// Clean metadata is not rewritten; through a `cache::Device`, a sync with
+/// nothing written since the last one issues no command.
+pub fn sync(
+    dev: &mut dyn BlockDevice,
+    alloc: &mut BlockAllocator,
+    ft: &mut FileTable,
+    file: &HeavenFile,
+) -> Result<(), FileError> {
+    // 1. Update the file table entry, if the length moved (getting the
+    //    entry mutably marks the table dirty)
+    if ft.get(file.file_table_index).is_some_and(|e| e.byte_length != file.byte_length) {
+        if let Some(entry) = ft.get_mut(file.file_table_index) {
+            entry.byte_length = file.byte_length;
+        }
+    }
+
+    // 2. Bitmap, barrier, file table, barrier — see `commit_metadata`
+    commit_metadata(dev, alloc, ft)
+}
+
+/// Truncate to `size` bytes, releasing whole blocks past the new end
+/// (rounded up to the chunk size) at the next `sync`.
+/// Truncating to a larger size is a no-op (SQLite behavior).
+pub fn truncate(
+    alloc: &mut BlockAllocator,
+    ft: &mut FileTable,
+    file: &mut HeavenFile,
+    size: u64,
+) {
+    if size > file.byte_length {
+        return;
+    }
+    file.byte_length = size;
+
+    let bs = file.block_size as u64;
+    let needed_blocks = if size == 0 {
+        // Keep at least 1 block so the file retains a valid start LBA.
+        1
+    } else {
+        round_to_chunk(file, size.div_ceil(bs))
+    };
+
+    if needed_blocks < file.block_count {
+        let old_start_block = file.start_lba - alloc.data_start_lba();
+        let excess_start = old_start_block + needed_blocks;
+        let excess_count = file.block_count - needed_blocks;
+        alloc.free_deferred(excess_start, excess_count);
+        file.block_count = needed_blocks;
+
+        if let Some(entry) = ft.get_mut(file.file_table_index) {
+            entry.block_count = needed_blocks;
+            entry.byte_length = size;
+        }
+    }
+}
+
+/// Delete a file by name. Deleting a non-existent file is not an error.
+/// The blocks become reusable at the next `sync` (see `grow`).
+pub fn delete(alloc: &mut BlockAllocator, ft: &mut FileTable, name: &[u8]) {
+    if let Some((idx, entry)) = ft.lookup(name) {
+        let start_block = entry.start_block;
+        let block_count = entry.block_count;
+
+        ft.delete(idx);
+        alloc.free_deferred(start_block, block_count);
+    }
+}
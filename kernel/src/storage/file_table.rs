@@ -6,8 +6,12 @@
 /// This is NOT a general-purpose filesystem. It maps a small number of
 /// well-known names (main.db, main.db-wal, main.db-shm, main.db-journal,
 /// temp files) to contiguous block allocations on disk.
+///
+/// The 8 bytes after the last entry hold an FNV-1a checksum of the entries.
+/// A zero checksum means "never written with a checksum" (legacy tables).
 use crate::drivers::nvme::NvmeError;
 use crate::mem::DmaBuf;
+use super::block_alloc::fnv1a64;
 use super::block_device::BlockDevice;
 
 /// Maximum file name length (including null terminator).
@@ -16,6 +20,9 @@ const MAX_NAME_LEN: usize = 64;
 /// Maximum entries in the file table.
 const MAX_ENTRIES: usize = 42;
 
+/// Byte offset of the table checksum within the file table block.
+const CHECKSUM_OFFSET: usize = MAX_ENTRIES * core::mem::size_of::<FileEntry>();
+
 /// A single file table entry — 96 bytes.
 #[repr(C)]
 #[derive(Clone, Copy)]
@@ -80,6 +87,7 @@ pub struct FileTable {
     file_table_lba: u64,
     block_size: u32,
     dirty: bool,
+    checksum_ok: bool,
 }
 
 impl FileTable {
@@ -90,6 +98,7 @@ impl FileTable {
             file_table_lba,
             block_size,
             dirty: false,
+            checksum_ok: true,
         }
     }
 
@@ -117,6 +126,13 @@ impl FileTable {
             }
         }
 
+        if CHECKSUM_OFFSET + 8 <= data.len() {
+            let stored = u64::from_le_bytes(
+                data[CHECKSUM_OFFSET..CHECKSUM_OFFSET + 8].try_into().unwrap()
+            );
+            table.checksum_ok = stored == 0 || stored == fnv1a64(&data[..CHECKSUM_OFFSET]);
+        }
+
         Ok(table)
     }
 
@@ -143,6 +159,11 @@ impl FileTable {
             }
         }
 
+        if CHECKSUM_OFFSET + 8 <= data.len() {
+            let sum = fnv1a64(&data[..CHECKSUM_OFFSET]);
+            data[CHECKSUM_OFFSET..CHECKSUM_OFFSET + 8].copy_from_slice(&sum.to_le_bytes());
+        }
+
         dev.write_blocks(self.file_table_lba, 1, &buf)?;
         self.dirty = false;
         Ok(())
@@ -224,4 +245,19 @@ impl FileTable {
     pub fn mark_dirty(&mut self) {
         self.dirty = true;
     }
+
+    /// Iterate over in-use entries with their indices.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &FileEntry)> {
+        self.entries.iter().enumerate().filter(|(_, e)| e.is_in_use())
+    }
+
+    /// Maximum number of files the table can hold.
+    pub fn capacity(&self) -> usize {
+        MAX_ENTRIES
+    }
+
+    /// Whether the on-disk checksum matched when the table was loaded.
+    pub fn checksum_ok(&self) -> bool {
+        self.checksum_ok
+    }
 }
@@ -15,6 +15,8 @@ pub struct RamDisk {
     block_size: u32,
     total_blocks: u64,
     flush_count: u64,
+    reads: u64,
+    writes: u64,
 }
 
 impl RamDisk {
@@ -26,6 +28,8 @@ impl RamDisk {
             block_size,
             total_blocks,
             flush_count: 0,
+            reads: 0,
+            writes: 0,
         }
     }
 
@@ -34,10 +38,20 @@ impl RamDisk {
         self.flush_count
     }
 
+    /// Read and write commands issued so far (for testing).
+    pub fn commands(&self) -> (u64, u64) {
+        (self.reads, self.writes)
+    }
+
     /// Read raw bytes at an offset (for test verification).
     pub fn read_raw(&self, offset: usize, len: usize) -> &[u8] {
         &self.data[offset..offset + len]
     }
+
+    /// Overwrite raw bytes at an offset (for fault injection).
+    pub fn write_raw(&mut self, offset: usize, data: &[u8]) {
+        self.data[offset..offset + data.len()].copy_from_slice(data);
+    }
 }
 
 impl BlockDevice for RamDisk {
@@ -49,6 +63,7 @@ impl BlockDevice for RamDisk {
         if start + len > self.data.len() {
             return Err(NvmeError::MediaError);
         }
+        self.reads += 1;
 
         let dst = buf.as_mut_slice();
         let copy_len = len.min(dst.len());
@@ -65,6 +80,7 @@ impl BlockDevice for RamDisk {
         if start + len > self.data.len() {
             return Err(NvmeError::MediaError);
         }
+        self.writes += 1;
 
         let src = buf.as_slice();
         let copy_len = len.min(src.len());
@@ -1,11 +1,22 @@
-mod block_alloc;
+pub mod block_alloc;
 pub mod block_device;
+pub mod cache;
+pub mod crypt;
 mod file_table;
+pub mod file_ops;
 pub mod mock_device;
+pub mod crash_device;
+mod mount;
 
 pub use block_alloc::{BlockAllocator, AllocError};
 pub use block_device::BlockDevice;
 pub use file_table::{FileTable, FileEntry};
+pub use file_ops::{HeavenFile, FileError};
+pub use mount::{MountMode, Damage, check};
 
 #[cfg(test)]
 mod tests;
+#[cfg(test)]
+mod prop_tests;
+#[cfg(test)]
+mod crash_tests;
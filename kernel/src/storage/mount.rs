@@ -0,0 +1,114 @@
+/// Mount modes and load-time consistency checks.
+///
+/// After `BlockAllocator::load` and `FileTable::load`, boot runs `check`
+/// to cross-validate the file table against the bitmap. Any damage drops
+/// the filesystem into `Degraded` mode: SQLite can still open the
+/// database read-only for inspection or backup, but nothing is written.
+use alloc::vec::Vec;
+
+use super::{BlockAllocator, FileTable};
+
+/// How the storage stack is mounted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MountMode {
+    /// Normal operation.
+    ReadWrite,
+    /// Read-only by request (`config set storage.mode ro`).
+    ReadOnly,
+    /// Read-only because `check` found damage.
+    Degraded,
+}
+
+impl MountMode {
+    /// Parse a `storage.mode` config value.
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "rw" => Some(MountMode::ReadWrite),
+            "ro" => Some(MountMode::ReadOnly),
+            _ => None,
+        }
+    }
+
+    pub fn is_writable(&self) -> bool {
+        *self == MountMode::ReadWrite
+    }
+}
+
+impl core::fmt::Display for MountMode {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            MountMode::ReadWrite => write!(f, "rw"),
+            MountMode::ReadOnly => write!(f, "ro"),
+            MountMode::Degraded => write!(f, "degraded (ro)"),
+        }
+    }
+}
+
+/// A single inconsistency found by `check`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Damage {
+    /// File table block checksum mismatch.
+    FileTableChecksum,
+    /// Entry points past the end of the data region.
+    EntryOutOfRange { index: usize },
+    /// Entry covers blocks the bitmap says are free.
+    BlocksNotAllocated { index: usize, block: u64 },
+    /// Two entries claim the same blocks.
+    Overlap { a: usize, b: usize },
+}
+
+impl core::fmt::Display for Damage {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Damage::FileTableChecksum => write!(f, "file table checksum mismatch"),
+            Damage::EntryOutOfRange { index } => {
+                write!(f, "file table entry {} out of range", index)
+            }
+            Damage::BlocksNotAllocated { index, block } => {
+                write!(f, "file table entry {} uses free block {}", index, block)
+            }
+            Damage::Overlap { a, b } => {
+                write!(f, "file table entries {} and {} overlap", a, b)
+            }
+        }
+    }
+}
+
+/// Cross-check the file table against the allocator bitmap.
+/// Returns every inconsistency found; empty means clean.
+pub fn check(alloc: &BlockAllocator, ft: &FileTable) -> Vec<Damage> {
+    let mut damage = Vec::new();
+
+    if !ft.checksum_ok() {
+        damage.push(Damage::FileTableChecksum);
+    }
+
+    let total = alloc.data_block_count();
+    let mut ranges: Vec<(usize, u64, u64)> = Vec::new();
+
+    for (index, entry) in ft.iter() {
+        let end = entry.start_block.checked_add(entry.block_count);
+        match end {
+            Some(end) if end <= total => {}
+            _ => {
+                damage.push(Damage::EntryOutOfRange { index });
+                continue;
+            }
+        }
+
+        // Report only the first free block per entry to keep the list short.
+        let start = entry.start_block;
+        if let Some(block) = (start..start + entry.block_count).find(|&b| !alloc.is_allocated(b)) {
+            damage.push(Damage::BlocksNotAllocated { index, block });
+        }
+
+        for &(other, o_start, o_count) in &ranges {
+            if start < o_start + o_count && o_start < start + entry.block_count {
+                damage.push(Damage::Overlap { a: other, b: index });
+            }
+        }
+        ranges.push((index, start, entry.block_count));
+    }
+
+    damage
+}
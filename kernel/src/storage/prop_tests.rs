@@ -0,0 +1,324 @@
+/// Randomized model tests for `file_ops` over `RamDisk`.
+///
+/// Each case runs a seeded sequence of SQLite-like operations (open, write,
+/// read, truncate, sync, delete, remount) against the real storage stack and
+/// against an in-memory model (name → bytes), checking after every step that
+/// the two agree and that `check()` finds no damage. A failing case prints
+/// its seed; rerun that seed alone by narrowing `CASES`.
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::*;
+use super::file_ops::{self, HeavenFile, INITIAL_ALLOC_BLOCKS};
+use super::mock_device::RamDisk;
+
+const BLOCK_SIZE: u32 = 4096;
+const TOTAL_BLOCKS: u64 = 2048; // 8 MiB
+const CASES: u64 = 200;
+const OPS_PER_CASE: usize = 60;
+
+/// File names SQLite actually uses, plus temp files.
+const NAMES: &[&[u8]] = &[b"main.db", b"main.db-journal", b"etilqs_1", b"etilqs_2"];
+
+/// xorshift64* — deterministic, no dependencies.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Rng(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
+
+/// Storage stack under test plus the reference model.
+struct Harness {
+    dev: RamDisk,
+    alloc: BlockAllocator,
+    ft: FileTable,
+    handles: BTreeMap<&'static [u8], HeavenFile>,
+    model: BTreeMap<&'static [u8], Vec<u8>>,
+    free_after_format: u64,
+    seed: u64,
+}
+
+impl Harness {
+    fn new(seed: u64) -> Self {
+        let mut dev = RamDisk::new(TOTAL_BLOCKS, BLOCK_SIZE);
+        let alloc = BlockAllocator::format(&mut dev, TOTAL_BLOCKS, BLOCK_SIZE).unwrap();
+        let ft = FileTable::new(alloc.data_start_lba() - 1, BLOCK_SIZE);
+        let free_after_format = alloc.free_count();
+        Harness {
+            dev,
+            alloc,
+            ft,
+            handles: BTreeMap::new(),
+            model: BTreeMap::new(),
+            free_after_format,
+            seed,
+        }
+    }
+
+    fn handle(&mut self, name: &'static [u8]) -> &mut HeavenFile {
+        if !self.handles.contains_key(name) {
+            let f = file_ops::open(&mut self.alloc, &mut self.ft, name, true)
+                .unwrap_or_else(|e| panic!("seed {}: open failed: {}", self.seed, e));
+            self.model.entry(name).or_default();
+            self.handles.insert(name, f);
+        }
+        self.handles.get_mut(name).unwrap()
+    }
+
+    fn write(&mut self, name: &'static [u8], offset: u64, data: &[u8]) {
+        let seed = self.seed;
+        self.handle(name);
+        let file = self.handles.get_mut(name).unwrap();
+        match file_ops::write(&mut self.dev, &mut self.alloc, &mut self.ft, file, data, offset) {
+            Ok(()) => {
+                let m = self.model.get_mut(name).unwrap();
+                let end = offset as usize + data.len();
+                if m.len() < end {
+                    m.resize(end, 0);
+                }
+                m[offset as usize..end].copy_from_slice(data);
+            }
+            // Running out of space is legitimate. Any zero-filled gap that
+            // was written before the failure is now part of the file.
+            Err(FileError::Full) => {
+                let m = self.model.get_mut(name).unwrap();
+                if (m.len() as u64) < file.byte_length {
+                    m.resize(file.byte_length as usize, 0);
+                }
+            }
+            Err(e) => panic!("seed {}: write failed: {}", seed, e),
+        }
+    }
+
+    fn read_and_compare(&mut self, name: &'static [u8], offset: u64, len: usize) {
+        let seed = self.seed;
+        self.handle(name);
+        let file = self.handles.get(name).unwrap();
+        let mut buf = vec![0xAAu8; len];
+        let rc = file_ops::read(&mut self.dev, file, &mut buf, offset);
+
+        let m = &self.model[name];
+        let start = (offset as usize).min(m.len());
+        let end = (offset as usize + len).min(m.len());
+        let expected_data = &m[start..end];
+        assert_eq!(&buf[..expected_data.len()], expected_data, "seed {}: read mismatch", seed);
+        assert!(buf[expected_data.len()..].iter().all(|&b| b == 0), "seed {}: no zero-fill", seed);
+        if expected_data.len() < len {
+            assert_eq!(rc, Err(FileError::ShortRead), "seed {}", seed);
+        } else {
+            assert_eq!(rc, Ok(()), "seed {}", seed);
+        }
+    }
+
+    fn truncate(&mut self, name: &'static [u8], size: u64) {
+        self.handle(name);
+        let file = self.handles.get_mut(name).unwrap();
+        file_ops::truncate(&mut self.alloc, &mut self.ft, file, size);
+        let m = self.model.get_mut(name).unwrap();
+        if (size as usize) < m.len() {
+            m.truncate(size as usize);
+        }
+    }
+
+    fn sync(&mut self, name: &'static [u8]) {
+        let seed = self.seed;
+        self.handle(name);
+        let file = self.handles.get(name).unwrap();
+        file_ops::sync(&mut self.dev, &mut self.alloc, &mut self.ft, file)
+            .unwrap_or_else(|e| panic!("seed {}: sync failed: {}", seed, e));
+    }
+
+    fn close(&mut self, name: &'static [u8]) {
+        if let Some(file) = self.handles.remove(name) {
+            file_ops::close(&mut self.ft, &file);
+        }
+    }
+
+    fn delete(&mut self, name: &'static [u8]) {
+        self.close(name);
+        file_ops::delete(&mut self.alloc, &mut self.ft, name);
+        self.model.remove(name);
+    }
+
+    /// Close everything, flush, then reload allocator and file table from
+    /// the device — as after a clean reboot.
+    fn remount(&mut self) {
+        let names: Vec<&'static [u8]> = self.handles.keys().copied().collect();
+        for name in names {
+            self.sync(name);
+            self.close(name);
+        }
+        self.alloc.flush(&mut self.dev).unwrap();
+        self.ft.flush(&mut self.dev).unwrap();
+        self.dev.flush().unwrap();
+
+        self.alloc = BlockAllocator::load(&mut self.dev).unwrap();
+        self.ft = FileTable::load(&mut self.dev, self.alloc.data_start_lba() - 1, BLOCK_SIZE).unwrap();
+        assert_eq!(self.alloc.superblock_repairs(), 0, "seed {}", self.seed);
+
+        let names: Vec<&'static [u8]> = self.model.keys().copied().collect();
+        for name in names {
+            let len = self.model[name].len();
+            self.read_and_compare(name, 0, len.max(1));
+        }
+    }
+
+    /// Structural invariants that must hold between operations.
+    fn check_invariants(&self) {
+        let damage = check(&self.alloc, &self.ft);
+        assert!(damage.is_empty(), "seed {}: damage {:?}", self.seed, damage);
+
+        let used: u64 = self.ft.iter().map(|(_, e)| e.block_count).sum();
+        assert_eq!(
+            self.alloc.free_count() + self.alloc.deferred_count(),
+            self.free_after_format - used,
+            "seed {}: leaked or double-counted blocks", self.seed
+        );
+
+        let files = self.ft.iter().count();
+        assert_eq!(files, self.model.len(), "seed {}: file count", self.seed);
+    }
+}
+
+fn fill(rng: &mut Rng, len: usize) -> Vec<u8> {
+    let byte = rng.next() as u8;
+    (0..len).map(|i| byte.wrapping_add(i as u8)).collect()
+}
+
+fn run_case(seed: u64) {
+    let mut h = Harness::new(seed);
+    let mut rng = Rng::new(seed);
+    let bs = BLOCK_SIZE as u64;
+
+    for _ in 0..OPS_PER_CASE {
+        let name = NAMES[rng.below(NAMES.len() as u64) as usize];
+        let len = h.model.get(name).map(|m| m.len() as u64).unwrap_or(0);
+
+        match rng.below(100) {
+            // Page-aligned writes (the common SQLite pattern), incl. appends
+            // that grow past the initial allocation.
+            0..=29 => {
+                let page = rng.below(len / bs + 2);
+                let pages = 1 + rng.below(3) as usize;
+                let data = fill(&mut rng, pages * bs as usize);
+                h.write(name, page * bs, &data);
+            }
+            // Unaligned writes (journal headers) — never past EOF, no holes.
+            30..=44 => {
+                let offset = rng.below(len + 1);
+                let n = 1 + rng.below(2 * bs) as usize;
+                let data = fill(&mut rng, n);
+                h.write(name, offset, &data);
+            }
+            45..=64 => {
+                let offset = rng.below(len + bs);
+                let n = 1 + rng.below(3 * bs) as usize;
+                h.read_and_compare(name, offset, n);
+            }
+            65..=72 => {
+                let size = rng.below(len + bs);
+                h.truncate(name, size);
+            }
+            73..=82 => h.sync(name),
+            83..=88 => h.close(name),
+            89..=94 => h.delete(name),
+            _ => h.remount(),
+        }
+        h.check_invariants();
+    }
+    h.remount();
+    h.check_invariants();
+}
+
+#[test]
+fn randomized_file_ops_match_model() {
+    for seed in 0..CASES {
+        run_case(seed);
+    }
+}
+
+#[test]
+fn grow_relocates_and_preserves_data() {
+    let mut h = Harness::new(u64::MAX);
+    let bs = BLOCK_SIZE as usize;
+    let data = fill(&mut Rng::new(7), bs * INITIAL_ALLOC_BLOCKS as usize);
+    h.write(b"main.db", 0, &data);
+    let before = h.handle(b"main.db").start_lba;
+
+    // One more page forces relocation to a bigger contiguous run.
+    h.write(b"main.db", data.len() as u64, &data[..bs]);
+    assert_ne!(h.handle(b"main.db").start_lba, before);
+    h.check_invariants();
+    h.read_and_compare(b"main.db", 0, data.len() + bs);
+    h.remount();
+}
+
+#[test]
+fn size_hint_preallocates_without_changing_length() {
+    let mut h = Harness::new(u64::MAX - 2);
+    let bs = BLOCK_SIZE as usize;
+    let blocks = 4 * INITIAL_ALLOC_BLOCKS;
+    h.handle(b"main.db");
+    let file = h.handles.get_mut(b"main.db".as_slice()).unwrap();
+    file_ops::reserve(&mut h.dev, &mut h.alloc, &mut h.ft, file, blocks * BLOCK_SIZE as u64).unwrap();
+    assert_eq!(file.byte_length, 0);
+    assert_eq!(file.block_count, blocks);
+    let reserved_at = file.start_lba;
+
+    // Filling the reservation must not relocate the file again.
+    let data = fill(&mut Rng::new(11), bs);
+    for i in 0..blocks {
+        h.write(b"main.db", i * BLOCK_SIZE as u64, &data);
+    }
+    assert_eq!(h.handle(b"main.db").start_lba, reserved_at);
+    h.check_invariants();
+    h.read_and_compare(b"main.db", 0, blocks as usize * bs);
+    h.remount();
+}
+
+#[test]
+fn chunk_size_rounds_growth_and_truncate() {
+    let mut h = Harness::new(u64::MAX - 3);
+    let chunk = 2 * INITIAL_ALLOC_BLOCKS;
+    h.handle(b"main.db").chunk_blocks = chunk;
+
+    let data = vec![0xA5u8; BLOCK_SIZE as usize];
+    h.write(b"main.db", INITIAL_ALLOC_BLOCKS * BLOCK_SIZE as u64, &data);
+    assert_eq!(h.handle(b"main.db").block_count, chunk);
+
+    h.truncate(b"main.db", BLOCK_SIZE as u64);
+    assert_eq!(h.handle(b"main.db").block_count, chunk);
+    h.check_invariants();
+    h.read_and_compare(b"main.db", 0, BLOCK_SIZE as usize);
+    h.remount();
+}
+
+#[test]
+fn write_until_full_keeps_model_consistent() {
+    let mut h = Harness::new(u64::MAX - 1);
+    let chunk = vec![0x5Au8; 64 * BLOCK_SIZE as usize];
+    let mut offset = 0u64;
+    // Relocation needs old + new space, so this hits Full well before the
+    // disk is nominally exhausted. The failed grow must not leak blocks.
+    for _ in 0..64 {
+        h.write(b"main.db", offset, &chunk);
+        offset = h.model[b"main.db".as_slice()].len() as u64;
+        h.check_invariants();
+    }
+    h.remount();
+    h.check_invariants();
+}
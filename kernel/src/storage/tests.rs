@@ -286,3 +286,422 @@ fn file_table_lookup_after_delete() {
     let idx = ft.create(b"d.db", 15, 3).unwrap();
     assert_eq!(idx, 1);
 }
+
+// ---- Superblock redundancy ----
+
+use mock_device::RamDisk;
+use crate::mem::DmaBuf;
+
+fn zero_block(dev: &mut RamDisk, lba: u64) {
+    let buf = DmaBuf::alloc(dev.block_size() as usize).unwrap();
+    dev.write_blocks(lba, 1, &buf).unwrap();
+}
+
+#[test]
+fn format_writes_backup_superblocks() {
+    let mut dev = RamDisk::new(256, 4096);
+    BlockAllocator::format(&mut dev, 256, 4096).unwrap();
+
+    let primary = dev.read_raw(0, 80).to_vec();
+    for lba in block_alloc::backup_superblock_lbas(256) {
+        let off = lba as usize * 4096;
+        assert_eq!(dev.read_raw(off, 80), &primary[..]);
+    }
+}
+
+#[test]
+fn format_reserves_mid_disk_backup() {
+    let mut dev = RamDisk::new(256, 4096);
+    let mut alloc = BlockAllocator::format(&mut dev, 256, 4096).unwrap();
+    let free = alloc.free_count();
+
+    let [mid, _] = block_alloc::backup_superblock_lbas(256);
+    while let Ok(block) = alloc.alloc(1) {
+        assert_ne!(alloc.to_lba(block), mid);
+        assert!(alloc.to_lba(block) < 255);
+    }
+    assert_eq!(free, 256 - 3 - 2); // superblock, bitmap, file table, 2 backups
+}
+
+#[test]
+fn load_clean_needs_no_repair() {
+    let mut dev = RamDisk::new(256, 4096);
+    BlockAllocator::format(&mut dev, 256, 4096).unwrap();
+
+    let alloc = BlockAllocator::load(&mut dev).unwrap();
+    assert_eq!(alloc.superblock_repairs(), 0);
+}
+
+#[test]
+fn load_repairs_primary_from_backup() {
+    let mut dev = RamDisk::new(256, 4096);
+    let formatted = BlockAllocator::format(&mut dev, 256, 4096).unwrap();
+    let original = dev.read_raw(0, 80).to_vec();
+
+    zero_block(&mut dev, 0);
+    let flushes = dev.flush_count();
+
+    let alloc = BlockAllocator::load(&mut dev).unwrap();
+    assert_eq!(alloc.superblock_repairs(), 1);
+    assert_eq!(alloc.data_start_lba(), formatted.data_start_lba());
+    assert_eq!(alloc.free_count(), formatted.free_count());
+    assert_eq!(dev.read_raw(0, 80), &original[..]);
+    assert!(dev.flush_count() > flushes);
+
+    // Second load is clean again
+    let again = BlockAllocator::load(&mut dev).unwrap();
+    assert_eq!(again.superblock_repairs(), 0);
+}
+
+#[test]
+fn load_repairs_corrupt_checksum() {
+    let mut dev = RamDisk::new(256, 4096);
+    BlockAllocator::format(&mut dev, 256, 4096).unwrap();
+
+    // Flip a geometry byte in the primary, keep magic intact
+    let mut buf = DmaBuf::alloc(4096).unwrap();
+    dev.read_blocks(0, 1, &mut buf).unwrap();
+    buf.as_mut_slice()[16] ^= 0xFF;
+    dev.write_blocks(0, 1, &buf).unwrap();
+
+    let alloc = BlockAllocator::load(&mut dev).unwrap();
+    assert_eq!(alloc.superblock_repairs(), 1);
+}
+
+#[test]
+fn load_repairs_damaged_backups() {
+    let mut dev = RamDisk::new(256, 4096);
+    BlockAllocator::format(&mut dev, 256, 4096).unwrap();
+
+    let [mid, last] = block_alloc::backup_superblock_lbas(256);
+    zero_block(&mut dev, mid);
+    zero_block(&mut dev, last);
+
+    let alloc = BlockAllocator::load(&mut dev).unwrap();
+    assert_eq!(alloc.superblock_repairs(), 2);
+    let primary = dev.read_raw(0, 80).to_vec();
+    assert_eq!(dev.read_raw(mid as usize * 4096, 80), &primary[..]);
+    assert_eq!(dev.read_raw(last as usize * 4096, 80), &primary[..]);
+}
+
+#[test]
+fn load_fails_when_all_copies_lost() {
+    let mut dev = RamDisk::new(256, 4096);
+    BlockAllocator::format(&mut dev, 256, 4096).unwrap();
+
+    zero_block(&mut dev, 0);
+    for lba in block_alloc::backup_superblock_lbas(256) {
+        zero_block(&mut dev, lba);
+    }
+
+    assert!(BlockAllocator::load(&mut dev).is_err());
+}
+
+#[test]
+fn load_small_block_size() {
+    // 512-byte blocks: the superblock struct is larger than one block
+    let mut dev = RamDisk::new(512, 512);
+    BlockAllocator::format(&mut dev, 512, 512).unwrap();
+    zero_block(&mut dev, 0);
+
+    let alloc = BlockAllocator::load(&mut dev).unwrap();
+    assert_eq!(alloc.superblock_repairs(), 1);
+    assert_eq!(alloc.block_size(), 512);
+}
+
+// ---- Consistency check / mount modes ----
+
+#[test]
+fn check_clean_filesystem() {
+    let mut alloc = BlockAllocator::new();
+    alloc.init_for_test(100, 4096, 10);
+    let mut ft = FileTable::new(5, 4096);
+
+    let a = alloc.alloc(10).unwrap();
+    ft.create(b"main.db", a, 10).unwrap();
+    let b = alloc.alloc(5).unwrap();
+    ft.create(b"main.db-journal", b, 5).unwrap();
+
+    assert!(check(&alloc, &ft).is_empty());
+}
+
+#[test]
+fn check_detects_free_blocks_in_use() {
+    let mut alloc = BlockAllocator::new();
+    alloc.init_for_test(100, 4096, 10);
+    let mut ft = FileTable::new(5, 4096);
+
+    ft.create(b"main.db", 20, 4).unwrap(); // never allocated
+    assert_eq!(
+        check(&alloc, &ft),
+        alloc::vec![Damage::BlocksNotAllocated { index: 0, block: 20 }]
+    );
+}
+
+#[test]
+fn check_detects_overlap_and_range() {
+    let mut alloc = BlockAllocator::new();
+    alloc.init_for_test(100, 4096, 10);
+    let mut ft = FileTable::new(5, 4096);
+
+    let a = alloc.alloc(10).unwrap();
+    ft.create(b"a.db", a, 10).unwrap();
+    ft.create(b"b.db", a + 5, 5).unwrap();
+    ft.create(b"c.db", 95, 10).unwrap();
+
+    let damage = check(&alloc, &ft);
+    assert!(damage.contains(&Damage::Overlap { a: 0, b: 1 }));
+    assert!(damage.contains(&Damage::EntryOutOfRange { index: 2 }));
+}
+
+#[test]
+fn file_table_checksum_roundtrip() {
+    let mut dev = RamDisk::new(16, 4096);
+    let mut ft = FileTable::new(2, 4096);
+    ft.create(b"main.db", 0, 4).unwrap();
+    ft.flush(&mut dev).unwrap();
+
+    let loaded = FileTable::load(&mut dev, 2, 4096).unwrap();
+    assert!(loaded.checksum_ok());
+    assert!(loaded.lookup(b"main.db").is_some());
+}
+
+#[test]
+fn file_table_checksum_detects_corruption() {
+    let mut dev = RamDisk::new(16, 4096);
+    let mut ft = FileTable::new(2, 4096);
+    ft.create(b"main.db", 0, 4).unwrap();
+    ft.flush(&mut dev).unwrap();
+
+    let mut buf = DmaBuf::alloc(4096).unwrap();
+    dev.read_blocks(2, 1, &mut buf).unwrap();
+    buf.as_mut_slice()[64] ^= 0x01; // start_block of entry 0
+    dev.write_blocks(2, 1, &buf).unwrap();
+
+    let loaded = FileTable::load(&mut dev, 2, 4096).unwrap();
+    assert!(!loaded.checksum_ok());
+
+    let alloc = BlockAllocator::new();
+    assert!(check(&alloc, &loaded).contains(&Damage::FileTableChecksum));
+}
+
+#[test]
+fn file_table_legacy_zero_checksum_accepted() {
+    // A zeroed block (fresh format, pre-checksum tables) loads as clean.
+    let mut dev = RamDisk::new(16, 4096);
+    let loaded = FileTable::load(&mut dev, 2, 4096).unwrap();
+    assert!(loaded.checksum_ok());
+}
+
+#[test]
+fn mount_mode_parse() {
+    assert_eq!(MountMode::parse("rw"), Some(MountMode::ReadWrite));
+    assert_eq!(MountMode::parse("ro"), Some(MountMode::ReadOnly));
+    assert_eq!(MountMode::parse("degraded"), None);
+    assert!(MountMode::ReadWrite.is_writable());
+    assert!(!MountMode::ReadOnly.is_writable());
+    assert!(!MountMode::Degraded.is_writable());
+}
+
+#[test]
+fn device_error_keeps_layer_trail() {
+    use crate::error::{KernelError, Layer};
+    use crate::drivers::nvme::NvmeError;
+
+    // A file whose blocks lie past the end of the disk
+    let mut dev = RamDisk::new(16, 4096);
+    let file = HeavenFile {
+        file_table_index: 0,
+        start_lba: 64,
+        block_count: 1,
+        byte_length: 4096,
+        block_size: 4096,
+        chunk_blocks: 0,
+    };
+    let mut buf = [0u8; 16];
+    let err = file_ops::read(&mut dev, &file, &mut buf, 0).unwrap_err();
+    assert_eq!(err, FileError::Read(NvmeError::MediaError));
+    assert_eq!(err.device_error(), Some(NvmeError::MediaError));
+
+    let report = KernelError::from(err).context(Layer::Vfs, "xRead");
+    assert_eq!(report.layer(), Layer::Nvme);
+    assert_eq!(report.layers().collect::<alloc::vec::Vec<_>>(), [Layer::Vfs, Layer::Storage, Layer::Nvme]);
+    assert_eq!(
+        alloc::format!("{}", report),
+        "vfs: xRead → storage: read → nvme: MediaError: media error (test stub)"
+    );
+
+    // Errors the storage layer raises itself start there
+    let full = KernelError::from(FileError::Full);
+    assert_eq!(alloc::format!("{}", full), "storage: Full: no space left");
+}
+
+// ---- Encryption at rest ----
+
+#[test]
+fn xts_matches_reference_vector() {
+    // XTS-AES-256, key 00..3f, data unit 0x1234 (reference: OpenSSL)
+    let key: [u8; crypt::KEY_LEN] = core::array::from_fn(|i| i as u8);
+    let plain: alloc::vec::Vec<u8> = (0..512).map(|i| (i * 7) as u8).collect();
+    let xts = crypt::Xts::new(&key);
+    let mut buf = plain.clone();
+    xts.encrypt(0x1234, &mut buf);
+    let hex = |b: &[u8]| b.iter().map(|x| alloc::format!("{:02x}", x)).collect::<alloc::string::String>();
+    assert_eq!(hex(&buf[..32]), "f0a4c124e2eb9a38697cf02ae86ea58d5a2300fd301a9ddcd4d0752bcba1d091");
+    assert_eq!(hex(&buf[496..]), "7101aa44e881df82ae2ed04f65ce8ed1");
+    xts.decrypt(0x1234, &mut buf);
+    assert_eq!(buf, plain);
+}
+
+#[test]
+fn pbkdf2_matches_rfc7914() {
+    let mut out = [0u8; 64];
+    crate::secrets::pbkdf2_sha256(b"passwd", b"salt", 1, &mut out);
+    let hex: alloc::string::String = out.iter().map(|x| alloc::format!("{:02x}", x)).collect();
+    assert_eq!(
+        hex,
+        "55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc\
+         49ca9cccf179b645991664b39d77ef317c71b845b1e30bd509112041d3a19783"
+    );
+}
+
+#[test]
+fn hkdf_expand_matches_rfc5869() {
+    // RFC 5869 test case 1, from its PRK
+    let prk: [u8; 32] = core::array::from_fn(|i| {
+        u8::from_str_radix(&"077709362c2e32df0ddc3f0dc47bba6390b6c73bb50f9c3122ec844ad7c2b3e5"[2 * i..2 * i + 2], 16).unwrap()
+    });
+    let info: alloc::vec::Vec<u8> = (0xf0..=0xf9).collect();
+    let mut out = [0u8; 42];
+    crate::secrets::hkdf_expand(&prk, &info, &mut out);
+    let hex: alloc::string::String = out.iter().map(|x| alloc::format!("{:02x}", x)).collect();
+    assert_eq!(hex, "3cb25f25faacd57a90434f64d0362f2a2d2d0a90cf1a5a4c5db02d56ecc4c5bf34007208d5b887185865");
+}
+
+#[test]
+fn crypt_header_unseals_legacy_wrapping() {
+    // A "HVNXTS01" header: the key XOR bare PBKDF2, as written before `secrets`
+    let key = [0x5au8; crypt::KEY_LEN];
+    let mut header = crypt::CryptHeader::seal(&key, b"hunter2", [7; 16], 10);
+    let mut wrap = [0u8; crypt::KEY_LEN];
+    crate::secrets::pbkdf2_sha256(b"hunter2", &[7; 16], 10, &mut wrap);
+    header.magic = 0x3130_5354_584E_5648;
+    header.wrapped_key = core::array::from_fn(|i| key[i] ^ wrap[i]);
+    assert!(header.is_encrypted() && header.is_legacy());
+    assert_eq!(header.unseal(b"hunter2"), Some(key));
+    assert_eq!(header.unseal(b"hunter3"), None);
+}
+
+#[test]
+fn crypt_header_unseals_only_with_passphrase() {
+    let key = [0x5au8; crypt::KEY_LEN];
+    let header = crypt::CryptHeader::seal(&key, b"hunter2", [7; 16], 10);
+    assert!(header.is_encrypted());
+    assert_eq!(header.unseal(b"hunter2"), Some(key));
+    assert_eq!(header.unseal(b"hunter3"), None);
+    assert!(!crypt::CryptHeader::NONE.is_encrypted());
+    assert_eq!(crypt::CryptHeader::NONE.unseal(b""), None);
+}
+
+#[test]
+fn encrypted_filesystem_roundtrip() {
+    let key = [0x42u8; crypt::KEY_LEN];
+    let xts = crypt::Xts::new(&key);
+    let mut disk = RamDisk::new(256, 4096);
+    let header = crypt::CryptHeader::seal(&key, b"pw", [1; 16], 10);
+    let (data_lba, ft_lba) = {
+        let mut dev = crypt::Device::new(&mut disk, Some(&xts));
+        let mut alloc = BlockAllocator::format(&mut dev, 256, 4096).unwrap();
+        block_alloc::set_crypt_header(&mut dev, &header).unwrap();
+        let mut ft = FileTable::new(alloc.data_start_lba() - 1, 4096);
+        let mut file = file_ops::open(&mut alloc, &mut ft, b"main.db", true).unwrap();
+        file_ops::write(&mut dev, &mut alloc, &mut ft, &mut file, b"secret rows", 0).unwrap();
+        file_ops::sync(&mut dev, &mut alloc, &mut ft, &file).unwrap();
+        (file.start_lba, alloc.data_start_lba() - 1)
+    };
+
+    // Nothing readable on the medium but the superblocks, which carry the header
+    let raw = disk.read_raw(data_lba as usize * 4096, 4096);
+    assert!(!raw.windows(11).any(|w| w == b"secret rows"));
+    assert!(!disk.read_raw(ft_lba as usize * 4096, 4096).windows(7).any(|w| w == b"main.db"));
+    assert_eq!(block_alloc::crypt_header(&mut disk).unwrap(), header);
+
+    let unsealed = block_alloc::crypt_header(&mut disk).unwrap().unseal(b"pw").unwrap();
+    let xts = crypt::Xts::new(&unsealed);
+    let mut dev = crypt::Device::new(&mut disk, Some(&xts));
+    let mut alloc = BlockAllocator::load(&mut dev).unwrap();
+    assert_eq!(alloc.superblock_repairs(), 0);
+    let mut ft = FileTable::load(&mut dev, alloc.data_start_lba() - 1, 4096).unwrap();
+    let file = file_ops::open(&mut alloc, &mut ft, b"main.db", false).unwrap();
+    let mut buf = [0u8; 11];
+    file_ops::read(&mut dev, &file, &mut buf, 0).unwrap();
+    assert_eq!(&buf, b"secret rows");
+
+    // Without the key the file table is noise
+    assert!(!FileTable::load(&mut disk, ft_lba, 4096).is_ok_and(|ft| ft.lookup(b"main.db").is_some()));
+}
+
+// ---- Read-ahead and write coalescing ----
+
+#[test]
+fn sequential_reads_are_served_from_readahead() {
+    let mut disk = RamDisk::new(128, 4096);
+    for lba in 0..128u64 {
+        disk.write_raw(lba as usize * 4096, &[lba as u8; 4096]);
+    }
+    let mut cache = cache::BlockCache::new();
+    let mut dev = cache::Device::new(&mut disk, &mut cache);
+    let mut buf = DmaBuf::alloc(4096).unwrap();
+    for lba in 10..50u64 {
+        dev.read_blocks(lba, 1, &mut buf).unwrap();
+        assert!(buf.as_slice()[..4096].iter().all(|&b| b == lba as u8), "block {}", lba);
+    }
+    // One read to spot the pattern, then a window of 1 + 32 twice over
+    assert_eq!(disk.commands().0, 3);
+    let stats = cache.stats();
+    assert_eq!((stats.misses, stats.hits, stats.prefetched), (3, 37, 64));
+}
+
+#[test]
+fn held_writes_are_read_back_and_coalesced_at_flush() {
+    let mut disk = RamDisk::new(64, 4096);
+    let mut cache = cache::BlockCache::new();
+    let mut dev = cache::Device::new(&mut disk, &mut cache);
+    let mut buf = DmaBuf::alloc(4096).unwrap();
+    // Pages written out of order, with a gap at 23
+    for lba in [21u64, 20, 22, 24, 25] {
+        buf.as_mut_slice().fill(lba as u8);
+        dev.write_blocks(lba, 1, &buf).unwrap();
+    }
+    dev.read_blocks(22, 1, &mut buf).unwrap();
+    assert!(buf.as_slice()[..4096].iter().all(|&b| b == 22));
+    dev.flush().unwrap();
+
+    assert_eq!(disk.commands(), (0, 2)); // 20..=22 and 24..=25
+    assert_eq!(disk.flush_count(), 1);
+    assert!(disk.read_raw(21 * 4096, 4096).iter().all(|&b| b == 21));
+    assert!(disk.read_raw(23 * 4096, 4096).iter().all(|&b| b == 0));
+    assert_eq!((cache.stats().written, cache.stats().submitted), (5, 2));
+    assert_eq!(cache.dirty_count(), 0);
+}
+
+#[test]
+fn sync_with_nothing_written_issues_no_commands() {
+    let mut disk = RamDisk::new(256, 4096);
+    let mut cache = cache::BlockCache::new();
+    let mut dev = cache::Device::new(&mut disk, &mut cache);
+    let mut alloc = BlockAllocator::format(&mut dev, 256, 4096).unwrap();
+    let mut ft = FileTable::new(alloc.data_start_lba() - 1, 4096);
+    let mut file = file_ops::open(&mut alloc, &mut ft, b"main.db", true).unwrap();
+    file_ops::write(&mut dev, &mut alloc, &mut ft, &mut file, b"rows", 0).unwrap();
+    file_ops::sync(&mut dev, &mut alloc, &mut ft, &file).unwrap();
+    drop(dev);
+    let before = (disk.commands(), disk.flush_count());
+
+    let mut dev = cache::Device::new(&mut disk, &mut cache);
+    file_ops::sync(&mut dev, &mut alloc, &mut ft, &file).unwrap();
+    drop(dev);
+    assert_eq!((disk.commands(), disk.flush_count()), before);
+    // Both barriers of the second sync
+    assert_eq!(cache.stats().flushes_skipped, 2);
+}
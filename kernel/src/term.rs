@@ -0,0 +1,180 @@
+/// ANSI colour and style for console output.
+///
+/// The shell, the `sql` table output and the agent loop wrap text in
+/// `paint(style, text)`; when colour is off that is the text unchanged, so
+/// callers never branch on it. Only what reaches the console is styled —
+/// session logs, files and CSV/JSON output stay plain.
+///
+/// `config set term.color on|off|auto`. `auto` (the default) asks the
+/// peer for its cursor position (`ESC [6n`) when the shell starts: a
+/// terminal answers within `PROBE_MS`, a script piping into the serial
+/// port does not, and gets plain text. The probe parks the cursor in the
+/// far corner first, so the answer also gives the screen height, which
+/// the pager uses unless `term.rows` overrides it.
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+
+use crate::arch::x86_64::serial::SERIAL;
+use crate::time;
+
+/// How long `detect` waits for the cursor position report.
+const PROBE_MS: u64 = 200;
+
+/// `term.color` setting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ColorMode {
+    Auto = 0,
+    On = 1,
+    Off = 2,
+}
+
+impl ColorMode {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "auto" => Some(ColorMode::Auto),
+            "on" => Some(ColorMode::On),
+            "off" => Some(ColorMode::Off),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ColorMode::Auto => "auto",
+            ColorMode::On => "on",
+            ColorMode::Off => "off",
+        }
+    }
+}
+
+static MODE: AtomicU8 = AtomicU8::new(ColorMode::Auto as u8);
+
+/// Result of the last `detect` (auto mode only).
+static PEER_IS_TERMINAL: AtomicBool = AtomicBool::new(false);
+
+/// Screen height reported by the last `detect` (0 = no answer).
+static PEER_ROWS: AtomicUsize = AtomicUsize::new(0);
+
+pub fn set_mode(mode: ColorMode) {
+    MODE.store(mode as u8, Ordering::Relaxed);
+}
+
+pub fn mode() -> ColorMode {
+    match MODE.load(Ordering::Relaxed) {
+        1 => ColorMode::On,
+        2 => ColorMode::Off,
+        _ => ColorMode::Auto,
+    }
+}
+
+/// Is console output being styled?
+pub fn enabled() -> bool {
+    match mode() {
+        ColorMode::On => true,
+        ColorMode::Off => false,
+        ColorMode::Auto => PEER_IS_TERMINAL.load(Ordering::Relaxed),
+    }
+}
+
+/// Screen height in lines: `term.rows` if set, else what the peer
+/// reported. None when neither is known — e.g. a script, which must not
+/// be stopped at a pager prompt.
+pub fn rows() -> Option<usize> {
+    if let Some(rows) = crate::sqlite::config_get("term.rows").and_then(|v| v.trim().parse::<usize>().ok()) {
+        return (rows > 0).then_some(rows);
+    }
+    match PEER_ROWS.load(Ordering::Relaxed) {
+        0 => None,
+        rows => Some(rows),
+    }
+}
+
+/// Probe the peer with a cursor position request and remember whether it
+/// answered like a terminal (`ESC [ <row> ; <col> R`), and with what
+/// height. Input typed during the probe is discarded.
+pub fn detect() -> bool {
+    let mut serial = SERIAL.lock();
+    while serial.try_read_byte().is_some() {}
+    // Save cursor, move to the bottom-right corner, ask, restore
+    serial.write_str_raw("\x1b7\x1b[999;999H\x1b[6n\x1b8");
+
+    // 0: want ESC, 1: want '[', 2: row digits, 3: column digits until 'R'
+    let mut state = 0;
+    let mut row = 0usize;
+    let mut answered = false;
+    let start = time::monotonic_ms();
+    while time::monotonic_ms() - start < PROBE_MS {
+        let Some(b) = serial.try_read_byte() else {
+            core::hint::spin_loop();
+            continue;
+        };
+        state = match (state, b) {
+            (_, 0x1B) => 1,
+            (1, b'[') => {
+                row = 0;
+                2
+            }
+            (2, b'0'..=b'9') => {
+                row = (row * 10 + (b - b'0') as usize).min(10_000);
+                2
+            }
+            (2, b';') => 3,
+            (3, b'0'..=b'9') => 3,
+            (3, b'R') => {
+                answered = true;
+                break;
+            }
+            _ => 0,
+        };
+    }
+    drop(serial);
+    PEER_IS_TERMINAL.store(answered, Ordering::Relaxed);
+    PEER_ROWS.store(if answered { row } else { 0 }, Ordering::Relaxed);
+    answered
+}
+
+/// A text style, as an SGR parameter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Style {
+    Bold,
+    Dim,
+    Red,
+    Green,
+    Yellow,
+    Cyan,
+}
+
+impl Style {
+    fn sgr(&self) -> &'static str {
+        match self {
+            Style::Bold => "1",
+            Style::Dim => "2",
+            Style::Red => "31",
+            Style::Green => "32",
+            Style::Yellow => "33",
+            Style::Cyan => "36",
+        }
+    }
+}
+
+/// Text that displays with `style` when colour is on.
+pub struct Painted<T> {
+    style: Style,
+    text: T,
+}
+
+/// Wrap `text` in `style` for display.
+pub fn paint<T: fmt::Display>(style: Style, text: T) -> Painted<T> {
+    Painted { style, text }
+}
+
+impl<T: fmt::Display> fmt::Display for Painted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if enabled() {
+            write!(f, "\x1b[{}m{}\x1b[0m", self.style.sgr(), self.text)
+        } else {
+            write!(f, "{}", self.text)
+        }
+    }
+}
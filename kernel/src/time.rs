@@ -0,0 +1,210 @@
+/// Time — the monotonic clock, delays and deadlines, all on the TSC.
+///
+/// `arch::x86_64::timer` calibrates the TSC against the PIT at boot; this
+/// module is what everything else reads time through:
+///
+/// - `monotonic_ms`/`monotonic_us`/`uptime_secs` for timestamps,
+/// - `Instant` (a TSC reading) and `core::time::Duration` for measuring,
+/// - `Deadline`, a one-shot timeout to poll or idle until,
+/// - `delay` and `sleep` to wait,
+/// - `epoch_secs` for a UTC calendar time in seconds since 1970.
+///
+/// A wait idles (see `arch::x86_64::idle`). Where the local APIC has a
+/// TSC-deadline timer, the wait arms it for the deadline and ends on
+/// time; otherwise it idles through whole PIT ticks and spins the last.
+///
+/// Before calibration the TSC is taken to run at `FALLBACK_TSC_PER_MS`,
+/// fast enough that no timeout is shorter than asked.
+use core::ops::{Add, Sub};
+pub use core::time::Duration;
+
+use crate::arch::x86_64::cpu::rdtsc;
+use crate::arch::x86_64::{idle, lapic, timer};
+
+/// TSC rate assumed before calibration (5 GHz).
+pub const FALLBACK_TSC_PER_MS: u64 = 5_000_000;
+
+/// Largest disagreement between the boot calibration and a new
+/// measurement, in tenths of a percent, before the TSC counts as drifted.
+pub const DRIFT_TOLERANCE_PERMILLE: u64 = 20;
+
+/// TSC ticks per millisecond, or the fallback before calibration.
+fn tsc_per_ms() -> u64 {
+    match timer::tsc_freq_hz() {
+        0 => FALLBACK_TSC_PER_MS,
+        _ => timer::tsc_per_ms().max(1),
+    }
+}
+
+/// TSC ticks in `d` at `per_ms` ticks per millisecond, saturating.
+pub fn ticks_at(d: Duration, per_ms: u64) -> u64 {
+    let ticks = d.as_nanos() * per_ms as u128 / 1_000_000;
+    ticks.min(u64::MAX as u128) as u64
+}
+
+/// How long `ticks` TSC ticks last at `per_ms` ticks per millisecond.
+pub fn duration_at(ticks: u64, per_ms: u64) -> Duration {
+    let nanos = ticks as u128 * 1_000_000 / per_ms.max(1) as u128;
+    Duration::new((nanos / 1_000_000_000) as u64, (nanos % 1_000_000_000) as u32)
+}
+
+/// TSC ticks in `d` at the calibrated rate.
+pub fn ticks(d: Duration) -> u64 {
+    ticks_at(d, tsc_per_ms())
+}
+
+/// How long `ticks` TSC ticks last at the calibrated rate.
+pub fn duration(ticks: u64) -> Duration {
+    duration_at(ticks, tsc_per_ms())
+}
+
+/// A point in time: a TSC reading.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Self {
+        Instant(rdtsc())
+    }
+
+    /// The instant at TSC value `tsc`.
+    pub fn from_tsc(tsc: u64) -> Self {
+        Instant(tsc)
+    }
+
+    pub fn tsc(&self) -> u64 {
+        self.0
+    }
+
+    /// Time from `earlier` to this instant; zero if `earlier` is later.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        duration(self.0.saturating_sub(earlier.0))
+    }
+
+    /// Time since this instant.
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    /// Time from boot (the end of calibration) to this instant.
+    pub fn since_boot(&self) -> Duration {
+        self.duration_since(Instant(timer::boot_tsc()))
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, d: Duration) -> Instant {
+        Instant(self.0.saturating_add(ticks(d)))
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+/// Milliseconds since boot.
+pub fn monotonic_ms() -> u64 {
+    Instant::now().since_boot().as_millis() as u64
+}
+
+/// Microseconds since boot, for timing short operations.
+pub fn monotonic_us() -> u64 {
+    Instant::now().since_boot().as_micros() as u64
+}
+
+/// Seconds since boot.
+pub fn uptime_secs() -> u64 {
+    Instant::now().since_boot().as_secs()
+}
+
+/// A one-shot timeout: poll `expired`, or `wait` for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Deadline(Instant);
+
+impl Deadline {
+    /// `d` from now.
+    pub fn after(d: Duration) -> Self {
+        Deadline(Instant::now() + d)
+    }
+
+    /// `ms` milliseconds from now.
+    pub fn after_ms(ms: u64) -> Self {
+        Deadline::after(Duration::from_millis(ms))
+    }
+
+    /// One that never expires.
+    pub fn never() -> Self {
+        Deadline(Instant(u64::MAX))
+    }
+
+    pub fn at(&self) -> Instant {
+        self.0
+    }
+
+    pub fn expired(&self) -> bool {
+        Instant::now() >= self.0
+    }
+
+    /// Time left; zero once expired.
+    pub fn remaining(&self) -> Duration {
+        self.0.duration_since(Instant::now())
+    }
+
+    /// Idle until the deadline passes.
+    pub fn wait(&self) {
+        self.wait_unless(|| false);
+    }
+
+    /// Idle until the deadline passes or `stop` says otherwise, checked
+    /// at least once a tick. True if the deadline was reached.
+    pub fn wait_unless(&self, mut stop: impl FnMut() -> bool) -> bool {
+        let tick = Duration::from_micros(1_000_000 / idle::TICK_HZ);
+        loop {
+            if self.expired() {
+                return true;
+            }
+            if stop() {
+                return false;
+            }
+            if lapic::deadline_timer() {
+                idle::wait_until(self.0.tsc());
+            } else if self.remaining() > tick {
+                idle::wait();
+            } else {
+                core::hint::spin_loop();
+            }
+        }
+    }
+}
+
+/// Wait `d`, idling.
+pub fn delay(d: Duration) {
+    Deadline::after(d).wait();
+}
+
+/// Wait `d` unless `interrupted` turns true first; true if the whole of
+/// `d` passed.
+pub fn sleep(d: Duration, interrupted: fn() -> bool) -> bool {
+    Deadline::after(d).wait_unless(interrupted)
+}
+
+/// Seconds since 1970 of a UTC date and time.
+pub fn epoch_secs(year: i64, month: i64, day: i64, hour: i64, minute: i64, second: i64) -> Option<i64> {
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+    // Days from civil (Howard Hinnant's algorithm)
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe - 719_468;
+    Some(days * 86_400 + hour * 3600 + minute * 60 + second)
+}
@@ -3,106 +3,49 @@
 /// This module implements the ~20 methods that SQLite requires from a VFS.
 /// SQLite sees "files" (main.db, .wal, .shm, .journal); the VFS translates
 /// every file operation into NVMe block reads/writes via the block allocator.
+/// The block-level logic lives in `storage::file_ops`; this layer owns the
+/// locks and maps `FileError` onto SQLite result codes.
 ///
 /// Key design decisions:
 /// - xRead: always reads full blocks, copies the requested byte range
 /// - xWrite: Read-Modify-Write for partial-block writes, fast path for aligned
-/// - xSync: bitmap flush + file table flush + NVMe Flush command = ACID
+/// - xSync: bitmap flush, NVMe Flush, file table flush, NVMe Flush = ACID;
+///   skipped when nothing changed since the last one, timed per phase
+///   (`SyncStats`), and logged past `storage.sync_warn_ms`
+/// - Blocks freed by relocation, truncate or delete are only reused after the
+///   next xSync, so a crash never exposes another file's data through the
+///   on-disk file table (verified by `storage::crash_tests`)
+/// - xLock: in-kernel SHARED/RESERVED/PENDING/EXCLUSIVE per file, so the
+///   writer and the read-only connections can share heaven.db
 /// - xShm*: RAM-backed (trivial in a single-address-space kernel)
+/// - Read-only/degraded mounts: every mutating method returns SQLITE_READONLY
+/// - Encryption at rest: with a disk key set, all block I/O goes through
+///   `storage::crypt::Device` (XTS-AES-256, tweaked by LBA)
+/// - Read-ahead for sequential reads and write-back of adjacent dirty blocks
+///   as one command, at the next flush (`storage::cache`)
 use core::ffi::c_int;
-use core::sync::atomic::Ordering;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
+use alloc::collections::BTreeMap;
 use alloc::vec::Vec;
 use spin::Mutex;
 
-use crate::drivers::nvme::{NVME, NvmeDriver};
-use crate::mem::DmaBuf;
-use crate::storage::{BlockAllocator, FileTable};
-
-/// Maximum blocks per single NVMe I/O command (u16::MAX).
-const MAX_BLOCKS_PER_IO: u64 = u16::MAX as u64;
-
-/// Read blocks from NVMe, splitting into chunks if block_count exceeds u16::MAX.
-fn chunked_read(
-    nvme: &mut NvmeDriver,
-    start_lba: u64,
-    block_count: u64,
-    dma: &mut DmaBuf,
-    block_size: u32,
-) -> Result<(), ()> {
-    let mut remaining = block_count;
-    let mut lba = start_lba;
-    let mut byte_offset = 0usize;
-
-    while remaining > 0 {
-        let chunk = remaining.min(MAX_BLOCKS_PER_IO) as u16;
-        let chunk_bytes = chunk as usize * block_size as usize;
-
-        if remaining == block_count && chunk as u64 == block_count {
-            // Single chunk — use the DMA buffer directly
-            if nvme.read_blocks(lba, chunk, dma).is_err() {
-                return Err(());
-            }
-        } else {
-            // Multiple chunks — read into a temporary buffer and copy
-            let mut tmp = DmaBuf::alloc(chunk_bytes).map_err(|_| ())?;
-            if nvme.read_blocks(lba, chunk, &mut tmp).is_err() {
-                return Err(());
-            }
-            dma.as_mut_slice()[byte_offset..byte_offset + chunk_bytes]
-                .copy_from_slice(&tmp.as_slice()[..chunk_bytes]);
-        }
-
-        remaining -= chunk as u64;
-        lba += chunk as u64;
-        byte_offset += chunk_bytes;
-    }
-    Ok(())
-}
-
-/// Write blocks to NVMe, splitting into chunks if block_count exceeds u16::MAX.
-fn chunked_write(
-    nvme: &mut NvmeDriver,
-    start_lba: u64,
-    block_count: u64,
-    dma: &DmaBuf,
-    block_size: u32,
-) -> Result<(), ()> {
-    let mut remaining = block_count;
-    let mut lba = start_lba;
-    let mut byte_offset = 0usize;
-
-    while remaining > 0 {
-        let chunk = remaining.min(MAX_BLOCKS_PER_IO) as u16;
-        let chunk_bytes = chunk as usize * block_size as usize;
-
-        if remaining == block_count && chunk as u64 == block_count {
-            // Single chunk — use the DMA buffer directly
-            if nvme.write_blocks(lba, chunk, dma).is_err() {
-                return Err(());
-            }
-        } else {
-            // Multiple chunks — copy slice into a temporary buffer and write
-            let mut tmp = DmaBuf::alloc(chunk_bytes).map_err(|_| ())?;
-            tmp.as_mut_slice()[..chunk_bytes]
-                .copy_from_slice(&dma.as_slice()[byte_offset..byte_offset + chunk_bytes]);
-            if nvme.write_blocks(lba, chunk, &tmp).is_err() {
-                return Err(());
-            }
-        }
+use crate::time;
+use crate::drivers::nvme::{NvmeDriver, NVME};
+use crate::error::{KernelError, Layer};
+use crate::serial_println;
+use crate::storage::cache::{self, BlockCache, CacheStats};
+use crate::storage::crypt::{self, CryptHeader, Xts};
+use crate::storage::{block_alloc, file_ops, BlockAllocator, FileError, FileTable, MountMode};
 
-        remaining -= chunk as u64;
-        lba += chunk as u64;
-        byte_offset += chunk_bytes;
-    }
-    Ok(())
-}
+pub use crate::storage::HeavenFile;
 
 // ---- SQLite constants (from sqlite3.h) ----
 
 const SQLITE_OK: c_int = 0;
 const SQLITE_ERROR: c_int = 1;
 const SQLITE_BUSY: c_int = 5;
+const SQLITE_READONLY: c_int = 8;
 const SQLITE_IOERR: c_int = 10;
 const SQLITE_FULL: c_int = 13;
 const SQLITE_CANTOPEN: c_int = 14;
@@ -123,9 +66,18 @@ const SQLITE_OPEN_SUBJOURNAL: c_int = 0x00002000;
 const SQLITE_OPEN_WAL: c_int = 0x00080000;
 const SQLITE_OPEN_CREATE: c_int = 0x00000004;
 
-const SQLITE_FCNTL_SIZE_HINT: c_int = 5;
-const SQLITE_FCNTL_CHUNK_SIZE: c_int = 6;
-const SQLITE_FCNTL_PRAGMA: c_int = 14;
+const SQLITE_IOCAP_ATOMIC512: c_int = 0x00000002;
+const SQLITE_IOCAP_ATOMIC1K: c_int = 0x00000004;
+const SQLITE_IOCAP_ATOMIC2K: c_int = 0x00000008;
+const SQLITE_IOCAP_ATOMIC4K: c_int = 0x00000010;
+const SQLITE_IOCAP_SAFE_APPEND: c_int = 0x00000200;
+const SQLITE_IOCAP_POWERSAFE_OVERWRITE: c_int = 0x00001000;
+
+pub const SQLITE_LOCK_NONE: c_int = 0;
+pub const SQLITE_LOCK_SHARED: c_int = 1;
+pub const SQLITE_LOCK_RESERVED: c_int = 2;
+pub const SQLITE_LOCK_PENDING: c_int = 3;
+pub const SQLITE_LOCK_EXCLUSIVE: c_int = 4;
 
 const SQLITE_SHM_NLOCK: usize = 8;
 const SQLITE_SHM_LOCK: c_int = 2;
@@ -133,25 +85,6 @@ const SQLITE_SHM_UNLOCK: c_int = 1;
 const SQLITE_SHM_SHARED: c_int = 4;
 const SQLITE_SHM_EXCLUSIVE: c_int = 8;
 
-// ---- Internal file handle ----
-
-/// Default initial allocation for a new file (in blocks).
-const INITIAL_ALLOC_BLOCKS: u64 = 16; // 64 KiB at 4096 block size
-
-/// Per-open-file state. Stored alongside the sqlite3_file header.
-pub struct HeavenFile {
-    /// Index into the file table.
-    pub file_table_index: usize,
-    /// Cached start LBA (absolute, not data-block index).
-    pub start_lba: u64,
-    /// Cached block count.
-    pub block_count: u64,
-    /// Cached byte length.
-    pub byte_length: u64,
-    /// Block size (from NVMe).
-    pub block_size: u32,
-}
-
 // ---- Shared Memory for WAL ----
 
 /// WAL shared memory state.
@@ -194,397 +127,644 @@ impl ShmLockState {
 
 static SHM: Mutex<Option<ShmState>> = Mutex::new(None);
 
+// ---- Database file locks (rollback journal) ----
+
+/// Lock state of one file shared by every connection that has it open.
+///
+/// Same model as os_unix's in-process locks: any number of SHARED holders,
+/// at most one handle above SHARED. PENDING keeps new readers out while a
+/// writer waits for the existing ones to finish.
+#[derive(Clone, Copy, Default)]
+struct FileLockState {
+    /// Handles holding SHARED or higher.
+    shared_count: u32,
+    /// Level of the one handle above SHARED, or NONE.
+    writer: c_int,
+}
+
 // ---- Main VFS Implementation ----
 
+/// Snapshot of the VFS counters.
+#[derive(Debug, Clone, Copy)]
+pub struct VfsStatus {
+    pub mode: MountMode,
+    pub block_size: u32,
+    pub data_blocks: u64,
+    pub free_blocks: u64,
+    /// Freed but not reusable until the next xSync.
+    pub deferred_blocks: u64,
+    pub files: usize,
+    pub max_files: usize,
+    /// Blocks read ahead of sequential reads (`storage.readahead`).
+    pub readahead: u16,
+    pub cache: CacheStats,
+    pub sync: SyncStats,
+}
+
+impl core::fmt::Display for VfsStatus {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "mode={} block_size={} blocks={} free={} deferred={} files={}/{} readahead={} {} {}",
+            self.mode, self.block_size, self.data_blocks, self.free_blocks,
+            self.deferred_blocks, self.files, self.max_files, self.readahead, self.cache,
+            self.sync,
+        )
+    }
+}
+
+/// xSync log threshold when `storage.sync_warn_ms` is unset.
+pub const DEFAULT_SYNC_WARN_MS: u64 = 100;
+
+/// Where xSync time goes, in microseconds summed over all syncs.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SyncStats {
+    pub syncs: u64,
+    /// Syncs with nothing written since the last flush: no NVMe command.
+    pub skipped: u64,
+    /// Syncs longer than `storage.sync_warn_ms`.
+    pub slow: u64,
+    /// Waiting for the NVMe, cache, allocator and file table locks.
+    pub lock_us: u64,
+    /// Writing back data blocks held by the cache.
+    pub data_us: u64,
+    /// Bitmap, barrier, file table, barrier.
+    pub commit_us: u64,
+    /// The slowest sync, end to end.
+    pub max_us: u64,
+}
+
+impl core::fmt::Display for SyncStats {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "syncs={} syncs_skipped={} syncs_slow={} sync_lock_us={} sync_data_us={} sync_commit_us={} sync_max_us={}",
+            self.syncs, self.skipped, self.slow, self.lock_us, self.data_us, self.commit_us, self.max_us,
+        )
+    }
+}
+
 /// The HeavenOS VFS — holds references to block allocator and file table.
 pub struct HeavenVfs {
+    /// Disk key, if the disk is encrypted.
+    key: Mutex<Option<Xts>>,
+    /// Read-ahead window and held writes, above the encryption.
+    cache: Mutex<BlockCache>,
     allocator: Mutex<BlockAllocator>,
     file_table: Mutex<FileTable>,
+    mode: Mutex<MountMode>,
+    /// Report IOCAP guarantees to SQLite (`storage.iocap`).
+    device_caps: AtomicBool,
+    /// Per-file lock state, keyed by file table index.
+    locks: Mutex<BTreeMap<usize, FileLockState>>,
+    sync_stats: Mutex<SyncStats>,
+    /// Log syncs slower than this (`storage.sync_warn_ms`; 0 = never).
+    sync_warn_ms: AtomicU64,
 }
 
 impl HeavenVfs {
     /// Create a new VFS backed by a block allocator and file table.
-    pub fn new(allocator: BlockAllocator, file_table: FileTable) -> Self {
+    pub fn new(allocator: BlockAllocator, file_table: FileTable, mode: MountMode) -> Self {
         Self {
+            key: Mutex::new(None),
+            cache: Mutex::new(BlockCache::new()),
             allocator: Mutex::new(allocator),
             file_table: Mutex::new(file_table),
+            mode: Mutex::new(mode),
+            device_caps: AtomicBool::new(true),
+            locks: Mutex::new(BTreeMap::new()),
+            sync_stats: Mutex::new(SyncStats::default()),
+            sync_warn_ms: AtomicU64::new(DEFAULT_SYNC_WARN_MS),
         }
     }
 
-    // ---- xOpen ----
+    /// Current mount mode.
+    pub fn mode(&self) -> MountMode {
+        *self.mode.lock()
+    }
 
-    /// Open a file. Creates it if SQLITE_OPEN_CREATE is set and it doesn't exist.
-    /// Lock order: allocator → file_table (NVME not needed here).
-    pub fn open(&self, name: &[u8], flags: c_int) -> Result<HeavenFile, c_int> {
+    /// Switch mount mode at runtime. A degraded mount stays degraded.
+    pub fn set_mode(&self, mode: MountMode) -> Result<(), &'static str> {
+        let mut current = self.mode.lock();
+        if *current == MountMode::Degraded && mode != MountMode::Degraded {
+            return Err("filesystem is damaged; refusing to leave degraded mode");
+        }
+        *current = mode;
+        Ok(())
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        !self.mode().is_writable()
+    }
+
+    /// Make the bitmap and file table durable and issue NVMe Flush.
+    /// Called on shutdown once the database is closed, so even metadata no
+    /// xSync covered (e.g. a final delete) reaches the medium.
+    pub fn flush_all(&self) -> Result<(), KernelError> {
+        if self.is_read_only() {
+            return Ok(());
+        }
+
+        // Lock order: NVME → key → cache → allocator → file_table
+        let mut nvme_guard = NVME.lock();
+        let nvme = nvme_guard.as_mut().ok_or_else(|| {
+            KernelError::new(Layer::Nvme, "NotInitialized", "NVMe not available")
+                .context(Layer::Vfs, "flush")
+        })?;
+        let key = self.key.lock();
+        let mut cache = self.cache.lock();
         let mut alloc = self.allocator.lock();
         let mut ft = self.file_table.lock();
+        let dev = &mut cache::Device::new(crypt::Device::new(nvme, key.as_ref()), &mut cache);
+        file_ops::commit_metadata(dev, &mut alloc, &mut ft)
+            .map_err(|e| KernelError::from(e).context(Layer::Vfs, "flush"))
+    }
+
+    /// Lend a free data block to `f`, by LBA, for I/O that must not touch
+    /// live data (`selftest`). The allocator stays locked meanwhile, so
+    /// nothing else is given the block; it is free again afterwards.
+    pub fn with_scratch_block<R>(&self, f: impl FnOnce(&mut NvmeDriver, u64) -> R) -> Result<R, KernelError> {
+        if self.is_read_only() {
+            return Err(KernelError::new(Layer::Vfs, "ReadOnly", "storage is not writable"));
+        }
+
+        // Lock order: NVME → cache → allocator
+        let mut nvme_guard = NVME.lock();
+        let nvme = nvme_guard.as_mut().ok_or_else(|| {
+            KernelError::new(Layer::Nvme, "NotInitialized", "NVMe not available")
+                .context(Layer::Vfs, "scratch block")
+        })?;
+        let mut cache = self.cache.lock();
+        let mut alloc = self.allocator.lock();
+        let block = alloc.alloc(1).map_err(|e| {
+            KernelError::new(Layer::Storage, alloc::format!("{:?}", e), e)
+                .context(Layer::Vfs, "scratch block")
+        })?;
+        let result = f(nvme, alloc.to_lba(block));
+        cache.invalidate(); // `f` went around the cache
+        alloc.free(block, 1);
+        Ok(result)
+    }
 
-        let block_size = alloc.block_size();
-
-        // Look up existing file
-        if let Some((idx, entry)) = ft.lookup(name) {
-            let start_lba = alloc.data_start_lba() + entry.start_block;
-            return Ok(HeavenFile {
-                file_table_index: idx,
-                start_lba,
-                block_count: entry.block_count,
-                byte_length: entry.byte_length,
-                block_size,
-            });
+    /// Use `key` for all I/O from now on. Set at mount, before SQLite opens
+    /// anything; the allocator and file table must have been loaded with it.
+    pub fn set_key(&self, key: Option<Xts>) {
+        *self.key.lock() = key;
+    }
+
+    /// The disk's encryption header (`CryptHeader::NONE` if unencrypted).
+    pub fn encryption(&self) -> Result<CryptHeader, KernelError> {
+        let mut nvme_guard = NVME.lock();
+        let nvme = nvme_guard.as_mut().ok_or_else(|| {
+            KernelError::new(Layer::Nvme, "NotInitialized", "NVMe not available")
+                .context(Layer::Vfs, "encryption")
+        })?;
+        block_alloc::crypt_header(nvme)
+            .map_err(|e| KernelError::from(e).context(Layer::Vfs, "encryption"))
+    }
+
+    /// Rewrite every block of the device under `key` (None: in the clear)
+    /// and store `header`, which must wrap that key. One of `key` and the
+    /// current key must be None. Everything else waits on the NVMe lock
+    /// meanwhile. Progress is kept in the superblocks, so an interrupted
+    /// run is finished at the next mount (`crypt::resume`).
+    pub fn reencrypt(
+        &self,
+        key: Option<Xts>,
+        header: &CryptHeader,
+        progress: impl Fn(u64, u64),
+    ) -> Result<(), KernelError> {
+        let ctx = |e: KernelError| e.context(Layer::Vfs, "re-encrypt");
+        if self.is_read_only() {
+            return Err(ctx(KernelError::new(Layer::Vfs, "ReadOnly", "storage is not writable")));
         }
 
-        // File doesn't exist — create if allowed
-        if flags & SQLITE_OPEN_CREATE == 0 {
-            return Err(SQLITE_CANTOPEN);
+        // Lock order: NVME → key → cache → allocator → file_table
+        let mut nvme_guard = NVME.lock();
+        let nvme = nvme_guard.as_mut().ok_or_else(|| {
+            ctx(KernelError::new(Layer::Nvme, "NotInitialized", "NVMe not available"))
+        })?;
+        let mut old = self.key.lock();
+        let mut cache = self.cache.lock();
+        let mut alloc = self.allocator.lock();
+        let mut ft = self.file_table.lock();
+        if !block_alloc::supports_encryption(nvme).map_err(|e| ctx(e.into()))? {
+            return Err(ctx(KernelError::new(
+                Layer::Storage,
+                "Unsupported",
+                "legacy layout has no backup superblocks; reformat to encrypt",
+            )));
         }
+        // The header that wraps the key for as long as the conversion runs
+        let (xts, direction, sealed) = match (old.as_ref(), key.as_ref()) {
+            (None, Some(new)) => (new, crypt::Direction::Encrypt, *header),
+            (Some(current), None) => {
+                let sealed = block_alloc::crypt_header(nvme).map_err(|e| ctx(e.into()))?;
+                (current, crypt::Direction::Decrypt, sealed)
+            }
+            _ => {
+                return Err(ctx(KernelError::new(
+                    Layer::Vfs,
+                    "InvalidArgument",
+                    "only turning encryption on or off is supported",
+                )))
+            }
+        };
+        // Also writes back the cache; what it still holds is plaintext, valid under any key
+        let dev = &mut cache::Device::new(crypt::Device::new(nvme, old.as_ref()), &mut cache);
+        file_ops::commit_metadata(dev, &mut alloc, &mut ft).map_err(|e| ctx(e.into()))?;
+
+        crypt::convert(nvme, xts, direction, &sealed, progress).map_err(|e| ctx(e.into()))?;
+        *old = key;
+        Ok(())
+    }
 
-        // Allocate initial blocks
-        let start_block = alloc.alloc(INITIAL_ALLOC_BLOCKS)
-            .map_err(|_| SQLITE_FULL)?;
+    /// Store `header`, which must wrap the current key under another
+    /// passphrase. Only the superblocks change.
+    pub fn rewrap(&self, header: &CryptHeader) -> Result<(), KernelError> {
+        if self.is_read_only() {
+            return Err(KernelError::new(Layer::Vfs, "ReadOnly", "storage is not writable"));
+        }
+        let mut nvme_guard = NVME.lock();
+        let nvme = nvme_guard.as_mut().ok_or_else(|| {
+            KernelError::new(Layer::Nvme, "NotInitialized", "NVMe not available")
+                .context(Layer::Vfs, "rewrap key")
+        })?;
+        block_alloc::set_crypt_header(nvme, header)
+            .map_err(|e| KernelError::from(e).context(Layer::Vfs, "rewrap key"))
+    }
 
-        let idx = ft.create(name, start_block, INITIAL_ALLOC_BLOCKS)
-            .ok_or(SQLITE_FULL)?;
+    // ---- xOpen ----
 
-        let start_lba = alloc.data_start_lba() + start_block;
+    /// Open a file. Creates it if SQLITE_OPEN_CREATE is set and it doesn't exist.
+    /// Lock order: allocator → file_table (NVME not needed here).
+    pub fn open(&self, name: &[u8], flags: c_int) -> Result<HeavenFile, c_int> {
+        let mut alloc = self.allocator.lock();
+        let mut ft = self.file_table.lock();
 
-        Ok(HeavenFile {
-            file_table_index: idx,
-            start_lba,
-            block_count: INITIAL_ALLOC_BLOCKS,
-            byte_length: 0,
-            block_size,
+        let create = flags & SQLITE_OPEN_CREATE != 0 && !self.is_read_only();
+        file_ops::open(&mut alloc, &mut ft, name, create).map_err(|e| match e {
+            FileError::NotFound => SQLITE_CANTOPEN,
+            e => fail("xOpen", e),
         })
     }
 
     // ---- xClose ----
 
     pub fn close(&self, file: &HeavenFile) -> c_int {
-        // Sync the file table entry with the cached byte_length.
-        let mut ft = self.file_table.lock();
-        if let Some(entry) = ft.get_mut(file.file_table_index) {
-            entry.byte_length = file.byte_length;
+        if self.is_read_only() {
+            return SQLITE_OK;
         }
+        file_ops::close(&mut self.file_table.lock(), file);
         SQLITE_OK
     }
 
     // ---- xRead ----
 
-    /// Read `amount` bytes at `offset` from the file into `buf`.
-    ///
-    /// Strategy: read full blocks from NVMe, copy the requested byte range.
+    /// Read `buf.len()` bytes at `offset` from the file into `buf`.
     pub fn read(
         &self,
         file: &HeavenFile,
         buf: &mut [u8],
         offset: u64,
     ) -> c_int {
-        let amount = buf.len();
-        let bs = file.block_size as u64;
-
-        // Short read: if reading past end-of-file, zero-fill
-        if offset >= file.byte_length {
-            buf.fill(0);
-            return SQLITE_IOERR_SHORT_READ;
-        }
-
-        let available = (file.byte_length - offset) as usize;
-        let to_read = amount.min(available);
-
-        let start_block = offset / bs;
-        let end_block = (offset + to_read as u64 - 1) / bs;
-        let block_count = end_block - start_block + 1;
-
-        // Bounds check
-        if start_block + block_count > file.block_count {
-            buf.fill(0);
-            return SQLITE_IOERR_SHORT_READ;
-        }
-
-        let start_lba = file.start_lba + start_block;
-
         let mut nvme_guard = NVME.lock();
         let nvme = match nvme_guard.as_mut() {
             Some(n) => n,
             None => return SQLITE_IOERR,
         };
 
-        let dma_size = (block_count as usize) * file.block_size as usize;
-        let mut dma = match DmaBuf::alloc(dma_size) {
-            Ok(d) => d,
-            Err(_) => return SQLITE_IOERR_NOMEM,
-        };
-
-        // NVMe read (chunked for large I/O that exceeds u16::MAX blocks)
-        if chunked_read(nvme, start_lba, block_count, &mut dma, file.block_size).is_err() {
-            return SQLITE_IOERR_READ;
+        let key = self.key.lock();
+        let mut cache = self.cache.lock();
+        let dev = &mut cache::Device::new(crypt::Device::new(nvme, key.as_ref()), &mut cache);
+        match file_ops::read(dev, file, buf, offset) {
+            Ok(()) => SQLITE_OK,
+            Err(e) => fail("xRead", e),
         }
-
-        // Copy the requested byte range
-        let byte_offset_in_first_block = (offset % bs) as usize;
-        dma.copy_to_slice(&mut buf[..to_read], byte_offset_in_first_block, to_read);
-
-        // Zero-fill remainder if short read
-        if to_read < amount {
-            buf[to_read..].fill(0);
-            return SQLITE_IOERR_SHORT_READ;
-        }
-
-        SQLITE_OK
     }
 
     // ---- xWrite ----
 
-    /// Write `data` at `offset` to the file.
-    ///
-    /// Strategy:
-    /// - Aligned writes: DMA directly
-    /// - Partial-block writes: Read-Modify-Write
+    /// Write `data` at `offset` to the file, relocating it if it must grow.
+    /// Lock order: NVME → key → cache → allocator → file_table.
     pub fn write(
         &self,
         file: &mut HeavenFile,
         data: &[u8],
         offset: u64,
     ) -> c_int {
-        let amount = data.len();
-        let bs = file.block_size as u64;
+        if self.is_read_only() {
+            return SQLITE_READONLY;
+        }
 
-        let start_block = offset / bs;
-        let end_block = (offset + amount as u64 - 1) / bs;
-        let block_count = end_block - start_block + 1;
+        let mut nvme_guard = NVME.lock();
+        let nvme = match nvme_guard.as_mut() {
+            Some(n) => n,
+            None => return SQLITE_IOERR,
+        };
+        let key = self.key.lock();
+        let mut cache = self.cache.lock();
+        let mut alloc = self.allocator.lock();
+        let mut ft = self.file_table.lock();
 
-        // Grow file if needed.
-        // Lock order: NVME → allocator → file_table.
-        if start_block + block_count > file.block_count {
-            let needed = start_block + block_count;
+        let dev = &mut cache::Device::new(crypt::Device::new(nvme, key.as_ref()), &mut cache);
+        let rc = match file_ops::write(dev, &mut alloc, &mut ft, file, data, offset) {
+            Ok(()) => SQLITE_OK,
+            Err(e) => fail("xWrite", e),
+        };
+        publish_length(&mut ft, file);
+        rc
+    }
+
+    // ---- xSync — THE ACID GUARANTEE ----
 
-            // Step 1: Take NVME lock first (consistent lock ordering).
+    /// Flush all dirty metadata and issue NVMe Flush.
+    ///
+    /// This is the function that makes SQLite's commit durable.
+    /// Without the NVMe Flush command, the device's volatile write cache
+    /// may reorder or lose writes on power loss.
+    ///
+    /// Group commit falls out of the dirty tracking: callers serialize on
+    /// the locks, and one that gets them after another's sync already made
+    /// its writes durable finds nothing to do and issues no command. There
+    /// is deliberately no window to wait for more syncs to join: with no
+    /// scheduler and no second CPU, nothing else can run while a caller
+    /// waits, so a window would only add its length to every commit. It
+    /// belongs here, before taking the locks, once there are threads.
+    pub fn sync(&self, file: &HeavenFile) -> c_int {
+        // Nothing can be dirty on a read-only mount.
+        if self.is_read_only() {
+            return SQLITE_OK;
+        }
+
+        let start = time::monotonic_us();
+        let (result, locked, written, skipped) = {
+            // Hold all the locks for the entire sync to ensure atomicity.
+            // Lock order: NVME → key → cache → allocator → file_table (consistent to prevent deadlock).
             let mut nvme_guard = NVME.lock();
             let nvme = match nvme_guard.as_mut() {
                 Some(n) => n,
-                None => return SQLITE_IOERR,
+                None => return SQLITE_IOERR_FSYNC,
             };
 
-            // Step 2: Take allocator lock.
+            let key = self.key.lock();
+            let mut cache = self.cache.lock();
             let mut alloc = self.allocator.lock();
+            let mut ft = self.file_table.lock();
+            let locked = time::monotonic_us();
+
+            let flushes = cache.stats().flushes;
+            let mut dev = cache::Device::new(crypt::Device::new(nvme, key.as_ref()), &mut cache);
+            // The data on its own first, to time it apart from the commit
+            let result = dev.write_back().map_err(FileError::Flush);
+            let written = time::monotonic_us();
+            let result = result.and_then(|()| file_ops::sync(&mut dev, &mut alloc, &mut ft, file));
+            (result, locked, written, cache.stats().flushes == flushes)
+        };
+        self.record_sync(start, locked, written, time::monotonic_us(), skipped);
 
-            // Try to allocate a new contiguous region and relocate.
-            // Crash-safe ordering:
-            //   1. Alloc new region
-            //   2. Copy old data → new region
-            //   3. NVMe Flush (new data durable)
-            //   4. Update file table to point to new region
-            //   5. Free old blocks (safe: file table already points to new region)
-            match alloc.alloc(needed) {
-                Ok(new_start_block) => {
-                    let old_data_start = file.start_lba;
-                    let old_start_block = file.start_lba - alloc.data_start_lba();
-                    let old_block_count = file.block_count;
-                    let new_data_start = alloc.data_start_lba() + new_start_block;
-
-                    // Copy existing blocks to new region
-                    let copy_bs = file.block_size as usize;
-                    if let Ok(mut tmp) = DmaBuf::alloc(copy_bs) {
-                        for blk in 0..old_block_count {
-                            if nvme.read_blocks(old_data_start + blk, 1, &mut tmp).is_err() {
-                                alloc.free(new_start_block, needed);
-                                return SQLITE_IOERR_READ;
-                            }
-                            if nvme.write_blocks(new_data_start + blk, 1, &tmp).is_err() {
-                                alloc.free(new_start_block, needed);
-                                return SQLITE_IOERR_WRITE;
-                            }
-                        }
-                    } else {
-                        alloc.free(new_start_block, needed);
-                        return SQLITE_IOERR_NOMEM;
-                    }
+        match result {
+            Ok(()) => SQLITE_OK,
+            Err(e) => {
+                fail("xSync", e);
+                SQLITE_IOERR_FSYNC
+            }
+        }
+    }
 
-                    // NVMe Flush to ensure new copies are durable
-                    if nvme.flush().is_err() {
-                        alloc.free(new_start_block, needed);
-                        return SQLITE_IOERR_FSYNC;
-                    }
+    /// Add one sync's phase times (monotonic microseconds) to `SyncStats`,
+    /// and log it if it was slow.
+    fn record_sync(&self, start: u64, locked: u64, written: u64, done: u64, skipped: bool) {
+        let total = done - start;
+        let warn_ms = self.sync_warn_ms.load(Ordering::Relaxed);
+        let slow = warn_ms != 0 && total > warn_ms * 1000;
+        {
+            let mut stats = self.sync_stats.lock();
+            stats.syncs += 1;
+            stats.skipped += u64::from(skipped);
+            stats.slow += u64::from(slow);
+            stats.lock_us += locked - start;
+            stats.data_us += written - locked;
+            stats.commit_us += done - written;
+            stats.max_us = stats.max_us.max(total);
+        }
+        if slow {
+            serial_println!(
+                "[vfs] slow xSync: {} ms (locks {} us, data {} us, commit {} us)",
+                total / 1000, locked - start, written - locked, done - written
+            );
+        }
+    }
 
-                    // Update metadata BEFORE freeing old blocks
-                    file.start_lba = new_data_start;
-                    file.block_count = needed;
+    /// Log syncs slower than `ms` milliseconds (0: never).
+    pub fn set_sync_warn_ms(&self, ms: u64) {
+        self.sync_warn_ms.store(ms, Ordering::Relaxed);
+    }
 
-                    let mut ft = self.file_table.lock();
-                    if let Some(entry) = ft.get_mut(file.file_table_index) {
-                        entry.start_block = new_start_block;
-                        entry.block_count = needed;
-                    }
-                    drop(ft);
+    // ---- xFileSize ----
 
-                    // Free old blocks (now safe)
-                    alloc.free(old_start_block, old_block_count);
-                }
-                Err(_) => {
-                    return SQLITE_FULL;
-                }
-            }
-            drop(alloc);
-            drop(nvme_guard);
-        }
+    pub fn file_size(&self, file: &HeavenFile) -> Result<u64, c_int> {
+        Ok(file.byte_length)
+    }
 
-        let start_lba = file.start_lba + start_block;
-        let byte_offset_in_first_block = (offset % bs) as usize;
-        let is_aligned = byte_offset_in_first_block == 0 && amount % (bs as usize) == 0;
+    // ---- xTruncate ----
 
-        let mut nvme_guard = NVME.lock();
-        let nvme = match nvme_guard.as_mut() {
-            Some(n) => n,
-            None => return SQLITE_IOERR,
-        };
+    pub fn truncate(&self, file: &mut HeavenFile, size: u64) -> c_int {
+        if self.is_read_only() {
+            return SQLITE_READONLY;
+        }
+        let mut alloc = self.allocator.lock();
+        let mut ft = self.file_table.lock();
+        file_ops::truncate(&mut alloc, &mut ft, file, size);
+        publish_length(&mut ft, file);
+        SQLITE_OK
+    }
 
-        let dma_size = (block_count as usize) * file.block_size as usize;
+    // ---- xLock / xUnlock / xCheckReservedLock ----
 
-        if is_aligned {
-            // Fast path: direct write
-            let mut dma = match DmaBuf::alloc(dma_size) {
-                Ok(d) => d,
-                Err(_) => return SQLITE_IOERR_NOMEM,
-            };
-            dma.copy_from_slice(data);
+    /// Raise `held` towards `want`. On SQLITE_BUSY `held` may be left at
+    /// PENDING, as SQLite expects; it retries (busy timeout) or unlocks.
+    pub fn lock(&self, file_index: usize, held: &mut c_int, want: c_int) -> c_int {
+        if *held >= want {
+            return SQLITE_OK;
+        }
+        let mut locks = self.locks.lock();
+        let state = locks.entry(file_index).or_default();
 
-            if chunked_write(nvme, start_lba, block_count, &dma, file.block_size).is_err() {
-                return SQLITE_IOERR_WRITE;
+        match want {
+            SQLITE_LOCK_SHARED => {
+                if state.writer >= SQLITE_LOCK_PENDING {
+                    return SQLITE_BUSY;
+                }
+                state.shared_count += 1;
+                *held = SQLITE_LOCK_SHARED;
             }
-        } else {
-            // Slow path: Read-Modify-Write
-            let mut dma = match DmaBuf::alloc(dma_size) {
-                Ok(d) => d,
-                Err(_) => return SQLITE_IOERR_NOMEM,
-            };
-
-            // 1. READ existing blocks (chunked for large I/O)
-            if chunked_read(nvme, start_lba, block_count, &mut dma, file.block_size).is_err() {
-                return SQLITE_IOERR_READ;
+            SQLITE_LOCK_RESERVED => {
+                if state.writer != SQLITE_LOCK_NONE {
+                    return SQLITE_BUSY;
+                }
+                state.writer = SQLITE_LOCK_RESERVED;
+                *held = SQLITE_LOCK_RESERVED;
             }
-
-            // 2. MODIFY: overlay the new data
-            let dst = dma.as_mut_slice();
-            dst[byte_offset_in_first_block..byte_offset_in_first_block + amount]
-                .copy_from_slice(data);
-
-            // 3. WRITE back (chunked for large I/O)
-            if chunked_write(nvme, start_lba, block_count, &dma, file.block_size).is_err() {
-                return SQLITE_IOERR_WRITE;
+            _ => {
+                // EXCLUSIVE, possibly straight from SHARED
+                if *held < SQLITE_LOCK_RESERVED && state.writer != SQLITE_LOCK_NONE {
+                    return SQLITE_BUSY;
+                }
+                state.writer = SQLITE_LOCK_PENDING;
+                *held = SQLITE_LOCK_PENDING;
+                if state.shared_count > 1 {
+                    return SQLITE_BUSY; // other readers still active
+                }
+                state.writer = SQLITE_LOCK_EXCLUSIVE;
+                *held = SQLITE_LOCK_EXCLUSIVE;
             }
         }
+        SQLITE_OK
+    }
 
-        // Update file byte length
-        let new_end = offset + amount as u64;
-        if new_end > file.byte_length {
-            file.byte_length = new_end;
+    /// Lower `held` to `want` (SHARED or NONE).
+    pub fn unlock(&self, file_index: usize, held: &mut c_int, want: c_int) -> c_int {
+        if *held <= want {
+            return SQLITE_OK;
         }
-
+        let mut locks = self.locks.lock();
+        if let Some(state) = locks.get_mut(&file_index) {
+            if *held > SQLITE_LOCK_SHARED {
+                state.writer = SQLITE_LOCK_NONE;
+            }
+            if want == SQLITE_LOCK_NONE {
+                state.shared_count = state.shared_count.saturating_sub(1);
+            }
+            if state.shared_count == 0 && state.writer == SQLITE_LOCK_NONE {
+                locks.remove(&file_index);
+            }
+        }
+        *held = want;
         SQLITE_OK
     }
 
-    // ---- xSync — THE ACID GUARANTEE ----
+    /// Does any handle hold RESERVED or higher on this file?
+    pub fn check_reserved(&self, file_index: usize) -> bool {
+        self.locks
+            .lock()
+            .get(&file_index)
+            .is_some_and(|s| s.writer >= SQLITE_LOCK_RESERVED)
+    }
+
+    /// Reload location and length from the file table. Called when a handle
+    /// takes its first lock, since another connection may have grown,
+    /// relocated or truncated the file since this handle last looked.
+    /// Lock order: allocator → file_table.
+    pub fn refresh(&self, file: &mut HeavenFile) {
+        let alloc = self.allocator.lock();
+        let ft = self.file_table.lock();
+        if let Some(entry) = ft.get(file.file_table_index) {
+            file.start_lba = alloc.data_start_lba() + entry.start_block;
+            file.block_count = entry.block_count;
+            file.byte_length = entry.byte_length;
+        }
+    }
+
+    // ---- xFileControl ----
+
+    /// SQLITE_FCNTL_SIZE_HINT: preallocate room for `size` bytes.
+    /// Lock order: NVME → key → cache → allocator → file_table.
+    pub fn size_hint(&self, file: &mut HeavenFile, size: u64) -> c_int {
+        if self.is_read_only() {
+            return SQLITE_READONLY;
+        }
 
-    /// Flush all dirty metadata and issue NVMe Flush.
-    ///
-    /// This is the function that makes SQLite's WAL commit durable.
-    /// Without the NVMe Flush command, the device's volatile write cache
-    /// may reorder or lose writes on power loss.
-    pub fn sync(&self, file: &HeavenFile) -> c_int {
-        // Hold all three locks for the entire sync to ensure atomicity.
-        // Lock order: NVME → allocator → file_table (consistent to prevent deadlock).
         let mut nvme_guard = NVME.lock();
         let nvme = match nvme_guard.as_mut() {
             Some(n) => n,
-            None => return SQLITE_IOERR_FSYNC,
+            None => return SQLITE_IOERR,
         };
-
+        let key = self.key.lock();
+        let mut cache = self.cache.lock();
         let mut alloc = self.allocator.lock();
         let mut ft = self.file_table.lock();
 
-        // 1. Update file table entry
-        if let Some(entry) = ft.get_mut(file.file_table_index) {
-            entry.byte_length = file.byte_length;
-        }
-
-        // 2. Flush block allocator bitmap to disk
-        if alloc.flush(nvme).is_err() {
-            return SQLITE_IOERR_FSYNC;
-        }
-
-        // 3. Flush file table to disk
-        if ft.flush(nvme).is_err() {
-            return SQLITE_IOERR_FSYNC;
+        let dev = &mut cache::Device::new(crypt::Device::new(nvme, key.as_ref()), &mut cache);
+        match file_ops::reserve(dev, &mut alloc, &mut ft, file, size) {
+            Ok(()) => SQLITE_OK,
+            Err(e) => fail("size hint", e),
         }
+    }
 
-        // 4. NVMe Flush — the critical barrier
-        if nvme.flush().is_err() {
-            return SQLITE_IOERR_FSYNC;
+    /// Allocator, file table and cache counters, for `PRAGMA heaven_status`.
+    pub fn status(&self) -> VfsStatus {
+        let (readahead, cache) = {
+            let cache = self.cache.lock();
+            (cache.readahead(), cache.stats())
+        };
+        let alloc = self.allocator.lock();
+        let ft = self.file_table.lock();
+        VfsStatus {
+            mode: self.mode(),
+            block_size: alloc.block_size(),
+            data_blocks: alloc.data_block_count(),
+            free_blocks: alloc.free_count(),
+            deferred_blocks: alloc.deferred_count(),
+            files: ft.iter().count(),
+            max_files: ft.capacity(),
+            readahead,
+            cache,
+            sync: *self.sync_stats.lock(),
         }
+    }
 
-        SQLITE_OK
+    /// Blocks to read ahead of sequential reads (`storage.readahead`).
+    pub fn set_readahead(&self, blocks: u16) {
+        self.cache.lock().set_readahead(blocks);
     }
 
-    // ---- xFileSize ----
+    // ---- xDeviceCharacteristics ----
 
-    pub fn file_size(&self, file: &HeavenFile) -> Result<u64, c_int> {
-        Ok(file.byte_length)
+    /// Turn IOCAP reporting on or off. Off makes SQLite assume the worst
+    /// (extra journal syncs), for devices whose guarantees can't be trusted.
+    pub fn set_device_caps(&self, enabled: bool) {
+        self.device_caps.store(enabled, Ordering::Relaxed);
     }
 
-    // ---- xTruncate ----
+    pub fn device_caps_enabled(&self) -> bool {
+        self.device_caps.load(Ordering::Relaxed)
+    }
 
-    pub fn truncate(&self, file: &mut HeavenFile, size: u64) -> c_int {
-        if size > file.byte_length {
-            return SQLITE_OK; // truncate to larger = no-op (SQLite behavior)
+    /// SQLITE_IOCAP_* flags for a file on this device.
+    ///
+    /// - ATOMICnnn: NVMe writes one logical block atomically, even across
+    ///   power loss (AWUPF is 0-based, so at least one block)
+    /// - SAFE_APPEND: `file_ops::write` puts the data on the medium before
+    ///   the new length reaches the file table (at xSync)
+    /// - POWERSAFE_OVERWRITE: partial-block writes are read-modify-write of
+    ///   whole atomic blocks, so bytes outside the written range survive
+    pub fn device_characteristics(&self, block_size: u32) -> c_int {
+        if !self.device_caps_enabled() {
+            return 0;
         }
-        file.byte_length = size;
-
-        // Release unused blocks back to the allocator.
-        let bs = file.block_size as u64;
-        let needed_blocks = if size == 0 {
-            // Keep at least 1 block so the file retains a valid start LBA.
-            1
-        } else {
-            (size + bs - 1) / bs
+        let atomic = match block_size {
+            512 => SQLITE_IOCAP_ATOMIC512,
+            1024 => SQLITE_IOCAP_ATOMIC1K,
+            2048 => SQLITE_IOCAP_ATOMIC2K,
+            4096 => SQLITE_IOCAP_ATOMIC4K,
+            _ => 0,
         };
-
-        if needed_blocks < file.block_count {
-            let mut alloc = self.allocator.lock();
-            let old_start_block = file.start_lba - alloc.data_start_lba();
-            let excess_start = old_start_block + needed_blocks;
-            let excess_count = file.block_count - needed_blocks;
-            alloc.free(excess_start, excess_count);
-            file.block_count = needed_blocks;
-
-            // Update file table entry
-            let mut ft = self.file_table.lock();
-            if let Some(entry) = ft.get_mut(file.file_table_index) {
-                entry.block_count = needed_blocks;
-                entry.byte_length = size;
-            }
-        }
-
-        SQLITE_OK
+        atomic | SQLITE_IOCAP_SAFE_APPEND | SQLITE_IOCAP_POWERSAFE_OVERWRITE
     }
 
     // ---- xDelete ----
 
     /// Lock order: allocator → file_table (NVME not needed for metadata-only ops).
+    /// SQLite expects OK for deleting non-existent files.
     pub fn delete(&self, name: &[u8]) -> c_int {
+        if self.is_read_only() {
+            return SQLITE_READONLY;
+        }
         let mut alloc = self.allocator.lock();
         let mut ft = self.file_table.lock();
-
-        if let Some((idx, entry)) = ft.lookup(name) {
-            let start_block = entry.start_block;
-            let block_count = entry.block_count;
-
-            ft.delete(idx);
-            alloc.free(start_block, block_count);
-
-            SQLITE_OK
-        } else {
-            // File doesn't exist — SQLite expects OK for deleting non-existent files
-            SQLITE_OK
-        }
+        file_ops::delete(&mut alloc, &mut ft, name);
+        SQLITE_OK
     }
 
     // ---- xAccess ----
@@ -688,9 +868,9 @@ impl HeavenVfs {
 
     // ---- xSleep ----
 
-    /// Sleep for `microseconds` using calibrated TSC busy-wait.
+    /// Sleep for `microseconds`, idling.
     pub fn sleep(&self, microseconds: u64) -> u64 {
-        crate::arch::x86_64::timer::delay_us(microseconds);
+        time::delay(time::Duration::from_micros(microseconds));
         microseconds
     }
 
@@ -729,32 +909,42 @@ impl HeavenVfs {
 
     // ---- xRandomness ----
 
-    /// Fill buffer with random bytes using RDRAND.
+    /// Fill buffer with random bytes from the kernel entropy source.
     pub fn randomness(&self, buf: &mut [u8]) {
-        for byte in buf.iter_mut() {
-            *byte = rdrand_u8();
-        }
+        crate::crypto::entropy::fill(buf);
     }
 }
 
-// ---- CPU instruction helpers ----
+/// Make a handle's length visible to other connections on the same file.
+/// Only the in-memory table changes; it reaches the medium at the next
+/// `commit_metadata`, after the data it describes.
+fn publish_length(ft: &mut FileTable, file: &HeavenFile) {
+    if let Some(entry) = ft.get_mut(file.file_table_index) {
+        entry.byte_length = file.byte_length;
+    }
+}
 
-fn rdrand_u64() -> u64 {
-    let mut val: u64;
-    unsafe {
-        core::arch::asm!(
-            "2:",
-            "rdrand {val}",
-            "jnc 2b",
-            val = out(reg) val,
-            options(nostack),
-        );
+/// Map a storage-layer error onto the matching SQLite result code.
+fn sqlite_code(e: FileError) -> c_int {
+    match e {
+        FileError::NotFound => SQLITE_IOERR,
+        FileError::Full => SQLITE_FULL,
+        FileError::NoMem => SQLITE_IOERR_NOMEM,
+        FileError::ShortRead => SQLITE_IOERR_SHORT_READ,
+        FileError::Read(_) => SQLITE_IOERR_READ,
+        FileError::Write(_) => SQLITE_IOERR_WRITE,
+        FileError::Flush(_) => SQLITE_IOERR_FSYNC,
     }
-    val
 }
 
-fn rdrand_u8() -> u8 {
-    (rdrand_u64() & 0xFF) as u8
+/// Map a failed operation onto its SQLite code, reporting device and
+/// memory failures on the console with the layers they came through.
+/// A short read or a full disk is an answer SQLite handles, not a fault.
+fn fail(op: &str, e: FileError) -> c_int {
+    if !matches!(e, FileError::ShortRead | FileError::Full | FileError::NotFound) {
+        serial_println!("[vfs] {}", KernelError::from(e).context(Layer::Vfs, op));
+    }
+    sqlite_code(e)
 }
 
 // ---- CMOS RTC reader ----
@@ -789,6 +979,15 @@ fn read_rtc_snapshot() -> (u8, u8, u8, u8, u8, u8, u8) {
     )
 }
 
+/// Wall-clock time from the CMOS RTC, seconds since 1970 (UTC).
+pub fn unix_time() -> i64 {
+    let (year, month, day, hour, minute, second) = read_cmos_rtc();
+    crate::time::epoch_secs(
+        year as i64, month as i64, day as i64, hour as i64, minute as i64, second as i64,
+    )
+    .unwrap_or(0)
+}
+
 /// Read date/time from CMOS RTC using the double-read algorithm for
 /// consistency. The RTC may start a new update cycle between individual
 /// register reads, so we read twice and compare. If they differ, we
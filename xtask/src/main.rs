@@ -0,0 +1,392 @@
+//! HeavenOS developer tooling — `cargo xtask <command>`.
+//!
+//! Replaces the hand-rolled Limine/xorriso/QEMU invocations with one
+//! host-side binary. Everything it produces lives under `target/xtask/`.
+//!
+//! Commands:
+//!   image  — build the kernel and assemble a bootable ISO
+//!   run    — image, then boot it in QEMU with NVMe + virtio-net, serial on stdio
+//!   test   — build with `--features ktest`, boot on a scratch disk, and exit
+//!            with the suite's pass/fail status (via isa-debug-exit)
+//!   gdb    — debug build, boot QEMU halted with a gdbstub, attach GDB
+//!
+//! Host tools needed: git, make, xorriso, qemu-system-x86_64 (and gdb).
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{self, Child, Command, ExitStatus};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Custom target spec (see `.cargo/config.toml`).
+const TARGET: &str = "x86_64-heavenos";
+
+/// Kernel build needs core/alloc rebuilt for the custom target.
+const BUILD_STD: &[&str] = &[
+    "-Zbuild-std=core,alloc,compiler_builtins",
+    "-Zbuild-std-features=compiler-builtins-mem",
+];
+
+const LIMINE_REPO: &str = "https://github.com/limine-bootloader/limine.git";
+const LIMINE_BRANCH: &str = "v10.x-binary";
+
+/// NVMe backing file size for a fresh disk image.
+const DISK_MIB: u64 = 64;
+
+/// isa-debug-exit status for a passing ktest run: (0x10 << 1) | 1.
+const KTEST_PASS: i32 = 33;
+
+/// Wall-clock limit for a ktest run before QEMU is killed.
+const KTEST_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// GDB stub port (`qemu -s` default).
+const GDB_PORT: u16 = 1234;
+
+const USAGE: &str = "\
+usage: cargo xtask <command> [options] [-- <extra qemu args>]
+
+commands:
+  image        build the kernel and assemble target/xtask/heavenos.iso
+  run          image, then boot it in QEMU (serial console on stdio)
+  test         build with the ktest suite, boot it, exit with its status
+  gdb          debug build, boot QEMU halted, attach GDB
+
+options:
+  --debug      build the dev profile (default for gdb)
+  --release    build the release profile (default otherwise)
+  --uefi       boot through OVMF instead of BIOS (run/gdb; needs --ovmf)
+  --ovmf PATH  OVMF firmware image (e.g. OVMF.fd) for --uefi
+  --disk PATH  NVMe backing file (default target/xtask/disk.img)
+  --gdb-bin X  debugger to launch (default: gdb)";
+
+/// Parsed command line.
+struct Opts {
+    command: String,
+    release: Option<bool>,
+    uefi: bool,
+    ovmf: Option<PathBuf>,
+    disk: Option<PathBuf>,
+    gdb_bin: String,
+    qemu_extra: Vec<String>,
+}
+
+fn main() {
+    let opts = match parse_args(env::args().skip(1).collect()) {
+        Ok(o) => o,
+        Err(e) => {
+            eprintln!("xtask: {}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+
+    let result = match opts.command.as_str() {
+        "image" => image(&opts, false).map(|_| ()),
+        "run" => run(&opts),
+        "test" => test(&opts),
+        "gdb" => gdb(&opts),
+        "help" | "-h" | "--help" => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        other => Err(format!("unknown command '{}'", other)),
+    };
+
+    if let Err(e) = result {
+        eprintln!("xtask: {}", e);
+        process::exit(1);
+    }
+}
+
+fn parse_args(args: Vec<String>) -> Result<Opts, String> {
+    let mut opts = Opts {
+        command: String::new(),
+        release: None,
+        uefi: false,
+        ovmf: None,
+        disk: None,
+        gdb_bin: String::from("gdb"),
+        qemu_extra: Vec::new(),
+    };
+
+    let mut it = args.into_iter();
+    while let Some(arg) = it.next() {
+        match arg.as_str() {
+            "--" => {
+                opts.qemu_extra.extend(it.by_ref());
+            }
+            "--debug" => opts.release = Some(false),
+            "--release" => opts.release = Some(true),
+            "--uefi" => opts.uefi = true,
+            "--ovmf" => opts.ovmf = Some(it.next().ok_or("--ovmf needs a path")?.into()),
+            "--disk" => opts.disk = Some(it.next().ok_or("--disk needs a path")?.into()),
+            "--gdb-bin" => opts.gdb_bin = it.next().ok_or("--gdb-bin needs a program")?,
+            s if s.starts_with('-') => return Err(format!("unknown option '{}'", s)),
+            s if opts.command.is_empty() => opts.command = String::from(s),
+            s => return Err(format!("unexpected argument '{}'", s)),
+        }
+    }
+
+    if opts.command.is_empty() {
+        return Err(String::from("missing command"));
+    }
+    if opts.uefi && opts.ovmf.is_none() {
+        return Err(String::from("--uefi needs --ovmf <path to OVMF firmware>"));
+    }
+    Ok(opts)
+}
+
+// ---- Paths ----
+
+fn workspace_root() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .parent()
+        .expect("xtask lives one level below the workspace root")
+        .to_path_buf()
+}
+
+fn out_dir() -> PathBuf {
+    workspace_root().join("target").join("xtask")
+}
+
+// ---- Commands ----
+
+/// Build the kernel and assemble the ISO. Returns (iso, kernel ELF).
+fn image(opts: &Opts, ktest: bool) -> Result<(PathBuf, PathBuf), String> {
+    let release = opts.release.unwrap_or(opts.command != "gdb");
+    let kernel = build_kernel(release, ktest)?;
+    let limine = ensure_limine()?;
+
+    let out = out_dir();
+    let iso_root = out.join("iso_root");
+    let _ = fs::remove_dir_all(&iso_root);
+    for dir in ["boot/limine", "EFI/BOOT"] {
+        mkdir(&iso_root.join(dir))?;
+    }
+
+    let root = workspace_root();
+    copy(&kernel, &iso_root.join("boot/kernel"))?;
+    copy(&root.join("limine.conf"), &iso_root.join("boot/limine/limine.conf"))?;
+    for f in ["limine-bios.sys", "limine-bios-cd.bin", "limine-uefi-cd.bin"] {
+        copy(&limine.join(f), &iso_root.join("boot/limine").join(f))?;
+    }
+    for f in ["BOOTX64.EFI", "BOOTIA32.EFI"] {
+        if limine.join(f).exists() {
+            copy(&limine.join(f), &iso_root.join("EFI/BOOT").join(f))?;
+        }
+    }
+
+    let iso = out.join(if ktest { "heavenos-ktest.iso" } else { "heavenos.iso" });
+    let mut xorriso = Command::new("xorriso");
+    xorriso
+        .args(["-as", "mkisofs", "-b", "boot/limine/limine-bios-cd.bin"])
+        .args(["-no-emul-boot", "-boot-load-size", "4", "-boot-info-table"])
+        .args(["--efi-boot", "boot/limine/limine-uefi-cd.bin"])
+        .args(["-efi-boot-part", "--efi-boot-image", "--protective-msdos-label"])
+        .arg(&iso_root)
+        .arg("-o")
+        .arg(&iso);
+    exec(&mut xorriso)?;
+    exec(Command::new(limine.join("limine")).arg("bios-install").arg(&iso))?;
+    let _ = fs::remove_dir_all(&iso_root);
+
+    eprintln!("xtask: image ready: {}", iso.display());
+    Ok((iso, kernel))
+}
+
+fn run(opts: &Opts) -> Result<(), String> {
+    let (iso, _) = image(opts, false)?;
+    let disk = persistent_disk(opts)?;
+    let status = exec_status(&mut qemu(opts, &iso, &disk))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("qemu exited with {}", status))
+    }
+}
+
+/// Boot the ktest build on a fresh scratch disk and translate the
+/// isa-debug-exit status into our own exit status.
+fn test(opts: &Opts) -> Result<(), String> {
+    let (iso, _) = image(opts, true)?;
+    let disk = out_dir().join("ktest.img");
+    let _ = fs::remove_file(&disk);
+    create_disk(&disk)?;
+
+    let mut cmd = qemu(opts, &iso, &disk);
+    cmd.args(["-device", "isa-debug-exit,iobase=0xf4,iosize=0x04", "-no-reboot"]);
+    let mut child = spawn(&mut cmd)?;
+    let status = wait_timeout(&mut child, KTEST_TIMEOUT)?;
+    let _ = fs::remove_file(&disk);
+
+    let Some(status) = status else {
+        return Err(format!("ktest timed out after {}s", KTEST_TIMEOUT.as_secs()));
+    };
+    match status.code() {
+        Some(KTEST_PASS) => {
+            eprintln!("xtask: ktest PASS");
+            Ok(())
+        }
+        Some(code) => Err(format!("ktest FAILED (qemu status {})", code)),
+        // No exit code: QEMU was killed by a signal
+        None => Err(format!("ktest FAILED (qemu {})", status)),
+    }
+}
+
+/// Boot QEMU halted at reset with a gdbstub, then run GDB against it.
+fn gdb(opts: &Opts) -> Result<(), String> {
+    let (iso, kernel) = image(opts, false)?;
+    let disk = persistent_disk(opts)?;
+
+    let mut cmd = qemu(opts, &iso, &disk);
+    cmd.args(["-S", "-gdb", &format!("tcp::{}", GDB_PORT)]);
+    let mut child = spawn(&mut cmd)?;
+
+    let result = exec_status(
+        Command::new(&opts.gdb_bin)
+            .arg(&kernel)
+            .args(["-ex", &format!("target remote :{}", GDB_PORT)])
+            .args(["-ex", "break kmain"]),
+    );
+    let _ = child.kill();
+    let _ = child.wait();
+    result.map(|_| ())
+}
+
+// ---- Build steps ----
+
+fn build_kernel(release: bool, ktest: bool) -> Result<PathBuf, String> {
+    let cargo = env::var("CARGO").unwrap_or_else(|_| String::from("cargo"));
+    let mut cmd = Command::new(cargo);
+    cmd.current_dir(workspace_root())
+        .args(["build", "-p", "heavenos-kernel"])
+        .args(BUILD_STD);
+    if release {
+        cmd.arg("--release");
+    }
+    if ktest {
+        cmd.args(["--features", "ktest"]);
+    }
+    exec(&mut cmd)?;
+
+    let profile = if release { "release" } else { "debug" };
+    let kernel = workspace_root()
+        .join("target")
+        .join(TARGET)
+        .join(profile)
+        .join("heavenos-kernel");
+    if !kernel.exists() {
+        return Err(format!("kernel not found at {}", kernel.display()));
+    }
+    Ok(kernel)
+}
+
+/// Fetch and build the Limine binary release once; reused afterwards.
+fn ensure_limine() -> Result<PathBuf, String> {
+    let dir = out_dir().join("limine");
+    if dir.join("limine").exists() {
+        return Ok(dir);
+    }
+
+    mkdir(&out_dir())?;
+    let _ = fs::remove_dir_all(&dir);
+    exec(Command::new("git")
+        .args(["clone", LIMINE_REPO, "--branch", LIMINE_BRANCH, "--depth=1"])
+        .arg(&dir))?;
+    exec(Command::new("make").arg("-C").arg(&dir))?;
+    Ok(dir)
+}
+
+/// The user's disk image: `--disk`, or target/xtask/disk.img (created once).
+fn persistent_disk(opts: &Opts) -> Result<PathBuf, String> {
+    let disk = opts.disk.clone().unwrap_or_else(|| out_dir().join("disk.img"));
+    if !disk.exists() {
+        create_disk(&disk)?;
+    }
+    Ok(disk)
+}
+
+fn create_disk(path: &Path) -> Result<(), String> {
+    if let Some(parent) = path.parent() {
+        mkdir(parent)?;
+    }
+    let f = fs::File::create(path).map_err(|e| format!("create {}: {}", path.display(), e))?;
+    f.set_len(DISK_MIB * 1024 * 1024)
+        .map_err(|e| format!("size {}: {}", path.display(), e))
+}
+
+/// QEMU command line: q35, 256 MB, NVMe drive, virtio-net with the HTTP
+/// port forwarded, serial console on stdio.
+fn qemu(opts: &Opts, iso: &Path, disk: &Path) -> Command {
+    let mut cmd = Command::new("qemu-system-x86_64");
+    cmd.args(["-M", "q35", "-m", "256"]);
+    if let (true, Some(ovmf)) = (opts.uefi, &opts.ovmf) {
+        cmd.arg("-bios").arg(ovmf);
+    }
+    cmd.arg("-cdrom").arg(iso);
+    if !opts.uefi {
+        cmd.args(["-boot", "d"]);
+    }
+    cmd.arg("-drive")
+        .arg(format!("file={},format=raw,if=none,id=nvme0", disk.display()))
+        .args(["-device", "nvme,serial=deadbeef,drive=nvme0"])
+        .args(["-netdev", "user,id=net0,hostfwd=tcp::8080-:80"])
+        .args(["-device", "virtio-net-pci,netdev=net0"])
+        .arg("-nographic")
+        .args(&opts.qemu_extra);
+    cmd
+}
+
+// ---- Process helpers ----
+
+fn describe(cmd: &Command) -> String {
+    let mut s = cmd.get_program().to_string_lossy().into_owned();
+    for a in cmd.get_args() {
+        s.push(' ');
+        s.push_str(&a.to_string_lossy());
+    }
+    s
+}
+
+fn spawn(cmd: &mut Command) -> Result<Child, String> {
+    eprintln!("xtask: $ {}", describe(cmd));
+    cmd.spawn().map_err(|e| format!("{}: {}", cmd.get_program().to_string_lossy(), e))
+}
+
+fn exec_status(cmd: &mut Command) -> Result<ExitStatus, String> {
+    spawn(cmd)?.wait().map_err(|e| e.to_string())
+}
+
+fn exec(cmd: &mut Command) -> Result<(), String> {
+    let status = exec_status(cmd)?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("`{}` failed with {}", cmd.get_program().to_string_lossy(), status))
+    }
+}
+
+/// Wait for `child`; kill it and return None once `limit` has passed.
+fn wait_timeout(child: &mut Child, limit: Duration) -> Result<Option<ExitStatus>, String> {
+    let start = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait().map_err(|e| e.to_string())? {
+            return Ok(Some(status));
+        }
+        if start.elapsed() >= limit {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+fn mkdir(path: &Path) -> Result<(), String> {
+    fs::create_dir_all(path).map_err(|e| format!("mkdir {}: {}", path.display(), e))
+}
+
+fn copy(from: &Path, to: &Path) -> Result<(), String> {
+    fs::copy(from, to)
+        .map(|_| ())
+        .map_err(|e| format!("copy {} → {}: {}", from.display(), to.display(), e))
+}